@@ -4,6 +4,11 @@
 
 //! Derives a 9P wire format encoding for a struct by recursively calling
 //! `WireFormat::encode` or `WireFormat::decode` on the fields of the struct.
+//!
+//! Enums are also supported if they have a `#[repr(u8)]` or `#[repr(u16)]`
+//! attribute and every variant has an explicit discriminant. The discriminant
+//! is encoded first, followed by the fields of the variant.
+//!
 //! This is only intended to be used from within the `p9` crate.
 
 #![recursion_limit = "256"]
@@ -14,8 +19,11 @@ use quote::quote;
 use quote::quote_spanned;
 use syn::parse_macro_input;
 use syn::spanned::Spanned;
+use syn::Attribute;
 use syn::Data;
+use syn::DataEnum;
 use syn::DeriveInput;
+use syn::Expr;
 use syn::Fields;
 use syn::Ident;
 
@@ -35,19 +43,56 @@ fn p9_wire_format_inner(input: DeriveInput) -> TokenStream {
 
     let container = input.ident;
 
-    let byte_size_impl = byte_size_sum(&input.data);
-    let encode_impl = encode_wire_format(&input.data);
-    let decode_impl = decode_wire_format(&input.data, &container);
+    // Discriminants may refer to constants from the module that defines an enum, so everything in
+    // that module has to be in scope.
+    let imports = if let Data::Enum(_) = input.data {
+        quote! { use super::*; }
+    } else {
+        quote! { use super::#container; }
+    };
+
+    let (byte_size_impl, encode_impl, decode_impl) = if let Data::Enum(ref data) = input.data {
+        let repr = match enum_repr(&input.attrs) {
+            Some(repr) => repr,
+            None => {
+                return quote_spanned! {container.span()=>
+                    compile_error!("derive(P9WireFormat) requires enums to be #[repr(u8)] or #[repr(u16)]");
+                };
+            }
+        };
+        if data.variants.is_empty() {
+            return quote_spanned! {container.span()=>
+                compile_error!("derive(P9WireFormat) does not support enums without variants");
+            };
+        }
+        if let Some(variant) = data.variants.iter().find(|v| v.discriminant.is_none()) {
+            return quote_spanned! {variant.span()=>
+                compile_error!("derive(P9WireFormat) requires every variant to have an explicit discriminant");
+            };
+        }
+
+        (
+            enum_byte_size_sum(data, &container, &repr),
+            enum_encode_wire_format(data, &container, &repr),
+            enum_decode_wire_format(data, &container, &repr),
+        )
+    } else {
+        (
+            byte_size_sum(&input.data),
+            encode_wire_format(&input.data),
+            decode_wire_format(&input.data, &container),
+        )
+    };
 
     let scope = format!("wire_format_{}", container).to_lowercase();
     let scope = Ident::new(&scope, Span::call_site());
     quote! {
         mod #scope {
-            use ::std::io;
-            use ::std::result::Result::Ok;
+            use ::core::result::Result::Ok;
 
-            use super::#container;
+            #imports
 
+            use crate::protocol::io;
             use crate::protocol::WireFormat;
 
             impl WireFormat for #container {
@@ -149,6 +194,124 @@ fn decode_wire_format(data: &Data, container: &Ident) -> TokenStream {
     }
 }
 
+// Returns the integer type named by a `#[repr(u8)]` or `#[repr(u16)]` attribute, which is used to
+// encode the discriminant of an enum.
+fn enum_repr(attrs: &[Attribute]) -> Option<Ident> {
+    let mut repr = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        // Other repr hints like `C` don't matter for the wire format, so they are ignored here.
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("u8") || meta.path.is_ident("u16") {
+                repr = meta.path.get_ident().cloned();
+            }
+            Ok(())
+        });
+    }
+
+    repr
+}
+
+// Returns the discriminant of every variant, along with the pattern that binds its fields and the
+// names of those bindings. Fields of tuple variants are bound to `__field0`, `__field1`, and so on.
+fn enum_variants<'a>(
+    data: &'a DataEnum,
+    container: &'a Ident,
+) -> impl Iterator<Item = (&'a Expr, TokenStream, Vec<Ident>)> + 'a {
+    data.variants.iter().map(move |v| {
+        let variant = &v.ident;
+        // The caller has already checked that every variant has a discriminant.
+        let (_, discriminant) = v.discriminant.as_ref().unwrap();
+        match v.fields {
+            Fields::Named(ref fields) => {
+                let names: Vec<Ident> = fields
+                    .named
+                    .iter()
+                    .map(|f| f.ident.clone().unwrap())
+                    .collect();
+                let pattern = quote! { #container::#variant { #(#names),* } };
+                (discriminant, pattern, names)
+            }
+            Fields::Unnamed(ref fields) => {
+                let names: Vec<Ident> = (0..fields.unnamed.len())
+                    .map(|i| Ident::new(&format!("__field{}", i), Span::call_site()))
+                    .collect();
+                let pattern = quote! { #container::#variant(#(#names),*) };
+                (discriminant, pattern, names)
+            }
+            Fields::Unit => (discriminant, quote! { #container::#variant }, Vec::new()),
+        }
+    })
+}
+
+// Generate code that adds the size of the discriminant to the size of every field in the variant.
+fn enum_byte_size_sum(data: &DataEnum, container: &Ident, repr: &Ident) -> TokenStream {
+    let arms = enum_variants(data, container).map(|(discriminant, pattern, names)| {
+        quote! {
+            #pattern => <#repr as WireFormat>::byte_size(&(#discriminant))
+                #(+ WireFormat::byte_size(#names))*,
+        }
+    });
+
+    quote! {
+        match self {
+            #(#arms)*
+        }
+    }
+}
+
+// Generate code that encodes the discriminant followed by every field in the variant.
+fn enum_encode_wire_format(data: &DataEnum, container: &Ident, repr: &Ident) -> TokenStream {
+    let arms = enum_variants(data, container).map(|(discriminant, pattern, names)| {
+        quote! {
+            #pattern => {
+                <#repr as WireFormat>::encode(&(#discriminant), _writer)?;
+                #(WireFormat::encode(#names, _writer)?;)*
+            }
+        }
+    });
+
+    quote! {
+        match self {
+            #(#arms)*
+        }
+
+        Ok(())
+    }
+}
+
+// Generate code that decodes the discriminant and then every field of the matching variant.
+fn enum_decode_wire_format(data: &DataEnum, container: &Ident, repr: &Ident) -> TokenStream {
+    let arms = data
+        .variants
+        .iter()
+        .zip(enum_variants(data, container))
+        .map(|(v, (discriminant, pattern, names))| {
+            let variant = &v.ident;
+            let value = match v.fields {
+                Fields::Named(_) => quote! { #container::#variant { #(#names: #names,)* } },
+                _ => pattern,
+            };
+            quote! {
+                _ if tag == (#discriminant) => {
+                    #(let #names = WireFormat::decode(_reader)?;)*
+                    Ok(#value)
+                }
+            }
+        });
+
+    let name = container.to_string();
+    quote! {
+        let tag = <#repr as WireFormat>::decode(_reader)?;
+        match tag {
+            #(#arms)*
+            _ => ::core::result::Result::Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ::alloc::format!("invalid discriminant {} for {}", tag, #name),
+            )),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use syn::parse_quote;
@@ -242,11 +405,11 @@ mod tests {
 
         let expected = quote! {
             mod wire_format_niijima_先輩 {
-                use ::std::io;
-                use ::std::result::Result::Ok;
+                use ::core::result::Result::Ok;
 
                 use super::Niijima_先輩;
 
+                use crate::protocol::io;
                 use crate::protocol::WireFormat;
 
                 impl WireFormat for Niijima_先輩 {
@@ -298,4 +461,104 @@ mod tests {
             expected.to_string(),
         );
     }
+
+    #[test]
+    fn enum_encode() {
+        let input: DeriveInput = parse_quote! {
+            #[repr(u8)]
+            enum Item {
+                Unit = 1,
+                Named { ident: u32, other: u8 } = 2,
+                Unnamed(String) = CONSTANT,
+            }
+        };
+
+        let data = match input.data {
+            Data::Enum(ref data) => data,
+            _ => unreachable!(),
+        };
+        let container = Ident::new("Item", Span::call_site());
+        let repr = enum_repr(&input.attrs).expect("missing repr");
+        let expected = quote! {
+            match self {
+                Item::Unit => {
+                    <u8 as WireFormat>::encode(&(1), _writer)?;
+                }
+                Item::Named { ident, other } => {
+                    <u8 as WireFormat>::encode(&(2), _writer)?;
+                    WireFormat::encode(ident, _writer)?;
+                    WireFormat::encode(other, _writer)?;
+                }
+                Item::Unnamed(__field0) => {
+                    <u8 as WireFormat>::encode(&(CONSTANT), _writer)?;
+                    WireFormat::encode(__field0, _writer)?;
+                }
+            }
+            Ok(())
+        };
+
+        assert_eq!(
+            enum_encode_wire_format(data, &container, &repr).to_string(),
+            expected.to_string(),
+        );
+    }
+
+    #[test]
+    fn enum_decode() {
+        let input: DeriveInput = parse_quote! {
+            #[repr(u16)]
+            enum Item {
+                Unit = 1,
+                Named { ident: u32, other: u8 } = 2,
+                Unnamed(String) = CONSTANT,
+            }
+        };
+
+        let data = match input.data {
+            Data::Enum(ref data) => data,
+            _ => unreachable!(),
+        };
+        let container = Ident::new("Item", Span::call_site());
+        let repr = enum_repr(&input.attrs).expect("missing repr");
+        let expected = quote! {
+            let tag = <u16 as WireFormat>::decode(_reader)?;
+            match tag {
+                _ if tag == (1) => {
+                    Ok(Item::Unit)
+                }
+                _ if tag == (2) => {
+                    let ident = WireFormat::decode(_reader)?;
+                    let other = WireFormat::decode(_reader)?;
+                    Ok(Item::Named { ident: ident, other: other, })
+                }
+                _ if tag == (CONSTANT) => {
+                    let __field0 = WireFormat::decode(_reader)?;
+                    Ok(Item::Unnamed(__field0))
+                }
+                _ => ::core::result::Result::Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    ::alloc::format!("invalid discriminant {} for {}", tag, "Item"),
+                )),
+            }
+        };
+
+        assert_eq!(
+            enum_decode_wire_format(data, &container, &repr).to_string(),
+            expected.to_string(),
+        );
+    }
+
+    #[test]
+    fn enum_without_repr() {
+        let input: DeriveInput = parse_quote! {
+            #[repr(C)]
+            enum Item {
+                Unit = 1,
+            }
+        };
+
+        assert!(p9_wire_format_inner(input)
+            .to_string()
+            .starts_with("compile_error !"));
+    }
 }
@@ -2,13 +2,11 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::io::Cursor;
-
 use crate::protocol::Tframe;
 use crate::protocol::WireFormat;
 
 pub fn tframe_decode(bytes: &[u8]) {
-    let mut cursor = Cursor::new(bytes);
+    let mut reader = bytes;
 
-    while Tframe::decode(&mut cursor).is_ok() {}
+    while Tframe::decode(&mut reader).is_ok() {}
 }
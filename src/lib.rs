@@ -2,15 +2,23 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#![cfg(unix)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-mod protocol;
+extern crate alloc;
+// The tests use the standard library for their own purposes even without the `std` feature.
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+pub mod protocol;
+#[cfg(all(unix, feature = "server"))]
 mod server;
 
 pub mod fuzzing;
 
+#[cfg(all(unix, feature = "server"))]
 pub use server::*;
 
+#[cfg(all(unix, feature = "server"))]
 #[macro_export]
 macro_rules! syscall {
     ($e:expr) => {{
@@ -0,0 +1,299 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! CRC32C checksums for frames sent over transports that can silently corrupt data.
+//!
+//! A 9P2000.L client asks for checksums by adding the `crc32c` extension to its version string,
+//! as in "9P2000.L+crc32c". Once the server agrees, every frame in either direction is followed
+//! by the CRC32C of all of its bytes, as a little-endian u32 that is not counted in the frame's
+//! size. Tversion and Rversion never carry a checksum, so that the connection can always be
+//! renegotiated.
+
+use alloc::format;
+
+use crate::protocol::io;
+use crate::protocol::io::ErrorKind;
+use crate::protocol::io::Read;
+use crate::protocol::io::Write;
+use crate::protocol::messages::is_checksummed;
+use crate::protocol::wire_format::WireFormat;
+
+// The reversed Castagnoli polynomial.
+const POLY: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// The offset of the message type in a frame.
+const TYPE_OFFSET: u64 = 4;
+
+// Continues a CRC32C computation over `data`. The state starts out as !0 and is inverted at the
+// end to get the checksum.
+fn update(mut state: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        state = TABLE[((state ^ u32::from(b)) & 0xff) as usize] ^ (state >> 8);
+    }
+    state
+}
+
+/// Returns the CRC32C of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !update(!0, data)
+}
+
+// Returns an error for a frame whose checksum doesn't match its contents.
+fn mismatch(expected: u32, actual: u32) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "frame checksum mismatch: expected {:#010x}, got {:#010x}",
+            expected, actual
+        ),
+    )
+}
+
+/// Verifies the checksum at the end of `frame`, which must hold exactly one complete frame and
+/// its checksum, and returns the frame without it.
+pub fn verify_frame(frame: &[u8]) -> io::Result<&[u8]> {
+    match frame.get(TYPE_OFFSET as usize) {
+        Some(&ty) if !is_checksummed(ty) => return Ok(frame),
+        _ => {}
+    }
+
+    if frame.len() < 4 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "frame is too short to hold a checksum",
+        ));
+    }
+    let (frame, mut trailer) = frame.split_at(frame.len() - 4);
+    let expected = u32::decode(&mut trailer)?;
+    let actual = crc32c(frame);
+    if expected != actual {
+        return Err(mismatch(expected, actual));
+    }
+
+    Ok(frame)
+}
+
+/// Computes the checksum of a frame as it is read from another reader.
+pub struct ChecksumReader<R> {
+    inner: R,
+    state: u32,
+    len: u64,
+    ty: Option<u8>,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> ChecksumReader<R> {
+        ChecksumReader {
+            inner,
+            state: !0,
+            len: 0,
+            ty: None,
+        }
+    }
+
+    /// Reads the checksum that follows the frame that was read and checks that it matches.
+    /// Returns the inner reader, positioned at the start of the next frame.
+    pub fn verify(mut self) -> io::Result<R> {
+        if self.ty.is_some_and(|ty| !is_checksummed(ty)) {
+            return Ok(self.inner);
+        }
+
+        let expected = u32::decode(&mut self.inner)?;
+        let actual = !self.state;
+        if expected != actual {
+            return Err(mismatch(expected, actual));
+        }
+
+        Ok(self.inner)
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        track(&mut self.state, &mut self.len, &mut self.ty, &buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Computes the checksum of a frame as it is written to another writer, and appends it once the
+/// frame is complete.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    state: u32,
+    len: u64,
+    ty: Option<u8>,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            state: !0,
+            len: 0,
+            ty: None,
+        }
+    }
+
+    /// Writes the checksum of the frame that was written and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.ty.is_none_or(is_checksummed) {
+            (!self.state).encode(&mut self.inner)?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        track(&mut self.state, &mut self.len, &mut self.ty, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Adds `data`, which follows the first `len` bytes of a frame, to the checksum and remembers the
+// type of the frame when it goes by.
+fn track(state: &mut u32, len: &mut u64, ty: &mut Option<u8>, data: &[u8]) {
+    if *len <= TYPE_OFFSET && TYPE_OFFSET < *len + data.len() as u64 {
+        *ty = Some(data[(TYPE_OFFSET - *len) as usize]);
+    }
+    *state = update(*state, data);
+    *len += data.len() as u64;
+}
+
+/// Appends the checksum of the frame in `buf[..len]` to it and returns the new length. Fails if
+/// there is no room for the checksum.
+pub fn append_to_slice(buf: &mut [u8], len: usize) -> io::Result<usize> {
+    if buf
+        .get(TYPE_OFFSET as usize)
+        .is_some_and(|&ty| !is_checksummed(ty))
+    {
+        return Ok(len);
+    }
+
+    let crc = crc32c(&buf[..len]).to_le_bytes();
+    let end = len + crc.len();
+    if buf.len() < end {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "buffer of {} bytes is too small for a checksummed frame of {} bytes",
+                buf.len(),
+                end
+            ),
+        ));
+    }
+    buf[len..end].copy_from_slice(&crc);
+
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::protocol::messages::Dialect;
+    use crate::protocol::messages::Rframe;
+    use crate::protocol::messages::Rmessage;
+    use crate::protocol::messages::Rversion;
+    use crate::protocol::messages::Rwrite;
+    use crate::protocol::wire_format::P9String;
+
+    fn encode(frame: &Rframe) -> Vec<u8> {
+        let mut writer = ChecksumWriter::new(Vec::new());
+        frame.encode(&mut writer).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = Rframe {
+            tag: 3,
+            msg: Rmessage::Write(Rwrite { count: 42 }),
+        };
+        let buf = encode(&frame);
+        assert_eq!(buf.len(), frame.byte_size() as usize + 4);
+        assert_eq!(
+            verify_frame(&buf).unwrap().len(),
+            frame.byte_size() as usize
+        );
+
+        let mut reader = ChecksumReader::new(&buf[..]);
+        Rframe::decode(&mut reader).unwrap();
+        assert!(reader.verify().unwrap().is_empty());
+
+        let mut slice = [0; 64];
+        let len = frame.encode_to_slice(&mut slice, Dialect::Linux).unwrap();
+        let len = append_to_slice(&mut slice, len).unwrap();
+        assert_eq!(slice[..len], buf[..]);
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut buf = encode(&Rframe {
+            tag: 3,
+            msg: Rmessage::Write(Rwrite { count: 42 }),
+        });
+        buf[7] ^= 0x10;
+
+        let err = verify_frame(&buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut reader = ChecksumReader::new(&buf[..]);
+        Rframe::decode(&mut reader).unwrap();
+        let err = reader.verify().map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn version_is_exempt() {
+        let frame = Rframe {
+            tag: u16::MAX,
+            msg: Rmessage::Version(Rversion {
+                msize: 8192,
+                version: P9String::new("9P2000.L+crc32c").unwrap(),
+            }),
+        };
+        let buf = encode(&frame);
+        assert_eq!(buf.len(), frame.byte_size() as usize);
+        assert_eq!(verify_frame(&buf).unwrap(), &buf[..]);
+
+        let mut reader = ChecksumReader::new(&buf[..]);
+        Rframe::decode(&mut reader).unwrap();
+        assert!(reader.verify().unwrap().is_empty());
+    }
+}
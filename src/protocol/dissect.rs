@@ -0,0 +1,371 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Renders raw 9P frames in a human-readable form, for debugging and for tools that inspect
+//! traffic between a client and a server.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+
+use crate::protocol::messages::message_name;
+use crate::protocol::messages::Dialect;
+use crate::protocol::messages::Rframe;
+use crate::protocol::messages::Rmessage;
+use crate::protocol::messages::Tframe;
+use crate::protocol::messages::Tmessage;
+
+// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = mem::size_of::<u32>() + mem::size_of::<u8>() + mem::size_of::<u16>();
+
+// The number of bytes of an undecodable frame that are shown when it is displayed.
+const PREVIEW_LEN: usize = 32;
+
+/// A decoded frame.
+#[derive(Debug)]
+pub enum Message {
+    Request { tag: u16, msg: Tmessage },
+    Reply { tag: u16, msg: Rmessage },
+}
+
+/// One frame found by `dissect`.
+#[derive(Debug)]
+pub struct Frame<'a> {
+    /// The position of the frame in the input.
+    pub offset: usize,
+    /// The bytes of the frame, including its header.
+    pub raw: &'a [u8],
+    /// The decoded frame, or the reason it could not be decoded.
+    pub message: Result<Message, String>,
+}
+
+impl Frame<'_> {
+    /// Returns the message type from the header of the frame, if it is long enough to have one.
+    pub fn ty(&self) -> Option<u8> {
+        self.raw.get(mem::size_of::<u32>()).copied()
+    }
+
+    /// Returns the tag from the header of the frame, if it is long enough to have one.
+    pub fn tag(&self) -> Option<u16> {
+        let start = mem::size_of::<u32>() + mem::size_of::<u8>();
+        self.raw
+            .get(start..HEADER_SIZE)
+            .map(|tag| u16::from_le_bytes([tag[0], tag[1]]))
+    }
+}
+
+/// Splits `bytes` into frames that were sent by a client or server that negotiated `dialect` and
+/// decodes each of them. Requests and replies may be interleaved because they are told apart by
+/// their type. Decoding stops at the first frame whose size doesn't make sense, since the start of
+/// the next frame can't be found after that.
+pub fn dissect(bytes: &[u8], dialect: Dialect) -> Vec<Frame<'_>> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let size = match rest.get(..mem::size_of::<u32>()) {
+            Some(size) => u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize,
+            None => {
+                frames.push(Frame {
+                    offset,
+                    raw: rest,
+                    message: Err("truncated frame header".to_string()),
+                });
+                break;
+            }
+        };
+        if size < HEADER_SIZE || size > rest.len() {
+            let error = if size < HEADER_SIZE {
+                format!("frame size ({}) is smaller than the header", size)
+            } else {
+                format!(
+                    "frame size ({}) exceeds the remaining {} bytes",
+                    size,
+                    rest.len()
+                )
+            };
+            frames.push(Frame {
+                offset,
+                raw: rest,
+                message: Err(error),
+            });
+            break;
+        }
+
+        let raw = &rest[..size];
+        frames.push(Frame {
+            offset,
+            raw,
+            message: decode(raw, dialect),
+        });
+        offset += size;
+    }
+
+    frames
+}
+
+fn decode(mut raw: &[u8], dialect: Dialect) -> Result<Message, String> {
+    // Every T-message type is even and the matching R-message type is one greater.
+    if raw[mem::size_of::<u32>()].is_multiple_of(2) {
+        match Tframe::decode_dialect(&mut raw, dialect) {
+            Ok(Tframe { tag, msg: Ok(msg) }) => Ok(Message::Request { tag, msg }),
+            Ok(Tframe { msg: Err(e), .. }) | Err(e) => Err(e.to_string()),
+        }
+    } else {
+        Rframe::decode_dialect(&mut raw, dialect)
+            .map(|Rframe { tag, msg }| Message::Reply { tag, msg })
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Displays the name of a message that has no fields.
+struct Name(&'static str);
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+fn tmessage_fields(msg: &Tmessage) -> &dyn fmt::Debug {
+    match *msg {
+        Tmessage::Version(ref msg) => msg,
+        Tmessage::Flush(ref msg) => msg,
+        Tmessage::Walk(ref msg) => msg,
+        Tmessage::Read(ref msg) => msg,
+        Tmessage::Write(ref msg) => msg,
+        Tmessage::Clunk(ref msg) => msg,
+        Tmessage::Remove(ref msg) => msg,
+        Tmessage::Attach(ref msg) => msg,
+        Tmessage::Auth(ref msg) => msg,
+        Tmessage::Open(ref msg) => msg,
+        Tmessage::Create(ref msg) => msg,
+        Tmessage::Stat(ref msg) => msg,
+        Tmessage::Wstat(ref msg) => msg,
+        Tmessage::Statfs(ref msg) => msg,
+        Tmessage::Lopen(ref msg) => msg,
+        Tmessage::Lcreate(ref msg) => msg,
+        Tmessage::Symlink(ref msg) => msg,
+        Tmessage::Mknod(ref msg) => msg,
+        Tmessage::Rename(ref msg) => msg,
+        Tmessage::Readlink(ref msg) => msg,
+        Tmessage::GetAttr(ref msg) => msg,
+        Tmessage::SetAttr(ref msg) => msg,
+        Tmessage::XattrWalk(ref msg) => msg,
+        Tmessage::XattrCreate(ref msg) => msg,
+        Tmessage::Readdir(ref msg) => msg,
+        Tmessage::ReaddirPlus(ref msg) => msg,
+        Tmessage::Ping => &Name("Tping"),
+        Tmessage::Resume(ref msg) => msg,
+        Tmessage::CopyRange(ref msg) => msg,
+        Tmessage::CloneRange(ref msg) => msg,
+        Tmessage::Lseek(ref msg) => msg,
+        Tmessage::Fallocate(ref msg) => msg,
+        Tmessage::Fadvise(ref msg) => msg,
+        Tmessage::Ioctl(ref msg) => msg,
+        Tmessage::Watch(ref msg) => msg,
+        Tmessage::Lease(ref msg) => msg,
+        Tmessage::LeaseBreaks => &Name("Tleasebreaks"),
+        Tmessage::RenameAt2(ref msg) => msg,
+        Tmessage::Fsync(ref msg) => msg,
+        Tmessage::Lock(ref msg) => msg,
+        Tmessage::GetLock(ref msg) => msg,
+        Tmessage::Link(ref msg) => msg,
+        Tmessage::Mkdir(ref msg) => msg,
+        Tmessage::RenameAt(ref msg) => msg,
+        Tmessage::UnlinkAt(ref msg) => msg,
+        Tmessage::Vendor(ref msg) => msg,
+    }
+}
+
+fn rmessage_fields(msg: &Rmessage) -> &dyn fmt::Debug {
+    match *msg {
+        Rmessage::Version(ref msg) => msg,
+        Rmessage::Flush => &Name("Rflush"),
+        Rmessage::Walk(ref msg) => msg,
+        Rmessage::Read(ref msg) => msg,
+        Rmessage::Write(ref msg) => msg,
+        Rmessage::Clunk => &Name("Rclunk"),
+        Rmessage::Remove => &Name("Rremove"),
+        Rmessage::Attach(ref msg) => msg,
+        Rmessage::Auth(ref msg) => msg,
+        Rmessage::Open(ref msg) => msg,
+        Rmessage::Create(ref msg) => msg,
+        Rmessage::Stat(ref msg) => msg,
+        Rmessage::Wstat => &Name("Rwstat"),
+        Rmessage::Statfs(ref msg) => msg,
+        Rmessage::Lopen(ref msg) => msg,
+        Rmessage::Lcreate(ref msg) => msg,
+        Rmessage::Symlink(ref msg) => msg,
+        Rmessage::Mknod(ref msg) => msg,
+        Rmessage::Rename => &Name("Rrename"),
+        Rmessage::Readlink(ref msg) => msg,
+        Rmessage::GetAttr(ref msg) => msg,
+        Rmessage::SetAttr => &Name("Rsetattr"),
+        Rmessage::XattrWalk(ref msg) => msg,
+        Rmessage::XattrCreate => &Name("Rxattrcreate"),
+        Rmessage::Readdir(ref msg) => msg,
+        Rmessage::ReaddirPlus(ref msg) => msg,
+        Rmessage::Ping => &Name("Rping"),
+        Rmessage::Resume(ref msg) => msg,
+        Rmessage::CopyRange(ref msg) => msg,
+        Rmessage::CloneRange => &Name("Rclonerange"),
+        Rmessage::Lseek(ref msg) => msg,
+        Rmessage::Fallocate => &Name("Rfallocate"),
+        Rmessage::Fadvise => &Name("Rfadvise"),
+        Rmessage::Ioctl(ref msg) => msg,
+        Rmessage::Watch => &Name("Rwatch"),
+        Rmessage::Lease => &Name("Rlease"),
+        Rmessage::LeaseBreaks(ref msg) => msg,
+        Rmessage::RenameAt2 => &Name("Rrenameat2"),
+        Rmessage::Fsync => &Name("Rfsync"),
+        Rmessage::Lock(ref msg) => msg,
+        Rmessage::GetLock(ref msg) => msg,
+        Rmessage::Link => &Name("Rlink"),
+        Rmessage::Mkdir(ref msg) => msg,
+        Rmessage::RenameAt => &Name("Rrenameat"),
+        Rmessage::UnlinkAt => &Name("Runlinkat"),
+        Rmessage::Lerror(ref msg) => msg,
+        Rmessage::Error(ref msg) => msg,
+        Rmessage::Vendor(ref msg) => msg,
+    }
+}
+
+// Displays the first few bytes of a frame in hex.
+struct Preview<'a>(&'a [u8]);
+
+impl fmt::Display for Preview<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, b) in self.0.iter().take(PREVIEW_LEN).enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        if self.0.len() > PREVIEW_LEN {
+            write!(f, " ... ({} bytes)", self.0.len())?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Shows the offset and tag of the frame followed by its fields, e.g.
+/// `0x0000 tag 1: Twalk { fid: 1, newfid: 2, wnames: ["foo"] }`. The alternate form (`{:#}`) puts
+/// each field on its own line.
+impl fmt::Display for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06x} ", self.offset)?;
+        if let Some(tag) = self.tag() {
+            write!(f, "tag {}: ", tag)?;
+        }
+
+        let fields = match self.message {
+            Ok(Message::Request { ref msg, .. }) => tmessage_fields(msg),
+            Ok(Message::Reply { ref msg, .. }) => rmessage_fields(msg),
+            Err(ref error) => {
+                if let Some(ty) = self.ty() {
+                    match message_name(ty) {
+                        Some(name) => write!(f, "{}: ", name)?,
+                        None => write!(f, "type {}: ", ty)?,
+                    }
+                }
+                return write!(f, "invalid frame: {} {}", error, Preview(self.raw));
+            }
+        };
+
+        if f.alternate() {
+            write!(f, "{:#?}", fields)
+        } else {
+            write!(f, "{:?}", fields)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+    use crate::protocol::messages::Rlerror;
+    use crate::protocol::messages::Twalk;
+    use crate::protocol::wire_format::P9String;
+    use crate::protocol::wire_format::WireFormat;
+
+    #[test]
+    fn frames() {
+        let mut bytes = Vec::new();
+        Tframe {
+            tag: 1,
+            msg: Ok(Tmessage::Walk(Twalk {
+                fid: 1,
+                newfid: 2,
+                wnames: vec![P9String::new("foo").unwrap()],
+            })),
+        }
+        .encode(&mut bytes)
+        .unwrap();
+        Rframe {
+            tag: 1,
+            msg: Rmessage::Lerror(Rlerror { ecode: 2 }),
+        }
+        .encode(&mut bytes)
+        .unwrap();
+        Rframe {
+            tag: 2,
+            msg: Rmessage::Clunk,
+        }
+        .encode(&mut bytes)
+        .unwrap();
+        // A vendor extension.
+        bytes.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0xfa, 0x03, 0x00, 0x2a]);
+        // A Tclunk that is too short.
+        bytes.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0x78, 0x05, 0x00, 0x2a]);
+        // A frame that claims to be larger than what's left.
+        bytes.extend_from_slice(&[0xff, 0x00, 0x00, 0x00, 0x6e, 0x04, 0x00]);
+
+        let frames = dissect(&bytes, Dialect::Linux);
+        let lines: Vec<String> = frames.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"0x0000 tag 1: Twalk { fid: 1, newfid: 2, wnames: ["foo"] }"#,
+                "0x0016 tag 1: Rlerror { ecode: 2 }",
+                "0x0021 tag 2: Rclunk",
+                "0x0028 tag 3: Tvendor { ty: 250, body: VendorBody([42]) }",
+                "0x0030 tag 5: Tclunk: invalid frame: failed to fill whole buffer \
+                 [08 00 00 00 78 05 00 2a]",
+                "0x0038 tag 4: Twalk: invalid frame: frame size (255) exceeds the remaining 7 \
+                 bytes [ff 00 00 00 6e 04 00]",
+            ]
+        );
+
+        assert_eq!(frames[1].offset, 22);
+        assert_eq!(frames[1].raw, &bytes[22..33]);
+        assert!(matches!(
+            frames[1].message,
+            Ok(Message::Reply {
+                tag: 1,
+                msg: Rmessage::Lerror(Rlerror { ecode: 2 })
+            })
+        ));
+        assert_eq!(frames[5].ty(), Some(110));
+        assert_eq!(frames[5].tag(), Some(4));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let frames = dissect(&[0x10, 0x00], Dialect::Linux);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].ty(), None);
+        assert_eq!(frames[0].tag(), None);
+        assert_eq!(
+            frames[0].to_string(),
+            "0x0000 invalid frame: truncated frame header [10 00]"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Optional extensions to 9P2000.L.
+//!
+//! A client asks for extensions by appending "+name" to the version string of its Tversion for
+//! each of them, as in "9P2000.L+readdirplus+crc32c". The server replies with the base version
+//! followed by the ones it agreed to, in the same form. Servers that don't know about
+//! extensions ignore the suffix, and clients that don't ask for any see plain 9P2000.L.
+
+use alloc::string::String;
+use core::fmt;
+
+use crate::protocol::messages::Dialect;
+
+/// A set of extensions to 9P2000.L.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Extensions(u32);
+
+impl Extensions {
+    /// Treaddirplus, which returns the attributes of each directory entry along with it.
+    pub const READDIRPLUS: Extensions = Extensions(1 << 0);
+    /// A CRC32C checksum after every frame. See the `checksum` module.
+    pub const CRC32C: Extensions = Extensions(1 << 1);
+    /// Tping, which the server answers with an Rping and nothing else, so that clients can check
+    /// that it is still there.
+    pub const PING: Extensions = Extensions(1 << 2);
+    /// Tresume, which lets a client take its fids over to a new connection if the current one
+    /// drops.
+    pub const RESUME: Extensions = Extensions(1 << 3);
+    /// A flag for Tlock, 4 in its flags, that asks for a whole-file flock(2) lock on the file of
+    /// the fid instead of a POSIX record lock. The Linux client sends flock(2) as a POSIX lock,
+    /// so clients that want the difference have to ask for this.
+    pub const FLOCK: Extensions = Extensions(1 << 4);
+    /// Tcopyrange, which copies data between two files without it going over the connection.
+    pub const COPYRANGE: Extensions = Extensions(1 << 5);
+    /// Tclonerange, which makes part of one file share storage with part of another.
+    pub const CLONERANGE: Extensions = Extensions(1 << 6);
+    /// Tlseek, which finds the data and holes in sparse files.
+    pub const LSEEK: Extensions = Extensions(1 << 7);
+    /// Tfallocate, which preallocates space in files or punches holes in them.
+    pub const FALLOCATE: Extensions = Extensions(1 << 8);
+    /// Tfadvise, which passes hints about access patterns on to the server's page cache.
+    pub const FADVISE: Extensions = Extensions(1 << 9);
+    /// Tioctl, which runs one of a few harmless ioctls on a file.
+    pub const IOCTL: Extensions = Extensions(1 << 10);
+    /// Twatch, which lets clients read about changes to files instead of polling them.
+    pub const NOTIFY: Extensions = Extensions(1 << 11);
+    /// Tlease and Tleasebreaks, which let clients cache files that nobody else is changing.
+    pub const LEASE: Extensions = Extensions(1 << 12);
+    /// Trenameat2, which renames files with the flags of renameat2(2), so that a rename can
+    /// refuse to replace its target or swap two files.
+    pub const RENAMEAT2: Extensions = Extensions(1 << 13);
+
+    // Every extension, in the order in which they are listed in version strings.
+    const NAMES: [(Extensions, &'static str); 14] = [
+        (Extensions::READDIRPLUS, "readdirplus"),
+        (Extensions::CRC32C, "crc32c"),
+        (Extensions::PING, "ping"),
+        (Extensions::RESUME, "resume"),
+        (Extensions::FLOCK, "flock"),
+        (Extensions::COPYRANGE, "copyrange"),
+        (Extensions::CLONERANGE, "clonerange"),
+        (Extensions::LSEEK, "lseek"),
+        (Extensions::FALLOCATE, "fallocate"),
+        (Extensions::FADVISE, "fadvise"),
+        (Extensions::IOCTL, "ioctl"),
+        (Extensions::NOTIFY, "notify"),
+        (Extensions::LEASE, "lease"),
+        (Extensions::RENAMEAT2, "renameat2"),
+    ];
+
+    /// Returns the empty set.
+    pub const fn empty() -> Extensions {
+        Extensions(0)
+    }
+
+    /// Returns the set of every extension that this crate knows about.
+    pub const fn all() -> Extensions {
+        Extensions(
+            Extensions::READDIRPLUS.0
+                | Extensions::CRC32C.0
+                | Extensions::PING.0
+                | Extensions::RESUME.0
+                | Extensions::FLOCK.0
+                | Extensions::COPYRANGE.0
+                | Extensions::CLONERANGE.0
+                | Extensions::LSEEK.0
+                | Extensions::FALLOCATE.0
+                | Extensions::FADVISE.0
+                | Extensions::IOCTL.0
+                | Extensions::NOTIFY.0
+                | Extensions::LEASE.0
+                | Extensions::RENAMEAT2.0,
+        )
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns true if every extension in `other` is also in `self`.
+    pub const fn contains(self, other: Extensions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Extensions) -> Extensions {
+        Extensions(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Extensions) -> Extensions {
+        Extensions(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Extensions) -> Extensions {
+        Extensions(self.0 & !other.0)
+    }
+
+    /// Returns the extension called `name` in version strings, if there is one.
+    pub fn from_name(name: &[u8]) -> Option<Extensions> {
+        Extensions::NAMES
+            .iter()
+            .find(|(_, n)| n.as_bytes() == name)
+            .map(|&(ext, _)| ext)
+    }
+
+    /// Returns the names of the extensions in the set.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Extensions::NAMES
+            .iter()
+            .filter(move |&&(ext, _)| self.contains(ext))
+            .map(|&(_, name)| name)
+    }
+
+    /// Parses a version string such as "9P2000.L+readdirplus" into the dialect it names and the
+    /// extensions it asks for. Unknown extensions are ignored, and extensions are only
+    /// recognized for 9P2000.L.
+    pub fn parse_version(version: &[u8]) -> (Option<Dialect>, Extensions) {
+        let mut parts = version.split(|&b| b == b'+');
+        let dialect = Dialect::from_version(parts.next().unwrap_or_default());
+
+        let mut extensions = Extensions::empty();
+        if dialect == Some(Dialect::Linux) {
+            for name in parts {
+                if let Some(ext) = Extensions::from_name(name) {
+                    extensions = extensions.union(ext);
+                }
+            }
+        }
+
+        (dialect, extensions)
+    }
+
+    /// Returns the version string that asks for, or agrees to, `self` on top of `dialect`.
+    pub fn version(self, dialect: Dialect) -> String {
+        let mut version = String::from(dialect.version());
+        if dialect == Dialect::Linux {
+            for name in self.names() {
+                version.push('+');
+                version.push_str(name);
+            }
+        }
+        version
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(
+            Extensions::parse_version(b"9P2000.L"),
+            (Some(Dialect::Linux), Extensions::empty())
+        );
+        assert_eq!(
+            Extensions::parse_version(b"9P2000.L+ping+bogus+readdirplus+ping"),
+            (
+                Some(Dialect::Linux),
+                Extensions::PING.union(Extensions::READDIRPLUS)
+            )
+        );
+        assert_eq!(
+            Extensions::parse_version(b"9P2000.u+ping"),
+            (Some(Dialect::Unix), Extensions::empty())
+        );
+        assert_eq!(
+            Extensions::parse_version(b"bogus+ping"),
+            (None, Extensions::empty())
+        );
+    }
+
+    #[test]
+    fn version() {
+        let extensions = Extensions::RESUME.union(Extensions::CRC32C);
+        assert_eq!(extensions.version(Dialect::Linux), "9P2000.L+crc32c+resume");
+        assert_eq!(extensions.version(Dialect::Unix), "9P2000.u");
+        assert_eq!(
+            Extensions::parse_version(Extensions::all().version(Dialect::Linux).as_bytes()),
+            (Some(Dialect::Linux), Extensions::all())
+        );
+        assert_eq!(format!("{:?}", extensions), "{\"crc32c\", \"resume\"}");
+    }
+}
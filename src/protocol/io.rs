@@ -0,0 +1,303 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The I/O traits used to encode and decode messages.
+//!
+//! With the `std` feature these are the ones from `std::io`. Without it, this module provides
+//! minimal replacements that only need `core` and `alloc` so that the protocol can be used in
+//! `no_std` environments.
+
+#[cfg(feature = "std")]
+pub use std::io::*;
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::cmp::min;
+    use core::error;
+    use core::fmt;
+    use core::mem;
+
+    /// The reasons for which encoding or decoding a message can fail. These are the subset of
+    /// `std::io::ErrorKind` that the protocol uses.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        InvalidInput,
+        InvalidData,
+        UnexpectedEof,
+        WriteZero,
+        AlreadyExists,
+        Unsupported,
+        Other,
+    }
+
+    impl ErrorKind {
+        fn as_str(&self) -> &'static str {
+            match self {
+                ErrorKind::InvalidInput => "invalid input parameter",
+                ErrorKind::InvalidData => "invalid data",
+                ErrorKind::UnexpectedEof => "unexpected end of file",
+                ErrorKind::WriteZero => "write zero",
+                ErrorKind::AlreadyExists => "entity already exists",
+                ErrorKind::Unsupported => "unsupported",
+                ErrorKind::Other => "other error",
+            }
+        }
+    }
+
+    /// An error along with the reason for it, like `std::io::Error`.
+    pub struct Error {
+        kind: ErrorKind,
+        error: Option<Box<dyn error::Error + Send + Sync>>,
+    }
+
+    impl Error {
+        pub fn new<E>(kind: ErrorKind, error: E) -> Error
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+        {
+            Error {
+                kind,
+                error: Some(error.into()),
+            }
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Error {
+            Error { kind, error: None }
+        }
+    }
+
+    impl fmt::Debug for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("Error")
+                .field("kind", &self.kind)
+                .field("error", &self.error)
+                .finish()
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.error {
+                Some(ref error) => error.fmt(f),
+                None => f.write_str(self.kind.as_str()),
+            }
+        }
+    }
+
+    impl error::Error for Error {}
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// A source of bytes, like `std::io::Read`.
+    pub trait Read {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf)? {
+                    // The same message as std, so that errors read the same with either.
+                    0 => {
+                        return Err(Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "failed to fill whole buffer",
+                        ))
+                    }
+                    n => buf = &mut buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let start = buf.len();
+            let mut chunk = [0u8; 512];
+            loop {
+                match self.read(&mut chunk)? {
+                    0 => return Ok(buf.len() - start),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+
+        fn take(self, limit: u64) -> Take<Self>
+        where
+            Self: Sized,
+        {
+            Take { inner: self, limit }
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = min(buf.len(), self.len());
+            let (a, b) = self.split_at(n);
+            buf[..n].copy_from_slice(a);
+            *self = b;
+            Ok(n)
+        }
+    }
+
+    /// A reader that returns at most `limit` bytes from another reader, like `std::io::Take`.
+    pub struct Take<R> {
+        inner: R,
+        limit: u64,
+    }
+
+    impl<R> Take<R> {
+        /// Returns the number of bytes that can still be read.
+        pub fn limit(&self) -> u64 {
+            self.limit
+        }
+    }
+
+    impl<R: Read> Read for Take<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = min(buf.len() as u64, self.limit) as usize;
+            let n = self.inner.read(&mut buf[..len])?;
+            self.limit -= n as u64;
+            Ok(n)
+        }
+    }
+
+    /// A sink for bytes, like `std::io::Write`.
+    pub trait Write {
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    impl Write for &mut [u8] {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let n = min(buf.len(), self.len());
+            let (a, b) = mem::take(self).split_at_mut(n);
+            a.copy_from_slice(&buf[..n]);
+            *self = b;
+            Ok(n)
+        }
+    }
+
+    /// A buffer in memory that is read or written from a position that moves along with it, like
+    /// `std::io::Cursor`.
+    #[derive(Debug, Default, Clone)]
+    pub struct Cursor<T> {
+        inner: T,
+        pos: u64,
+    }
+
+    impl<T> Cursor<T> {
+        pub fn new(inner: T) -> Cursor<T> {
+            Cursor { inner, pos: 0 }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+
+        pub fn get_ref(&self) -> &T {
+            &self.inner
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            &mut self.inner
+        }
+
+        pub fn position(&self) -> u64 {
+            self.pos
+        }
+
+        pub fn set_position(&mut self, pos: u64) {
+            self.pos = pos;
+        }
+    }
+
+    impl<T: AsRef<[u8]>> Read for Cursor<T> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let inner = self.inner.as_ref();
+            let start = min(self.pos, inner.len() as u64) as usize;
+            let n = (&inner[start..]).read(buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Write for Cursor<&mut [u8]> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let start = min(self.pos, self.inner.len() as u64) as usize;
+            let n = (&mut self.inner[start..]).write(buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    // Writes past the end grow the vector, filling any gap with zeroes.
+    fn write_vec(vec: &mut Vec<u8>, pos: &mut u64, buf: &[u8]) -> Result<usize> {
+        let start = usize::try_from(*pos).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+        let end = start + buf.len();
+        if vec.len() < end {
+            vec.resize(end, 0);
+        }
+        vec[start..end].copy_from_slice(buf);
+        *pos = end as u64;
+        Ok(buf.len())
+    }
+
+    impl Write for Cursor<Vec<u8>> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            write_vec(&mut self.inner, &mut self.pos, buf)
+        }
+    }
+
+    impl Write for Cursor<&mut Vec<u8>> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            write_vec(self.inner, &mut self.pos, buf)
+        }
+    }
+}
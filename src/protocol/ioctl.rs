@@ -0,0 +1,160 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The ioctls that clients can forward to the server with Tioctl.
+//!
+//! Only the ioctls listed here can ever be forwarded, and servers only forward the ones that they
+//! were configured to allow. Each is identified on the wire by the request number that it has on
+//! x86-64 Linux, whatever the architectures of the client and the server, and its argument has a
+//! fixed size. The data of a Tioctl is the argument that is passed in, and the data of the Rioctl
+//! is the argument as the ioctl left it. Arguments are made up of 32-bit words, which are sent
+//! little-endian like everything else in 9P.
+
+use alloc::format;
+use core::fmt;
+
+use crate::protocol::io;
+use crate::protocol::io::ErrorKind;
+
+/// An ioctl that clients may forward with Tioctl.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Ioctl {
+    /// The name of the ioctl in the Linux headers.
+    pub name: &'static str,
+    /// The request number that identifies the ioctl on the wire.
+    pub request: u32,
+    /// The size of the argument that the client sends.
+    pub input: usize,
+    /// The size of the argument that the server sends back.
+    pub output: usize,
+}
+
+/// Returns the flags of an inode, as used by chattr(1).
+pub const FS_IOC_GETFLAGS: Ioctl = Ioctl {
+    name: "FS_IOC_GETFLAGS",
+    request: 0x8008_6601,
+    input: 0,
+    output: 4,
+};
+
+/// Returns the generation number of an inode.
+pub const FS_IOC_GETVERSION: Ioctl = Ioctl {
+    name: "FS_IOC_GETVERSION",
+    request: 0x8008_7601,
+    input: 0,
+    output: 4,
+};
+
+/// Returns the block size of the file system.
+pub const FIGETBSZ: Ioctl = Ioctl {
+    name: "FIGETBSZ",
+    request: 0x0000_0002,
+    input: 0,
+    output: 4,
+};
+
+/// Returns the extended attributes of an inode as a `struct fsxattr`, as used by xfs_io(8).
+pub const FS_IOC_FSGETXATTR: Ioctl = Ioctl {
+    name: "FS_IOC_FSGETXATTR",
+    request: 0x801c_581f,
+    input: 0,
+    output: 28,
+};
+
+/// Every ioctl that can be forwarded.
+pub const IOCTLS: [Ioctl; 4] = [
+    FS_IOC_GETFLAGS,
+    FS_IOC_GETVERSION,
+    FIGETBSZ,
+    FS_IOC_FSGETXATTR,
+];
+
+impl Ioctl {
+    /// Returns the ioctl with request number `request` on the wire, if it can be forwarded.
+    pub fn from_request(request: u32) -> Option<Ioctl> {
+        IOCTLS
+            .iter()
+            .find(|ioctl| ioctl.request == request)
+            .copied()
+    }
+
+    /// Returns the ioctl called `name`, if it can be forwarded.
+    pub fn from_name(name: &str) -> Option<Ioctl> {
+        IOCTLS.iter().find(|ioctl| ioctl.name == name).copied()
+    }
+
+    /// Checks that `data` has the size of the argument that a Tioctl for this ioctl sends.
+    pub fn check_input(&self, data: &[u8]) -> io::Result<()> {
+        check_size(self.input, data, "input")
+    }
+
+    /// Checks that `data` has the size of the argument that an Rioctl for this ioctl sends back.
+    pub fn check_output(&self, data: &[u8]) -> io::Result<()> {
+        check_size(self.output, data, "output")
+    }
+}
+
+/// Converts the 32-bit words of an ioctl argument from little-endian to the native byte order, or
+/// back. Any trailing bytes that don't fill a word are left alone.
+pub fn convert_words(data: &mut [u8]) {
+    for word in data.chunks_exact_mut(4) {
+        let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        word.copy_from_slice(&value.to_ne_bytes());
+    }
+}
+
+impl fmt::Debug for Ioctl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+fn check_size(expected: usize, data: &[u8], what: &'static str) -> io::Result<()> {
+    if data.len() == expected {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "ioctl {} is {} bytes rather than {}",
+                what,
+                data.len(),
+                expected
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        for ioctl in IOCTLS {
+            assert_eq!(Ioctl::from_request(ioctl.request), Some(ioctl));
+            assert_eq!(Ioctl::from_name(ioctl.name), Some(ioctl));
+        }
+        assert_eq!(Ioctl::from_request(0x4008_6602), None);
+        assert_eq!(Ioctl::from_name("FS_IOC_SETFLAGS"), None);
+    }
+
+    #[test]
+    fn sizes() {
+        FIGETBSZ.check_input(&[]).unwrap();
+        FIGETBSZ.check_output(&[0; 4]).unwrap();
+        let err = FIGETBSZ.check_input(&[0; 4]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = FS_IOC_FSGETXATTR.check_output(&[0; 4]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn words() {
+        let mut data = [1, 2, 3, 4, 5, 6, 7, 8];
+        convert_words(&mut data);
+        assert_eq!(data[..4], 0x0403_0201u32.to_ne_bytes());
+        assert_eq!(data[4..], 0x0807_0605u32.to_ne_bytes());
+    }
+}
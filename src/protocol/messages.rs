@@ -2,16 +2,23 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::io;
-use std::io::ErrorKind;
-use std::io::Read;
-use std::io::Write;
-use std::mem;
-use std::vec::Vec;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
 
 use p9_wire_format_derive::P9WireFormat;
-
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::protocol::io;
+use crate::protocol::io::ErrorKind;
+use crate::protocol::io::Read;
+use crate::protocol::io::Write;
 use crate::protocol::wire_format::Data;
+use crate::protocol::wire_format::DataRef;
 use crate::protocol::wire_format::P9String;
 use crate::protocol::wire_format::WireFormat;
 
@@ -65,15 +72,15 @@ const RAUTH: u8 = TAUTH + 1;
 const TATTACH: u8 = 104;
 const RATTACH: u8 = TATTACH + 1;
 const _TERROR: u8 = 106;
-const _RERROR: u8 = _TERROR + 1;
+const RERROR: u8 = _TERROR + 1;
 const TFLUSH: u8 = 108;
 const RFLUSH: u8 = TFLUSH + 1;
 const TWALK: u8 = 110;
 const RWALK: u8 = TWALK + 1;
-const _TOPEN: u8 = 112;
-const _ROPEN: u8 = _TOPEN + 1;
-const _TCREATE: u8 = 114;
-const _RCREATE: u8 = _TCREATE + 1;
+const TOPEN: u8 = 112;
+const ROPEN: u8 = TOPEN + 1;
+const TCREATE: u8 = 114;
+const RCREATE: u8 = TCREATE + 1;
 const TREAD: u8 = 116;
 const RREAD: u8 = TREAD + 1;
 const TWRITE: u8 = 118;
@@ -82,13 +89,244 @@ const TCLUNK: u8 = 120;
 const RCLUNK: u8 = TCLUNK + 1;
 const TREMOVE: u8 = 122;
 const RREMOVE: u8 = TREMOVE + 1;
-const _TSTAT: u8 = 124;
-const _RSTAT: u8 = _TSTAT + 1;
-const _TWSTAT: u8 = 126;
-const _RWSTAT: u8 = _TWSTAT + 1;
+const TSTAT: u8 = 124;
+const RSTAT: u8 = TSTAT + 1;
+const TWSTAT: u8 = 126;
+const RWSTAT: u8 = TWSTAT + 1;
+
+// Extensions to 9P2000.L that the client must ask for in its Tversion.
+const TREADDIRPLUS: u8 = 42;
+const RREADDIRPLUS: u8 = TREADDIRPLUS + 1;
+const TPING: u8 = 44;
+const RPING: u8 = TPING + 1;
+const TRESUME: u8 = 46;
+const RRESUME: u8 = TRESUME + 1;
+const TCOPYRANGE: u8 = 48;
+const RCOPYRANGE: u8 = TCOPYRANGE + 1;
+const TCLONERANGE: u8 = 56;
+const RCLONERANGE: u8 = TCLONERANGE + 1;
+const TLSEEK: u8 = 58;
+const RLSEEK: u8 = TLSEEK + 1;
+const TFALLOCATE: u8 = 60;
+const RFALLOCATE: u8 = TFALLOCATE + 1;
+const TFADVISE: u8 = 62;
+const RFADVISE: u8 = TFADVISE + 1;
+const TIOCTL: u8 = 64;
+const RIOCTL: u8 = TIOCTL + 1;
+const TWATCH: u8 = 66;
+const RWATCH: u8 = TWATCH + 1;
+const TLEASE: u8 = 68;
+const RLEASE: u8 = TLEASE + 1;
+const TLEASEBREAKS: u8 = 78;
+const RLEASEBREAKS: u8 = TLEASEBREAKS + 1;
+const TRENAMEAT2: u8 = 80;
+const RRENAMEAT2: u8 = TRENAMEAT2 + 1;
+
+/// Returns the name of the message with type `ty`, e.g. "Twalk", or `None` if it isn't part of any
+/// of the supported dialects.
+pub fn message_name(ty: u8) -> Option<&'static str> {
+    let name = match ty {
+        TLERROR => "Tlerror",
+        RLERROR => "Rlerror",
+        TSTATFS => "Tstatfs",
+        RSTATFS => "Rstatfs",
+        TLOPEN => "Tlopen",
+        RLOPEN => "Rlopen",
+        TLCREATE => "Tlcreate",
+        RLCREATE => "Rlcreate",
+        TSYMLINK => "Tsymlink",
+        RSYMLINK => "Rsymlink",
+        TMKNOD => "Tmknod",
+        RMKNOD => "Rmknod",
+        TRENAME => "Trename",
+        RRENAME => "Rrename",
+        TREADLINK => "Treadlink",
+        RREADLINK => "Rreadlink",
+        TGETATTR => "Tgetattr",
+        RGETATTR => "Rgetattr",
+        TSETATTR => "Tsetattr",
+        RSETATTR => "Rsetattr",
+        TXATTRWALK => "Txattrwalk",
+        RXATTRWALK => "Rxattrwalk",
+        TXATTRCREATE => "Txattrcreate",
+        RXATTRCREATE => "Rxattrcreate",
+        TREADDIR => "Treaddir",
+        RREADDIR => "Rreaddir",
+        TFSYNC => "Tfsync",
+        RFSYNC => "Rfsync",
+        TLOCK => "Tlock",
+        RLOCK => "Rlock",
+        TGETLOCK => "Tgetlock",
+        RGETLOCK => "Rgetlock",
+        TLINK => "Tlink",
+        RLINK => "Rlink",
+        TMKDIR => "Tmkdir",
+        RMKDIR => "Rmkdir",
+        TRENAMEAT => "Trenameat",
+        RRENAMEAT => "Rrenameat",
+        TUNLINKAT => "Tunlinkat",
+        RUNLINKAT => "Runlinkat",
+        TVERSION => "Tversion",
+        RVERSION => "Rversion",
+        TAUTH => "Tauth",
+        RAUTH => "Rauth",
+        TATTACH => "Tattach",
+        RATTACH => "Rattach",
+        _TERROR => "Terror",
+        RERROR => "Rerror",
+        TFLUSH => "Tflush",
+        RFLUSH => "Rflush",
+        TWALK => "Twalk",
+        RWALK => "Rwalk",
+        TOPEN => "Topen",
+        ROPEN => "Ropen",
+        TCREATE => "Tcreate",
+        RCREATE => "Rcreate",
+        TREAD => "Tread",
+        RREAD => "Rread",
+        TWRITE => "Twrite",
+        RWRITE => "Rwrite",
+        TCLUNK => "Tclunk",
+        RCLUNK => "Rclunk",
+        TREMOVE => "Tremove",
+        RREMOVE => "Rremove",
+        TSTAT => "Tstat",
+        RSTAT => "Rstat",
+        TWSTAT => "Twstat",
+        RWSTAT => "Rwstat",
+        TREADDIRPLUS => "Treaddirplus",
+        RREADDIRPLUS => "Rreaddirplus",
+        TPING => "Tping",
+        RPING => "Rping",
+        TRESUME => "Tresume",
+        RRESUME => "Rresume",
+        TCOPYRANGE => "Tcopyrange",
+        RCOPYRANGE => "Rcopyrange",
+        TCLONERANGE => "Tclonerange",
+        RCLONERANGE => "Rclonerange",
+        TLSEEK => "Tlseek",
+        RLSEEK => "Rlseek",
+        TFALLOCATE => "Tfallocate",
+        RFALLOCATE => "Rfallocate",
+        TFADVISE => "Tfadvise",
+        RFADVISE => "Rfadvise",
+        TIOCTL => "Tioctl",
+        RIOCTL => "Rioctl",
+        TWATCH => "Twatch",
+        RWATCH => "Rwatch",
+        TLEASE => "Tlease",
+        RLEASE => "Rlease",
+        TLEASEBREAKS => "Tleasebreaks",
+        RLEASEBREAKS => "Rleasebreaks",
+        TRENAMEAT2 => "Trenameat2",
+        RRENAMEAT2 => "Rrenameat2",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Returns true if frames of type `ty` are followed by a checksum once one has been negotiated.
+/// Tversion and Rversion never are, so that a connection can always be renegotiated.
+pub fn is_checksummed(ty: u8) -> bool {
+    ty != TVERSION && ty != RVERSION
+}
+
+/// Returns true if `ty` is a T-message type that isn't used by any of the supported dialects, so
+/// that it and the matching R-message type `ty + 1` are free for vendor extensions.
+pub fn is_vendor_type(ty: u8) -> bool {
+    ty.is_multiple_of(2)
+        && ty != u8::MAX
+        && !matches!(
+            ty,
+            TLERROR
+                | TSTATFS
+                | TLOPEN
+                | TLCREATE
+                | TSYMLINK
+                | TMKNOD
+                | TRENAME
+                | TREADLINK
+                | TGETATTR
+                | TSETATTR
+                | TXATTRWALK
+                | TXATTRCREATE
+                | TREADDIR
+                | TREADDIRPLUS
+                | TPING
+                | TRESUME
+                | TCOPYRANGE
+                | TCLONERANGE
+                | TLSEEK
+                | TFALLOCATE
+                | TFADVISE
+                | TIOCTL
+                | TWATCH
+                | TLEASE
+                | TLEASEBREAKS
+                | TRENAMEAT2
+                | TFSYNC
+                | TLOCK
+                | TGETLOCK
+                | TLINK
+                | TMKDIR
+                | TRENAMEAT
+                | TUNLINKAT
+                | TVERSION
+                | TAUTH
+                | TATTACH
+                | _TERROR
+                | TFLUSH
+                | TWALK
+                | TOPEN
+                | TCREATE
+                | TREAD
+                | TWRITE
+                | TCLUNK
+                | TREMOVE
+                | TSTAT
+                | TWSTAT
+        )
+}
+
+/// A dialect of the 9P protocol, as negotiated by the Tversion message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Dialect {
+    /// The original 9P2000 protocol, as spoken by Plan 9 and most other 9P implementations.
+    Legacy,
+    /// 9P2000.u, which extends 9P2000 with numeric ids, symlinks, and device files.
+    Unix,
+    /// 9P2000.L, which replaces most of the 9P2000 message set with Linux-specific messages.
+    Linux,
+}
+
+impl Dialect {
+    /// Returns the dialect that corresponds to the version string `version`, if any.  Unknown
+    /// extensions of 9P2000 (e.g. "9P2000.x") fall back to plain 9P2000, as the protocol
+    /// requires.
+    pub fn from_version(version: &[u8]) -> Option<Dialect> {
+        match version {
+            b"9P2000" => Some(Dialect::Legacy),
+            b"9P2000.u" => Some(Dialect::Unix),
+            b"9P2000.L" => Some(Dialect::Linux),
+            v if v.starts_with(b"9P2000.") => Some(Dialect::Legacy),
+            _ => None,
+        }
+    }
+
+    /// Returns the version string that identifies this dialect on the wire.
+    pub fn version(&self) -> &'static str {
+        match self {
+            Dialect::Legacy => "9P2000",
+            Dialect::Unix => "9P2000.u",
+            Dialect::Linux => "9P2000.L",
+        }
+    }
+}
 
 /// A message sent from a 9P client to a 9P server.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Tmessage {
     Version(Tversion),
     Flush(Tflush),
@@ -99,6 +337,10 @@ pub enum Tmessage {
     Remove(Tremove),
     Attach(Tattach),
     Auth(Tauth),
+    Open(Topen),
+    Create(Tcreate),
+    Stat(Tstat),
+    Wstat(Twstat),
     Statfs(Tstatfs),
     Lopen(Tlopen),
     Lcreate(Tlcreate),
@@ -111,6 +353,19 @@ pub enum Tmessage {
     XattrWalk(Txattrwalk),
     XattrCreate(Txattrcreate),
     Readdir(Treaddir),
+    ReaddirPlus(Treaddirplus),
+    Ping,
+    Resume(Tresume),
+    CopyRange(Tcopyrange),
+    CloneRange(Tclonerange),
+    Lseek(Tlseek),
+    Fallocate(Tfallocate),
+    Fadvise(Tfadvise),
+    Ioctl(Tioctl),
+    Watch(Twatch),
+    Lease(Tlease),
+    LeaseBreaks,
+    RenameAt2(Trenameat2),
     Fsync(Tfsync),
     Lock(Tlock),
     GetLock(Tgetlock),
@@ -118,14 +373,44 @@ pub enum Tmessage {
     Mkdir(Tmkdir),
     RenameAt(Trenameat),
     UnlinkAt(Tunlinkat),
+    Vendor(Tvendor),
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tframe {
     pub tag: u16,
+    #[cfg_attr(feature = "serde", serde(with = "serde_tmessage"))]
     pub msg: io::Result<Tmessage>,
 }
 
+// Serializes the message of a `Tframe`. Frames that failed to decode have no message to
+// serialize, so they produce an error instead.
+#[cfg(feature = "serde")]
+mod serde_tmessage {
+    use alloc::format;
+
+    use serde::de::Deserializer;
+    use serde::ser::Error;
+    use serde::ser::Serializer;
+    use serde::Deserialize;
+    use serde::Serialize;
+
+    use super::Tmessage;
+    use crate::protocol::io;
+
+    pub fn serialize<S: Serializer>(msg: &io::Result<Tmessage>, s: S) -> Result<S::Ok, S::Error> {
+        match msg {
+            Ok(msg) => msg.serialize(s),
+            Err(e) => Err(S::Error::custom(format!("Tframe has no message: {}", e))),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<io::Result<Tmessage>, D::Error> {
+        Tmessage::deserialize(d).map(Ok)
+    }
+}
+
 impl WireFormat for Tframe {
     fn byte_size(&self) -> u32 {
         let msg = self
@@ -142,6 +427,10 @@ impl WireFormat for Tframe {
             Tmessage::Remove(ref remove) => remove.byte_size(),
             Tmessage::Attach(ref attach) => attach.byte_size(),
             Tmessage::Auth(ref auth) => auth.byte_size(),
+            Tmessage::Open(ref open) => open.byte_size(),
+            Tmessage::Create(ref create) => create.byte_size(),
+            Tmessage::Stat(ref stat) => stat.byte_size(),
+            Tmessage::Wstat(ref wstat) => wstat.byte_size(),
             Tmessage::Statfs(ref statfs) => statfs.byte_size(),
             Tmessage::Lopen(ref lopen) => lopen.byte_size(),
             Tmessage::Lcreate(ref lcreate) => lcreate.byte_size(),
@@ -154,6 +443,19 @@ impl WireFormat for Tframe {
             Tmessage::XattrWalk(ref xattrwalk) => xattrwalk.byte_size(),
             Tmessage::XattrCreate(ref xattrcreate) => xattrcreate.byte_size(),
             Tmessage::Readdir(ref readdir) => readdir.byte_size(),
+            Tmessage::ReaddirPlus(ref readdirplus) => readdirplus.byte_size(),
+            Tmessage::Ping => 0,
+            Tmessage::Resume(ref resume) => resume.byte_size(),
+            Tmessage::CopyRange(ref copyrange) => copyrange.byte_size(),
+            Tmessage::CloneRange(ref clonerange) => clonerange.byte_size(),
+            Tmessage::Lseek(ref lseek) => lseek.byte_size(),
+            Tmessage::Fallocate(ref fallocate) => fallocate.byte_size(),
+            Tmessage::Fadvise(ref fadvise) => fadvise.byte_size(),
+            Tmessage::Ioctl(ref ioctl) => ioctl.byte_size(),
+            Tmessage::Watch(ref watch) => watch.byte_size(),
+            Tmessage::Lease(ref lease) => lease.byte_size(),
+            Tmessage::LeaseBreaks => 0,
+            Tmessage::RenameAt2(ref renameat2) => renameat2.byte_size(),
             Tmessage::Fsync(ref fsync) => fsync.byte_size(),
             Tmessage::Lock(ref lock) => lock.byte_size(),
             Tmessage::GetLock(ref getlock) => getlock.byte_size(),
@@ -161,6 +463,7 @@ impl WireFormat for Tframe {
             Tmessage::Mkdir(ref mkdir) => mkdir.byte_size(),
             Tmessage::RenameAt(ref renameat) => renameat.byte_size(),
             Tmessage::UnlinkAt(ref unlinkat) => unlinkat.byte_size(),
+            Tmessage::Vendor(ref vendor) => vendor.body.byte_size(),
         };
 
         // size + type + tag + message size
@@ -190,6 +493,10 @@ impl WireFormat for Tframe {
             Tmessage::Remove(_) => TREMOVE,
             Tmessage::Attach(_) => TATTACH,
             Tmessage::Auth(_) => TAUTH,
+            Tmessage::Open(_) => TOPEN,
+            Tmessage::Create(_) => TCREATE,
+            Tmessage::Stat(_) => TSTAT,
+            Tmessage::Wstat(_) => TWSTAT,
             Tmessage::Statfs(_) => TSTATFS,
             Tmessage::Lopen(_) => TLOPEN,
             Tmessage::Lcreate(_) => TLCREATE,
@@ -202,6 +509,19 @@ impl WireFormat for Tframe {
             Tmessage::XattrWalk(_) => TXATTRWALK,
             Tmessage::XattrCreate(_) => TXATTRCREATE,
             Tmessage::Readdir(_) => TREADDIR,
+            Tmessage::ReaddirPlus(_) => TREADDIRPLUS,
+            Tmessage::Ping => TPING,
+            Tmessage::Resume(_) => TRESUME,
+            Tmessage::CopyRange(_) => TCOPYRANGE,
+            Tmessage::CloneRange(_) => TCLONERANGE,
+            Tmessage::Lseek(_) => TLSEEK,
+            Tmessage::Fallocate(_) => TFALLOCATE,
+            Tmessage::Fadvise(_) => TFADVISE,
+            Tmessage::Ioctl(_) => TIOCTL,
+            Tmessage::Watch(_) => TWATCH,
+            Tmessage::Lease(_) => TLEASE,
+            Tmessage::LeaseBreaks => TLEASEBREAKS,
+            Tmessage::RenameAt2(_) => TRENAMEAT2,
             Tmessage::Fsync(_) => TFSYNC,
             Tmessage::Lock(_) => TLOCK,
             Tmessage::GetLock(_) => TGETLOCK,
@@ -209,6 +529,7 @@ impl WireFormat for Tframe {
             Tmessage::Mkdir(_) => TMKDIR,
             Tmessage::RenameAt(_) => TRENAMEAT,
             Tmessage::UnlinkAt(_) => TUNLINKAT,
+            Tmessage::Vendor(ref vendor) => vendor.ty,
         };
 
         ty.encode(writer)?;
@@ -224,6 +545,10 @@ impl WireFormat for Tframe {
             Tmessage::Remove(ref remove) => remove.encode(writer),
             Tmessage::Attach(ref attach) => attach.encode(writer),
             Tmessage::Auth(ref auth) => auth.encode(writer),
+            Tmessage::Open(ref open) => open.encode(writer),
+            Tmessage::Create(ref create) => create.encode(writer),
+            Tmessage::Stat(ref stat) => stat.encode(writer),
+            Tmessage::Wstat(ref wstat) => wstat.encode(writer),
             Tmessage::Statfs(ref statfs) => statfs.encode(writer),
             Tmessage::Lopen(ref lopen) => lopen.encode(writer),
             Tmessage::Lcreate(ref lcreate) => lcreate.encode(writer),
@@ -236,6 +561,19 @@ impl WireFormat for Tframe {
             Tmessage::XattrWalk(ref xattrwalk) => xattrwalk.encode(writer),
             Tmessage::XattrCreate(ref xattrcreate) => xattrcreate.encode(writer),
             Tmessage::Readdir(ref readdir) => readdir.encode(writer),
+            Tmessage::ReaddirPlus(ref readdirplus) => readdirplus.encode(writer),
+            Tmessage::Ping => Ok(()),
+            Tmessage::Resume(ref resume) => resume.encode(writer),
+            Tmessage::CopyRange(ref copyrange) => copyrange.encode(writer),
+            Tmessage::CloneRange(ref clonerange) => clonerange.encode(writer),
+            Tmessage::Lseek(ref lseek) => lseek.encode(writer),
+            Tmessage::Fallocate(ref fallocate) => fallocate.encode(writer),
+            Tmessage::Fadvise(ref fadvise) => fadvise.encode(writer),
+            Tmessage::Ioctl(ref ioctl) => ioctl.encode(writer),
+            Tmessage::Watch(ref watch) => watch.encode(writer),
+            Tmessage::Lease(ref lease) => lease.encode(writer),
+            Tmessage::LeaseBreaks => Ok(()),
+            Tmessage::RenameAt2(ref renameat2) => renameat2.encode(writer),
             Tmessage::Fsync(ref fsync) => fsync.encode(writer),
             Tmessage::Lock(ref lock) => lock.encode(writer),
             Tmessage::GetLock(ref getlock) => getlock.encode(writer),
@@ -243,10 +581,19 @@ impl WireFormat for Tframe {
             Tmessage::Mkdir(ref mkdir) => mkdir.encode(writer),
             Tmessage::RenameAt(ref renameat) => renameat.encode(writer),
             Tmessage::UnlinkAt(ref unlinkat) => unlinkat.encode(writer),
+            Tmessage::Vendor(ref vendor) => vendor.body.encode(writer),
         }
     }
 
     fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Self::decode_dialect(reader, Dialect::Linux)
+    }
+}
+
+impl Tframe {
+    /// Decodes a frame sent by a client that has negotiated `dialect`.  Plain 9P2000 omits the
+    /// trailing fields that 9P2000.u added to Tauth, Tattach, and Tcreate.
+    pub fn decode_dialect<R: Read>(reader: &mut R, dialect: Dialect) -> io::Result<Self> {
         let byte_size: u32 = WireFormat::decode(reader)?;
 
         // byte_size includes the size of byte_size so remove that from the
@@ -265,15 +612,81 @@ impl WireFormat for Tframe {
         reader.read_exact(&mut ty)?;
 
         let tag: u16 = WireFormat::decode(reader)?;
-        let msg = Self::decode_message(reader, ty[0]);
+        let msg = Self::decode_message(reader, ty[0], dialect);
 
         Ok(Tframe { tag, msg })
     }
-}
 
-impl Tframe {
-    fn decode_message<R: Read>(reader: &mut R, ty: u8) -> io::Result<Tmessage> {
+    /// Encodes `self` for a server that has negotiated `dialect`.  Plain 9P2000 omits the
+    /// trailing fields that 9P2000.u added to Tauth, Tattach, and Tcreate, and the 9P2000.u fields
+    /// from the stat of a Twstat.
+    pub fn encode_dialect<W: Write>(&self, writer: &mut W, dialect: Dialect) -> io::Result<()> {
+        if dialect != Dialect::Legacy {
+            return self.encode(writer);
+        }
+
+        let mut body = Vec::new();
+        let ty = match self.msg {
+            Ok(Tmessage::Attach(ref attach)) => {
+                attach.fid.encode(&mut body)?;
+                attach.afid.encode(&mut body)?;
+                attach.uname.encode(&mut body)?;
+                attach.aname.encode(&mut body)?;
+                TATTACH
+            }
+            Ok(Tmessage::Auth(ref auth)) => {
+                auth.afid.encode(&mut body)?;
+                auth.uname.encode(&mut body)?;
+                auth.aname.encode(&mut body)?;
+                TAUTH
+            }
+            Ok(Tmessage::Create(ref create)) => {
+                create.fid.encode(&mut body)?;
+                create.name.encode(&mut body)?;
+                create.perm.encode(&mut body)?;
+                create.mode.encode(&mut body)?;
+                TCREATE
+            }
+            Ok(Tmessage::Wstat(ref wstat)) => {
+                wstat.fid.encode(&mut body)?;
+                (wstat.stat.byte_size_dialect(dialect) as u16).encode(&mut body)?;
+                wstat.stat.encode_dialect(&mut body, dialect)?;
+                TWSTAT
+            }
+            _ => return self.encode(writer),
+        };
+
+        // size + type + tag + message
+        let size =
+            mem::size_of::<u32>() + mem::size_of::<u8>() + mem::size_of::<u16>() + body.len();
+        (size as u32).encode(writer)?;
+        ty.encode(writer)?;
+        self.tag.encode(writer)?;
+        writer.write_all(&body)
+    }
+
+    fn decode_message<R: Read>(reader: &mut R, ty: u8, dialect: Dialect) -> io::Result<Tmessage> {
         match ty {
+            TATTACH if dialect == Dialect::Legacy => Ok(Tmessage::Attach(Tattach {
+                fid: WireFormat::decode(reader)?,
+                afid: WireFormat::decode(reader)?,
+                uname: WireFormat::decode(reader)?,
+                aname: WireFormat::decode(reader)?,
+                n_uname: u32::MAX,
+            })),
+            TAUTH if dialect == Dialect::Legacy => Ok(Tmessage::Auth(Tauth {
+                afid: WireFormat::decode(reader)?,
+                uname: WireFormat::decode(reader)?,
+                aname: WireFormat::decode(reader)?,
+                n_uname: u32::MAX,
+            })),
+            TCREATE if dialect == Dialect::Legacy => Ok(Tmessage::Create(Tcreate {
+                fid: WireFormat::decode(reader)?,
+                name: WireFormat::decode(reader)?,
+                perm: WireFormat::decode(reader)?,
+                mode: WireFormat::decode(reader)?,
+                extension: Default::default(),
+            })),
             TVERSION => Ok(Tmessage::Version(WireFormat::decode(reader)?)),
             TFLUSH => Ok(Tmessage::Flush(WireFormat::decode(reader)?)),
             TWALK => Ok(Tmessage::Walk(WireFormat::decode(reader)?)),
@@ -283,6 +696,10 @@ impl Tframe {
             TREMOVE => Ok(Tmessage::Remove(WireFormat::decode(reader)?)),
             TATTACH => Ok(Tmessage::Attach(WireFormat::decode(reader)?)),
             TAUTH => Ok(Tmessage::Auth(WireFormat::decode(reader)?)),
+            TOPEN => Ok(Tmessage::Open(WireFormat::decode(reader)?)),
+            TCREATE => Ok(Tmessage::Create(WireFormat::decode(reader)?)),
+            TSTAT => Ok(Tmessage::Stat(WireFormat::decode(reader)?)),
+            TWSTAT => Ok(Tmessage::Wstat(WireFormat::decode(reader)?)),
             TSTATFS => Ok(Tmessage::Statfs(WireFormat::decode(reader)?)),
             TLOPEN => Ok(Tmessage::Lopen(WireFormat::decode(reader)?)),
             TLCREATE => Ok(Tmessage::Lcreate(WireFormat::decode(reader)?)),
@@ -295,6 +712,19 @@ impl Tframe {
             TXATTRWALK => Ok(Tmessage::XattrWalk(WireFormat::decode(reader)?)),
             TXATTRCREATE => Ok(Tmessage::XattrCreate(WireFormat::decode(reader)?)),
             TREADDIR => Ok(Tmessage::Readdir(WireFormat::decode(reader)?)),
+            TREADDIRPLUS => Ok(Tmessage::ReaddirPlus(WireFormat::decode(reader)?)),
+            TPING => Ok(Tmessage::Ping),
+            TRESUME => Ok(Tmessage::Resume(WireFormat::decode(reader)?)),
+            TCOPYRANGE => Ok(Tmessage::CopyRange(WireFormat::decode(reader)?)),
+            TCLONERANGE => Ok(Tmessage::CloneRange(WireFormat::decode(reader)?)),
+            TLSEEK => Ok(Tmessage::Lseek(WireFormat::decode(reader)?)),
+            TFALLOCATE => Ok(Tmessage::Fallocate(WireFormat::decode(reader)?)),
+            TFADVISE => Ok(Tmessage::Fadvise(WireFormat::decode(reader)?)),
+            TIOCTL => Ok(Tmessage::Ioctl(WireFormat::decode(reader)?)),
+            TWATCH => Ok(Tmessage::Watch(WireFormat::decode(reader)?)),
+            TLEASE => Ok(Tmessage::Lease(WireFormat::decode(reader)?)),
+            TLEASEBREAKS => Ok(Tmessage::LeaseBreaks),
+            TRENAMEAT2 => Ok(Tmessage::RenameAt2(WireFormat::decode(reader)?)),
             TFSYNC => Ok(Tmessage::Fsync(WireFormat::decode(reader)?)),
             TLOCK => Ok(Tmessage::Lock(WireFormat::decode(reader)?)),
             TGETLOCK => Ok(Tmessage::GetLock(WireFormat::decode(reader)?)),
@@ -302,6 +732,10 @@ impl Tframe {
             TMKDIR => Ok(Tmessage::Mkdir(WireFormat::decode(reader)?)),
             TRENAMEAT => Ok(Tmessage::RenameAt(WireFormat::decode(reader)?)),
             TUNLINKAT => Ok(Tmessage::UnlinkAt(WireFormat::decode(reader)?)),
+            ty if is_vendor_type(ty) => Ok(Tmessage::Vendor(Tvendor {
+                ty,
+                body: WireFormat::decode(reader)?,
+            })),
             err => Err(io::Error::new(
                 ErrorKind::InvalidData,
                 format!("unknown message type {}", err),
@@ -311,24 +745,61 @@ impl Tframe {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tversion {
     pub msize: u32,
     pub version: P9String,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tflush {
     pub oldtag: u16,
 }
 
+/// The maximum number of path elements in a single Twalk.
+pub const MAXWELEM: usize = 16;
+
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Twalk {
     pub fid: u32,
     pub newfid: u32,
     pub wnames: Vec<P9String>,
 }
 
+impl Twalk {
+    /// Splits a walk from `fid` to `newfid` through any number of `wnames` into a chain of walks
+    /// with at most `MAXWELEM` names each. The first walk clones `fid` into `newfid` and the rest
+    /// continue from `newfid`, so they must be sent in order and the chain stops at the first walk
+    /// that doesn't return a qid for every name.
+    pub fn split(fid: u32, newfid: u32, wnames: Vec<P9String>) -> Vec<Twalk> {
+        if wnames.is_empty() {
+            return vec![Twalk {
+                fid,
+                newfid,
+                wnames,
+            }];
+        }
+
+        let mut walks = Vec::with_capacity(wnames.len().div_ceil(MAXWELEM));
+        let mut wnames = wnames.into_iter().peekable();
+        let mut from = fid;
+        while wnames.peek().is_some() {
+            walks.push(Twalk {
+                fid: from,
+                newfid,
+                wnames: wnames.by_ref().take(MAXWELEM).collect(),
+            });
+            from = newfid;
+        }
+
+        walks
+    }
+}
+
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tread {
     pub fid: u32,
     pub offset: u64,
@@ -336,23 +807,76 @@ pub struct Tread {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Twrite {
     pub fid: u32,
     pub offset: u64,
     pub data: Data,
 }
 
+/// A Twrite whose data borrows from the frame that it was decoded from.
+#[derive(Debug, Clone, Copy)]
+pub struct TwriteRef<'a> {
+    pub fid: u32,
+    pub offset: u64,
+    pub data: DataRef<'a>,
+}
+
+impl<'a> TwriteRef<'a> {
+    /// Decodes the complete frame in `frame`, returning its tag and body if it holds a Twrite and
+    /// `None` if it holds any other message. The data is not copied out of `frame`.
+    pub fn decode_frame(frame: &'a [u8]) -> io::Result<Option<(u16, TwriteRef<'a>)>> {
+        let mut buf = frame;
+        let size: u32 = WireFormat::decode(&mut buf)?;
+        if size as usize != frame.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "frame size ({}) does not match buffer length ({})",
+                    size,
+                    frame.len()
+                ),
+            ));
+        }
+
+        let ty: u8 = WireFormat::decode(&mut buf)?;
+        if ty != TWRITE {
+            return Ok(None);
+        }
+
+        let tag = WireFormat::decode(&mut buf)?;
+        let fid = WireFormat::decode(&mut buf)?;
+        let offset = WireFormat::decode(&mut buf)?;
+        let data = DataRef::decode_slice(&mut buf)?;
+
+        Ok(Some((tag, TwriteRef { fid, offset, data })))
+    }
+}
+
+impl<'a> From<&'a Twrite> for TwriteRef<'a> {
+    fn from(write: &'a Twrite) -> Self {
+        TwriteRef {
+            fid: write.fid,
+            offset: write.offset,
+            data: DataRef(&write.data),
+        }
+    }
+}
+
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tclunk {
     pub fid: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tremove {
     pub fid: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tauth {
     pub afid: u32,
     pub uname: P9String,
@@ -361,6 +885,7 @@ pub struct Tauth {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tattach {
     pub fid: u32,
     pub afid: u32,
@@ -370,17 +895,72 @@ pub struct Tattach {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Topen {
+    pub fid: u32,
+    pub mode: u8,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tcreate {
+    pub fid: u32,
+    pub name: P9String,
+    pub perm: u32,
+    pub mode: u8,
+    pub extension: P9String,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tstat {
+    pub fid: u32,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Twstat {
+    pub fid: u32,
+    pub stat: Stat,
+}
+
+// The stat in a Twstat is preceded by an additional count of the bytes that follow, even though
+// the stat itself also starts with its size.
+impl WireFormat for Twstat {
+    fn byte_size(&self) -> u32 {
+        self.fid.byte_size() + mem::size_of::<u16>() as u32 + self.stat.byte_size()
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.fid.encode(writer)?;
+        (self.stat.byte_size() as u16).encode(writer)?;
+        self.stat.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let fid = WireFormat::decode(reader)?;
+        let _nstat: u16 = WireFormat::decode(reader)?;
+        let stat = WireFormat::decode(reader)?;
+
+        Ok(Twstat { fid, stat })
+    }
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tstatfs {
     pub fid: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlopen {
     pub fid: u32,
     pub flags: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlcreate {
     pub fid: u32,
     pub name: P9String,
@@ -390,6 +970,7 @@ pub struct Tlcreate {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tsymlink {
     pub fid: u32,
     pub name: P9String,
@@ -398,6 +979,7 @@ pub struct Tsymlink {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tmknod {
     pub dfid: u32,
     pub name: P9String,
@@ -408,6 +990,7 @@ pub struct Tmknod {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Trename {
     pub fid: u32,
     pub dfid: u32,
@@ -415,17 +998,20 @@ pub struct Trename {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Treadlink {
     pub fid: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tgetattr {
     pub fid: u32,
     pub request_mask: u64,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tsetattr {
     pub fid: u32,
     pub valid: u32,
@@ -440,6 +1026,7 @@ pub struct Tsetattr {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Txattrwalk {
     pub fid: u32,
     pub newfid: u32,
@@ -447,6 +1034,7 @@ pub struct Txattrwalk {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Txattrcreate {
     pub fid: u32,
     pub name: P9String,
@@ -455,19 +1043,167 @@ pub struct Txattrcreate {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Treaddir {
     pub fid: u32,
     pub offset: u64,
     pub count: u32,
 }
 
+/// Like Treaddir, but each entry in the reply also carries the attributes of the file it names.
+/// Only available once the client has negotiated the `readdirplus` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Treaddirplus {
+    pub fid: u32,
+    pub offset: u64,
+    pub count: u32,
+}
+
+/// Asks for the id of the current session if `session` is None. Otherwise adopts the fids of the
+/// earlier session with that id, whose connection went away, on a connection without any fids of
+/// its own. Only available once the client has negotiated the `resume` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tresume {
+    pub session: Option<u64>,
+}
+
+/// Copies up to `count` bytes from `src_offset` in the file open on `src_fid` to `dst_offset` in
+/// the file open on `dst_fid` without sending the data over the connection. Like Twrite, the
+/// reply may report a short copy. Only available once the client has negotiated the `copyrange`
+/// extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tcopyrange {
+    pub src_fid: u32,
+    pub src_offset: u64,
+    pub dst_fid: u32,
+    pub dst_offset: u64,
+    pub count: u64,
+}
+
+/// Makes `count` bytes at `dst_offset` in the file open on `dst_fid` share storage with the ones
+/// at `src_offset` in the file open on `src_fid`, as in a reflink. A `count` of 0 clones
+/// everything up to the end of the source. Either the whole range is cloned or the request fails,
+/// with EOPNOTSUPP if the files don't support it. Only available once the client has negotiated
+/// the `clonerange` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tclonerange {
+    pub src_fid: u32,
+    pub src_offset: u64,
+    pub dst_fid: u32,
+    pub dst_offset: u64,
+    pub count: u64,
+}
+
+/// Finds the start of the first run of data (`whence` is 3, as in SEEK_DATA) or of the first hole
+/// (`whence` is 4, as in SEEK_HOLE) at or after `offset` in the file open on `fid`. Only available
+/// once the client has negotiated the `lseek` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tlseek {
+    pub fid: u32,
+    pub offset: u64,
+    pub whence: u32,
+}
+
+/// Allocates, deallocates or zeroes `length` bytes at `offset` in the file open on `fid`. `mode`
+/// holds the Linux FALLOC_FL_* flags: 0 preallocates the range, growing the file if needed, and
+/// KEEP_SIZE (0x1), PUNCH_HOLE (0x2) and ZERO_RANGE (0x10) work as they do for fallocate(2). Only
+/// available once the client has negotiated the `fallocate` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tfallocate {
+    pub fid: u32,
+    pub mode: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Tells the server how the client expects to access `length` bytes at `offset` in the file open
+/// on `fid`, or up to the end of the file if `length` is 0. `advice` is NORMAL (0), RANDOM (1),
+/// SEQUENTIAL (2), WILLNEED (3), DONTNEED (4) or NOREUSE (5), as for posix_fadvise(2) on most
+/// Linux architectures. Only available once the client has negotiated the `fadvise` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tfadvise {
+    pub fid: u32,
+    pub offset: u64,
+    pub length: u64,
+    pub advice: u32,
+}
+
+/// Runs one of the ioctls in the `ioctl` module on the file open on `fid`, with `data` as its
+/// argument. Only available once the client has negotiated the `ioctl` extension, and only for the
+/// ioctls that the server allows.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tioctl {
+    pub fid: u32,
+    pub request: u32,
+    pub data: Data,
+}
+
+/// Makes `newfid` report changes to the file that `fid` refers to, or to the entries of the
+/// directory. `mask` holds the Linux IN_* flags of the events to report, as for
+/// inotify_add_watch(2). Reading `newfid` with Tread returns the events that happened since the
+/// last read as a sequence of `WatchEvent`s, or nothing if there are none yet, and clunking it
+/// removes the watch. Only available once the client has negotiated the `notify` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Twatch {
+    pub fid: u32,
+    pub newfid: u32,
+    pub mask: u32,
+}
+
+/// Asks for a read or write lease on the file open on `fid`, or gives up the lease that the fid
+/// holds. While a client holds a lease it may cache the file, and changes to it in the case of a
+/// write lease, until the server reports that the lease was broken in an Rleasebreaks. Fails with
+/// EAGAIN if someone else already uses the file in a way that conflicts with the lease. Only
+/// available once the client has negotiated the `lease` extension.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tlease {
+    pub fid: u32,
+    pub ty: LeaseType,
+}
+
+/// What a Tlease asks for, encoded as a single byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum LeaseType {
+    Read = 0,
+    Write = 1,
+    Release = 2,
+}
+
+/// Renames `oldname` in `olddirfid` to `newname` in `newdirfid` like Trenameat, with the
+/// renameat2 `flags`. With 1 (RENAME_NOREPLACE) it fails with EEXIST instead of replacing
+/// `newname`, and with 2 (RENAME_EXCHANGE) it swaps the two entries, which must both exist. Only
+/// available once the client has negotiated the `renameat2` extension.
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Trenameat2 {
+    pub olddirfid: u32,
+    pub oldname: P9String,
+    pub newdirfid: u32,
+    pub newname: P9String,
+    pub flags: u32,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tfsync {
     pub fid: u32,
     pub datasync: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlock {
     pub fid: u32,
     pub type_: u8,
@@ -479,6 +1215,7 @@ pub struct Tlock {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tgetlock {
     pub fid: u32,
     pub type_: u8,
@@ -489,6 +1226,7 @@ pub struct Tgetlock {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlink {
     pub dfid: u32,
     pub fid: u32,
@@ -496,6 +1234,7 @@ pub struct Tlink {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tmkdir {
     pub dfid: u32,
     pub name: P9String,
@@ -504,6 +1243,7 @@ pub struct Tmkdir {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Trenameat {
     pub olddirfid: u32,
     pub oldname: P9String,
@@ -512,6 +1252,7 @@ pub struct Trenameat {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tunlinkat {
     pub dirfd: u32,
     pub name: P9String,
@@ -521,6 +1262,7 @@ pub struct Tunlinkat {
 /// A message sent from a 9P server to a 9P client in response to a request from
 /// that client.  Encapsulates a full frame.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Rmessage {
     Version(Rversion),
     Flush,
@@ -531,6 +1273,10 @@ pub enum Rmessage {
     Remove,
     Attach(Rattach),
     Auth(Rauth),
+    Open(Ropen),
+    Create(Rcreate),
+    Stat(Rstat),
+    Wstat,
     Statfs(Rstatfs),
     Lopen(Rlopen),
     Lcreate(Rlcreate),
@@ -543,6 +1289,19 @@ pub enum Rmessage {
     XattrWalk(Rxattrwalk),
     XattrCreate,
     Readdir(Rreaddir),
+    ReaddirPlus(Rreaddirplus),
+    Ping,
+    Resume(Rresume),
+    CopyRange(Rcopyrange),
+    CloneRange,
+    Lseek(Rlseek),
+    Fallocate,
+    Fadvise,
+    Ioctl(Rioctl),
+    Watch,
+    Lease,
+    LeaseBreaks(Rleasebreaks),
+    RenameAt2,
     Fsync,
     Lock(Rlock),
     GetLock(Rgetlock),
@@ -551,16 +1310,122 @@ pub enum Rmessage {
     RenameAt,
     UnlinkAt,
     Lerror(Rlerror),
+    Error(Rerror),
+    Vendor(Rvendor),
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rframe {
     pub tag: u16,
     pub msg: Rmessage,
 }
 
+// The 9P2000.u and 9P2000.L encodings agree on every message, so that is what the WireFormat
+// implementation uses.
 impl WireFormat for Rframe {
     fn byte_size(&self) -> u32 {
+        self.byte_size_dialect(Dialect::Linux)
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.encode_dialect(writer, Dialect::Linux)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Self::decode_dialect(reader, Dialect::Linux)
+    }
+}
+
+impl Rframe {
+    /// Decodes a frame sent by a server that has negotiated `dialect`.  Plain 9P2000 omits the
+    /// errno from Rerror, which is decoded as 0.
+    pub fn decode_dialect<R: Read>(reader: &mut R, dialect: Dialect) -> io::Result<Self> {
+        let byte_size: u32 = WireFormat::decode(reader)?;
+
+        // byte_size includes the size of byte_size so remove that from the
+        // expected length of the message.  Also make sure that byte_size is at least
+        // that long to begin with.
+        if byte_size < mem::size_of::<u32>() as u32 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("byte_size(= {}) is less than 4 bytes", byte_size),
+            ));
+        }
+
+        let reader = &mut reader.take((byte_size - mem::size_of::<u32>() as u32) as u64);
+
+        let mut ty = [0u8];
+        reader.read_exact(&mut ty)?;
+
+        let tag: u16 = WireFormat::decode(reader)?;
+
+        let msg = match ty[0] {
+            RERROR if dialect == Dialect::Legacy => Ok(Rmessage::Error(Rerror {
+                ename: WireFormat::decode(reader)?,
+                errno: 0,
+            })),
+            RVERSION => Ok(Rmessage::Version(WireFormat::decode(reader)?)),
+            RFLUSH => Ok(Rmessage::Flush),
+            RWALK => Ok(Rmessage::Walk(WireFormat::decode(reader)?)),
+            RREAD => Ok(Rmessage::Read(WireFormat::decode(reader)?)),
+            RWRITE => Ok(Rmessage::Write(WireFormat::decode(reader)?)),
+            RCLUNK => Ok(Rmessage::Clunk),
+            RREMOVE => Ok(Rmessage::Remove),
+            RATTACH => Ok(Rmessage::Attach(WireFormat::decode(reader)?)),
+            RAUTH => Ok(Rmessage::Auth(WireFormat::decode(reader)?)),
+            ROPEN => Ok(Rmessage::Open(WireFormat::decode(reader)?)),
+            RCREATE => Ok(Rmessage::Create(WireFormat::decode(reader)?)),
+            RSTAT => Ok(Rmessage::Stat(WireFormat::decode(reader)?)),
+            RWSTAT => Ok(Rmessage::Wstat),
+            RSTATFS => Ok(Rmessage::Statfs(WireFormat::decode(reader)?)),
+            RLOPEN => Ok(Rmessage::Lopen(WireFormat::decode(reader)?)),
+            RLCREATE => Ok(Rmessage::Lcreate(WireFormat::decode(reader)?)),
+            RSYMLINK => Ok(Rmessage::Symlink(WireFormat::decode(reader)?)),
+            RMKNOD => Ok(Rmessage::Mknod(WireFormat::decode(reader)?)),
+            RRENAME => Ok(Rmessage::Rename),
+            RREADLINK => Ok(Rmessage::Readlink(WireFormat::decode(reader)?)),
+            RGETATTR => Ok(Rmessage::GetAttr(WireFormat::decode(reader)?)),
+            RSETATTR => Ok(Rmessage::SetAttr),
+            RXATTRWALK => Ok(Rmessage::XattrWalk(WireFormat::decode(reader)?)),
+            RXATTRCREATE => Ok(Rmessage::XattrCreate),
+            RREADDIR => Ok(Rmessage::Readdir(WireFormat::decode(reader)?)),
+            RREADDIRPLUS => Ok(Rmessage::ReaddirPlus(WireFormat::decode(reader)?)),
+            RPING => Ok(Rmessage::Ping),
+            RRESUME => Ok(Rmessage::Resume(WireFormat::decode(reader)?)),
+            RCOPYRANGE => Ok(Rmessage::CopyRange(WireFormat::decode(reader)?)),
+            RCLONERANGE => Ok(Rmessage::CloneRange),
+            RLSEEK => Ok(Rmessage::Lseek(WireFormat::decode(reader)?)),
+            RFALLOCATE => Ok(Rmessage::Fallocate),
+            RFADVISE => Ok(Rmessage::Fadvise),
+            RIOCTL => Ok(Rmessage::Ioctl(WireFormat::decode(reader)?)),
+            RWATCH => Ok(Rmessage::Watch),
+            RLEASE => Ok(Rmessage::Lease),
+            RLEASEBREAKS => Ok(Rmessage::LeaseBreaks(WireFormat::decode(reader)?)),
+            RRENAMEAT2 => Ok(Rmessage::RenameAt2),
+            RFSYNC => Ok(Rmessage::Fsync),
+            RLOCK => Ok(Rmessage::Lock(WireFormat::decode(reader)?)),
+            RGETLOCK => Ok(Rmessage::GetLock(WireFormat::decode(reader)?)),
+            RLINK => Ok(Rmessage::Link),
+            RMKDIR => Ok(Rmessage::Mkdir(WireFormat::decode(reader)?)),
+            RRENAMEAT => Ok(Rmessage::RenameAt),
+            RUNLINKAT => Ok(Rmessage::UnlinkAt),
+            RLERROR => Ok(Rmessage::Lerror(WireFormat::decode(reader)?)),
+            RERROR => Ok(Rmessage::Error(WireFormat::decode(reader)?)),
+            ty if ty % 2 == 1 && is_vendor_type(ty - 1) => Ok(Rmessage::Vendor(Rvendor {
+                ty,
+                body: WireFormat::decode(reader)?,
+            })),
+            err => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown message type {}", err),
+            )),
+        }?;
+
+        Ok(Rframe { tag, msg })
+    }
+    /// Returns the encoded size of `self` for a client that has negotiated `dialect`.
+    pub fn byte_size_dialect(&self, dialect: Dialect) -> u32 {
         let msg_size = match self.msg {
             Rmessage::Version(ref version) => version.byte_size(),
             Rmessage::Flush => 0,
@@ -571,6 +1436,10 @@ impl WireFormat for Rframe {
             Rmessage::Remove => 0,
             Rmessage::Attach(ref attach) => attach.byte_size(),
             Rmessage::Auth(ref auth) => auth.byte_size(),
+            Rmessage::Open(ref open) => open.byte_size(),
+            Rmessage::Create(ref create) => create.byte_size(),
+            Rmessage::Stat(ref stat) => stat.byte_size_dialect(dialect),
+            Rmessage::Wstat => 0,
             Rmessage::Statfs(ref statfs) => statfs.byte_size(),
             Rmessage::Lopen(ref lopen) => lopen.byte_size(),
             Rmessage::Lcreate(ref lcreate) => lcreate.byte_size(),
@@ -583,6 +1452,19 @@ impl WireFormat for Rframe {
             Rmessage::XattrWalk(ref xattrwalk) => xattrwalk.byte_size(),
             Rmessage::XattrCreate => 0,
             Rmessage::Readdir(ref readdir) => readdir.byte_size(),
+            Rmessage::ReaddirPlus(ref readdirplus) => readdirplus.byte_size(),
+            Rmessage::Ping => 0,
+            Rmessage::Resume(ref resume) => resume.byte_size(),
+            Rmessage::CopyRange(ref copyrange) => copyrange.byte_size(),
+            Rmessage::CloneRange => 0,
+            Rmessage::Lseek(ref lseek) => lseek.byte_size(),
+            Rmessage::Fallocate => 0,
+            Rmessage::Fadvise => 0,
+            Rmessage::Ioctl(ref ioctl) => ioctl.byte_size(),
+            Rmessage::Watch => 0,
+            Rmessage::Lease => 0,
+            Rmessage::LeaseBreaks(ref lease_breaks) => lease_breaks.byte_size(),
+            Rmessage::RenameAt2 => 0,
             Rmessage::Fsync => 0,
             Rmessage::Lock(ref lock) => lock.byte_size(),
             Rmessage::GetLock(ref getlock) => getlock.byte_size(),
@@ -591,48 +1473,40 @@ impl WireFormat for Rframe {
             Rmessage::RenameAt => 0,
             Rmessage::UnlinkAt => 0,
             Rmessage::Lerror(ref lerror) => lerror.byte_size(),
+            Rmessage::Error(ref error) => error.byte_size_dialect(dialect),
+            Rmessage::Vendor(ref vendor) => vendor.body.byte_size(),
         };
 
         // size + type + tag + message size
         (mem::size_of::<u32>() + mem::size_of::<u8>() + mem::size_of::<u16>()) as u32 + msg_size
     }
 
-    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        self.byte_size().encode(writer)?;
+    /// Encodes `self` for a client that has negotiated `dialect` into the front of `buf` and
+    /// returns the number of bytes used. Fails without writing anything if `buf` is smaller than
+    /// `byte_size_dialect`.
+    pub fn encode_to_slice(&self, buf: &mut [u8], dialect: Dialect) -> io::Result<usize> {
+        let size = self.byte_size_dialect(dialect) as usize;
+        if buf.len() < size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "buffer too small for frame: want: {} bytes, got: {} bytes",
+                    size,
+                    buf.len()
+                ),
+            ));
+        }
 
-        let ty = match self.msg {
-            Rmessage::Version(_) => RVERSION,
-            Rmessage::Flush => RFLUSH,
-            Rmessage::Walk(_) => RWALK,
-            Rmessage::Read(_) => RREAD,
-            Rmessage::Write(_) => RWRITE,
-            Rmessage::Clunk => RCLUNK,
-            Rmessage::Remove => RREMOVE,
-            Rmessage::Attach(_) => RATTACH,
-            Rmessage::Auth(_) => RAUTH,
-            Rmessage::Statfs(_) => RSTATFS,
-            Rmessage::Lopen(_) => RLOPEN,
-            Rmessage::Lcreate(_) => RLCREATE,
-            Rmessage::Symlink(_) => RSYMLINK,
-            Rmessage::Mknod(_) => RMKNOD,
-            Rmessage::Rename => RRENAME,
-            Rmessage::Readlink(_) => RREADLINK,
-            Rmessage::GetAttr(_) => RGETATTR,
-            Rmessage::SetAttr => RSETATTR,
-            Rmessage::XattrWalk(_) => RXATTRWALK,
-            Rmessage::XattrCreate => RXATTRCREATE,
-            Rmessage::Readdir(_) => RREADDIR,
-            Rmessage::Fsync => RFSYNC,
-            Rmessage::Lock(_) => RLOCK,
-            Rmessage::GetLock(_) => RGETLOCK,
-            Rmessage::Link => RLINK,
-            Rmessage::Mkdir(_) => RMKDIR,
-            Rmessage::RenameAt => RRENAMEAT,
-            Rmessage::UnlinkAt => RUNLINKAT,
-            Rmessage::Lerror(_) => RLERROR,
-        };
+        self.encode_dialect(&mut &mut buf[..size], dialect)?;
+        Ok(size)
+    }
 
-        ty.encode(writer)?;
+    /// Encodes `self` for a client that has negotiated `dialect`.  Plain 9P2000 omits the errno
+    /// from Rerror and the 9P2000.u fields from stats.
+    pub fn encode_dialect<W: Write>(&self, writer: &mut W, dialect: Dialect) -> io::Result<()> {
+        self.byte_size_dialect(dialect).encode(writer)?;
+
+        self.message_type().encode(writer)?;
         self.tag.encode(writer)?;
 
         match self.msg {
@@ -645,6 +1519,10 @@ impl WireFormat for Rframe {
             Rmessage::Remove => Ok(()),
             Rmessage::Attach(ref attach) => attach.encode(writer),
             Rmessage::Auth(ref auth) => auth.encode(writer),
+            Rmessage::Open(ref open) => open.encode(writer),
+            Rmessage::Create(ref create) => create.encode(writer),
+            Rmessage::Stat(ref stat) => stat.encode_dialect(writer, dialect),
+            Rmessage::Wstat => Ok(()),
             Rmessage::Statfs(ref statfs) => statfs.encode(writer),
             Rmessage::Lopen(ref lopen) => lopen.encode(writer),
             Rmessage::Lcreate(ref lcreate) => lcreate.encode(writer),
@@ -657,6 +1535,19 @@ impl WireFormat for Rframe {
             Rmessage::XattrWalk(ref xattrwalk) => xattrwalk.encode(writer),
             Rmessage::XattrCreate => Ok(()),
             Rmessage::Readdir(ref readdir) => readdir.encode(writer),
+            Rmessage::ReaddirPlus(ref readdirplus) => readdirplus.encode(writer),
+            Rmessage::Ping => Ok(()),
+            Rmessage::Resume(ref resume) => resume.encode(writer),
+            Rmessage::CopyRange(ref copyrange) => copyrange.encode(writer),
+            Rmessage::CloneRange => Ok(()),
+            Rmessage::Lseek(ref lseek) => lseek.encode(writer),
+            Rmessage::Fallocate => Ok(()),
+            Rmessage::Fadvise => Ok(()),
+            Rmessage::Ioctl(ref ioctl) => ioctl.encode(writer),
+            Rmessage::Watch => Ok(()),
+            Rmessage::Lease => Ok(()),
+            Rmessage::LeaseBreaks(ref lease_breaks) => lease_breaks.encode(writer),
+            Rmessage::RenameAt2 => Ok(()),
             Rmessage::Fsync => Ok(()),
             Rmessage::Lock(ref lock) => lock.encode(writer),
             Rmessage::GetLock(ref getlock) => getlock.encode(writer),
@@ -665,62 +1556,139 @@ impl WireFormat for Rframe {
             Rmessage::RenameAt => Ok(()),
             Rmessage::UnlinkAt => Ok(()),
             Rmessage::Lerror(ref lerror) => lerror.encode(writer),
+            Rmessage::Error(ref error) => error.encode_dialect(writer, dialect),
+            Rmessage::Vendor(ref vendor) => vendor.body.encode(writer),
         }
     }
 
-    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
-        let byte_size: u32 = WireFormat::decode(reader)?;
+    /// Returns the payload of `self`, which is the data that an Rread, Rreaddir or Rreaddirplus
+    /// ends with, or None for the other messages.
+    pub fn payload(&self) -> Option<&[u8]> {
+        match self.msg {
+            Rmessage::Read(ref read) => Some(&read.data),
+            Rmessage::Readdir(ref readdir) => Some(&readdir.data),
+            Rmessage::ReaddirPlus(ref readdirplus) => Some(&readdirplus.data),
+            _ => None,
+        }
+    }
 
-        // byte_size includes the size of byte_size so remove that from the
-        // expected length of the message.
-        let reader = &mut reader.take((byte_size - mem::size_of::<u32>() as u32) as u64);
+    /// Encodes all of `self` for a client that has negotiated `dialect` but its `payload`, which
+    /// is to be written right after, so that the payload doesn't have to be copied into the same
+    /// buffer as the rest. Frames without a payload are encoded in full.
+    pub fn encode_header_dialect<W: Write>(
+        &self,
+        writer: &mut W,
+        dialect: Dialect,
+    ) -> io::Result<()> {
+        let payload = match self.payload() {
+            Some(payload) => payload,
+            None => return self.encode_dialect(writer, dialect),
+        };
+        if payload.len() > u32::MAX as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "data is too large"));
+        }
 
-        let mut ty = [0u8];
-        reader.read_exact(&mut ty)?;
+        self.byte_size_dialect(dialect).encode(writer)?;
+        self.message_type().encode(writer)?;
+        self.tag.encode(writer)?;
+        (payload.len() as u32).encode(writer)
+    }
 
-        let tag: u16 = WireFormat::decode(reader)?;
+    fn message_type(&self) -> u8 {
+        match self.msg {
+            Rmessage::Version(_) => RVERSION,
+            Rmessage::Flush => RFLUSH,
+            Rmessage::Walk(_) => RWALK,
+            Rmessage::Read(_) => RREAD,
+            Rmessage::Write(_) => RWRITE,
+            Rmessage::Clunk => RCLUNK,
+            Rmessage::Remove => RREMOVE,
+            Rmessage::Attach(_) => RATTACH,
+            Rmessage::Auth(_) => RAUTH,
+            Rmessage::Open(_) => ROPEN,
+            Rmessage::Create(_) => RCREATE,
+            Rmessage::Stat(_) => RSTAT,
+            Rmessage::Wstat => RWSTAT,
+            Rmessage::Statfs(_) => RSTATFS,
+            Rmessage::Lopen(_) => RLOPEN,
+            Rmessage::Lcreate(_) => RLCREATE,
+            Rmessage::Symlink(_) => RSYMLINK,
+            Rmessage::Mknod(_) => RMKNOD,
+            Rmessage::Rename => RRENAME,
+            Rmessage::Readlink(_) => RREADLINK,
+            Rmessage::GetAttr(_) => RGETATTR,
+            Rmessage::SetAttr => RSETATTR,
+            Rmessage::XattrWalk(_) => RXATTRWALK,
+            Rmessage::XattrCreate => RXATTRCREATE,
+            Rmessage::Readdir(_) => RREADDIR,
+            Rmessage::ReaddirPlus(_) => RREADDIRPLUS,
+            Rmessage::Ping => RPING,
+            Rmessage::Resume(_) => RRESUME,
+            Rmessage::CopyRange(_) => RCOPYRANGE,
+            Rmessage::CloneRange => RCLONERANGE,
+            Rmessage::Lseek(_) => RLSEEK,
+            Rmessage::Fallocate => RFALLOCATE,
+            Rmessage::Fadvise => RFADVISE,
+            Rmessage::Ioctl(_) => RIOCTL,
+            Rmessage::Watch => RWATCH,
+            Rmessage::Lease => RLEASE,
+            Rmessage::LeaseBreaks(_) => RLEASEBREAKS,
+            Rmessage::RenameAt2 => RRENAMEAT2,
+            Rmessage::Fsync => RFSYNC,
+            Rmessage::Lock(_) => RLOCK,
+            Rmessage::GetLock(_) => RGETLOCK,
+            Rmessage::Link => RLINK,
+            Rmessage::Mkdir(_) => RMKDIR,
+            Rmessage::RenameAt => RRENAMEAT,
+            Rmessage::UnlinkAt => RUNLINKAT,
+            Rmessage::Lerror(_) => RLERROR,
+            Rmessage::Error(_) => RERROR,
+            Rmessage::Vendor(ref vendor) => vendor.ty,
+        }
+    }
+}
 
-        let msg = match ty[0] {
-            RVERSION => Ok(Rmessage::Version(WireFormat::decode(reader)?)),
-            RFLUSH => Ok(Rmessage::Flush),
-            RWALK => Ok(Rmessage::Walk(WireFormat::decode(reader)?)),
-            RREAD => Ok(Rmessage::Read(WireFormat::decode(reader)?)),
-            RWRITE => Ok(Rmessage::Write(WireFormat::decode(reader)?)),
-            RCLUNK => Ok(Rmessage::Clunk),
-            RREMOVE => Ok(Rmessage::Remove),
-            RATTACH => Ok(Rmessage::Attach(WireFormat::decode(reader)?)),
-            RAUTH => Ok(Rmessage::Auth(WireFormat::decode(reader)?)),
-            RSTATFS => Ok(Rmessage::Statfs(WireFormat::decode(reader)?)),
-            RLOPEN => Ok(Rmessage::Lopen(WireFormat::decode(reader)?)),
-            RLCREATE => Ok(Rmessage::Lcreate(WireFormat::decode(reader)?)),
-            RSYMLINK => Ok(Rmessage::Symlink(WireFormat::decode(reader)?)),
-            RMKNOD => Ok(Rmessage::Mknod(WireFormat::decode(reader)?)),
-            RRENAME => Ok(Rmessage::Rename),
-            RREADLINK => Ok(Rmessage::Readlink(WireFormat::decode(reader)?)),
-            RGETATTR => Ok(Rmessage::GetAttr(WireFormat::decode(reader)?)),
-            RSETATTR => Ok(Rmessage::SetAttr),
-            RXATTRWALK => Ok(Rmessage::XattrWalk(WireFormat::decode(reader)?)),
-            RXATTRCREATE => Ok(Rmessage::XattrCreate),
-            RREADDIR => Ok(Rmessage::Readdir(WireFormat::decode(reader)?)),
-            RFSYNC => Ok(Rmessage::Fsync),
-            RLOCK => Ok(Rmessage::Lock(WireFormat::decode(reader)?)),
-            RGETLOCK => Ok(Rmessage::GetLock(WireFormat::decode(reader)?)),
-            RLINK => Ok(Rmessage::Link),
-            RMKDIR => Ok(Rmessage::Mkdir(WireFormat::decode(reader)?)),
-            RRENAMEAT => Ok(Rmessage::RenameAt),
-            RUNLINKAT => Ok(Rmessage::UnlinkAt),
-            RLERROR => Ok(Rmessage::Lerror(WireFormat::decode(reader)?)),
-            err => Err(io::Error::new(
-                ErrorKind::InvalidData,
-                format!("unknown message type {}", err),
-            )),
-        }?;
+/// The raw body of a vendor extension message with type `ty`, which is decoded by whoever
+/// registered that type. The body takes up the rest of the frame so it has no length prefix.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tvendor {
+    pub ty: u8,
+    pub body: VendorBody,
+}
 
-        Ok(Rframe { tag, msg })
+/// The raw body of the reply to a vendor extension message. `ty` is the R-message type.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rvendor {
+    pub ty: u8,
+    pub body: VendorBody,
+}
+
+/// The bytes of a vendor message body. Since the body is not length-prefixed, decoding consumes
+/// everything that is left in the reader, which must therefore be limited to a single frame.
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VendorBody(pub Vec<u8>);
+
+impl WireFormat for VendorBody {
+    fn byte_size(&self) -> u32 {
+        self.0.len() as u32
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.0)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        Ok(VendorBody(body))
     }
 }
 
 #[derive(Debug, Copy, Clone, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Qid {
     pub ty: u8,
     pub version: u32,
@@ -728,6 +1696,7 @@ pub struct Qid {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Dirent {
     pub qid: Qid,
     pub offset: u64,
@@ -735,43 +1704,273 @@ pub struct Dirent {
     pub name: P9String,
 }
 
+/// A directory entry returned by Treaddirplus: a `Dirent` followed by the attributes of the file,
+/// encoded as in an Rgetattr.
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DirentPlus {
+    pub dirent: Dirent,
+    pub attr: Rgetattr,
+}
+
+/// A change reported by reading a fid created with Twatch. `mask` holds the Linux IN_* flags that
+/// describe the event, `cookie` ties together the two halves of a rename, and `name` is the name
+/// of the directory entry that changed, or empty if the event is about the watched file itself.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WatchEvent {
+    pub mask: u32,
+    pub cookie: u32,
+    pub name: P9String,
+}
+
+/// The directory entry used by the 9P2000.u dialect, both in Rstat and Twstat messages and when
+/// reading directories with Tread.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Stat {
+    pub ty: u16,
+    pub dev: u32,
+    pub qid: Qid,
+    pub mode: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub length: u64,
+    pub name: P9String,
+    pub uid: P9String,
+    pub gid: P9String,
+    pub muid: P9String,
+    pub extension: P9String,
+    pub n_uid: u32,
+    pub n_gid: u32,
+    pub n_muid: u32,
+}
+
+// A stat is encoded as a u16 count of the bytes that follow, followed by the fields in order.
+impl WireFormat for Stat {
+    fn byte_size(&self) -> u32 {
+        self.byte_size_dialect(Dialect::Unix)
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.encode_dialect(writer, Dialect::Unix)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let size: u16 = WireFormat::decode(reader)?;
+        let reader = &mut reader.take(size as u64);
+
+        let mut stat = Stat {
+            ty: WireFormat::decode(reader)?,
+            dev: WireFormat::decode(reader)?,
+            qid: WireFormat::decode(reader)?,
+            mode: WireFormat::decode(reader)?,
+            atime: WireFormat::decode(reader)?,
+            mtime: WireFormat::decode(reader)?,
+            length: WireFormat::decode(reader)?,
+            name: WireFormat::decode(reader)?,
+            uid: WireFormat::decode(reader)?,
+            gid: WireFormat::decode(reader)?,
+            muid: WireFormat::decode(reader)?,
+            extension: Default::default(),
+            n_uid: u32::MAX,
+            n_gid: u32::MAX,
+            n_muid: u32::MAX,
+        };
+
+        // A plain 9P2000 stat ends here.
+        if reader.limit() > 0 {
+            stat.extension = WireFormat::decode(reader)?;
+            stat.n_uid = WireFormat::decode(reader)?;
+            stat.n_gid = WireFormat::decode(reader)?;
+            stat.n_muid = WireFormat::decode(reader)?;
+        }
+
+        // Skip over any fields that we don't know about so that the next stat in a sequence can
+        // be decoded.
+        reader.read_to_end(&mut Vec::new())?;
+
+        Ok(stat)
+    }
+}
+
+impl Stat {
+    /// Returns the encoded size of `self` for a client that has negotiated `dialect`.
+    pub fn byte_size_dialect(&self, dialect: Dialect) -> u32 {
+        let size = mem::size_of::<u16>() as u32
+            + self.ty.byte_size()
+            + self.dev.byte_size()
+            + self.qid.byte_size()
+            + self.mode.byte_size()
+            + self.atime.byte_size()
+            + self.mtime.byte_size()
+            + self.length.byte_size()
+            + self.name.byte_size()
+            + self.uid.byte_size()
+            + self.gid.byte_size()
+            + self.muid.byte_size();
+
+        if dialect == Dialect::Legacy {
+            size
+        } else {
+            size + self.extension.byte_size()
+                + self.n_uid.byte_size()
+                + self.n_gid.byte_size()
+                + self.n_muid.byte_size()
+        }
+    }
+
+    /// Encodes `self` for a client that has negotiated `dialect`.  Plain 9P2000 stats end after
+    /// the muid.
+    pub fn encode_dialect<W: Write>(&self, writer: &mut W, dialect: Dialect) -> io::Result<()> {
+        let size = self.byte_size_dialect(dialect) - mem::size_of::<u16>() as u32;
+        if size > u16::MAX as u32 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "stat is too large"));
+        }
+
+        (size as u16).encode(writer)?;
+        self.ty.encode(writer)?;
+        self.dev.encode(writer)?;
+        self.qid.encode(writer)?;
+        self.mode.encode(writer)?;
+        self.atime.encode(writer)?;
+        self.mtime.encode(writer)?;
+        self.length.encode(writer)?;
+        self.name.encode(writer)?;
+        self.uid.encode(writer)?;
+        self.gid.encode(writer)?;
+        self.muid.encode(writer)?;
+
+        if dialect != Dialect::Legacy {
+            self.extension.encode(writer)?;
+            self.n_uid.encode(writer)?;
+            self.n_gid.encode(writer)?;
+            self.n_muid.encode(writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rversion {
     pub msize: u32,
     pub version: P9String,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rwalk {
     pub wqids: Vec<Qid>,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rread {
     pub data: Data,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rwrite {
     pub count: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rauth {
     pub aqid: Qid,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rattach {
     pub qid: Qid,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlerror {
     pub ecode: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rerror {
+    pub ename: P9String,
+    pub errno: u32,
+}
+
+impl Rerror {
+    fn byte_size_dialect(&self, dialect: Dialect) -> u32 {
+        if dialect == Dialect::Legacy {
+            self.ename.byte_size()
+        } else {
+            self.byte_size()
+        }
+    }
+
+    fn encode_dialect<W: Write>(&self, writer: &mut W, dialect: Dialect) -> io::Result<()> {
+        if dialect == Dialect::Legacy {
+            self.ename.encode(writer)
+        } else {
+            self.encode(writer)
+        }
+    }
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Ropen {
+    pub qid: Qid,
+    pub iounit: u32,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rcreate {
+    pub qid: Qid,
+    pub iounit: u32,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rstat {
+    pub stat: Stat,
+}
+
+// Like Twstat, the stat in an Rstat is preceded by an additional count of its size.
+impl WireFormat for Rstat {
+    fn byte_size(&self) -> u32 {
+        self.byte_size_dialect(Dialect::Unix)
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.encode_dialect(writer, Dialect::Unix)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let _nstat: u16 = WireFormat::decode(reader)?;
+        let stat = WireFormat::decode(reader)?;
+
+        Ok(Rstat { stat })
+    }
+}
+
+impl Rstat {
+    fn byte_size_dialect(&self, dialect: Dialect) -> u32 {
+        mem::size_of::<u16>() as u32 + self.stat.byte_size_dialect(dialect)
+    }
+
+    fn encode_dialect<W: Write>(&self, writer: &mut W, dialect: Dialect) -> io::Result<()> {
+        (self.stat.byte_size_dialect(dialect) as u16).encode(writer)?;
+        self.stat.encode_dialect(writer, dialect)
+    }
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rstatfs {
     pub ty: u32,
     pub bsize: u32,
@@ -785,33 +1984,39 @@ pub struct Rstatfs {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlopen {
     pub qid: Qid,
     pub iounit: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlcreate {
     pub qid: Qid,
     pub iounit: u32,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rsymlink {
     pub qid: Qid,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rmknod {
     pub qid: Qid,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rreadlink {
     pub target: P9String,
 }
 
-#[derive(Debug, P9WireFormat)]
+#[derive(Debug, Copy, Clone, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rgetattr {
     pub valid: u64,
     pub qid: Qid,
@@ -836,21 +2041,63 @@ pub struct Rgetattr {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rxattrwalk {
     pub size: u64,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rreaddir {
     pub data: Data,
 }
 
+/// A sequence of `DirentPlus` entries, packed the same way as the `Dirent`s of an Rreaddir.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rreaddirplus {
+    pub data: Data,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rresume {
+    pub session: u64,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rcopyrange {
+    pub count: u64,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rlseek {
+    pub offset: u64,
+}
+
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rioctl {
+    pub data: Data,
+}
+
+/// The fids whose leases were broken since the last Tleasebreaks. They no longer hold a lease.
+#[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rleasebreaks {
+    pub fids: Vec<u32>,
+}
+
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlock {
     pub status: u8,
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rgetlock {
     pub type_: u8,
     pub start: u64,
@@ -860,6 +2107,7 @@ pub struct Rgetlock {
 }
 
 #[derive(Debug, P9WireFormat)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rmkdir {
     pub qid: Qid,
 }
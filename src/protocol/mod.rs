@@ -2,10 +2,21 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+pub mod checksum;
+pub mod dissect;
+mod extensions;
+#[cfg(all(test, feature = "std"))]
+mod golden;
+pub mod io;
+pub mod ioctl;
 mod messages;
+mod vendor;
 mod wire_format;
 
+pub use self::extensions::Extensions;
 pub use self::messages::*;
+pub use self::vendor::VendorRegistry;
 pub use self::wire_format::Data;
+pub use self::wire_format::DataRef;
 pub use self::wire_format::P9String;
 pub use self::wire_format::WireFormat;
@@ -0,0 +1,86 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+
+use crate::protocol::io;
+use crate::protocol::io::ErrorKind;
+use crate::protocol::messages::is_vendor_type;
+use crate::protocol::messages::Rvendor;
+use crate::protocol::messages::Tvendor;
+use crate::protocol::messages::VendorBody;
+use crate::protocol::wire_format::WireFormat;
+
+type Handler = Box<dyn FnMut(&[u8]) -> io::Result<Vec<u8>> + Send>;
+
+/// Associates message types that are not used by 9P with handlers for vendor extensions.
+///
+/// Each handler decodes the body of its T-message as a `WireFormat` type, and its reply is
+/// encoded as the body of the R-message whose type is one greater.
+#[derive(Default)]
+pub struct VendorRegistry {
+    handlers: BTreeMap<u8, Handler>,
+}
+
+impl VendorRegistry {
+    pub fn new() -> VendorRegistry {
+        Default::default()
+    }
+
+    /// Registers `handler` for T-messages of type `ty`. Fails if `ty` is used by 9P (see
+    /// `is_vendor_type`) or already has a handler.
+    pub fn register<T, R, F>(&mut self, ty: u8, mut handler: F) -> io::Result<()>
+    where
+        T: WireFormat,
+        R: WireFormat,
+        F: FnMut(T) -> io::Result<R> + Send + 'static,
+    {
+        if !is_vendor_type(ty) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("message type {} is not available for vendor extensions", ty),
+            ));
+        }
+        if self.handlers.contains_key(&ty) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("message type {} is already registered", ty),
+            ));
+        }
+
+        let handler = move |body: &[u8]| {
+            let request = T::decode(&mut &*body)?;
+            let reply = handler(request)?;
+            let mut buf = Vec::with_capacity(reply.byte_size() as usize);
+            reply.encode(&mut buf)?;
+            Ok(buf)
+        };
+        self.handlers.insert(ty, Box::new(handler));
+
+        Ok(())
+    }
+
+    /// Returns true if there is a handler for T-messages of type `ty`.
+    pub fn contains(&self, ty: u8) -> bool {
+        self.handlers.contains_key(&ty)
+    }
+
+    /// Passes `msg` to the handler registered for its type and returns the reply.
+    pub fn dispatch(&mut self, msg: &Tvendor) -> io::Result<Rvendor> {
+        let handler = self.handlers.get_mut(&msg.ty).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::Unsupported,
+                format!("no handler for message type {}", msg.ty),
+            )
+        })?;
+
+        Ok(Rvendor {
+            ty: msg.ty + 1,
+            body: VendorBody(handler(&msg.body.0)?),
+        })
+    }
+}
@@ -2,21 +2,34 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::ffi::CStr;
-use std::ffi::CString;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use core::mem;
+use core::ops::Deref;
+use core::ops::DerefMut;
+#[cfg(feature = "std")]
 use std::ffi::OsStr;
-use std::fmt;
-use std::io;
-use std::io::ErrorKind;
-use std::io::Read;
-use std::io::Write;
-use std::mem;
-use std::ops::Deref;
-use std::ops::DerefMut;
-use std::vec::Vec;
+
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Deserializer;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde::Serializer;
+
+use crate::protocol::io;
+use crate::protocol::io::ErrorKind;
+use crate::protocol::io::Read;
+use crate::protocol::io::Write;
 
 /// A type that can be encoded on the wire using the 9P protocol.
-pub trait WireFormat: std::marker::Sized {
+pub trait WireFormat: core::marker::Sized {
     /// Returns the number of bytes necessary to fully encode `self`.
     fn byte_size(&self) -> u32;
 
@@ -65,7 +78,7 @@ uint_wire_format_impl!(u64);
 ///
 /// It is represented as a C string with a terminating 0 (NUL) character to allow it to be passed
 /// directly to libc functions.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
 pub struct P9String {
     cstr: CString,
 }
@@ -82,7 +95,7 @@ impl P9String {
         }
 
         // 9p strings must be valid UTF-8.
-        let _check_utf8 = std::str::from_utf8(&string_bytes)
+        let _check_utf8 = core::str::from_utf8(&string_bytes)
             .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
 
         let cstr =
@@ -95,6 +108,10 @@ impl P9String {
         self.cstr.as_bytes().len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.cstr.as_bytes().is_empty()
+    }
+
     pub fn as_c_str(&self) -> &CStr {
         self.cstr.as_c_str()
     }
@@ -107,17 +124,26 @@ impl P9String {
     ///
     /// The string bytes are always followed by a NUL terminator ('\0'), so the pointer can be
     /// passed directly to libc functions that expect a C string.
-    pub fn as_ptr(&self) -> *const libc::c_char {
+    pub fn as_ptr(&self) -> *const core::ffi::c_char {
         self.cstr.as_ptr()
     }
 }
 
+// Show the string itself rather than the `CString` that holds it.
+impl fmt::Debug for P9String {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Safe to unwrap because `P9String::new` checks that the string is valid UTF-8.
+        fmt::Debug::fmt(core::str::from_utf8(self.as_bytes()).unwrap(), f)
+    }
+}
+
 impl PartialEq<&str> for P9String {
     fn eq(&self, other: &&str) -> bool {
         self.cstr.as_bytes() == other.as_bytes()
     }
 }
 
+#[cfg(feature = "std")]
 impl TryFrom<&OsStr> for P9String {
     type Error = io::Error;
 
@@ -127,6 +153,23 @@ impl TryFrom<&OsStr> for P9String {
     }
 }
 
+// Strings are serialized as UTF-8 strings rather than as a struct containing a C string.
+#[cfg(feature = "serde")]
+impl Serialize for P9String {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Safe to unwrap because `P9String::new` checks that the string is valid UTF-8.
+        serializer.serialize_str(core::str::from_utf8(self.as_bytes()).unwrap())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for P9String {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = alloc::string::String::deserialize(deserializer)?;
+        P9String::new(string).map_err(serde::de::Error::custom)
+    }
+}
+
 // The 9P protocol requires that strings are UTF-8 encoded.  The wire format is a u16
 // count |N|, encoded in little endian, followed by |N| bytes of UTF-8 data.
 impl WireFormat for P9String {
@@ -182,10 +225,41 @@ impl<T: WireFormat> WireFormat for Vec<T> {
     }
 }
 
+// Optional values are encoded as a presence byte, which is 1 if the value follows and 0 if it
+// doesn't. This is not part of any 9P dialect and is only used by extension messages.
+impl<T: WireFormat> WireFormat for Option<T> {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u8>() as u32 + self.as_ref().map_or(0, WireFormat::byte_size)
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Some(value) => {
+                1u8.encode(writer)?;
+                value.encode(writer)
+            }
+            None => 0u8.encode(writer),
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let present: u8 = WireFormat::decode(reader)?;
+        match present {
+            0 => Ok(None),
+            1 => Ok(Some(WireFormat::decode(reader)?)),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid presence byte for optional value: {}", present),
+            )),
+        }
+    }
+}
+
 /// A type that encodes an arbitrary number of bytes of data.  Typically used for Rread
 /// Twrite messages.  This differs from a `Vec<u8>` in that it encodes the number of bytes
 /// using a `u32` instead of a `u16`.
 #[derive(PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Data(pub Vec<u8>);
 
 // The maximum length of a data buffer that we support.  In practice the server's max message
@@ -255,11 +329,52 @@ impl WireFormat for Data {
     }
 }
 
+/// Borrowed counterpart of `Data`, which refers to the bytes of a message that was decoded in
+/// place instead of copying them into a new buffer.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct DataRef<'a>(pub &'a [u8]);
+
+impl<'a> DataRef<'a> {
+    /// Decodes data that is encoded the same way as `Data` from the front of `buf`, which is
+    /// advanced past it.
+    pub fn decode_slice(buf: &mut &'a [u8]) -> io::Result<Self> {
+        let len: u32 = WireFormat::decode(buf)?;
+        if len as usize > buf.len() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "unexpected end of data: want: {} bytes, got: {} bytes",
+                    len,
+                    buf.len()
+                ),
+            ));
+        }
+
+        let (data, rest) = buf.split_at(len as usize);
+        *buf = rest;
+        Ok(DataRef(data))
+    }
+}
+
+impl fmt::Debug for DataRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DataRef({} bytes)", self.len())
+    }
+}
+
+impl Deref for DataRef<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
-    use std::mem;
-    use std::string::String;
+    use alloc::string::String;
+    use core::mem;
+
+    use crate::protocol::io::Cursor;
 
     use p9_wire_format_derive::P9WireFormat;
 
@@ -353,7 +468,7 @@ mod test {
         ];
 
         let expected = values.iter().map(|v| {
-            let len = v.as_bytes().len();
+            let len = v.len();
             let mut buf = Vec::with_capacity(len + mem::size_of::<u16>());
 
             buf.push(len as u8);
@@ -558,16 +673,16 @@ mod test {
     #[test]
     fn error_cases() {
         // string is too long.
-        let mut long_str = String::with_capacity(std::u16::MAX as usize);
-        while long_str.len() < std::u16::MAX as usize {
+        let mut long_str = String::with_capacity(u16::MAX as usize);
+        while long_str.len() < u16::MAX as usize {
             long_str.push_str("long");
         }
         long_str.push('!');
         P9String::new(long_str).expect_err("long string");
 
         // vector is too long.
-        let mut long_vec: Vec<u32> = Vec::with_capacity(std::u16::MAX as usize);
-        while long_vec.len() < std::u16::MAX as usize {
+        let mut long_vec: Vec<u32> = Vec::with_capacity(u16::MAX as usize);
+        while long_vec.len() < u16::MAX as usize {
             long_vec.push(0x8bad_f00d);
         }
         long_vec.push(0x00ba_b10c);
@@ -756,4 +871,144 @@ mod test {
                 .expect("failed to decode value")
         );
     }
+
+    const SHAPE_POINT: u16 = 0x0b0b;
+
+    #[derive(Debug, PartialEq, P9WireFormat)]
+    #[repr(u16)]
+    enum Shape {
+        Point = SHAPE_POINT,
+        Circle { radius: u32, label: P9String } = 0x0c1c,
+        Line(u64, u64) = 0x1111,
+    }
+
+    #[test]
+    fn enum_encode() {
+        let mut actual = Vec::new();
+        WireFormat::encode(&Shape::Point, &mut actual).expect("failed to encode point");
+        assert_eq!(actual, vec![0x0b, 0x0b]);
+
+        let circle = Shape::Circle {
+            radius: 0xba5e_ba11,
+            label: P9String::new("円").unwrap(),
+        };
+        let mut expected = vec![0x1c, 0x0c, 0x11, 0xba, 0x5e, 0xba, 0x03, 0x00];
+        expected.extend_from_slice("円".as_bytes());
+        assert_eq!(circle.byte_size() as usize, expected.len());
+
+        let mut actual = Vec::new();
+        WireFormat::encode(&circle, &mut actual).expect("failed to encode circle");
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn enum_decode() {
+        let values = [
+            Shape::Point,
+            Shape::Circle {
+                radius: 0x0d15_ea5e,
+                label: P9String::new("Круг").unwrap(),
+            },
+            Shape::Line(0xdead_beef, 0x0bad_cafe),
+        ];
+
+        for expected in values {
+            let mut input = Vec::new();
+            WireFormat::encode(&expected, &mut input).expect("failed to encode value");
+            assert_eq!(expected.byte_size() as usize, input.len());
+            assert_eq!(
+                expected,
+                <Shape as WireFormat>::decode(&mut Cursor::new(&*input))
+                    .expect("failed to decode value")
+            );
+        }
+
+        let err = <Shape as WireFormat>::decode(&mut Cursor::new(&[0x0d, 0xf0][..]))
+            .expect_err("decoded unknown discriminant");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[derive(Debug, PartialEq, P9WireFormat)]
+    struct Optional {
+        a: u32,
+        b: Option<P9String>,
+        c: Option<u16>,
+    }
+
+    #[test]
+    fn option_encode() {
+        let value = Optional {
+            a: 0x00dd_ba11,
+            b: None,
+            c: Some(0xf00d),
+        };
+
+        let expected = vec![0x11, 0xba, 0xdd, 0x00, 0x00, 0x01, 0x0d, 0xf0];
+        assert_eq!(value.byte_size() as usize, expected.len());
+
+        let mut actual = Vec::new();
+        WireFormat::encode(&value, &mut actual).expect("failed to encode value");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn option_decode() {
+        let expected = Optional {
+            a: 0x00c0_ffee,
+            b: Some(P9String::new("選択").unwrap()),
+            c: None,
+        };
+
+        let mut input = Vec::new();
+        WireFormat::encode(&expected, &mut input).expect("failed to encode value");
+        assert_eq!(
+            expected,
+            <Optional as WireFormat>::decode(&mut Cursor::new(&*input))
+                .expect("failed to decode value")
+        );
+
+        <Option<u8> as WireFormat>::decode(&mut Cursor::new(&[2, 0][..]))
+            .expect_err("decoded invalid presence byte");
+    }
+
+    #[test]
+    fn data_ref_decode() {
+        let input = [0x03, 0x00, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef];
+        let mut buf = &input[..];
+
+        let data = DataRef::decode_slice(&mut buf).expect("failed to decode data");
+        assert_eq!(&*data, &input[4..7]);
+        assert_eq!(data.0.as_ptr(), input[4..].as_ptr());
+        assert_eq!(buf, &[0xef]);
+
+        let mut buf = &input[..6];
+        let err = DataRef::decode_slice(&mut buf).expect_err("decoded truncated data");
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn string_deserialize() {
+        use serde::de::value::Error;
+        use serde::de::value::StrDeserializer;
+        use serde::de::IntoDeserializer;
+
+        let deserializer: StrDeserializer<Error> = "Le Chiffre".into_deserializer();
+        let s = P9String::deserialize(deserializer).expect("failed to deserialize string");
+        assert_eq!(s, "Le Chiffre");
+
+        let deserializer: StrDeserializer<Error> = "nul\0byte".into_deserializer();
+        P9String::deserialize(deserializer).expect_err("deserialized string with a nul byte");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn frames_are_serializable() {
+        use crate::protocol::Rframe;
+        use crate::protocol::Tframe;
+
+        fn check<T: Serialize + for<'de> Deserialize<'de>>() {}
+        check::<Tframe>();
+        check::<Rframe>();
+    }
 }
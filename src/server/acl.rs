@@ -0,0 +1,151 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! POSIX ACLs, which Linux keeps in the system.posix_acl_access and system.posix_acl_default
+//! extended attributes. Their values name users and groups by id, so the ids in them have to be
+//! translated like the owners of files are.
+
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::io;
+
+use super::Export;
+
+const ACL_ACCESS: &[u8] = b"system.posix_acl_access";
+const ACL_DEFAULT: &[u8] = b"system.posix_acl_default";
+
+// The value is a version followed by entries of a tag, the permissions and an id, all little
+// endian. See include/uapi/linux/posix_acl_xattr.h.
+const ACL_VERSION: u32 = 2;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+fn is_acl(name: &CStr) -> bool {
+    let name = name.to_bytes();
+    name == ACL_ACCESS || name == ACL_DEFAULT
+}
+
+// Checks that `value` is an ACL and replaces the id of every user entry in it with `uid(id)` and
+// of every group entry with `gid(id)`. Returns None if it isn't an ACL or an id maps to None.
+fn map_ids<U, G>(value: &mut [u8], uid: U, gid: G) -> Option<()>
+where
+    U: Fn(u32) -> Option<u32>,
+    G: Fn(u32) -> Option<u32>,
+{
+    let (header, entries) = value.split_at_mut_checked(HEADER_SIZE)?;
+    if u32::from_le_bytes(header.try_into().ok()?) != ACL_VERSION || entries.len() % ENTRY_SIZE != 0
+    {
+        return None;
+    }
+
+    for entry in entries.chunks_exact_mut(ENTRY_SIZE) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u16::from_le_bytes([entry[2], entry[3]]);
+        if perm & !0o7 != 0 {
+            return None;
+        }
+        let map: &dyn Fn(u32) -> Option<u32> = match tag {
+            ACL_USER => &uid,
+            ACL_GROUP => &gid,
+            ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER => continue,
+            _ => return None,
+        };
+        let id = u32::from_le_bytes(entry[4..].try_into().ok()?);
+        entry[4..].copy_from_slice(&map(id)?.to_le_bytes());
+    }
+    Some(())
+}
+
+// Replaces the host ids in `value`, the value of the extended attribute `name` of a file of
+// `export`, with the ones that clients see if it is an ACL. Host ACLs that can't be parsed fail
+// with EIO.
+pub(super) fn to_client(export: &Export, name: &CStr, mut value: Vec<u8>) -> io::Result<Vec<u8>> {
+    if is_acl(name) {
+        map_ids(
+            &mut value,
+            |uid| Some(export.uid_to_client(uid)),
+            |gid| Some(export.gid_to_client(gid)),
+        )
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EIO))?;
+    }
+    Ok(value)
+}
+
+// Replaces the ids that a client sent in `value`, the value it is giving the extended attribute
+// `name` of a file of `export`, with the host ids if it is an ACL. ACLs that can't be parsed, or
+// that have ids without a host id, fail with EINVAL, as they would for setxattr(2).
+pub(super) fn to_host<'a>(
+    export: &Export,
+    name: &CStr,
+    value: &'a [u8],
+) -> io::Result<Cow<'a, [u8]>> {
+    if !is_acl(name) {
+        return Ok(Cow::Borrowed(value));
+    }
+    let mut value = value.to_vec();
+    map_ids(
+        &mut value,
+        |uid| export.uid_to_host(uid),
+        |gid| export.gid_to_host(gid),
+    )
+    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+    Ok(Cow::Owned(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = ACL_VERSION.to_le_bytes().to_vec();
+        for &(tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn map() {
+        let mut value = acl(&[
+            (ACL_USER_OBJ, 0o6, u32::MAX),
+            (ACL_USER, 0o4, 1000),
+            (ACL_GROUP_OBJ, 0o4, u32::MAX),
+            (ACL_GROUP, 0o5, 100),
+            (ACL_MASK, 0o7, u32::MAX),
+            (ACL_OTHER, 0o0, u32::MAX),
+        ]);
+        map_ids(&mut value, |uid| Some(uid + 1), |gid| Some(gid + 2)).expect("failed to map ACL");
+        assert_eq!(
+            value,
+            acl(&[
+                (ACL_USER_OBJ, 0o6, u32::MAX),
+                (ACL_USER, 0o4, 1001),
+                (ACL_GROUP_OBJ, 0o4, u32::MAX),
+                (ACL_GROUP, 0o5, 102),
+                (ACL_MASK, 0o7, u32::MAX),
+                (ACL_OTHER, 0o0, u32::MAX),
+            ])
+        );
+
+        let same = Some;
+        assert!(map_ids(&mut acl(&[]), same, same).is_some());
+        assert!(map_ids(&mut acl(&[(ACL_GROUP, 0o4, 0)]), same, |_| None).is_none());
+        assert!(map_ids(&mut [], same, same).is_none());
+        assert!(map_ids(&mut acl(&[(ACL_USER, 0o4, 0)])[..11], same, same).is_none());
+        assert!(map_ids(&mut acl(&[(0x40, 0o4, 0)]), same, same).is_none());
+        assert!(map_ids(&mut acl(&[(ACL_USER, 0o10, 0)]), same, same).is_none());
+        let mut value = acl(&[]);
+        value[0] = 1;
+        assert!(map_ids(&mut value, same, same).is_none());
+    }
+}
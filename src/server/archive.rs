@@ -0,0 +1,1060 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Component;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+
+use super::inflate::crc32;
+use super::inflate::inflate;
+use super::*;
+use crate::protocol::*;
+
+const ROOT: usize = 0;
+
+// The offsets of the "." and ".." entries of every directory. The other entries come after them,
+// in the order of their names.
+const DOT_OFFSET: u64 = 1;
+const DOTDOT_OFFSET: u64 = 2;
+
+const TAR_BLOCK: u64 = 512;
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END: u32 = 0x0605_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+
+// The end of central directory record is followed by a comment of up to this many bytes.
+const ZIP_MAX_COMMENT: u64 = 0xffff;
+
+fn err(errno: libc::c_int) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid archive: {what}"),
+    )
+}
+
+/// Serves the contents of a tar or zip archive, without unpacking it. The archive is indexed
+/// once when it is opened and then only read from as clients read the files in it, so it must
+/// not change while it is being served. The root of every export is a directory in the archive,
+/// "/" being the top of it.
+///
+/// Everything is read-only: requests that would change the tree fail with EROFS. Tar archives
+/// may be in the ustar, GNU or pax formats, and zip archives may use ZIP64. Files in zip archives
+/// that use anything other than the stored or deflate methods, or that are encrypted, can be
+/// looked up but not opened. Deflated files are decompressed in full when they are opened, and
+/// kept in memory for as long as any client has them open, however many times over.
+pub struct Archive {
+    file: File,
+    nodes: Vec<Node>,
+    // The contents of the compressed files that are open, by node.
+    inflated: Mutex<BTreeMap<usize, Weak<[u8]>>>,
+}
+
+/// A file in an `Archive`, along with the root of the export that it was reached from.
+pub struct ArchiveInode {
+    node: usize,
+    root: usize,
+}
+
+/// A file in an `Archive` that a client has opened.
+pub struct ArchiveHandle {
+    node: usize,
+    // The contents of a compressed file, which are decompressed when it is opened.
+    contents: Option<Arc<[u8]>>,
+}
+
+struct Node {
+    name: Vec<u8>,
+    parent: usize,
+    // The type and permissions of the file, as in `st_mode`.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    rdev: u64,
+    // The size of the file once it is decompressed, or of the target of a symlink.
+    size: u64,
+    data: Data,
+}
+
+enum Data {
+    // The entries of a directory by name.
+    Dir(BTreeMap<Vec<u8>, usize>),
+    // Contents that were in the headers of the archive, such as the targets of tar symlinks.
+    Inline(Vec<u8>),
+    // `size` bytes at `offset` in the archive.
+    Stored {
+        offset: u64,
+    },
+    Deflated {
+        offset: u64,
+        compressed: u64,
+        crc: u32,
+    },
+    // Contents in a format that can't be read, such as encrypted zip entries.
+    Unsupported,
+    None,
+}
+
+impl Node {
+    fn new(mode: u32) -> Node {
+        Node {
+            name: Vec::new(),
+            parent: ROOT,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            rdev: 0,
+            size: 0,
+            data: if mode & libc::S_IFMT == libc::S_IFDIR {
+                Data::Dir(BTreeMap::new())
+            } else {
+                Data::None
+            },
+        }
+    }
+
+    fn entries(&self) -> io::Result<&BTreeMap<Vec<u8>, usize>> {
+        match self.data {
+            Data::Dir(ref entries) => Ok(entries),
+            _ => Err(err(libc::ENOTDIR)),
+        }
+    }
+}
+
+// Splits `path` into the names that it is made of, or returns None if it has a ".." in it, which
+// would put it outside of the archive.
+fn split_path(path: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut names = Vec::new();
+    for name in path.split(|&b| b == b'/') {
+        match name {
+            b"" | b"." => {}
+            b".." => return None,
+            name => names.push(name),
+        }
+    }
+    Some(names)
+}
+
+// Adds `node` to the tree at `path`, along with any of the directories above it that are
+// missing. A file that is already there is replaced, except that a directory that replaces
+// another one keeps the entries of the old one.
+fn insert(nodes: &mut Vec<Node>, path: &[u8], mut node: Node) {
+    let Some(names) = split_path(path) else {
+        return;
+    };
+    let Some((&name, dirs)) = names.split_last() else {
+        // The archive has an entry for its own top directory.
+        if let Data::Dir(_) = node.data {
+            node.data = mem::replace(&mut nodes[ROOT].data, Data::None);
+            nodes[ROOT] = node;
+        }
+        return;
+    };
+
+    let mut parent = ROOT;
+    for &dir in dirs {
+        parent = match nodes[parent].entries() {
+            Ok(entries) => match entries.get(dir) {
+                Some(&child) if nodes[child].entries().is_ok() => child,
+                _ => add_entry(nodes, parent, dir, Node::new(libc::S_IFDIR | 0o755)),
+            },
+            // One of the directories is a file, so there is nowhere to put it.
+            Err(_) => return,
+        };
+    }
+
+    let old = nodes[parent]
+        .entries()
+        .ok()
+        .and_then(|e| e.get(name).copied());
+    if let Some(old) = old.filter(|&old| nodes[old].entries().is_ok()) {
+        if let Data::Dir(_) = node.data {
+            node.data = mem::replace(&mut nodes[old].data, Data::None);
+            node.name = name.to_vec();
+            node.parent = parent;
+            nodes[old] = node;
+            return;
+        }
+    }
+    add_entry(nodes, parent, name, node);
+}
+
+// Adds `node` to the directory `parent` as `name`, replacing what was there, and returns it.
+fn add_entry(nodes: &mut Vec<Node>, parent: usize, name: &[u8], mut node: Node) -> usize {
+    let index = nodes.len();
+    node.name = name.to_vec();
+    node.parent = parent;
+    nodes.push(node);
+    if let Data::Dir(ref mut entries) = nodes[parent].data {
+        entries.insert(name.to_vec(), index);
+    }
+    index
+}
+
+// Returns the file at `path` in the tree, without following symlinks.
+fn find(nodes: &[Node], path: &[u8]) -> Option<usize> {
+    split_path(path)?.into_iter().try_fold(ROOT, |dir, name| {
+        nodes[dir].entries().ok()?.get(name).copied()
+    })
+}
+
+fn read_exact_at(file: &File, len: u64, offset: u64) -> io::Result<Vec<u8>> {
+    // Don't trust the sizes in the archive with how much memory to allocate.
+    if offset
+        .checked_add(len)
+        .is_none_or(|end| end > file.metadata().map_or(0, |m| m.len()))
+    {
+        return Err(invalid("it ends too early"));
+    }
+    let mut buf = vec![0; len as usize];
+    file.read_exact_at(&mut buf, offset)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("it ends too early"),
+            _ => e,
+        })?;
+    Ok(buf)
+}
+
+// Returns the bytes of `field` up to the first nul byte.
+fn cstr(field: &[u8]) -> &[u8] {
+    field.split(|&b| b == 0).next().unwrap_or_default()
+}
+
+// Parses a number field of a tar header, which is either octal text or, if the top bit of the
+// first byte is set, a big endian binary number in the rest of the field.
+fn tar_number(field: &[u8]) -> io::Result<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        return Ok(field[1..]
+            .iter()
+            .fold(0u64, |n, &b| n.wrapping_shl(8) | u64::from(b)));
+    }
+
+    let text = cstr(field);
+    let text = std::str::from_utf8(text).map_err(|_| invalid("bad tar number"))?;
+    let text = text.trim_matches(' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid("bad tar number"))
+}
+
+// The fields of a tar entry that may come from GNU long name entries or pax headers rather than
+// the entry itself.
+#[derive(Default)]
+struct TarOverrides {
+    path: Option<Vec<u8>>,
+    linkpath: Option<Vec<u8>>,
+    size: Option<u64>,
+    mtime: Option<i64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl TarOverrides {
+    // Applies the records of a pax extended header, each of which looks like
+    // "<length> <key>=<value>\n".
+    fn parse_pax(&mut self, mut records: &[u8]) -> io::Result<()> {
+        while !records.is_empty() {
+            let space = records
+                .iter()
+                .position(|&b| b == b' ')
+                .ok_or_else(|| invalid("bad pax record"))?;
+            let len = std::str::from_utf8(&records[..space])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|&len| len > space && len <= records.len())
+                .ok_or_else(|| invalid("bad pax record"))?;
+            let record = &records[space + 1..len];
+            records = &records[len..];
+
+            let record = record.strip_suffix(b"\n").unwrap_or(record);
+            let Some(eq) = record.iter().position(|&b| b == b'=') else {
+                continue;
+            };
+            let (key, value) = (&record[..eq], &record[eq + 1..]);
+            let number = || std::str::from_utf8(value).ok();
+            match key {
+                b"path" => self.path = Some(value.to_vec()),
+                b"linkpath" => self.linkpath = Some(value.to_vec()),
+                b"size" => self.size = number().and_then(|n| n.parse().ok()),
+                b"uid" => self.uid = number().and_then(|n| n.parse().ok()),
+                b"gid" => self.gid = number().and_then(|n| n.parse().ok()),
+                // Times may have a fractional part, which is dropped.
+                b"mtime" => {
+                    self.mtime = number()
+                        .and_then(|n| n.split('.').next())
+                        .and_then(|n| n.parse().ok())
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn index_tar(file: &File) -> io::Result<Vec<Node>> {
+    let mut nodes = vec![Node::new(libc::S_IFDIR | 0o755)];
+    let len = file.metadata()?.len();
+    let mut offset = 0;
+    let mut overrides = TarOverrides::default();
+
+    while offset + TAR_BLOCK <= len {
+        let header = read_exact_at(file, TAR_BLOCK, offset)?;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        // The checksum is the sum of the bytes of the header with the checksum itself taken to be
+        // spaces.
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    b.into()
+                }
+            })
+            .sum();
+        if tar_number(&header[148..156])? != sum {
+            return Err(invalid("bad tar header checksum"));
+        }
+
+        let size = overrides
+            .size
+            .take()
+            .unwrap_or(tar_number(&header[124..136])?);
+        let data = offset + TAR_BLOCK;
+        offset = data
+            .checked_add(size.next_multiple_of(TAR_BLOCK))
+            .ok_or_else(|| invalid("tar entry is too big"))?;
+
+        let type_ = header[156];
+        match type_ {
+            b'L' | b'K' | b'x' => {
+                let contents = read_exact_at(file, size, data)?;
+                match type_ {
+                    b'L' => overrides.path = Some(cstr(&contents).to_vec()),
+                    b'K' => overrides.linkpath = Some(cstr(&contents).to_vec()),
+                    _ => overrides.parse_pax(&contents)?,
+                }
+                continue;
+            }
+            // Global pax headers and the other GNU extensions only hold things that don't matter
+            // here.
+            b'g' | b'V' | b'M' | b'N' => {
+                overrides = TarOverrides::default();
+                continue;
+            }
+            _ => {}
+        }
+        let overrides = mem::take(&mut overrides);
+
+        let path = overrides.path.unwrap_or_else(|| {
+            let name = cstr(&header[..100]);
+            // Only POSIX ustar headers have a prefix, which GNU ones use for other things.
+            let prefix = cstr(&header[345..500]);
+            if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                [prefix, b"/", name].concat()
+            } else {
+                name.to_vec()
+            }
+        });
+        let linkpath = overrides
+            .linkpath
+            .unwrap_or_else(|| cstr(&header[157..257]).to_vec());
+
+        let perms = tar_number(&header[100..108])? as u32 & 0o7777;
+        let ifmt = match type_ {
+            b'1' => {
+                // Hard links are copies of files that came earlier.
+                if let Some(target) = find(&nodes, &linkpath) {
+                    let target = &nodes[target];
+                    if target.entries().is_err() {
+                        let mut node = Node::new(target.mode);
+                        node.uid = target.uid;
+                        node.gid = target.gid;
+                        node.mtime = target.mtime;
+                        node.rdev = target.rdev;
+                        node.size = target.size;
+                        node.data = match target.data {
+                            Data::Inline(ref target) => Data::Inline(target.clone()),
+                            Data::Stored { offset } => Data::Stored { offset },
+                            _ => Data::None,
+                        };
+                        insert(&mut nodes, &path, node);
+                    }
+                }
+                continue;
+            }
+            b'2' => libc::S_IFLNK,
+            b'3' => libc::S_IFCHR,
+            b'4' => libc::S_IFBLK,
+            b'5' => libc::S_IFDIR,
+            b'6' => libc::S_IFIFO,
+            // Anything else is to be read as a regular file.
+            _ if path.ends_with(b"/") => libc::S_IFDIR,
+            _ => libc::S_IFREG,
+        };
+
+        let mut node = Node::new(ifmt | perms);
+        node.uid = overrides
+            .uid
+            .unwrap_or(tar_number(&header[108..116])? as u32);
+        node.gid = overrides
+            .gid
+            .unwrap_or(tar_number(&header[116..124])? as u32);
+        node.mtime = overrides
+            .mtime
+            .unwrap_or(tar_number(&header[136..148])? as i64);
+        match ifmt {
+            libc::S_IFREG => {
+                node.size = size;
+                node.data = Data::Stored { offset: data };
+            }
+            libc::S_IFLNK => {
+                node.size = linkpath.len() as u64;
+                node.data = Data::Inline(linkpath);
+            }
+            libc::S_IFCHR | libc::S_IFBLK => {
+                let major = tar_number(&header[329..337])? as u32;
+                let minor = tar_number(&header[337..345])? as u32;
+                node.rdev = libc::makedev(major, minor);
+            }
+            _ => {}
+        }
+        insert(&mut nodes, &path, node);
+    }
+
+    Ok(nodes)
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+// Converts an MS-DOS date and time, which zip archives use when they don't have a Unix time, to
+// seconds since the epoch. They have no time zone, so they are taken to be in UTC.
+fn dos_time(date: u16, time: u16) -> i64 {
+    let (year, month, day) = (
+        i64::from(date >> 9) + 1980,
+        i64::from(date >> 5 & 0xf),
+        i64::from(date & 0x1f),
+    );
+    // The number of days since 1970-01-01, counting years from March so that leap days come
+    // last.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let days = 365 * y + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719_468;
+    let secs = i64::from(time >> 11) * 3600 + i64::from(time >> 5 & 0x3f) * 60;
+    days * 86400 + secs + i64::from(time & 0x1f) * 2
+}
+
+// Returns the position of the end of central directory record in `tail`, which is the end of
+// the file.
+fn find_zip_end(tail: &[u8]) -> Option<usize> {
+    (0..tail.len().checked_sub(22)? + 1)
+        .rev()
+        .find(|&pos| u32_at(tail, pos) == ZIP_END)
+}
+
+fn index_zip(file: &File) -> io::Result<Vec<Node>> {
+    let len = file.metadata()?.len();
+    let tail_len = min(len, 22 + ZIP_MAX_COMMENT);
+    let tail = read_exact_at(file, tail_len, len - tail_len)?;
+    let end = find_zip_end(&tail).ok_or_else(|| invalid("not a tar or zip archive"))?;
+    let end_offset = len - tail_len + end as u64;
+
+    let mut count = u64::from(u16_at(&tail, end + 10));
+    let mut cd_size = u64::from(u32_at(&tail, end + 12));
+    let mut cd_offset = u64::from(u32_at(&tail, end + 16));
+    if count == 0xffff || cd_size == 0xffff_ffff || cd_offset == 0xffff_ffff {
+        if end_offset < 20 {
+            return Err(invalid("missing ZIP64 locator"));
+        }
+        let locator = read_exact_at(file, 20, end_offset - 20)?;
+        if u32_at(&locator, 0) != ZIP64_LOCATOR {
+            return Err(invalid("missing ZIP64 locator"));
+        }
+        let record = read_exact_at(file, 56, u64_at(&locator, 8))?;
+        if u32_at(&record, 0) != ZIP64_END {
+            return Err(invalid("missing ZIP64 end of central directory"));
+        }
+        count = u64_at(&record, 32);
+        cd_size = u64_at(&record, 40);
+        cd_offset = u64_at(&record, 48);
+    }
+    if cd_offset
+        .checked_add(cd_size)
+        .is_none_or(|cd_end| cd_end > len)
+    {
+        return Err(invalid("central directory is out of bounds"));
+    }
+
+    let cd = read_exact_at(file, cd_size, cd_offset)?;
+    let mut nodes = vec![Node::new(libc::S_IFDIR | 0o755)];
+    let mut pos = 0;
+    for _ in 0..count {
+        if pos + 46 > cd.len() || u32_at(&cd, pos) != ZIP_CENTRAL_HEADER {
+            return Err(invalid("bad central directory header"));
+        }
+        let header = &cd[pos..];
+        let made_by = u16_at(header, 4) >> 8;
+        let flags = u16_at(header, 8);
+        let method = u16_at(header, 10);
+        let crc = u32_at(header, 16);
+        let mut compressed = u64::from(u32_at(header, 20));
+        let mut size = u64::from(u32_at(header, 24));
+        let name_len = usize::from(u16_at(header, 28));
+        let extra_len = usize::from(u16_at(header, 30));
+        let comment_len = usize::from(u16_at(header, 32));
+        let attrs = u32_at(header, 38);
+        let mut local_offset = u64::from(u32_at(header, 42));
+        let next = 46 + name_len + extra_len + comment_len;
+        if pos + next > cd.len() {
+            return Err(invalid("bad central directory header"));
+        }
+        let path = &header[46..46 + name_len];
+        let mut extra = &header[46 + name_len..46 + name_len + extra_len];
+        pos += next;
+
+        let mut mtime = dos_time(u16_at(header, 14), u16_at(header, 12));
+        let (mut uid, mut gid) = (0, 0);
+        while extra.len() >= 4 {
+            let id = u16_at(extra, 0);
+            let field_len = min(usize::from(u16_at(extra, 2)), extra.len() - 4);
+            let field = &extra[4..4 + field_len];
+            extra = &extra[4 + field_len..];
+            match id {
+                // ZIP64 sizes and offset, which are only there for the fields that didn't fit.
+                0x0001 => {
+                    let mut values = field.chunks_exact(8).map(|v| u64_at(v, 0));
+                    for value in [&mut size, &mut compressed, &mut local_offset] {
+                        if *value == 0xffff_ffff {
+                            *value = values.next().ok_or_else(|| invalid("bad ZIP64 field"))?;
+                        }
+                    }
+                }
+                // The extended timestamp, which starts with the Unix modification time.
+                0x5455 if field.len() >= 5 && field[0] & 1 != 0 => {
+                    mtime = i64::from(u32_at(field, 1) as i32);
+                }
+                // The Unix owner, as little endian numbers of any size.
+                0x7875 if field.first() == Some(&1) => {
+                    let mut rest = &field[1..];
+                    let mut ids = [0u32; 2];
+                    for id in &mut ids {
+                        let Some((&id_len, tail)) = rest.split_first() else {
+                            break;
+                        };
+                        let id_len = min(usize::from(id_len), tail.len());
+                        *id = tail[..id_len]
+                            .iter()
+                            .rev()
+                            .fold(0u32, |n, &b| n.wrapping_shl(8) | u32::from(b));
+                        rest = &tail[id_len..];
+                    }
+                    (uid, gid) = (ids[0], ids[1]);
+                }
+                _ => {}
+            }
+        }
+
+        // Archives made on Unix keep the mode in the top half of the external attributes. Others
+        // only say whether an entry is a directory, with the MS-DOS directory attribute.
+        let is_dir = path.ends_with(b"/") || attrs & 0x10 != 0;
+        let mode = match attrs >> 16 {
+            mode if made_by == 3 && mode & libc::S_IFMT != 0 => mode,
+            _ if is_dir => libc::S_IFDIR | 0o755,
+            _ => libc::S_IFREG | 0o644,
+        };
+
+        let mut node = Node::new(mode);
+        node.uid = uid;
+        node.gid = gid;
+        node.mtime = mtime;
+        match mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFLNK => {
+                node.size = size;
+                node.data = zip_data(file, local_offset, flags, method, compressed, crc)?;
+            }
+            libc::S_IFDIR => {}
+            // Zip archives have nowhere to keep device numbers.
+            _ => node.mode = libc::S_IFREG | mode & 0o7777,
+        }
+        insert(&mut nodes, path, node);
+    }
+
+    Ok(nodes)
+}
+
+// Returns where the contents of the zip entry whose local header is at `offset` are.
+fn zip_data(
+    file: &File,
+    offset: u64,
+    flags: u16,
+    method: u16,
+    compressed: u64,
+    crc: u32,
+) -> io::Result<Data> {
+    // Encrypted entries can't be read.
+    if flags & 1 != 0 {
+        return Ok(Data::Unsupported);
+    }
+
+    let header = read_exact_at(file, 30, offset)?;
+    if u32_at(&header, 0) != ZIP_LOCAL_HEADER {
+        return Err(invalid("bad local file header"));
+    }
+    let offset = offset + 30 + u64::from(u16_at(&header, 26)) + u64::from(u16_at(&header, 28));
+    Ok(match method {
+        0 => Data::Stored { offset },
+        8 => Data::Deflated {
+            offset,
+            compressed,
+            crc,
+        },
+        _ => Data::Unsupported,
+    })
+}
+
+impl Archive {
+    /// Opens and indexes the tar or zip archive at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Archive> {
+        Archive::new(File::open(path)?)
+    }
+
+    /// Indexes the tar or zip archive in `file`, which is read from until the file system is
+    /// dropped.
+    pub fn new(file: File) -> io::Result<Archive> {
+        // Every tar archive this can read has a ustar header, which zip archives can't start
+        // with.
+        let mut magic = [0; 6];
+        let is_tar = match file.read_exact_at(&mut magic, 257) {
+            Ok(()) => magic[..5] == *b"ustar",
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+        let nodes = if is_tar {
+            index_tar(&file)?
+        } else {
+            index_zip(&file)?
+        };
+        Ok(Archive {
+            file,
+            nodes,
+            inflated: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    // Returns the contents of a file that has them in the archive, decompressing them if need
+    // be.
+    fn contents(&self, node: &Node) -> io::Result<Vec<u8>> {
+        match node.data {
+            Data::Inline(ref data) => Ok(data.clone()),
+            Data::Stored { offset } => read_exact_at(&self.file, node.size, offset),
+            Data::Deflated {
+                offset,
+                compressed,
+                crc,
+            } => {
+                let data = read_exact_at(&self.file, compressed, offset)?;
+                let data = inflate(&data, node.size as usize)?;
+                if data.len() as u64 != node.size || crc32(&data) != crc {
+                    return Err(err(libc::EIO));
+                }
+                Ok(data)
+            }
+            Data::Unsupported => Err(err(libc::EOPNOTSUPP)),
+            Data::Dir(_) => Err(err(libc::EISDIR)),
+            Data::None => Err(err(libc::EINVAL)),
+        }
+    }
+
+    // Returns the decompressed contents of the file `index`, which are shared with every other
+    // handle that has it open.
+    fn inflated(&self, index: usize) -> io::Result<Arc<[u8]>> {
+        // The lock is held while decompressing, so that a file that is opened several times at
+        // once is only decompressed once.
+        let mut inflated = self.inflated.lock().unwrap();
+        if let Some(data) = inflated.get(&index).and_then(Weak::upgrade) {
+            return Ok(data);
+        }
+
+        let data: Arc<[u8]> = self.contents(self.node(index))?.into();
+        inflated.retain(|_, data| data.strong_count() > 0);
+        inflated.insert(index, Arc::downgrade(&data));
+        Ok(data)
+    }
+
+    fn stat_node(&self, index: usize) -> libc::stat64 {
+        let node = self.node(index);
+        let nlink = match node.data {
+            Data::Dir(ref entries) => {
+                2 + entries
+                    .values()
+                    .filter(|&&child| self.node(child).entries().is_ok())
+                    .count()
+            }
+            _ => 1,
+        };
+
+        // Safe because this only has integer fields, for which zero is a valid value.
+        let mut st: libc::stat64 = unsafe { mem::zeroed() };
+        st.st_ino = index as u64 + 1;
+        st.st_mode = node.mode;
+        st.st_nlink = nlink as _;
+        st.st_uid = node.uid;
+        st.st_gid = node.gid;
+        st.st_rdev = node.rdev;
+        st.st_size = node.size as _;
+        st.st_blksize = 4096;
+        st.st_blocks = node.size.div_ceil(512) as _;
+        st.st_atime = node.mtime;
+        st.st_mtime = node.mtime;
+        st.st_ctime = node.mtime;
+        st
+    }
+
+    fn dir_entry(&self, offset: u64, name: &[u8], index: usize) -> io::Result<DirEntry> {
+        let type_ = match self.node(index).mode & libc::S_IFMT {
+            libc::S_IFDIR => libc::DT_DIR,
+            libc::S_IFLNK => libc::DT_LNK,
+            libc::S_IFCHR => libc::DT_CHR,
+            libc::S_IFBLK => libc::DT_BLK,
+            libc::S_IFIFO => libc::DT_FIFO,
+            _ => libc::DT_REG,
+        };
+        Ok(DirEntry {
+            offset,
+            type_,
+            name: P9String::new(name)?,
+        })
+    }
+}
+
+impl FileSystem for Archive {
+    type Inode = ArchiveInode;
+    type Handle = ArchiveHandle;
+
+    fn root(&self, export: &Export) -> io::Result<ArchiveInode> {
+        let mut path = Vec::new();
+        for component in export.root.components() {
+            match component {
+                Component::Normal(name) => {
+                    path.extend_from_slice(name.as_encoded_bytes());
+                    path.push(b'/');
+                }
+                Component::ParentDir => return Err(err(libc::ENOENT)),
+                _ => {}
+            }
+        }
+        let node = find(&self.nodes, &path).ok_or_else(|| err(libc::ENOENT))?;
+        self.node(node).entries()?;
+        Ok(ArchiveInode { node, root: node })
+    }
+
+    fn lookup(&self, parent: &ArchiveInode, name: &CStr) -> io::Result<ArchiveInode> {
+        let entries = self.node(parent.node).entries()?;
+        let node = match name.to_bytes() {
+            b"." => parent.node,
+            // Clients can't walk out of the export.
+            b".." if parent.node == parent.root => parent.root,
+            b".." => self.node(parent.node).parent,
+            name => *entries.get(name).ok_or_else(|| err(libc::ENOENT))?,
+        };
+        Ok(ArchiveInode {
+            node,
+            root: parent.root,
+        })
+    }
+
+    fn try_clone(&self, inode: &ArchiveInode) -> io::Result<ArchiveInode> {
+        Ok(ArchiveInode {
+            node: inode.node,
+            root: inode.root,
+        })
+    }
+
+    fn stat(&self, inode: &ArchiveInode) -> io::Result<libc::stat64> {
+        Ok(self.stat_node(inode.node))
+    }
+
+    fn setattr(
+        &self,
+        _inode: &ArchiveInode,
+        _handle: Option<&ArchiveHandle>,
+        _attr: &Tsetattr,
+    ) -> io::Result<()> {
+        Err(err(libc::EROFS))
+    }
+
+    fn open(&self, inode: &ArchiveInode, flags: u32) -> io::Result<ArchiveHandle> {
+        if open_modifies(flags) {
+            return Err(err(libc::EROFS));
+        }
+
+        let node = self.node(inode.node);
+        let contents = match node.mode & libc::S_IFMT {
+            libc::S_IFDIR => None,
+            _ if flags & P9_DIRECTORY != 0 => return Err(err(libc::ENOTDIR)),
+            libc::S_IFREG => match node.data {
+                Data::Deflated { .. } | Data::Unsupported => Some(self.inflated(inode.node)?),
+                _ => None,
+            },
+            libc::S_IFLNK => return Err(err(libc::ELOOP)),
+            // There is nothing behind the devices and FIFOs in an archive.
+            _ => return Err(err(libc::ENXIO)),
+        };
+        Ok(ArchiveHandle {
+            node: inode.node,
+            contents,
+        })
+    }
+
+    fn create(
+        &self,
+        _dir: &ArchiveInode,
+        _name: &CStr,
+        _flags: u32,
+        _mode: u32,
+        _uid: u32,
+        _gid: u32,
+    ) -> io::Result<(ArchiveInode, ArchiveHandle)> {
+        Err(err(libc::EROFS))
+    }
+
+    fn read(&self, handle: &ArchiveHandle, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let node = self.node(handle.node);
+        if let Some(ref data) = handle.contents {
+            let start = min(offset, data.len() as u64) as usize;
+            let count = min(buf.len(), data.len() - start);
+            buf[..count].copy_from_slice(&data[start..start + count]);
+            return Ok(count);
+        }
+
+        match node.data {
+            Data::Stored { offset: start } => {
+                // Reads at or past the end don't need to know where that is in the archive.
+                let count = min(buf.len() as u64, node.size.saturating_sub(offset)) as usize;
+                if count == 0 {
+                    return Ok(0);
+                }
+                let start = start.checked_add(offset).ok_or_else(|| err(libc::EINVAL))?;
+                self.file.read_exact_at(&mut buf[..count], start)?;
+                Ok(count)
+            }
+            Data::Dir(_) => Err(err(libc::EISDIR)),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&self, _handle: &ArchiveHandle, _buf: &[u8], _offset: u64) -> io::Result<usize> {
+        Err(ebadf())
+    }
+
+    fn readdir(
+        &self,
+        dir: &ArchiveInode,
+        _handle: &mut ArchiveHandle,
+        offset: u64,
+        entry: &mut dyn FnMut(DirEntry, &libc::stat64) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        let entries = self.node(dir.node).entries()?;
+        let parent = self.lookup(dir, c"..")?.node;
+        let dots = [
+            (DOT_OFFSET, &b"."[..], dir.node),
+            (DOTDOT_OFFSET, &b".."[..], parent),
+        ];
+        let rest = entries
+            .iter()
+            .zip(DOTDOT_OFFSET + 1..)
+            .map(|((name, &index), offset)| (offset, &name[..], index));
+
+        for (offset, name, index) in dots.into_iter().chain(rest).skip(offset as usize) {
+            if !entry(self.dir_entry(offset, name, index)?, &self.stat_node(index))? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn readlink(&self, inode: &ArchiveInode) -> io::Result<Vec<u8>> {
+        let node = self.node(inode.node);
+        if node.mode & libc::S_IFMT != libc::S_IFLNK {
+            return Err(err(libc::EINVAL));
+        }
+        self.contents(node)
+    }
+
+    fn mkdir(
+        &self,
+        _dir: &ArchiveInode,
+        _name: &CStr,
+        _mode: u32,
+        _uid: u32,
+        _gid: u32,
+    ) -> io::Result<libc::stat64> {
+        Err(err(libc::EROFS))
+    }
+
+    fn link(&self, _inode: &ArchiveInode, _dir: &ArchiveInode, _name: &CStr) -> io::Result<()> {
+        Err(err(libc::EROFS))
+    }
+
+    fn rename(
+        &self,
+        _olddir: &ArchiveInode,
+        _oldname: &CStr,
+        _newdir: &ArchiveInode,
+        _newname: &CStr,
+    ) -> io::Result<()> {
+        Err(err(libc::EROFS))
+    }
+
+    fn unlink(&self, _dir: &ArchiveInode, _name: &CStr, _flags: libc::c_int) -> io::Result<()> {
+        Err(err(libc::EROFS))
+    }
+
+    fn parent(
+        &self,
+        _export: &Export,
+        inode: &ArchiveInode,
+        _walk_path: &Path,
+    ) -> io::Result<(ArchiveInode, CString)> {
+        if inode.node == inode.root {
+            return Err(err(libc::EBUSY));
+        }
+        let node = self.node(inode.node);
+        let name = CString::new(node.name.clone()).map_err(|_| err(libc::EINVAL))?;
+        let parent = ArchiveInode {
+            node: node.parent,
+            root: inode.root,
+        };
+        Ok((parent, name))
+    }
+
+    fn statfs(&self, _inode: &ArchiveInode) -> io::Result<Rstatfs> {
+        // Report the file system that the archive is on, without any room left in it.
+        let mut buf = mem::MaybeUninit::zeroed();
+
+        // Safe because this will only modify `out` and we check the return value.
+        syscall!(unsafe { libc::fstatfs64(self.file.as_raw_fd(), buf.as_mut_ptr()) })?;
+
+        // Safe because this only has integer types and any value is valid.
+        let out: libc::statfs64 = unsafe { buf.assume_init() };
+        Ok(Rstatfs {
+            ty: out.f_type as u32,
+            bsize: out.f_bsize as u32,
+            blocks: self.file.metadata()?.len().div_ceil(out.f_bsize as u64),
+            bfree: 0,
+            bavail: 0,
+            files: self.nodes.len() as u64,
+            ffree: 0,
+            fsid: 0,
+            namelen: out.f_namelen as u32,
+        })
+    }
+
+    fn fsync(&self, _handle: &ArchiveHandle, _datasync: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn keep_fds(&self) -> Vec<RawFd> {
+        vec![self.file.as_raw_fd()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    #[test]
+    fn share_inflated() {
+        let content = b"hello, hello, hello, world!\n";
+        let deflated = [
+            0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa1, 0xca, 0xf3, 0x8b, 0x72,
+            0x52, 0x14, 0xb9, 0x00,
+        ];
+        // Safe because this doesn't touch any memory other than the name, and the file
+        // descriptor is checked and then owned by the `File`.
+        let mut file = unsafe {
+            let fd = libc::memfd_create(c"archive".as_ptr(), libc::MFD_CLOEXEC);
+            assert!(fd >= 0, "failed to create file");
+            File::from_raw_fd(fd)
+        };
+        file.write_all(&deflated).expect("failed to write archive");
+
+        let mut root = Node::new(libc::S_IFDIR | 0o755);
+        root.data = Data::Dir(BTreeMap::from([(b"file".to_vec(), 1)]));
+        let mut node = Node::new(libc::S_IFREG | 0o644);
+        node.size = content.len() as u64;
+        node.data = Data::Deflated {
+            offset: 0,
+            compressed: deflated.len() as u64,
+            crc: crc32(content),
+        };
+        let archive = Archive {
+            file,
+            nodes: vec![root, node],
+            inflated: Mutex::new(BTreeMap::new()),
+        };
+
+        let inode = ArchiveInode { node: 1, root: 0 };
+        let open = || {
+            archive
+                .open(&inode, P9_RDONLY)
+                .expect("failed to open file")
+        };
+        let (a, b) = (open(), open());
+        let (a, b) = (a.contents.unwrap(), b.contents.unwrap());
+        assert_eq!(&*a, content);
+        assert!(Arc::ptr_eq(&a, &b));
+
+        // The data goes away with the last handle.
+        let weak = Arc::downgrade(&a);
+        drop((a, b));
+        assert!(weak.upgrade().is_none());
+        assert_eq!(&*open().contents.unwrap(), content);
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::protocol::Rgetattr;
+
+// How many files a cache holds the attributes of at most.
+const MAX_ENTRIES: usize = 1024;
+
+// The attributes that Tgetattr recently got for files, by the path of their qid, so that asking
+// again right away doesn't have to go to the host. Changes made through the server drop the
+// attributes of what they changed, and changes made on the host show up once they are `ttl` old.
+pub(super) struct AttrCache {
+    ttl: Duration,
+    entries: BTreeMap<u64, (Instant, Rgetattr)>,
+    // The entries in the order that they were added, oldest first, which is also the order
+    // that they expire in. Entries that were dropped or replaced since are still in it.
+    order: VecDeque<(Instant, u64)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl AttrCache {
+    pub(super) fn new(ttl: Duration) -> AttrCache {
+        AttrCache {
+            ttl,
+            entries: BTreeMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Returns the attributes of the file with qid path `qid`, if it is known and they are cached,
+    // haven't expired by `now` and include all of those in `request_mask`.
+    pub(super) fn get(
+        &mut self,
+        qid: Option<u64>,
+        request_mask: u64,
+        now: Instant,
+    ) -> Option<Rgetattr> {
+        let hit = qid
+            .and_then(|qid| self.entries.get(&qid))
+            .filter(|(added, attr)| {
+                now.duration_since(*added) < self.ttl && attr.valid & request_mask == request_mask
+            })
+            .map(|&(_, attr)| attr);
+        match hit {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        hit
+    }
+
+    // Caches `attr` as the attributes of the file that it is for as of `now`.
+    pub(super) fn insert(&mut self, attr: Rgetattr, now: Instant) {
+        let qid = attr.qid.path;
+        self.entries.insert(qid, (now, attr));
+        self.order.push_back((now, qid));
+
+        while let Some(&(added, qid)) = self.order.front() {
+            if self.entries.len() <= MAX_ENTRIES && now.duration_since(added) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            if self.entries.get(&qid).is_some_and(|&(at, _)| at == added) {
+                self.entries.remove(&qid);
+            }
+        }
+    }
+
+    // Drops the attributes of the file with qid path `qid`.
+    pub(super) fn remove(&mut self, qid: u64) {
+        self.entries.remove(&qid);
+    }
+
+    // Drops the attributes of every file.
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    // Returns how many Tgetattrs were answered from the cache.
+    pub(super) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    // Returns how many Tgetattrs had to go to the host.
+    pub(super) fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Qid;
+
+    fn attr(path: u64, valid: u64) -> Rgetattr {
+        Rgetattr {
+            valid,
+            qid: Qid {
+                ty: 0,
+                version: 0,
+                path,
+            },
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+            atime_sec: 0,
+            atime_nsec: 0,
+            mtime_sec: 0,
+            mtime_nsec: 0,
+            ctime_sec: 0,
+            ctime_nsec: 0,
+            btime_sec: 0,
+            btime_nsec: 0,
+            gen: 0,
+            data_version: 0,
+        }
+    }
+
+    #[test]
+    fn expiry_and_bounds() {
+        let ttl = Duration::from_secs(1);
+        let mut cache = AttrCache::new(ttl);
+        let now = Instant::now();
+
+        cache.insert(attr(1, 0b11), now);
+        assert!(cache.get(Some(1), 0b01, now).is_some());
+        // Attributes that weren't asked for last time, and ones that are too old, aren't there.
+        assert!(cache.get(Some(1), 0b100, now).is_none());
+        assert!(cache.get(Some(1), 0b01, now + ttl).is_none());
+        assert!(cache.get(Some(2), 0b01, now).is_none());
+        assert!(cache.get(None, 0b01, now).is_none());
+        assert_eq!((cache.hits(), cache.misses()), (1, 4));
+
+        cache.remove(1);
+        assert!(cache.get(Some(1), 0b01, now).is_none());
+
+        // The oldest attributes make room for new ones.
+        for path in 0..MAX_ENTRIES as u64 + 10 {
+            cache.insert(attr(path, 1), now);
+        }
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(cache.get(Some(9), 1, now).is_none());
+        assert!(cache.get(Some(10), 1, now).is_some());
+
+        cache.clear();
+        assert!(cache.get(Some(10), 1, now).is_none());
+    }
+}
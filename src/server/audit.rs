@@ -0,0 +1,186 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Audit events, which tell an `AuditSink` about every request through which a client changed,
+//! or tried to change, an exported tree.
+
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use super::*;
+
+/// Who a client attached as, from the uname and n_uname of its Tattach.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub uname: String,
+    pub n_uname: Option<u32>,
+}
+
+/// What kind of change a request made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOp {
+    /// Opening a file in a way that could change it, like for writing or to truncate it.
+    Open,
+    /// Creating a file, directory, symlink, device node or hard link.
+    Create,
+    /// Writing to a file, or allocating or copying its blocks.
+    Write,
+    /// Renaming a file. The old path comes before the new one.
+    Rename,
+    /// Removing a file or directory.
+    Remove,
+    /// Changing the mode, owner, size or timestamps of a file.
+    SetAttr,
+    /// Setting or removing an extended attribute.
+    SetXattr,
+}
+
+/// A request through which a client changed an exported tree, or tried to.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    /// When the server was done with the request.
+    pub time: SystemTime,
+    /// Who the client attached as to the tree that the request was for.
+    pub identity: Arc<Identity>,
+    pub op: AuditOp,
+    /// The root on the host of the export that the request was for.
+    pub root: Box<Path>,
+    /// The paths of the files that the request changed, from `root`, as they were walked to by
+    /// the client like those that `Config::rules` match.
+    pub paths: Vec<PathBuf>,
+    /// The errno that the request failed with, if it did.
+    pub error: Option<i32>,
+}
+
+/// Receives the audit events of a `Server`.
+pub trait AuditSink: Send {
+    /// Records `event`. This is called on a thread of its own, in the order in which the
+    /// requests were handled.
+    fn record(&mut self, event: AuditEvent);
+}
+
+// An audit event for a request that is still being handled.
+pub(super) struct PendingAudit {
+    identity: Arc<Identity>,
+    op: AuditOp,
+    root: Box<Path>,
+    paths: Vec<PathBuf>,
+}
+
+// Returns what kind of change `msg` makes, if it is one that is audited at all.
+fn audit_op(msg: &Tmessage) -> Option<AuditOp> {
+    let op = match msg {
+        Tmessage::Lopen(lopen) if lopen.flags & P9_TMPFILE == P9_TMPFILE => AuditOp::Create,
+        Tmessage::Open(_) | Tmessage::Lopen(_) => AuditOp::Open,
+        Tmessage::Create(_)
+        | Tmessage::Lcreate(_)
+        | Tmessage::Symlink(_)
+        | Tmessage::Mknod(_)
+        | Tmessage::Mkdir(_)
+        | Tmessage::Link(_) => AuditOp::Create,
+        Tmessage::Write(_)
+        | Tmessage::CopyRange(_)
+        | Tmessage::CloneRange(_)
+        | Tmessage::Fallocate(_) => AuditOp::Write,
+        Tmessage::Wstat(wstat) if !wstat.stat.name.is_empty() => AuditOp::Rename,
+        Tmessage::Rename(_) | Tmessage::RenameAt(_) | Tmessage::RenameAt2(_) => AuditOp::Rename,
+        Tmessage::Remove(_) | Tmessage::UnlinkAt(_) => AuditOp::Remove,
+        Tmessage::Wstat(_) | Tmessage::SetAttr(_) => AuditOp::SetAttr,
+        Tmessage::XattrCreate(_) => AuditOp::SetXattr,
+        _ => return None,
+    };
+    Some(op)
+}
+
+impl<F: FileSystem> Server<F> {
+    /// Sends an audit event to `sink` for every request that changes, or tries to change, an
+    /// exported tree, except in a dry run. `sink` runs on a thread of its own, which the server
+    /// hands events to through a queue of `capacity` events. Requests wait for room in the queue
+    /// when it is full, so that no event is lost.
+    pub fn set_audit_sink(
+        &mut self,
+        mut sink: Box<dyn AuditSink>,
+        capacity: usize,
+    ) -> io::Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        thread::Builder::new()
+            .name("p9-audit".to_string())
+            .spawn(move || {
+                for event in receiver {
+                    sink.record(event);
+                }
+            })?;
+        self.audit = Some(sender);
+        Ok(())
+    }
+
+    // Starts the audit event for `msg`, if it gets one.
+    pub(super) fn start_audit(&self, msg: &Tmessage) -> Option<PendingAudit> {
+        if self.audit.is_none() || self.cfg.dry_run.is_some() {
+            return None;
+        }
+        let fid = self.changed_fid(msg)?;
+        let op = match msg {
+            // A Tclunk only changes anything when it removes its file or sets an attribute.
+            Tmessage::Clunk(_) if lock_fid(&self.fids.get(fid)?).remove_on_close => AuditOp::Remove,
+            Tmessage::Clunk(_) => AuditOp::SetXattr,
+            msg => audit_op(msg)?,
+        };
+
+        let mut paths = Vec::new();
+        for (path, needed) in self.accessed_paths(msg) {
+            if needed > Access::Read && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        self.pending_audit(op, fid, paths)
+    }
+
+    // Starts an audit event for a change of kind `op` through `fid` to the files at `paths`,
+    // or to that of `fid` if there are none.
+    pub(super) fn pending_audit(
+        &self,
+        op: AuditOp,
+        fid: u32,
+        mut paths: Vec<PathBuf>,
+    ) -> Option<PendingAudit> {
+        self.audit.as_ref()?;
+        let fid = self.fids.get(fid)?;
+        let fid = lock_fid(&fid);
+        if paths.is_empty() {
+            paths.push(fid.walk_path.clone());
+        }
+        Some(PendingAudit {
+            identity: fid.identity.clone(),
+            op,
+            root: fid.export.root.clone(),
+            paths,
+        })
+    }
+
+    // Sends the audit event of a request that was replied to with `rmsg`.
+    pub(super) fn finish_audit(&self, audit: PendingAudit, rmsg: &io::Result<Rmessage>) {
+        let event = AuditEvent {
+            time: SystemTime::now(),
+            identity: audit.identity,
+            op: audit.op,
+            root: audit.root,
+            paths: audit.paths,
+            error: rmsg
+                .as_ref()
+                .err()
+                .map(|e| e.raw_os_error().unwrap_or(libc::EIO)),
+        };
+        if let Some(sender) = self.audit.as_ref() {
+            // The sink only goes away if it panicked, after which there is nowhere to send the
+            // event anyway.
+            let _ = sender.send(event);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+
+use super::Squash;
+
+/// Decides which clients are allowed to attach to a `Server`.
+///
+/// Once a server has an authenticator, clients must establish an auth fid with Tauth and carry
+/// out the authentication protocol by reading from and writing to it. Only an auth fid that has
+/// been successfully authenticated may be passed to a Tattach, and only for the same user and
+/// tree that were named in the Tauth.
+pub trait Authenticator: Send {
+    /// Starts authenticating the user `uname`, whose numeric id is `n_uname` if the client sent
+    /// one, for attaching to the tree `aname`. Returning an error rejects the Tauth.
+    fn start(
+        &mut self,
+        uname: &str,
+        n_uname: Option<u32>,
+        aname: &str,
+    ) -> io::Result<Box<dyn AuthSession>>;
+}
+
+/// A single authentication conversation, carried out over an auth fid.
+pub trait AuthSession: Send {
+    /// Fills `buf` with the next message for the client, e.g. a challenge, and returns its length.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Consumes a message from the client, e.g. the response to a challenge, and returns the
+    /// number of bytes that were accepted.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Returns true once the client has proven its identity.
+    fn is_authenticated(&self) -> bool;
+}
+
+/// What the hook from `Server::set_attach_hook` lets a client have of the tree it attaches to,
+/// on top of the options of its export. They apply to everything that the client walks to from
+/// there.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AttachOptions {
+    /// Refuses changes to the tree with EROFS, as with `Config::read_only`.
+    pub read_only: bool,
+    /// Squashes every identity into one, as with `Config::all_squash`, instead of what the
+    /// export does.
+    pub all_squash: Option<Squash>,
+}
+
+// What `Server::set_attach_hook` takes.
+pub(super) type AttachHook =
+    Box<dyn FnMut(&str, Option<u32>, &str) -> io::Result<AttachOptions> + Send>;
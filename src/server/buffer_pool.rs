@@ -0,0 +1,92 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// How many free buffers a pool holds on to. A server only has one request in hand at a time, so
+// it never needs more than a few.
+const MAX_FREE: usize = 4;
+
+// The buffers that a server reads requests into and encodes replies into, which are given back
+// once it is done with them so that the next message doesn't have to allocate new ones.
+pub(super) struct BufferPool {
+    free: Vec<Vec<u8>>,
+    // How much every buffer can hold without growing, which is enough for any frame the
+    // server can send or receive.
+    size: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl BufferPool {
+    pub(super) fn new(size: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::new(),
+            size,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Returns an empty buffer that can hold at least `size` bytes without growing.
+    pub(super) fn get(&mut self) -> Vec<u8> {
+        match self.free.pop() {
+            Some(buf) => {
+                self.hits += 1;
+                buf
+            }
+            None => {
+                self.misses += 1;
+                Vec::with_capacity(self.size)
+            }
+        }
+    }
+
+    // Gives `buf` back to the pool. Buffers that are too small, like those that didn't come from
+    // the pool, are dropped instead.
+    pub(super) fn put(&mut self, mut buf: Vec<u8>) {
+        if buf.capacity() >= self.size && self.free.len() < MAX_FREE {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+
+    // Returns how many buffers were handed out from the pool.
+    pub(super) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    // Returns how many buffers had to be allocated because the pool had none free.
+    pub(super) fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let mut pool = BufferPool::new(64);
+        let mut buf = pool.get();
+        assert!(buf.capacity() >= 64);
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!((pool.hits(), pool.misses()), (0, 1));
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!((pool.hits(), pool.misses()), (1, 1));
+
+        // Buffers too small to be handed out again aren't kept, and neither are any past the
+        // limit.
+        pool.put(Vec::with_capacity(16));
+        assert!(pool.free.is_empty());
+        for _ in 0..MAX_FREE + 2 {
+            pool.put(Vec::with_capacity(64));
+        }
+        assert_eq!(pool.free.len(), MAX_FREE);
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Merging Twrites that follow on from each other into one, so that a client that writes a file
+//! a little at a time doesn't cost the host a write for every one.
+
+use std::time::Duration;
+use std::time::Instant;
+
+// How long the data of writes may wait before it is written to the file, whatever else the
+// client does. Clients that stop writing without closing or syncing the file get it written the
+// next time that the server gets a request, or when the transport calls `Server::flush_writes`.
+pub(super) const COALESCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The data of the writes to a fid that were replied to but haven't been written to its file yet,
+// which all follow on from each other.
+pub(super) struct PendingWrite {
+    pub(super) offset: u64,
+    pub(super) data: Vec<u8>,
+    // When the first of the writes came in.
+    pub(super) since: Instant,
+    // The device and inode number of the file, so that writes to it through other fids can
+    // have these written first.
+    pub(super) file: (u64, u64),
+}
+
+impl PendingWrite {
+    pub(super) fn new(offset: u64, data: &[u8], file: (u64, u64)) -> PendingWrite {
+        PendingWrite {
+            offset,
+            data: data.to_vec(),
+            since: Instant::now(),
+            file,
+        }
+    }
+
+    // Adds a write of `data` at `offset` to the end, if it starts right where the others end and
+    // there is room for it under `limit`. Returns whether it was added.
+    pub(super) fn append(&mut self, offset: u64, data: &[u8], limit: usize) -> bool {
+        let end = self.offset + self.data.len() as u64;
+        if offset != end || self.data.len() + data.len() > limit {
+            return false;
+        }
+        self.data.extend_from_slice(data);
+        true
+    }
+
+    // Returns whether the data has waited long enough by `now`.
+    pub(super) fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.since) >= COALESCE_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append() {
+        let mut pending = PendingWrite::new(10, b"abc", (1, 2));
+        assert!(pending.append(13, b"de", 8));
+        // Writes that overlap, leave a hole or go over the limit aren't merged.
+        assert!(!pending.append(14, b"x", 8));
+        assert!(!pending.append(16, b"x", 8));
+        assert!(!pending.append(15, b"xyzw", 8));
+        assert!(pending.append(15, b"xyz", 8));
+        assert_eq!((pending.offset, &pending.data[..]), (10, &b"abcdexyz"[..]));
+
+        assert!(!pending.expired(pending.since));
+        assert!(pending.expired(pending.since + COALESCE_TIMEOUT));
+    }
+}
@@ -0,0 +1,474 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The control tree, a small synthetic tree that clients attach to with the aname in
+//! `Config::control`. Its files are generated by the server rather than served by the file
+//! system:
+//!
+//! - `stats` counts the requests that the server has handled and the bytes that it moved.
+//! - `connection` describes the protocol that was negotiated with the client.
+//! - `fids` lists the fids of the exported trees, along with the files that they refer to.
+//! - `ctl` holds the options that can be changed at runtime, one per line as "<name> <value>".
+//!   Writing lines of the same form to it changes them, if `Config::control_writable` allows it.
+//!
+//! The contents of a file are generated when it is opened, so reads see a consistent snapshot.
+
+use std::fmt::Write as _;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::*;
+
+fn err(errno: libc::c_int) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Root,
+    Ctl,
+    Stats,
+    Connection,
+    Fids,
+}
+
+// The files in the root of the control tree, in the order that they are listed in.
+const FILES: [(&str, Node); 4] = [
+    ("connection", Node::Connection),
+    ("ctl", Node::Ctl),
+    ("fids", Node::Fids),
+    ("stats", Node::Stats),
+];
+
+impl Node {
+    fn lookup(self, name: &[u8]) -> io::Result<Node> {
+        if self != Node::Root {
+            return Err(err(libc::ENOTDIR));
+        }
+        match name {
+            b"." | b".." => Ok(Node::Root),
+            name => FILES
+                .iter()
+                .find(|(file, _)| file.as_bytes() == name)
+                .map(|&(_, node)| node)
+                .ok_or_else(|| err(libc::ENOENT)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        FILES
+            .iter()
+            .find(|&&(_, node)| node == self)
+            .map_or("/", |&(name, _)| name)
+    }
+}
+
+// A fid that refers to a file of the control tree.
+pub(super) struct ControlFid {
+    node: Node,
+    // The Tlopen flags and the contents of the file as of when it was opened, once it has been.
+    open: Option<(u32, Vec<u8>)>,
+}
+
+// Counts what a server has done since it was created.
+pub(super) struct Stats {
+    started: SystemTime,
+    requests: u64,
+    errors: u64,
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+impl Stats {
+    pub(super) fn new() -> Stats {
+        Stats {
+            started: SystemTime::now(),
+            requests: 0,
+            errors: 0,
+            read_bytes: 0,
+            written_bytes: 0,
+        }
+    }
+
+    // Counts a request that was answered with `rmsg`.
+    pub(super) fn record(&mut self, rmsg: &io::Result<Rmessage>) {
+        self.requests += 1;
+        match rmsg {
+            Ok(Rmessage::Read(read)) => self.read_bytes += read.data.len() as u64,
+            Ok(Rmessage::Write(write)) => self.written_bytes += u64::from(write.count),
+            Ok(_) => {}
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    // Counts the data of an Rread that is sent after it rather than in it, which `record` doesn't
+    // see.
+    pub(super) fn record_read_data(&mut self, bytes: u64) {
+        self.read_bytes += bytes;
+    }
+}
+
+// Parses the value of a boolean option.
+fn parse_bool(value: &str) -> io::Result<bool> {
+    value.parse().map_err(|_| err(libc::EINVAL))
+}
+
+impl<F: FileSystem> Server<F> {
+    // Returns the reply to `msg` if it is for a fid of the control tree. Requests that the tree
+    // doesn't support are left to fail like they would for any fid that the file system doesn't
+    // know of.
+    pub(super) fn control_request(&mut self, msg: &Tmessage) -> Option<io::Result<Rmessage>> {
+        let fid = match msg {
+            Tmessage::Walk(walk) => walk.fid,
+            Tmessage::Lopen(lopen) => lopen.fid,
+            Tmessage::Open(open) => open.fid,
+            Tmessage::Read(read) => read.fid,
+            Tmessage::Write(write) => write.fid,
+            Tmessage::Clunk(clunk) => clunk.fid,
+            Tmessage::Remove(remove) => remove.fid,
+            Tmessage::GetAttr(get_attr) => get_attr.fid,
+            Tmessage::SetAttr(set_attr) => set_attr.fid,
+            Tmessage::Stat(stat) => stat.fid,
+            Tmessage::Wstat(wstat) => wstat.fid,
+            Tmessage::Statfs(statfs) => statfs.fid,
+            Tmessage::Readdir(readdir) => readdir.fid,
+            Tmessage::XattrWalk(xattr_walk) => xattr_walk.fid,
+            Tmessage::Fsync(fsync) => fsync.fid,
+            _ => return None,
+        };
+        let node = self.control_fids.get(&fid)?.node;
+
+        Some(match msg {
+            Tmessage::Walk(walk) => self.control_walk(walk, node).map(Rmessage::Walk),
+            Tmessage::Lopen(lopen) => self
+                .control_open(lopen.fid, node, lopen.flags)
+                .map(|qid| Rmessage::Lopen(Rlopen { qid, iounit: 0 })),
+            Tmessage::Open(open) => self
+                .control_open(open.fid, node, omode_to_p9_flags(open.mode))
+                .map(|qid| Rmessage::Open(Ropen { qid, iounit: 0 })),
+            Tmessage::Read(read) => self.control_read(read).map(Rmessage::Read),
+            Tmessage::Write(write) => self.control_write(write).map(Rmessage::Write),
+            Tmessage::Clunk(clunk) => {
+                self.control_fids.remove(&clunk.fid);
+                Ok(Rmessage::Clunk)
+            }
+            Tmessage::Remove(remove) => {
+                // The fid is clunked even though the remove fails.
+                self.control_fids.remove(&remove.fid);
+                Err(err(libc::EPERM))
+            }
+            Tmessage::GetAttr(_) => Ok(Rmessage::GetAttr(stat_to_attr(&self.control_stat(node)))),
+            // Opening a file with O_TRUNC truncates it first, which is how the shell writes to
+            // one, so that is allowed for `ctl` but does nothing.
+            Tmessage::SetAttr(set_attr)
+                if node == Node::Ctl
+                    && self.cfg.control_writable
+                    && set_attr.valid & !P9_SETATTR_SIZE == 0 =>
+            {
+                Ok(Rmessage::SetAttr)
+            }
+            Tmessage::SetAttr(_) | Tmessage::Wstat(_) => Err(err(libc::EPERM)),
+            Tmessage::Stat(_) => self
+                .control_p9_stat(node)
+                .map(|stat| Rmessage::Stat(Rstat { stat })),
+            Tmessage::Statfs(_) => Ok(Rmessage::Statfs(Rstatfs {
+                ty: 0,
+                bsize: 4096,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: FILES.len() as u64 + 1,
+                ffree: 0,
+                fsid: 0,
+                namelen: 255,
+            })),
+            Tmessage::Readdir(readdir) => self.control_readdir(readdir).map(Rmessage::Readdir),
+            Tmessage::XattrWalk(_) => Err(err(libc::EOPNOTSUPP)),
+            Tmessage::Fsync(_) => Ok(Rmessage::Fsync),
+            _ => unreachable!("not a control tree request"),
+        })
+    }
+
+    // Attaches `fid` to the root of the control tree.
+    pub(super) fn control_attach(&mut self, fid: u32) -> io::Result<Rattach> {
+        if self.fids.contains(fid) {
+            return Err(ebadf());
+        }
+        self.control_fids.insert(
+            fid,
+            ControlFid {
+                node: Node::Root,
+                open: None,
+            },
+        );
+        Ok(Rattach {
+            qid: self.control_stat(Node::Root).into(),
+        })
+    }
+
+    fn control_walk(&mut self, walk: &Twalk, start: Node) -> io::Result<Rwalk> {
+        // `newfid` must not currently be in use unless it is the same as `fid`.
+        if walk.fid != walk.newfid && self.fid_in_use(walk.newfid) {
+            return Err(ebadf());
+        }
+        self.check_fid_limit(walk.newfid)?;
+        if walk.wnames.len() > MAXWELEM {
+            return Err(err(libc::EINVAL));
+        }
+
+        let mut node = start;
+        let mut wqids = Vec::with_capacity(walk.wnames.len());
+        for wname in &walk.wnames {
+            match node.lookup(wname.as_bytes()) {
+                Ok(next) => node = next,
+                // Only an error on the first name fails the walk.
+                Err(e) if wqids.is_empty() => return Err(e),
+                Err(_) => return Ok(Rwalk { wqids }),
+            }
+            wqids.push(self.control_stat(node).into());
+        }
+
+        self.control_fids
+            .insert(walk.newfid, ControlFid { node, open: None });
+        Ok(Rwalk { wqids })
+    }
+
+    fn control_open(&mut self, fid: u32, node: Node, flags: u32) -> io::Result<Qid> {
+        let writable = flags & P9_NOACCESS != P9_RDONLY;
+        match node {
+            Node::Root if writable => return Err(err(libc::EISDIR)),
+            Node::Root => {}
+            _ if flags & P9_DIRECTORY != 0 => return Err(err(libc::ENOTDIR)),
+            Node::Ctl if self.cfg.control_writable => {}
+            _ if writable => return Err(err(libc::EACCES)),
+            _ => {}
+        }
+
+        let contents = self.control_contents(node);
+        let qid = self.control_stat(node).into();
+        if let Some(fid) = self.control_fids.get_mut(&fid) {
+            fid.open = Some((flags, contents));
+        }
+        Ok(qid)
+    }
+
+    fn control_read(&mut self, read: &Tread) -> io::Result<Rread> {
+        let capacity = self.read_capacity(read.count) as usize;
+        let fid = self.control_fids.get(&read.fid).ok_or_else(ebadf)?;
+        let (flags, ref contents) = *fid.open.as_ref().ok_or_else(ebadf)?;
+        if flags & P9_NOACCESS == P9_WRONLY {
+            return Err(ebadf());
+        }
+
+        if fid.node != Node::Root {
+            let start = min(read.offset, contents.len() as u64) as usize;
+            let end = min(start + capacity, contents.len());
+            return Ok(Rread {
+                data: Data(contents[start..end].to_vec()),
+            });
+        }
+
+        // Directories are read as a sequence of stats in the older dialects. Only whole stats
+        // are returned, so offsets always fall between them.
+        if self.dialect == Dialect::Linux {
+            return Err(err(libc::EISDIR));
+        }
+        let mut data = Vec::new();
+        let mut pos = 0;
+        for &(_, node) in &FILES {
+            let stat = self.control_p9_stat(node)?;
+            let len = stat.byte_size_dialect(self.dialect) as u64;
+            if pos >= read.offset {
+                if data.len() as u64 + len > capacity as u64 {
+                    break;
+                }
+                stat.encode_dialect(&mut data, self.dialect)?;
+            }
+            pos += len;
+        }
+        Ok(Rread { data: Data(data) })
+    }
+
+    fn control_write(&mut self, write: &Twrite) -> io::Result<Rwrite> {
+        let fid = self.control_fids.get(&write.fid).ok_or_else(ebadf)?;
+        let (flags, _) = fid.open.as_ref().ok_or_else(ebadf)?;
+        if fid.node != Node::Ctl || flags & P9_NOACCESS == P9_RDONLY {
+            return Err(ebadf());
+        }
+
+        let commands = std::str::from_utf8(&write.data).map_err(|_| err(libc::EINVAL))?;
+        for command in commands.lines().map(str::trim).filter(|c| !c.is_empty()) {
+            let (name, value) = command
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| err(libc::EINVAL))?;
+            self.control_set(name, value.trim())?;
+        }
+
+        Ok(Rwrite {
+            count: write.data.len() as u32,
+        })
+    }
+
+    // Changes the option `name` to `value`, as written to `ctl`.
+    fn control_set(&mut self, name: &str, value: &str) -> io::Result<()> {
+        match name {
+            // This applies to every export, including the fids that were already walked from
+            // them. Turning it off again leaves the exports that are configured to be read-only
+            // as they are.
+            "read_only" => self.read_only = parse_bool(value)?,
+            "idle_timeout" => {
+                self.cfg.idle_timeout = match value {
+                    "none" => None,
+                    secs => Some(Duration::from_secs(
+                        secs.parse().map_err(|_| err(libc::EINVAL))?,
+                    )),
+                };
+            }
+            _ => return Err(err(libc::EINVAL)),
+        }
+        Ok(())
+    }
+
+    fn control_readdir(&mut self, readdir: &Treaddir) -> io::Result<Rreaddir> {
+        let fid = self.control_fids.get(&readdir.fid).ok_or_else(ebadf)?;
+        if fid.node != Node::Root {
+            return Err(err(libc::ENOTDIR));
+        }
+        if fid.open.is_none() {
+            return Err(ebadf());
+        }
+
+        let count = self.read_capacity(readdir.count) as usize;
+        let dots = [(".", Node::Root), ("..", Node::Root)];
+        let mut data = Vec::new();
+        for (offset, (name, node)) in dots
+            .into_iter()
+            .chain(FILES)
+            .enumerate()
+            .skip(readdir.offset as usize)
+        {
+            let ty = if node == Node::Root {
+                libc::DT_DIR
+            } else {
+                libc::DT_REG
+            };
+            let dirent = Dirent {
+                qid: self.control_stat(node).into(),
+                offset: offset as u64 + 1,
+                ty,
+                name: P9String::new(name)?,
+            };
+            if data.len() + dirent.byte_size() as usize > count {
+                break;
+            }
+            dirent.encode(&mut data)?;
+        }
+        Ok(Rreaddir { data: Data(data) })
+    }
+
+    // Returns the metadata of `node`, as the file system would.
+    fn control_stat(&self, node: Node) -> libc::stat64 {
+        // Sizes are those of the contents that opening the file would get.
+        let (mode, nlink) = match node {
+            Node::Root => (libc::S_IFDIR | 0o555, 2),
+            Node::Ctl if self.cfg.control_writable => (libc::S_IFREG | 0o644, 1),
+            _ => (libc::S_IFREG | 0o444, 1),
+        };
+        let size = self.control_contents(node).len();
+        let started = self
+            .stats
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        // Safe because this only has integer fields, for which zero is a valid value.
+        let mut st: libc::stat64 = unsafe { mem::zeroed() };
+        st.st_ino = node as u64 + 1;
+        st.st_mode = mode;
+        st.st_nlink = nlink;
+        st.st_size = size as _;
+        st.st_blksize = 4096;
+        st.st_atime = started.as_secs() as _;
+        st.st_mtime = started.as_secs() as _;
+        st.st_ctime = started.as_secs() as _;
+        st
+    }
+
+    // Returns the 9P2000.u stat of `node`.
+    fn control_p9_stat(&self, node: Node) -> io::Result<Stat> {
+        let export = Export::new(Path::new("/"));
+        let st = self.control_stat(node);
+        to_stat(
+            &export,
+            &st,
+            st.into(),
+            || Ok(Vec::new()),
+            P9String::new(node.name())?,
+        )
+    }
+
+    // Generates the current contents of `node`.
+    fn control_contents(&self, node: Node) -> Vec<u8> {
+        let mut out = String::new();
+        // Writing to a String can't fail.
+        match node {
+            Node::Root => {}
+            Node::Ctl => {
+                let idle_timeout = self
+                    .cfg
+                    .idle_timeout
+                    .map_or("none".to_string(), |timeout| timeout.as_secs().to_string());
+                let _ = writeln!(out, "read_only {}", self.read_only);
+                let _ = writeln!(out, "idle_timeout {idle_timeout}");
+            }
+            Node::Stats => {
+                let uptime = self.stats.started.elapsed().unwrap_or_default();
+                let _ = writeln!(out, "uptime {}", uptime.as_secs());
+                let _ = writeln!(out, "requests {}", self.stats.requests);
+                let _ = writeln!(out, "errors {}", self.stats.errors);
+                let _ = writeln!(out, "read_bytes {}", self.stats.read_bytes);
+                let _ = writeln!(out, "written_bytes {}", self.stats.written_bytes);
+                let _ = writeln!(out, "buffer_pool_hits {}", self.buffers.hits());
+                let _ = writeln!(out, "buffer_pool_misses {}", self.buffers.misses());
+                if let Some(attrs) = self.attrs.as_ref() {
+                    let _ = writeln!(out, "attr_cache_hits {}", attrs.hits());
+                    let _ = writeln!(out, "attr_cache_misses {}", attrs.misses());
+                }
+                if let Some(missing) = self.missing.as_ref() {
+                    let _ = writeln!(out, "negative_cache_hits {}", missing.hits());
+                }
+            }
+            Node::Connection => {
+                let session = self
+                    .session
+                    .map_or("none".to_string(), |session| session.to_string());
+                let _ = writeln!(out, "version {}", self.extensions.version(self.dialect));
+                let _ = writeln!(out, "msize {}", self.msize);
+                let _ = writeln!(out, "session {session}");
+                let _ = writeln!(out, "fids {}", self.fids.len());
+            }
+            Node::Fids => {
+                // One line per fid with its open flags, the root of its export and the name of
+                // its file, which goes last since it may have spaces in it, in order of fid.
+                for (fid, f) in self.fids.entries() {
+                    let f = lock_fid(&f);
+                    let flags = match f.file {
+                        Some(_) => format!("{:o}", f.open_flags),
+                        None => "-".to_string(),
+                    };
+                    let name = self
+                        .fs
+                        .file_name(&f.export, &f.path, &f.walk_path)
+                        .map(|name| String::from_utf8_lossy(name.as_bytes()).into_owned())
+                        .unwrap_or_else(|_| "?".to_string());
+                    let _ = writeln!(out, "{fid} {flags} {} {name}", f.export.root.display());
+                }
+            }
+        }
+        out.into_bytes()
+    }
+}
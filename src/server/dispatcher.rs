@@ -0,0 +1,448 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Handling the requests of a connection on several threads, so that one that takes long, like a
+//! Tfsync of a big file, doesn't hold up the others.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::thread;
+
+use super::*;
+
+// The part of a request that a handler left to the dispatcher, to run on a worker without the
+// server. It gets the token of the request and returns its reply.
+pub(super) type Job = Box<dyn FnOnce(&CancelToken) -> io::Result<Rmessage> + Send>;
+
+// A request whose handler left a `Job` to the dispatcher.
+pub(super) struct Deferred {
+    pub(super) tag: u16,
+    pub(super) cancel: CancelToken,
+    pub(super) job: Job,
+}
+
+// How many requests per worker can wait in the queue, after which the rest are left in the
+// transport until a worker takes one.
+const QUEUED_PER_WORKER: usize = 4;
+
+// Returns the fids that `msg` is for, whose requests must be handled in the order that they came
+// in, or nothing if it isn't for any.
+fn request_fids(msg: &Tmessage) -> Vec<u32> {
+    match msg {
+        Tmessage::Attach(attach) => vec![attach.fid],
+        Tmessage::Auth(auth) => vec![auth.afid],
+        Tmessage::Clunk(clunk) => vec![clunk.fid],
+        Tmessage::Remove(remove) => vec![remove.fid],
+        Tmessage::Walk(walk) => vec![walk.fid, walk.newfid],
+        msg => used_fids(msg),
+    }
+}
+
+// A request that waits for a worker, and the fids that it is for.
+struct Queued {
+    tag: u16,
+    msg: io::Result<Tmessage>,
+    fids: Vec<u32>,
+}
+
+// The requests that wait for a worker. A request is only taken once no worker is running one for
+// any of its fids and no request before it is for them either, so that the requests for a fid are
+// handled one at a time and in order while the ones for other fids go on.
+#[derive(Default)]
+struct Queue {
+    requests: VecDeque<Queued>,
+    // The fids that workers are running a request for.
+    busy: BTreeSet<u32>,
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    fn push(&mut self, request: Queued) {
+        self.requests.push_back(request);
+    }
+
+    // Takes the first request that can be run, if there is one, and marks its fids busy.
+    fn pop(&mut self) -> Option<Queued> {
+        let mut blocked = self.busy.clone();
+        let index = self.requests.iter().position(|request| {
+            let runnable = request.fids.iter().all(|fid| !blocked.contains(fid));
+            blocked.extend(&request.fids);
+            runnable
+        })?;
+        let request = self.requests.remove(index)?;
+        self.busy.extend(&request.fids);
+        Some(request)
+    }
+
+    // Marks the fids of a request that a worker is done with as no longer busy.
+    fn done(&mut self, fids: &[u32]) {
+        for fid in fids {
+            self.busy.remove(fid);
+        }
+    }
+}
+
+struct State<W> {
+    writer: W,
+    // The requests that wait for a worker.
+    queue: Queue,
+    // The tags of the requests that were queued and haven't been replied to, and the fids that
+    // each is for.
+    inflight: BTreeMap<u16, Vec<u32>>,
+    // The first error that a worker got while replying, after which the connection is no use.
+    error: Option<io::Error>,
+    // Set once there are no more requests to read.
+    done: bool,
+}
+
+impl<W> State<W> {
+    fn queue(&mut self, request: Queued) {
+        self.inflight.insert(request.tag, request.fids.clone());
+        self.queue.push(request);
+    }
+}
+
+struct Shared<'a, F: FileSystem, W> {
+    server: &'a Mutex<Server<F>>,
+    state: Mutex<State<W>>,
+    // Signalled whenever a request is taken off the queue or replied to.
+    idle: Condvar,
+    // Signalled whenever a request is queued, a worker is done with a request or there are no
+    // more requests to read.
+    work: Condvar,
+}
+
+impl<F: FileSystem, W: Write> Shared<'_, F, W> {
+    // Handles `msg` with the server and sends the reply, unless its handler left part of it to
+    // the dispatcher, which is returned instead.
+    fn handle(&self, tag: u16, msg: io::Result<Tmessage>, offload: bool) -> Option<Deferred> {
+        let mut server = self.server.lock().unwrap();
+        // Middlewares have to see the reply that goes out.
+        server.offload = offload && server.middleware.is_empty();
+        let response = server.handle_request(tag, |server| server.dispatch(msg));
+        server.offload = false;
+
+        if let Some(deferred) = server.deferred.take() {
+            return Some(deferred);
+        }
+        self.reply(server, tag, response);
+        None
+    }
+
+    // Runs queued requests on a worker until there are no more.
+    fn work(&self) {
+        loop {
+            let Queued { tag, msg, fids } = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if let Some(request) = state.queue.pop() {
+                        break request;
+                    }
+                    if state.done && state.queue.len() == 0 {
+                        return;
+                    }
+                    state = self.work.wait(state).unwrap();
+                }
+            };
+            // There is room in the queue again.
+            self.idle.notify_all();
+
+            // The worker runs the rest of the request itself, while the others go on with the
+            // requests for other fids.
+            if let Some(deferred) = self.handle(tag, msg, true) {
+                self.finish(deferred);
+            }
+            self.state.lock().unwrap().queue.done(&fids);
+            self.work.notify_all();
+        }
+    }
+
+    // Runs the part of a request that its handler left to the dispatcher, without the server,
+    // and sends the reply.
+    fn finish(&self, Deferred { tag, cancel, job }: Deferred) {
+        let rmsg = job(&cancel);
+        let mut server = self.server.lock().unwrap();
+        let response = server.finish_request(tag, &cancel, rmsg);
+        self.reply(server, tag, response);
+    }
+
+    fn reply(&self, mut server: MutexGuard<'_, Server<F>>, tag: u16, response: Option<Rframe>) {
+        // Replies are sent with the server locked, so that a Tflush for the request can't be
+        // replied to in between.
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = server.send_response(response, &mut state.writer) {
+            state.error.get_or_insert(e);
+        }
+        state.inflight.remove(&tag);
+        self.idle.notify_all();
+    }
+
+    // Waits until fewer than `max` requests wait in the queue.
+    fn wait_queue(&self, max: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.queue.len() >= max {
+            state = self.idle.wait(state).unwrap();
+        }
+    }
+
+    // Waits until fewer than `max` of the requests that were queued haven't been replied to.
+    fn wait_inflight(&self, max: usize) -> MutexGuard<'_, State<W>> {
+        let mut state = self.state.lock().unwrap();
+        while state.inflight.len() >= max {
+            state = self.idle.wait(state).unwrap();
+        }
+        state
+    }
+
+    // Waits until every request that was queued has been replied to.
+    fn wait_idle(&self) -> MutexGuard<'_, State<W>> {
+        self.wait_inflight(1)
+    }
+
+    // Ends the session once the client has been idle for longer than the configured
+    // `idle_timeout`, after which the connection fails with `ErrorKind::TimedOut`. Returns when
+    // it has, or when there are no more requests to read.
+    fn reap_idle(&self) {
+        loop {
+            let mut server = self.server.lock().unwrap();
+            let mut state = self.state.lock().unwrap();
+            if state.done {
+                return;
+            }
+            // A request that takes longer than the timeout doesn't make the client idle.
+            if state.inflight.is_empty() && server.reap_idle() {
+                state.error.get_or_insert_with(|| {
+                    io::Error::new(io::ErrorKind::TimedOut, "the session was idle for too long")
+                });
+                return;
+            }
+
+            // The timeout can be changed by a request, which is replied to before the next one
+            // is read.
+            let deadline = server.idle_deadline().filter(|_| state.inflight.is_empty());
+            drop(server);
+            match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    drop(self.idle.wait_timeout(state, timeout).unwrap());
+                }
+                None => drop(self.idle.wait(state).unwrap()),
+            }
+        }
+    }
+
+    fn finish_reading(&self) {
+        self.state.lock().unwrap().done = true;
+        self.idle.notify_all();
+        self.work.notify_all();
+    }
+}
+
+/// Handles the requests of one connection on a fixed number of worker threads. Frames are read and
+/// decoded on the thread that calls `serve` and put in a queue, from which the workers take the
+/// requests for different fids alongside each other, while the requests for a fid are handled
+/// one at a time in the order that the client sent them. The queue holds a few requests per
+/// worker, and the rest are left in the transport until a worker takes one.
+///
+/// Handlers take the server for the bookkeeping that they do, which is quick, but the slow parts
+/// of some requests run on their worker with only the fid that they are for, so that those of
+/// different fids run in parallel: syncing the file of a Tfsync, and reading the data of a Tread
+/// from a regular file on the host that isn't in its page cache. Each request is replied to as
+/// soon as it is done, so replies can go out in a different order than the requests came in,
+/// which 9P allows. Requests that aren't for any fid, such as Tversion, wait for all of the others
+/// to be replied to first. `Config::max_inflight` limits how many requests it has in hand at once.
+///
+/// The dispatcher also keeps the timer for `Config::idle_timeout`: once the client has gone that
+/// long without sending a request, it ends the session and clunks its fids, and `serve` fails with
+/// `ErrorKind::TimedOut` when the next request comes in or the connection is closed.
+pub struct Dispatcher<F: FileSystem> {
+    server: Mutex<Server<F>>,
+    workers: usize,
+}
+
+impl<F> Dispatcher<F>
+where
+    F: FileSystem + Send,
+    F::Inode: Send,
+    F::Handle: Send,
+{
+    /// Creates a dispatcher that handles the requests for `server` on `workers` threads, or on
+    /// one if `workers` is 0.
+    pub fn new(server: Server<F>, workers: usize) -> Dispatcher<F> {
+        Dispatcher {
+            server: Mutex::new(server),
+            workers: workers.max(1),
+        }
+    }
+
+    /// Returns the server.
+    pub fn into_server(self) -> Server<F> {
+        self.server.into_inner().unwrap()
+    }
+
+    /// Handles the requests read from `reader` until it runs out, sending the replies to
+    /// `writer`. Fails like `Server::handle_message` when a request or its reply can't be
+    /// transferred, once the requests before it have been replied to.
+    pub fn serve<R: Read, W: Write + Send>(&self, reader: &mut R, writer: W) -> io::Result<()> {
+        // Requests can be flushed while they wait for a worker.
+        let flusher = self.server.lock().unwrap().flusher();
+        let shared = Shared {
+            server: &self.server,
+            state: Mutex::new(State {
+                writer,
+                queue: Queue::default(),
+                inflight: BTreeMap::new(),
+                error: None,
+                done: false,
+            }),
+            idle: Condvar::new(),
+            work: Condvar::new(),
+        };
+
+        let res = thread::scope(|scope| {
+            for _ in 0..self.workers {
+                let worker = &shared;
+                scope.spawn(move || worker.work());
+            }
+            let reaper = &shared;
+            scope.spawn(move || reaper.reap_idle());
+
+            let res = self.read_requests(&shared, &flusher, reader);
+            shared.finish_reading();
+            res
+        });
+
+        let state = shared.state.into_inner().unwrap();
+        res.and(state.error.map_or(Ok(()), Err))
+    }
+
+    fn read_requests<R: Read, W: Write>(
+        &self,
+        shared: &Shared<'_, F, W>,
+        flusher: &Flusher,
+        reader: &mut R,
+    ) -> io::Result<()> {
+        loop {
+            // Nothing that changes these can be in progress, since Tversion waits for everything
+            // else to be replied to and is handled before the next frame is read.
+            let (msize, checksum, dialect, max_inflight) = {
+                let server = shared.server.lock().unwrap();
+                let checksum = server.extensions.contains(Extensions::CRC32C);
+                (
+                    server.msize,
+                    checksum,
+                    server.dialect,
+                    server.cfg.max_inflight,
+                )
+            };
+
+            // Requests that can't be handled yet are left in the transport, so that a client
+            // that sends too many ends up waiting to send more.
+            shared.wait_queue(self.workers * QUEUED_PER_WORKER);
+            if let Some(max) = max_inflight {
+                drop(shared.wait_inflight(max.max(1)));
+            }
+
+            let mut frame = Vec::new();
+            let res = if checksum {
+                let mut reader = ChecksumReader::new(&mut *reader);
+                receive_frame(&mut reader, msize, &mut frame)
+                    .and_then(|()| reader.verify().map(drop))
+            } else {
+                receive_frame(reader, msize, &mut frame)
+            };
+            match res {
+                Ok(()) => {}
+                // The client is gone.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && frame.is_empty() => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+            let Tframe { tag, msg } = Tframe::decode_dialect(&mut &frame[..], dialect)?;
+
+            if let Some(e) = shared.state.lock().unwrap().error.take() {
+                return Err(e);
+            }
+
+            // A Tflush goes after the request it flushes, which is never replied to once it
+            // has been flushed, so that the Rflush goes out after anything that was. Workers
+            // reply with the server locked, so the request can't be replied to in between.
+            if let Ok(Tmessage::Flush(ref flush)) = msg {
+                let server = shared.server.lock().unwrap();
+                let mut state = shared.state.lock().unwrap();
+                if let Some(fids) = state.inflight.get(&flush.oldtag).cloned() {
+                    server.flusher.flush(flush.oldtag);
+                    state.queue(Queued { tag, msg, fids });
+                    shared.work.notify_all();
+                    continue;
+                }
+                drop(state);
+                drop(server);
+                shared.handle(tag, msg, false);
+                continue;
+            }
+
+            let fids = msg.as_ref().map(request_fids).unwrap_or_default();
+            if fids.is_empty() {
+                drop(shared.wait_idle());
+                shared.handle(tag, msg, false);
+                continue;
+            }
+            flusher.queue(tag);
+            shared
+                .state
+                .lock()
+                .unwrap()
+                .queue(Queued { tag, msg, fids });
+            shared.work.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(tag: u16, fids: &[u32]) -> Queued {
+        Queued {
+            tag,
+            msg: Ok(Tmessage::Ping),
+            fids: fids.to_vec(),
+        }
+    }
+
+    #[test]
+    fn queue() {
+        let mut queue = Queue::default();
+        queue.push(queued(1, &[1]));
+        queue.push(queued(2, &[1]));
+        queue.push(queued(3, &[2, 3]));
+        queue.push(queued(4, &[3]));
+        queue.push(queued(5, &[4]));
+
+        // The requests for other fids don't wait for the ones of a busy fid, or for the ones
+        // before them.
+        let tags: Vec<_> = std::iter::from_fn(|| queue.pop()).map(|q| q.tag).collect();
+        assert_eq!(tags, vec![1, 3, 5]);
+        assert_eq!(queue.len(), 2);
+
+        queue.done(&[2, 3]);
+        assert_eq!(queue.pop().map(|q| q.tag), Some(4));
+        assert!(queue.pop().is_none());
+        queue.done(&[1]);
+        assert_eq!(queue.pop().map(|q| q.tag), Some(2));
+        assert_eq!(queue.len(), 0);
+    }
+}
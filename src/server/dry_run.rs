@@ -0,0 +1,120 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Dry runs, in which the requests that would change the exported trees are only reported to
+//! the hook from `Server::set_dry_run_hook` rather than carried out, so that what a client would
+//! do to the host can be watched without it being done.
+
+use std::io;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::*;
+
+/// What the requests that would change an exported tree get back in a dry run. As an option it
+/// is `succeed` or `fail`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DryRun {
+    /// The reply that they would have gotten had they succeeded. Those that would have to
+    /// return a new file, like Tlcreate and Tmkdir, fail with EROFS anyway, and opening a file
+    /// for writing opens it for reading instead.
+    Succeed,
+    /// EROFS, as though the trees were read-only.
+    Fail,
+}
+
+impl FromStr for DryRun {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "succeed" => Ok(DryRun::Succeed),
+            "fail" => Ok(DryRun::Fail),
+            _ => Err("`dry_run` must be `succeed` or `fail`"),
+        }
+    }
+}
+
+// What `Server::set_dry_run_hook` takes.
+pub(super) type DryRunHook = Box<dyn FnMut(&Tmessage) + Send>;
+
+fn erofs() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+impl<F: FileSystem> Server<F> {
+    /// Calls `hook` with every request that a dry run keeps from changing the exported trees,
+    /// before it is replied to.
+    pub fn set_dry_run_hook(&mut self, hook: Box<dyn FnMut(&Tmessage) + Send>) {
+        self.on_dry_run = Some(hook);
+    }
+
+    // Returns the reply to `msg` if it would change an exported tree and this is a dry run.
+    pub(super) fn dry_run(&mut self, msg: &Tmessage) -> Option<io::Result<Rmessage>> {
+        let mode = self.cfg.dry_run?;
+        self.changed_fid(msg)?;
+
+        if let Some(hook) = self.on_dry_run.as_mut() {
+            hook(msg);
+        }
+
+        // Tremove and Tclunk get rid of their fid no matter what, just without the changes.
+        let gone = match msg {
+            Tmessage::Remove(remove) => Some((remove.fid, Rmessage::Remove)),
+            Tmessage::Clunk(clunk) => Some((clunk.fid, Rmessage::Clunk)),
+            _ => None,
+        };
+        if let Some((fid, rmsg)) = gone {
+            if let Some(f) = self.fids.get(fid) {
+                let mut f = lock_fid(&f);
+                f.remove_on_close = false;
+                f.xattr = None;
+            }
+            let result = self.clunk(&Tclunk { fid });
+            return Some(match mode {
+                DryRun::Succeed => result.and(Ok(rmsg)),
+                DryRun::Fail => Err(erofs()),
+            });
+        }
+        if mode == DryRun::Fail {
+            return Some(Err(erofs()));
+        }
+
+        Some(match msg {
+            Tmessage::Write(write) => Ok(Rmessage::Write(Rwrite {
+                count: write.data.len() as u32,
+            })),
+            Tmessage::CopyRange(copy_range) => Ok(Rmessage::CopyRange(Rcopyrange {
+                count: copy_range.count,
+            })),
+            Tmessage::Lopen(lopen) if lopen.flags & P9_TMPFILE != P9_TMPFILE => {
+                let flags = lopen.flags & !(P9_NOACCESS | P9_CREATE | P9_TRUNC) | P9_RDONLY;
+                self.lopen(&Tlopen {
+                    fid: lopen.fid,
+                    flags,
+                })
+                .map(Rmessage::Lopen)
+            }
+            Tmessage::Open(open) => self
+                .open(&Topen {
+                    fid: open.fid,
+                    mode: open.mode & !(P9_OEXEC | P9_OTRUNC | P9_ORCLOSE) | P9_OREAD,
+                })
+                .map(Rmessage::Open),
+            Tmessage::Wstat(_) => Ok(Rmessage::Wstat),
+            Tmessage::SetAttr(_) => Ok(Rmessage::SetAttr),
+            Tmessage::Rename(_) => Ok(Rmessage::Rename),
+            Tmessage::RenameAt(_) => Ok(Rmessage::RenameAt),
+            Tmessage::RenameAt2(_) => Ok(Rmessage::RenameAt2),
+            Tmessage::UnlinkAt(_) => Ok(Rmessage::UnlinkAt),
+            Tmessage::Link(_) => Ok(Rmessage::Link),
+            Tmessage::XattrCreate(_) => Ok(Rmessage::XattrCreate),
+            Tmessage::Fallocate(_) => Ok(Rmessage::Fallocate),
+            Tmessage::CloneRange(_) => Ok(Rmessage::CloneRange),
+            _ => Err(erofs()),
+        })
+    }
+}
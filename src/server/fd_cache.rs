@@ -0,0 +1,99 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+// Keeps track of which fids have a regular file open and when they last used it, so that a server
+// can close the files that were used least recently when too many are open and reopen them when
+// they are needed again.
+#[derive(Default)]
+pub(super) struct FdCache {
+    cap: Option<usize>,
+    tick: u64,
+    // When each fid with an open file that can be closed last used it, and the same the other way
+    // around so that the oldest one is first.
+    last_used: BTreeMap<u32, u64>,
+    lru: BTreeMap<u64, u32>,
+    // The fids whose files were closed to make room for others.
+    evicted: BTreeSet<u32>,
+    // The fids whose files can't be closed without losing state, such as flock locks. They still
+    // count against the cap.
+    pinned: BTreeSet<u32>,
+}
+
+impl FdCache {
+    pub fn new(cap: Option<usize>) -> FdCache {
+        FdCache {
+            cap,
+            ..Default::default()
+        }
+    }
+
+    // Returns true if the file of `fid` was closed to make room for others.
+    pub fn is_evicted(&self, fid: u32) -> bool {
+        self.evicted.contains(&fid)
+    }
+
+    // Records that the fids in `used` have their files open and just used them, and returns the
+    // fids whose files should be closed to stay under the cap. The fids in `used` are never among
+    // them, even if that means going over the cap for a while.
+    pub fn touch(&mut self, used: &[u32]) -> Vec<u32> {
+        let cap = match self.cap {
+            Some(cap) => cap,
+            None => return Vec::new(),
+        };
+
+        for &fid in used {
+            self.evicted.remove(&fid);
+            if self.pinned.contains(&fid) {
+                continue;
+            }
+            self.tick += 1;
+            if let Some(old) = self.last_used.insert(fid, self.tick) {
+                self.lru.remove(&old);
+            }
+            self.lru.insert(self.tick, fid);
+        }
+
+        let mut evict = Vec::new();
+        let mut open = self.last_used.len() + self.pinned.len();
+        let mut oldest = self.lru.iter();
+        while open > cap {
+            match oldest.next() {
+                Some((_, fid)) if !used.contains(fid) => evict.push(*fid),
+                Some(_) => continue,
+                None => break,
+            }
+            open -= 1;
+        }
+        for &fid in &evict {
+            self.forget(fid);
+            self.evicted.insert(fid);
+        }
+        evict
+    }
+
+    // Keeps the file of `fid` open until the fid goes away.
+    pub fn pin(&mut self, fid: u32) {
+        if self.cap.is_some() {
+            self.forget(fid);
+            self.pinned.insert(fid);
+        }
+    }
+
+    // Stops tracking `fid`, whose file was closed.
+    pub fn forget(&mut self, fid: u32) {
+        if let Some(tick) = self.last_used.remove(&fid) {
+            self.lru.remove(&tick);
+        }
+        self.evicted.remove(&fid);
+        self.pinned.remove(&fid);
+    }
+
+    // Stops tracking every fid.
+    pub fn clear(&mut self) {
+        *self = FdCache::new(self.cap);
+    }
+}
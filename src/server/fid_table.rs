@@ -0,0 +1,159 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+// How many shards a table has. Clients hand out fids from 0 up, so consecutive fids go in
+// different shards.
+const SHARDS: usize = 16;
+
+// The fids of a server, split into shards by fid that each have a lock of their own, so that a
+// lookup only locks the shard of the fid for as long as it takes to find it. Each fid is behind a
+// lock of its own too, which is only held for as long as a request uses it, so a request that has
+// looked up its fid doesn't keep the table or the other fids from being used.
+pub(super) struct FidTable<T> {
+    shards: [Mutex<BTreeMap<u32, Arc<Mutex<T>>>>; SHARDS],
+}
+
+impl<T> Default for FidTable<T> {
+    fn default() -> FidTable<T> {
+        FidTable {
+            shards: std::array::from_fn(|_| Mutex::new(BTreeMap::new())),
+        }
+    }
+}
+
+// Returns the shard that `fid` goes in.
+fn shard(fid: u32) -> usize {
+    fid as usize % SHARDS
+}
+
+impl<T> FidTable<T> {
+    fn shard(&self, fid: u32) -> MutexGuard<'_, BTreeMap<u32, Arc<Mutex<T>>>> {
+        self.shards[shard(fid)].lock().unwrap()
+    }
+
+    pub(super) fn get(&self, fid: u32) -> Option<Arc<Mutex<T>>> {
+        self.shard(fid).get(&fid).cloned()
+    }
+
+    pub(super) fn contains(&self, fid: u32) -> bool {
+        self.shard(fid).contains_key(&fid)
+    }
+
+    // Adds `value` as `fid`, replacing the fid that was there.
+    pub(super) fn insert(&self, fid: u32, value: T) -> Arc<Mutex<T>> {
+        let value = Arc::new(Mutex::new(value));
+        self.shard(fid).insert(fid, value.clone());
+        value
+    }
+
+    // Removes `fid` from the table. Whoever is still using it keeps it until they are done.
+    pub(super) fn remove(&self, fid: u32) -> Option<Arc<Mutex<T>>> {
+        self.shard(fid).remove(&fid)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.lock().unwrap().is_empty())
+    }
+
+    // Returns the fids in the table, sorted. Fids that are added or removed in the meantime may
+    // or may not be in there.
+    pub(super) fn entries(&self) -> Vec<(u32, Arc<Mutex<T>>)> {
+        let mut entries: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|s| {
+                let shard = s.lock().unwrap();
+                shard
+                    .iter()
+                    .map(|(&fid, value)| (fid, value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        entries.sort_unstable_by_key(|&(fid, _)| fid);
+        entries
+    }
+
+    pub(super) fn fids(&self) -> Vec<u32> {
+        self.entries().into_iter().map(|(fid, _)| fid).collect()
+    }
+
+    // Removes all the fids from the table and returns them, sorted.
+    pub(super) fn take(&self) -> Vec<(u32, Arc<Mutex<T>>)> {
+        let mut entries: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|s| std::mem::take(&mut *s.lock().unwrap()))
+            .collect();
+        entries.sort_unstable_by_key(|&(fid, _)| fid);
+        entries
+    }
+
+    pub(super) fn clear(&self) {
+        drop(self.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn shards() {
+        let table = FidTable::default();
+        assert!(table.is_empty());
+        for fid in 0..40 {
+            table.insert(fid, fid * 2);
+        }
+        assert_eq!(table.len(), 40);
+        assert!(table.shards.iter().all(|s| !s.lock().unwrap().is_empty()));
+
+        let fid = table.get(7).unwrap();
+        assert_eq!(*fid.lock().unwrap(), 14);
+        *fid.lock().unwrap() = 0;
+        let removed = table.remove(7).unwrap();
+        assert!(Arc::ptr_eq(&fid, &removed));
+        assert!(!table.contains(7));
+        assert!(table.get(7).is_none());
+        // Whoever still has the fid can keep using it.
+        assert_eq!(*fid.lock().unwrap(), 0);
+
+        let fids = table.fids();
+        assert_eq!(fids, (0..40).filter(|&fid| fid != 7).collect::<Vec<_>>());
+
+        assert_eq!(table.take().len(), 39);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn lock_fid() {
+        let table = FidTable::default();
+        table.insert(1, 0);
+        table.insert(2, 0);
+
+        // Holding one fid doesn't keep others, or the table, from being used.
+        let busy = table.get(1).unwrap();
+        let busy = busy.lock().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                *table.get(2).unwrap().lock().unwrap() += 1;
+                table.insert(3, 0);
+                assert!(table.get(1).unwrap().try_lock().is_err());
+            });
+        });
+        drop(busy);
+        assert_eq!(*table.get(2).unwrap().lock().unwrap(), 1);
+        assert_eq!(table.fids(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use super::stat_to_attr;
+use super::DirEntry;
+use super::Export;
+use crate::protocol::P9String;
+use crate::protocol::Rgetattr;
+use crate::protocol::Rstatfs;
+use crate::protocol::Tsetattr;
+
+/// The tree that a `Server` serves to its clients. The server takes care of fids, sessions,
+/// locks, leases and the mapping of ids, and calls into the file system for everything that
+/// touches files. `Passthrough`, which serves a directory on the host, is the default.
+///
+/// Owners are always host ids: the server maps the ids that clients send before passing them on
+/// and maps the ones in the returned attributes before replying. Flags are the ones of Tlopen,
+/// and errors are passed on to the client as they are, so they should carry an errno.
+pub trait FileSystem {
+    /// Refers to a file without it being open, like an O_PATH file descriptor. Every walked fid
+    /// holds one.
+    type Inode;
+
+    /// A file that has been opened with Tlopen or Tlcreate.
+    type Handle;
+
+    /// Returns the root directory of `export`, which a client just attached to.
+    fn root(&self, export: &Export) -> io::Result<Self::Inode>;
+
+    /// Returns the entry `name` in the directory `parent`, without following it if it is a
+    /// symlink.
+    fn lookup(&self, parent: &Self::Inode, name: &CStr) -> io::Result<Self::Inode>;
+
+    /// Returns another reference to the same file as `inode`.
+    fn try_clone(&self, inode: &Self::Inode) -> io::Result<Self::Inode>;
+
+    /// Returns the metadata of `inode`, which is also where qids come from.
+    fn stat(&self, inode: &Self::Inode) -> io::Result<libc::stat64>;
+
+    /// Returns the attributes of `inode` that are in `request_mask`, or more. `handle` is the
+    /// file that the fid has open, if there is one.
+    fn getattr(
+        &self,
+        inode: &Self::Inode,
+        _handle: Option<&Self::Handle>,
+        _request_mask: u64,
+    ) -> io::Result<Rgetattr> {
+        self.stat(inode).map(|st| stat_to_attr(&st))
+    }
+
+    /// Changes the attributes of `inode` that are set in the `valid` mask of `attr`. `handle` is
+    /// the file that the fid has open, if there is one.
+    fn setattr(
+        &self,
+        inode: &Self::Inode,
+        handle: Option<&Self::Handle>,
+        attr: &Tsetattr,
+    ) -> io::Result<()>;
+
+    /// Opens `inode` with the Tlopen `flags`.
+    fn open(&self, inode: &Self::Inode, flags: u32) -> io::Result<Self::Handle>;
+
+    /// Creates and opens the regular file `name` in `dir`, which must not exist yet. The new file
+    /// belongs to `uid` and `gid`, either of which may be `u32::MAX` to leave it to the file
+    /// system.
+    fn create(
+        &self,
+        dir: &Self::Inode,
+        name: &CStr,
+        flags: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<(Self::Inode, Self::Handle)>;
+
+    /// Creates and opens a regular file in `dir` that has no name, as with O_TMPFILE, and that
+    /// goes away when it is closed unless it is given one through `link` first. `uid` and `gid`
+    /// are as for `create`.
+    fn tmpfile(
+        &self,
+        _dir: &Self::Inode,
+        _flags: u32,
+        _mode: u32,
+        _uid: u32,
+        _gid: u32,
+    ) -> io::Result<(Self::Inode, Self::Handle)> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Reads from `handle` at `offset` into `buf` and returns how many bytes were read.
+    fn read(&self, handle: &Self::Handle, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Writes `buf` to `handle` at `offset` and returns how many bytes were written.
+    fn write(&self, handle: &Self::Handle, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// Calls `entry` with each entry of the directory `dir`, which is open in `handle`, along
+    /// with its metadata, starting at `offset`. That is 0 for the first entry and the `offset` of
+    /// an entry for the one after it. Offsets must stay good when the directory is opened again,
+    /// since clients carry on reading with them after the server had to reopen it, or after they
+    /// did. Stops early when `entry` returns false or fails.
+    fn readdir(
+        &self,
+        dir: &Self::Inode,
+        handle: &mut Self::Handle,
+        offset: u64,
+        entry: &mut dyn FnMut(DirEntry, &libc::stat64) -> io::Result<bool>,
+    ) -> io::Result<()>;
+
+    /// Returns the target of the symlink `inode`.
+    fn readlink(&self, inode: &Self::Inode) -> io::Result<Vec<u8>>;
+
+    /// Creates the directory `name` in `dir`, owned like the files from `create`, and returns
+    /// its metadata.
+    fn mkdir(
+        &self,
+        dir: &Self::Inode,
+        name: &CStr,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64>;
+
+    /// Creates the special file `name` in `dir`, with the type and permissions in `mode` and the
+    /// device number `rdev`, owned like the files from `create`, and returns its metadata. The
+    /// server has already checked the type against `Config::mknod`.
+    fn mknod(
+        &self,
+        _dir: &Self::Inode,
+        _name: &CStr,
+        _mode: u32,
+        _rdev: libc::dev_t,
+        _uid: u32,
+        _gid: u32,
+    ) -> io::Result<libc::stat64> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Makes `name` in `dir` another link to `inode`.
+    fn link(&self, inode: &Self::Inode, dir: &Self::Inode, name: &CStr) -> io::Result<()>;
+
+    /// Moves `oldname` in `olddir` to `newname` in `newdir`, replacing whatever was there.
+    fn rename(
+        &self,
+        olddir: &Self::Inode,
+        oldname: &CStr,
+        newdir: &Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<()>;
+
+    /// Like `rename`, with the renameat2 `flags`, which are 0, `libc::RENAME_NOREPLACE` or
+    /// `libc::RENAME_EXCHANGE`. By default only a `flags` of 0 is supported, and the others fail
+    /// with EINVAL as they do on host file systems that don't support them.
+    fn rename2(
+        &self,
+        olddir: &Self::Inode,
+        oldname: &CStr,
+        newdir: &Self::Inode,
+        newname: &CStr,
+        flags: libc::c_uint,
+    ) -> io::Result<()> {
+        if flags != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.rename(olddir, oldname, newdir, newname)
+    }
+
+    /// Removes `name` from `dir`. `flags` is `libc::AT_REMOVEDIR` for directories and 0
+    /// otherwise.
+    fn unlink(&self, dir: &Self::Inode, name: &CStr, flags: libc::c_int) -> io::Result<()>;
+
+    /// Returns the directory that `inode` was reached through, within `export`, and its name in
+    /// there. Fails with EBUSY for the root of the export. `walk_path` is the path that the
+    /// client walked to `inode` through from the root, such as "/usr/bin", which is where it
+    /// still is unless it has been renamed since.
+    fn parent(
+        &self,
+        export: &Export,
+        inode: &Self::Inode,
+        walk_path: &Path,
+    ) -> io::Result<(Self::Inode, CString)>;
+
+    /// Returns the name of `inode` in its directory, or "/" for the root of `export`.
+    /// `walk_path` is as for `parent`.
+    fn file_name(
+        &self,
+        export: &Export,
+        inode: &Self::Inode,
+        walk_path: &Path,
+    ) -> io::Result<P9String> {
+        match self.parent(export, inode, walk_path) {
+            Ok((_, name)) => P9String::new(name.as_bytes()),
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => P9String::new(b"/"),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns information about the file system that holds `inode`.
+    fn statfs(&self, inode: &Self::Inode) -> io::Result<Rstatfs>;
+
+    /// Commits the data of `handle`, and its metadata unless `datasync` is set, to stable
+    /// storage.
+    fn fsync(&self, handle: &Self::Handle, datasync: bool) -> io::Result<()>;
+
+    /// Returns the value of the extended attribute `name` of `inode`, or the list of the names of
+    /// all of its attributes, each followed by a nul byte, if `name` is empty.
+    fn getxattr(
+        &self,
+        _inode: &Self::Inode,
+        _handle: Option<&Self::Handle>,
+        _name: &CStr,
+    ) -> io::Result<Vec<u8>> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Sets the extended attribute `name` of `inode` to `value`. `flags` may hold
+    /// `libc::XATTR_CREATE` or `libc::XATTR_REPLACE`.
+    fn setxattr(
+        &self,
+        _inode: &Self::Inode,
+        _handle: Option<&Self::Handle>,
+        _name: &CStr,
+        _value: &[u8],
+        _flags: libc::c_int,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Removes the extended attribute `name` of `inode`.
+    fn removexattr(
+        &self,
+        _inode: &Self::Inode,
+        _handle: Option<&Self::Handle>,
+        _name: &CStr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Returns the O_PATH file on the host that `inode` refers to, if there is one. Requests that
+    /// only make sense for host files, such as Twatch, fail with EOPNOTSUPP without it.
+    fn host_path<'a>(&self, _inode: &'a Self::Inode) -> Option<&'a File> {
+        None
+    }
+
+    /// Returns the open file on the host behind `handle`, if there is one. Locks, ioctls,
+    /// server-side copies and the like fail with EOPNOTSUPP without it.
+    fn host_file<'a>(&self, _handle: &'a Self::Handle) -> Option<&'a File> {
+        None
+    }
+
+    /// Returns the file descriptors that the file system needs to keep open, for a caller that
+    /// closes all the others before sandboxing itself.
+    fn keep_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+}
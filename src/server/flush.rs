@@ -0,0 +1,79 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Tracks whether the request with a particular tag has been flushed by the client.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Marks the request as cancelled.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Returns true if the request has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// The cancellation tokens of the requests that a `Server` has not yet replied to, indexed by tag.
+#[derive(Clone, Default)]
+pub struct Flusher {
+    inflight: Arc<Mutex<BTreeMap<u16, CancelToken>>>,
+}
+
+impl Flusher {
+    /// Cancels the request with tag `tag`, if it is being processed or was queued with `queue`.
+    /// This can be called from a different thread than the one running the server, e.g. by a
+    /// transport that sees a Tflush before the server gets to the request. A request that is
+    /// flushed before the server starts processing it is never handled. One that is already being
+    /// processed is aborted at the next opportunity and its reply discarded. Tags that aren't in
+    /// flight are left alone, so that the next request to use them is handled as usual.
+    pub fn flush(&self, tag: u16) {
+        if let Some(token) = self.inflight.lock().unwrap().get(&tag) {
+            token.cancel();
+        }
+    }
+
+    /// Records that the request with tag `tag` has been read and is waiting for the server, so
+    /// that `flush` can cancel it before the server gets to it. Transports that read requests
+    /// ahead of the server call this for each of them.
+    pub fn queue(&self, tag: u16) {
+        self.inflight.lock().unwrap().entry(tag).or_default();
+    }
+
+    // Returns the token for the request with tag `tag`, which the server is about to process.
+    pub(super) fn start(&self, tag: u16) -> CancelToken {
+        self.inflight
+            .lock()
+            .unwrap()
+            .entry(tag)
+            .or_default()
+            .clone()
+    }
+
+    // Forgets about the request with tag `tag`, returning its token if it was still outstanding.
+    pub(super) fn finish(&self, tag: u16) -> Option<CancelToken> {
+        self.inflight.lock().unwrap().remove(&tag)
+    }
+
+    // Forgets about the request with tag `tag` and token `token` once it has been handled, unless
+    // it was flushed and another request has the tag by now.
+    pub(super) fn end(&self, tag: u16, token: &CancelToken) {
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight
+            .get(&tag)
+            .is_some_and(|t| Arc::ptr_eq(&t.0, &token.0))
+        {
+            inflight.remove(&tag);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::CancelToken;
+
+// How often a request that waits for a thaw checks whether it was flushed.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct State {
+    frozen: bool,
+    // The requests that are changing the tree right now.
+    changing: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    // Signalled on every thaw and whenever the last change in progress ends.
+    changed: Condvar,
+}
+
+/// Pauses the changes that clients make to the tree, such as for taking a snapshot of it on the
+/// host. The same freezer should be given to every `Server` that serves the same tree, so that
+/// freezing it stops all of them.
+///
+/// While the tree is frozen, requests that would change it wait until it is thawed, or fail
+/// with EAGAIN with `Config::freeze_fails`. Reads and everything else go on as usual.
+#[derive(Clone, Default)]
+pub struct Freezer {
+    inner: Arc<Inner>,
+}
+
+impl Freezer {
+    /// Creates a freezer for a tree that isn't frozen.
+    pub fn new() -> Freezer {
+        Default::default()
+    }
+
+    /// Freezes the tree, and returns once none of the changes that were already in progress
+    /// are left. `Server::freeze` also commits the files that its own client wrote to.
+    pub fn freeze(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.frozen = true;
+        while state.changing > 0 {
+            state = self.inner.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Lets clients change the tree again, starting with the requests that were waiting.
+    pub fn thaw(&self) {
+        self.inner.state.lock().unwrap().frozen = false;
+        self.inner.changed.notify_all();
+    }
+
+    /// Returns true if the tree is frozen.
+    pub fn is_frozen(&self) -> bool {
+        self.inner.state.lock().unwrap().frozen
+    }
+
+    // Waits until the tree isn't frozen, or fails with EAGAIN if `fail` is set, and then counts
+    // a change as being in progress until the returned guard is dropped. Fails with EINTR if
+    // `cancel` is cancelled while waiting.
+    pub(super) fn start_change(&self, fail: bool, cancel: &CancelToken) -> io::Result<Change> {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.frozen {
+            if fail {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+            }
+            if cancel.is_cancelled() {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            state = self
+                .inner
+                .changed
+                .wait_timeout(state, CANCEL_POLL)
+                .unwrap()
+                .0;
+        }
+        state.changing += 1;
+        Ok(Change {
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+// A change to the tree that is in progress.
+pub(super) struct Change {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Change {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.changing -= 1;
+        if state.changing == 0 {
+            self.inner.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn freeze() {
+        let freezer = Freezer::new();
+        let cancel = CancelToken::default();
+        let change = freezer
+            .start_change(false, &cancel)
+            .expect("failed to start change");
+
+        // Freezing waits for the change in progress.
+        let frozen = {
+            let freezer = freezer.clone();
+            thread::spawn(move || freezer.freeze())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!frozen.is_finished());
+        drop(change);
+        frozen.join().unwrap();
+        assert!(freezer.is_frozen());
+
+        let err = freezer
+            .start_change(true, &cancel)
+            .err()
+            .and_then(|e| e.raw_os_error());
+        assert_eq!(err, Some(libc::EAGAIN));
+
+        let waiting = {
+            let freezer = freezer.clone();
+            thread::spawn(move || {
+                freezer
+                    .start_change(false, &CancelToken::default())
+                    .map(drop)
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        freezer.thaw();
+        waiting.join().unwrap().expect("failed to start change");
+
+        freezer.freeze();
+        cancel.cancel();
+        let err = freezer
+            .start_change(false, &cancel)
+            .err()
+            .and_then(|e| e.raw_os_error());
+        assert_eq!(err, Some(libc::EINTR));
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Shell-style wildcard patterns, for the options that pick out files by name or path.
+//!
+//! `*` matches any run of bytes but `/`, `**` any run at all, `?` any single byte but `/`, and
+//! `[...]` any single byte in a set of bytes and ranges such as `[a-z_]`, or outside of it if the
+//! set starts with `!` or `^`. A `\` matches the byte after it literally. Patterns are matched
+//! against the whole name, a byte at a time, so `?` only matches ASCII characters.
+
+// Matches the byte `c` against the set that starts right after a `[` in `pattern`. Returns
+// whether it matched along with the length of the set including its closing `]`, or None if the
+// set isn't closed and the `[` is just a byte.
+fn match_set(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let (negated, mut i) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let lo = *pattern.get(i)?;
+        if lo == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(&hi)) if hi != b']' => {
+                matched |= (lo..=hi).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= lo == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Returns true if all of `text` matches `pattern`.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => {
+            let any = pattern.get(1) == Some(&b'*');
+            let rest = if any { &pattern[2..] } else { &pattern[1..] };
+            for i in 0..=text.len() {
+                if matches(rest, &text[i..]) {
+                    return true;
+                }
+                if !any && text.get(i) == Some(&b'/') {
+                    return false;
+                }
+            }
+            false
+        }
+        Some(b'?') => match text.first() {
+            Some(&c) if c != b'/' => matches(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => {
+            let c = match text.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            match match_set(&pattern[1..], c) {
+                Some((matched, len)) => matched && matches(&pattern[1 + len..], &text[1..]),
+                None => c == b'[' && matches(&pattern[1..], &text[1..]),
+            }
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && matches(&pattern[2..], &text[1..])
+        }
+        Some(&p) => text.first() == Some(&p) && matches(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b".*", b".git"));
+        assert!(!matches(b".*", b"git"));
+        assert!(matches(b"*.sw[a-p]", b"main.rs.swp"));
+        assert!(!matches(b"*.sw[!a-p]", b"main.rs.swp"));
+        assert!(matches(b"?ecret?", b"secrets"));
+        assert!(matches(b"[]x]", b"]"));
+        assert!(matches(b"a[b", b"a[b"));
+        assert!(matches(b"\\*", b"*"));
+        assert!(!matches(b"\\*", b"x"));
+
+        assert!(matches(b"/usr/*", b"/usr/bin"));
+        assert!(!matches(b"/usr/*", b"/usr/bin/ls"));
+        assert!(matches(b"/usr/**", b"/usr/bin/ls"));
+        assert!(matches(b"/**/*.key", b"/home/user/.ssh/id.key"));
+        assert!(!matches(b"/home/?/x", b"/home///x"));
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// `count` consecutive ids that start at `client` as the client sees them and at `host` on the
+/// host, like a line of /proc/<pid>/uid_map or an idmapped mount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdRange {
+    pub client: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl IdRange {
+    // Returns the id that `id` in the range starting at `from` maps to in the one starting at
+    // `to`, if it is in the range.
+    fn translate(&self, from: u32, to: u32, id: u32) -> Option<u32> {
+        id.checked_sub(from)
+            .filter(|&offset| offset < self.count)
+            .map(|offset| to + offset)
+    }
+}
+
+// The id that the kernel reports for ids that a user namespace or idmapped mount has no mapping
+// for.
+const OVERFLOW_ID: u32 = 65534;
+
+/// Maps between the user or group ids of the client and those of the host. The owners of files
+/// are reported to the client as the ids they map to, and the ids that the client sends are
+/// mapped back before they are used on the host.
+///
+/// As on an idmapped mount, host ids that aren't in any range are reported as the overflow id,
+/// 65534, and ids that the client sends that aren't in any range are rejected with EINVAL. A map
+/// without any ranges passes every id through unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerIdMap {
+    ranges: Vec<IdRange>,
+    // Whether ids that aren't in any range are passed through unchanged, as for maps from older
+    // versions of this crate.
+    #[serde(default)]
+    passthrough: bool,
+}
+
+pub type ServerUidMap = ServerIdMap;
+pub type ServerGidMap = ServerIdMap;
+
+impl ServerIdMap {
+    /// Creates a map that passes every id through unchanged.
+    pub fn new() -> ServerIdMap {
+        Default::default()
+    }
+
+    /// Adds a range to the map. Ranges may not overlap on either side, so that every id maps to
+    /// at most one other.
+    pub fn add(&mut self, range: IdRange) -> Result<(), &'static str> {
+        let end = |start: u32| {
+            start
+                .checked_add(range.count)
+                .ok_or("id range overflows a u32")
+        };
+        let (client_end, host_end) = (end(range.client)?, end(range.host)?);
+        if range.count == 0 {
+            return Err("id range is empty");
+        }
+
+        let overlaps = |start: u32, end: u32, other_start: u32, other: &IdRange| {
+            start < other_start + other.count && other_start < end
+        };
+        if self.ranges.iter().any(|other| {
+            overlaps(range.client, client_end, other.client, other)
+                || overlaps(range.host, host_end, other.host, other)
+        }) {
+            return Err("id ranges overlap");
+        }
+
+        self.ranges.push(range);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // Whether ids that aren't in any range map to themselves.
+    fn passes_through(&self) -> bool {
+        self.passthrough || self.ranges.is_empty()
+    }
+
+    /// Returns the id that the client sees for the host id `id`.
+    pub fn to_client(&self, id: u32) -> u32 {
+        self.ranges
+            .iter()
+            .find_map(|range| range.translate(range.host, range.client, id))
+            .unwrap_or(if self.passes_through() {
+                id
+            } else {
+                OVERFLOW_ID
+            })
+    }
+
+    /// Returns the host id for the id `id` that the client sent, or None if it has none.
+    pub fn to_host(&self, id: u32) -> Option<u32> {
+        self.find_host(id)
+            .or_else(|| self.passes_through().then_some(id))
+    }
+
+    // Returns the host id for the client id `id`, if it is in one of the ranges.
+    pub(super) fn find_host(&self, id: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .find_map(|range| range.translate(range.client, range.host, id))
+    }
+}
+
+/// Maps each host id in the keys to the client id in the value. As in older versions of this
+/// crate, ids that aren't in the map are passed through unchanged. Unlike in them, the map also
+/// applies to the ids that clients send, in Tsetattr and in the ACLs that they set, which are
+/// mapped back to the host ids.
+impl From<BTreeMap<u32, u32>> for ServerIdMap {
+    fn from(map: BTreeMap<u32, u32>) -> ServerIdMap {
+        ServerIdMap {
+            passthrough: true,
+            ranges: map
+                .into_iter()
+                .map(|(host, client)| IdRange {
+                    client,
+                    host,
+                    count: 1,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parses a comma-separated list of ranges in the form `client/host/count`, such as
+/// "0/1000/1,1/100000/65536".
+impl FromStr for ServerIdMap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = ServerIdMap::new();
+        for range in s.split(',').filter(|range| !range.is_empty()) {
+            let mut ids = range.split('/').map(|id| id.parse::<u32>());
+            match (ids.next(), ids.next(), ids.next(), ids.next()) {
+                (Some(Ok(client)), Some(Ok(host)), Some(Ok(count)), None) => {
+                    map.add(IdRange {
+                        client,
+                        host,
+                        count,
+                    })?;
+                }
+                _ => return Err("id ranges must be of the form `client/host/count`"),
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Squashes every identity into one, like the `all_squash` export option of NFS. Everything
+/// that clients create, or change the owner of, ends up owned by the host user and group, and
+/// every file is reported as owned by the client user and group, whatever their real owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Squash {
+    pub host_uid: u32,
+    pub host_gid: u32,
+    pub client_uid: u32,
+    pub client_gid: u32,
+}
+
+/// Parses `host_uid/host_gid`, which reports files as owned by root, or
+/// `host_uid/host_gid/client_uid/client_gid`.
+impl FromStr for Squash {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ids = s
+            .split('/')
+            .map(|id| id.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "squashed ids must be numbers")?;
+        match ids[..] {
+            [host_uid, host_gid] => Ok(Squash {
+                host_uid,
+                host_gid,
+                client_uid: 0,
+                client_gid: 0,
+            }),
+            [host_uid, host_gid, client_uid, client_gid] => Ok(Squash {
+                host_uid,
+                host_gid,
+                client_uid,
+                client_gid,
+            }),
+            _ => {
+                Err("squashed ids must be of the form `uid/gid` or `uid/gid/client_uid/client_gid`")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_ranges() {
+        let map: ServerIdMap = "0/1000/1,1/100000/65536".parse().unwrap();
+        assert_eq!(map.to_client(1000), 0);
+        assert_eq!(map.to_client(100000), 1);
+        assert_eq!(map.to_client(100000 + 65535), 65536);
+        assert_eq!(map.to_client(100000 + 65536), 65534);
+        assert_eq!(map.to_host(0), Some(1000));
+        assert_eq!(map.to_host(65536), Some(100000 + 65535));
+        assert_eq!(map.to_host(65537), None);
+        assert_eq!(map.find_host(65537), None);
+
+        let map = ServerIdMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.to_client(1000), 1000);
+        assert_eq!(map.to_host(1000), Some(1000));
+    }
+
+    #[test]
+    fn bad_ranges() {
+        assert!("0/1000/2,1/2000/1".parse::<ServerIdMap>().is_err());
+        assert!("0/1000/1,1/1000/1".parse::<ServerIdMap>().is_err());
+        assert!("0/1000/0".parse::<ServerIdMap>().is_err());
+        assert!("0/4294967295/2".parse::<ServerIdMap>().is_err());
+        assert!("0/1000".parse::<ServerIdMap>().is_err());
+        assert!("0/1000/1/1".parse::<ServerIdMap>().is_err());
+        assert!("a/1000/1".parse::<ServerIdMap>().is_err());
+    }
+
+    #[test]
+    fn from_btree_map() {
+        let map = ServerIdMap::from(BTreeMap::from([(1000, 0)]));
+        assert_eq!(map.to_client(1000), 0);
+        assert_eq!(map.to_host(0), Some(1000));
+        assert_eq!(map.to_client(2000), 2000);
+        assert_eq!(map.to_host(2000), Some(2000));
+        assert_eq!(map.find_host(2000), None);
+    }
+
+    #[test]
+    fn parse_squash() {
+        assert_eq!(
+            "1000/100".parse(),
+            Ok(Squash {
+                host_uid: 1000,
+                host_gid: 100,
+                client_uid: 0,
+                client_gid: 0,
+            })
+        );
+        assert_eq!(
+            "1000/100/65534/65534".parse(),
+            Ok(Squash {
+                host_uid: 1000,
+                host_gid: 100,
+                client_uid: 65534,
+                client_gid: 65534,
+            })
+        );
+        assert!("1000".parse::<Squash>().is_err());
+        assert!("1000/100/0".parse::<Squash>().is_err());
+        assert!("1000/users".parse::<Squash>().is_err());
+    }
+}
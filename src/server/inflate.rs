@@ -0,0 +1,311 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A small decompressor for raw DEFLATE streams (RFC 1951), which is what zip archives use, along
+//! with the CRC-32 that they check the results with. It favors simplicity over speed, in the
+//! style of zlib's puff.c.
+
+use std::io;
+
+const MAX_BITS: usize = 15;
+
+// The base lengths and numbers of extra bits of length codes 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// The base distances and numbers of extra bits of distance codes 0 to 29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order in which the lengths of the code length code are sent.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid deflate stream")
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    // Returns the next `n` bits, least significant first.
+    fn take(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or_else(invalid)?;
+            self.pos += 1;
+            self.buf |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let bits = self.buf & ((1u32 << n) - 1);
+        self.buf = self.buf.checked_shr(n).unwrap_or(0);
+        self.count -= n;
+        Ok(bits)
+    }
+
+    // Skips to the next byte boundary.
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+// A canonical Huffman code, as the number of codes of each length and the symbols in the order of
+// their codes.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+
+        // Codes that would need more bits than there are make the code invalid. Incomplete codes
+        // are allowed, as a code with a single distance is.
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(invalid());
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = i32::from(count);
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid())
+    }
+}
+
+fn fixed_codes() -> io::Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> io::Result<(Huffman, Huffman)> {
+    let nlen = bits.take(5)? as usize + 257;
+    let ndist = bits.take(5)? as usize + 1;
+    let ncode = bits.take(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(invalid());
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[i] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (len, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let prev = *lengths[..i].last().ok_or_else(invalid)?;
+                (prev, 3 + bits.take(2)?)
+            }
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        let end = i + repeat as usize;
+        lengths.get_mut(i..end).ok_or_else(invalid)?.fill(len);
+        i = end;
+    }
+
+    // Without an end of block code there would be no way to stop.
+    if lengths[256] == 0 {
+        return Err(invalid());
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen])?,
+        Huffman::new(&lengths[nlen..])?,
+    ))
+}
+
+/// Decompresses the raw DEFLATE stream `data`, which must hold no more than `limit` bytes once
+/// decompressed.
+pub fn inflate(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut bits = Bits {
+        data,
+        pos: 0,
+        buf: 0,
+        count: 0,
+    };
+    let mut out = Vec::with_capacity(limit.min(1 << 20));
+    let too_big = || io::Error::new(io::ErrorKind::InvalidData, "deflate stream is too big");
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let header = data.get(bits.pos..bits.pos + 4).ok_or_else(invalid)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(invalid());
+                }
+                let start = bits.pos + 4;
+                let stored = data.get(start..start + len as usize).ok_or_else(invalid)?;
+                if out.len() + stored.len() > limit {
+                    return Err(too_big());
+                }
+                out.extend_from_slice(stored);
+                bits.pos = start + len as usize;
+            }
+            btype @ (1 | 2) => {
+                let (lengths, dists) = if btype == 1 {
+                    fixed_codes()?
+                } else {
+                    dynamic_codes(&mut bits)?
+                };
+                loop {
+                    let symbol = lengths.decode(&mut bits)? as usize;
+                    if symbol < 256 {
+                        if out.len() == limit {
+                            return Err(too_big());
+                        }
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+
+                    let symbol = symbol - 257;
+                    let len = *LENGTH_BASE.get(symbol).ok_or_else(invalid)? as usize
+                        + bits.take(LENGTH_EXTRA[symbol].into())? as usize;
+                    let symbol = dists.decode(&mut bits)? as usize;
+                    let dist = *DIST_BASE.get(symbol).ok_or_else(invalid)? as usize
+                        + bits.take(DIST_EXTRA[symbol].into())? as usize;
+                    if dist > out.len() {
+                        return Err(invalid());
+                    }
+                    if out.len() + len > limit {
+                        return Err(too_big());
+                    }
+                    // The copy may overlap what it produces, so it goes a byte at a time.
+                    let start = out.len() - dist;
+                    for i in 0..len {
+                        out.push(out[start + i]);
+                    }
+                }
+            }
+            _ => return Err(invalid()),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Returns the CRC-32 of `data`, as used by zip and gzip.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn stored_and_fixed() {
+        let expected = b"hello, hello, hello, world!\n";
+        let stored = unhex("011c00e3ff68656c6c6f2c2068656c6c6f2c2068656c6c6f2c20776f726c64210a");
+        assert_eq!(inflate(&stored, 100).unwrap(), expected);
+        let fixed = unhex("cb48cdc9c9d751c840a1caf38b725214b900");
+        assert_eq!(inflate(&fixed, 100).unwrap(), expected);
+        assert_eq!(crc32(expected), 0x4608cb80);
+
+        assert!(inflate(&fixed, expected.len() - 1).is_err());
+        assert!(inflate(&fixed[..fixed.len() - 2], 100).is_err());
+    }
+
+    #[test]
+    fn dynamic() {
+        let expected: Vec<u8> = (0..60u32)
+            .flat_map(|i| format!("{i} squared is {}; ", i * i).into_bytes())
+            .collect();
+        let data = unhex(concat!(
+            "5d934b6e05210c04afc211f00f06cd6922258b2c93a7dc3ff4aca8b7c4b2eceaa6dddbebe7efe3f7ebb3",
+            "7dbf5abf9b9d6fbb9b9fefbc5b9cef75b744ffb85b9d05afbb8db310bb6362e49e719d85b197acb3706d",
+            "0aeb58d3054a525717602df7240bf2ed6d46e4b5898cd0a2b6411dea02b85f9a05f4706d5cd42b0f419f",
+            "a277d067aa8b565f7b9683be7c6f74d0d7dc5c0efa217a07fd78ba403f9f59a09fcf465a2fae00fd127d",
+            "807e4963d0fb2e2b82e677391674df646c14bf520a62b0a66f0a48b0d06fc6f5f6e7da0b1156ca463241",
+            "433292111a4a5a52c7541c933a2e6536df52a4602763d4a5239923d3892483e45df3a0c3a36b2f7478f6",
+            "cd579db9948e820e1f4f1f74f8d4bc0a06587b0b3a7c3d270c1df1e8289eb1496fcdb7f46b1e2f22e45f",
+            "f124523eff03",
+        ));
+        assert_eq!(inflate(&data, expected.len()).unwrap(), expected);
+        assert_eq!(crc32(&expected), 0x51543bfd);
+    }
+}
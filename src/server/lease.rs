@@ -0,0 +1,148 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// A read lease lets the holder cache the contents and attributes of the file, and a write lease
+// also lets it cache changes to them. Fids that hold no lease have None rather than `Release`.
+use crate::protocol::LeaseType;
+
+// A file that one of the fids of a server has open. Files are identified by device and inode so
+// that opens conflict no matter which path they went through.
+struct Open {
+    file: (u64, u64),
+    writable: bool,
+    lease: Option<LeaseType>,
+}
+
+#[derive(Default)]
+struct Leases {
+    next_owner: u64,
+    // The open fids of every server, by owner and fid.
+    opens: BTreeMap<(u64, u32), Open>,
+    // The fids whose leases were broken since their owner last asked.
+    breaks: BTreeMap<u64, Vec<u32>>,
+}
+
+impl Leases {
+    // Returns true if a lease of type `ty` can be held while another owner has the file open, for
+    // writing if `writable` is set.
+    fn compatible(ty: LeaseType, writable: bool) -> bool {
+        ty == LeaseType::Read && !writable
+    }
+
+    // Breaks the leases of owners other than `owner` on `file` that conflict with an open for
+    // writing if `writable` is set, or for reading otherwise.
+    fn break_conflicting(&mut self, owner: u64, file: (u64, u64), writable: bool) {
+        for (&(holder, fid), open) in self.opens.iter_mut() {
+            if holder == owner || open.file != file {
+                continue;
+            }
+            match open.lease {
+                Some(ty) if !Leases::compatible(ty, writable) => {
+                    open.lease = None;
+                    self.breaks.entry(holder).or_default().push(fid);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Keeps track of the files that clients have open and of the leases that they hold on them, so
+/// that a client can cache a file for as long as nobody else uses it in a conflicting way. The
+/// same table should be given to every `Server` that serves the same tree.
+///
+/// A client that negotiated the `lease` extension asks for a read or write lease on an open fid
+/// with Tlease. The lease is only granted if no other client has the file open in a conflicting
+/// way: a read lease conflicts with other opens for writing, and a write lease with any other
+/// open. When another client later opens the file in a conflicting way, the lease is broken and
+/// the holder finds the fid in the reply to its next Tleasebreaks. Changes made on the host or
+/// through servers that don't share the table are not seen.
+#[derive(Clone, Default)]
+pub struct LeaseTable {
+    inner: Arc<Mutex<Leases>>,
+}
+
+impl LeaseTable {
+    /// Creates an empty table.
+    pub fn new() -> LeaseTable {
+        Default::default()
+    }
+
+    // Returns a new id for a server that uses the table.
+    pub(super) fn new_owner(&self) -> u64 {
+        let mut leases = self.inner.lock().unwrap();
+        leases.next_owner += 1;
+        leases.next_owner
+    }
+
+    // Records that `fid` of `owner` now has `file` open, breaking the leases that conflict with it.
+    pub(super) fn opened(&self, owner: u64, fid: u32, file: (u64, u64), writable: bool) {
+        let mut leases = self.inner.lock().unwrap();
+        leases.break_conflicting(owner, file, writable);
+        leases.opens.insert(
+            (owner, fid),
+            Open {
+                file,
+                writable,
+                lease: None,
+            },
+        );
+    }
+
+    // Forgets about `fid` of `owner` and any lease it holds.
+    pub(super) fn closed(&self, owner: u64, fid: u32) {
+        let mut leases = self.inner.lock().unwrap();
+        leases.opens.remove(&(owner, fid));
+    }
+
+    // Forgets about all the fids of `owner` and their leases.
+    pub(super) fn close_all(&self, owner: u64) {
+        let mut leases = self.inner.lock().unwrap();
+        leases.opens.retain(|&(o, _), _| o != owner);
+        leases.breaks.remove(&owner);
+    }
+
+    // Grants a lease of type `ty` on the file that `fid` of `owner` has open, or releases its lease
+    // if `ty` is `None`.
+    pub(super) fn set_lease(&self, owner: u64, fid: u32, ty: Option<LeaseType>) -> io::Result<()> {
+        let mut leases = self.inner.lock().unwrap();
+        let file = leases
+            .opens
+            .get(&(owner, fid))
+            .map(|open| open.file)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+
+        if let Some(ty) = ty {
+            let conflict = leases.opens.iter().any(|(&(o, _), open)| {
+                o != owner
+                    && open.file == file
+                    && (!Leases::compatible(ty, open.writable)
+                        || open.lease == Some(LeaseType::Write))
+            });
+            if conflict {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+            }
+        }
+
+        if let Some(open) = leases.opens.get_mut(&(owner, fid)) {
+            open.lease = ty;
+        }
+        Ok(())
+    }
+
+    // Returns up to `max` of the fids of `owner` whose leases were broken since the last call.
+    pub(super) fn take_breaks(&self, owner: u64, max: usize) -> Vec<u32> {
+        let mut leases = self.inner.lock().unwrap();
+        match leases.breaks.get_mut(&owner) {
+            Some(breaks) if breaks.len() > max => breaks.drain(..max).collect(),
+            Some(_) => leases.breaks.remove(&owner).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+use std::mem;
+
+use super::*;
+
+/// Runs around every request that a `Server` handles, such as for keeping metrics, rewriting
+/// requests or replies, or refusing requests by rules of its own.
+///
+/// A server runs the `before` of each of its middlewares in the order in which they were added,
+/// then handles the request, and then runs their `after` in the opposite order. Requests are
+/// handled one at a time, so the `after` that follows a `before` is always for the same request.
+/// Requests that fail to decode only get a reply.
+pub trait Middleware: Send {
+    /// Looks at a request before the server handles it, and may change it. Returning an error
+    /// replies with it instead, and neither the server nor the middlewares added after this one
+    /// get to see the request. The `after` of this one and of those before it still see the
+    /// reply.
+    fn before(&mut self, _msg: &mut Tmessage) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Looks at the reply to the request that `before` was last called with, and may change it.
+    fn after(&mut self, _reply: &mut io::Result<Rmessage>) {}
+}
+
+impl<F: FileSystem> Server<F> {
+    /// Runs `middleware` around every request, after all the middlewares added before it.
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    // Handles `msg`, running the middlewares around it.
+    pub(super) fn dispatch_with_middleware(&mut self, mut msg: Tmessage) -> io::Result<Rmessage> {
+        // The middlewares are taken out while the request is handled so that they can be used
+        // along with the server.
+        let mut middleware = mem::take(&mut self.middleware);
+        let mut ran = 0;
+        let mut vetoed = None;
+        for m in middleware.iter_mut() {
+            ran += 1;
+            if let Err(e) = m.before(&mut msg) {
+                vetoed = Some(e);
+                break;
+            }
+        }
+
+        let mut reply = match vetoed {
+            Some(e) => Err(e),
+            None => self.dispatch_message(Ok(msg)),
+        };
+        for m in middleware[..ran].iter_mut().rev() {
+            m.after(&mut reply);
+        }
+        self.middleware = middleware;
+        reply
+    }
+}
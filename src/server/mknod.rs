@@ -0,0 +1,124 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Which special files clients may create with Tmknod, since device nodes would give them
+//! access to the devices of the host.
+
+use std::io;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A character or block device, by its major and minor numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceNode {
+    pub block: bool,
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// Which special files clients may create with Tmknod. Everything else fails with EPERM. As an
+/// option it is `none`, `fifos`, or a comma-separated list of devices of the form `c/major/minor`
+/// or `b/major/minor`, such as `c/1/3,c/1/5`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MknodPolicy {
+    /// Nothing at all.
+    #[default]
+    None,
+    /// FIFOs and sockets, but no device nodes.
+    Fifos,
+    /// FIFOs and sockets, and the device nodes in the list.
+    Devices(Vec<DeviceNode>),
+}
+
+impl FromStr for MknodPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => return Ok(MknodPolicy::None),
+            "fifos" => return Ok(MknodPolicy::Fifos),
+            _ => {}
+        }
+
+        let mut devices = Vec::new();
+        for device in s.split(',').filter(|device| !device.is_empty()) {
+            let mut parts = device.split('/');
+            let block = match parts.next() {
+                Some("b") => true,
+                Some("c") => false,
+                _ => return Err("devices must be of the form `c/major/minor` or `b/major/minor`"),
+            };
+            let mut number = || {
+                parts
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("device numbers must be numbers")
+            };
+            let (major, minor) = (number()?, number()?);
+            if parts.next().is_some() {
+                return Err("devices must be of the form `c/major/minor` or `b/major/minor`");
+            }
+            devices.push(DeviceNode {
+                block,
+                major,
+                minor,
+            });
+        }
+        Ok(MknodPolicy::Devices(devices))
+    }
+}
+
+impl MknodPolicy {
+    // Fails with EPERM unless the policy allows creating a file of the type in `mode` with the
+    // device numbers `major` and `minor`.
+    pub(super) fn check(&self, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        let allowed = match (mode & libc::S_IFMT, self) {
+            (_, MknodPolicy::None) => false,
+            // mknod(2) creates a regular file when there is no type.
+            (0 | libc::S_IFREG | libc::S_IFIFO | libc::S_IFSOCK, _) => true,
+            (ty @ (libc::S_IFCHR | libc::S_IFBLK), MknodPolicy::Devices(devices)) => devices
+                .contains(&DeviceNode {
+                    block: ty == libc::S_IFBLK,
+                    major,
+                    minor,
+                }),
+            _ => false,
+        };
+        if !allowed {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("none".parse(), Ok(MknodPolicy::None));
+        assert_eq!("fifos".parse(), Ok(MknodPolicy::Fifos));
+        assert_eq!(
+            "c/1/3,b/7/0".parse(),
+            Ok(MknodPolicy::Devices(vec![
+                DeviceNode {
+                    block: false,
+                    major: 1,
+                    minor: 3,
+                },
+                DeviceNode {
+                    block: true,
+                    major: 7,
+                    minor: 0,
+                },
+            ]))
+        );
+        assert!("c/1".parse::<MknodPolicy>().is_err());
+        assert!("x/1/3".parse::<MknodPolicy>().is_err());
+        assert!("c/1/3/4".parse::<MknodPolicy>().is_err());
+    }
+}
@@ -2,39 +2,137 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod acl;
+mod archive;
+mod attr_cache;
+mod audit;
+mod auth;
+mod buffer_pool;
+mod coalesce;
+mod control;
+mod dispatcher;
+mod dry_run;
+mod fd_cache;
+mod fid_table;
+mod filesystem;
+mod flush;
+mod freeze;
+mod glob;
+mod id_map;
+mod inflate;
+mod lease;
+mod middleware;
+mod mknod;
+mod names;
+mod negative_cache;
+mod overlay;
+mod passthrough;
+mod policy;
+mod qid_map;
+mod quota;
 mod read_dir;
-
+mod read_frame;
+mod session;
+mod special;
+mod splice;
+mod statfs;
+mod symlinks;
+mod tmpfs;
+mod unicode;
+mod xattr;
+
+use std::cmp::max;
 use std::cmp::min;
 use std::collections::btree_map;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::fs::File;
 use std::io;
 use std::io::Cursor;
+use std::io::IoSlice;
 use std::io::Read;
 use std::io::Write;
 use std::mem;
-use std::mem::MaybeUninit;
 use std::ops::Deref;
-use std::os::unix::ffi::OsStrExt;
+use std::ops::Range;
 use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::path::PathBuf;
+use std::ptr;
 use std::str::FromStr;
-
-#[cfg(target_os = "android")]
-use libc::__fsid_t as fsid_t;
-#[cfg(not(target_os = "android"))]
-use libc::fsid_t;
-use read_dir::read_dir;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+pub use archive::Archive;
+pub use audit::AuditEvent;
+pub use audit::AuditOp;
+pub use audit::AuditSink;
+pub use audit::Identity;
+use auth::AttachHook;
+pub use auth::AttachOptions;
+pub use auth::AuthSession;
+pub use auth::Authenticator;
+use dispatcher::Deferred;
+pub use dispatcher::Dispatcher;
+use dispatcher::Job;
+pub use dry_run::DryRun;
+use dry_run::DryRunHook;
+pub use filesystem::FileSystem;
+pub use flush::CancelToken;
+pub use flush::Flusher;
+pub use freeze::Freezer;
+pub use id_map::IdRange;
+pub use id_map::ServerGidMap;
+pub use id_map::ServerIdMap;
+pub use id_map::ServerUidMap;
+pub use id_map::Squash;
+pub use lease::LeaseTable;
+pub use middleware::Middleware;
+pub use mknod::DeviceNode;
+pub use mknod::MknodPolicy;
+pub use overlay::Overlay;
+pub use passthrough::Passthrough;
+pub use policy::Access;
+pub use policy::Rule;
+pub use qid_map::QidMap;
+pub use read_dir::DirEntry;
 use serde::Deserialize;
 use serde::Serialize;
-
+pub use session::SessionStore;
+pub use special::SpecialFiles;
+pub use statfs::StatfsOverride;
+pub use symlinks::AbsoluteSymlinks;
+pub use tmpfs::Tmpfs;
+pub use unicode::NormalForm;
+pub use xattr::CapabilityXattr;
+
+use crate::protocol::checksum;
+use crate::protocol::checksum::ChecksumReader;
+use crate::protocol::checksum::ChecksumWriter;
+use crate::protocol::ioctl::Ioctl;
 use crate::protocol::*;
 use crate::syscall;
+use attr_cache::AttrCache;
+use buffer_pool::BufferPool;
+use coalesce::PendingWrite;
+use control::ControlFid;
+use control::Stats;
+use fd_cache::FdCache;
+use fid_table::FidTable;
+use negative_cache::NegativeCache;
+use quota::Quotas;
+use read_frame::ReadFrame;
+use read_frame::ReadInto;
+use splice::SplicePipe;
 
 // Tlopen and Tlcreate flags.  Taken from "include/net/9p/9p.h" in the linux tree.
 const P9_RDONLY: u32 = 0o00000000;
@@ -56,6 +154,8 @@ const P9_NOFOLLOW: u32 = 0o00400000;
 const P9_NOATIME: u32 = 0o01000000;
 const _P9_CLOEXEC: u32 = 0o02000000;
 const P9_SYNC: u32 = 0o04000000;
+// O_TMPFILE, which includes P9_DIRECTORY since it is opened against a directory.
+const P9_TMPFILE: u32 = 0o20200000;
 
 // Mapping from 9P flags to libc flags.
 const MAPPED_FLAGS: [(u32, i32); 16] = [
@@ -77,32 +177,60 @@ const MAPPED_FLAGS: [(u32, i32); 16] = [
     (P9_SYNC, libc::O_SYNC),
 ];
 
+// Topen and Tcreate modes used by the 9P2000.u dialect.  Taken from "include/net/9p/9p.h" in the
+// linux tree.
+const P9_OREAD: u8 = 0x00;
+const P9_OWRITE: u8 = 0x01;
+const P9_ORDWR: u8 = 0x02;
+const P9_OEXEC: u8 = 0x03;
+const P9_OTRUNC: u8 = 0x10;
+const _P9_OREXEC: u8 = 0x20;
+const P9_ORCLOSE: u8 = 0x40;
+const P9_OAPPEND: u8 = 0x80;
+
+// Permission and file type bits used in the mode of a 9P2000.u stat.  Taken from
+// "include/net/9p/9p.h" in the linux tree.
+const P9_DMDIR: u32 = 0x80000000;
+const _P9_DMAPPEND: u32 = 0x40000000;
+const _P9_DMEXCL: u32 = 0x20000000;
+const _P9_DMMOUNT: u32 = 0x10000000;
+const _P9_DMAUTH: u32 = 0x08000000;
+const _P9_DMTMP: u32 = 0x04000000;
+const P9_DMSYMLINK: u32 = 0x02000000;
+const P9_DMLINK: u32 = 0x01000000;
+const P9_DMDEVICE: u32 = 0x00800000;
+const P9_DMNAMEDPIPE: u32 = 0x00200000;
+const P9_DMSOCKET: u32 = 0x00100000;
+const P9_DMSETUID: u32 = 0x00080000;
+const P9_DMSETGID: u32 = 0x00040000;
+const P9_DMSETVTX: u32 = 0x00010000;
+
 // 9P Qid types.  Taken from "include/net/9p/9p.h" in the linux tree.
 const P9_QTDIR: u8 = 0x80;
 const _P9_QTAPPEND: u8 = 0x40;
 const _P9_QTEXCL: u8 = 0x20;
 const _P9_QTMOUNT: u8 = 0x10;
-const _P9_QTAUTH: u8 = 0x08;
+const P9_QTAUTH: u8 = 0x08;
 const _P9_QTTMP: u8 = 0x04;
 const P9_QTSYMLINK: u8 = 0x02;
 const _P9_QTLINK: u8 = 0x01;
 const P9_QTFILE: u8 = 0x00;
 
 // Bitmask values for the getattr request.
-const _P9_GETATTR_MODE: u64 = 0x00000001;
-const _P9_GETATTR_NLINK: u64 = 0x00000002;
-const _P9_GETATTR_UID: u64 = 0x00000004;
-const _P9_GETATTR_GID: u64 = 0x00000008;
-const _P9_GETATTR_RDEV: u64 = 0x00000010;
-const _P9_GETATTR_ATIME: u64 = 0x00000020;
-const _P9_GETATTR_MTIME: u64 = 0x00000040;
-const _P9_GETATTR_CTIME: u64 = 0x00000080;
-const _P9_GETATTR_INO: u64 = 0x00000100;
-const _P9_GETATTR_SIZE: u64 = 0x00000200;
-const _P9_GETATTR_BLOCKS: u64 = 0x00000400;
-
-const _P9_GETATTR_BTIME: u64 = 0x00000800;
-const _P9_GETATTR_GEN: u64 = 0x00001000;
+const P9_GETATTR_MODE: u64 = 0x00000001;
+const P9_GETATTR_NLINK: u64 = 0x00000002;
+const P9_GETATTR_UID: u64 = 0x00000004;
+const P9_GETATTR_GID: u64 = 0x00000008;
+const P9_GETATTR_RDEV: u64 = 0x00000010;
+const P9_GETATTR_ATIME: u64 = 0x00000020;
+const P9_GETATTR_MTIME: u64 = 0x00000040;
+const P9_GETATTR_CTIME: u64 = 0x00000080;
+const P9_GETATTR_INO: u64 = 0x00000100;
+const P9_GETATTR_SIZE: u64 = 0x00000200;
+const P9_GETATTR_BLOCKS: u64 = 0x00000400;
+
+const P9_GETATTR_BTIME: u64 = 0x00000800;
+const P9_GETATTR_GEN: u64 = 0x00001000;
 const _P9_GETATTR_DATA_VERSION: u64 = 0x00002000;
 
 const P9_GETATTR_BASIC: u64 = 0x000007ff; /* Mask for fields up to BLOCKS */
@@ -120,21 +248,61 @@ const P9_SETATTR_ATIME_SET: u32 = 0x00000080;
 const P9_SETATTR_MTIME_SET: u32 = 0x00000100;
 
 // 9p lock constants. Taken from "include/net/9p/9p.h" in the linux kernel.
-const _P9_LOCK_TYPE_RDLCK: u8 = 0;
-const _P9_LOCK_TYPE_WRLCK: u8 = 1;
+const P9_LOCK_TYPE_RDLCK: u8 = 0;
+const P9_LOCK_TYPE_WRLCK: u8 = 1;
 const P9_LOCK_TYPE_UNLCK: u8 = 2;
 const _P9_LOCK_FLAGS_BLOCK: u8 = 1;
 const _P9_LOCK_FLAGS_RECLAIM: u8 = 2;
+// Not part of the Linux definitions. Clients that negotiated `Extensions::FLOCK` set this in the
+// flags of a Tlock to request a whole-file flock(2) lock instead of a POSIX record lock.
+const P9_LOCK_FLAGS_FLOCK: u32 = 4;
 const P9_LOCK_SUCCESS: u8 = 0;
-const _P9_LOCK_BLOCKED: u8 = 1;
+const P9_LOCK_BLOCKED: u8 = 1;
 const _P9_LOCK_ERROR: u8 = 2;
 const _P9_LOCK_GRACE: u8 = 3;
 
+// The `whence` values of Tlseek, which are the same as the Linux ones.
+const P9_SEEK_DATA: u32 = 3;
+const P9_SEEK_HOLE: u32 = 4;
+
+// The `mode` flags of Tfallocate, which are the same as the Linux ones.
+const P9_FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+const P9_FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+const P9_FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
+// The `flags` of Trenameat2, which are the same as the Linux ones.
+const P9_RENAME_NOREPLACE: u32 = 1;
+const P9_RENAME_EXCHANGE: u32 = 2;
+
+// The `advice` values of Tfadvise.
+const P9_FADV_NORMAL: u32 = 0;
+const P9_FADV_RANDOM: u32 = 1;
+const P9_FADV_SEQUENTIAL: u32 = 2;
+const P9_FADV_WILLNEED: u32 = 3;
+const P9_FADV_DONTNEED: u32 = 4;
+const P9_FADV_NOREUSE: u32 = 5;
+
+// The largest extended attribute value that Linux supports. Taken from
+// "include/uapi/linux/limits.h" in the linux kernel.
+const XATTR_SIZE_MAX: u64 = 64 * 1024;
+
+// Reads and writes of regular files are split into chunks of this size so that a flushed request
+// can be aborted between them rather than only after all the I/O has finished.
+const IO_CHUNK_SIZE: usize = 16 * 1024;
+
+// The same for server-side copies, which don't go through our buffers and so can use much bigger
+// chunks.
+const COPY_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+// The size of everything but the payload of the replies that have one: size, type, tag and the
+// length of the payload.
+const PAYLOAD_HEADER_SIZE: usize = 11;
+
 // Minimum and maximum message size that we'll expect from the client.
 const MIN_MESSAGE_SIZE: u32 = 256;
 const MAX_MESSAGE_SIZE: u32 = 64 * 1024 + 24; // 64 KiB of payload plus some extra for the header
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum FileType {
     Regular,
     Directory,
@@ -156,10 +324,80 @@ impl From<libc::mode_t> for FileType {
 // 32-bit number chosen by the client. Most messages sent by clients include a fid on which to
 // operate. The fid in a Tattach message represents the root of the file system tree that the client
 // is allowed to access. A client can create more fids by walking the directory tree from that fid.
-struct Fid {
-    path: File,
-    file: Option<File>,
+struct Fid<F: FileSystem> {
+    path: F::Inode,
+    file: Option<F::Handle>,
+    // The Tlopen flags that `file` was opened with.
+    open_flags: u32,
     filetype: FileType,
+    // The offset at which the next 9P2000.u directory read is expected to start, along with the
+    // position in the directory stream of the entry that will be returned from that read.
+    dir_offset: (u64, u64),
+    // The offset at which the next sequential read of the file is expected to start, along with
+    // where the host was last told to read ahead to, for `Config::readahead`.
+    read_ahead: (u64, u64),
+    // The writes to the file that haven't been written to it yet, for `Config::coalesce_writes`,
+    // and the first error that writing them got, which the next Twrite, Tfsync or Tclunk of the
+    // fid fails with.
+    pending_write: Option<PendingWrite>,
+    write_error: Option<io::Error>,
+    // The path of the qid of the file, if it is known, for `Config::attr_cache` and
+    // `Config::negative_cache`.
+    qid_path: Option<u64>,
+    // Set when the fid refers to an extended attribute rather than to the file itself.
+    xattr: Option<Xattr>,
+    // Set when the fid reads the events of an inotify watch on the file rather than the file
+    // itself.
+    watch: Option<File>,
+    // The owners of the POSIX locks that were taken through this fid.
+    lock_owners: BTreeSet<LockOwner>,
+    // Set when the fid was opened with ORCLOSE, so that its file is removed when it is clunked.
+    remove_on_close: bool,
+    // The export that the fid was walked from.
+    export: Arc<Export>,
+    // The path that the fid was walked through from the root of `export`, such as "/usr/bin",
+    // for `Config::rules`. Renames through other fids or on the host don't change it.
+    walk_path: PathBuf,
+    // When a request last used the fid, for `Config::fid_timeout`, or None for the fids of
+    // Tattach, which never expire.
+    last_used: Option<Instant>,
+    // Who the client attached as, for audit events.
+    identity: Arc<Identity>,
+}
+
+// The owner of POSIX record locks on a single file. The host only offers per open file
+// description (OFD) locks, so each owner gets a separate open file description on which its locks
+// are placed. This lets locks taken by the same owner through different fids merge and release
+// the way POSIX locks do, while still conflicting with those of other owners.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct LockOwner {
+    dev: u64,
+    ino: u64,
+    proc_id: u32,
+    client_id: Vec<u8>,
+}
+
+// A fid created by Tauth, over which the client authenticates itself before attaching.
+struct AuthFid {
+    session: Box<dyn AuthSession>,
+    uname: P9String,
+    aname: P9String,
+    n_uname: u32,
+}
+
+// The extended attribute state of a fid created by Txattrwalk or converted by Txattrcreate.
+enum Xattr {
+    // The value of a single attribute, or the list of attribute names, that the client can read.
+    Read(Vec<u8>),
+    // An attribute that is being written by the client. It is applied when the fid is clunked.
+    // The value may take many Twrites to send, and `written` holds the parts of it that have
+    // been sent so far, merged and in order.
+    Write {
+        name: CString,
+        value: Vec<u8>,
+        written: Vec<Range<usize>>,
+        flags: libc::c_int,
+    },
 }
 
 impl From<libc::stat64> for Qid {
@@ -180,39 +418,100 @@ impl From<libc::stat64> for Qid {
     }
 }
 
-fn statat(d: &File, name: &CStr, flags: libc::c_int) -> io::Result<libc::stat64> {
-    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+// Records in `leases` that `fid` of `owner` has the file with metadata `st` open with the Tlopen
+// `flags`.
+fn track_open(leases: &LeaseTable, owner: u64, fid: u32, st: &libc::stat64, flags: u32) {
+    let writable = flags & P9_NOACCESS != P9_RDONLY;
+    leases.opened(owner, fid, (st.st_dev, st.st_ino), writable);
+}
 
-    // Safe because the kernel will only write data in `st` and we check the return
-    // value.
-    let res = unsafe {
-        libc::fstatat64(
-            d.as_raw_fd(),
-            name.as_ptr(),
-            st.as_mut_ptr(),
-            flags | libc::AT_SYMLINK_NOFOLLOW,
-        )
+// Reads the pending events of the inotify instance `watch` and encodes them as `WatchEvent`s, in
+// no more than `capacity` bytes. The encoded events are never bigger than the kernel's, so whatever
+// fits in a buffer of that size also fits in the reply.
+fn read_watch_events(watch: &File, capacity: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; capacity];
+    let len = match (&*watch).read(&mut buf) {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+        Err(e) => return Err(e),
     };
-    if res >= 0 {
-        // Safe because the kernel guarantees that the struct is now fully initialized.
-        Ok(unsafe { st.assume_init() })
+
+    let header = mem::size_of::<libc::inotify_event>();
+    let mut events = Vec::new();
+    let mut pos = 0;
+    while pos + header <= len {
+        // Safe because the kernel only returns whole events and `read_unaligned` doesn't care
+        // about the alignment of `buf`.
+        let event: libc::inotify_event =
+            unsafe { ptr::read_unaligned(buf[pos..].as_ptr() as *const libc::inotify_event) };
+        let name = &buf[pos + header..pos + header + event.len as usize];
+        let name = CStr::from_bytes_until_nul(name)
+            .map(CStr::to_bytes)
+            .unwrap_or(name);
+
+        WatchEvent {
+            mask: event.mask,
+            cookie: event.cookie,
+            name: P9String::new(name)?,
+        }
+        .encode(&mut events)?;
+
+        pos += header + event.len as usize;
+    }
+
+    Ok(events)
+}
+
+// Returns the size of the argument that `ioctl` is declared with on this host. The FS_IOC_* ones
+// are declared with a long, but the kernel only ever reads or writes an int.
+fn host_arg_size(ioctl: &Ioctl) -> usize {
+    if *ioctl == ioctl::FS_IOC_GETFLAGS || *ioctl == ioctl::FS_IOC_GETVERSION {
+        mem::size_of::<libc::c_long>()
     } else {
-        Err(io::Error::last_os_error())
+        ((ioctl.request >> 16) & 0x3fff) as usize
     }
 }
 
-fn stat(f: &File) -> io::Result<libc::stat64> {
-    // Safe because this is a constant value and a valid C string.
-    let pathname = unsafe { CStr::from_bytes_with_nul_unchecked(b"\0") };
+// Returns the request number of `ioctl` on this host. It only differs from the one on the wire in
+// the size of the argument and, on a few architectures, in how the direction is encoded.
+fn host_request(ioctl: &Ioctl) -> u32 {
+    let dir = ioctl.request >> 30;
+    let size = host_arg_size(ioctl) as u32;
+    let ty_nr = ioctl.request & 0xffff;
+    if cfg!(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )) {
+        // _IOC_NONE is 1 rather than 0 and _IOC_WRITE is 4 rather than 1.
+        let dir = match dir {
+            0 => 1,
+            d => (d & 2) | ((d & 1) << 2),
+        };
+        (dir << 29) | (size << 16) | ty_nr
+    } else {
+        (dir << 30) | (size << 16) | ty_nr
+    }
+}
 
-    statat(f, pathname, libc::AT_EMPTY_PATH)
+// Returns the generation number of the inode that `f` refers to.
+fn get_generation(f: &File) -> io::Result<u64> {
+    // Big enough for the long that the ioctl is declared with.
+    let mut gen: [libc::c_int; 2] = [0; 2];
+    let request = host_request(&ioctl::FS_IOC_GETVERSION);
+    // Safe because the kernel only writes an int to `gen` and we check the return value.
+    syscall!(unsafe { libc::ioctl(f.as_raw_fd(), request as _, gen.as_mut_ptr()) })?;
+    Ok(u64::from(gen[0] as u32))
 }
 
 fn string_to_cstring(s: String) -> io::Result<CString> {
     CString::new(s).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
 }
 
-fn error_to_rmessage(err: io::Error) -> Rmessage {
+fn error_to_rmessage(err: io::Error, dialect: Dialect) -> Rmessage {
     let errno = if let Some(errno) = err.raw_os_error() {
         errno
     } else {
@@ -240,9 +539,17 @@ fn error_to_rmessage(err: io::Error) -> Rmessage {
         }
     };
 
-    Rmessage::Lerror(Rlerror {
-        ecode: errno as u32,
-    })
+    match dialect {
+        Dialect::Linux => Rmessage::Lerror(Rlerror {
+            ecode: errno as u32,
+        }),
+        Dialect::Legacy | Dialect::Unix => Rmessage::Error(Rerror {
+            // Clients primarily use the errno so an empty string is fine if the description of
+            // the error is somehow not a valid 9P string.
+            ename: P9String::new(err.to_string()).unwrap_or_default(),
+            errno: errno as u32,
+        }),
+    }
 }
 
 // Sigh.. Cow requires the underlying type to implement Clone.
@@ -276,97 +583,809 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
-pub type ServerIdMap<T> = BTreeMap<T, T>;
-pub type ServerUidMap = ServerIdMap<libc::uid_t>;
-pub type ServerGidMap = ServerIdMap<libc::gid_t>;
+// Locks `fid`. A fid is only ever locked by the request that is using it, and by the server while
+// it goes over all of them.
+fn lock_fid<T>(fid: &Arc<Mutex<T>>) -> MutexGuard<'_, T> {
+    fid.lock().unwrap()
+}
 
-fn map_id_from_host<T: Clone + Ord>(map: &ServerIdMap<T>, id: T) -> T {
-    map.get(&id).map_or(id.clone(), |v| v.clone())
+// Locks the fids `a` and `b` of a request that uses two, which may well be the same fid. The
+// second guard is None if they are.
+fn lock_pair<'a, T>(
+    a: &'a Arc<Mutex<T>>,
+    b: &'a Arc<Mutex<T>>,
+) -> (MutexGuard<'a, T>, Option<MutexGuard<'a, T>>) {
+    let first = lock_fid(a);
+    let second = (!Arc::ptr_eq(a, b)).then(|| lock_fid(b));
+    (first, second)
 }
 
-// Performs an ascii case insensitive lookup and returns an O_PATH fd for the entry, if found.
-fn ascii_casefold_lookup(proc: &File, parent: &File, name: &[u8]) -> io::Result<File> {
-    let mut dir = open_fid(proc, parent, P9_DIRECTORY)?;
-    let mut dirents = read_dir(&mut dir, 0)?;
+// Returns the host owner of a file that a client just created and asked to belong to `gid`: the
+// host group that `gid` maps to, or the squashed owner if identities are squashed. Ids that
+// shouldn't be changed from whatever the file system picks are `u32::MAX`, as for chown(2).
+fn created_owner(export: &Export, gid: u32) -> (u32, u32) {
+    match (export.all_squash, export.gid_map.find_host(gid)) {
+        (Some(squash), _) => (squash.host_uid, squash.host_gid),
+        (None, Some(gid)) => (libc::uid_t::MAX, gid),
+        (None, None) => (libc::uid_t::MAX, libc::gid_t::MAX),
+    }
+}
 
-    while let Some(entry) = dirents.next().transpose()? {
-        if name.eq_ignore_ascii_case(entry.name.as_bytes()) {
-            return lookup(parent, entry.name.as_c_str());
-        }
+// Gives `inode`, a file of `export` that was just created with the mode `mode` and whose metadata
+// is now `st`, all of the permissions in `mode` if the export has a umask, since the umask of the
+// process may have taken some of them away.
+fn apply_create_mode<F: FileSystem>(
+    fs: &F,
+    export: &Export,
+    inode: &F::Inode,
+    st: &libc::stat64,
+    mode: u32,
+) -> io::Result<()> {
+    if export.umask.is_none() || st.st_mode & 0o777 == mode & 0o777 {
+        return Ok(());
+    }
+    let attr = Tsetattr {
+        fid: 0,
+        valid: P9_SETATTR_MODE,
+        mode: (st.st_mode & 0o7000) | (mode & 0o777),
+        uid: 0,
+        gid: 0,
+        size: 0,
+        atime_sec: 0,
+        atime_nsec: 0,
+        mtime_sec: 0,
+        mtime_nsec: 0,
+    };
+    fs.setattr(inode, None, &attr)
+}
+
+// Fills in the basic attributes of an Rgetattr from `st`, with the owner as the host ids.
+#[allow(clippy::unnecessary_cast)] // nlink_t is u32 on 32-bit platforms
+fn stat_to_attr(st: &libc::stat64) -> Rgetattr {
+    Rgetattr {
+        valid: P9_GETATTR_BASIC,
+        qid: (*st).into(),
+        mode: st.st_mode,
+        uid: st.st_uid,
+        gid: st.st_gid,
+        nlink: st.st_nlink as u64,
+        rdev: st.st_rdev,
+        size: st.st_size as u64,
+        blksize: st.st_blksize as u64,
+        blocks: st.st_blocks as u64,
+        atime_sec: st.st_atime as u64,
+        atime_nsec: st.st_atime_nsec as u64,
+        mtime_sec: st.st_mtime as u64,
+        mtime_nsec: st.st_mtime_nsec as u64,
+        ctime_sec: st.st_ctime as u64,
+        ctime_nsec: st.st_ctime_nsec as u64,
+        btime_sec: 0,
+        btime_nsec: 0,
+        gen: 0,
+        data_version: 0,
+    }
+}
+
+// Applies a pending extended attribute write once the client has finished sending the value. An
+// empty value removes the attribute, which is how the Linux client implements removexattr.
+fn write_xattr<F: FileSystem>(fs: &F, fid: &Fid<F>) -> io::Result<()> {
+    let (name, value, written, flags) = match fid.xattr {
+        Some(Xattr::Write {
+            ref name,
+            ref value,
+            ref written,
+            flags,
+        }) => (name, value, written, flags),
+        _ => return Ok(()),
+    };
+
+    // The client must send every byte of the value that it said it would.
+    if !value.is_empty() && written.first() != Some(&(0..value.len())) {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    if value.is_empty() {
+        fs.removexattr(&fid.path, fid.file.as_ref(), name)
+    } else {
+        let value = acl::to_host(&fid.export, name, value)?;
+        fs.setxattr(&fid.path, fid.file.as_ref(), name, &value, flags)
+    }
+}
+
+// Adds `range` to `ranges`, which are in order and neither overlap nor touch, merging it with the
+// ones that it overlaps or touches so that they stay that way.
+fn add_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    if range.is_empty() {
+        return;
     }
+    let mut merged = range;
+    ranges.retain(|r| {
+        let apart = r.end < merged.start || merged.end < r.start;
+        if !apart {
+            merged = min(r.start, merged.start)..max(r.end, merged.end);
+        }
+        apart
+    });
+    let i = ranges.partition_point(|r| r.start < merged.start);
+    ranges.insert(i, merged);
+}
+
+// Commits the entries of the directory `dir` to stable storage, so that the files that were just
+// created in, renamed into or out of, or removed from it stay that way if the host crashes.
+fn sync_dir<F: FileSystem>(fs: &F, dir: &F::Inode) -> io::Result<()> {
+    let handle = fs.open(dir, P9_DIRECTORY | P9_RDONLY)?;
+    fs.fsync(&handle, false)
+}
 
-    Err(io::Error::from_raw_os_error(libc::ENOENT))
+// Returns the owner of the POSIX locks that a client process holds on the file behind `fid`.
+fn lock_owner<F: FileSystem>(
+    fs: &F,
+    fid: &Fid<F>,
+    proc_id: u32,
+    client_id: &P9String,
+) -> io::Result<LockOwner> {
+    let st = fs.stat(&fid.path)?;
+    Ok(LockOwner {
+        dev: st.st_dev,
+        ino: st.st_ino,
+        proc_id,
+        client_id: client_id.as_bytes().to_vec(),
+    })
+}
+
+// Converts the byte range of a 9P lock request into a `flock64`. A length of 0 extends the range
+// to the end of the file for both.
+fn to_flock(l_type: libc::c_int, start: u64, length: u64) -> io::Result<libc::flock64> {
+    let einval = || io::Error::from_raw_os_error(libc::EINVAL);
+    Ok(libc::flock64 {
+        l_type: l_type as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: start.try_into().map_err(|_| einval())?,
+        l_len: length.try_into().map_err(|_| einval())?,
+        // Must be 0 for OFD locks.
+        l_pid: 0,
+    })
 }
 
-fn lookup(parent: &File, name: &CStr) -> io::Result<File> {
+// Takes or releases a flock(2) lock on `file`. These locks always cover the whole file and belong
+// to the open file description, so each fid owns its own flock lock independently of the POSIX
+// locks of any lock owner and it is released when the fid is clunked.
+fn flock(file: &File, l_type: libc::c_int, start: u64, length: u64) -> io::Result<u8> {
+    if start != 0 || length != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let operation = match l_type {
+        libc::F_RDLCK => libc::LOCK_SH,
+        libc::F_WRLCK => libc::LOCK_EX,
+        _ => libc::LOCK_UN,
+    };
+
+    // Like POSIX locks, the client retries blocking requests when it gets P9_LOCK_BLOCKED back.
     // Safe because this doesn't modify any memory and we check the return value.
-    let fd = syscall!(unsafe {
-        libc::openat64(
-            parent.as_raw_fd(),
-            name.as_ptr(),
-            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-        )
-    })?;
+    match syscall!(unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) }) {
+        Ok(_) => Ok(P9_LOCK_SUCCESS),
+        Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(P9_LOCK_BLOCKED),
+        Err(e) => Err(e),
+    }
+}
+
+// Opens a new open file description for `fid` on which the OFD locks of a single lock owner are
+// placed. It is opened for reading and writing if possible so that the owner can take both kinds
+// of locks, falling back to the access mode `fl` of the client's own file otherwise.
+fn open_lock_file<F: FileSystem>(fs: &F, fid: &Fid<F>, fl: libc::c_int) -> io::Result<F::Handle> {
+    fs.open(&fid.path, P9_NONBLOCK | P9_RDWR)
+        .or_else(|_| fs.open(&fid.path, P9_NONBLOCK | (fl & libc::O_ACCMODE) as u32))
+}
 
-    // Safe because we just opened this fd.
-    Ok(unsafe { File::from_raw_fd(fd) })
+// Returns the host file that `fid` has open, for the requests that only make sense for one.
+fn open_host_file<'a, F: FileSystem>(fs: &F, fid: &'a Fid<F>) -> io::Result<&'a File> {
+    let handle = fid.file.as_ref().ok_or_else(ebadf)?;
+    fs.host_file(handle)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EOPNOTSUPP))
 }
 
-fn do_walk(
-    proc: &File,
+// Walks `wnames` from `start`, which was walked to through `start_path`, pushing the metadata of
+// every file along the way to `mds`, and returns the last one.
+fn do_walk<F: FileSystem>(
+    fs: &F,
+    export: &Export,
+    cfg: &Config,
     wnames: Vec<P9String>,
-    start: &File,
-    ascii_casefold: bool,
+    start: &F::Inode,
+    start_path: &Path,
     mds: &mut Vec<libc::stat64>,
-) -> io::Result<File> {
+) -> io::Result<F::Inode> {
     let mut current = MaybeOwned::Borrowed(start);
+    let mut path = start_path.to_path_buf();
+    // Everything that can be reached is on the same device as the root, so that is also the
+    // device of `start`.
+    let dev = if export.no_cross_mounts {
+        Some(fs.stat(start)?.st_dev)
+    } else {
+        None
+    };
 
     for wname in wnames {
-        current = MaybeOwned::Owned(lookup(current.as_ref(), wname.as_c_str()).or_else(|e| {
-            if ascii_casefold {
-                if let Some(libc::ENOENT) = e.raw_os_error() {
-                    return ascii_casefold_lookup(proc, current.as_ref(), wname.as_bytes());
-                }
+        check_single_name(wname.as_bytes())?;
+        // Going up is done by the server rather than the file system so that it stops at the
+        // root of the export, which file systems that resolve names beneath their parent
+        // wouldn't let through anyway.
+        let next = if wname.as_bytes() == b".." {
+            match fs.parent(export, current.as_ref(), &path) {
+                Ok((parent, _)) => parent,
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => fs.try_clone(&current)?,
+                Err(e) => return Err(e),
             }
-
-            Err(e)
-        })?);
-        mds.push(stat(&current)?);
+        } else if is_hidden(cfg, wname.as_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        } else {
+            names::lookup(fs, cfg, current.as_ref(), wname.as_c_str())?
+        };
+        let st = fs.stat(&next)?;
+        if dev.is_some_and(|dev| st.st_dev != dev) {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        if cfg.special_files.hides(st.st_mode) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        current = MaybeOwned::Owned(next);
+        path = policy::join(&path, &wname);
+        mds.push(st);
     }
 
     match current {
         MaybeOwned::Owned(owned) => Ok(owned),
-        MaybeOwned::Borrowed(borrowed) => borrowed.try_clone(),
+        MaybeOwned::Borrowed(borrowed) => fs.try_clone(borrowed),
+    }
+}
+
+// Returns true if opening a file with the Tlopen flags `flags` could change it.
+fn open_modifies(flags: u32) -> bool {
+    flags & P9_NOACCESS != P9_RDONLY || flags & (P9_CREATE | P9_TRUNC) != 0
+}
+
+// Returns true if `fid` has a regular file open for writing.
+fn open_for_writing<F: FileSystem>(fid: &Fid<F>) -> bool {
+    fid.filetype == FileType::Regular
+        && fid.xattr.is_none()
+        && matches!(fid.open_flags & P9_NOACCESS, P9_WRONLY | P9_RDWR)
+}
+
+// Returns the fid through which `msg` could change an exported tree, if it could change one at
+// all. Tremove isn't included because it clunks the fid even when it fails, so it checks for
+// itself.
+fn modified_fid(msg: &Tmessage) -> Option<u32> {
+    match msg {
+        Tmessage::Open(open) => Some(open.fid)
+            .filter(|_| open_modifies(omode_to_p9_flags(open.mode)) || open.mode & P9_ORCLOSE != 0),
+        Tmessage::Lopen(lopen) => Some(lopen.fid).filter(|_| open_modifies(lopen.flags)),
+        Tmessage::Write(write) => Some(write.fid),
+        Tmessage::Create(create) => Some(create.fid),
+        Tmessage::Wstat(wstat) => Some(wstat.fid),
+        Tmessage::Lcreate(lcreate) => Some(lcreate.fid),
+        Tmessage::Symlink(symlink) => Some(symlink.fid),
+        Tmessage::Mknod(mknod) => Some(mknod.dfid),
+        Tmessage::Rename(rename) => Some(rename.fid),
+        Tmessage::SetAttr(set_attr) => Some(set_attr.fid),
+        Tmessage::XattrCreate(xattr_create) => Some(xattr_create.fid),
+        Tmessage::CopyRange(copy_range) => Some(copy_range.dst_fid),
+        Tmessage::CloneRange(clone_range) => Some(clone_range.dst_fid),
+        Tmessage::Fallocate(fallocate) => Some(fallocate.fid),
+        Tmessage::Link(link) => Some(link.dfid),
+        Tmessage::Mkdir(mkdir) => Some(mkdir.dfid),
+        // Renames between exports are refused anyway.
+        Tmessage::RenameAt(rename_at) => Some(rename_at.newdirfid),
+        Tmessage::RenameAt2(rename_at2) => Some(rename_at2.newdirfid),
+        Tmessage::UnlinkAt(unlink_at) => Some(unlink_at.dirfd),
+        _ => None,
+    }
+}
+
+// Returns the fids that `msg` uses, other than the new ones that it creates and the ones that it
+// clunks.
+fn used_fids(msg: &Tmessage) -> Vec<u32> {
+    match msg {
+        Tmessage::Walk(walk) => vec![walk.fid],
+        Tmessage::Read(read) => vec![read.fid],
+        Tmessage::Write(write) => vec![write.fid],
+        Tmessage::Open(open) => vec![open.fid],
+        Tmessage::Create(create) => vec![create.fid],
+        Tmessage::Stat(stat) => vec![stat.fid],
+        Tmessage::Wstat(wstat) => vec![wstat.fid],
+        Tmessage::Statfs(statfs) => vec![statfs.fid],
+        Tmessage::Lopen(lopen) => vec![lopen.fid],
+        Tmessage::Lcreate(lcreate) => vec![lcreate.fid],
+        Tmessage::Symlink(symlink) => vec![symlink.fid],
+        Tmessage::Mknod(mknod) => vec![mknod.dfid],
+        Tmessage::Rename(rename) => vec![rename.fid, rename.dfid],
+        Tmessage::Readlink(readlink) => vec![readlink.fid],
+        Tmessage::GetAttr(get_attr) => vec![get_attr.fid],
+        Tmessage::SetAttr(set_attr) => vec![set_attr.fid],
+        Tmessage::XattrWalk(xattr_walk) => vec![xattr_walk.fid],
+        Tmessage::XattrCreate(xattr_create) => vec![xattr_create.fid],
+        Tmessage::Readdir(readdir) => vec![readdir.fid],
+        Tmessage::ReaddirPlus(readdirplus) => vec![readdirplus.fid],
+        Tmessage::CopyRange(copy_range) => vec![copy_range.src_fid, copy_range.dst_fid],
+        Tmessage::CloneRange(clone_range) => vec![clone_range.src_fid, clone_range.dst_fid],
+        Tmessage::Lseek(lseek) => vec![lseek.fid],
+        Tmessage::Fallocate(fallocate) => vec![fallocate.fid],
+        Tmessage::Fadvise(fadvise) => vec![fadvise.fid],
+        Tmessage::Ioctl(ioctl) => vec![ioctl.fid],
+        Tmessage::Watch(watch) => vec![watch.fid],
+        Tmessage::Lease(lease) => vec![lease.fid],
+        Tmessage::RenameAt2(rename_at2) => vec![rename_at2.olddirfid, rename_at2.newdirfid],
+        Tmessage::Fsync(fsync) => vec![fsync.fid],
+        Tmessage::Lock(lock) => vec![lock.fid],
+        Tmessage::GetLock(get_lock) => vec![get_lock.fid],
+        Tmessage::Link(link) => vec![link.dfid, link.fid],
+        Tmessage::Mkdir(mkdir) => vec![mkdir.dfid],
+        Tmessage::RenameAt(rename_at) => vec![rename_at.olddirfid, rename_at.newdirfid],
+        Tmessage::UnlinkAt(unlink_at) => vec![unlink_at.dirfd],
+        _ => Vec::new(),
+    }
+}
+
+// Fails with EXDEV unless `name` is a single name, as looking it up beneath its directory would.
+// Names are checked for this first, so that one with a "/" in it can't get around the checks that
+// are made on each name, like `Config::hide`.
+fn check_single_name(name: &[u8]) -> io::Result<()> {
+    if name.contains(&b'/') {
+        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+    }
+    Ok(())
+}
+
+// Returns true if `name` matches one of the `hide` patterns, in which case clients must not be
+// able to tell that it exists.
+fn is_hidden(cfg: &Config, name: &[u8]) -> bool {
+    let name = cfg.fold_name(name);
+    !matches!(&*name, b"" | b"." | b"..")
+        && cfg
+            .hide
+            .iter()
+            .any(|pattern| glob::matches(&cfg.fold_name(pattern.as_bytes()), &name))
+}
+
+// Fails the way unlinkat(2) on Linux would if `unlink_at` can't remove its entry of `dir`, for
+// `Config::strict_unlink`.
+fn check_unlink<F: FileSystem>(
+    fs: &F,
+    cfg: &Config,
+    dir: &F::Inode,
+    unlink_at: &Tunlinkat,
+) -> io::Result<()> {
+    let err = |errno| Err(io::Error::from_raw_os_error(errno));
+    let rmdir = match unlink_at.flags {
+        0 => false,
+        flags if flags == libc::AT_REMOVEDIR as u32 => true,
+        _ => return err(libc::EINVAL),
+    };
+    match (unlink_at.name.as_bytes(), rmdir) {
+        (b"", _) => return err(libc::ENOENT),
+        (b".", true) => return err(libc::EINVAL),
+        (b"..", true) => return err(libc::ENOTEMPTY),
+        (b"." | b"..", false) => return err(libc::EISDIR),
+        _ => {}
+    }
+
+    let entry = names::lookup(fs, cfg, dir, unlink_at.name.as_c_str())?;
+    let is_dir = fs.stat(&entry)?.st_mode & libc::S_IFMT == libc::S_IFDIR;
+    match (is_dir, rmdir) {
+        (true, false) => err(libc::EISDIR),
+        (false, true) => err(libc::ENOTDIR),
+        _ => Ok(()),
+    }
+}
+
+// Returns the names of directory entries that `msg` uses other than through a walk, along with
+// the error that it fails with if they are hidden. Existing entries are not found, and new ones
+// can't be created since clients wouldn't see them afterwards.
+fn entry_names(msg: &Tmessage) -> Vec<(&P9String, libc::c_int)> {
+    match msg {
+        Tmessage::Create(create) => vec![(&create.name, libc::EACCES)],
+        Tmessage::Wstat(wstat) => vec![(&wstat.stat.name, libc::EACCES)],
+        Tmessage::Lcreate(lcreate) => vec![(&lcreate.name, libc::EACCES)],
+        Tmessage::Symlink(symlink) => vec![(&symlink.name, libc::EACCES)],
+        Tmessage::Mknod(mknod) => vec![(&mknod.name, libc::EACCES)],
+        Tmessage::Rename(rename) => vec![(&rename.name, libc::EACCES)],
+        Tmessage::Link(link) => vec![(&link.name, libc::EACCES)],
+        Tmessage::Mkdir(mkdir) => vec![(&mkdir.name, libc::EACCES)],
+        Tmessage::RenameAt(rename_at) => vec![
+            (&rename_at.oldname, libc::ENOENT),
+            (&rename_at.newname, libc::EACCES),
+        ],
+        Tmessage::RenameAt2(rename_at2) => vec![
+            (&rename_at2.oldname, libc::ENOENT),
+            (&rename_at2.newname, libc::EACCES),
+        ],
+        Tmessage::UnlinkAt(unlink_at) => vec![(&unlink_at.name, libc::ENOENT)],
+        _ => Vec::new(),
+    }
+}
+
+// Converts a 9P2000.u open mode into the equivalent Tlopen flags.
+fn omode_to_p9_flags(mode: u8) -> u32 {
+    let mut flags = match mode & P9_OEXEC {
+        P9_OWRITE => P9_WRONLY,
+        P9_ORDWR => P9_RDWR,
+        // Execution is checked by the client so OEXEC only needs read access.
+        P9_OREAD | P9_OEXEC => P9_RDONLY,
+        _ => unreachable!(),
+    };
+
+    if mode & P9_OTRUNC != 0 {
+        flags |= P9_TRUNC;
+    }
+    if mode & P9_OAPPEND != 0 {
+        flags |= P9_APPEND;
+    }
+
+    flags
+}
+
+// Converts the permission bits of a 9P2000.u mode into a unix mode, ignoring the file type bits.
+fn p9_perm_to_mode(perm: u32) -> u32 {
+    let mut mode = perm & 0o777;
+    if perm & P9_DMSETUID != 0 {
+        mode |= libc::S_ISUID;
+    }
+    if perm & P9_DMSETGID != 0 {
+        mode |= libc::S_ISGID;
+    }
+    if perm & P9_DMSETVTX != 0 {
+        mode |= libc::S_ISVTX;
+    }
+
+    mode
+}
+
+// Converts a unix mode into the equivalent 9P2000.u mode.
+fn mode_to_p9_perm(mode: libc::mode_t) -> u32 {
+    let mut perm = mode & 0o777;
+    if mode & libc::S_ISUID != 0 {
+        perm |= P9_DMSETUID;
+    }
+    if mode & libc::S_ISGID != 0 {
+        perm |= P9_DMSETGID;
+    }
+    if mode & libc::S_ISVTX != 0 {
+        perm |= P9_DMSETVTX;
+    }
+
+    perm | match mode & libc::S_IFMT {
+        libc::S_IFDIR => P9_DMDIR,
+        libc::S_IFLNK => P9_DMSYMLINK,
+        libc::S_IFCHR | libc::S_IFBLK => P9_DMDEVICE,
+        libc::S_IFIFO => P9_DMNAMEDPIPE,
+        libc::S_IFSOCK => P9_DMSOCKET,
+        _ => 0,
+    }
+}
+
+// Builds the 9P2000.u stat for a file whose metadata is `st` and whose qid is `qid`. `p9name` is
+// the name reported to the client, and `readlink` returns the target of the file if it is a
+// symlink.
+fn to_stat<R>(
+    export: &Export,
+    st: &libc::stat64,
+    qid: Qid,
+    readlink: R,
+    p9name: P9String,
+) -> io::Result<Stat>
+where
+    R: FnOnce() -> io::Result<Vec<u8>>,
+{
+    let extension = match st.st_mode & libc::S_IFMT {
+        libc::S_IFLNK => P9String::new(readlink()?)?,
+        ty @ (libc::S_IFCHR | libc::S_IFBLK) => {
+            // Safe because these only perform arithmetic on their argument.
+            let (major, minor) = unsafe { (libc::major(st.st_rdev), libc::minor(st.st_rdev)) };
+            let kind = if ty == libc::S_IFCHR { 'c' } else { 'b' };
+            P9String::new(format!("{} {} {}", kind, major, minor))?
+        }
+        _ => Default::default(),
+    };
+
+    let uid = export.uid_to_client(st.st_uid);
+    let gid = export.gid_to_client(st.st_gid);
+
+    Ok(Stat {
+        ty: 0,
+        dev: 0,
+        qid,
+        mode: mode_to_p9_perm(export.mode_to_client(st.st_mode)),
+        // TODO: deal with the 2038 problem before 2038
+        atime: st.st_atime as u32,
+        mtime: st.st_mtime as u32,
+        length: st.st_size as u64,
+        name: p9name,
+        uid: P9String::new(uid.to_string())?,
+        gid: P9String::new(gid.to_string())?,
+        muid: Default::default(),
+        extension,
+        n_uid: uid,
+        n_gid: gid,
+        n_muid: u32::MAX,
+    })
+}
+
+// Returns EINTR if the request that is currently being handled has been flushed.
+fn check_cancelled(cancel: &CancelToken) -> io::Result<()> {
+    if cancel.is_cancelled() {
+        Err(io::Error::from_raw_os_error(libc::EINTR))
+    } else {
+        Ok(())
     }
 }
 
-fn open_fid(proc: &File, path: &File, p9_flags: u32) -> io::Result<File> {
-    let pathname = string_to_cstring(format!("self/fd/{}", path.as_raw_fd()))?;
+// Reads the next frame from `reader` into `buf`, checking its size before reading any more of it
+// so that a client can't make us buffer more than the msize it agreed to.
+fn receive_frame<R: Read>(reader: &mut R, msize: u32, buf: &mut Vec<u8>) -> io::Result<()> {
+    let size: u32 = WireFormat::decode(reader)?;
+    if size > msize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame size ({}) exceeds msize ({})", size, msize),
+        ));
+    }
+    buf.extend_from_slice(&size.to_le_bytes());
+    let body = u64::from(size).saturating_sub(mem::size_of::<u32>() as u64);
+    if reader.take(body).read_to_end(buf)? < body as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
 
-    // We always open files with O_CLOEXEC.
-    let mut flags: i32 = libc::O_CLOEXEC;
-    for &(p9f, of) in &MAPPED_FLAGS {
-        if (p9_flags & p9f) != 0 {
-            flags |= of;
+// Writes all of `bufs` to `writer`, in as few calls to `write_vectored` as it takes.
+fn write_all_vectored<W: Write>(writer: &mut W, mut bufs: &mut [IoSlice]) -> io::Result<()> {
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
+
+// Has the host read up to `window` bytes of `file` past a read of `count` bytes at `offset`
+// ahead of time, if the read starts where the last one ended according to `state`, which holds
+// the offset that the next read is expected at and how far the host was told to read ahead to.
+// The host is only told again once the reads are halfway through what it was last told to read
+// ahead.
+fn read_ahead(file: &File, state: &mut (u64, u64), offset: u64, count: u32, window: u64) {
+    let (next, ahead) = *state;
+    let end = offset.saturating_add(u64::from(count));
+    if offset != next {
+        // What was read ahead for the reads before is of no use for the ones after.
+        *state = (end, end);
+        return;
+    }
+    *state = (end, ahead);
+    if window == 0 || end.saturating_add(window / 2) < ahead {
+        return;
+    }
 
-    if p9_flags & P9_NOACCESS == P9_RDONLY {
-        flags |= libc::O_RDONLY;
+    let start = max(ahead, end);
+    let ahead = end.saturating_add(window);
+    if let (Ok(offset), Ok(len)) = (
+        libc::off64_t::try_from(start),
+        libc::off64_t::try_from(ahead - start),
+    ) {
+        // SAFETY: this doesn't modify any memory. It is only advice, so it doesn't matter if it
+        // fails.
+        unsafe { libc::posix_fadvise64(file.as_raw_fd(), offset, len, libc::POSIX_FADV_WILLNEED) };
     }
+    *state = (end, ahead);
+}
 
-    // Safe because this doesn't modify any memory and we check the return value. We need to
-    // clear the O_NOFOLLOW flag because we want to follow the proc symlink.
-    let fd = syscall!(unsafe {
-        libc::openat64(
-            proc.as_raw_fd(),
-            pathname.as_ptr(),
-            flags & !libc::O_NOFOLLOW,
-        )
-    })?;
+// Not every libc has this.
+const RWF_NOWAIT: libc::c_int = 0x00000008;
+
+// Reads the regular file `file` from `offset` on into `buf` as far as it can without waiting for
+// the disk, and returns how much was read and whether that was all of it. Reads that would have
+// to wait, and reads of files or kernels that can't tell, stop early.
+fn read_cached(file: &File, buf: &mut [u8], offset: u64) -> (usize, bool) {
+    let mut count = 0;
+    while count < buf.len() {
+        let pos = offset + count as u64;
+        // Shifted in two steps like the kernel puts it back together, since a shift by the
+        // whole width of a 64-bit long would overflow.
+        let half = libc::c_ulong::BITS / 2;
+        let iov = libc::iovec {
+            iov_base: buf[count..].as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len() - count,
+        };
+        // SAFETY: the kernel only writes to the rest of `buf`, which the iovec covers, and we
+        // check the return value. The syscall is used directly since not every libc has a
+        // wrapper for it, and it takes the offset in two halves.
+        let res = syscall!(unsafe {
+            libc::syscall(
+                libc::SYS_preadv2,
+                file.as_raw_fd(),
+                &iov,
+                1,
+                pos as libc::c_ulong,
+                (pos >> half >> half) as libc::c_ulong,
+                RWF_NOWAIT,
+            )
+        });
+        match res {
+            // The end of the file.
+            Ok(0) => return (count, true),
+            Ok(n) => count += n as usize,
+            Err(_) => return (count, false),
+        }
+    }
+    (count, true)
+}
+
+// Reads a file of type `filetype` from `offset` on into `buf` by calling `read` with each part
+// of `buf` and where in the file it starts, and returns how much was read. Like read(2), it only
+// fails if nothing was read; an error or a flush after that just cuts the read short.
+fn read_file<R>(
+    mut read: R,
+    filetype: FileType,
+    buf: &mut [u8],
+    offset: u64,
+    cancel: &CancelToken,
+) -> io::Result<usize>
+where
+    R: FnMut(&mut [u8], u64) -> io::Result<usize>,
+{
+    // Short reads of anything other than a regular file don't mean that there is no more data,
+    // so only regular files are read in chunks.
+    let chunk_size = if filetype == FileType::Regular {
+        IO_CHUNK_SIZE
+    } else {
+        buf.len()
+    };
+
+    let mut count = 0;
+    while count < buf.len() {
+        let end = min(count + chunk_size, buf.len());
+        let n = match check_cancelled(cancel)
+            .and_then(|()| read(&mut buf[count..end], offset + count as u64))
+        {
+            Ok(n) => n,
+            Err(_) if count > 0 => break,
+            Err(e) => return Err(e),
+        };
+        let short = n < end - count;
+        count += n;
+        if short {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+// Writes `data` to `file` from `offset` on, a chunk at a time so that the request can be flushed
+// in between, and returns how much was written. Like `read_file`, it only fails if nothing was
+// written.
+fn write_chunks<F: FileSystem>(
+    fs: &F,
+    cancel: &CancelToken,
+    file: &F::Handle,
+    data: &[u8],
+    offset: u64,
+) -> io::Result<usize> {
+    let mut count = 0;
+    for chunk in data.chunks(IO_CHUNK_SIZE) {
+        let n = match check_cancelled(cancel)
+            .and_then(|()| fs.write(file, chunk, offset + count as u64))
+        {
+            Ok(n) => n,
+            Err(_) if count > 0 => break,
+            Err(e) => return Err(e),
+        };
+        count += n;
+        if n < chunk.len() {
+            break;
+        }
+    }
+    Ok(count)
+}
 
-    // Safe because we just opened this fd and we know it is valid.
-    Ok(unsafe { File::from_raw_fd(fd) })
+// Copies `total` bytes from `src` at `src_offset` to `dst` at `dst_offset`, a chunk at a time so
+// that the request can be flushed in between, and returns how much was copied. Like `read_file`,
+// it only fails if nothing was copied.
+fn copy_chunks(
+    cancel: &CancelToken,
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    total: u64,
+) -> io::Result<u64> {
+    let mut src_offset = src_offset as libc::loff_t;
+    let mut dst_offset = dst_offset as libc::loff_t;
+    let mut count = 0;
+    while count < total {
+        let len = min(total - count, COPY_CHUNK_SIZE) as usize;
+        let res = check_cancelled(cancel).and_then(|()| {
+            // Safe because the kernel only reads and writes through the file descriptors, which
+            // we own, and the offsets, which live on the stack, and we check the return value.
+            syscall!(unsafe {
+                libc::copy_file_range(
+                    src.as_raw_fd(),
+                    &mut src_offset,
+                    dst.as_raw_fd(),
+                    &mut dst_offset,
+                    len,
+                    0,
+                )
+            })
+        });
+        let n = match res {
+            Ok(n) => n as u64,
+            Err(_) if count > 0 => break,
+            Err(e) => return Err(e),
+        };
+        count += n;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+// Parses the string form of a uid or gid in a stat.
+fn parse_id(id: &P9String) -> io::Result<u32> {
+    std::str::from_utf8(id.as_bytes())
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+/// How `Config::sync_on_close` commits files to stable storage. As an option it is `fsync` or
+/// `fdatasync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncMode {
+    /// With fsync(2), which commits the data and metadata of the file.
+    Fsync,
+    /// With fdatasync(2), which leaves out the metadata that isn't needed to read the data back.
+    Fdatasync,
+}
+
+impl FromStr for SyncMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fsync" => Ok(SyncMode::Fsync),
+            "fdatasync" => Ok(SyncMode::Fdatasync),
+            _ => Err("`sync_on_close` must be `fsync` or `fdatasync`"),
+        }
+    }
+}
+
+/// Where clients may create hard links with Tlink. As an option it is `all`, `same_dir` or
+/// `none`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinkPolicy {
+    /// Anywhere in the export of the file.
+    #[default]
+    All,
+    /// Only in the directory that the file was walked to through. Others fail with EXDEV.
+    SameDir,
+    /// Nowhere. Every Tlink fails with EPERM.
+    None,
+}
+
+impl FromStr for LinkPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(LinkPolicy::All),
+            "same_dir" => Ok(LinkPolicy::SameDir),
+            "none" => Ok(LinkPolicy::None),
+            _ => Err("`links` must be one of `all`, `same_dir` or `none`"),
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -374,10 +1393,242 @@ pub struct Config {
     pub root: Box<Path>,
     pub msize: u32,
 
+    /// How the user and group ids of the client map to those of the host. Owners without a
+    /// mapping are reported as 65534, and changing the owner of a file to an id that has none
+    /// fails with EINVAL. When a group map is given, the files and directories that clients
+    /// create get the host group that the gid in their Tlcreate or Tmkdir maps to, as long as it
+    /// is in one of the ranges.
     pub uid_map: ServerUidMap,
     pub gid_map: ServerGidMap,
 
+    /// Whether to squash every identity into a single one, which takes the place of the uid and
+    /// gid maps.
+    pub all_squash: Option<Squash>,
+
+    /// Whether names that only differ in ASCII case refer to the same file, for trees that come
+    /// from hosts where they do. Walks, removals and renames find files whatever the case of
+    /// their names, creating a file that would only differ in case from an existing one fails
+    /// with EEXIST, and `hide` patterns and `rules` match regardless of case. Files keep the names
+    /// that they were created with, which are the ones that directory listings show.
     pub ascii_casefold: bool,
+
+    /// The Unicode normal form to give the names of new files. Names that only differ in their
+    /// normal form then refer to the same file, so that the decomposed names that macOS tools
+    /// create can be found through the composed ones that Linux clients send. As with
+    /// `ascii_casefold`, a new file that would only differ in normal form from an existing one
+    /// fails with EEXIST, and `hide` patterns and `rules` match every form. Names that aren't
+    /// UTF-8 are left alone, and none are normalized by default.
+    pub normalize_names: Option<NormalForm>,
+
+    /// Shell-style wildcard patterns, with `*`, `?` and `[...]`, of the names of files that
+    /// clients must not see. Hidden files are left out of directory listings, walking to them
+    /// fails with ENOENT, and nothing can be created or renamed with their names. The "." and
+    /// ".." entries are never hidden.
+    pub hide: Vec<String>,
+
+    /// Limits on what clients can do with the files under some paths, such as only reading the
+    /// ones under /usr. The first rule that matches the path that a client walked to reach a file
+    /// applies, and files that no rule matches can be used in every way. Requests that the rules
+    /// don't allow fail with EACCES.
+    pub rules: Vec<Rule>,
+
+    /// Whether to look up names with openat2(2) and RESOLVE_BENEATH, which lets the kernel make
+    /// sure that no lookup leaves its directory. Without it names are looked up with openat(2)
+    /// one at a time, for sandboxes whose seccomp filters kill the server for trying openat2.
+    /// On by default.
+    pub openat2: bool,
+
+    /// Whether walking to a symlink fails with ELOOP, so that clients can never follow one that
+    /// is in the exported tree. They still see symlinks when listing directories and can create
+    /// new ones.
+    pub no_symlinks: bool,
+
+    /// What clients see of symlinks whose targets are absolute paths on the host, which lead
+    /// somewhere else in the tree of the client. Links are taken to be where they were walked
+    /// to, as they are for `rules`. Targets are kept as they are by default.
+    pub absolute_symlinks: AbsoluteSymlinks,
+
+    /// How long a client may go without sending any requests before the server ends its session.
+    /// A `Dispatcher` keeps the timer itself; transports that call `Server::handle_message`
+    /// themselves have to call `Server::reap_idle` by the `Server::idle_deadline`. Clients that
+    /// negotiate the ping extension can send Tping to stay alive.
+    pub idle_timeout: Option<Duration>,
+
+    /// How long a fid may go unused before the server clunks it, so that clients that leak fids
+    /// can't make it hold on to ever more of them. Fids from Tattach and the ones that hold POSIX
+    /// locks never expire. Clients that expect their fids to stay around for as long as they
+    /// cache files, like the Linux one does, will find them gone. Fids never expire by default.
+    pub fid_timeout: Option<Duration>,
+
+    /// The most fids that a client may have at once, counting those of Tauth and of the control
+    /// tree. Requests that would create more fail with EMFILE. There is no limit by default.
+    pub max_fids: Option<usize>,
+
+    /// The most requests of a client that a `Dispatcher` has in hand at once. Once it has that
+    /// many, it stops reading from the transport until some of them have been replied to, so that
+    /// a client can't make it queue up more than it can handle. It is never less than one. There
+    /// is no limit by default.
+    pub max_inflight: Option<usize>,
+
+    /// How many bytes past a Tread of a regular file on the host to have the host read ahead of
+    /// time, when it starts where the last Tread of its fid ended, so that the next ones don't
+    /// have to wait for the disk. Clients that read a big file from start to end send one Tread
+    /// after another like that. There is no readahead beyond what the host does by itself by
+    /// default.
+    pub readahead: Option<u64>,
+
+    /// How many bytes of Twrites to a regular file that follow on from each other to merge into
+    /// one write to the host. The writes are replied to as soon as they come in, and their data
+    /// is written to the file once there is that much of it, when the client sends anything other
+    /// than a Twrite, or after a second at the latest. When writing it fails, the next Twrite,
+    /// Tfsync or Tclunk of the fid fails instead. Writes aren't merged for files that are opened
+    /// for appending or whose export has a byte quota. Every write goes to the host on its own by
+    /// default.
+    pub coalesce_writes: Option<usize>,
+
+    /// How long the attributes that Tgetattr gets for a file may be given out again without
+    /// asking the host, which saves a statx for every Tgetattr that clients send one after the
+    /// other. Requests that change a file through the server drop its attributes, and those that
+    /// change the tree, like renames and unlinks, drop them all, but changes made on the host
+    /// take this long to show up. Attributes aren't cached by default.
+    pub attr_cache: Option<Duration>,
+
+    /// How long a Twalk may fail with ENOENT without asking the host when it looks for a name
+    /// that was recently found not to be in the directory, which saves the lookups of builds that
+    /// probe for the same missing files again and again. Creating or renaming a file in a
+    /// directory through the server drops the names of that directory, but files created on the
+    /// host take this long to show up. Missing names aren't cached by default.
+    pub negative_cache: Option<Duration>,
+
+    /// Whether to report the generation number of inodes in Rgetattr, so that clients can tell
+    /// apart files that reuse the inode number of a deleted one. Costs an extra open and ioctl
+    /// for every Tgetattr that asks for it.
+    pub inode_generation: bool,
+
+    /// Whether to commit a directory to stable storage whenever a client creates, links, renames
+    /// or removes an entry of it, before replying, so that the changes that clients saw succeed
+    /// outlive a crash of the host. Costs an fsync for every such request.
+    pub sync_dirs: bool,
+
+    /// Whether Tunlinkat and Tremove should fail exactly like unlinkat(2) on Linux does, with
+    /// EINVAL for flags other than AT_REMOVEDIR, EISDIR or ENOTDIR for entries of the wrong type,
+    /// and EINVAL, ENOTEMPTY or EISDIR for `.` and `..`, whatever the file system would have
+    /// done. Removing the root of an export fails with EBUSY. The file system decides by default.
+    pub strict_unlink: bool,
+
+    /// How to commit the regular files that clients opened for writing to stable storage when
+    /// they clunk their fids, so that a successful close(2) means that the data is safe. Errors
+    /// are reported in the Rclunk. Files are only committed when clients ask by default.
+    pub sync_on_close: Option<SyncMode>,
+
+    /// Whether to pass O_NOATIME through to the host when clients open files with it. Opening a
+    /// file with it fails with EPERM unless the server owns the file or has CAP_FOWNER, so it is
+    /// dropped by default and the file is opened without it.
+    pub noatime: bool,
+
+    /// The most regular files that clients may have open on the host at once. When opening one
+    /// more goes over it, the files that were used least recently are closed behind the backs of
+    /// their fids and reopened with the same flags the next time they are needed, which fails if
+    /// the file can no longer be opened that way. Files with flock locks stay open. There is no
+    /// limit by default.
+    pub max_open_files: Option<usize>,
+
+    /// The wire request numbers of the ioctls that clients may run with Tioctl, out of those in
+    /// `protocol::ioctl`. None are allowed by default.
+    pub ioctls: Vec<u32>,
+
+    /// The special files that clients may create with Tmknod, or with Tcreate in 9P2000.u. None
+    /// are allowed by default.
+    pub mknod: MknodPolicy,
+
+    /// What clients can do with the FIFOs and unix sockets in the exported trees. They are
+    /// opened like any other file by default.
+    pub special_files: SpecialFiles,
+
+    /// Where clients may create hard links to files. Links never reach across exports, and need
+    /// the rules to allow creating files both where the link goes and where the file is, since
+    /// the link gives the file whatever access the rules give the new name. They can be created
+    /// anywhere by default.
+    pub links: LinkPolicy,
+
+    /// What clients may do with the security.capability extended attribute, which would let them
+    /// give capabilities to the programs that the host runs. They can set it by default.
+    pub capability_xattr: CapabilityXattr,
+
+    /// Shell-style wildcard patterns, like those of `hide`, of the names of the extended
+    /// attributes that clients can use, such as `user.*`. All of them can be used if there are
+    /// none.
+    pub xattr_allow: Vec<String>,
+
+    /// Patterns of the names of extended attributes that clients can't use even if `xattr_allow`
+    /// lets them, such as `trusted.*`. Listing attributes leaves these out, reading them fails
+    /// with ENODATA and setting or removing them fails with EPERM.
+    pub xattr_deny: Vec<String>,
+
+    /// Whether to reject every request that would change the exported tree with EROFS, whatever
+    /// the permissions of the files on the host.
+    pub read_only: bool,
+
+    /// Makes this a dry run, in which the requests that would change the exported trees are
+    /// only reported to the hook from `Server::set_dry_run_hook`, and get the replies that this
+    /// says, while everything else is carried out as usual. It is not a dry run by default.
+    pub dry_run: Option<DryRun>,
+
+    /// Whether the requests that would change the exported trees fail with EAGAIN while they
+    /// are frozen with `Server::freeze` or a `Freezer`, rather than wait until they are thawed.
+    pub freeze_fails: bool,
+
+    /// Whether walks into file systems mounted inside the exported tree fail with EXDEV, like
+    /// NFS without `crossmnt`, so that clients only ever see the file system of the root.
+    pub no_cross_mounts: bool,
+
+    /// Whether to drop the setuid and setgid bits from the modes that clients give files in
+    /// Tlcreate, Tmkdir, Tmknod and Tsetattr, so that they can't plant setuid programs in the
+    /// exported tree.
+    pub strip_setid: bool,
+
+    /// Whether to also leave the setuid and setgid bits out of the modes that clients see, for
+    /// files that got them some other way.
+    pub hide_setid: bool,
+
+    /// The permission bits to take away from the modes of the files and directories that clients
+    /// create, in place of the umask of the server process, which no longer has any effect. As
+    /// an option it is in octal, like `umask=022`. By default the process umask applies.
+    pub umask: Option<u32>,
+
+    /// The largest size in bytes that clients can make a file through Twrite, Tsetattr, Tcopyrange
+    /// or Tfallocate. Writes that would go past it are cut short, or fail with EFBIG if they
+    /// start there, like they do when a process is over its RLIMIT_FSIZE. There is no limit by
+    /// default.
+    pub max_file_size: Option<u64>,
+
+    /// How many bytes the regular files of the exported tree can add up to. Writes and size
+    /// changes that would go over it are cut short or fail with EDQUOT, and Tstatfs reports no
+    /// more space than is left. The server scans the tree when a client first attaches to it and
+    /// then counts the changes made through it, so changes made on the host meanwhile aren't
+    /// seen. There is no quota by default.
+    pub quota_bytes: Option<u64>,
+
+    /// How many files and directories the exported tree can hold, counted like `quota_bytes`.
+    /// Creating more fails with EDQUOT. There is no quota by default.
+    pub quota_inodes: Option<u64>,
+
+    /// What Tstatfs reports about the exported tree in place of what the host does. Other than
+    /// for the quotas above, nothing is replaced by default.
+    pub statfs: StatfsOverride,
+
+    /// More trees that clients can attach to, by the aname that selects them. `root` and the
+    /// options above are what clients get with an empty aname, or with any aname if there are
+    /// no other exports.
+    pub exports: BTreeMap<String, Export>,
+
+    /// The aname of a synthetic tree of files that report the state of the server and change
+    /// some of the options above at runtime, instead of any export. There is none by default.
+    pub control: Option<String>,
+
+    /// Whether clients can change options through the control tree. The server can't tell who
+    /// writes to it, so anyone who can attach to it could, and it is read-only by default.
+    pub control_writable: bool,
 }
 
 impl FromStr for Config {
@@ -401,6 +1652,209 @@ impl FromStr for Config {
                         .map_err(|_| "`ascii_casefold` must be a boolean")?;
                     cfg.ascii_casefold = ascii_casefold;
                 }
+                "normalize_names" => cfg.normalize_names = Some(value.parse()?),
+                "hide" => {
+                    cfg.hide = value
+                        .split(',')
+                        .filter(|pattern| !pattern.is_empty())
+                        .map(String::from)
+                        .collect();
+                }
+                "rule" => {
+                    let (pattern, access) = value
+                        .rsplit_once('=')
+                        .ok_or("rules must be of the form `pattern=access`")?;
+                    cfg.rules.push(Rule {
+                        pattern: pattern.to_string(),
+                        access: access.parse()?,
+                    });
+                }
+                "openat2" => {
+                    cfg.openat2 = value.parse().map_err(|_| "`openat2` must be a boolean")?;
+                }
+                "no_symlinks" => {
+                    let no_symlinks = value
+                        .parse()
+                        .map_err(|_| "`no_symlinks` must be a boolean")?;
+                    cfg.no_symlinks = no_symlinks;
+                }
+                "uid_map" => cfg.uid_map = value.parse()?,
+                "gid_map" => cfg.gid_map = value.parse()?,
+                "all_squash" => cfg.all_squash = Some(value.parse()?),
+                "fid_timeout" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| "`fid_timeout` must be a number of seconds")?;
+                    cfg.fid_timeout = Some(Duration::from_secs(secs));
+                }
+                "max_fids" => {
+                    let max_fids = value.parse().map_err(|_| "`max_fids` must be a number")?;
+                    cfg.max_fids = Some(max_fids);
+                }
+                "max_inflight" => {
+                    let max_inflight = value
+                        .parse()
+                        .map_err(|_| "`max_inflight` must be a number")?;
+                    cfg.max_inflight = Some(max_inflight);
+                }
+                "readahead" => {
+                    let readahead = value
+                        .parse()
+                        .map_err(|_| "`readahead` must be a number of bytes")?;
+                    cfg.readahead = Some(readahead);
+                }
+                "coalesce_writes" => {
+                    let coalesce_writes = value
+                        .parse()
+                        .map_err(|_| "`coalesce_writes` must be a number of bytes")?;
+                    cfg.coalesce_writes = Some(coalesce_writes);
+                }
+                "attr_cache" => {
+                    let secs = value
+                        .parse()
+                        .ok()
+                        .filter(|secs: &f64| secs.is_finite() && *secs >= 0.0)
+                        .ok_or("`attr_cache` must be a number of seconds")?;
+                    cfg.attr_cache = Some(Duration::from_secs_f64(secs));
+                }
+                "negative_cache" => {
+                    let secs = value
+                        .parse()
+                        .ok()
+                        .filter(|secs: &f64| secs.is_finite() && *secs >= 0.0)
+                        .ok_or("`negative_cache` must be a number of seconds")?;
+                    cfg.negative_cache = Some(Duration::from_secs_f64(secs));
+                }
+                "idle_timeout" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| "`idle_timeout` must be a number of seconds")?;
+                    cfg.idle_timeout = Some(Duration::from_secs(secs));
+                }
+                "inode_generation" => {
+                    let inode_generation = value
+                        .parse()
+                        .map_err(|_| "`inode_generation` must be a boolean")?;
+                    cfg.inode_generation = inode_generation;
+                }
+                "sync_dirs" => {
+                    let sync_dirs = value.parse().map_err(|_| "`sync_dirs` must be a boolean")?;
+                    cfg.sync_dirs = sync_dirs;
+                }
+                "strict_unlink" => {
+                    let strict_unlink = value
+                        .parse()
+                        .map_err(|_| "`strict_unlink` must be a boolean")?;
+                    cfg.strict_unlink = strict_unlink;
+                }
+                "sync_on_close" => cfg.sync_on_close = Some(value.parse()?),
+                "noatime" => {
+                    let noatime = value.parse().map_err(|_| "`noatime` must be a boolean")?;
+                    cfg.noatime = noatime;
+                }
+                "max_open_files" => {
+                    let max_open_files = value
+                        .parse()
+                        .map_err(|_| "`max_open_files` must be a number")?;
+                    cfg.max_open_files = Some(max_open_files);
+                }
+                "ioctls" => {
+                    cfg.ioctls = value
+                        .split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(|name| Ioctl::from_name(name).map(|ioctl| ioctl.request))
+                        .collect::<Option<_>>()
+                        .ok_or("`ioctls` must be a comma-separated list of known ioctls")?;
+                }
+                "mknod" => cfg.mknod = value.parse()?,
+                "special_files" => cfg.special_files = value.parse()?,
+                "absolute_symlinks" => cfg.absolute_symlinks = value.parse()?,
+                "links" => cfg.links = value.parse()?,
+                "capability_xattr" => cfg.capability_xattr = value.parse()?,
+                "xattr_allow" => {
+                    cfg.xattr_allow = value
+                        .split(',')
+                        .filter(|pattern| !pattern.is_empty())
+                        .map(String::from)
+                        .collect();
+                }
+                "xattr_deny" => {
+                    cfg.xattr_deny = value
+                        .split(',')
+                        .filter(|pattern| !pattern.is_empty())
+                        .map(String::from)
+                        .collect();
+                }
+                "dry_run" => cfg.dry_run = Some(value.parse()?),
+                "freeze_fails" => {
+                    let freeze_fails = value
+                        .parse()
+                        .map_err(|_| "`freeze_fails` must be a boolean")?;
+                    cfg.freeze_fails = freeze_fails;
+                }
+                "read_only" => {
+                    let read_only = value.parse().map_err(|_| "`read_only` must be a boolean")?;
+                    cfg.read_only = read_only;
+                }
+                "no_cross_mounts" => {
+                    let no_cross_mounts = value
+                        .parse()
+                        .map_err(|_| "`no_cross_mounts` must be a boolean")?;
+                    cfg.no_cross_mounts = no_cross_mounts;
+                }
+                "strip_setid" => {
+                    let strip_setid = value
+                        .parse()
+                        .map_err(|_| "`strip_setid` must be a boolean")?;
+                    cfg.strip_setid = strip_setid;
+                }
+                "hide_setid" => {
+                    let hide_setid = value
+                        .parse()
+                        .map_err(|_| "`hide_setid` must be a boolean")?;
+                    cfg.hide_setid = hide_setid;
+                }
+                "umask" => {
+                    let umask = u32::from_str_radix(value, 8)
+                        .ok()
+                        .filter(|umask| umask & !0o777 == 0)
+                        .ok_or("`umask` must be an octal number of permission bits")?;
+                    cfg.umask = Some(umask);
+                }
+                "max_file_size" => {
+                    let max_file_size = value
+                        .parse()
+                        .map_err(|_| "`max_file_size` must be a number of bytes")?;
+                    cfg.max_file_size = Some(max_file_size);
+                }
+                "quota_bytes" => {
+                    let quota_bytes = value
+                        .parse()
+                        .map_err(|_| "`quota_bytes` must be a number of bytes")?;
+                    cfg.quota_bytes = Some(quota_bytes);
+                }
+                "quota_inodes" => {
+                    let quota_inodes = value
+                        .parse()
+                        .map_err(|_| "`quota_inodes` must be a number")?;
+                    cfg.quota_inodes = Some(quota_inodes);
+                }
+                "statfs" => cfg.statfs = value.parse()?,
+                "export" | "read_only_export" => {
+                    let (name, root) = value
+                        .split_once('=')
+                        .ok_or("exports must be of the form `name=path`")?;
+                    let mut export = Export::new(Path::new(root));
+                    export.read_only = kind == "read_only_export";
+                    cfg.exports.insert(name.to_string(), export);
+                }
+                "control" => cfg.control = Some(value.to_string()),
+                "control_writable" => {
+                    let control_writable = value
+                        .parse()
+                        .map_err(|_| "`control_writable` must be a boolean")?;
+                    cfg.control_writable = control_writable;
+                }
                 _ => return Err("unrecognized option for p9 config"),
             }
         }
@@ -408,25 +1862,285 @@ impl FromStr for Config {
     }
 }
 
-impl Default for Config {
-    fn default() -> Config {
-        Config {
-            root: Path::new("/").into(),
-            msize: MAX_MESSAGE_SIZE,
+/// A tree that clients can attach to, along with the options that only apply to it. The options
+/// mean the same as those with the same names in `Config`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Export {
+    pub root: Box<Path>,
+    pub read_only: bool,
+    pub no_cross_mounts: bool,
+    pub strip_setid: bool,
+    pub hide_setid: bool,
+    pub umask: Option<u32>,
+    pub max_file_size: Option<u64>,
+    pub quota_bytes: Option<u64>,
+    pub quota_inodes: Option<u64>,
+    pub statfs: StatfsOverride,
+    pub uid_map: ServerUidMap,
+    pub gid_map: ServerGidMap,
+    pub all_squash: Option<Squash>,
+}
+
+// Returns how many of the `len` bytes from `offset` on can be written to a file of `export`, as
+// long as it is more than none.
+fn writable_len(export: &Export, offset: u64, len: u64) -> io::Result<u64> {
+    match export.max_file_size {
+        Some(max) if len > 0 && offset >= max => Err(io::Error::from_raw_os_error(libc::EFBIG)),
+        Some(max) => Ok(min(len, max.saturating_sub(offset))),
+        None => Ok(len),
+    }
+}
+
+impl Export {
+    /// Creates an export of `root` with the default options.
+    pub fn new<P: Into<Box<Path>>>(root: P) -> Export {
+        Export {
+            root: root.into(),
+            read_only: false,
+            no_cross_mounts: false,
+            strip_setid: false,
+            hide_setid: false,
+            umask: None,
+            max_file_size: None,
+            quota_bytes: None,
+            quota_inodes: None,
+            statfs: Default::default(),
             uid_map: Default::default(),
             gid_map: Default::default(),
-            ascii_casefold: false,
+            all_squash: None,
         }
     }
-}
-pub struct Server {
-    fids: BTreeMap<u32, Fid>,
-    proc: File,
-    cfg: Config,
-}
 
-impl Server {
-    pub fn new<P: Into<Box<Path>>>(
+    // Returns the uid that clients see for the host uid `uid`.
+    fn uid_to_client(&self, uid: u32) -> u32 {
+        match self.all_squash {
+            Some(squash) => squash.client_uid,
+            None => self.uid_map.to_client(uid),
+        }
+    }
+
+    // Returns the gid that clients see for the host gid `gid`.
+    fn gid_to_client(&self, gid: u32) -> u32 {
+        match self.all_squash {
+            Some(squash) => squash.client_gid,
+            None => self.gid_map.to_client(gid),
+        }
+    }
+
+    // Replaces the host ids in the owner of `attr` with the ones that clients see.
+    fn attr_to_client(&self, mut attr: Rgetattr) -> Rgetattr {
+        if attr.valid & P9_GETATTR_UID != 0 {
+            attr.uid = self.uid_to_client(attr.uid);
+        }
+        if attr.valid & P9_GETATTR_GID != 0 {
+            attr.gid = self.gid_to_client(attr.gid);
+        }
+        if attr.valid & P9_GETATTR_MODE != 0 {
+            attr.mode = self.mode_to_client(attr.mode);
+        }
+        attr
+    }
+
+    // Returns the mode that clients see for a file whose host mode is `mode`.
+    fn mode_to_client(&self, mode: u32) -> u32 {
+        if self.hide_setid {
+            mode & !(libc::S_ISUID | libc::S_ISGID)
+        } else {
+            mode
+        }
+    }
+
+    // Returns the mode to give a file for the mode `mode` that a client asked for.
+    fn mode_to_host(&self, mode: u32) -> u32 {
+        if self.strip_setid {
+            mode & !(libc::S_ISUID | libc::S_ISGID)
+        } else {
+            mode
+        }
+    }
+
+    // Returns the mode to create a file with for the mode `mode` that a client asked for.
+    fn create_mode(&self, mode: u32) -> u32 {
+        self.mode_to_host(mode) & !self.umask.unwrap_or(0)
+    }
+
+    // Returns the host uid for the uid `uid` that a client sent, or None if it has none.
+    fn uid_to_host(&self, uid: u32) -> Option<u32> {
+        match self.all_squash {
+            Some(squash) => Some(squash.host_uid),
+            None => self.uid_map.to_host(uid),
+        }
+    }
+
+    // Returns the host gid for the gid `gid` that a client sent, or None if it has none.
+    fn gid_to_host(&self, gid: u32) -> Option<u32> {
+        match self.all_squash {
+            Some(squash) => Some(squash.host_gid),
+            None => self.gid_map.to_host(gid),
+        }
+    }
+}
+
+impl Config {
+    // Returns the export that clients attach to with `aname`.
+    fn export(&self, aname: &[u8]) -> io::Result<Export> {
+        if aname.is_empty() || self.exports.is_empty() {
+            return Ok(Export {
+                root: self.root.clone(),
+                read_only: self.read_only,
+                no_cross_mounts: self.no_cross_mounts,
+                strip_setid: self.strip_setid,
+                hide_setid: self.hide_setid,
+                umask: self.umask,
+                max_file_size: self.max_file_size,
+                quota_bytes: self.quota_bytes,
+                quota_inodes: self.quota_inodes,
+                statfs: self.statfs,
+                uid_map: self.uid_map.clone(),
+                gid_map: self.gid_map.clone(),
+                all_squash: self.all_squash,
+            });
+        }
+
+        std::str::from_utf8(aname)
+            .ok()
+            .and_then(|aname| self.exports.get(aname))
+            .cloned()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            root: Path::new("/").into(),
+            msize: MAX_MESSAGE_SIZE,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+            all_squash: None,
+            ascii_casefold: false,
+            normalize_names: None,
+            hide: Vec::new(),
+            rules: Vec::new(),
+            openat2: true,
+            no_symlinks: false,
+            idle_timeout: None,
+            fid_timeout: None,
+            max_fids: None,
+            max_inflight: None,
+            readahead: None,
+            coalesce_writes: None,
+            attr_cache: None,
+            negative_cache: None,
+            inode_generation: false,
+            sync_dirs: false,
+            strict_unlink: false,
+            sync_on_close: None,
+            noatime: false,
+            max_open_files: None,
+            ioctls: Vec::new(),
+            mknod: MknodPolicy::None,
+            special_files: SpecialFiles::Open,
+            links: LinkPolicy::All,
+            absolute_symlinks: AbsoluteSymlinks::Keep,
+            capability_xattr: CapabilityXattr::Allow,
+            xattr_allow: Vec::new(),
+            xattr_deny: Vec::new(),
+            read_only: false,
+            dry_run: None,
+            freeze_fails: false,
+            no_cross_mounts: false,
+            strip_setid: false,
+            hide_setid: false,
+            umask: None,
+            max_file_size: None,
+            quota_bytes: None,
+            quota_inodes: None,
+            statfs: Default::default(),
+            exports: BTreeMap::new(),
+            control: None,
+            control_writable: false,
+        }
+    }
+}
+pub struct Server<F: FileSystem = Passthrough> {
+    fs: F,
+    fids: FidTable<Fid<F>>,
+    cfg: Config,
+    dialect: Dialect,
+    // The extensions negotiated in the last Tversion.
+    extensions: Extensions,
+    // The id of the current session, once the client has asked for one, and where to keep its
+    // fids if the connection goes away.
+    session: Option<u64>,
+    sessions: Option<SessionStore<F>>,
+    // The open files and leases of every server that serves the same tree, and the id of this one
+    // in there.
+    leases: LeaseTable,
+    lease_owner: u64,
+    // When the client last sent a request.
+    last_activity: Instant,
+    // The earliest that a fid can expire, and who to tell when one does.
+    fid_expiry: Option<Instant>,
+    on_fid_expiry: Option<Box<dyn FnMut(u32) + Send>>,
+    // Who to tell about the requests that a dry run doesn't carry out.
+    on_dry_run: Option<DryRunHook>,
+    // Who decides whether clients can attach, past authentication.
+    on_attach: Option<AttachHook>,
+    // What runs around every request, in order.
+    middleware: Vec<Box<dyn Middleware>>,
+    // Where audit events go, if anywhere.
+    audit: Option<SyncSender<AuditEvent>>,
+    // The anames of the exports removed with `remove_export`, which clients can't attach to
+    // `cfg.root` with either.
+    removed_exports: BTreeSet<String>,
+    // Where the data of the Rread for the request being handled goes, and the frame and the pipe
+    // that it can go into instead of the Rread. The pipe is only created once there is something
+    // to splice through it.
+    read_into: ReadInto,
+    read_frame: ReadFrame,
+    splice_pipe: Option<SplicePipe>,
+    // The buffers that requests are read into and replies are encoded into.
+    buffers: BufferPool,
+    // The fids that have writes that haven't been written to their files yet.
+    dirty: BTreeSet<u32>,
+    // The attributes of files that Tgetattr recently got, if `Config::attr_cache` is set.
+    attrs: Option<AttrCache>,
+    // The names that Twalk recently didn't find, if `Config::negative_cache` is set.
+    missing: Option<NegativeCache>,
+    // Whether the request being handled is for a `Dispatcher`, which the handlers of some
+    // requests can leave the slow part of them to, and what that part is, before and after
+    // `handle_request` has seen it.
+    offload: bool,
+    offloaded: Option<Job>,
+    deferred: Option<Deferred>,
+    // The maximum message size negotiated with the client, which is never larger than the one in
+    // `cfg`.
+    msize: u32,
+    // The open file descriptions that hold the POSIX locks of each lock owner.
+    locks: BTreeMap<LockOwner, F::Handle>,
+    // The requests that have not been replied to yet, and the token of the one being handled.
+    flusher: Flusher,
+    cancel: CancelToken,
+    authenticator: Option<Box<dyn Authenticator>>,
+    auth_fids: BTreeMap<u32, AuthFid>,
+    // The fids of the control tree, which aren't part of `fs`.
+    control_fids: BTreeMap<u32, ControlFid>,
+    // Set through the control tree to make every export read-only, on top of those that are
+    // configured to be.
+    read_only: bool,
+    stats: Stats,
+    // The fids with regular files open, for closing some when there are too many.
+    fd_cache: FdCache,
+    // The usage of each export with a quota.
+    quotas: Quotas,
+    qids: QidMap,
+    freezer: Freezer,
+    vendor: VendorRegistry,
+}
+
+impl Server<Passthrough> {
+    pub fn new<P: Into<Box<Path>>>(
         root: P,
         uid_map: ServerUidMap,
         gid_map: ServerGidMap,
@@ -436,34 +2150,292 @@ impl Server {
             msize: MAX_MESSAGE_SIZE,
             uid_map,
             gid_map,
+            all_squash: None,
             ascii_casefold: false,
+            normalize_names: None,
+            hide: Vec::new(),
+            rules: Vec::new(),
+            openat2: true,
+            no_symlinks: false,
+            idle_timeout: None,
+            fid_timeout: None,
+            max_fids: None,
+            max_inflight: None,
+            readahead: None,
+            coalesce_writes: None,
+            attr_cache: None,
+            negative_cache: None,
+            inode_generation: false,
+            sync_dirs: false,
+            strict_unlink: false,
+            sync_on_close: None,
+            noatime: false,
+            max_open_files: None,
+            ioctls: Vec::new(),
+            mknod: MknodPolicy::None,
+            special_files: SpecialFiles::Open,
+            links: LinkPolicy::All,
+            absolute_symlinks: AbsoluteSymlinks::Keep,
+            capability_xattr: CapabilityXattr::Allow,
+            xattr_allow: Vec::new(),
+            xattr_deny: Vec::new(),
+            read_only: false,
+            dry_run: None,
+            freeze_fails: false,
+            no_cross_mounts: false,
+            strip_setid: false,
+            hide_setid: false,
+            umask: None,
+            max_file_size: None,
+            quota_bytes: None,
+            quota_inodes: None,
+            statfs: Default::default(),
+            exports: BTreeMap::new(),
+            control: None,
+            control_writable: false,
         })
     }
 
     pub fn with_config(cfg: Config) -> io::Result<Server> {
-        // Safe because this is a valid c-string.
-        let proc_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(b"/proc\0") };
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        let fd = syscall!(unsafe {
-            libc::openat64(
-                libc::AT_FDCWD,
-                proc_cstr.as_ptr(),
-                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-            )
-        })?;
+        let fs = Passthrough::new(&cfg)?;
+        Ok(Server::with_filesystem(fs, cfg))
+    }
+}
 
-        // Safe because we just opened this fd and we know it is valid.
-        let proc = unsafe { File::from_raw_fd(fd) };
-        Ok(Server {
-            fids: BTreeMap::new(),
-            proc,
+impl<F: FileSystem> Server<F> {
+    /// Creates a server that serves `fs` rather than directories on the host. The roots of the
+    /// exports in `cfg` are passed to `FileSystem::root`, and the options that only concern the
+    /// host are up to `fs`.
+    pub fn with_filesystem(fs: F, cfg: Config) -> Server<F> {
+        let leases = LeaseTable::new();
+        let lease_owner = leases.new_owner();
+        let fd_cache = FdCache::new(cfg.max_open_files);
+        // Room for the largest frame there can be and its checksum.
+        let buffers = BufferPool::new(cfg.msize as usize + 4);
+        let attrs = cfg.attr_cache.map(AttrCache::new);
+        let missing = cfg.negative_cache.map(NegativeCache::new);
+        Server {
+            fs,
+            fids: Default::default(),
+            msize: cfg.msize,
             cfg,
-        })
+            dialect: Dialect::Linux,
+            extensions: Extensions::empty(),
+            session: None,
+            sessions: None,
+            leases,
+            lease_owner,
+            last_activity: Instant::now(),
+            fid_expiry: None,
+            on_fid_expiry: None,
+            on_dry_run: None,
+            on_attach: None,
+            middleware: Vec::new(),
+            audit: None,
+            removed_exports: BTreeSet::new(),
+            read_into: ReadInto::Reply,
+            read_frame: Default::default(),
+            splice_pipe: None,
+            buffers,
+            dirty: BTreeSet::new(),
+            attrs,
+            missing,
+            offload: false,
+            offloaded: None,
+            deferred: None,
+            locks: BTreeMap::new(),
+            flusher: Default::default(),
+            cancel: Default::default(),
+            authenticator: None,
+            auth_fids: BTreeMap::new(),
+            control_fids: BTreeMap::new(),
+            read_only: false,
+            stats: Stats::new(),
+            fd_cache,
+            quotas: Default::default(),
+            qids: QidMap::new(),
+            freezer: Freezer::new(),
+            vendor: VendorRegistry::new(),
+        }
     }
 
     pub fn keep_fds(&self) -> Vec<RawFd> {
-        vec![self.proc.as_raw_fd()]
+        self.fs.keep_fds()
+    }
+
+    /// Returns the file system that the server serves.
+    pub fn filesystem(&self) -> &F {
+        &self.fs
+    }
+
+    /// Requires clients to authenticate with `authenticator` before they can attach.
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// Calls `hook` with the uname, n_uname and aname of every Tattach once the client is
+    /// authenticated, like `Authenticator::start`. Returning an error rejects the Tattach, and
+    /// otherwise the options that it returns apply to the new fid and every fid walked from it.
+    /// Attaches to the `Config::control` tree only get through or not.
+    pub fn set_attach_hook(&mut self, hook: AttachHook) {
+        self.on_attach = Some(hook);
+    }
+
+    /// Dispatches vendor extension messages to the handlers in `registry`. Messages with types
+    /// that have no handler are rejected with EOPNOTSUPP.
+    pub fn set_vendor_registry(&mut self, registry: VendorRegistry) {
+        self.vendor = registry;
+    }
+
+    /// Lets clients that negotiate the resume extension take their fids over to a new `Server`
+    /// that uses the same `store` after this one is dropped.
+    pub fn set_session_store(&mut self, store: SessionStore<F>) {
+        self.sessions = Some(store);
+    }
+
+    /// Makes leases granted by this `Server` conflict with the files that other `Server`s that use
+    /// the same `table` have open, and the other way around. Without a shared table, leases only
+    /// protect against the other fids of the same client.
+    pub fn set_lease_table(&mut self, table: LeaseTable) {
+        self.leases.close_all(self.lease_owner);
+        self.leases = table;
+        self.lease_owner = self.leases.new_owner();
+        self.track_all_opens();
+    }
+
+    /// Makes this `Server` give files the same qids as the other `Server`s that use the same
+    /// `map`. It should be set before the first Tattach, since qids that clients already have
+    /// may change.
+    pub fn set_qid_map(&mut self, map: QidMap) {
+        self.qids = map;
+    }
+
+    /// Calls `hook` with the number of every fid that the server clunks because it went unused
+    /// for longer than `fid_timeout`.
+    pub fn set_fid_expiry_hook(&mut self, hook: Box<dyn FnMut(u32) + Send>) {
+        self.on_fid_expiry = Some(hook);
+    }
+
+    /// Makes `Server::freeze` and the `freezer` itself freeze this `Server` along with all the
+    /// others that use the same `freezer`.
+    pub fn set_freezer(&mut self, freezer: Freezer) {
+        self.freezer = freezer;
+    }
+
+    /// Freezes the exported trees, and returns once the changes that were in progress are done
+    /// and the files that the client has open for writing are committed to stable storage. See
+    /// `Freezer` for what that does to clients. The trees are frozen even if committing a file
+    /// fails.
+    pub fn freeze(&mut self) -> io::Result<()> {
+        self.freezer.freeze();
+        let written: Vec<u32> = self
+            .fids
+            .entries()
+            .into_iter()
+            .filter(|(_, f)| open_for_writing(&lock_fid(f)))
+            .map(|(fid, _)| fid)
+            .collect();
+        for fid in written {
+            self.reopen_files(&[fid])?;
+            if let Some(f) = self.fids.get(fid) {
+                if let Some(file) = lock_fid(&f).file.as_ref() {
+                    self.fs.fsync(file, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Thaws the exported trees, letting clients change them again.
+    pub fn thaw(&self) {
+        self.freezer.thaw();
+    }
+
+    /// Lets clients attach to `export` with the aname `name` from now on. Fails with EEXIST if
+    /// there already is an export by that name, and with EINVAL for an empty name, which always
+    /// selects `Config::root`. Once there are any exports, clients can no longer attach to
+    /// `Config::root` with other anames.
+    pub fn add_export(&mut self, name: String, export: Export) -> io::Result<()> {
+        if name.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if self.cfg.exports.contains_key(&name) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        self.removed_exports.remove(&name);
+        self.cfg.exports.insert(name, export);
+        Ok(())
+    }
+
+    /// Stops clients from attaching to the export with the aname `name`, and returns it. The
+    /// fids that the client already has in it keep working until they are clunked, and
+    /// `Server::export_fids` tells how many are left. Clients can't attach with `name` at all
+    /// after that, even once there are no exports left, until it is added again.
+    pub fn remove_export(&mut self, name: &str) -> Option<Export> {
+        let export = self.cfg.exports.remove(name)?;
+        self.removed_exports.insert(name.to_string());
+        Some(export)
+    }
+
+    /// Opens the root of every export again from its path, for when the path has come to lead
+    /// to another directory, such as after a symlink along it was replaced. The fids that the
+    /// client has for the old roots then refer to the new ones, so that what it walks to from
+    /// there on is in the new directories, while all its other fids, and those roots it has
+    /// open, keep referring to the files they did. Nothing changes if any root fails to open.
+    pub fn reopen_roots(&mut self) -> io::Result<()> {
+        let mut roots = Vec::new();
+        for (fid, f) in self.fids.entries() {
+            let f = lock_fid(&f);
+            if f.walk_path != Path::new("/")
+                || f.file.is_some()
+                || f.xattr.is_some()
+                || f.watch.is_some()
+            {
+                continue;
+            }
+            let root = self.fs.root(&f.export)?;
+            let st = self.fs.stat(&root)?;
+            roots.push((fid, root, st));
+        }
+
+        for (fid, root, st) in roots {
+            if let Some(f) = self.fids.get(fid) {
+                let mut f = lock_fid(&f);
+                f.path = root;
+                f.filetype = st.st_mode.into();
+                f.qid_path = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how many of the fids of the client are in the export with its root at `root`,
+    /// whether or not it has been removed.
+    pub fn export_fids(&self, root: &Path) -> usize {
+        self.fids
+            .entries()
+            .iter()
+            .filter(|(_, f)| &*lock_fid(f).export.root == root)
+            .count()
+    }
+
+    /// Returns the extensions that the client negotiated in its last Tversion.
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+
+    // Returns the extensions that the server can offer to clients.
+    fn supported_extensions(&self) -> Extensions {
+        if self.sessions.is_some() {
+            Extensions::all()
+        } else {
+            Extensions::all().difference(Extensions::RESUME)
+        }
+    }
+
+    /// Returns a handle that can be used to flush outstanding requests from another thread.
+    pub fn flusher(&self) -> Flusher {
+        self.flusher.clone()
     }
 
     pub fn handle_message<R: Read, W: Write>(
@@ -471,667 +2443,2948 @@ impl Server {
         reader: &mut R,
         writer: &mut W,
     ) -> io::Result<()> {
-        let Tframe { tag, msg } = WireFormat::decode(&mut reader.take(self.cfg.msize as u64))?;
+        let frame = self.read_request(reader)?;
+        let response = self.handle_frame(&frame, ReadInto::Frame);
+        self.buffers.put(frame);
+        self.send_response(response?, writer)
+    }
 
-        let rmsg = match msg {
-            Ok(Tmessage::Version(ref version)) => self.version(version).map(Rmessage::Version),
-            Ok(Tmessage::Flush(ref flush)) => self.flush(flush).and(Ok(Rmessage::Flush)),
-            Ok(Tmessage::Walk(walk)) => self.walk(walk).map(Rmessage::Walk),
-            Ok(Tmessage::Read(ref read)) => self.read(read).map(Rmessage::Read),
-            Ok(Tmessage::Write(ref write)) => self.write(write).map(Rmessage::Write),
-            Ok(Tmessage::Clunk(ref clunk)) => self.clunk(clunk).and(Ok(Rmessage::Clunk)),
-            Ok(Tmessage::Remove(ref remove)) => self.remove(remove).and(Ok(Rmessage::Remove)),
-            Ok(Tmessage::Attach(ref attach)) => self.attach(attach).map(Rmessage::Attach),
-            Ok(Tmessage::Auth(ref auth)) => self.auth(auth).map(Rmessage::Auth),
-            Ok(Tmessage::Statfs(ref statfs)) => self.statfs(statfs).map(Rmessage::Statfs),
-            Ok(Tmessage::Lopen(ref lopen)) => self.lopen(lopen).map(Rmessage::Lopen),
-            Ok(Tmessage::Lcreate(lcreate)) => self.lcreate(lcreate).map(Rmessage::Lcreate),
-            Ok(Tmessage::Symlink(ref symlink)) => self.symlink(symlink).map(Rmessage::Symlink),
-            Ok(Tmessage::Mknod(ref mknod)) => self.mknod(mknod).map(Rmessage::Mknod),
-            Ok(Tmessage::Rename(ref rename)) => self.rename(rename).and(Ok(Rmessage::Rename)),
-            Ok(Tmessage::Readlink(ref readlink)) => self.readlink(readlink).map(Rmessage::Readlink),
-            Ok(Tmessage::GetAttr(ref get_attr)) => self.get_attr(get_attr).map(Rmessage::GetAttr),
-            Ok(Tmessage::SetAttr(ref set_attr)) => {
-                self.set_attr(set_attr).and(Ok(Rmessage::SetAttr))
-            }
-            Ok(Tmessage::XattrWalk(ref xattr_walk)) => {
-                self.xattr_walk(xattr_walk).map(Rmessage::XattrWalk)
-            }
-            Ok(Tmessage::XattrCreate(ref xattr_create)) => self
-                .xattr_create(xattr_create)
-                .and(Ok(Rmessage::XattrCreate)),
-            Ok(Tmessage::Readdir(ref readdir)) => self.readdir(readdir).map(Rmessage::Readdir),
-            Ok(Tmessage::Fsync(ref fsync)) => self.fsync(fsync).and(Ok(Rmessage::Fsync)),
-            Ok(Tmessage::Lock(ref lock)) => self.lock(lock).map(Rmessage::Lock),
-            Ok(Tmessage::GetLock(ref get_lock)) => self.get_lock(get_lock).map(Rmessage::GetLock),
-            Ok(Tmessage::Link(link)) => self.link(link).and(Ok(Rmessage::Link)),
-            Ok(Tmessage::Mkdir(mkdir)) => self.mkdir(mkdir).map(Rmessage::Mkdir),
-            Ok(Tmessage::RenameAt(rename_at)) => {
-                self.rename_at(rename_at).and(Ok(Rmessage::RenameAt))
-            }
-            Ok(Tmessage::UnlinkAt(unlink_at)) => {
-                self.unlink_at(unlink_at).and(Ok(Rmessage::UnlinkAt))
+    /// Like `handle_message`, but encodes the reply into the front of `buf` and returns its
+    /// length, which is 0 if the request was flushed and needs no reply. Replies are sized to fit
+    /// in the negotiated msize, so `buf` should be at least that long, plus 4 bytes for the
+    /// checksum if one was negotiated.
+    pub fn handle_message_into<R: Read>(
+        &mut self,
+        reader: &mut R,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let frame = self.read_request(reader)?;
+        let response = self.handle_frame(&frame, ReadInto::Frame);
+        self.buffers.put(frame);
+        let response = response?;
+        let len = match (self.read_frame.take(&response)?, response) {
+            (Some(frame), _) => {
+                let available = buf.len();
+                let dst = buf.get_mut(..frame.len()).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "buffer too small for frame: want: {} bytes, got: {} bytes",
+                            frame.len(),
+                            available
+                        ),
+                    )
+                })?;
+                dst.copy_from_slice(frame);
+                frame.len()
             }
-            Err(e) => {
-                // The header was successfully decoded, but the body failed to decode - send an
-                // error response for this tag.
-                let error = format!("Tframe message decode failed: {}", e);
-                Err(io::Error::new(io::ErrorKind::InvalidData, error))
+            (None, Some(response)) => {
+                let len = response.encode_to_slice(buf, self.dialect)?;
+                self.recycle(response);
+                len
             }
+            (None, None) => return Ok(0),
         };
+        if self.extensions.contains(Extensions::CRC32C) {
+            checksum::append_to_slice(buf, len)
+        } else {
+            Ok(len)
+        }
+    }
 
-        // Errors while handling requests are never fatal.
-        let response = Rframe {
-            tag,
-            msg: rmsg.unwrap_or_else(error_to_rmessage),
+    /// Like `handle_message`, but for a transport that is a pipe or a socket. The data of an
+    /// Rread from a regular file on the host is spliced from the file into `writer` after the
+    /// rest of the reply has been written, rather than being copied through a buffer. `writer`
+    /// must pass on what was written to it by the time it is flushed.
+    pub fn handle_message_splice<R: Read, W: Write + AsRawFd>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let frame = self.read_request(reader)?;
+        let response = self.handle_frame(&frame, ReadInto::Pipe);
+        self.buffers.put(frame);
+        let response = response?;
+
+        let len = self.splice_pipe.as_ref().map_or(0, SplicePipe::len);
+        if len == 0 {
+            return self.send_response(response, writer);
+        }
+        let tag = match response {
+            Some(Rframe {
+                tag,
+                msg: Rmessage::Read(_),
+            }) => tag,
+            // The data is of no use if the request failed or was flushed, and there is no
+            // getting it out of the pipe other than by reading it.
+            response => {
+                self.splice_pipe = None;
+                return self.send_response(response, writer);
+            }
         };
 
-        response.encode(writer)?;
-        writer.flush()
+        let mut header = [0; PAYLOAD_HEADER_SIZE];
+        read_frame::encode_rread_header(tag, len, &mut header)?;
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        let pipe = self.splice_pipe.as_mut().unwrap();
+        pipe.drain(writer).inspect_err(|_| self.splice_pipe = None)
     }
 
-    fn auth(&mut self, _auth: &Tauth) -> io::Result<Rauth> {
-        // Returning an error for the auth message means that the server does not require
-        // authentication.
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    // Reads the next frame from `reader` into a buffer from the pool, and checks its checksum if
+    // one was negotiated. The checksum isn't part of the frame that is returned.
+    fn read_request<R: Read>(&mut self, reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut frame = self.buffers.get();
+        let res = if self.extensions.contains(Extensions::CRC32C) {
+            let mut reader = ChecksumReader::new(reader);
+            receive_frame(&mut reader, self.msize, &mut frame)
+                .and_then(|()| reader.verify().map(drop))
+        } else {
+            receive_frame(reader, self.msize, &mut frame)
+        };
+        match res {
+            Ok(()) => Ok(frame),
+            Err(e) => {
+                self.buffers.put(frame);
+                Err(e)
+            }
+        }
     }
 
-    fn attach(&mut self, attach: &Tattach) -> io::Result<Rattach> {
-        // TODO: Check attach parameters
-        match self.fids.entry(attach.fid) {
-            btree_map::Entry::Vacant(entry) => {
-                let root = CString::new(self.cfg.root.as_os_str().as_bytes())
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fn decode_frame<R: Read>(&self, reader: &mut R) -> io::Result<Tframe> {
+        // Check the size of the frame before reading any of it so that a client can't make us
+        // buffer more than the msize it agreed to.
+        let size: u32 = WireFormat::decode(reader)?;
+        if size > self.msize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame size ({}) exceeds msize ({})", size, self.msize),
+            ));
+        }
+        let header = size.to_le_bytes();
+        let mut body = reader.take(u64::from(size).saturating_sub(header.len() as u64));
+        let frame = Tframe::decode_dialect(&mut (&header[..]).chain(&mut body), self.dialect);
+
+        // Skip whatever the message didn't use so that the next frame, or the checksum of this
+        // one, is read from the right place.
+        io::copy(&mut body, &mut io::sink())?;
+        frame
+    }
 
-                // Safe because this doesn't modify any memory and we check the return value.
-                let fd = syscall!(unsafe {
-                    libc::openat64(
-                        libc::AT_FDCWD,
-                        root.as_ptr(),
-                        libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-                    )
-                })?;
+    /// Like `handle_message`, but for a request that is already in memory. `frame` must hold
+    /// exactly one complete frame, followed by its checksum if one was negotiated. The data of a
+    /// Twrite is written to the file straight from `frame` rather than being copied into a new
+    /// buffer first.
+    pub fn handle_message_slice<W: Write>(
+        &mut self,
+        frame: &[u8],
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let frame = if self.extensions.contains(Extensions::CRC32C) {
+            checksum::verify_frame(frame)?
+        } else {
+            frame
+        };
+        let response = self.handle_frame(frame, ReadInto::Frame)?;
+        self.send_response(response, writer)
+    }
 
-                let root_path = unsafe { File::from_raw_fd(fd) };
-                let st = stat(&root_path)?;
+    // Handles the request in `frame`, which holds exactly one frame without its checksum, and
+    // returns the reply. The data of a Twrite is written to the file straight from `frame`, and
+    // the data of the Rread for a Tread goes to `read_into` rather than into the Rread when it
+    // can.
+    fn handle_frame(&mut self, frame: &[u8], read_into: ReadInto) -> io::Result<Option<Rframe>> {
+        if frame.len() > self.msize as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame size ({}) exceeds msize ({})",
+                    frame.len(),
+                    self.msize
+                ),
+            ));
+        }
 
-                let fid = Fid {
-                    // Safe because we just opened this fd.
-                    path: root_path,
-                    file: None,
-                    filetype: st.st_mode.into(),
-                };
-                let response = Rattach { qid: st.into() };
-                entry.insert(fid);
-                Ok(response)
-            }
-            btree_map::Entry::Occupied(_) => Err(io::Error::from_raw_os_error(libc::EBADF)),
+        if let Ok(Some((tag, write))) = TwriteRef::decode_frame(frame) {
+            return Ok(self.handle_request(tag, |server| {
+                // Dry runs and middlewares need the write to be in a Tmessage.
+                if server.cfg.dry_run.is_some() || !server.middleware.is_empty() {
+                    let write = Twrite {
+                        fid: write.fid,
+                        offset: write.offset,
+                        data: Data(write.data.to_vec()),
+                    };
+                    return server.dispatch(Ok(Tmessage::Write(write)));
+                }
+                let audit = server.pending_audit(AuditOp::Write, write.fid, Vec::new());
+                let rmsg = server.write_ref_checked(&write);
+                if let Some(audit) = audit {
+                    server.finish_audit(audit, &rmsg);
+                }
+                rmsg
+            }));
         }
-    }
 
-    fn version(&mut self, version: &Tversion) -> io::Result<Rversion> {
-        if version.msize < MIN_MESSAGE_SIZE {
-            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        // Everything else is decoded as usual, including a malformed Twrite so that it is
+        // replied to like one.
+        let Tframe { tag, msg } = self.decode_frame(&mut &*frame)?;
+        // Middlewares may want to look at the data, and checksums cover it.
+        let splice_checksum =
+            read_into == ReadInto::Pipe && self.extensions.contains(Extensions::CRC32C);
+        if matches!(msg, Ok(Tmessage::Read(_))) && self.middleware.is_empty() && !splice_checksum {
+            self.read_into = read_into;
         }
+        let response = self.handle_request(tag, |server| server.dispatch(msg));
+        self.read_into = ReadInto::Reply;
+        Ok(response)
+    }
 
-        // A Tversion request clunks all open fids and terminates any pending I/O.
-        self.fids.clear();
-        self.cfg.msize = min(self.cfg.msize, version.msize);
+    // Handles a Twrite that was decoded in place, like `dispatch_checked` does one that wasn't.
+    fn write_ref_checked(&mut self, write: &TwriteRef) -> io::Result<Rmessage> {
+        self.use_fids(&[write.fid]);
+        self.forget_attrs_of(write.fid);
+        if self
+            .fids
+            .get(write.fid)
+            .is_some_and(|fid| self.read_only || lock_fid(&fid).export.read_only)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        let _change = self
+            .freezer
+            .start_change(self.cfg.freeze_fails, &self.cancel)?;
+        self.write_ref(write).map(Rmessage::Write)
+    }
 
-        Ok(Rversion {
-            msize: self.cfg.msize,
-            version: if version.version.as_bytes() == b"9P2000.L" {
-                P9String::new(b"9P2000.L")?
-            } else {
-                P9String::new(b"unknown")?
-            },
-        })
+    /// Returns when the session will have been idle for longer than the configured
+    /// `idle_timeout`, or `None` if idle sessions are kept forever. A transport can use this as
+    /// the deadline for waiting on the next request.
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        self.cfg
+            .idle_timeout
+            .map(|timeout| self.last_activity + timeout)
     }
 
-    #[allow(clippy::unnecessary_wraps)]
-    fn flush(&mut self, _flush: &Tflush) -> io::Result<()> {
-        // TODO: Since everything is synchronous we can't actually flush requests.
-        Ok(())
+    /// Ends the session if the client hasn't sent a request within the configured
+    /// `idle_timeout`, clunking all of its fids. Returns true if it did, after which the
+    /// transport should close the connection. Also drops the sessions in the session store that
+    /// weren't resumed in time.
+    pub fn reap_idle(&mut self) -> bool {
+        if let Some(sessions) = &self.sessions {
+            sessions.expire();
+        }
+        match self.idle_deadline() {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.reset_session();
+                true
+            }
+            _ => false,
+        }
     }
 
-    fn walk(&mut self, walk: Twalk) -> io::Result<Rwalk> {
-        // `newfid` must not currently be in use unless it is the same as `fid`.
-        if walk.fid != walk.newfid && self.fids.contains_key(&walk.newfid) {
-            return Err(io::Error::from_raw_os_error(libc::EBADF));
+    /// Clunks the fids that have gone unused for longer than the configured `fid_timeout`, and
+    /// returns how many there were. The server also does this itself whenever it gets a request.
+    pub fn expire_fids(&mut self) -> usize {
+        let timeout = match self.cfg.fid_timeout {
+            Some(timeout) => timeout,
+            None => return 0,
+        };
+        let now = Instant::now();
+        if self.fid_expiry.is_some_and(|expiry| now < expiry) {
+            return 0;
         }
 
-        // We need to walk the tree.  First get the starting path.
-        let start = &self.fids.get(&walk.fid).ok_or_else(ebadf)?.path;
+        let mut expired = Vec::new();
+        let mut next = None;
+        for (fid, f) in self.fids.entries() {
+            // A fid that is locked is being used right now.
+            let Ok(f) = f.try_lock() else {
+                continue;
+            };
+            let last_used = match f.last_used {
+                Some(last_used) if f.lock_owners.is_empty() => last_used,
+                _ => continue,
+            };
+            if now.duration_since(last_used) > timeout {
+                expired.push(fid);
+            } else {
+                next = Some(next.map_or(last_used, |next: Instant| next.min(last_used)));
+            }
+        }
+        self.fid_expiry = next.map(|last_used| last_used + timeout);
 
-        // Now walk the tree and break on the first error, if any.
-        let expected_len = walk.wnames.len();
-        let mut mds = Vec::with_capacity(expected_len);
-        match do_walk(
-            &self.proc,
-            walk.wnames,
-            start,
-            self.cfg.ascii_casefold,
-            &mut mds,
-        ) {
-            Ok(end) => {
-                // Store the new fid if the full walk succeeded.
-                if mds.len() == expected_len {
-                    let st = mds.last().copied().map(Ok).unwrap_or_else(|| stat(&end))?;
-                    self.fids.insert(
-                        walk.newfid,
-                        Fid {
-                            path: end,
-                            file: None,
-                            filetype: st.st_mode.into(),
-                        },
-                    );
+        for &fid in &expired {
+            // The client is no longer around to be told how clunking went.
+            let _ = self.clunk(&Tclunk { fid });
+            if let Some(hook) = self.on_fid_expiry.as_mut() {
+                hook(fid);
+            }
+        }
+        expired.len()
+    }
+
+    // Expires the fids that went unused for too long, and then records that `fids` were just
+    // used.
+    fn use_fids(&mut self, fids: &[u32]) {
+        self.expire_fids();
+        if self.cfg.fid_timeout.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        for &fid in fids {
+            if let Some(f) = self.fids.get(fid) {
+                if let Some(last_used) = lock_fid(&f).last_used.as_mut() {
+                    *last_used = now;
                 }
             }
-            Err(e) => {
-                // Only return an error if it occurred on the first component.
-                if mds.is_empty() {
-                    return Err(e);
+        }
+    }
+
+    // Tells the lease table about every fid that has a file open.
+    fn track_all_opens(&self) {
+        for (fid, f) in self.fids.entries() {
+            let f = lock_fid(&f);
+            if f.file.is_some() {
+                // A file that can't be looked at can't be leased by anyone else either.
+                if let Ok(st) = self.fs.stat(&f.path) {
+                    track_open(&self.leases, self.lease_owner, fid, &st, f.open_flags);
                 }
             }
         }
+    }
 
-        Ok(Rwalk {
-            wqids: mds.into_iter().map(Qid::from).collect(),
-        })
+    // Clunks all open fids and drops all locks. The session can no longer be resumed.
+    fn reset_session(&mut self) {
+        self.flush_writes();
+        self.leases.close_all(self.lease_owner);
+        self.remove_on_close();
+        self.fids.clear();
+        self.locks.clear();
+        self.auth_fids.clear();
+        self.control_fids.clear();
+        self.fd_cache.clear();
+        self.session = None;
     }
 
-    fn read(&mut self, read: &Tread) -> io::Result<Rread> {
-        // Thankfully, `read` cannot be used to read directories in 9P2000.L.
-        let file = self
+    // Removes the files of the fids that were opened with ORCLOSE, which are all about to go away
+    // without being clunked.
+    fn remove_on_close(&mut self) {
+        for (_, fid) in self.fids.take() {
+            let fid = lock_fid(&fid);
+            if fid.remove_on_close {
+                let _ = self.unlink_fid(&fid);
+            }
+        }
+    }
+
+    // Runs `handler` to service the request with tag `tag` and returns the reply, if the client
+    // still expects one.
+    fn handle_request<H>(&mut self, tag: u16, handler: H) -> Option<Rframe>
+    where
+        H: FnOnce(&mut Self) -> io::Result<Rmessage>,
+    {
+        self.last_activity = Instant::now();
+        self.cancel = self.flusher.start(tag);
+        let rmsg = if self.cancel.is_cancelled() {
+            // The request was flushed before we got to it.
+            Err(io::Error::from_raw_os_error(libc::EINTR))
+        } else {
+            handler(self)
+        };
+        let cancel = mem::take(&mut self.cancel);
+
+        if let Some(job) = self.offloaded.take() {
+            // The dispatcher replies once it has run the rest of the request.
+            self.deferred = Some(Deferred { tag, cancel, job });
+            return None;
+        }
+        self.finish_request(tag, &cancel, rmsg)
+    }
+
+    // Replies to the request with tag `tag` and token `cancel` with `rmsg`, if the client still
+    // expects a reply.
+    fn finish_request(
+        &mut self,
+        tag: u16,
+        cancel: &CancelToken,
+        rmsg: io::Result<Rmessage>,
+    ) -> Option<Rframe> {
+        self.stats.record(&rmsg);
+
+        self.flusher.end(tag, cancel);
+        if cancel.is_cancelled() {
+            // The client has already been told that the request was flushed and has forgotten
+            // about the tag, so it no longer expects a reply.
+            return None;
+        }
+
+        // Errors while handling requests are never fatal.
+        Some(Rframe {
+            tag,
+            msg: rmsg.unwrap_or_else(|e| error_to_rmessage(e, self.dialect)),
+        })
+    }
+
+    // Sends `response`, or the frame that the data of it was read into if there is one.
+    fn send_response<W: Write>(
+        &mut self,
+        response: Option<Rframe>,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        match self.read_frame.take(&response)? {
+            Some(frame) if self.extensions.contains(Extensions::CRC32C) => {
+                let mut writer = ChecksumWriter::new(writer);
+                writer.write_all(frame)?;
+                return writer.finish()?.flush();
+            }
+            Some(frame) => {
+                writer.write_all(frame)?;
+                return writer.flush();
+            }
+            None => {}
+        }
+
+        match response {
+            Some(response) if self.extensions.contains(Extensions::CRC32C) => {
+                let mut writer = ChecksumWriter::new(writer);
+                response.encode_dialect(&mut writer, self.dialect)?;
+                self.recycle(response);
+                writer.finish()?.flush()
+            }
+            // The header and the payload go out in one call, without the payload being copied
+            // next to the header first.
+            Some(response) if response.payload().is_some() => {
+                let mut header = [0; PAYLOAD_HEADER_SIZE];
+                response.encode_header_dialect(&mut &mut header[..], self.dialect)?;
+                let payload = response.payload().unwrap_or_default();
+                write_all_vectored(writer, &mut [IoSlice::new(&header), IoSlice::new(payload)])?;
+                self.recycle(response);
+                writer.flush()
+            }
+            // The rest are encoded into a buffer first so that they are written all at once.
+            Some(response) => {
+                let mut buf = self.buffers.get();
+                let res = response
+                    .encode_dialect(&mut buf, self.dialect)
+                    .and_then(|()| writer.write_all(&buf));
+                self.buffers.put(buf);
+                res?;
+                writer.flush()
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Gives the payload of `response`, which has been sent, back to the buffer pool.
+    fn recycle(&mut self, response: Rframe) {
+        match response.msg {
+            Rmessage::Read(Rread { data })
+            | Rmessage::Readdir(Rreaddir { data })
+            | Rmessage::ReaddirPlus(Rreaddirplus { data }) => self.buffers.put(data.0),
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self, msg: io::Result<Tmessage>) -> io::Result<Rmessage> {
+        match msg {
+            Ok(msg) if !self.middleware.is_empty() => self.dispatch_with_middleware(msg),
+            msg => self.dispatch_message(msg),
+        }
+    }
+
+    fn dispatch_message(&mut self, msg: io::Result<Tmessage>) -> io::Result<Rmessage> {
+        let audit = msg.as_ref().ok().and_then(|msg| self.start_audit(msg));
+        let rmsg = self.dispatch_checked(msg);
+        if let Some(audit) = audit {
+            self.finish_audit(audit, &rmsg);
+        }
+        rmsg
+    }
+
+    fn dispatch_checked(&mut self, msg: io::Result<Tmessage>) -> io::Result<Rmessage> {
+        // Anything but another write may look at what was written.
+        if !matches!(msg, Ok(Tmessage::Write(_))) {
+            self.flush_writes();
+        }
+        if let Ok(ref msg) = msg {
+            self.forget_attrs(msg);
+            self.forget_missing(msg);
+        }
+
+        if let Ok(ref msg) = msg {
+            self.use_fids(&used_fids(msg));
+        }
+
+        if let Some(fid) = msg.as_ref().ok().and_then(modified_fid) {
+            if self
+                .fids
+                .get(fid)
+                .is_some_and(|fid| self.read_only || lock_fid(&fid).export.read_only)
+            {
+                return Err(io::Error::from_raw_os_error(libc::EROFS));
+            }
+        }
+
+        if let Ok(ref msg) = msg {
+            for (name, errno) in entry_names(msg) {
+                check_single_name(name.as_bytes())?;
+                if is_hidden(&self.cfg, name.as_bytes()) {
+                    return Err(io::Error::from_raw_os_error(errno));
+                }
+            }
+            self.check_policy(msg)?;
+            if let Some(rmsg) = self.dry_run(msg) {
+                return rmsg;
+            }
+        }
+        let _change = match msg {
+            Ok(ref msg) if self.changed_fid(msg).is_some() => Some(
+                self.freezer
+                    .start_change(self.cfg.freeze_fails, &self.cancel)?,
+            ),
+            _ => None,
+        };
+
+        if let Ok(ref msg) = msg {
+            if let Some(rmsg) = self.control_request(msg) {
+                return rmsg;
+            }
+        }
+
+        match msg {
+            Ok(Tmessage::Version(ref version)) => self.version(version).map(Rmessage::Version),
+            Ok(Tmessage::Flush(ref flush)) => self.flush(flush).and(Ok(Rmessage::Flush)),
+            Ok(Tmessage::Walk(walk)) => self.walk(walk).map(Rmessage::Walk),
+            Ok(Tmessage::Read(ref read)) => self.read(read).map(Rmessage::Read),
+            Ok(Tmessage::Write(ref write)) => self.write(write).map(Rmessage::Write),
+            Ok(Tmessage::Clunk(ref clunk)) => self.clunk(clunk).and(Ok(Rmessage::Clunk)),
+            Ok(Tmessage::Remove(ref remove)) => self.remove(remove).and(Ok(Rmessage::Remove)),
+            Ok(Tmessage::Attach(ref attach)) => self.attach(attach).map(Rmessage::Attach),
+            Ok(Tmessage::Auth(ref auth)) => self.auth(auth).map(Rmessage::Auth),
+            Ok(Tmessage::Open(ref open)) => self.open(open).map(Rmessage::Open),
+            Ok(Tmessage::Create(create)) => self.create(create).map(Rmessage::Create),
+            Ok(Tmessage::Stat(ref stat)) => self.stat(stat).map(Rmessage::Stat),
+            Ok(Tmessage::Wstat(ref wstat)) => self.wstat(wstat).and(Ok(Rmessage::Wstat)),
+            Ok(Tmessage::Statfs(ref statfs)) => self.statfs(statfs).map(Rmessage::Statfs),
+            Ok(Tmessage::Lopen(ref lopen)) => self.lopen(lopen).map(Rmessage::Lopen),
+            Ok(Tmessage::Lcreate(lcreate)) => self.lcreate(lcreate).map(Rmessage::Lcreate),
+            Ok(Tmessage::Symlink(ref symlink)) => self.symlink(symlink).map(Rmessage::Symlink),
+            Ok(Tmessage::Mknod(ref mknod)) => self.mknod(mknod).map(Rmessage::Mknod),
+            Ok(Tmessage::Rename(ref rename)) => self.rename(rename).and(Ok(Rmessage::Rename)),
+            Ok(Tmessage::Readlink(ref readlink)) => self.readlink(readlink).map(Rmessage::Readlink),
+            Ok(Tmessage::GetAttr(ref get_attr)) => self.get_attr(get_attr).map(Rmessage::GetAttr),
+            Ok(Tmessage::SetAttr(ref set_attr)) => {
+                self.set_attr(set_attr).and(Ok(Rmessage::SetAttr))
+            }
+            Ok(Tmessage::XattrWalk(ref xattr_walk)) => {
+                self.xattr_walk(xattr_walk).map(Rmessage::XattrWalk)
+            }
+            Ok(Tmessage::XattrCreate(ref xattr_create)) => self
+                .xattr_create(xattr_create)
+                .and(Ok(Rmessage::XattrCreate)),
+            Ok(Tmessage::Readdir(ref readdir)) => self.readdir(readdir).map(Rmessage::Readdir),
+            Ok(Tmessage::ReaddirPlus(ref readdirplus)) => {
+                self.readdirplus(readdirplus).map(Rmessage::ReaddirPlus)
+            }
+            Ok(Tmessage::Ping) => self.ping().and(Ok(Rmessage::Ping)),
+            Ok(Tmessage::Resume(ref resume)) => self.resume(resume).map(Rmessage::Resume),
+            Ok(Tmessage::CopyRange(ref copy_range)) => {
+                self.copy_range(copy_range).map(Rmessage::CopyRange)
+            }
+            Ok(Tmessage::CloneRange(ref clone_range)) => {
+                self.clone_range(clone_range).and(Ok(Rmessage::CloneRange))
+            }
+            Ok(Tmessage::Lseek(ref lseek)) => self.lseek(lseek).map(Rmessage::Lseek),
+            Ok(Tmessage::Fallocate(ref fallocate)) => {
+                self.fallocate(fallocate).and(Ok(Rmessage::Fallocate))
+            }
+            Ok(Tmessage::Fadvise(ref fadvise)) => self.fadvise(fadvise).and(Ok(Rmessage::Fadvise)),
+            Ok(Tmessage::Ioctl(ref ioctl)) => self.ioctl(ioctl).map(Rmessage::Ioctl),
+            Ok(Tmessage::Watch(ref watch)) => self.watch(watch).and(Ok(Rmessage::Watch)),
+            Ok(Tmessage::Lease(ref lease)) => self.lease(lease).and(Ok(Rmessage::Lease)),
+            Ok(Tmessage::LeaseBreaks) => self.lease_breaks().map(Rmessage::LeaseBreaks),
+            Ok(Tmessage::RenameAt2(ref rename_at2)) => {
+                self.rename_at2(rename_at2).and(Ok(Rmessage::RenameAt2))
+            }
+            Ok(Tmessage::Fsync(ref fsync)) => self.fsync(fsync).and(Ok(Rmessage::Fsync)),
+            Ok(Tmessage::Lock(ref lock)) => self.lock(lock).map(Rmessage::Lock),
+            Ok(Tmessage::GetLock(ref get_lock)) => self.get_lock(get_lock).map(Rmessage::GetLock),
+            Ok(Tmessage::Link(link)) => self.link(link).and(Ok(Rmessage::Link)),
+            Ok(Tmessage::Mkdir(mkdir)) => self.mkdir(mkdir).map(Rmessage::Mkdir),
+            Ok(Tmessage::RenameAt(rename_at)) => {
+                self.rename_at(rename_at).and(Ok(Rmessage::RenameAt))
+            }
+            Ok(Tmessage::UnlinkAt(unlink_at)) => {
+                self.unlink_at(unlink_at).and(Ok(Rmessage::UnlinkAt))
+            }
+            Ok(Tmessage::Vendor(ref vendor)) => self.vendor(vendor).map(Rmessage::Vendor),
+            Err(e) => {
+                // The header was successfully decoded, but the body failed to decode - send an
+                // error response for this tag.
+                let error = format!("Tframe message decode failed: {}", e);
+                Err(io::Error::new(io::ErrorKind::InvalidData, error))
+            }
+        }
+    }
+
+    // Returns the fid of an exported tree through which `msg` could change it, if it could
+    // change one at all. Unlike `modified_fid`, this includes Tremove and the Tclunks that apply
+    // changes.
+    fn changed_fid(&self, msg: &Tmessage) -> Option<u32> {
+        let fid = match msg {
+            Tmessage::Remove(remove) => remove.fid,
+            Tmessage::Clunk(clunk) => Some(clunk.fid).filter(|fid| {
+                self.fids.get(*fid).is_some_and(|f| {
+                    let f = lock_fid(&f);
+                    f.remove_on_close || matches!(f.xattr, Some(Xattr::Write { .. }))
+                })
+            })?,
+            msg => modified_fid(msg)?,
+        };
+        Some(fid).filter(|&fid| self.fids.contains(fid))
+    }
+
+    // Returns true if the client is using `fid` for anything.
+    fn fid_in_use(&self, fid: u32) -> bool {
+        self.fids.contains(fid)
+            || self.auth_fids.contains_key(&fid)
+            || self.control_fids.contains_key(&fid)
+    }
+
+    // Fails with EMFILE if `fid` would be a new fid that takes the client over `max_fids`.
+    fn check_fid_limit(&self, fid: u32) -> io::Result<()> {
+        let count = self.fids.len() + self.auth_fids.len() + self.control_fids.len();
+        match self.cfg.max_fids {
+            Some(max) if count >= max && !self.fid_in_use(fid) => {
+                Err(io::Error::from_raw_os_error(libc::EMFILE))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Returns how many bytes of data fit in an Rread of no more than `count` bytes of data.
+    fn read_capacity(&self, count: u32) -> u32 {
+        min(self.msize - read_frame::rread_header_size() as u32, count)
+    }
+
+    // Drops the POSIX locks of every owner that locked a file through `fid`. Closing any file
+    // descriptor for a file releases all of the POSIX locks that the process holds on it, and
+    // the Linux client sends an explicit unlock for the closing process anyway.
+    fn release_locks(&mut self, fid: &Fid<F>) {
+        for owner in &fid.lock_owners {
+            self.locks.remove(owner);
+        }
+    }
+
+    // Records that the fids in `fids` just used their files, closing those of the fids that were
+    // used least recently if that leaves more open than `cfg.max_open_files` allows.
+    fn touch_files(&mut self, fids: &[u32]) {
+        let used: Vec<u32> = fids
+            .iter()
+            .copied()
+            .filter(|&fid| {
+                self.fids.get(fid).is_some_and(|f| {
+                    let f = lock_fid(&f);
+                    f.filetype == FileType::Regular && f.file.is_some()
+                })
+            })
+            .collect();
+        for fid in self.fd_cache.touch(&used) {
+            self.flush_write(fid);
+            if let Some(f) = self.fids.get(fid) {
+                lock_fid(&f).file = None;
+            }
+        }
+    }
+
+    // Reopens the files of any of `fids` that were closed to make room for others, before a
+    // request uses them.
+    fn reopen_files(&mut self, fids: &[u32]) -> io::Result<()> {
+        for &fid in fids {
+            self.reopen_file(fid)?;
+        }
+        self.touch_files(fids);
+        Ok(())
+    }
+
+    fn reopen_file(&mut self, fid: u32) -> io::Result<()> {
+        if !self.fd_cache.is_evicted(fid) {
+            return Ok(());
+        }
+        let f = self.fids.get(fid).ok_or_else(ebadf)?;
+        let mut f = lock_fid(&f);
+        // Whatever the original open created or truncated is not to be done again.
+        let flags = f.open_flags & !(P9_CREATE | P9_EXCL | P9_TRUNC | P9_TMPFILE);
+        f.file = Some(self.fs.open(&f.path, flags)?);
+        Ok(())
+    }
+
+    fn vendor(&mut self, vendor: &Tvendor) -> io::Result<Rvendor> {
+        if !self.vendor.contains(vendor.ty) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        self.vendor.dispatch(vendor)
+    }
+
+    fn auth(&mut self, auth: &Tauth) -> io::Result<Rauth> {
+        // Returning an error for the auth message means that the server does not require
+        // authentication.
+        let in_use = self.fid_in_use(auth.afid);
+        let limit = self.check_fid_limit(auth.afid);
+        let authenticator = self
+            .authenticator
+            .as_mut()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOPNOTSUPP))?;
+
+        if in_use {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+        limit?;
+
+        // P9String is always valid UTF-8.
+        let uname = std::str::from_utf8(auth.uname.as_bytes()).unwrap_or_default();
+        let aname = std::str::from_utf8(auth.aname.as_bytes()).unwrap_or_default();
+        let n_uname = Some(auth.n_uname).filter(|&n| n != u32::MAX);
+        let session = authenticator.start(uname, n_uname, aname)?;
+
+        self.auth_fids.insert(
+            auth.afid,
+            AuthFid {
+                session,
+                uname: auth.uname.clone(),
+                aname: auth.aname.clone(),
+                n_uname: auth.n_uname,
+            },
+        );
+
+        Ok(Rauth {
+            aqid: Qid {
+                ty: P9_QTAUTH,
+                version: 0,
+                path: auth.afid.into(),
+            },
+        })
+    }
+
+    fn attach(&mut self, attach: &Tattach) -> io::Result<Rattach> {
+        if self.authenticator.is_some() {
+            let authenticated = self.auth_fids.get(&attach.afid).is_some_and(|afid| {
+                afid.session.is_authenticated()
+                    && afid.uname == attach.uname
+                    && afid.aname == attach.aname
+                    && afid.n_uname == attach.n_uname
+            });
+            if !authenticated {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+        }
+
+        if self.auth_fids.contains_key(&attach.fid) || self.control_fids.contains_key(&attach.fid) {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+        self.check_fid_limit(attach.fid)?;
+        let options = match self.on_attach.as_mut() {
+            Some(hook) => {
+                // P9String is always valid UTF-8.
+                let uname = std::str::from_utf8(attach.uname.as_bytes()).unwrap_or_default();
+                let aname = std::str::from_utf8(attach.aname.as_bytes()).unwrap_or_default();
+                let n_uname = Some(attach.n_uname).filter(|&n| n != u32::MAX);
+                hook(uname, n_uname, aname)?
+            }
+            None => AttachOptions::default(),
+        };
+        if self.cfg.control.as_deref().map(str::as_bytes) == Some(attach.aname.as_bytes()) {
+            return self.control_attach(attach.fid);
+        }
+
+        if self.fids.contains(attach.fid) {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+        if std::str::from_utf8(attach.aname.as_bytes())
+            .is_ok_and(|aname| self.removed_exports.contains(aname))
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        let mut export = self.cfg.export(attach.aname.as_bytes())?;
+        export.read_only |= options.read_only;
+        if options.all_squash.is_some() {
+            export.all_squash = options.all_squash;
+        }
+        let root = self.fs.root(&export)?;
+        let st = self.fs.stat(&root)?;
+        self.quotas.attach(&self.fs, &export, &root)?;
+
+        let fid = Fid {
+            path: root,
+            file: None,
+            open_flags: 0,
+            filetype: st.st_mode.into(),
+            dir_offset: (0, 0),
+            read_ahead: (0, 0),
+            pending_write: None,
+            write_error: None,
+            qid_path: Some(self.qids.qid(&st).path),
+            xattr: None,
+            watch: None,
+            lock_owners: BTreeSet::new(),
+            remove_on_close: false,
+            export: Arc::new(export),
+            walk_path: PathBuf::from("/"),
+            last_used: None,
+            identity: Arc::new(Identity {
+                uname: String::from_utf8_lossy(attach.uname.as_bytes()).into_owned(),
+                n_uname: Some(attach.n_uname).filter(|&n| n != u32::MAX),
+            }),
+        };
+        let response = Rattach {
+            qid: self.qids.qid(&st),
+        };
+        self.fids.insert(attach.fid, fid);
+        Ok(response)
+    }
+
+    fn version(&mut self, version: &Tversion) -> io::Result<Rversion> {
+        if version.msize < MIN_MESSAGE_SIZE {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // A Tversion request clunks all open fids and terminates any pending I/O.
+        self.reset_session();
+        self.msize = min(self.cfg.msize, version.msize);
+
+        let (dialect, requested) = Extensions::parse_version(version.version.as_bytes());
+        self.dialect = dialect.unwrap_or(Dialect::Linux);
+
+        // The reply lists the extensions that the server agreed to, which are all of the ones it
+        // supports.
+        self.extensions = requested.intersection(self.supported_extensions());
+        let version = match dialect {
+            Some(dialect) => self.extensions.version(dialect),
+            None => "unknown".to_string(),
+        };
+
+        Ok(Rversion {
+            msize: self.msize,
+            version: P9String::new(version)?,
+        })
+    }
+
+    fn ping(&self) -> io::Result<()> {
+        // Handling the request has already counted as activity.
+        if self.extensions.contains(Extensions::PING) {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+    }
+
+    fn resume(&mut self, resume: &Tresume) -> io::Result<Rresume> {
+        let sessions = match self.sessions {
+            Some(ref sessions) if self.extensions.contains(Extensions::RESUME) => sessions,
+            _ => return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+        };
+
+        let session = match resume.session {
+            Some(session) => session,
+            None => {
+                let session = match self.session {
+                    Some(session) => session,
+                    None => *self.session.insert(sessions.new_id()?),
+                };
+                return Ok(Rresume { session });
+            }
+        };
+
+        // Only a connection that hasn't started a session of its own can adopt an old one.
+        if self.session.is_some() || !self.fids.is_empty() || !self.auth_fids.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        let saved = sessions
+            .take(session)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))?;
+        self.fids = saved.fids;
+        self.locks = saved.locks;
+        // The time that the client spent away doesn't count against its fids.
+        let now = Instant::now();
+        for (_, fid) in self.fids.entries() {
+            if let Some(last_used) = lock_fid(&fid).last_used.as_mut() {
+                *last_used = now;
+            }
+        }
+        self.fid_expiry = None;
+        self.session = Some(session);
+        self.track_all_opens();
+
+        Ok(Rresume { session })
+    }
+
+    #[allow(clippy::unnecessary_wraps)]
+    fn flush(&mut self, flush: &Tflush) -> io::Result<()> {
+        // Requests are handled one at a time so by the time we see a Tflush, the request it refers
+        // to has either been replied to or is being handled by another thread that checks its
+        // token. Either way we can reply right away.
+        if let Some(token) = self.flusher.finish(flush.oldtag) {
+            token.cancel();
+        }
+        Ok(())
+    }
+
+    fn walk(&mut self, walk: Twalk) -> io::Result<Rwalk> {
+        // `newfid` must not currently be in use unless it is the same as `fid`.
+        if walk.fid != walk.newfid && self.fid_in_use(walk.newfid) {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+        self.check_fid_limit(walk.newfid)?;
+
+        if walk.wnames.len() > MAXWELEM {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // We need to walk the tree.  First get the starting path.
+        let start = self.fids.get(walk.fid).ok_or_else(ebadf)?;
+        let start = lock_fid(&start);
+        let export = start.export.clone();
+        let identity = start.identity.clone();
+
+        // The walk stops before the first file that the rules don't let the client reach.
+        let expected_len = walk.wnames.len();
+        let mut wnames = walk.wnames;
+        let mut walk_path = start.walk_path.clone();
+        for (i, wname) in wnames.iter().enumerate() {
+            let next = policy::join(&walk_path, wname);
+            if let Err(e) = self.check_access(&next, Access::Read) {
+                if i == 0 {
+                    return Err(e);
+                }
+                wnames.truncate(i);
+                break;
+            }
+            walk_path = next;
+        }
+        // Walks that look for a name that was just found missing fail on the spot.
+        let now = Instant::now();
+        let missing = match (self.missing.as_mut(), start.qid_path, wnames.first()) {
+            (Some(missing), Some(dir), Some(name)) if name.as_bytes() != b".." => {
+                if missing.contains(dir, name.as_bytes(), now) {
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+                Some((dir, name.as_bytes().to_vec()))
+            }
+            _ => None,
+        };
+        let start_path = &start.walk_path;
+        let start = &start.path;
+
+        // Now walk the tree and break on the first error, if any.
+        let mut mds = Vec::with_capacity(expected_len);
+        match do_walk(
+            &self.fs, &export, &self.cfg, wnames, start, start_path, &mut mds,
+        ) {
+            Ok(end) => {
+                // Store the new fid if the full walk succeeded.
+                if mds.len() == expected_len {
+                    let st = mds
+                        .last()
+                        .copied()
+                        .map(Ok)
+                        .unwrap_or_else(|| self.fs.stat(&end))?;
+                    self.fids.insert(
+                        walk.newfid,
+                        Fid {
+                            path: end,
+                            file: None,
+                            open_flags: 0,
+                            filetype: st.st_mode.into(),
+                            dir_offset: (0, 0),
+                            read_ahead: (0, 0),
+                            pending_write: None,
+                            write_error: None,
+                            qid_path: Some(self.qids.qid(&st).path),
+                            xattr: None,
+                            watch: None,
+                            lock_owners: BTreeSet::new(),
+                            remove_on_close: false,
+                            export,
+                            walk_path,
+                            last_used: Some(Instant::now()),
+                            identity,
+                        },
+                    );
+                }
+            }
+            Err(e) => {
+                // Only return an error if it occurred on the first component.
+                if mds.is_empty() {
+                    if let (Some(cache), Some((dir, name))) = (self.missing.as_mut(), missing) {
+                        if e.raw_os_error() == Some(libc::ENOENT) {
+                            cache.insert(dir, &name, now);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(Rwalk {
+            wqids: mds.iter().map(|st| self.qids.qid(st)).collect(),
+        })
+    }
+
+    fn read(&mut self, read: &Tread) -> io::Result<Rread> {
+        let capacity = self.read_capacity(read.count);
+
+        // Auth fids are streams so the offset doesn't matter.
+        if let Some(afid) = self.auth_fids.get_mut(&read.fid) {
+            let mut buf = Data(vec![0u8; capacity as usize]);
+            let count = afid.session.read(&mut buf)?;
+            buf.truncate(count);
+            return Ok(Rread { data: buf });
+        }
+
+        self.reopen_files(&[read.fid])?;
+        let f = self.fids.get(read.fid).ok_or_else(ebadf)?;
+        let mut guard = lock_fid(&f);
+        let fid = &mut *guard;
+
+        match fid.xattr {
+            Some(Xattr::Read(ref value)) => {
+                let start = min(read.offset, value.len() as u64) as usize;
+                let end = min(start + capacity as usize, value.len());
+                return Ok(Rread {
+                    data: Data(value[start..end].to_vec()),
+                });
+            }
+            Some(Xattr::Write { .. }) => return Err(io::Error::from_raw_os_error(libc::EBADF)),
+            None => {}
+        }
+
+        // Watches are streams too.
+        if let Some(ref watch) = fid.watch {
+            let data = read_watch_events(watch, capacity as usize)?;
+            return Ok(Rread { data: Data(data) });
+        }
+
+        // Thankfully, `read` cannot be used to read directories in 9P2000.L. Older dialects
+        // return a sequence of stats instead.
+        if fid.filetype == FileType::Directory && self.dialect != Dialect::Linux {
+            drop(guard);
+            let data = self.read_dir_stats(read.fid, read.offset, capacity)?;
+            return Ok(Rread { data: Data(data) });
+        }
+
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
+        if let Some(window) = self.cfg.readahead {
+            if let Some(host_file) = self.fs.host_file(file) {
+                read_ahead(
+                    host_file,
+                    &mut fid.read_ahead,
+                    read.offset,
+                    capacity,
+                    window,
+                );
+            }
+        }
+        match self.read_into {
+            // `handle_message_splice` sends the data of regular files on the host after the
+            // reply, as long as it all fits in the pipe. A short read of one would look like its
+            // end.
+            ReadInto::Pipe if fid.filetype == FileType::Regular => {
+                if let Some(file) = self.fs.host_file(file) {
+                    let pipe = splice::pipe(&mut self.splice_pipe, self.cfg.msize as usize);
+                    if let Some(pipe) = pipe.filter(|pipe| pipe.capacity() >= capacity as usize) {
+                        // Not every file system can be spliced from.
+                        match pipe.fill(file, read.offset, capacity as usize, &self.cancel) {
+                            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && pipe.len() == 0 => {
+                            }
+                            res => {
+                                let count = res?;
+                                self.stats.record_read_data(count as u64);
+                                return Ok(Rread {
+                                    data: Data(Vec::new()),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+            ReadInto::Frame => {
+                let data = self.read_frame.data(capacity as usize);
+                let count = read_file(
+                    |buf, offset| self.fs.read(file, buf, offset),
+                    fid.filetype,
+                    data,
+                    read.offset,
+                    &self.cancel,
+                )?;
+                self.read_frame.set_len(count);
+                self.stats.record_read_data(count as u64);
+                return Ok(Rread {
+                    data: Data(Vec::new()),
+                });
+            }
+            _ => {}
+        }
+
+        let mut buf = Data(self.buffers.get());
+        buf.0.resize(capacity as usize, 0);
+
+        // A dispatcher reads regular files on the host without holding up other requests, unless
+        // the data is already in the page cache of the host, which is quicker to copy right here
+        // than to hand to a thread. The read goes to a file of its own, since the fid can have
+        // its file closed to make room for others in the meantime.
+        if self.offload && fid.filetype == FileType::Regular {
+            if let Some(file) = self.fs.host_file(file) {
+                let (cached, done) = read_cached(file, &mut buf, read.offset);
+                if done {
+                    buf.truncate(cached);
+                    return Ok(Rread { data: buf });
+                }
+                let file = file.try_clone()?;
+                let offset = read.offset + cached as u64;
+                self.offloaded = Some(Box::new(move |cancel| {
+                    let read = |buf: &mut [u8], offset| file.read_at(buf, offset);
+                    let rest = &mut buf[cached..];
+                    let count = read_file(read, FileType::Regular, rest, offset, cancel)?;
+                    buf.truncate(cached + count);
+                    Ok(Rmessage::Read(Rread { data: buf }))
+                }));
+                return Ok(Rread {
+                    data: Data(Vec::new()),
+                });
+            }
+        }
+
+        let count = read_file(
+            |buf, offset| self.fs.read(file, buf, offset),
+            fid.filetype,
+            &mut buf,
+            read.offset,
+            &self.cancel,
+        )?;
+        buf.truncate(count);
+
+        Ok(Rread { data: buf })
+    }
+
+    fn write(&mut self, write: &Twrite) -> io::Result<Rwrite> {
+        self.write_ref(&write.into())
+    }
+
+    fn write_ref(&mut self, write: &TwriteRef) -> io::Result<Rwrite> {
+        if let Some(afid) = self.auth_fids.get_mut(&write.fid) {
+            let count = afid.session.write(&write.data)?;
+            return Ok(Rwrite {
+                count: count as u32,
+            });
+        }
+
+        self.reopen_files(&[write.fid])?;
+        let fid = self.fids.get(write.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+
+        match fid.xattr {
+            Some(Xattr::Write {
+                ref mut value,
+                ref mut written,
+                ..
+            }) => {
+                let start = write.offset as usize;
+                let end = start
+                    .checked_add(write.data.len())
+                    .filter(|&end| write.offset <= value.len() as u64 && end <= value.len())
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSPC))?;
+                value[start..end].copy_from_slice(&write.data);
+                add_range(written, start..end);
+
+                return Ok(Rwrite {
+                    count: write.data.len() as u32,
+                });
+            }
+            Some(Xattr::Read(_)) => return Err(io::Error::from_raw_os_error(libc::EBADF)),
+            None => {}
+        }
+
+        if let Some(e) = fid.write_error.take() {
+            return Err(e);
+        }
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
+
+        if fid.filetype != FileType::Regular {
+            check_cancelled(&self.cancel)?;
+            let count = self.fs.write(file, &write.data, write.offset)?;
+            return Ok(Rwrite {
+                count: count as u32,
+            });
+        }
+
+        // Writes to files opened with O_APPEND go to the end of the file whatever their offset,
+        // and in one go so that they don't interleave with the appends of other writers.
+        let append = fid.open_flags & P9_APPEND != 0;
+        let offset = if append
+            && (fid.export.max_file_size.is_some() || self.quotas.counts_bytes(&fid.export))
+        {
+            self.fs.stat(&fid.path)?.st_size as u64
+        } else {
+            write.offset
+        };
+
+        let mut len = writable_len(&fid.export, offset, write.data.len() as u64)?;
+        let size = if self.quotas.counts_bytes(&fid.export) {
+            let size = self.fs.stat(&fid.path)?.st_size as u64;
+            len = self.quotas.writable_len(&fid.export, size, offset, len)?;
+            Some(size)
+        } else {
+            None
+        };
+
+        let data = &write.data[..len as usize];
+        if let Some(limit) = self.cfg.coalesce_writes {
+            if !append && size.is_none() && self.cfg.dry_run.is_none() {
+                drop(fid);
+                return self.coalesce_write(write.fid, data, offset, limit);
+            }
+        }
+        let result = if append {
+            check_cancelled(&self.cancel).and_then(|()| self.fs.write(file, data, offset))
+        } else {
+            write_chunks(&self.fs, &self.cancel, file, data, offset)
+        };
+        if let Some(size) = size {
+            // Even a write that fails may have written some of the data.
+            if let Ok(st) = self.fs.stat(&fid.path) {
+                self.quotas.resized(&fid.export, size, st.st_size as u64);
+            }
+        }
+
+        Ok(Rwrite {
+            count: result? as u32,
+        })
+    }
+
+    // Handles a write of `data` at `offset` to the regular file of `fid` for
+    // `Config::coalesce_writes`, merging it with the ones before it if it can.
+    fn coalesce_write(
+        &mut self,
+        fid: u32,
+        data: &[u8],
+        offset: u64,
+        limit: usize,
+    ) -> io::Result<Rwrite> {
+        let count = data.len() as u32;
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .dirty
+            .iter()
+            .copied()
+            .filter(|&fid| {
+                self.fids.get(fid).is_some_and(|f| {
+                    lock_fid(&f)
+                        .pending_write
+                        .as_ref()
+                        .is_some_and(|pending| pending.expired(now))
+                })
+            })
+            .collect();
+        for fid in expired {
+            self.flush_write(fid);
+        }
+
+        // Writes to the same file through other fids have to be written first, since this one
+        // may overlap them.
+        let f = self.fids.get(fid).ok_or_else(ebadf)?;
+        let pending = lock_fid(&f)
+            .pending_write
+            .as_ref()
+            .map(|pending| pending.file);
+        let file = match pending {
+            Some(file) => file,
+            None => {
+                let st = self.fs.stat(&lock_fid(&f).path)?;
+                (st.st_dev, st.st_ino)
+            }
+        };
+        let others: Vec<u32> = self
+            .dirty
+            .iter()
+            .copied()
+            .filter(|&other| {
+                other != fid
+                    && self.fids.get(other).is_some_and(|f| {
+                        lock_fid(&f)
+                            .pending_write
+                            .as_ref()
+                            .is_some_and(|pending| pending.file == file)
+                    })
+            })
+            .collect();
+        for other in others {
+            self.flush_write(other);
+        }
+
+        let mut guard = lock_fid(&f);
+        if let Some(pending) = guard.pending_write.as_mut() {
+            if pending.append(offset, data, limit) {
+                let full = pending.data.len() >= limit;
+                drop(guard);
+                if full {
+                    self.flush_write(fid);
+                }
+                return Ok(Rwrite { count });
+            }
+        }
+        drop(guard);
+
+        // A write that doesn't follow on from the ones before has them written first.
+        self.flush_write(fid);
+        let mut f = lock_fid(&f);
+        if let Some(e) = f.write_error.take() {
+            return Err(e);
+        }
+        if data.len() >= limit {
+            let file = f.file.as_ref().ok_or_else(ebadf)?;
+            let count = write_chunks(&self.fs, &self.cancel, file, data, offset)?;
+            return Ok(Rwrite {
+                count: count as u32,
+            });
+        }
+        f.pending_write = Some(PendingWrite::new(offset, data, file));
+        self.dirty.insert(fid);
+        Ok(Rwrite { count })
+    }
+
+    // Writes the writes to `fid` that haven't been written to its file yet, if there are any.
+    fn flush_write(&mut self, fid: u32) {
+        self.dirty.remove(&fid);
+        let Some(f) = self.fids.get(fid) else {
+            return;
+        };
+        let mut f = lock_fid(&f);
+        let pending = match f.pending_write.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let result = f.file.as_ref().ok_or_else(ebadf).and_then(|file| {
+            write_chunks(
+                &self.fs,
+                &CancelToken::default(),
+                file,
+                &pending.data,
+                pending.offset,
+            )
+        });
+        let result = match result {
+            Ok(count) if count < pending.data.len() => Err(io::Error::from_raw_os_error(libc::EIO)),
+            result => result.map(drop),
+        };
+        if let Err(e) = result {
+            f.write_error.get_or_insert(e);
+        }
+    }
+
+    /// Writes the data of the Twrites that `Config::coalesce_writes` held back to their files.
+    /// The server does this itself before it handles anything other than a Twrite, so a
+    /// transport only needs to when it has been waiting a while for the next request.
+    pub fn flush_writes(&mut self) {
+        for fid in mem::take(&mut self.dirty) {
+            self.flush_write(fid);
+        }
+    }
+
+    fn clunk(&mut self, clunk: &Tclunk) -> io::Result<()> {
+        if self.auth_fids.remove(&clunk.fid).is_some() {
+            return Ok(());
+        }
+        self.flush_write(clunk.fid);
+        let synced = self.sync_on_close(clunk.fid);
+
+        // The fid is clunked even if applying a pending attribute write fails.
+        let fid = self.fids.remove(clunk.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+        self.release_locks(&fid);
+        self.leases.closed(self.lease_owner, clunk.fid);
+        self.fd_cache.forget(clunk.fid);
+        let written = fid.write_error.take().map_or(Ok(()), Err);
+        let result = synced.and(written).and(write_xattr(&self.fs, &fid));
+        // A file that was created without a name and never linked in goes away now.
+        if fid.open_flags & P9_TMPFILE == P9_TMPFILE && self.quotas.tracks(&fid.export) {
+            if let Some(st) = self.fs.stat(&fid.path).ok().filter(|st| st.st_nlink == 0) {
+                self.quotas.removed(&fid.export, &st);
+            }
+        }
+        if fid.remove_on_close {
+            self.unlink_fid(&fid)?;
+        }
+        result
+    }
+
+    // Commits the file of `fid` to stable storage as `sync_on_close` says, if the client opened
+    // it for writing.
+    fn sync_on_close(&mut self, fid: u32) -> io::Result<()> {
+        let datasync = match self.cfg.sync_on_close {
+            Some(mode) => mode == SyncMode::Fdatasync,
+            None => return Ok(()),
+        };
+        if !self
             .fids
-            .get_mut(&read.fid)
-            .and_then(|fid| fid.file.as_mut())
-            .ok_or_else(ebadf)?;
+            .get(fid)
+            .is_some_and(|f| open_for_writing(&lock_fid(&f)))
+        {
+            return Ok(());
+        }
+
+        // Files that were closed to make room for others are reopened, since their data may not
+        // have made it to stable storage either.
+        self.reopen_files(&[fid])?;
+        let Some(f) = self.fids.get(fid) else {
+            return Ok(());
+        };
+        let f = lock_fid(&f);
+        match f.file.as_ref() {
+            Some(file) => self.fs.fsync(file, datasync),
+            None => Ok(()),
+        }
+    }
+
+    fn remove(&mut self, remove: &Tremove) -> io::Result<()> {
+        // The fid is clunked even if the remove fails.
+        if self.auth_fids.remove(&remove.fid).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let fid = self.fids.remove(remove.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        self.release_locks(&fid);
+        self.leases.closed(self.lease_owner, remove.fid);
+        self.fd_cache.forget(remove.fid);
+        if self.read_only || fid.export.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        self.check_access(&fid.walk_path, Access::Create)?;
+        if self.cfg.strict_unlink && fid.walk_path == Path::new("/") {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        self.unlink_fid(&fid)
+    }
+
+    // Unlinks the file of `fid` from the directory that it is in now, which is not the one that
+    // it was walked from if it has been renamed since.
+    fn unlink_fid(&mut self, fid: &Fid<F>) -> io::Result<()> {
+        // A file could be linked into multiple locations so unlink the one that the client
+        // walked through to reach it.
+        let (parent, name) = self.fs.parent(&fid.export, &fid.path, &fid.walk_path)?;
+        let flags = if fid.filetype == FileType::Directory {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        let removed = self.quota_entry(&fid.export, &parent, &name);
+
+        self.fs.unlink(&parent, &name, flags)?;
+        if let Some(st) = removed {
+            self.quotas.removed(&fid.export, &st);
+        }
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &parent)?;
+        }
+        Ok(())
+    }
+
+    fn statfs(&mut self, statfs: &Tstatfs) -> io::Result<Rstatfs> {
+        let fid = self.fids.get(statfs.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let statfs = self.fs.statfs(&fid.path)?;
+        let statfs = self.quotas.statfs(&fid.export, statfs);
+        Ok(fid.export.statfs.apply(statfs))
+    }
+
+    // Returns the flags to open a file with on the host for the Tlopen or Tlcreate `flags` that
+    // a client sent.
+    fn open_flags(&self, flags: u32) -> u32 {
+        if self.cfg.noatime {
+            flags
+        } else {
+            flags & !P9_NOATIME
+        }
+    }
+
+    fn lopen(&mut self, lopen: &Tlopen) -> io::Result<Rlopen> {
+        let flags = self.open_flags(lopen.flags);
+        if flags & P9_TMPFILE == P9_TMPFILE {
+            // Tlopen has no mode, so the file starts out private to its owner.
+            let st = self.open_tmpfile(lopen.fid, flags, 0o600, libc::gid_t::MAX)?;
+            return Ok(Rlopen {
+                qid: self.qids.qid(&st),
+                iounit: 0,
+            });
+        }
+
+        let fid = self.fids.get(lopen.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+
+        let mut flags = flags;
+        if fid.filetype == FileType::Other
+            && self.cfg.special_files != SpecialFiles::Open
+            && special::is_special(self.fs.stat(&fid.path)?.st_mode)
+        {
+            if self.cfg.special_files != SpecialFiles::NonBlock {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+            flags |= P9_NONBLOCK;
+        }
+
+        let truncated = if flags & P9_TRUNC != 0
+            && fid.filetype == FileType::Regular
+            && self.quotas.counts_bytes(&fid.export)
+        {
+            Some(self.fs.stat(&fid.path)?.st_size as u64)
+        } else {
+            None
+        };
+        let file = self.fs.open(&fid.path, flags)?;
+        let st = self.fs.stat(&fid.path)?;
+        if let Some(size) = truncated {
+            self.quotas.resized(&fid.export, size, st.st_size as u64);
+        }
+        track_open(&self.leases, self.lease_owner, lopen.fid, &st, flags);
+
+        fid.file = Some(file);
+        fid.open_flags = flags;
+        drop(fid);
+        self.touch_files(&[lopen.fid]);
+        Ok(Rlopen {
+            qid: self.qids.qid(&st),
+            iounit: 0, // Allow the client to send requests up to the negotiated max message size.
+        })
+    }
+
+    fn lcreate(&mut self, lcreate: Tlcreate) -> io::Result<Rlcreate> {
+        let flags = self.open_flags(lcreate.flags);
+        // The name of an O_TMPFILE file is only given to it if it is linked in later.
+        if flags & P9_TMPFILE == P9_TMPFILE {
+            let st = self.open_tmpfile(lcreate.fid, flags, lcreate.mode, lcreate.gid)?;
+            return Ok(Rlcreate {
+                qid: self.qids.qid(&st),
+                iounit: 0,
+            });
+        }
+
+        let fid = self.fids.get(lcreate.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+
+        if fid.filetype != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let name = names::new_entry_name(&self.fs, &self.cfg, &fid.path, lcreate.name.as_c_str())?;
+        self.quotas.check_create(&fid.export)?;
+        let (uid, gid) = created_owner(&fid.export, lcreate.gid);
+        let mode = fid.export.create_mode(lcreate.mode);
+        let (path, file) = self.fs.create(&fid.path, &name, flags, mode, uid, gid)?;
+        self.quotas.created(&fid.export);
+        let st = self.fs.stat(&path)?;
+        apply_create_mode(&self.fs, &fid.export, &path, &st, mode)?;
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &fid.path)?;
+        }
+        track_open(&self.leases, self.lease_owner, lcreate.fid, &st, flags);
+
+        // This fid now refers to the newly created file.
+        fid.walk_path = policy::join(&fid.walk_path, &lcreate.name);
+        fid.path = path;
+        fid.file = Some(file);
+        fid.open_flags = flags;
+        fid.qid_path = None;
+        fid.filetype = FileType::Regular;
+        drop(fid);
+        self.touch_files(&[lcreate.fid]);
+
+        Ok(Rlcreate {
+            qid: self.qids.qid(&st),
+            iounit: 0, // Allow the client to send requests up to the negotiated max message size.
+        })
+    }
+
+    // Makes `fid`, which refers to a directory, refer to a new regular file in there that has no
+    // name, open with the Tlopen `flags`. Clients can give it a name with Tlink.
+    fn open_tmpfile(
+        &mut self,
+        fid: u32,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64> {
+        let f = self.fids.get(fid).ok_or_else(ebadf)?;
+        let mut f = lock_fid(&f);
+        if f.filetype != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        self.quotas.check_create(&f.export)?;
+        let (uid, gid) = created_owner(&f.export, gid);
+        let mode = f.export.create_mode(mode);
+        let (path, file) = self.fs.tmpfile(&f.path, flags, mode, uid, gid)?;
+        self.quotas.created(&f.export);
+        let st = self.fs.stat(&path)?;
+        apply_create_mode(&self.fs, &f.export, &path, &st, mode)?;
+        track_open(&self.leases, self.lease_owner, fid, &st, flags);
+
+        f.path = path;
+        f.file = Some(file);
+        f.open_flags = flags;
+        f.filetype = FileType::Regular;
+        f.qid_path = None;
+        drop(f);
+        self.touch_files(&[fid]);
+        Ok(st)
+    }
+
+    fn open(&mut self, open: &Topen) -> io::Result<Ropen> {
+        let rlopen = self.lopen(&Tlopen {
+            fid: open.fid,
+            flags: omode_to_p9_flags(open.mode),
+        })?;
+        if open.mode & P9_ORCLOSE != 0 {
+            let fid = self.fids.get(open.fid).ok_or_else(ebadf)?;
+            let mut fid = lock_fid(&fid);
+            fid.remove_on_close = true;
+        }
+
+        Ok(Ropen {
+            qid: rlopen.qid,
+            iounit: rlopen.iounit,
+        })
+    }
+
+    fn create(&mut self, create: Tcreate) -> io::Result<Rcreate> {
+        let fid = self.fids.get(create.fid).ok_or_else(ebadf)?;
+        if lock_fid(&fid).filetype != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let mode = p9_perm_to_mode(create.perm);
+        let gid = libc::gid_t::MAX;
+
+        if create.perm & (P9_DMSYMLINK | P9_DMLINK | P9_DMDEVICE | P9_DMNAMEDPIPE | P9_DMSOCKET)
+            == 0
+        {
+            if create.perm & P9_DMDIR == 0 {
+                let rlcreate = self.lcreate(Tlcreate {
+                    fid: create.fid,
+                    name: create.name,
+                    flags: omode_to_p9_flags(create.mode),
+                    mode,
+                    gid,
+                })?;
+                if create.mode & P9_ORCLOSE != 0 {
+                    let fid = self.fids.get(create.fid).ok_or_else(ebadf)?;
+                    let mut fid = lock_fid(&fid);
+                    fid.remove_on_close = true;
+                }
+
+                return Ok(Rcreate {
+                    qid: rlcreate.qid,
+                    iounit: rlcreate.iounit,
+                });
+            }
+
+            self.mkdir(Tmkdir {
+                dfid: create.fid,
+                name: create.name.clone(),
+                mode,
+                gid,
+            })?;
+        } else if create.perm & P9_DMSYMLINK != 0 {
+            self.symlink(&Tsymlink {
+                fid: create.fid,
+                name: create.name.clone(),
+                symtgt: create.extension,
+                gid,
+            })?;
+        } else if create.perm & P9_DMLINK != 0 {
+            // The extension holds the fid of the link target.
+            let target = std::str::from_utf8(create.extension.as_bytes())
+                .ok()
+                .and_then(|ext| ext.trim().parse().ok())
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+            self.link(Tlink {
+                dfid: create.fid,
+                fid: target,
+                name: create.name.clone(),
+            })?;
+        } else {
+            let (ty, major, minor) = if create.perm & P9_DMDEVICE != 0 {
+                // The extension is of the form "b major minor" or "c major minor".
+                let ext = std::str::from_utf8(create.extension.as_bytes()).unwrap_or("");
+                let mut parts = ext.split_whitespace();
+                let ty = match parts.next() {
+                    Some("b") => libc::S_IFBLK,
+                    Some("c") => libc::S_IFCHR,
+                    _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+                };
+                let mut num = || {
+                    parts
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+                };
+                (ty, num()?, num()?)
+            } else if create.perm & P9_DMNAMEDPIPE != 0 {
+                (libc::S_IFIFO, 0, 0)
+            } else {
+                (libc::S_IFSOCK, 0, 0)
+            };
+
+            self.mknod(&Tmknod {
+                dfid: create.fid,
+                name: create.name.clone(),
+                mode: mode | ty,
+                major,
+                minor,
+                gid,
+            })?;
+        }
+
+        // The fid now refers to the newly created file.
+        let mut f = lock_fid(&fid);
+        f.path = names::lookup(&self.fs, &self.cfg, &f.path, create.name.as_c_str())?;
+        f.walk_path = policy::join(&f.walk_path, &create.name);
+        f.qid_path = None;
+        let st = self.fs.stat(&f.path)?;
+        f.filetype = st.st_mode.into();
+
+        if f.filetype == FileType::Directory {
+            drop(f);
+            let ropen = self.open(&Topen {
+                fid: create.fid,
+                mode: create.mode,
+            })?;
+
+            return Ok(Rcreate {
+                qid: ropen.qid,
+                iounit: ropen.iounit,
+            });
+        }
+
+        // Links and special files are not opened.
+        Ok(Rcreate {
+            qid: self.qids.qid(&st),
+            iounit: 0,
+        })
+    }
+
+    fn stat(&mut self, tstat: &Tstat) -> io::Result<Rstat> {
+        let fid = self.fids.get(tstat.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+
+        let st = self.fs.stat(&fid.path)?;
+        let name = self.fs.file_name(&fid.export, &fid.path, &fid.walk_path)?;
+        let readlink = || {
+            let link = self.fs.readlink(&fid.path)?;
+            Ok(self
+                .cfg
+                .absolute_symlinks
+                .target(&fid.export.root, &fid.walk_path, link)
+                .unwrap_or_default())
+        };
+
+        Ok(Rstat {
+            stat: to_stat(&fid.export, &st, self.qids.qid(&st), readlink, name)?,
+        })
+    }
+
+    fn wstat(&mut self, wstat: &Twstat) -> io::Result<()> {
+        let stat = &wstat.stat;
+        let f = self.fids.get(wstat.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&f);
+
+        let mut tsetattr = Tsetattr {
+            fid: wstat.fid,
+            valid: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime_sec: 0,
+            atime_nsec: 0,
+            mtime_sec: 0,
+            mtime_nsec: 0,
+        };
+
+        // Fields that the client doesn't want to change are set to all ones or the empty string.
+        if stat.mode != u32::MAX {
+            // The type of a file cannot be changed.
+            if (stat.mode & P9_DMDIR != 0) != (fid.filetype == FileType::Directory) {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            tsetattr.valid |= P9_SETATTR_MODE;
+            tsetattr.mode = p9_perm_to_mode(stat.mode);
+        }
+        if stat.atime != u32::MAX {
+            tsetattr.valid |= P9_SETATTR_ATIME | P9_SETATTR_ATIME_SET;
+            tsetattr.atime_sec = stat.atime.into();
+        }
+        if stat.mtime != u32::MAX {
+            tsetattr.valid |= P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET;
+            tsetattr.mtime_sec = stat.mtime.into();
+        }
+        if stat.length != u64::MAX {
+            tsetattr.valid |= P9_SETATTR_SIZE;
+            tsetattr.size = stat.length;
+        }
+        // Plain 9P2000 clients only have the string forms of the ids, which we always report as
+        // numbers.
+        if stat.n_uid != u32::MAX {
+            tsetattr.valid |= P9_SETATTR_UID;
+            tsetattr.uid = stat.n_uid;
+        } else if !stat.uid.is_empty() {
+            tsetattr.valid |= P9_SETATTR_UID;
+            tsetattr.uid = parse_id(&stat.uid)?;
+        }
+        if stat.n_gid != u32::MAX {
+            tsetattr.valid |= P9_SETATTR_GID;
+            tsetattr.gid = stat.n_gid;
+        } else if !stat.gid.is_empty() {
+            tsetattr.valid |= P9_SETATTR_GID;
+            tsetattr.gid = parse_id(&stat.gid)?;
+        }
+
+        if tsetattr.valid == 0 && stat.name.is_empty() {
+            // A wstat that doesn't change anything is a request to commit the file to stable
+            // storage.
+            drop(fid);
+            self.reopen_files(&[wstat.fid])?;
+            let fid = self.fids.get(wstat.fid).ok_or_else(ebadf)?;
+            let fid = lock_fid(&fid);
+            return match fid.file.as_ref() {
+                Some(file) => self.fs.fsync(file, false),
+                None => Ok(()),
+            };
+        }
+
+        if !stat.name.is_empty() {
+            if stat.name.as_bytes().contains(&b'/') {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            // Files can only be renamed within the directory that contains them.
+            let (parent, name) = self.fs.parent(&fid.export, &fid.path, &fid.walk_path)?;
+            let newname = names::rename_target(
+                &self.fs,
+                &self.cfg,
+                &parent,
+                &name,
+                &parent,
+                stat.name.as_c_str(),
+            )?;
+            self.fs.rename(&parent, &name, &parent, &newname)?;
+            if self.cfg.sync_dirs {
+                sync_dir(&self.fs, &parent)?;
+            }
+
+            let dir = fid.walk_path.parent().unwrap_or(Path::new("/"));
+            fid.walk_path = policy::join(dir, &stat.name);
+        }
+        drop(fid);
+
+        if tsetattr.valid != 0 {
+            self.set_attr(&tsetattr)?;
+        }
+
+        Ok(())
+    }
+
+    fn symlink(&mut self, _symlink: &Tsymlink) -> io::Result<Rsymlink> {
+        // symlinks are not allowed.
+        Err(io::Error::from_raw_os_error(libc::EACCES))
+    }
+
+    fn mknod(&mut self, mknod: &Tmknod) -> io::Result<Rmknod> {
+        let fid = self.fids.get(mknod.dfid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        self.cfg.mknod.check(mknod.mode, mknod.major, mknod.minor)?;
+        if self.cfg.special_files.hides(mknod.mode) {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
+        let name = names::new_entry_name(&self.fs, &self.cfg, &fid.path, mknod.name.as_c_str())?;
+        self.quotas.check_create(&fid.export)?;
+        let (uid, gid) = created_owner(&fid.export, mknod.gid);
+        let rdev = libc::makedev(mknod.major, mknod.minor);
+        let mode = fid.export.create_mode(mknod.mode);
+        let st = self.fs.mknod(&fid.path, &name, mode, rdev, uid, gid)?;
+        self.quotas.created(&fid.export);
+        if fid.export.umask.is_some() {
+            let inode = self.fs.lookup(&fid.path, &name)?;
+            apply_create_mode(&self.fs, &fid.export, &inode, &st, mode)?;
+        }
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &fid.path)?;
+        }
+        Ok(Rmknod {
+            qid: self.qids.qid(&st),
+        })
+    }
+
+    fn rename(&mut self, _rename: &Trename) -> io::Result<()> {
+        // We cannot support this as an inode may be linked into multiple directories but we don't
+        // know which one the client wants us to rename. Linux uses rename_at anyway, so we don't
+        // need to worry about this.
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    fn readlink(&mut self, readlink: &Treadlink) -> io::Result<Rreadlink> {
+        let fid = self.fids.get(readlink.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+
+        let link = self.fs.readlink(&fid.path)?;
+        let link = self
+            .cfg
+            .absolute_symlinks
+            .target(&fid.export.root, &fid.walk_path, link)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EACCES))?;
+        let target = P9String::new(link)?;
+        Ok(Rreadlink { target })
+    }
+
+    fn get_attr(&mut self, get_attr: &Tgetattr) -> io::Result<Rgetattr> {
+        let fid = self.fids.get(get_attr.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+        let now = Instant::now();
+        if let Some(attrs) = self.attrs.as_mut() {
+            if let Some(rgetattr) = attrs.get(fid.qid_path, get_attr.request_mask, now) {
+                return Ok(fid.export.attr_to_client(rgetattr));
+            }
+        }
+
+        let mut rgetattr = self
+            .fs
+            .getattr(&fid.path, fid.file.as_ref(), get_attr.request_mask)?;
+        // File systems report inode numbers, which are also the paths as long as the tree is on a
+        // single device.
+        if !self.qids.is_identity() {
+            rgetattr.qid.path = self.qids.qid(&self.fs.stat(&fid.path)?).path;
+        }
+        if let Some(attrs) = self.attrs.as_mut() {
+            fid.qid_path = Some(rgetattr.qid.path);
+            attrs.insert(rgetattr, now);
+        }
+        Ok(fid.export.attr_to_client(rgetattr))
+    }
+
+    // Drops the names that were found missing from the directory that `msg` adds a name to.
+    fn forget_missing(&mut self, msg: &Tmessage) {
+        let dfid = match msg {
+            Tmessage::Create(_)
+            | Tmessage::Lcreate(_)
+            | Tmessage::Symlink(_)
+            | Tmessage::Mknod(_)
+            | Tmessage::Mkdir(_)
+            | Tmessage::Link(_)
+            | Tmessage::RenameAt(_)
+            | Tmessage::RenameAt2(_) => modified_fid(msg),
+            Tmessage::Rename(rename) => Some(rename.dfid),
+            // Renames through Twstat keep the file in the directory that it was in, which isn't
+            // known.
+            Tmessage::Wstat(_) => None,
+            _ => return,
+        };
+        let dir = dfid
+            .and_then(|dfid| self.fids.get(dfid))
+            .and_then(|fid| lock_fid(&fid).qid_path);
+        match (self.missing.as_mut(), dir) {
+            (Some(missing), Some(dir)) => missing.forget_dir(dir),
+            (Some(missing), None) => missing.clear(),
+            (None, _) => {}
+        }
+    }
+
+    // Drops the cached attributes of whatever `msg` changes.
+    fn forget_attrs(&mut self, msg: &Tmessage) {
+        match msg {
+            // These only change the file of the fid.
+            Tmessage::Write(_)
+            | Tmessage::SetAttr(_)
+            | Tmessage::Fallocate(_)
+            | Tmessage::CopyRange(_)
+            | Tmessage::CloneRange(_)
+            | Tmessage::XattrCreate(_)
+            | Tmessage::Open(_)
+            | Tmessage::Lopen(_) => {
+                if let Some(fid) = modified_fid(msg) {
+                    self.forget_attrs_of(fid);
+                }
+            }
+            // Anything else that changes the tree may change other files too, like the one that
+            // a rename replaces.
+            _ if self.changed_fid(msg).is_some() => {
+                if let Some(attrs) = self.attrs.as_mut() {
+                    attrs.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Drops the cached attributes of the file of `fid`, or of every file if it isn't known
+    // which one that is.
+    fn forget_attrs_of(&mut self, fid: u32) {
+        let qid = self.fids.get(fid).and_then(|fid| lock_fid(&fid).qid_path);
+        match (self.attrs.as_mut(), qid) {
+            (Some(attrs), Some(qid)) => attrs.remove(qid),
+            (Some(attrs), None) => attrs.clear(),
+            (None, _) => {}
+        }
+    }
+
+    fn set_attr(&mut self, set_attr: &Tsetattr) -> io::Result<()> {
+        let fid = self.fids.get(set_attr.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+
+        if set_attr.valid & P9_SETATTR_SIZE != 0 && fid.filetype == FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        if set_attr.valid & P9_SETATTR_SIZE != 0
+            && fid
+                .export
+                .max_file_size
+                .is_some_and(|max| set_attr.size > max)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EFBIG));
+        }
+        let size = if set_attr.valid & P9_SETATTR_SIZE != 0 && self.quotas.counts_bytes(&fid.export)
+        {
+            let size = self.fs.stat(&fid.path)?.st_size as u64;
+            self.quotas.check_resize(&fid.export, size, set_attr.size)?;
+            Some(size)
+        } else {
+            None
+        };
+
+        // The file system only ever sees host ids, and only the modes that the export allows.
+        // Like chown(2) on an idmapped mount, ids that have no host id fail with EINVAL.
+        let to_host = |flag, id: Option<u32>| match id {
+            Some(id) => Ok(id),
+            None if set_attr.valid & flag == 0 => Ok(u32::MAX),
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let attr = Tsetattr {
+            mode: fid.export.mode_to_host(set_attr.mode),
+            uid: to_host(P9_SETATTR_UID, fid.export.uid_to_host(set_attr.uid))?,
+            gid: to_host(P9_SETATTR_GID, fid.export.gid_to_host(set_attr.gid))?,
+            ..*set_attr
+        };
+        self.fs.setattr(&fid.path, fid.file.as_ref(), &attr)?;
+        if let Some(size) = size {
+            self.quotas.resized(&fid.export, size, set_attr.size);
+        }
+        Ok(())
+    }
+
+    fn xattr_walk(&mut self, xattr_walk: &Txattrwalk) -> io::Result<Rxattrwalk> {
+        // `newfid` must not currently be in use unless it is the same as `fid`.
+        if xattr_walk.fid != xattr_walk.newfid && self.fid_in_use(xattr_walk.newfid) {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+        self.check_fid_limit(xattr_walk.newfid)?;
+
+        let fid = self.fids.get(xattr_walk.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let name = xattr_walk.name.as_c_str();
+        let value = if name.is_empty() {
+            let list = self.fs.getxattr(&fid.path, fid.file.as_ref(), name)?;
+            self.cfg.xattr_list_to_client(list)
+        } else {
+            let host_name = self.cfg.readable_xattr(name)?;
+            let value = self.fs.getxattr(&fid.path, fid.file.as_ref(), host_name)?;
+            acl::to_client(&fid.export, name, value)?
+        };
+        let size = value.len() as u64;
+
+        let newfid = Fid {
+            path: self.fs.try_clone(&fid.path)?,
+            file: None,
+            open_flags: 0,
+            filetype: fid.filetype,
+            dir_offset: (0, 0),
+            read_ahead: (0, 0),
+            pending_write: None,
+            write_error: None,
+            qid_path: None,
+            xattr: Some(Xattr::Read(value)),
+            watch: None,
+            lock_owners: BTreeSet::new(),
+            remove_on_close: false,
+            export: fid.export.clone(),
+            walk_path: fid.walk_path.clone(),
+            last_used: Some(Instant::now()),
+            identity: fid.identity.clone(),
+        };
+        self.fids.insert(xattr_walk.newfid, newfid);
+
+        Ok(Rxattrwalk { size })
+    }
+
+    fn xattr_create(&mut self, xattr_create: &Txattrcreate) -> io::Result<()> {
+        let flags = xattr_create.flags as libc::c_int;
+        if flags & !(libc::XATTR_CREATE | libc::XATTR_REPLACE) != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if xattr_create.attr_size > XATTR_SIZE_MAX {
+            return Err(io::Error::from_raw_os_error(libc::E2BIG));
+        }
+        if xattr_create.name.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let fid = self.fids.get(xattr_create.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+        if fid.xattr.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // The fid now refers to the attribute, whose value the client sends with Twrite.
+        let name = self.cfg.writable_xattr(xattr_create.name.as_c_str())?;
+        let size = xattr_create.attr_size as usize;
+        fid.xattr = Some(Xattr::Write {
+            name: name.to_owned(),
+            value: vec![0; size],
+            written: Vec::new(),
+            flags,
+        });
+
+        Ok(())
+    }
+
+    fn readdir(&mut self, readdir: &Treaddir) -> io::Result<Rreaddir> {
+        let data = self.read_dir_entries(
+            readdir.fid,
+            readdir.offset,
+            readdir.count,
+            |_, dirent, _| dirent,
+        )?;
+        Ok(Rreaddir { data })
+    }
+
+    fn readdirplus(&mut self, readdirplus: &Treaddirplus) -> io::Result<Rreaddirplus> {
+        if !self.extensions.contains(Extensions::READDIRPLUS) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        let data = self.read_dir_entries(
+            readdirplus.fid,
+            readdirplus.offset,
+            readdirplus.count,
+            |export, dirent, st| {
+                let mut attr = stat_to_attr(st);
+                attr.qid = dirent.qid;
+                DirentPlus {
+                    dirent,
+                    attr: export.attr_to_client(attr),
+                }
+            },
+        )?;
+        Ok(Rreaddirplus { data })
+    }
+
+    // Reads the entries of the directory `fid`, starting at `offset`, and packs as many of them
+    // as fit in `count` bytes. Every entry is stat'ed relative to the directory for its qid, and
+    // `entry` turns the dirent and that stat into what is sent to the client.
+    fn read_dir_entries<E, G>(
+        &mut self,
+        fid: u32,
+        offset: u64,
+        count: u32,
+        mut entry: G,
+    ) -> io::Result<Data>
+    where
+        E: WireFormat,
+        G: FnMut(&Export, Dirent, &libc::stat64) -> E,
+    {
+        let fid = self.fids.get(fid).ok_or_else(ebadf)?;
+        let mut guard = lock_fid(&fid);
+        let fid = &mut *guard;
 
-        // Use an empty Rread struct to figure out the overhead of the header.
+        if fid.filetype != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        // Use an empty Rreaddir struct to figure out the maximum number of bytes that
+        // can be returned.
         let header_size = Rframe {
             tag: 0,
-            msg: Rmessage::Read(Rread {
+            msg: Rmessage::Readdir(Rreaddir {
                 data: Data(Vec::new()),
             }),
         }
         .byte_size();
+        let count = min(self.msize - header_size, count);
+        let mut cursor = Cursor::new(Vec::with_capacity(count as usize));
 
-        let capacity = min(self.cfg.msize - header_size, read.count);
-        let mut buf = Data(vec![0u8; capacity as usize]);
-
-        let count = file.read_at(&mut buf, read.offset)?;
-        buf.truncate(count);
-
-        Ok(Rread { data: buf })
-    }
+        let dir = fid.file.as_mut().ok_or_else(ebadf)?;
+        self.fs.readdir(&fid.path, dir, offset, &mut |dirent, st| {
+            check_cancelled(&self.cancel)?;
+            if is_hidden(&self.cfg, dirent.name.as_bytes())
+                || self.cfg.special_files.hides(st.st_mode)
+            {
+                return Ok(true);
+            }
 
-    fn write(&mut self, write: &Twrite) -> io::Result<Rwrite> {
-        let file = self
-            .fids
-            .get_mut(&write.fid)
-            .and_then(|fid| fid.file.as_mut())
-            .ok_or_else(ebadf)?;
+            let entry = entry(
+                &fid.export,
+                Dirent {
+                    qid: self.qids.qid(st),
+                    offset: dirent.offset,
+                    ty: dirent.type_,
+                    name: dirent.name,
+                },
+                st,
+            );
 
-        let count = file.write_at(&write.data, write.offset)?;
-        Ok(Rwrite {
-            count: count as u32,
-        })
-    }
+            let byte_size = entry.byte_size() as usize;
 
-    fn clunk(&mut self, clunk: &Tclunk) -> io::Result<()> {
-        match self.fids.entry(clunk.fid) {
-            btree_map::Entry::Vacant(_) => Err(io::Error::from_raw_os_error(libc::EBADF)),
-            btree_map::Entry::Occupied(entry) => {
-                entry.remove();
-                Ok(())
+            if cursor.get_ref().capacity() - cursor.get_ref().len() < byte_size {
+                // No more room in the buffer.
+                return Ok(false);
             }
-        }
-    }
 
-    fn remove(&mut self, _remove: &Tremove) -> io::Result<()> {
-        // Since a file could be linked into multiple locations, there is no way to know exactly
-        // which path we are supposed to unlink. Linux uses unlink_at anyway, so we can just return
-        // an error here.
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
-    }
+            entry.encode(&mut cursor)?;
+            Ok(true)
+        })?;
 
-    fn statfs(&mut self, statfs: &Tstatfs) -> io::Result<Rstatfs> {
-        let fid = self.fids.get(&statfs.fid).ok_or_else(ebadf)?;
-        let mut buf = MaybeUninit::zeroed();
-
-        // Safe because this will only modify `out` and we check the return value.
-        syscall!(unsafe { libc::fstatfs64(fid.path.as_raw_fd(), buf.as_mut_ptr()) })?;
-
-        // Safe because this only has integer types and any value is valid.
-        let out = unsafe { buf.assume_init() };
-        Ok(Rstatfs {
-            ty: out.f_type as u32,
-            bsize: out.f_bsize as u32,
-            blocks: out.f_blocks,
-            bfree: out.f_bfree,
-            bavail: out.f_bavail,
-            files: out.f_files,
-            ffree: out.f_ffree,
-            // Safe because the fsid has only integer fields and the compiler will verify that is
-            // the same width as the `fsid` field in Rstatfs.
-            fsid: unsafe { mem::transmute::<fsid_t, u64>(out.f_fsid) },
-            namelen: out.f_namelen as u32,
-        })
+        Ok(Data(cursor.into_inner()))
     }
 
-    fn lopen(&mut self, lopen: &Tlopen) -> io::Result<Rlopen> {
-        let fid = self.fids.get_mut(&lopen.fid).ok_or_else(ebadf)?;
+    // Reads the entries of the directory open in `fid` as a sequence of stats, which is how
+    // directories are read in 9P2000.u. The offset of each read must either be 0 or the offset
+    // immediately following the data returned by the previous read.
+    fn read_dir_stats(&mut self, fid: u32, offset: u64, count: u32) -> io::Result<Vec<u8>> {
+        let fid = self.fids.get(fid).ok_or_else(ebadf)?;
+        let mut guard = lock_fid(&fid);
+        let fid = &mut *guard;
+        let (next_offset, mut pos) = if offset == 0 { (0, 0) } else { fid.dir_offset };
+        if offset != next_offset {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
 
-        let file = open_fid(&self.proc, &fid.path, lopen.flags)?;
-        let st = stat(&file)?;
+        let mut buf = Vec::with_capacity(count as usize);
 
-        fid.file = Some(file);
-        Ok(Rlopen {
-            qid: st.into(),
-            iounit: 0, // Allow the client to send requests up to the negotiated max message size.
-        })
-    }
+        let fs = &self.fs;
+        let dir = fid.file.as_mut().ok_or_else(ebadf)?;
+        fs.readdir(&fid.path, dir, pos, &mut |dirent, st| {
+            check_cancelled(&self.cancel)?;
+
+            // 9P2000 directories never contain entries for themselves or their parents.
+            let dots = dirent.name == "." || dirent.name == "..";
+            if !dots
+                && !is_hidden(&self.cfg, dirent.name.as_bytes())
+                && !self.cfg.special_files.hides(st.st_mode)
+            {
+                let readlink = || {
+                    let link = fs.readlink(&fs.lookup(&fid.path, dirent.name.as_c_str())?)?;
+                    let path = policy::join(&fid.walk_path, &dirent.name);
+                    Ok(self
+                        .cfg
+                        .absolute_symlinks
+                        .target(&fid.export.root, &path, link)
+                        .unwrap_or_default())
+                };
+                let qid = self.qids.qid(st);
+                let stat = to_stat(&fid.export, st, qid, readlink, dirent.name.clone())?;
 
-    fn lcreate(&mut self, lcreate: Tlcreate) -> io::Result<Rlcreate> {
-        let fid = self.fids.get_mut(&lcreate.fid).ok_or_else(ebadf)?;
+                if count as usize - buf.len() < stat.byte_size_dialect(self.dialect) as usize {
+                    if buf.is_empty() {
+                        // Not even a single entry fits in the requested count.
+                        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                    }
 
-        if fid.filetype != FileType::Directory {
-            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
-        }
+                    // No more room in the buffer.
+                    return Ok(false);
+                }
 
-        let mut flags: i32 = libc::O_CLOEXEC | libc::O_CREAT | libc::O_EXCL;
-        for &(p9f, of) in &MAPPED_FLAGS {
-            if (lcreate.flags & p9f) != 0 {
-                flags |= of;
+                stat.encode_dialect(&mut buf, self.dialect)?;
             }
-        }
-        if lcreate.flags & P9_NOACCESS == P9_RDONLY {
-            flags |= libc::O_RDONLY;
-        }
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let fd = syscall!(unsafe {
-            libc::openat64(
-                fid.path.as_raw_fd(),
-                lcreate.name.as_ptr(),
-                flags,
-                lcreate.mode,
-            )
+            pos = dirent.offset;
+            Ok(true)
         })?;
 
-        // Safe because we just opened this fd and we know it is valid.
-        let file = unsafe { File::from_raw_fd(fd) };
-        let st = stat(&file)?;
+        fid.dir_offset = (offset + buf.len() as u64, pos);
+        Ok(buf)
+    }
 
-        fid.file = Some(file);
-        fid.filetype = FileType::Regular;
+    fn copy_range(&mut self, copy_range: &Tcopyrange) -> io::Result<Rcopyrange> {
+        if !self.extensions.contains(Extensions::COPYRANGE) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-        // This fid now refers to the newly created file so we need to update the O_PATH fd for it
-        // as well.
-        fid.path = lookup(&fid.path, lcreate.name.as_c_str())?;
+        self.reopen_files(&[copy_range.src_fid, copy_range.dst_fid])?;
+        let src = self.fids.get(copy_range.src_fid).ok_or_else(ebadf)?;
+        let dst = self.fids.get(copy_range.dst_fid).ok_or_else(ebadf)?;
+        let (src, dst) = lock_pair(&src, &dst);
+        let dst = dst.as_deref().unwrap_or(&src);
+        if src.filetype != FileType::Regular || dst.filetype != FileType::Regular {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let src_file = open_host_file(&self.fs, &src)?;
+        let dst_file = open_host_file(&self.fs, dst)?;
+
+        let mut total = writable_len(&dst.export, copy_range.dst_offset, copy_range.count)?;
+        let size = if self.quotas.counts_bytes(&dst.export) {
+            let size = self.fs.stat(&dst.path)?.st_size as u64;
+            total = self
+                .quotas
+                .writable_len(&dst.export, size, copy_range.dst_offset, total)?;
+            Some(size)
+        } else {
+            None
+        };
 
-        Ok(Rlcreate {
-            qid: st.into(),
-            iounit: 0, // Allow the client to send requests up to the negotiated max message size.
-        })
-    }
+        let result = copy_chunks(
+            &self.cancel,
+            src_file,
+            copy_range.src_offset,
+            dst_file,
+            copy_range.dst_offset,
+            total,
+        );
+        if let Some(size) = size {
+            // Even a copy that fails may have copied some of the data.
+            if let Ok(st) = self.fs.stat(&dst.path) {
+                self.quotas.resized(&dst.export, size, st.st_size as u64);
+            }
+        }
 
-    fn symlink(&mut self, _symlink: &Tsymlink) -> io::Result<Rsymlink> {
-        // symlinks are not allowed.
-        Err(io::Error::from_raw_os_error(libc::EACCES))
+        Ok(Rcopyrange { count: result? })
     }
 
-    fn mknod(&mut self, _mknod: &Tmknod) -> io::Result<Rmknod> {
-        // No nodes either.
-        Err(io::Error::from_raw_os_error(libc::EACCES))
-    }
+    fn clone_range(&mut self, clone_range: &Tclonerange) -> io::Result<()> {
+        if !self.extensions.contains(Extensions::CLONERANGE) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-    fn rename(&mut self, _rename: &Trename) -> io::Result<()> {
-        // We cannot support this as an inode may be linked into multiple directories but we don't
-        // know which one the client wants us to rename. Linux uses rename_at anyway, so we don't
-        // need to worry about this.
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        self.reopen_files(&[clone_range.src_fid, clone_range.dst_fid])?;
+        let src = self.fids.get(clone_range.src_fid).ok_or_else(ebadf)?;
+        let dst = self.fids.get(clone_range.dst_fid).ok_or_else(ebadf)?;
+        let (src, dst) = lock_pair(&src, &dst);
+        let dst = dst.as_deref().unwrap_or(&src);
+        if src.filetype != FileType::Regular || dst.filetype != FileType::Regular {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let src_file = open_host_file(&self.fs, &src)?;
+        let dst_file = open_host_file(&self.fs, dst)?;
+
+        let range = libc::file_clone_range {
+            src_fd: src_file.as_raw_fd().into(),
+            src_offset: clone_range.src_offset,
+            src_length: clone_range.count,
+            dest_offset: clone_range.dst_offset,
+        };
+        // Safe because the kernel only reads `range`, which lives on the stack, and we check the
+        // return value.
+        syscall!(unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONERANGE as _, &range) })
+            .map(drop)
+            .map_err(|e| match e.raw_os_error() {
+                // File systems without reflinks don't implement the ioctl at all, and files on
+                // different file systems can never share storage. Clients only need to know
+                // that they have to copy the data instead.
+                Some(libc::ENOTTY) | Some(libc::EXDEV) => {
+                    io::Error::from_raw_os_error(libc::EOPNOTSUPP)
+                }
+                _ => e,
+            })
     }
 
-    fn readlink(&mut self, readlink: &Treadlink) -> io::Result<Rreadlink> {
-        let fid = self.fids.get(&readlink.fid).ok_or_else(ebadf)?;
+    fn lseek(&mut self, lseek: &Tlseek) -> io::Result<Rlseek> {
+        if !self.extensions.contains(Extensions::LSEEK) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-        let mut link = vec![0; libc::PATH_MAX as usize];
+        // All other I/O goes through pread and pwrite, so the file offset only matters here and
+        // there's no notion of a current position to seek from.
+        let whence = match lseek.whence {
+            P9_SEEK_DATA => libc::SEEK_DATA,
+            P9_SEEK_HOLE => libc::SEEK_HOLE,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let offset = libc::off64_t::try_from(lseek.offset)
+            .map_err(|_| io::Error::from_raw_os_error(libc::ENXIO))?;
 
-        // Safe because this will only modify `link` and we check the return value.
-        let len = syscall!(unsafe {
-            libc::readlinkat(
-                fid.path.as_raw_fd(),
-                [0].as_ptr(),
-                link.as_mut_ptr() as *mut libc::c_char,
-                link.len(),
-            )
-        })? as usize;
-        link.truncate(len);
-        let target = P9String::new(link)?;
-        Ok(Rreadlink { target })
-    }
+        self.reopen_files(&[lseek.fid])?;
+        let fid = self.fids.get(lseek.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let file = open_host_file(&self.fs, &fid)?;
 
-    #[allow(clippy::unnecessary_cast)] // nlink_t is u32 on 32-bit platforms
-    fn get_attr(&mut self, get_attr: &Tgetattr) -> io::Result<Rgetattr> {
-        let fid = self.fids.get_mut(&get_attr.fid).ok_or_else(ebadf)?;
-
-        let st = stat(&fid.path)?;
-
-        Ok(Rgetattr {
-            valid: P9_GETATTR_BASIC,
-            qid: st.into(),
-            mode: st.st_mode,
-            uid: map_id_from_host(&self.cfg.uid_map, st.st_uid),
-            gid: map_id_from_host(&self.cfg.gid_map, st.st_gid),
-            nlink: st.st_nlink as u64,
-            rdev: st.st_rdev,
-            size: st.st_size as u64,
-            blksize: st.st_blksize as u64,
-            blocks: st.st_blocks as u64,
-            atime_sec: st.st_atime as u64,
-            atime_nsec: st.st_atime_nsec as u64,
-            mtime_sec: st.st_mtime as u64,
-            mtime_nsec: st.st_mtime_nsec as u64,
-            ctime_sec: st.st_ctime as u64,
-            ctime_nsec: st.st_ctime_nsec as u64,
-            btime_sec: 0,
-            btime_nsec: 0,
-            gen: 0,
-            data_version: 0,
+        // Safe because this doesn't modify any memory and we check the return value.
+        let offset = syscall!(unsafe { libc::lseek64(file.as_raw_fd(), offset, whence) })?;
+
+        Ok(Rlseek {
+            offset: offset as u64,
         })
     }
 
-    fn set_attr(&mut self, set_attr: &Tsetattr) -> io::Result<()> {
-        let fid = self.fids.get(&set_attr.fid).ok_or_else(ebadf)?;
-        let path = string_to_cstring(format!("self/fd/{}", fid.path.as_raw_fd()))?;
+    fn fallocate(&mut self, fallocate: &Tfallocate) -> io::Result<()> {
+        if !self.extensions.contains(Extensions::FALLOCATE) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-        if set_attr.valid & P9_SETATTR_MODE != 0 {
-            // Safe because this doesn't modify any memory and we check the return value.
-            syscall!(unsafe {
-                libc::fchmodat(self.proc.as_raw_fd(), path.as_ptr(), set_attr.mode, 0)
-            })?;
+        // Only pass on the combinations that fallocate(2) documents, so that a client can't reach
+        // modes such as collapsing or inserting ranges that we haven't thought about.
+        let mode = match fallocate.mode {
+            0 => 0,
+            P9_FALLOC_FL_KEEP_SIZE => libc::FALLOC_FL_KEEP_SIZE,
+            m if m == P9_FALLOC_FL_PUNCH_HOLE | P9_FALLOC_FL_KEEP_SIZE => {
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+            }
+            P9_FALLOC_FL_ZERO_RANGE => libc::FALLOC_FL_ZERO_RANGE,
+            m if m == P9_FALLOC_FL_ZERO_RANGE | P9_FALLOC_FL_KEEP_SIZE => {
+                libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE
+            }
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let offset = libc::off64_t::try_from(fallocate.offset)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+        let length = libc::off64_t::try_from(fallocate.length)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+
+        self.reopen_files(&[fallocate.fid])?;
+        let fid = self.fids.get(fallocate.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        if fid.filetype != FileType::Regular {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV));
+        }
+        // Only punching holes gives space back, everything else may allocate all of the range.
+        let end = fallocate.offset.saturating_add(fallocate.length);
+        if mode & libc::FALLOC_FL_PUNCH_HOLE == 0
+            && fid.export.max_file_size.is_some_and(|max| end > max)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EFBIG));
         }
+        // Quotas only count the sizes of files, so only the modes that can change those matter.
+        let size = if mode & libc::FALLOC_FL_KEEP_SIZE == 0 && self.quotas.counts_bytes(&fid.export)
+        {
+            let size = self.fs.stat(&fid.path)?.st_size as u64;
+            self.quotas
+                .check_resize(&fid.export, size, max(size, end))?;
+            Some(size)
+        } else {
+            None
+        };
+        let file = open_host_file(&self.fs, &fid)?;
 
-        if set_attr.valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
-            let uid = if set_attr.valid & P9_SETATTR_UID != 0 {
-                set_attr.uid
-            } else {
-                -1i32 as u32
-            };
-            let gid = if set_attr.valid & P9_SETATTR_GID != 0 {
-                set_attr.gid
-            } else {
-                -1i32 as u32
-            };
+        // Safe because this doesn't modify any memory and we check the return value. File
+        // systems that don't support a mode fail with EOPNOTSUPP, which is passed on as is.
+        syscall!(unsafe { libc::fallocate64(file.as_raw_fd(), mode, offset, length) })?;
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            syscall!(unsafe { libc::fchownat(self.proc.as_raw_fd(), path.as_ptr(), uid, gid, 0) })?;
+        if let Some(size) = size {
+            self.quotas.resized(&fid.export, size, max(size, end));
         }
+        Ok(())
+    }
 
-        if set_attr.valid & P9_SETATTR_SIZE != 0 {
-            let file = if fid.filetype == FileType::Directory {
-                return Err(io::Error::from_raw_os_error(libc::EISDIR));
-            } else if let Some(ref file) = fid.file {
-                MaybeOwned::Borrowed(file)
-            } else {
-                MaybeOwned::Owned(open_fid(&self.proc, &fid.path, P9_NONBLOCK | P9_RDWR)?)
-            };
-
-            file.set_len(set_attr.size)?;
+    fn fadvise(&mut self, fadvise: &Tfadvise) -> io::Result<()> {
+        if !self.extensions.contains(Extensions::FADVISE) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
 
-        if set_attr.valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
-            let times = [
-                libc::timespec {
-                    tv_sec: set_attr.atime_sec as _,
-                    tv_nsec: if set_attr.valid & P9_SETATTR_ATIME == 0 {
-                        libc::UTIME_OMIT
-                    } else if set_attr.valid & P9_SETATTR_ATIME_SET == 0 {
-                        libc::UTIME_NOW
-                    } else {
-                        set_attr.atime_nsec as _
-                    },
-                },
-                libc::timespec {
-                    tv_sec: set_attr.mtime_sec as _,
-                    tv_nsec: if set_attr.valid & P9_SETATTR_MTIME == 0 {
-                        libc::UTIME_OMIT
-                    } else if set_attr.valid & P9_SETATTR_MTIME_SET == 0 {
-                        libc::UTIME_NOW
-                    } else {
-                        set_attr.mtime_nsec as _
-                    },
-                },
-            ];
-
-            // Safe because file is valid and we have initialized times fully.
-            let ret = unsafe {
-                libc::utimensat(
-                    self.proc.as_raw_fd(),
-                    path.as_ptr(),
-                    &times as *const libc::timespec,
-                    0,
-                )
-            };
-            if ret < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-
-        // The ctime would have been updated by any of the above operations so we only
-        // need to change it if it was the only option given.
-        if set_attr.valid & P9_SETATTR_CTIME != 0 && set_attr.valid & (!P9_SETATTR_CTIME) == 0 {
-            // Setting -1 as the uid and gid will not actually change anything but will
-            // still update the ctime.
-            let ret = unsafe {
-                libc::fchownat(
-                    self.proc.as_raw_fd(),
-                    path.as_ptr(),
-                    libc::uid_t::MAX,
-                    libc::gid_t::MAX,
-                    0,
-                )
-            };
-            if ret < 0 {
-                return Err(io::Error::last_os_error());
-            }
+        // The values of some of these differ between architectures, so they can't be passed on
+        // as they are.
+        let advice = match fadvise.advice {
+            P9_FADV_NORMAL => libc::POSIX_FADV_NORMAL,
+            P9_FADV_RANDOM => libc::POSIX_FADV_RANDOM,
+            P9_FADV_SEQUENTIAL => libc::POSIX_FADV_SEQUENTIAL,
+            P9_FADV_WILLNEED => libc::POSIX_FADV_WILLNEED,
+            P9_FADV_DONTNEED => libc::POSIX_FADV_DONTNEED,
+            P9_FADV_NOREUSE => libc::POSIX_FADV_NOREUSE,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let offset = libc::off64_t::try_from(fadvise.offset)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let length = libc::off64_t::try_from(fadvise.length)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        self.reopen_files(&[fadvise.fid])?;
+        let fid = self.fids.get(fadvise.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let file = open_host_file(&self.fs, &fid)?;
+
+        // Safe because this doesn't modify any memory. posix_fadvise returns the error rather
+        // than setting errno.
+        let ret = unsafe { libc::posix_fadvise64(file.as_raw_fd(), offset, length, advice) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
         }
 
         Ok(())
     }
 
-    fn xattr_walk(&mut self, _xattr_walk: &Txattrwalk) -> io::Result<Rxattrwalk> {
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
-    }
+    fn ioctl(&mut self, tioctl: &Tioctl) -> io::Result<Rioctl> {
+        if !self.extensions.contains(Extensions::IOCTL) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-    fn xattr_create(&mut self, _xattr_create: &Txattrcreate) -> io::Result<()> {
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        let ioctl = Ioctl::from_request(tioctl.request)
+            .filter(|ioctl| self.cfg.ioctls.contains(&ioctl.request))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOTTY))?;
+        ioctl
+            .check_input(&tioctl.data)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        self.reopen_files(&[tioctl.fid])?;
+        let fid = self.fids.get(tioctl.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let file = open_host_file(&self.fs, &fid)?;
+
+        // Some ioctls are declared with a bigger argument than the kernel actually uses, so make
+        // sure that there is room for the declared one.
+        let mut arg = vec![0u8; ioctl.input.max(ioctl.output).max(host_arg_size(&ioctl))];
+        arg[..ioctl.input].copy_from_slice(&tioctl.data);
+        ioctl::convert_words(&mut arg[..ioctl.input]);
+
+        // Safe because `arg` is at least as big as the argument of the ioctl, which is one of a
+        // few that only read or write their argument, and we check the return value.
+        syscall!(unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                host_request(&ioctl) as _,
+                arg.as_mut_ptr(),
+            )
+        })?;
+
+        arg.truncate(ioctl.output);
+        ioctl::convert_words(&mut arg);
+        Ok(Rioctl { data: Data(arg) })
     }
 
-    fn readdir(&mut self, readdir: &Treaddir) -> io::Result<Rreaddir> {
-        let fid = self.fids.get_mut(&readdir.fid).ok_or_else(ebadf)?;
+    fn watch(&mut self, watch: &Twatch) -> io::Result<()> {
+        if !self.extensions.contains(Extensions::NOTIFY) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        if watch.mask == 0 || watch.mask & !libc::IN_ALL_EVENTS != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
 
-        if fid.filetype != FileType::Directory {
-            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        // `newfid` must not currently be in use unless it is the same as `fid`.
+        if watch.fid != watch.newfid && self.fid_in_use(watch.newfid) {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
         }
+        self.check_fid_limit(watch.newfid)?;
 
-        // Use an empty Rreaddir struct to figure out the maximum number of bytes that
-        // can be returned.
-        let header_size = Rframe {
-            tag: 0,
-            msg: Rmessage::Readdir(Rreaddir {
-                data: Data(Vec::new()),
-            }),
+        let fid = self.fids.get(watch.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let path = self
+            .fs
+            .host_path(&fid.path)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOPNOTSUPP))?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = syscall!(unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) })?;
+        // Safe because we just opened this fd and we know it is valid.
+        let inotify = unsafe { File::from_raw_fd(fd) };
+
+        // inotify only takes paths, but the proc symlink leads to exactly the file that the fid
+        // refers to.
+        let path = string_to_cstring(format!("/proc/self/fd/{}", path.as_raw_fd()))?;
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe {
+            libc::inotify_add_watch(inotify.as_raw_fd(), path.as_ptr(), watch.mask)
+        })?;
+
+        let newfid = Fid {
+            path: self.fs.try_clone(&fid.path)?,
+            file: None,
+            open_flags: 0,
+            filetype: fid.filetype,
+            dir_offset: (0, 0),
+            read_ahead: (0, 0),
+            pending_write: None,
+            write_error: None,
+            qid_path: None,
+            xattr: None,
+            watch: Some(inotify),
+            lock_owners: BTreeSet::new(),
+            remove_on_close: false,
+            export: fid.export.clone(),
+            walk_path: fid.walk_path.clone(),
+            last_used: Some(Instant::now()),
+            identity: fid.identity.clone(),
+        };
+        self.fids.insert(watch.newfid, newfid);
+
+        Ok(())
+    }
+
+    fn lease(&mut self, lease: &Tlease) -> io::Result<()> {
+        if !self.extensions.contains(Extensions::LEASE) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
-        .byte_size();
-        let count = min(self.cfg.msize - header_size, readdir.count);
-        let mut cursor = Cursor::new(Vec::with_capacity(count as usize));
 
-        let dir = fid.file.as_mut().ok_or_else(ebadf)?;
-        let mut dirents = read_dir(dir, readdir.offset as libc::c_long)?;
-        while let Some(dirent) = dirents.next().transpose()? {
-            let st = statat(&fid.path, dirent.name.as_c_str(), 0)?;
-
-            let entry = Dirent {
-                qid: st.into(),
-                offset: dirent.offset,
-                ty: dirent.type_,
-                name: dirent.name,
-            };
+        let ty = match lease.ty {
+            LeaseType::Release => None,
+            ty => Some(ty),
+        };
 
-            let byte_size = entry.byte_size() as usize;
+        self.leases.set_lease(self.lease_owner, lease.fid, ty)
+    }
 
-            if cursor.get_ref().capacity() - cursor.get_ref().len() < byte_size {
-                // No more room in the buffer.
-                break;
-            }
+    fn lease_breaks(&mut self) -> io::Result<Rleasebreaks> {
+        if !self.extensions.contains(Extensions::LEASE) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-            entry.encode(&mut cursor)?;
+        // Whatever doesn't fit in the reply is left for the next request.
+        let header_size = Rframe {
+            tag: 0,
+            msg: Rmessage::LeaseBreaks(Rleasebreaks { fids: Vec::new() }),
         }
+        .byte_size();
+        let max = (self.msize - header_size) as usize / mem::size_of::<u32>();
 
-        Ok(Rreaddir {
-            data: Data(cursor.into_inner()),
+        Ok(Rleasebreaks {
+            fids: self.leases.take_breaks(self.lease_owner, max),
         })
     }
 
     fn fsync(&mut self, fsync: &Tfsync) -> io::Result<()> {
-        let file = self
-            .fids
-            .get(&fsync.fid)
-            .and_then(|fid| fid.file.as_ref())
-            .ok_or_else(ebadf)?;
-
-        if fsync.datasync == 0 {
-            file.sync_all()?;
-        } else {
-            file.sync_data()?;
+        self.reopen_files(&[fsync.fid])?;
+        let fid = self.fids.get(fsync.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+        if let Some(e) = fid.write_error.take() {
+            return Err(e);
         }
-        Ok(())
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
+
+        // A dispatcher syncs files on the host without holding up other requests, through a file
+        // of its own like for reads.
+        let datasync = fsync.datasync != 0;
+        if let Some(file) = self.fs.host_file(file).filter(|_| self.offload) {
+            let file = file.try_clone()?;
+            self.offloaded = Some(Box::new(move |_| {
+                let res = if datasync {
+                    file.sync_data()
+                } else {
+                    file.sync_all()
+                };
+                res.map(|()| Rmessage::Fsync)
+            }));
+            return Ok(());
+        }
+
+        self.fs.fsync(file, datasync)
     }
 
-    /// Implement posix byte range locking code.
-    /// Our implementation mirrors that of QEMU/9p - that is to say,
-    /// we essentially punt on mirroring lock state between client/server
-    /// and defer lock semantics to the VFS layer on the client side. Aside
-    /// from fd existence check we always return success. QEMU reference:
-    /// <https://github.com/qemu/qemu/blob/754f756cc4c6d9d14b7230c62b5bb20f9d655888/hw/9pfs/9p.c#L3669>
-    ///
-    /// NOTE: this means that files locked on the client may be interefered with
-    /// from either the server's side, or from other clients (guests). This
-    /// tracks with QEMU implementation, and will be obviated if crosvm decides
-    /// to drop 9p in favor of virtio-fs. QEMU only allows for a single client,
-    /// and we leave it to users of the crate to provide actual lock handling.
+    /// Implements POSIX byte range locks with open file description locks on the host, so that
+    /// they conflict with the locks of other clients and of processes on the host. Each lock
+    /// owner, which is a process of the client identified by its proc_id and client_id, gets an
+    /// open file description of its own for the file, since OFD locks belong to the description
+    /// rather than to a process. Locks are never waited for: a lock that is held by someone else
+    /// gets P9_LOCK_BLOCKED, and the client retries blocking requests itself.
     fn lock(&mut self, lock: &Tlock) -> io::Result<Rlock> {
-        // Ensure fd passed in TLOCK request exists and has a mapping.
-        let fd = self
-            .fids
-            .get(&lock.fid)
-            .and_then(|fid| fid.file.as_ref())
-            .ok_or_else(ebadf)?
-            .as_raw_fd();
+        self.reopen_files(&[lock.fid])?;
+        let fid = self.fids.get(lock.fid).ok_or_else(ebadf)?;
+        let mut fid = lock_fid(&fid);
+        let file = open_host_file(&self.fs, &fid)?;
+
+        let l_type = match lock.type_ {
+            P9_LOCK_TYPE_RDLCK => libc::F_RDLCK,
+            P9_LOCK_TYPE_WRLCK => libc::F_WRLCK,
+            P9_LOCK_TYPE_UNLCK => libc::F_UNLCK,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
 
-        syscall!(unsafe {
-            // Safe because zero-filled libc::stat is a valid value, fstat
-            // populates the struct fields.
-            let mut stbuf: libc::stat64 = std::mem::zeroed();
-            // Safe because this doesn't modify memory and we check the return value.
-            libc::fstat64(fd, &mut stbuf)
-        })?;
+        if lock.flags & P9_LOCK_FLAGS_FLOCK != 0 && self.extensions.contains(Extensions::FLOCK) {
+            // The lock belongs to the open file description, so closing it would drop the lock.
+            self.fd_cache.pin(lock.fid);
+            return flock(file, l_type, lock.start, lock.length).map(|status| Rlock { status });
+        }
 
-        Ok(Rlock {
-            status: P9_LOCK_SUCCESS,
-        })
+        // Like fcntl, only allow read locks on files opened for reading and write locks on files
+        // opened for writing.
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fl = syscall!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) })?;
+        let forbidden = match l_type {
+            libc::F_RDLCK => libc::O_WRONLY,
+            libc::F_WRLCK => libc::O_RDONLY,
+            _ => -1,
+        };
+        if fl & libc::O_ACCMODE == forbidden {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        let owner = lock_owner(&self.fs, &fid, lock.proc_id, &lock.client_id)?;
+        let mut flock = to_flock(l_type, lock.start, lock.length)?;
+
+        let lock_file = match self.locks.entry(owner.clone()) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            // Unlocking a range that was never locked is a no-op.
+            btree_map::Entry::Vacant(_) if l_type == libc::F_UNLCK => {
+                return Ok(Rlock {
+                    status: P9_LOCK_SUCCESS,
+                })
+            }
+            btree_map::Entry::Vacant(entry) => entry.insert(open_lock_file(&self.fs, &fid, fl)?),
+        };
+        let lock_file = self
+            .fs
+            .host_file(lock_file)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOPNOTSUPP))?;
+        fid.lock_owners.insert(owner);
+
+        // Never block the server. The client retries blocking requests when it gets
+        // P9_LOCK_BLOCKED back.
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res =
+            syscall!(unsafe { libc::fcntl(lock_file.as_raw_fd(), libc::F_OFD_SETLK, &mut flock) });
+        let status = match res {
+            Ok(_) => P9_LOCK_SUCCESS,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)) => {
+                P9_LOCK_BLOCKED
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Rlock { status })
     }
 
-    ///
-    /// Much like lock(), defer locking semantics to VFS and return success.
-    ///
     fn get_lock(&mut self, get_lock: &Tgetlock) -> io::Result<Rgetlock> {
-        // Ensure fd passed in GETTLOCK request exists and has a mapping.
-        let fd = self
-            .fids
-            .get(&get_lock.fid)
-            .and_then(|fid| fid.file.as_ref())
-            .ok_or_else(ebadf)?
-            .as_raw_fd();
-
-        // Safe because this doesn't modify memory and we check the return value.
-        syscall!(unsafe {
-            let mut stbuf: libc::stat64 = std::mem::zeroed();
-            libc::fstat64(fd, &mut stbuf)
-        })?;
+        self.reopen_files(&[get_lock.fid])?;
+        let fid = self.fids.get(get_lock.fid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+        let file = open_host_file(&self.fs, &fid)?;
+
+        let l_type = match get_lock.type_ {
+            P9_LOCK_TYPE_RDLCK => libc::F_RDLCK,
+            P9_LOCK_TYPE_WRLCK => libc::F_WRLCK,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let mut flock = to_flock(l_type, get_lock.start, get_lock.length)?;
+
+        // Test against the owner's own open file description, if it has one, so that its own
+        // locks are not reported as conflicts.
+        let owner = lock_owner(&self.fs, &fid, get_lock.proc_id, &get_lock.client_id)?;
+        let lock_file = match self.locks.get(&owner) {
+            Some(f) => MaybeOwned::Borrowed(f),
+            None => {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let fl = syscall!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) })?;
+                MaybeOwned::Owned(open_lock_file(&self.fs, &fid, fl)?)
+            }
+        };
+        let lock_file = self
+            .fs
+            .host_file(&lock_file)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOPNOTSUPP))?;
+
+        // Safe because this only modifies `flock` and we check the return value.
+        syscall!(unsafe { libc::fcntl(lock_file.as_raw_fd(), libc::F_OFD_GETLK, &mut flock) })?;
+
+        if flock.l_type == libc::F_UNLCK as libc::c_short {
+            return Ok(Rgetlock {
+                type_: P9_LOCK_TYPE_UNLCK,
+                start: get_lock.start,
+                length: get_lock.length,
+                proc_id: get_lock.proc_id,
+                client_id: get_lock.client_id.clone(),
+            });
+        }
 
         Ok(Rgetlock {
-            type_: P9_LOCK_TYPE_UNLCK,
-            start: get_lock.start,
-            length: get_lock.length,
-            proc_id: get_lock.proc_id,
-            client_id: get_lock.client_id.clone(),
+            type_: if flock.l_type == libc::F_WRLCK as libc::c_short {
+                P9_LOCK_TYPE_WRLCK
+            } else {
+                P9_LOCK_TYPE_RDLCK
+            },
+            start: flock.l_start as u64,
+            length: flock.l_len as u64,
+            // OFD locks don't belong to any process.
+            proc_id: if flock.l_pid > 0 {
+                flock.l_pid as u32
+            } else {
+                0
+            },
+            client_id: Default::default(),
         })
     }
 
     fn link(&mut self, link: Tlink) -> io::Result<()> {
-        let target = self.fids.get(&link.fid).ok_or_else(ebadf)?;
-        let path = string_to_cstring(format!("self/fd/{}", target.path.as_raw_fd()))?;
-
-        let dir = self.fids.get(&link.dfid).ok_or_else(ebadf)?;
+        let target = self.fids.get(link.fid).ok_or_else(ebadf)?;
+        let dir = self.fids.get(link.dfid).ok_or_else(ebadf)?;
+        let (target, dir) = lock_pair(&target, &dir);
+        let dir = dir.as_deref().unwrap_or(&target);
+        if target.export.root != dir.export.root {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        match self.cfg.links {
+            LinkPolicy::All => {}
+            LinkPolicy::SameDir => {
+                let (parent, _) =
+                    self.fs
+                        .parent(&target.export, &target.path, &target.walk_path)?;
+                let (parent, dir) = (self.fs.stat(&parent)?, self.fs.stat(&dir.path)?);
+                if (parent.st_dev, parent.st_ino) != (dir.st_dev, dir.st_ino) {
+                    return Err(io::Error::from_raw_os_error(libc::EXDEV));
+                }
+            }
+            LinkPolicy::None => return Err(io::Error::from_raw_os_error(libc::EPERM)),
+        }
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        syscall!(unsafe {
-            libc::linkat(
-                self.proc.as_raw_fd(),
-                path.as_ptr(),
-                dir.path.as_raw_fd(),
-                link.name.as_ptr(),
-                libc::AT_SYMLINK_FOLLOW,
-            )
-        })?;
+        let name = names::new_entry_name(&self.fs, &self.cfg, &dir.path, link.name.as_c_str())?;
+        self.fs.link(&target.path, &dir.path, &name)?;
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &dir.path)?;
+        }
         Ok(())
     }
 
     fn mkdir(&mut self, mkdir: Tmkdir) -> io::Result<Rmkdir> {
-        let fid = self.fids.get(&mkdir.dfid).ok_or_else(ebadf)?;
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        syscall!(unsafe { libc::mkdirat(fid.path.as_raw_fd(), mkdir.name.as_ptr(), mkdir.mode) })?;
+        let fid = self.fids.get(mkdir.dfid).ok_or_else(ebadf)?;
+        let fid = lock_fid(&fid);
+
+        let name = names::new_entry_name(&self.fs, &self.cfg, &fid.path, mkdir.name.as_c_str())?;
+        self.quotas.check_create(&fid.export)?;
+        let (uid, gid) = created_owner(&fid.export, mkdir.gid);
+        let mode = fid.export.create_mode(mkdir.mode);
+        let st = self.fs.mkdir(&fid.path, &name, mode, uid, gid)?;
+        self.quotas.created(&fid.export);
+        if fid.export.umask.is_some() {
+            let inode = self.fs.lookup(&fid.path, &name)?;
+            apply_create_mode(&self.fs, &fid.export, &inode, &st, mode)?;
+        }
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &fid.path)?;
+        }
         Ok(Rmkdir {
-            qid: statat(&fid.path, mkdir.name.as_c_str(), 0).map(Qid::from)?,
+            qid: self.qids.qid(&st),
         })
     }
 
     fn rename_at(&mut self, rename_at: Trenameat) -> io::Result<()> {
-        let olddir = self.fids.get(&rename_at.olddirfid).ok_or_else(ebadf)?;
-        let newdir = self.fids.get(&rename_at.newdirfid).ok_or_else(ebadf)?;
+        self.do_rename_at(
+            rename_at.olddirfid,
+            &rename_at.oldname,
+            rename_at.newdirfid,
+            &rename_at.newname,
+            0,
+        )
+    }
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        syscall!(unsafe {
-            libc::renameat(
-                olddir.path.as_raw_fd(),
-                rename_at.oldname.as_ptr(),
-                newdir.path.as_raw_fd(),
-                rename_at.newname.as_ptr(),
-            )
-        })?;
+    fn rename_at2(&mut self, rename_at2: &Trenameat2) -> io::Result<()> {
+        if !self.extensions.contains(Extensions::RENAMEAT2) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
+        // RENAME_WHITEOUT is left out since it creates a device file, which clients can't do
+        // otherwise.
+        let flags = match rename_at2.flags {
+            0 => 0,
+            P9_RENAME_NOREPLACE => libc::RENAME_NOREPLACE,
+            P9_RENAME_EXCHANGE => libc::RENAME_EXCHANGE,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        self.do_rename_at(
+            rename_at2.olddirfid,
+            &rename_at2.oldname,
+            rename_at2.newdirfid,
+            &rename_at2.newname,
+            flags,
+        )
+    }
+
+    // Renames `oldname` in the directory of `olddirfid` to `newname` in that of `newdirfid`, with
+    // the renameat2 `flags`.
+    fn do_rename_at(
+        &mut self,
+        olddirfid: u32,
+        oldname: &P9String,
+        newdirfid: u32,
+        newname: &P9String,
+        flags: libc::c_uint,
+    ) -> io::Result<()> {
+        let olddir = self.fids.get(olddirfid).ok_or_else(ebadf)?;
+        let newdir = self.fids.get(newdirfid).ok_or_else(ebadf)?;
+        let (olddir, newdir) = lock_pair(&olddir, &newdir);
+        let newdir = newdir.as_deref().unwrap_or(&olddir);
+        if olddir.export.root != newdir.export.root {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        let oldname = names::entry_name(&self.fs, &self.cfg, &olddir.path, oldname.as_c_str())?;
+        let newname = names::rename_target(
+            &self.fs,
+            &self.cfg,
+            &olddir.path,
+            &oldname,
+            &newdir.path,
+            newname.as_c_str(),
+        )?;
+        // Renaming a file over another link to itself leaves both in place, and so does swapping
+        // two files.
+        let replaced = self
+            .quota_entry(&newdir.export, &newdir.path, &newname)
+            .filter(|new| {
+                flags == 0
+                    && self
+                        .quota_entry(&olddir.export, &olddir.path, &oldname)
+                        .is_none_or(|old| (old.st_dev, old.st_ino) != (new.st_dev, new.st_ino))
+            });
+
+        self.fs
+            .rename2(&olddir.path, &oldname, &newdir.path, &newname, flags)?;
+        if let Some(st) = replaced {
+            self.quotas.removed(&newdir.export, &st);
+        }
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &olddir.path)?;
+            if newdirfid != olddirfid {
+                sync_dir(&self.fs, &newdir.path)?;
+            }
+        }
         Ok(())
     }
 
     fn unlink_at(&mut self, unlink_at: Tunlinkat) -> io::Result<()> {
-        let dir = self.fids.get(&unlink_at.dirfd).ok_or_else(ebadf)?;
-
-        syscall!(unsafe {
-            libc::unlinkat(
-                dir.path.as_raw_fd(),
-                unlink_at.name.as_ptr(),
-                unlink_at.flags as libc::c_int,
-            )
-        })?;
+        let dir = self.fids.get(unlink_at.dirfd).ok_or_else(ebadf)?;
+        let dir = lock_fid(&dir);
+        if self.cfg.strict_unlink {
+            check_unlink(&self.fs, &self.cfg, &dir.path, &unlink_at)?;
+        }
+        let name = names::entry_name(&self.fs, &self.cfg, &dir.path, unlink_at.name.as_c_str())?;
+        let removed = self.quota_entry(&dir.export, &dir.path, &name);
 
+        self.fs
+            .unlink(&dir.path, &name, unlink_at.flags as libc::c_int)?;
+        if let Some(st) = removed {
+            self.quotas.removed(&dir.export, &st);
+        }
+        if self.cfg.sync_dirs {
+            sync_dir(&self.fs, &dir.path)?;
+        }
         Ok(())
     }
 }
 
+impl<F: FileSystem> Drop for Server<F> {
+    fn drop(&mut self) {
+        self.flush_writes();
+        self.leases.close_all(self.lease_owner);
+
+        // Keep the fids around in case the client reconnects. The server that it reconnects to
+        // doesn't know which files were closed to make room for others, so they are reopened.
+        if self.session.is_some() && self.sessions.is_some() {
+            for fid in self.fids.fids() {
+                let _ = self.reopen_file(fid);
+            }
+        }
+        if let (Some(session), Some(sessions)) = (self.session, self.sessions.as_ref()) {
+            sessions.save(
+                session,
+                mem::take(&mut self.fids),
+                mem::take(&mut self.locks),
+            );
+        }
+        self.remove_on_close();
+    }
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,165 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Names that are made to refer to the same file even though they differ: ones that only differ
+//! in ASCII case with the `ascii_casefold` option, as they would on Windows or macOS, and
+//! different Unicode normal forms of the same name with `normalize_names`. Existing files keep
+//! the names that they have, and those are the ones that directory listings show.
+
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::io;
+
+use super::Config;
+use super::FileSystem;
+use super::NormalForm;
+use super::P9_DIRECTORY;
+use super::P9_RDONLY;
+
+impl Config {
+    fn folds_names(&self) -> bool {
+        self.ascii_casefold || self.normalize_names.is_some()
+    }
+
+    // Returns `name` folded so that it is the same as all the names that refer to the same file,
+    // for comparing it with them or matching it against patterns folded the same way.
+    pub(super) fn fold_name<'a>(&self, name: &'a [u8]) -> Cow<'a, [u8]> {
+        let name = match self.normalize_names {
+            Some(_) => NormalForm::Nfd.normalize(name),
+            None => Cow::Borrowed(name),
+        };
+        if self.ascii_casefold && name.iter().any(u8::is_ascii_uppercase) {
+            Cow::Owned(name.to_ascii_lowercase())
+        } else {
+            name
+        }
+    }
+
+    // Returns the name to give a new file that a client wants to call `name`.
+    fn new_name<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+        match self
+            .normalize_names
+            .map(|form| form.normalize(name.to_bytes()))
+        {
+            Some(Cow::Owned(normalized)) => {
+                CString::new(normalized).map_or(Cow::Borrowed(name), Cow::Owned)
+            }
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+// Returns the name of the entry of `dir` that `name` refers to, which is `name` itself if there
+// is an entry with exactly that name, or None if there is no such entry at all. Names are taken
+// to refer to the entry with exactly that name when they aren't folded.
+fn find_entry<'a, F: FileSystem>(
+    fs: &F,
+    cfg: &Config,
+    dir: &F::Inode,
+    name: &'a CStr,
+) -> io::Result<Option<Cow<'a, CStr>>> {
+    // Without folding, names can only refer to the entry with exactly that name, so there is no
+    // need to look.
+    if !cfg.folds_names() || matches!(name.to_bytes(), b"" | b"." | b"..") {
+        return Ok(Some(Cow::Borrowed(name)));
+    }
+    // Errors other than ENOENT are left for whatever the name is used for to run into.
+    let exists = |name: &CStr| match fs.lookup(dir, name) {
+        Err(e) => e.raw_os_error() != Some(libc::ENOENT),
+        Ok(_) => true,
+    };
+    if exists(name) {
+        return Ok(Some(Cow::Borrowed(name)));
+    }
+
+    // New files get their names in normal form, so that is the most likely one to exist.
+    let normalized = cfg.new_name(name);
+    if *normalized != *name && exists(&normalized) {
+        return Ok(Some(normalized));
+    }
+
+    // Directories that can't be listed can only be searched for exact names.
+    let mut handle = match fs.open(dir, P9_RDONLY | P9_DIRECTORY) {
+        Ok(handle) => handle,
+        Err(_) => return Ok(None),
+    };
+    let folded = cfg.fold_name(name.to_bytes());
+    let mut found = None;
+    fs.readdir(dir, &mut handle, 0, &mut |entry, _| {
+        if cfg.fold_name(entry.name.as_bytes()) == folded {
+            found = Some(entry.name.as_c_str().to_owned());
+            return Ok(false);
+        }
+        Ok(true)
+    })?;
+    Ok(found.map(Cow::Owned))
+}
+
+// Returns the name of the entry of `dir` that `name` refers to, or `name` itself if there is no
+// such entry.
+pub(super) fn entry_name<'a, F: FileSystem>(
+    fs: &F,
+    cfg: &Config,
+    dir: &F::Inode,
+    name: &'a CStr,
+) -> io::Result<Cow<'a, CStr>> {
+    Ok(find_entry(fs, cfg, dir, name)?.unwrap_or(Cow::Borrowed(name)))
+}
+
+// Looks up the entry of `dir` that `name` refers to.
+pub(super) fn lookup<F: FileSystem>(
+    fs: &F,
+    cfg: &Config,
+    dir: &F::Inode,
+    name: &CStr,
+) -> io::Result<F::Inode> {
+    match fs.lookup(dir, name) {
+        Err(e) if cfg.folds_names() && e.raw_os_error() == Some(libc::ENOENT) => {
+            fs.lookup(dir, &entry_name(fs, cfg, dir, name)?)
+        }
+        result => result,
+    }
+}
+
+// Returns the name to create a new file that a client wants to call `name` in `dir` under. Fails
+// with EEXIST if `name` refers to an existing entry by another name, which the new file would be
+// indistinguishable from.
+pub(super) fn new_entry_name<'a, F: FileSystem>(
+    fs: &F,
+    cfg: &Config,
+    dir: &F::Inode,
+    name: &'a CStr,
+) -> io::Result<Cow<'a, CStr>> {
+    match find_entry(fs, cfg, dir, name)? {
+        Some(existing) if *existing != *name => Err(io::Error::from_raw_os_error(libc::EEXIST)),
+        Some(_) => Ok(Cow::Borrowed(name)),
+        None => Ok(cfg.new_name(name)),
+    }
+}
+
+// Returns the name to give the file `oldname` in `olddir` when renaming it to `newname` in
+// `newdir`. An entry that `newname` refers to by another name is replaced like one with exactly
+// that name would be, unless it is the file itself, which is then just renamed to `newname`.
+pub(super) fn rename_target<'a, F: FileSystem>(
+    fs: &F,
+    cfg: &Config,
+    olddir: &F::Inode,
+    oldname: &CStr,
+    newdir: &F::Inode,
+    newname: &'a CStr,
+) -> io::Result<Cow<'a, CStr>> {
+    match find_entry(fs, cfg, newdir, newname)? {
+        Some(target) if *target == *oldname && *target != *newname => {
+            let (old, new) = (fs.stat(olddir)?, fs.stat(newdir)?);
+            if (old.st_dev, old.st_ino) == (new.st_dev, new.st_ino) {
+                Ok(cfg.new_name(newname))
+            } else {
+                Ok(target)
+            }
+        }
+        Some(target) => Ok(target),
+        None => Ok(cfg.new_name(newname)),
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+// How many names a cache holds at most.
+const MAX_ENTRIES: usize = 4096;
+
+// The names that Twalk recently found not to be in a directory, by the path of the qid of the
+// directory, so that looking for them again right away doesn't have to go to the host. Creating
+// or renaming a file in a directory through the server drops the names of that directory, and
+// files created on the host show up once the names are `ttl` old.
+pub(super) struct NegativeCache {
+    ttl: Duration,
+    entries: BTreeMap<(u64, Vec<u8>), Instant>,
+    // The entries in the order that they were added, oldest first, which is also the order
+    // that they expire in. Entries that were dropped or replaced since are still in it.
+    order: VecDeque<(Instant, u64, Vec<u8>)>,
+    hits: u64,
+}
+
+impl NegativeCache {
+    pub(super) fn new(ttl: Duration) -> NegativeCache {
+        NegativeCache {
+            ttl,
+            entries: BTreeMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+        }
+    }
+
+    // Returns true if `name` was recently found not to be in the directory with qid path `dir`
+    // and that hasn't expired by `now`.
+    pub(super) fn contains(&mut self, dir: u64, name: &[u8], now: Instant) -> bool {
+        let hit = self
+            .entries
+            .get(&(dir, name.to_vec()))
+            .is_some_and(|&added| now.duration_since(added) < self.ttl);
+        if hit {
+            self.hits += 1;
+        }
+        hit
+    }
+
+    // Records that `name` isn't in the directory with qid path `dir` as of `now`.
+    pub(super) fn insert(&mut self, dir: u64, name: &[u8], now: Instant) {
+        self.entries.insert((dir, name.to_vec()), now);
+        self.order.push_back((now, dir, name.to_vec()));
+
+        while let Some(&(added, _, _)) = self.order.front() {
+            if self.entries.len() <= MAX_ENTRIES && now.duration_since(added) < self.ttl {
+                break;
+            }
+            let (added, dir, name) = self.order.pop_front().unwrap();
+            let key = (dir, name);
+            if self.entries.get(&key) == Some(&added) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    // Drops the names of the directory with qid path `dir`.
+    pub(super) fn forget_dir(&mut self, dir: u64) {
+        let names: Vec<_> = self
+            .entries
+            .range((dir, Vec::new())..)
+            .take_while(|((d, _), _)| *d == dir)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in names {
+            self.entries.remove(&key);
+        }
+    }
+
+    // Drops the names of every directory.
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    // Returns how many walks were answered from the cache.
+    pub(super) fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let ttl = Duration::from_secs(1);
+        let mut cache = NegativeCache::new(ttl);
+        let now = Instant::now();
+
+        cache.insert(1, b"foo.h", now);
+        cache.insert(1, b"bar.h", now);
+        cache.insert(2, b"foo.h", now);
+        assert!(cache.contains(1, b"foo.h", now));
+        assert!(!cache.contains(1, b"baz.h", now));
+        assert!(!cache.contains(1, b"foo.h", now + ttl));
+        assert_eq!(cache.hits(), 1);
+
+        cache.forget_dir(1);
+        assert!(!cache.contains(1, b"foo.h", now));
+        assert!(!cache.contains(1, b"bar.h", now));
+        assert!(cache.contains(2, b"foo.h", now));
+
+        // The oldest names make room for new ones.
+        for i in 0..MAX_ENTRIES + 10 {
+            cache.insert(3, i.to_string().as_bytes(), now);
+        }
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(!cache.contains(2, b"foo.h", now));
+        assert!(cache.contains(3, b"4105", now));
+
+        cache.clear();
+        assert!(!cache.contains(3, b"4105", now));
+    }
+}
@@ -0,0 +1,910 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use super::passthrough::host_path;
+use super::passthrough::lookup;
+use super::passthrough::open_fid;
+use super::passthrough::readlinkat;
+use super::passthrough::stat;
+use super::passthrough::statat;
+use super::passthrough::Resolve;
+use super::read_dir::sort_by_cookie;
+use super::*;
+use crate::protocol::*;
+use crate::syscall;
+
+// Names in the upper directory that start with this are kept for the overlay itself and never
+// shown to clients. `.wh.<name>` hides `<name>` in the lower directories, like an aufs whiteout.
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+// Marks a directory in the upper directory as opaque, which hides everything in the lower
+// directories of the same name.
+const OPAQUE: &CStr = c".wh..wh..opq";
+
+// Files are copied up under a hidden name first and only then moved into place, so that a copy
+// that was cut short never shows up as the real file.
+const COPY_UP_PREFIX: &str = ".wh..wh.copyup.";
+
+fn err(errno: libc::c_int) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn is_dir(st: &libc::stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn to_cstring(name: &[u8]) -> io::Result<CString> {
+    CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn whiteout_name(name: &CStr) -> io::Result<CString> {
+    to_cstring(&[WHITEOUT_PREFIX, name.to_bytes()].concat())
+}
+
+fn try_clone_all(files: &[File]) -> io::Result<Vec<File>> {
+    files.iter().map(File::try_clone).collect()
+}
+
+// Returns whether `name` exists in `dir`, without following symlinks.
+fn exists(dir: &File, name: &CStr) -> io::Result<bool> {
+    match statat(dir, name, 0) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Like `lookup` but returns `None` if there is no `name` in `dir`.
+fn lookup_opt(resolve: Resolve, dir: &File, name: &CStr) -> io::Result<Option<File>> {
+    match lookup(resolve, dir, name) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Like `lookup_opt` but for the path `path` beneath `dir`, which is walked a name at a time.
+fn lookup_path_opt(resolve: Resolve, dir: &File, path: &Path) -> io::Result<Option<File>> {
+    let mut file = dir.try_clone()?;
+    for component in path.components() {
+        let name = to_cstring(component.as_os_str().as_bytes())?;
+        file = match lookup_opt(resolve, &file, &name)? {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(file))
+}
+
+// Creates the empty file `name` in `dir`, which is how whiteouts and opaque markers are made.
+fn create_marker(dir: &File, name: &CStr) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = syscall!(unsafe {
+        libc::openat64(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC | libc::O_NOFOLLOW,
+            0o600,
+        )
+    })?;
+
+    // Safe because we just opened this fd.
+    drop(unsafe { File::from_raw_fd(fd) });
+    Ok(())
+}
+
+// Removes the whiteout for `name` from `dir`, if there is one.
+fn remove_whiteout(dir: &File, name: &CStr) -> io::Result<()> {
+    let whiteout = whiteout_name(name)?;
+    // Safe because this doesn't modify any memory and we check the return value.
+    match syscall!(unsafe { libc::unlinkat(dir.as_raw_fd(), whiteout.as_ptr(), 0) }) {
+        Ok(_) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// The directories that an export is made of.
+struct Layers {
+    upper: File,
+    lowers: Vec<File>,
+    // Where `upper` is on the host, to find where files in there are relative to it.
+    upper_path: PathBuf,
+}
+
+/// Serves read-only lower directories on the host with the changes that clients make kept in a
+/// separate upper directory, like overlayfs but without needing any privileges. The root of each
+/// export is its upper directory, on top of the lower directories that the file system was
+/// created with.
+///
+/// Files are looked up in the upper directory first and then in each of the lower ones in turn,
+/// directories are merged, and files are copied up into the upper directory the first time they
+/// are changed. Removed files are hidden with aufs-style `.wh.<name>` whiteouts, which clients
+/// never see. Directories that exist in a lower directory can't be renamed, which fails with
+/// EXDEV as it does on overlayfs so that clients fall back to copying them.
+pub struct Overlay {
+    passthrough: Passthrough,
+    lowers: Vec<File>,
+}
+
+/// A file in an `Overlay`, in the upper directory, a lower one or, for directories, several.
+pub struct OverlayInode {
+    // Filled in once the file is copied up.
+    upper: OnceLock<File>,
+    // The file in each lower directory that it is found in, in order. Only directories have more
+    // than one.
+    lowers: Vec<File>,
+    // Where the file is relative to the roots of the layers. Only files that have been copied up
+    // can be moved, and their path comes from `upper` instead, so this never goes stale.
+    path: PathBuf,
+    layers: Arc<Layers>,
+}
+
+/// A file in an `Overlay` that a client has opened.
+pub struct OverlayHandle {
+    file: File,
+    // Whether `file` is in the upper directory.
+    upper: bool,
+    // The merged entries of a directory in the order of their cookies, as of the last read from
+    // its start.
+    entries: Vec<(u64, P9String, (u8, libc::stat64))>,
+}
+
+impl Overlay {
+    /// Creates a file system that serves `lower` underneath the root of every export, with the
+    /// passthrough options of `cfg`.
+    pub fn new<P: AsRef<Path>>(lower: P, cfg: &Config) -> io::Result<Overlay> {
+        Overlay::with_layers([lower], cfg)
+    }
+
+    /// Creates a file system that serves the union of `lowers` underneath the root of every
+    /// export, which is where everything that clients write goes. Earlier directories take
+    /// priority over later ones.
+    pub fn with_layers<I, P>(lowers: I, cfg: &Config) -> io::Result<Overlay>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let passthrough = Passthrough::new(cfg)?;
+        let lowers = lowers
+            .into_iter()
+            .map(|lower| passthrough.root(&Export::new(lower.as_ref())))
+            .collect::<io::Result<_>>()?;
+        Ok(Overlay {
+            passthrough,
+            lowers,
+        })
+    }
+
+    // Returns the upper file of `inode`, if there is one. Another fid may have copied the file
+    // up since `inode` was looked up, so that is checked for again if it hasn't been found yet.
+    fn upper<'a>(&self, inode: &'a OverlayInode) -> io::Result<Option<&'a File>> {
+        if let Some(upper) = inode.upper.get() {
+            return Ok(Some(upper));
+        }
+
+        let resolve = self.passthrough.resolve;
+        match lookup_path_opt(resolve, &inode.layers.upper, &inode.path)? {
+            Some(upper) => Ok(Some(inode.upper.get_or_init(|| upper))),
+            None => Ok(None),
+        }
+    }
+
+    // Returns the file that `inode` is served from.
+    fn top<'a>(&self, inode: &'a OverlayInode) -> io::Result<&'a File> {
+        match self.upper(inode)? {
+            Some(upper) => Ok(upper),
+            None => inode.lowers.first().ok_or_else(|| err(libc::ESTALE)),
+        }
+    }
+
+    // Returns where `inode` is relative to the roots of the layers.
+    fn path(&self, inode: &OverlayInode) -> io::Result<PathBuf> {
+        let upper = match inode.upper.get() {
+            Some(upper) => upper,
+            None => return Ok(inode.path.clone()),
+        };
+        if stat(upper)?.st_nlink == 0 {
+            return Err(err(libc::ENOENT));
+        }
+
+        let path = host_path(&self.passthrough.proc, upper)?;
+        path.strip_prefix(&inode.layers.upper_path)
+            .map(Path::to_path_buf)
+            .map_err(|_| err(libc::EBUSY))
+    }
+
+    fn layer_root(&self, layers: &Arc<Layers>) -> io::Result<OverlayInode> {
+        Ok(OverlayInode {
+            upper: OnceLock::from(layers.upper.try_clone()?),
+            lowers: try_clone_all(&layers.lowers)?,
+            path: PathBuf::new(),
+            layers: layers.clone(),
+        })
+    }
+
+    // Walks from the root of `layers` to `path`.
+    fn walk_path(&self, layers: &Arc<Layers>, path: &Path) -> io::Result<OverlayInode> {
+        let mut inode = self.layer_root(layers)?;
+        for component in path.components() {
+            let name = to_cstring(component.as_os_str().as_bytes())?;
+            inode = self.child(&inode, &name)?;
+        }
+        Ok(inode)
+    }
+
+    // Returns whether `name` in the lower directories of `dir` shows through its upper one.
+    fn lower_visible(&self, dir: &OverlayInode, name: &CStr) -> io::Result<bool> {
+        if dir.lowers.is_empty() {
+            return Ok(false);
+        }
+        match self.upper(dir)? {
+            Some(upper) => Ok(!exists(upper, OPAQUE)? && !exists(upper, &whiteout_name(name)?)?),
+            None => Ok(true),
+        }
+    }
+
+    // Looks up the entry `name` of `dir` in both layers.
+    fn child(&self, dir: &OverlayInode, name: &CStr) -> io::Result<OverlayInode> {
+        if name.to_bytes().starts_with(WHITEOUT_PREFIX) {
+            return Err(err(libc::ENOENT));
+        }
+
+        let upper = match self.upper(dir)? {
+            Some(upper_dir) => match self.passthrough.lookup(upper_dir, name) {
+                Ok(upper) => Some(upper),
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+
+        // Only directories are merged. Anything else hides whatever is below it.
+        let mut merge = match upper {
+            Some(ref upper) => is_dir(&stat(upper)?),
+            None => true,
+        };
+        let mut lowers = Vec::new();
+        if merge && self.lower_visible(dir, name)? {
+            for lower_dir in &dir.lowers {
+                let lower = match self.lookup_layer(lower_dir, name)? {
+                    Some(lower) => lower,
+                    None => continue,
+                };
+                let lower_is_dir = is_dir(&stat(&lower)?);
+                if upper.is_none() && lowers.is_empty() {
+                    merge = lower_is_dir;
+                } else if !lower_is_dir {
+                    break;
+                }
+                lowers.push(lower);
+                if !merge {
+                    break;
+                }
+            }
+        }
+        if upper.is_none() && lowers.is_empty() {
+            return Err(err(libc::ENOENT));
+        }
+
+        Ok(OverlayInode {
+            upper: upper.map(OnceLock::from).unwrap_or_default(),
+            lowers,
+            path: self.path(dir)?.join(OsStr::from_bytes(name.to_bytes())),
+            layers: dir.layers.clone(),
+        })
+    }
+
+    // Makes sure that `name` can be created in `dir`.
+    fn check_new(&self, dir: &OverlayInode, name: &CStr) -> io::Result<()> {
+        if name.to_bytes().starts_with(WHITEOUT_PREFIX) {
+            return Err(err(libc::EINVAL));
+        }
+        match self.child(dir, name) {
+            Ok(_) => Err(err(libc::EEXIST)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Returns the entries of `dir` as clients see them, with the upper ones first.
+    fn merged_entries(&self, dir: &OverlayInode) -> io::Result<Vec<(P9String, u8, libc::stat64)>> {
+        let mut entries = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut opaque = false;
+
+        let mut layers = Vec::new();
+        if let Some(upper) = self.upper(dir)? {
+            layers.push(upper);
+        }
+        layers.extend(&dir.lowers);
+
+        for layer in layers {
+            if opaque {
+                break;
+            }
+            let mut file = open_fid(&self.passthrough.proc, layer, P9_DIRECTORY | P9_RDONLY)?;
+            self.passthrough
+                .readdir(layer, &mut file, 0, &mut |dirent, st| {
+                    let name = dirent.name.as_bytes();
+                    if name == OPAQUE.to_bytes() {
+                        opaque = true;
+                    } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                        // Whiteouts only ever hide what is below them.
+                        seen.insert(hidden.to_vec());
+                    } else if seen.insert(name.to_vec()) {
+                        entries.push((dirent.name, dirent.type_, *st));
+                    }
+                    Ok(true)
+                })?;
+        }
+
+        Ok(entries)
+    }
+
+    // Fails unless the directory `dir` looks empty to clients, and then removes the whiteouts
+    // from its upper directory so that the host will let it be removed.
+    fn clear_empty_dir(&self, dir: &OverlayInode) -> io::Result<()> {
+        if self
+            .merged_entries(dir)?
+            .iter()
+            .any(|(name, _, _)| *name != "." && *name != "..")
+        {
+            return Err(err(libc::ENOTEMPTY));
+        }
+
+        let upper = match self.upper(dir)? {
+            Some(upper) => upper,
+            None => return Ok(()),
+        };
+        let mut file = open_fid(&self.passthrough.proc, upper, P9_DIRECTORY | P9_RDONLY)?;
+        let mut markers = Vec::new();
+        self.passthrough
+            .readdir(upper, &mut file, 0, &mut |dirent, _| {
+                if dirent.name.as_bytes().starts_with(WHITEOUT_PREFIX) {
+                    markers.push(dirent.name);
+                }
+                Ok(true)
+            })?;
+        for marker in markers {
+            self.passthrough.unlink(upper, marker.as_c_str(), 0)?;
+        }
+        Ok(())
+    }
+
+    // Copies `inode` into the upper directory, along with any of its parents that aren't there
+    // yet, and returns the copy.
+    fn copy_up<'a>(&self, inode: &'a OverlayInode) -> io::Result<&'a File> {
+        if let Some(upper) = self.upper(inode)? {
+            return Ok(upper);
+        }
+        let lower = inode.lowers.first().ok_or_else(|| err(libc::ESTALE))?;
+        let name = match inode.path.file_name() {
+            Some(name) => to_cstring(name.as_bytes())?,
+            None => return Err(err(libc::ESTALE)),
+        };
+
+        // Each parent is copied from the first lower directory that has it.
+        let resolve = self.passthrough.resolve;
+        let mut upper_dir = inode.layers.upper.try_clone()?;
+        let mut lower_dirs = try_clone_all(&inode.layers.lowers)?;
+        for component in inode.path.parent().into_iter().flat_map(Path::components) {
+            let component = to_cstring(component.as_os_str().as_bytes())?;
+            lower_dirs = lower_dirs
+                .iter()
+                .filter_map(|dir| lookup_opt(resolve, dir, &component).transpose())
+                .collect::<io::Result<_>>()?;
+            let lower = lower_dirs.first().ok_or_else(|| err(libc::ENOENT))?;
+            upper_dir = match lookup_opt(resolve, &upper_dir, &component)? {
+                Some(upper) => upper,
+                None => {
+                    self.copy_file(&upper_dir, &component, lower)?;
+                    lookup(resolve, &upper_dir, &component)?
+                }
+            };
+        }
+
+        self.copy_file(&upper_dir, &name, lower)?;
+        let upper = lookup(resolve, &upper_dir, &name)?;
+        Ok(inode.upper.get_or_init(|| upper))
+    }
+
+    // Copies the single file `lower` to `name` in the upper directory `dir`, keeping its mode,
+    // owner and times. Directories are copied without their contents.
+    fn copy_file(&self, dir: &File, name: &CStr, lower: &File) -> io::Result<()> {
+        let st = stat(lower)?;
+        let tmp = to_cstring(format!("{COPY_UP_PREFIX}{}", st.st_ino).as_bytes())?;
+        let mode = st.st_mode & 0o7777;
+        let unlink_flags = if is_dir(&st) { libc::AT_REMOVEDIR } else { 0 };
+
+        // Safe because none of these modify any memory and we check the return values.
+        match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => {
+                syscall!(unsafe { libc::mkdirat(dir.as_raw_fd(), tmp.as_ptr(), mode) })?;
+            }
+            libc::S_IFREG => {
+                let fd = syscall!(unsafe {
+                    libc::openat64(
+                        dir.as_raw_fd(),
+                        tmp.as_ptr(),
+                        libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY | libc::O_CLOEXEC,
+                        mode,
+                    )
+                })?;
+                // Safe because we just opened this fd.
+                let mut copy = unsafe { File::from_raw_fd(fd) };
+                let mut src = open_fid(&self.passthrough.proc, lower, P9_RDONLY)?;
+                if let Err(e) = io::copy(&mut src, &mut copy) {
+                    // Safe because this doesn't modify any memory. There is nothing more to do
+                    // if it fails.
+                    unsafe { libc::unlinkat(dir.as_raw_fd(), tmp.as_ptr(), 0) };
+                    return Err(e);
+                }
+            }
+            libc::S_IFLNK => {
+                let target = to_cstring(&readlinkat(lower, c"")?)?;
+                syscall!(unsafe {
+                    libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), tmp.as_ptr())
+                })?;
+            }
+            _ => {
+                syscall!(unsafe {
+                    libc::mknodat(dir.as_raw_fd(), tmp.as_ptr(), st.st_mode, st.st_rdev)
+                })?;
+            }
+        }
+
+        let times = [
+            libc::timespec {
+                tv_sec: st.st_atime,
+                tv_nsec: st.st_atime_nsec as _,
+            },
+            libc::timespec {
+                tv_sec: st.st_mtime,
+                tv_nsec: st.st_mtime_nsec as _,
+            },
+        ];
+        // Safe because this doesn't modify any memory and we check the return values.
+        let res = syscall!(unsafe {
+            libc::fchownat(
+                dir.as_raw_fd(),
+                tmp.as_ptr(),
+                st.st_uid,
+                st.st_gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })
+        .and_then(|_| {
+            syscall!(unsafe {
+                libc::utimensat(
+                    dir.as_raw_fd(),
+                    tmp.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })
+        })
+        .and_then(|_| {
+            syscall!(unsafe {
+                libc::syscall(
+                    libc::SYS_renameat2,
+                    dir.as_raw_fd(),
+                    tmp.as_ptr(),
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    libc::RENAME_NOREPLACE,
+                )
+            })
+        });
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // Safe because this doesn't modify any memory. There is nothing more to do if it
+                // fails.
+                unsafe { libc::unlinkat(dir.as_raw_fd(), tmp.as_ptr(), unlink_flags) };
+                // Another fid copied the same file up in the meantime.
+                if e.raw_os_error() == Some(libc::EEXIST) {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    // Returns whether any lower directory of `dir` has an entry called `name`, which has to be
+    // hidden behind a whiteout once `name` is gone from the upper directory.
+    fn lower_has(&self, dir: &OverlayInode, name: &CStr) -> io::Result<bool> {
+        for lower in &dir.lowers {
+            if exists(lower, name)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Looks up `name` in the single layer `dir`, with the passthrough options.
+    fn lookup_layer(&self, dir: &File, name: &CStr) -> io::Result<Option<File>> {
+        match self.passthrough.lookup(dir, name) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn upper_handle<'a>(&self, handle: Option<&'a OverlayHandle>) -> Option<&'a File> {
+        handle
+            .filter(|handle| handle.upper)
+            .map(|handle| &handle.file)
+    }
+}
+
+impl FileSystem for Overlay {
+    type Inode = OverlayInode;
+    type Handle = OverlayHandle;
+
+    fn root(&self, export: &Export) -> io::Result<OverlayInode> {
+        let layers = Arc::new(Layers {
+            upper: self.passthrough.root(export)?,
+            lowers: try_clone_all(&self.lowers)?,
+            upper_path: export.root.canonicalize()?,
+        });
+        self.layer_root(&layers)
+    }
+
+    fn lookup(&self, parent: &OverlayInode, name: &CStr) -> io::Result<OverlayInode> {
+        match name.to_bytes() {
+            b"." => self.try_clone(parent),
+            // Nothing can be reached above the root.
+            b".." => {
+                let path = self.path(parent)?;
+                self.walk_path(&parent.layers, path.parent().unwrap_or(&path))
+            }
+            _ => self.child(parent, name),
+        }
+    }
+
+    fn try_clone(&self, inode: &OverlayInode) -> io::Result<OverlayInode> {
+        Ok(OverlayInode {
+            upper: match inode.upper.get() {
+                Some(upper) => OnceLock::from(upper.try_clone()?),
+                None => OnceLock::new(),
+            },
+            lowers: try_clone_all(&inode.lowers)?,
+            path: inode.path.clone(),
+            layers: inode.layers.clone(),
+        })
+    }
+
+    fn stat(&self, inode: &OverlayInode) -> io::Result<libc::stat64> {
+        stat(self.top(inode)?)
+    }
+
+    fn getattr(
+        &self,
+        inode: &OverlayInode,
+        handle: Option<&OverlayHandle>,
+        request_mask: u64,
+    ) -> io::Result<Rgetattr> {
+        let file = handle.map(|handle| &handle.file);
+        self.passthrough
+            .getattr(self.top(inode)?, file, request_mask)
+    }
+
+    fn setattr(
+        &self,
+        inode: &OverlayInode,
+        handle: Option<&OverlayHandle>,
+        attr: &Tsetattr,
+    ) -> io::Result<()> {
+        let upper = self.copy_up(inode)?;
+        self.passthrough
+            .setattr(upper, self.upper_handle(handle), attr)
+    }
+
+    fn open(&self, inode: &OverlayInode, flags: u32) -> io::Result<OverlayHandle> {
+        let file = if open_modifies(flags) {
+            self.copy_up(inode)?
+        } else {
+            self.top(inode)?
+        };
+        Ok(OverlayHandle {
+            file: self.passthrough.open(file, flags)?,
+            upper: inode.upper.get().is_some(),
+            entries: Vec::new(),
+        })
+    }
+
+    fn create(
+        &self,
+        dir: &OverlayInode,
+        name: &CStr,
+        flags: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<(OverlayInode, OverlayHandle)> {
+        self.check_new(dir, name)?;
+        let upper_dir = self.copy_up(dir)?;
+        let (upper, file) = self
+            .passthrough
+            .create(upper_dir, name, flags, mode, uid, gid)?;
+        remove_whiteout(upper_dir, name)?;
+
+        let inode = OverlayInode {
+            upper: OnceLock::from(upper),
+            lowers: Vec::new(),
+            path: self.path(dir)?.join(OsStr::from_bytes(name.to_bytes())),
+            layers: dir.layers.clone(),
+        };
+        let handle = OverlayHandle {
+            file,
+            upper: true,
+            entries: Vec::new(),
+        };
+        Ok((inode, handle))
+    }
+
+    fn read(&self, handle: &OverlayHandle, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.passthrough.read(&handle.file, buf, offset)
+    }
+
+    fn write(&self, handle: &OverlayHandle, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.passthrough.write(&handle.file, buf, offset)
+    }
+
+    fn readdir(
+        &self,
+        dir: &OverlayInode,
+        handle: &mut OverlayHandle,
+        offset: u64,
+        entry: &mut dyn FnMut(DirEntry, &libc::stat64) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        // The merged list is read again whenever a client starts over, or carries on after the
+        // directory was opened again, and the offset of each entry is its cookie, which doesn't
+        // depend on where the entry is in the list.
+        if offset == 0 || handle.entries.is_empty() {
+            let entries = self
+                .merged_entries(dir)?
+                .into_iter()
+                .map(|(name, type_, st)| (name, (type_, st)));
+            handle.entries = sort_by_cookie(entries.collect());
+        }
+
+        let start = handle
+            .entries
+            .partition_point(|&(cookie, _, _)| cookie <= offset);
+        for (cookie, name, (type_, st)) in &handle.entries[start..] {
+            let dirent = DirEntry {
+                offset: *cookie,
+                type_: *type_,
+                name: name.clone(),
+            };
+            if !entry(dirent, st)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn readlink(&self, inode: &OverlayInode) -> io::Result<Vec<u8>> {
+        self.passthrough.readlink(self.top(inode)?)
+    }
+
+    fn mkdir(
+        &self,
+        dir: &OverlayInode,
+        name: &CStr,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64> {
+        self.check_new(dir, name)?;
+        let upper_dir = self.copy_up(dir)?;
+        let st = self.passthrough.mkdir(upper_dir, name, mode, uid, gid)?;
+
+        // Whatever was removed from the lower directories must stay hidden.
+        if exists(upper_dir, &whiteout_name(name)?)? {
+            create_marker(&lookup(self.passthrough.resolve, upper_dir, name)?, OPAQUE)?;
+            remove_whiteout(upper_dir, name)?;
+        }
+        Ok(st)
+    }
+
+    fn mknod(
+        &self,
+        dir: &OverlayInode,
+        name: &CStr,
+        mode: u32,
+        rdev: libc::dev_t,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64> {
+        self.check_new(dir, name)?;
+        let upper_dir = self.copy_up(dir)?;
+        let st = self
+            .passthrough
+            .mknod(upper_dir, name, mode, rdev, uid, gid)?;
+        remove_whiteout(upper_dir, name)?;
+        Ok(st)
+    }
+
+    fn link(&self, inode: &OverlayInode, dir: &OverlayInode, name: &CStr) -> io::Result<()> {
+        self.check_new(dir, name)?;
+        let upper = self.copy_up(inode)?;
+        let upper_dir = self.copy_up(dir)?;
+        self.passthrough.link(upper, upper_dir, name)?;
+        remove_whiteout(upper_dir, name)?;
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        olddir: &OverlayInode,
+        oldname: &CStr,
+        newdir: &OverlayInode,
+        newname: &CStr,
+    ) -> io::Result<()> {
+        if newname.to_bytes().starts_with(WHITEOUT_PREFIX) {
+            return Err(err(libc::EINVAL));
+        }
+        let old = self.child(olddir, oldname)?;
+        let old_is_dir = is_dir(&self.stat(&old)?);
+        if old_is_dir && !old.lowers.is_empty() {
+            return Err(err(libc::EXDEV));
+        }
+
+        match self.child(newdir, newname) {
+            Ok(target) => match (old_is_dir, is_dir(&self.stat(&target)?)) {
+                (true, false) => return Err(err(libc::ENOTDIR)),
+                (false, true) => return Err(err(libc::EISDIR)),
+                (true, true) => self.clear_empty_dir(&target)?,
+                (false, false) => {}
+            },
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        // A directory moved over one in a lower directory must not show what is in there.
+        let opaque = old_is_dir && self.lower_has(newdir, newname)?;
+
+        self.copy_up(&old)?;
+        let old_upper = self.copy_up(olddir)?;
+        let new_upper = self.copy_up(newdir)?;
+        if self.lower_has(olddir, oldname)? {
+            create_marker(old_upper, &whiteout_name(oldname)?)?;
+        }
+        self.passthrough
+            .rename(old_upper, oldname, new_upper, newname)?;
+        if opaque {
+            create_marker(
+                &lookup(self.passthrough.resolve, new_upper, newname)?,
+                OPAQUE,
+            )?;
+        }
+        remove_whiteout(new_upper, newname)?;
+        Ok(())
+    }
+
+    fn unlink(&self, dir: &OverlayInode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+        let target = self.child(dir, name)?;
+        match (
+            flags & libc::AT_REMOVEDIR != 0,
+            is_dir(&self.stat(&target)?),
+        ) {
+            (true, false) => return Err(err(libc::ENOTDIR)),
+            (false, true) => return Err(err(libc::EISDIR)),
+            (true, true) => self.clear_empty_dir(&target)?,
+            (false, false) => {}
+        }
+
+        let upper_dir = self.copy_up(dir)?;
+        if self.lower_has(dir, name)? {
+            create_marker(upper_dir, &whiteout_name(name)?)?;
+        }
+        if self.upper(&target)?.is_some() {
+            self.passthrough.unlink(upper_dir, name, flags)?;
+        }
+        Ok(())
+    }
+
+    fn parent(
+        &self,
+        _export: &Export,
+        inode: &OverlayInode,
+        _walk_path: &Path,
+    ) -> io::Result<(OverlayInode, CString)> {
+        let path = self.path(inode)?;
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok((
+                self.walk_path(&inode.layers, parent)?,
+                to_cstring(name.as_bytes())?,
+            )),
+            _ => Err(err(libc::EBUSY)),
+        }
+    }
+
+    fn file_name(
+        &self,
+        _export: &Export,
+        inode: &OverlayInode,
+        _walk_path: &Path,
+    ) -> io::Result<P9String> {
+        match self.path(inode)?.file_name() {
+            Some(name) => P9String::try_from(name),
+            None => P9String::new(b"/"),
+        }
+    }
+
+    fn statfs(&self, inode: &OverlayInode) -> io::Result<Rstatfs> {
+        self.passthrough.statfs(&inode.layers.upper)
+    }
+
+    fn fsync(&self, handle: &OverlayHandle, datasync: bool) -> io::Result<()> {
+        self.passthrough.fsync(&handle.file, datasync)
+    }
+
+    fn getxattr(
+        &self,
+        inode: &OverlayInode,
+        handle: Option<&OverlayHandle>,
+        name: &CStr,
+    ) -> io::Result<Vec<u8>> {
+        let file = handle.map(|handle| &handle.file);
+        self.passthrough.getxattr(self.top(inode)?, file, name)
+    }
+
+    fn setxattr(
+        &self,
+        inode: &OverlayInode,
+        handle: Option<&OverlayHandle>,
+        name: &CStr,
+        value: &[u8],
+        flags: libc::c_int,
+    ) -> io::Result<()> {
+        let upper = self.copy_up(inode)?;
+        self.passthrough
+            .setxattr(upper, self.upper_handle(handle), name, value, flags)
+    }
+
+    fn removexattr(
+        &self,
+        inode: &OverlayInode,
+        handle: Option<&OverlayHandle>,
+        name: &CStr,
+    ) -> io::Result<()> {
+        let upper = self.copy_up(inode)?;
+        self.passthrough
+            .removexattr(upper, self.upper_handle(handle), name)
+    }
+
+    fn host_path<'a>(&self, inode: &'a OverlayInode) -> Option<&'a File> {
+        inode.upper.get().or(inode.lowers.first())
+    }
+
+    fn host_file<'a>(&self, handle: &'a OverlayHandle) -> Option<&'a File> {
+        Some(&handle.file)
+    }
+
+    fn keep_fds(&self) -> Vec<RawFd> {
+        let mut fds = self.passthrough.keep_fds();
+        fds.extend(self.lowers.iter().map(File::as_raw_fd));
+        fds
+    }
+}
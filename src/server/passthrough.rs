@@ -0,0 +1,1089 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[cfg(target_os = "android")]
+use libc::__fsid_t as fsid_t;
+#[cfg(not(target_os = "android"))]
+use libc::fsid_t;
+
+use super::read_dir::read_dir;
+use super::read_dir::sort_by_cookie;
+use super::read_dir::DirStreams;
+use super::*;
+use crate::protocol::*;
+use crate::syscall;
+
+/// Serves directories on the host. Every inode is an O_PATH file descriptor for the file that
+/// it refers to, and every handle a regular one. Names are looked up with openat2 and
+/// RESOLVE_BENEATH where the kernel has it, so that no name can lead out of its directory.
+pub struct Passthrough {
+    pub(super) proc: File,
+    pub(super) resolve: Resolve,
+    inode_generation: bool,
+    dir_streams: Mutex<DirStreams>,
+}
+
+impl Passthrough {
+    /// Creates a file system that serves the host with the passthrough options of `cfg`.
+    pub fn new(cfg: &Config) -> io::Result<Passthrough> {
+        let proc_cstr = c"/proc";
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = syscall!(unsafe {
+            libc::openat64(
+                libc::AT_FDCWD,
+                proc_cstr.as_ptr(),
+                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })?;
+
+        Ok(Passthrough {
+            // Safe because we just opened this fd and we know it is valid.
+            proc: unsafe { File::from_raw_fd(fd) },
+            resolve: Resolve {
+                openat2: cfg.openat2,
+                no_symlinks: cfg.no_symlinks,
+            },
+            inode_generation: cfg.inode_generation,
+            dir_streams: Default::default(),
+        })
+    }
+
+    // Returns a file that refers to the same inode as `path` and can be used with the f*xattr
+    // functions, which don't accept O_PATH fds.
+    fn xattr_file<'a>(
+        &self,
+        path: &File,
+        file: Option<&'a File>,
+    ) -> io::Result<MaybeOwned<'a, File>> {
+        if let Some(file) = file {
+            return Ok(MaybeOwned::Borrowed(file));
+        }
+
+        // Opening anything other than a regular file or a directory may have side effects.
+        match stat(path)?.st_mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFDIR => Ok(MaybeOwned::Owned(open_fid(
+                &self.proc,
+                path,
+                P9_NONBLOCK | P9_RDONLY,
+            )?)),
+            _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+        }
+    }
+}
+
+impl FileSystem for Passthrough {
+    type Inode = File;
+    type Handle = File;
+
+    fn root(&self, export: &Export) -> io::Result<File> {
+        let root = CString::new(export.root.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // The root is configured on the host, so it can be a symlink that is followed, such as
+        // one that is flipped between releases.
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = syscall!(unsafe {
+            libc::openat64(
+                libc::AT_FDCWD,
+                root.as_ptr(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        })?;
+
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn lookup(&self, parent: &File, name: &CStr) -> io::Result<File> {
+        let file = lookup(self.resolve, parent, name)?;
+
+        // Symlinks along the way are refused by openat2, and there are none without it since
+        // names are single components, so that leaves the one that the lookup ends at, which
+        // is opened rather than followed.
+        if self.resolve.no_symlinks && stat(&file)?.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            return Err(io::Error::from_raw_os_error(libc::ELOOP));
+        }
+        Ok(file)
+    }
+
+    fn try_clone(&self, inode: &File) -> io::Result<File> {
+        inode.try_clone()
+    }
+
+    fn stat(&self, inode: &File) -> io::Result<libc::stat64> {
+        stat(inode)
+    }
+
+    fn getattr(
+        &self,
+        inode: &File,
+        handle: Option<&File>,
+        request_mask: u64,
+    ) -> io::Result<Rgetattr> {
+        let mut rgetattr = getattr(inode, request_mask)?;
+
+        if self.inode_generation
+            && request_mask & P9_GETATTR_GEN != 0
+            && FileType::from(rgetattr.mode) != FileType::Other
+        {
+            // The ioctl needs a real file descriptor rather than an O_PATH one. Not every file
+            // system keeps generation numbers, in which case the field is just left out. Only
+            // the owner of a file can open it with O_NOATIME, so the server has to do without
+            // that for files it doesn't own.
+            let gen = match handle {
+                Some(file) => get_generation(file),
+                None => open_fid(&self.proc, inode, P9_RDONLY | P9_NONBLOCK | P9_NOATIME)
+                    .or_else(|e| match e.raw_os_error() {
+                        Some(libc::EPERM) => open_fid(&self.proc, inode, P9_RDONLY | P9_NONBLOCK),
+                        _ => Err(e),
+                    })
+                    .and_then(|file| get_generation(&file)),
+            };
+            if let Ok(gen) = gen {
+                rgetattr.valid |= P9_GETATTR_GEN;
+                rgetattr.gen = gen;
+            }
+        }
+
+        Ok(rgetattr)
+    }
+
+    fn setattr(&self, inode: &File, handle: Option<&File>, attr: &Tsetattr) -> io::Result<()> {
+        let path = string_to_cstring(format!("self/fd/{}", inode.as_raw_fd()))?;
+
+        if attr.valid & P9_SETATTR_MODE != 0 {
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::fchmodat(self.proc.as_raw_fd(), path.as_ptr(), attr.mode, 0)
+            })?;
+        }
+
+        if attr.valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if attr.valid & P9_SETATTR_UID != 0 {
+                attr.uid
+            } else {
+                -1i32 as u32
+            };
+            let gid = if attr.valid & P9_SETATTR_GID != 0 {
+                attr.gid
+            } else {
+                -1i32 as u32
+            };
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe { libc::fchownat(self.proc.as_raw_fd(), path.as_ptr(), uid, gid, 0) })?;
+        }
+
+        if attr.valid & P9_SETATTR_SIZE != 0 {
+            let file = match handle {
+                Some(file) => MaybeOwned::Borrowed(file),
+                None => MaybeOwned::Owned(open_fid(&self.proc, inode, P9_NONBLOCK | P9_RDWR)?),
+            };
+
+            file.set_len(attr.size)?;
+        }
+
+        if attr.valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let times = [
+                libc::timespec {
+                    tv_sec: attr.atime_sec as _,
+                    tv_nsec: if attr.valid & P9_SETATTR_ATIME == 0 {
+                        libc::UTIME_OMIT
+                    } else if attr.valid & P9_SETATTR_ATIME_SET == 0 {
+                        libc::UTIME_NOW
+                    } else {
+                        attr.atime_nsec as _
+                    },
+                },
+                libc::timespec {
+                    tv_sec: attr.mtime_sec as _,
+                    tv_nsec: if attr.valid & P9_SETATTR_MTIME == 0 {
+                        libc::UTIME_OMIT
+                    } else if attr.valid & P9_SETATTR_MTIME_SET == 0 {
+                        libc::UTIME_NOW
+                    } else {
+                        attr.mtime_nsec as _
+                    },
+                },
+            ];
+
+            // Safe because file is valid and we have initialized times fully.
+            let ret = unsafe {
+                libc::utimensat(
+                    self.proc.as_raw_fd(),
+                    path.as_ptr(),
+                    &times as *const libc::timespec,
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // The ctime would have been updated by any of the above operations so we only
+        // need to change it if it was the only option given.
+        if attr.valid & P9_SETATTR_CTIME != 0 && attr.valid & (!P9_SETATTR_CTIME) == 0 {
+            // Setting -1 as the uid and gid will not actually change anything but will
+            // still update the ctime.
+            let ret = unsafe {
+                libc::fchownat(
+                    self.proc.as_raw_fd(),
+                    path.as_ptr(),
+                    libc::uid_t::MAX,
+                    libc::gid_t::MAX,
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, inode: &File, flags: u32) -> io::Result<File> {
+        open_fid(&self.proc, inode, flags)
+    }
+
+    fn create(
+        &self,
+        dir: &File,
+        name: &CStr,
+        flags: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<(File, File)> {
+        let mut oflags: i32 = libc::O_CLOEXEC | libc::O_CREAT | libc::O_EXCL;
+        for &(p9f, of) in &MAPPED_FLAGS {
+            if (flags & p9f) != 0 {
+                oflags |= of;
+            }
+        }
+        if flags & P9_NOACCESS == P9_RDONLY {
+            oflags |= libc::O_RDONLY;
+        }
+
+        let file = open_beneath(self.resolve, dir, name, oflags, mode)?;
+        set_owner(dir, name, uid, gid, 0)?;
+
+        Ok((lookup(self.resolve, dir, name)?, file))
+    }
+
+    fn tmpfile(
+        &self,
+        dir: &File,
+        flags: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<(File, File)> {
+        let mut oflags: i32 = libc::O_CLOEXEC | libc::O_TMPFILE;
+        for &(p9f, of) in &MAPPED_FLAGS {
+            if (flags & p9f) != 0 {
+                oflags |= of;
+            }
+        }
+
+        let file = open_beneath(self.resolve, dir, c".", oflags, mode)?;
+        if uid != libc::uid_t::MAX || gid != libc::gid_t::MAX {
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe { libc::fchown(file.as_raw_fd(), uid, gid) })?;
+        }
+
+        // The file has no name to look up, but it can still be reached through /proc.
+        let path = string_to_cstring(format!("self/fd/{}", file.as_raw_fd()))?;
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = syscall!(unsafe {
+            libc::openat64(
+                self.proc.as_raw_fd(),
+                path.as_ptr(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        })?;
+
+        // Safe because we just opened this fd.
+        Ok((unsafe { File::from_raw_fd(fd) }, file))
+    }
+
+    fn read(&self, handle: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        handle.read_at(buf, offset)
+    }
+
+    fn write(&self, handle: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        handle.write_at(buf, offset)
+    }
+
+    fn readdir(
+        &self,
+        dir: &File,
+        handle: &mut File,
+        offset: u64,
+        entry: &mut dyn FnMut(DirEntry, &libc::stat64) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        // The offsets of the host are only good for the stream that they came from, so the whole
+        // directory is read when a client starts reading it and its entries put in the order of
+        // their cookies, which are kept for the reads that carry on from there.
+        let st = stat(handle)?;
+        let file = (st.st_dev, st.st_ino);
+        let mtime = Duration::new(st.st_mtime.max(0) as u64, st.st_mtime_nsec as u32);
+        let fd = handle.as_raw_fd();
+        let kept = self
+            .dir_streams
+            .lock()
+            .unwrap()
+            .take(fd, file, mtime, offset);
+        // Entries that were kept were read long enough after the directory last changed.
+        let mut read_at = Duration::MAX;
+        let entries = match kept {
+            Some(entries) => entries,
+            None => {
+                read_at = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let mut names = Vec::new();
+                let mut dirents = read_dir(handle, 0)?;
+                while let Some(dirent) = dirents.next().transpose()? {
+                    names.push((dirent.name, dirent.type_));
+                }
+                sort_by_cookie(names)
+            }
+        };
+
+        let start = entries.partition_point(|&(cookie, _, _)| cookie <= offset);
+        let mut next = offset;
+        let mut result = Ok(());
+        for (cookie, name, type_) in &entries[start..] {
+            let st = match statat(dir, name.as_c_str(), 0) {
+                // The entry was removed since the directory was read.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+                Ok(st) => st,
+            };
+            let dirent = DirEntry {
+                offset: *cookie,
+                type_: *type_,
+                name: name.clone(),
+            };
+            match entry(dirent, &st) {
+                Ok(true) => next = *cookie,
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.dir_streams
+            .lock()
+            .unwrap()
+            .put(fd, file, mtime, read_at, entries, offset, next);
+        result
+    }
+
+    fn readlink(&self, inode: &File) -> io::Result<Vec<u8>> {
+        readlinkat(inode, c"")
+    }
+
+    fn mkdir(
+        &self,
+        dir: &File,
+        name: &CStr,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode) })?;
+        set_owner(dir, name, uid, gid, libc::AT_REMOVEDIR)?;
+        statat(dir, name, 0)
+    }
+
+    fn mknod(
+        &self,
+        dir: &File,
+        name: &CStr,
+        mode: u32,
+        rdev: libc::dev_t,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe { libc::mknodat(dir.as_raw_fd(), name.as_ptr(), mode, rdev) })?;
+        set_owner(dir, name, uid, gid, 0)?;
+        statat(dir, name, 0)
+    }
+
+    fn link(&self, inode: &File, dir: &File, name: &CStr) -> io::Result<()> {
+        let path = string_to_cstring(format!("self/fd/{}", inode.as_raw_fd()))?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe {
+            libc::linkat(
+                self.proc.as_raw_fd(),
+                path.as_ptr(),
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        olddir: &File,
+        oldname: &CStr,
+        newdir: &File,
+        newname: &CStr,
+    ) -> io::Result<()> {
+        // renameat2 has no way to resolve beneath a directory, but the names can only stay in
+        // there if they are single entries.
+        check_beneath(oldname)?;
+        check_beneath(newname)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe {
+            libc::renameat(
+                olddir.as_raw_fd(),
+                oldname.as_ptr(),
+                newdir.as_raw_fd(),
+                newname.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn rename2(
+        &self,
+        olddir: &File,
+        oldname: &CStr,
+        newdir: &File,
+        newname: &CStr,
+        flags: libc::c_uint,
+    ) -> io::Result<()> {
+        if flags == 0 {
+            return self.rename(olddir, oldname, newdir, newname);
+        }
+        check_beneath(oldname)?;
+        check_beneath(newname)?;
+
+        // Safe because this doesn't modify any memory and we check the return value. The
+        // syscall is used directly since not every libc has a wrapper for it.
+        syscall!(unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                olddir.as_raw_fd(),
+                oldname.as_ptr(),
+                newdir.as_raw_fd(),
+                newname.as_ptr(),
+                flags,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn unlink(&self, dir: &File, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn parent(
+        &self,
+        export: &Export,
+        inode: &File,
+        walk_path: &Path,
+    ) -> io::Result<(File, CString)> {
+        let root = self.root(export)?;
+        lookup_parent(
+            &self.proc,
+            self.resolve,
+            &root,
+            &export.root,
+            inode,
+            walk_path,
+        )
+    }
+
+    fn file_name(&self, _export: &Export, inode: &File, _walk_path: &Path) -> io::Result<P9String> {
+        let path = host_path(&self.proc, inode)?;
+        match path.file_name() {
+            Some(name) => P9String::try_from(name),
+            None => P9String::new(b"/"),
+        }
+    }
+
+    fn statfs(&self, inode: &File) -> io::Result<Rstatfs> {
+        let mut buf = MaybeUninit::zeroed();
+
+        // Safe because this will only modify `out` and we check the return value.
+        syscall!(unsafe { libc::fstatfs64(inode.as_raw_fd(), buf.as_mut_ptr()) })?;
+
+        // Safe because this only has integer types and any value is valid.
+        let out = unsafe { buf.assume_init() };
+        Ok(Rstatfs {
+            ty: out.f_type as u32,
+            bsize: out.f_bsize as u32,
+            blocks: out.f_blocks,
+            bfree: out.f_bfree,
+            bavail: out.f_bavail,
+            files: out.f_files,
+            ffree: out.f_ffree,
+            // Safe because the fsid has only integer fields and the compiler will verify that is
+            // the same width as the `fsid` field in Rstatfs.
+            fsid: unsafe { mem::transmute::<fsid_t, u64>(out.f_fsid) },
+            namelen: out.f_namelen as u32,
+        })
+    }
+
+    fn fsync(&self, handle: &File, datasync: bool) -> io::Result<()> {
+        if datasync {
+            handle.sync_data()
+        } else {
+            handle.sync_all()
+        }
+    }
+
+    fn getxattr(&self, inode: &File, handle: Option<&File>, name: &CStr) -> io::Result<Vec<u8>> {
+        read_xattr(&*self.xattr_file(inode, handle)?, name)
+    }
+
+    fn setxattr(
+        &self,
+        inode: &File,
+        handle: Option<&File>,
+        name: &CStr,
+        value: &[u8],
+        flags: libc::c_int,
+    ) -> io::Result<()> {
+        let file = self.xattr_file(inode, handle)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                flags,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn removexattr(&self, inode: &File, handle: Option<&File>, name: &CStr) -> io::Result<()> {
+        let file = self.xattr_file(inode, handle)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) })?;
+        Ok(())
+    }
+
+    fn host_path<'a>(&self, inode: &'a File) -> Option<&'a File> {
+        Some(inode)
+    }
+
+    fn host_file<'a>(&self, handle: &'a File) -> Option<&'a File> {
+        Some(handle)
+    }
+
+    fn keep_fds(&self) -> Vec<RawFd> {
+        vec![self.proc.as_raw_fd()]
+    }
+}
+
+pub(super) fn statat(d: &File, name: &CStr, flags: libc::c_int) -> io::Result<libc::stat64> {
+    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+    // Safe because the kernel will only write data in `st` and we check the return
+    // value.
+    let res = unsafe {
+        libc::fstatat64(
+            d.as_raw_fd(),
+            name.as_ptr(),
+            st.as_mut_ptr(),
+            flags | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res >= 0 {
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        Ok(unsafe { st.assume_init() })
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+pub(super) fn stat(f: &File) -> io::Result<libc::stat64> {
+    statat(f, c"", libc::AT_EMPTY_PATH)
+}
+
+// The statx fields that hold each of the attributes in a Tgetattr request mask. rdev and blksize
+// are always returned. Rgetattr already has room for the birth time, so it needs no extension.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+const GETATTR_STATX: [(u64, libc::c_uint); 12] = [
+    (P9_GETATTR_MODE, libc::STATX_TYPE | libc::STATX_MODE),
+    (P9_GETATTR_NLINK, libc::STATX_NLINK),
+    (P9_GETATTR_UID, libc::STATX_UID),
+    (P9_GETATTR_GID, libc::STATX_GID),
+    (P9_GETATTR_RDEV, 0),
+    (P9_GETATTR_ATIME, libc::STATX_ATIME),
+    (P9_GETATTR_MTIME, libc::STATX_MTIME),
+    (P9_GETATTR_CTIME, libc::STATX_CTIME),
+    (P9_GETATTR_INO, libc::STATX_INO),
+    (P9_GETATTR_SIZE, libc::STATX_SIZE),
+    (P9_GETATTR_BLOCKS, libc::STATX_BLOCKS),
+    (P9_GETATTR_BTIME, libc::STATX_BTIME),
+];
+
+// Returns `value` if `bit` is set in `valid`, and 0 otherwise.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn field<T: Default>(valid: u64, bit: u64, value: T) -> T {
+    if valid & bit != 0 {
+        value
+    } else {
+        T::default()
+    }
+}
+
+// Gets the attributes of `f` that are in `request_mask`, only asking the kernel for those and the
+// ones needed for the qid. Falls back to `stat` on kernels without statx.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn getattr(f: &File, request_mask: u64) -> io::Result<Rgetattr> {
+    let mut mask = libc::STATX_TYPE | libc::STATX_INO | libc::STATX_MTIME;
+    for &(bit, statx_mask) in &GETATTR_STATX {
+        if request_mask & bit != 0 {
+            mask |= statx_mask;
+        }
+    }
+
+    let mut stx = MaybeUninit::<libc::statx>::zeroed();
+    // Safe because the kernel will only write data in `stx` and we check the return value.
+    let res = syscall!(unsafe {
+        libc::statx(
+            f.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            mask,
+            stx.as_mut_ptr(),
+        )
+    });
+    match res {
+        Ok(_) => {}
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+            return stat(f).map(|st| stat_to_attr(&st));
+        }
+        Err(e) => return Err(e),
+    }
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    let stx = unsafe { stx.assume_init() };
+
+    // Only report the attributes that were both requested and returned.
+    let mut valid = 0;
+    for &(bit, statx_mask) in &GETATTR_STATX {
+        if request_mask & bit != 0 && stx.stx_mask & statx_mask == statx_mask {
+            valid |= bit;
+        }
+    }
+    let mode = u32::from(stx.stx_mode);
+
+    Ok(Rgetattr {
+        valid,
+        qid: Qid {
+            ty: match mode & libc::S_IFMT {
+                libc::S_IFDIR => P9_QTDIR,
+                libc::S_IFREG => P9_QTFILE,
+                libc::S_IFLNK => P9_QTSYMLINK,
+                _ => 0,
+            },
+            version: stx.stx_mtime.tv_sec as u32,
+            path: stx.stx_ino,
+        },
+        mode: field(valid, P9_GETATTR_MODE, mode),
+        uid: field(valid, P9_GETATTR_UID, stx.stx_uid),
+        gid: field(valid, P9_GETATTR_GID, stx.stx_gid),
+        nlink: field(valid, P9_GETATTR_NLINK, stx.stx_nlink.into()),
+        rdev: field(
+            valid,
+            P9_GETATTR_RDEV,
+            libc::makedev(stx.stx_rdev_major, stx.stx_rdev_minor),
+        ),
+        size: field(valid, P9_GETATTR_SIZE, stx.stx_size),
+        blksize: stx.stx_blksize.into(),
+        blocks: field(valid, P9_GETATTR_BLOCKS, stx.stx_blocks),
+        atime_sec: field(valid, P9_GETATTR_ATIME, stx.stx_atime.tv_sec as u64),
+        atime_nsec: field(valid, P9_GETATTR_ATIME, stx.stx_atime.tv_nsec.into()),
+        mtime_sec: field(valid, P9_GETATTR_MTIME, stx.stx_mtime.tv_sec as u64),
+        mtime_nsec: field(valid, P9_GETATTR_MTIME, stx.stx_mtime.tv_nsec.into()),
+        ctime_sec: field(valid, P9_GETATTR_CTIME, stx.stx_ctime.tv_sec as u64),
+        ctime_nsec: field(valid, P9_GETATTR_CTIME, stx.stx_ctime.tv_nsec.into()),
+        btime_sec: field(valid, P9_GETATTR_BTIME, stx.stx_btime.tv_sec as u64),
+        btime_nsec: field(valid, P9_GETATTR_BTIME, stx.stx_btime.tv_nsec.into()),
+        gen: 0,
+        data_version: 0,
+    })
+}
+
+// Without statx, all of the basic attributes are always returned.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn getattr(f: &File, _request_mask: u64) -> io::Result<Rgetattr> {
+    stat(f).map(|st| stat_to_attr(&st))
+}
+
+// Gives `name` in `dir`, which was just created, the owner `uid` and `gid`. Nothing is changed if
+// both are `u32::MAX`. If the owner can't be changed the file is removed again, with
+// `unlink_flags`, so that it doesn't linger with the wrong owner.
+fn set_owner(
+    dir: &File,
+    name: &CStr,
+    uid: u32,
+    gid: u32,
+    unlink_flags: libc::c_int,
+) -> io::Result<()> {
+    if uid == libc::uid_t::MAX && gid == libc::gid_t::MAX {
+        return Ok(());
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = syscall!(unsafe {
+        libc::fchownat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            uid,
+            gid,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    });
+    if let Err(e) = res {
+        // Safe because this doesn't modify any memory. There is nothing more to do if it fails.
+        unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), unlink_flags) };
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub(super) fn lookup(resolve: Resolve, parent: &File, name: &CStr) -> io::Result<File> {
+    open_beneath(
+        resolve,
+        parent,
+        name,
+        libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        0,
+    )
+}
+
+// Set once openat2 has turned out not to work, on kernels older than 5.6 or in sandboxes that
+// don't allow it.
+static NO_OPENAT2: AtomicBool = AtomicBool::new(false);
+
+// How a file system looks up names beneath their directory.
+#[derive(Clone, Copy)]
+pub(super) struct Resolve {
+    // Whether openat2 may be tried, which `Config::openat2` turns off.
+    openat2: bool,
+    // Whether lookups fail with ELOOP on any symlink they would follow, for `Config::no_symlinks`.
+    no_symlinks: bool,
+}
+
+// The argument of openat2, from "include/uapi/linux/openat2.h" in the linux tree.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+// Opens `name` relative to `dir` with openat2, which fails with EXDEV if resolving it would leave
+// `dir` through "..", an absolute path or a symlink, and with ELOOP on any symlink at all if
+// `resolve` refuses them.
+#[cfg(target_os = "linux")]
+fn openat2_beneath(
+    resolve: Resolve,
+    dir: &File,
+    name: &CStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> io::Result<File> {
+    let mut how = OpenHow {
+        flags: flags as u64,
+        mode: mode.into(),
+        resolve: libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS,
+    };
+    if resolve.no_symlinks {
+        how.resolve |= libc::RESOLVE_NO_SYMLINKS;
+    }
+
+    // Safe because the kernel only reads `name` and `how`, which outlive the call, and we check
+    // the return value.
+    let fd = syscall!(unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            &how as *const OpenHow,
+            mem::size_of::<OpenHow>(),
+        )
+    })?;
+
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn openat2_beneath(
+    _resolve: Resolve,
+    _dir: &File,
+    _name: &CStr,
+    _flags: libc::c_int,
+    _mode: libc::mode_t,
+) -> io::Result<File> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+// Fails with EXDEV, as openat2 would for the ones that lead out, unless `name` is a single entry
+// of a directory that can't lead out of it. Without openat2 this is all that keeps lookups inside
+// the export, together with O_NOFOLLOW.
+fn check_beneath(name: &CStr) -> io::Result<()> {
+    let name = name.to_bytes();
+    if name == b".." || name.contains(&b'/') {
+        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+    }
+    Ok(())
+}
+
+// Opens `name` relative to `dir` such that the kernel guarantees that the lookup never leaves
+// `dir`, falling back to openat where openat2 isn't available or `resolve` doesn't allow it.
+// Either way `name` has to be a single name: the server checks every name that a client looks up
+// on its own, which openat2 would get around by resolving a path in one go.
+fn open_beneath(
+    resolve: Resolve,
+    dir: &File,
+    name: &CStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> io::Result<File> {
+    check_beneath(name)?;
+    if !resolve.openat2 || NO_OPENAT2.load(Ordering::Relaxed) {
+        return openat_beneath(dir, name, flags, mode);
+    }
+
+    match openat2_beneath(resolve, dir, name, flags, mode) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+            NO_OPENAT2.store(true, Ordering::Relaxed);
+            openat_beneath(dir, name, flags, mode)
+        }
+        // Seccomp filters often deny syscalls that they don't know with EPERM, which openat
+        // would also fail with for some files. It is only openat2 that doesn't work if openat
+        // does.
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+            let res = openat_beneath(dir, name, flags, mode);
+            if res.is_ok() {
+                NO_OPENAT2.store(true, Ordering::Relaxed);
+            }
+            res
+        }
+        res => res,
+    }
+}
+
+// Opens the single name `name` relative to `dir` with openat.
+fn openat_beneath(
+    dir: &File,
+    name: &CStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> io::Result<File> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = syscall!(unsafe { libc::openat64(dir.as_raw_fd(), name.as_ptr(), flags, mode) })?;
+
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub(super) fn open_fid(proc: &File, path: &File, p9_flags: u32) -> io::Result<File> {
+    let pathname = string_to_cstring(format!("self/fd/{}", path.as_raw_fd()))?;
+
+    // We always open files with O_CLOEXEC.
+    let mut flags: i32 = libc::O_CLOEXEC;
+    for &(p9f, of) in &MAPPED_FLAGS {
+        if (p9_flags & p9f) != 0 {
+            flags |= of;
+        }
+    }
+
+    if p9_flags & P9_NOACCESS == P9_RDONLY {
+        flags |= libc::O_RDONLY;
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value. We need to
+    // clear the O_NOFOLLOW flag because we want to follow the proc symlink.
+    let fd = syscall!(unsafe {
+        libc::openat64(
+            proc.as_raw_fd(),
+            pathname.as_ptr(),
+            flags & !libc::O_NOFOLLOW,
+        )
+    })?;
+
+    // Safe because we just opened this fd and we know it is valid.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub(super) fn readlinkat(dir: &File, name: &CStr) -> io::Result<Vec<u8>> {
+    let mut link = vec![0; libc::PATH_MAX as usize];
+
+    // Safe because this will only modify `link` and we check the return value.
+    let len = syscall!(unsafe {
+        libc::readlinkat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            link.as_mut_ptr() as *mut libc::c_char,
+            link.len(),
+        )
+    })? as usize;
+    link.truncate(len);
+
+    Ok(link)
+}
+
+// Reads the value of the extended attribute `name` of `file`, or the list of names of all its
+// extended attributes if `name` is empty.
+fn read_xattr(file: &File, name: &CStr) -> io::Result<Vec<u8>> {
+    let get = |buf: &mut [u8]| {
+        let ptr = buf.as_mut_ptr() as *mut libc::c_void;
+        if name.is_empty() {
+            // Safe because this will only modify `buf` and we check the return value.
+            syscall!(unsafe { libc::flistxattr(file.as_raw_fd(), ptr as *mut _, buf.len()) })
+        } else {
+            // Safe because this will only modify `buf` and we check the return value.
+            syscall!(unsafe { libc::fgetxattr(file.as_raw_fd(), name.as_ptr(), ptr, buf.len()) })
+        }
+    };
+
+    loop {
+        // Ask for the size first. The attribute may change between the two calls, in which case
+        // the second one fails with ERANGE and we try again.
+        let size = get(&mut [])?;
+        let mut buf = vec![0; size as usize];
+        match get(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len as usize);
+                return Ok(buf);
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Returns the path of `f` on the host, as reported by /proc/self/fd. Since `f` was opened by
+// walking a particular path, this is the path the client used to reach it even if the underlying
+// inode is linked into multiple directories.
+pub(super) fn host_path(proc: &File, f: &File) -> io::Result<PathBuf> {
+    let pathname = string_to_cstring(format!("self/fd/{}", f.as_raw_fd()))?;
+    let link = readlinkat(proc, &pathname)?;
+
+    Ok(PathBuf::from(OsString::from_vec(link)))
+}
+
+// Returns an O_PATH fd for the directory containing `f` along with the name of `f` in that
+// directory. The directory is looked up beneath `root`, the root of the export at `root_path`,
+// one name at a time along `walk_path`, or along the path that the host has for `f` if it has
+// been renamed since it was walked to, and `f` has to be in there under that name. Fails with
+// EBUSY if `f` is the root of the export and with ENOENT if it has been unlinked.
+fn lookup_parent(
+    proc: &File,
+    resolve: Resolve,
+    root: &File,
+    root_path: &Path,
+    f: &File,
+    walk_path: &Path,
+) -> io::Result<(File, CString)> {
+    let st = stat(f)?;
+    if st.st_nlink == 0 {
+        return Err(io::Error::from_raw_os_error(libc::ENOENT));
+    }
+    let root_st = stat(root)?;
+    if (st.st_dev, st.st_ino) == (root_st.st_dev, root_st.st_ino) {
+        return Err(io::Error::from_raw_os_error(libc::EBUSY));
+    }
+
+    // The path from /proc is only a hint: whatever it says, the lookup can't leave the export.
+    let host_path = host_path(proc, f).ok().and_then(|path| {
+        let root = root_path.canonicalize().ok()?;
+        Some(path.strip_prefix(root).ok()?.to_path_buf())
+    });
+    for path in [Some(walk_path.to_path_buf()), host_path]
+        .into_iter()
+        .flatten()
+    {
+        let (parent, name) = match lookup_parent_path(resolve, root, &path) {
+            Ok(found) => found,
+            Err(_) => continue,
+        };
+        if statat(&parent, &name, libc::AT_SYMLINK_NOFOLLOW)
+            .is_ok_and(|found| (found.st_dev, found.st_ino) == (st.st_dev, st.st_ino))
+        {
+            return Ok((parent, name));
+        }
+    }
+    Err(io::Error::from_raw_os_error(libc::ENOENT))
+}
+
+// Looks up the directory that `path` is in beneath `root`, a name at a time without following
+// symlinks, and returns it along with the last name in `path`.
+fn lookup_parent_path(resolve: Resolve, root: &File, path: &Path) -> io::Result<(File, CString)> {
+    let to_cstring = |name: &OsStr| {
+        CString::new(name.as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+    };
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(to_cstring(name)?),
+            Component::RootDir | Component::CurDir => {}
+            _ => return Err(io::Error::from_raw_os_error(libc::EXDEV)),
+        }
+    }
+    let name = names
+        .pop()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EBUSY))?;
+
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let mut dir = open_beneath(resolve, root, c".", flags, 0)?;
+    for name in names {
+        dir = open_beneath(resolve, &dir, &name, flags, 0)?;
+    }
+    Ok((dir, name))
+}
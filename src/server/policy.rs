@@ -0,0 +1,201 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Rules that limit what clients can do with parts of an exported tree, by the path that they
+//! walked to reach them.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::*;
+
+/// What clients may do with the files that a `Rule` applies to. Each level allows everything
+/// that the ones before it do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Access {
+    /// The files can't even be walked to.
+    None,
+    /// The files can be walked to, read and listed.
+    Read,
+    /// The contents and attributes of existing files can also be changed.
+    Write,
+    /// Files can also be created, removed, linked and renamed.
+    Create,
+}
+
+impl FromStr for Access {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Access::None),
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            "create" => Ok(Access::Create),
+            _ => Err("access must be one of `none`, `read`, `write` or `create`"),
+        }
+    }
+}
+
+/// Gives the files whose paths match `pattern` the access in `access`. Paths start with a `/`
+/// for the root of the export, as in `/usr/bin/ls`. A pattern with any of `*`, `?` or `[` in it
+/// is a shell-style wildcard pattern that must match the whole path, where `**` matches across
+/// `/`. Any other pattern is a prefix that matches the path itself and everything under it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub pattern: String,
+    pub access: Access,
+}
+
+impl Rule {
+    // Returns true if the rule applies to `path`, which has been folded by `cfg`.
+    fn matches(&self, cfg: &Config, path: &[u8]) -> bool {
+        let pattern = cfg.fold_name(self.pattern.as_bytes());
+        if pattern.iter().any(|c| b"*?[".contains(c)) {
+            glob::matches(&pattern, path)
+        } else {
+            Path::new(OsStr::from_bytes(path)).starts_with(OsStr::from_bytes(&pattern))
+        }
+    }
+}
+
+// Returns the access that the first of the rules of `cfg` that matches `path` gives, or full
+// access if none of them do.
+fn access(cfg: &Config, path: &Path) -> Access {
+    let path = cfg.fold_name(path.as_os_str().as_bytes());
+    cfg.rules
+        .iter()
+        .find(|rule| rule.matches(cfg, &path))
+        .map_or(Access::Create, |rule| rule.access)
+}
+
+// Returns the path of the entry `name` of the directory at `dir`.
+pub(super) fn join(dir: &Path, name: &P9String) -> PathBuf {
+    match name.as_bytes() {
+        b".." => dir.parent().unwrap_or(dir).to_path_buf(),
+        b"." => dir.to_path_buf(),
+        name => dir.join(OsStr::from_bytes(name)),
+    }
+}
+
+impl<F: FileSystem> Server<F> {
+    // Fails with EACCES unless the rules give at least `needed` access to `path`.
+    pub(super) fn check_access(&self, path: &Path, needed: Access) -> io::Result<()> {
+        if access(&self.cfg, path) < needed {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        Ok(())
+    }
+
+    // Checks that the rules allow `msg`. Walks and Tremove check for themselves, since they
+    // partially succeed or clunk their fid even when they fail.
+    pub(super) fn check_policy(&self, msg: &Tmessage) -> io::Result<()> {
+        if self.cfg.rules.is_empty() {
+            return Ok(());
+        }
+        for (path, needed) in self.accessed_paths(msg) {
+            self.check_access(&path, needed)?;
+        }
+        Ok(())
+    }
+
+    // Returns the paths of the files that `msg` reaches, by the fids that it uses, along with
+    // what it does with each.
+    pub(super) fn accessed_paths(&self, msg: &Tmessage) -> Vec<(PathBuf, Access)> {
+        // The paths of the fids of other trees, which the requests fail on anyway, don't matter.
+        let path = |&fid: &u32| {
+            self.fids
+                .get(fid)
+                .map(|fid| lock_fid(&fid).walk_path.clone())
+        };
+        let entry = |dir: &u32, name: &P9String| path(dir).map(|dir| join(&dir, name));
+        let mut checks: Vec<(PathBuf, Access)> = Vec::new();
+        let mut check = |path: Option<PathBuf>, needed| checks.extend(path.map(|p| (p, needed)));
+
+        match msg {
+            Tmessage::Open(open) => {
+                let needed = if open.mode & P9_ORCLOSE != 0 {
+                    Access::Create
+                } else if open_modifies(omode_to_p9_flags(open.mode)) {
+                    Access::Write
+                } else {
+                    Access::Read
+                };
+                check(path(&open.fid), needed);
+            }
+            Tmessage::Lopen(lopen) => {
+                let needed = if lopen.flags & P9_TMPFILE == P9_TMPFILE {
+                    Access::Create
+                } else if open_modifies(lopen.flags) {
+                    Access::Write
+                } else {
+                    Access::Read
+                };
+                check(path(&lopen.fid), needed);
+            }
+            Tmessage::Create(create) => check(entry(&create.fid, &create.name), Access::Create),
+            Tmessage::Lcreate(lcreate) => check(entry(&lcreate.fid, &lcreate.name), Access::Create),
+            Tmessage::Symlink(symlink) => check(entry(&symlink.fid, &symlink.name), Access::Create),
+            Tmessage::Mknod(mknod) => check(entry(&mknod.dfid, &mknod.name), Access::Create),
+            Tmessage::Mkdir(mkdir) => check(entry(&mkdir.dfid, &mkdir.name), Access::Create),
+            Tmessage::Link(link) => {
+                check(path(&link.fid), Access::Create);
+                check(entry(&link.dfid, &link.name), Access::Create);
+            }
+            Tmessage::Rename(rename) => {
+                check(path(&rename.fid), Access::Create);
+                check(entry(&rename.dfid, &rename.name), Access::Create);
+            }
+            Tmessage::RenameAt(rename_at) => {
+                check(
+                    entry(&rename_at.olddirfid, &rename_at.oldname),
+                    Access::Create,
+                );
+                check(
+                    entry(&rename_at.newdirfid, &rename_at.newname),
+                    Access::Create,
+                );
+            }
+            Tmessage::RenameAt2(rename_at2) => {
+                check(
+                    entry(&rename_at2.olddirfid, &rename_at2.oldname),
+                    Access::Create,
+                );
+                check(
+                    entry(&rename_at2.newdirfid, &rename_at2.newname),
+                    Access::Create,
+                );
+            }
+            Tmessage::UnlinkAt(unlink_at) => {
+                check(entry(&unlink_at.dirfd, &unlink_at.name), Access::Create)
+            }
+            Tmessage::Wstat(wstat) => {
+                let file = path(&wstat.fid);
+                if !wstat.stat.name.is_empty() {
+                    let renamed = file.as_deref().map(|file| {
+                        file.parent()
+                            .unwrap_or(file)
+                            .join(OsStr::from_bytes(wstat.stat.name.as_bytes()))
+                    });
+                    check(file.clone(), Access::Create);
+                    check(renamed, Access::Create);
+                }
+                check(file, Access::Write);
+            }
+            msg => {
+                if let Some(fid) = modified_fid(msg) {
+                    check(path(&fid), Access::Write);
+                }
+            }
+        }
+        checks
+    }
+}
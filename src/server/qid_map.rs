@@ -0,0 +1,203 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::protocol::Qid;
+
+// Paths are made of a device prefix above the low bits, which hold the inode number, for the
+// inode numbers that fit in them. The top bit is left clear for the paths of the files whose
+// inode numbers don't fit, or whose devices ran out of prefixes, which are numbered one by one.
+const INODE_BITS: u32 = 48;
+const MAX_PREFIXES: u64 = 1 << (63 - INODE_BITS);
+const NUMBERED: u64 = 1 << 63;
+
+#[derive(Default)]
+struct Paths {
+    // The prefix of each device, in the order that they were seen.
+    prefixes: BTreeMap<u64, u64>,
+    // The paths of the files that don't fit under a prefix, by device and inode.
+    numbered: BTreeMap<(u64, u64), u64>,
+    // Where new prefixes and numbered paths are recorded, for maps that are kept on disk. Each
+    // is a line of `d <dev> <prefix>` or `f <dev> <ino> <path>`, in the order they were given
+    // out.
+    file: Option<File>,
+}
+
+impl Paths {
+    fn path(&mut self, dev: u64, ino: u64) -> u64 {
+        let next_prefix = self.prefixes.len() as u64;
+        let prefix = match self.prefixes.get(&dev) {
+            Some(&prefix) => Some(prefix),
+            None if next_prefix < MAX_PREFIXES => {
+                self.prefixes.insert(dev, next_prefix);
+                self.record(format_args!("d {dev} {next_prefix}\n"));
+                Some(next_prefix)
+            }
+            None => None,
+        };
+        match prefix {
+            Some(prefix) if ino >> INODE_BITS == 0 => prefix << INODE_BITS | ino,
+            _ => {
+                if let Some(&path) = self.numbered.get(&(dev, ino)) {
+                    return path;
+                }
+                let path = NUMBERED | self.numbered.len() as u64;
+                self.numbered.insert((dev, ino), path);
+                self.record(format_args!("f {dev} {ino} {path}\n"));
+                path
+            }
+        }
+    }
+
+    // Appends `line` to the file of the map, if it has one. The path has already been given out
+    // by then, so a write that fails only means that it may not survive a restart.
+    fn record(&mut self, line: std::fmt::Arguments) {
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.write_fmt(line);
+        }
+    }
+
+    // Adds the prefixes and paths recorded in `data` to the map, and returns the length of the
+    // part of `data` that holds whole lines. Anything after that was cut short by a crash.
+    fn load(&mut self, data: &[u8]) -> io::Result<usize> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid qid map");
+        let len = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let data = std::str::from_utf8(&data[..len]).map_err(|_| invalid())?;
+        for line in data.lines() {
+            let mut fields = line.split(' ');
+            let kind = fields.next();
+            let numbers = fields
+                .map(|n| n.parse::<u64>().map_err(|_| invalid()))
+                .collect::<io::Result<Vec<u64>>>()?;
+            // Prefixes and paths are only valid in the order that they would have been given
+            // out in, which also keeps them from being given out twice.
+            match (kind, &numbers[..]) {
+                (Some("d"), &[dev, prefix])
+                    if prefix == self.prefixes.len() as u64
+                        && prefix < MAX_PREFIXES
+                        && !self.prefixes.contains_key(&dev) =>
+                {
+                    self.prefixes.insert(dev, prefix);
+                }
+                (Some("f"), &[dev, ino, path])
+                    if path == NUMBERED | self.numbered.len() as u64
+                        && !self.numbered.contains_key(&(dev, ino)) =>
+                {
+                    self.numbered.insert((dev, ino), path);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Gives every file that a server serves a qid path of its own, so that clients can tell files
+/// apart even when the exported tree spans several file systems whose inode numbers overlap. The
+/// same map should be given to every `Server` that serves the same tree, so that they agree on
+/// the qids of its files.
+///
+/// The files of the first device that the map comes across, which is normally that of the root
+/// of the export, keep their inode numbers as their paths, and those of every other device get
+/// its own range of paths. Which range depends on the order in which the devices were seen, so a
+/// map that qids should survive restarts of the server with has to be kept on disk.
+#[derive(Clone, Default)]
+pub struct QidMap {
+    inner: Arc<Mutex<Paths>>,
+}
+
+impl QidMap {
+    /// Creates an empty map.
+    pub fn new() -> QidMap {
+        Default::default()
+    }
+
+    /// Creates a map that is kept in the file at `path`, which is created if it doesn't exist,
+    /// so that files keep their qids when the server restarts. The file grows by a line for
+    /// every device the map sees and for every file that doesn't fit in the range of its device.
+    /// Devices are told apart by their device numbers, which only stay the same for file systems
+    /// on block devices, so the qids of files on others may still change.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<QidMap> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut paths = Paths::default();
+        let len = paths.load(&data)?;
+        if len < data.len() {
+            file.set_len(len as u64)?;
+        }
+        paths.file = Some(file);
+        Ok(QidMap {
+            inner: Arc::new(Mutex::new(paths)),
+        })
+    }
+
+    // Returns the qid of the file with metadata `st`.
+    pub(super) fn qid(&self, st: &libc::stat64) -> Qid {
+        let mut qid = Qid::from(*st);
+        qid.path = self.inner.lock().unwrap().path(st.st_dev, st.st_ino);
+        qid
+    }
+
+    // Returns true if the path of every file so far has been its inode number. Any file whose
+    // device isn't known yet would get a different one.
+    pub(super) fn is_identity(&self) -> bool {
+        let paths = self.inner.lock().unwrap();
+        paths.prefixes.len() <= 1 && paths.numbered.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let mut paths = Paths::default();
+        assert_eq!(paths.path(8, 2), 2);
+        assert_eq!(paths.path(9, 2), 1 << INODE_BITS | 2);
+        assert_eq!(paths.path(8, 1 << INODE_BITS), NUMBERED);
+        assert_eq!(paths.path(9, u64::MAX), NUMBERED | 1);
+        assert_eq!(paths.path(8, 1 << INODE_BITS), NUMBERED);
+        assert_eq!(paths.path(8, 2), 2);
+
+        for dev in 10..MAX_PREFIXES + 8 {
+            paths.path(dev, 2);
+        }
+        assert_eq!(paths.path(MAX_PREFIXES + 8, 2), NUMBERED | 2);
+    }
+
+    #[test]
+    fn load() {
+        let mut paths = Paths::default();
+        let data = format!("d 8 0\nd 9 1\nf 8 {} {NUMBERED}\nd 10", 1u64 << INODE_BITS);
+        // The last line was cut short.
+        let len = paths.load(data.as_bytes()).expect("failed to load");
+        assert_eq!(len, data.len() - "d 10".len());
+        assert_eq!(paths.path(9, 2), 1 << INODE_BITS | 2);
+        assert_eq!(paths.path(8, 1 << INODE_BITS), NUMBERED);
+        assert_eq!(paths.path(10, 2), 2 << INODE_BITS | 2);
+
+        for data in ["d 8 1\n", "d 8 0\nd 8 1\n", "f 8 2 3\n", "d 8\n", "x 8 0\n"] {
+            let err = Paths::default()
+                .load(data.as_bytes())
+                .expect_err("loaded an invalid map");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{data:?}");
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Limits on how many bytes and files clients can keep in an export.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::io;
+use std::path::Path;
+
+use super::*;
+
+// What the files of an export use up.
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    bytes: u64,
+    inodes: u64,
+}
+
+// Keeps track of the usage of each export that has a quota, by its root. A server works out the
+// usage of an export with a scan when a client first attaches to it, and from then on only counts
+// the changes that its own clients make. Changes made on the host or by other servers are only
+// seen by the next server to scan the export.
+#[derive(Default)]
+pub(super) struct Quotas {
+    usage: BTreeMap<Box<Path>, Usage>,
+}
+
+fn edquot() -> io::Error {
+    io::Error::from_raw_os_error(libc::EDQUOT)
+}
+
+fn has_quota(export: &Export) -> bool {
+    export.quota_bytes.is_some() || export.quota_inodes.is_some()
+}
+
+// Adds up the sizes of the regular files and the number of files under `root`, counting files
+// with more than one link once. Files that can't be read are left out.
+fn scan<F: FileSystem>(fs: &F, root: &F::Inode) -> io::Result<Usage> {
+    let mut usage = Usage {
+        bytes: 0,
+        inodes: 1,
+    };
+    let mut seen = BTreeSet::new();
+    let mut dirs = vec![fs.try_clone(root)?];
+    while let Some(dir) = dirs.pop() {
+        let mut handle = match fs.open(&dir, P9_DIRECTORY | P9_RDONLY) {
+            Ok(handle) => handle,
+            Err(_) => continue,
+        };
+        let mut subdirs = Vec::new();
+        let _ = fs.readdir(&dir, &mut handle, 0, &mut |dirent, st| {
+            let name = dirent.name.as_bytes();
+            if name == b"." || name == b".." || !seen.insert((st.st_dev, st.st_ino)) {
+                return Ok(true);
+            }
+            usage.inodes += 1;
+            match st.st_mode & libc::S_IFMT {
+                libc::S_IFREG => usage.bytes += st.st_size as u64,
+                libc::S_IFDIR => subdirs.push(dirent.name),
+                _ => {}
+            }
+            Ok(true)
+        });
+        dirs.extend(
+            subdirs
+                .iter()
+                .filter_map(|name| fs.lookup(&dir, name.as_c_str()).ok()),
+        );
+    }
+    Ok(usage)
+}
+
+impl Quotas {
+    // Works out the usage of `export`, whose root is `root`, unless it is already known or the
+    // export has no quota.
+    pub fn attach<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        export: &Export,
+        root: &F::Inode,
+    ) -> io::Result<()> {
+        if has_quota(export) && !self.usage.contains_key(&export.root) {
+            let usage = scan(fs, root)?;
+            self.usage.insert(export.root.clone(), usage);
+        }
+        Ok(())
+    }
+
+    // Returns true if files created in or removed from `export` count against a quota.
+    pub fn tracks(&self, export: &Export) -> bool {
+        self.usage.contains_key(&export.root)
+    }
+
+    // Returns true if changes to the sizes of the files of `export` count against a quota.
+    pub fn counts_bytes(&self, export: &Export) -> bool {
+        export.quota_bytes.is_some() && self.usage.contains_key(&export.root)
+    }
+
+    // Returns how many of the `len` bytes from `offset` on can be written to a file of `export`
+    // that is `size` bytes long, as long as it is more than none.
+    pub fn writable_len(
+        &self,
+        export: &Export,
+        size: u64,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<u64> {
+        let (limit, usage) = match (export.quota_bytes, self.usage.get(&export.root)) {
+            (Some(limit), Some(usage)) => (limit, usage),
+            _ => return Ok(len),
+        };
+        let max_size = size.saturating_add(limit.saturating_sub(usage.bytes));
+        if offset.saturating_add(len) <= max_size {
+            Ok(len)
+        } else if len > 0 && offset >= max_size {
+            Err(edquot())
+        } else {
+            Ok(max_size - offset)
+        }
+    }
+
+    // Fails with EDQUOT if a file of `export` can't grow from `old` to `new` bytes.
+    pub fn check_resize(&self, export: &Export, old: u64, new: u64) -> io::Result<()> {
+        if new > old && self.writable_len(export, old, old, new - old)? < new - old {
+            return Err(edquot());
+        }
+        Ok(())
+    }
+
+    // Records that a file of `export` went from `old` to `new` bytes.
+    pub fn resized(&mut self, export: &Export, old: u64, new: u64) {
+        if let Some(usage) = self.usage.get_mut(&export.root) {
+            usage.bytes = usage.bytes.saturating_add(new).saturating_sub(old);
+        }
+    }
+
+    // Fails with EDQUOT if no more files can be created in `export`.
+    pub fn check_create(&self, export: &Export) -> io::Result<()> {
+        match (export.quota_inodes, self.usage.get(&export.root)) {
+            (Some(limit), Some(usage)) if usage.inodes >= limit => Err(edquot()),
+            _ => Ok(()),
+        }
+    }
+
+    // Records that a file was created in `export`.
+    pub fn created(&mut self, export: &Export) {
+        if let Some(usage) = self.usage.get_mut(&export.root) {
+            usage.inodes += 1;
+        }
+    }
+
+    // Records that a file of `export` with the metadata in `st` was unlinked from its directory.
+    // Files with other links still use up as much as they did.
+    pub fn removed(&mut self, export: &Export, st: &libc::stat64) {
+        let usage = match self.usage.get_mut(&export.root) {
+            Some(usage) => usage,
+            None => return,
+        };
+        let is_dir = st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        if st.st_nlink <= 1 || is_dir {
+            usage.inodes = usage.inodes.saturating_sub(1);
+            if st.st_mode & libc::S_IFMT == libc::S_IFREG {
+                usage.bytes = usage.bytes.saturating_sub(st.st_size as u64);
+            }
+        }
+    }
+
+    // Limits what `statfs` reports for `export` to its quotas, so that clients see as much space
+    // and as many files as they can actually use, or reports the quotas in place of what the
+    // host does with `StatfsOverride::quota`.
+    pub fn statfs(&self, export: &Export, mut statfs: Rstatfs) -> Rstatfs {
+        let usage = match self.usage.get(&export.root) {
+            Some(usage) => *usage,
+            None => return statfs,
+        };
+        let limit_to = |reported: u64, limit: u64| {
+            if export.statfs.quota {
+                limit
+            } else {
+                min(reported, limit)
+            }
+        };
+        if let Some(limit) = export.quota_bytes {
+            let bsize = u64::from(statfs.bsize.max(1));
+            let free = limit.saturating_sub(usage.bytes) / bsize;
+            statfs.blocks = limit_to(statfs.blocks, limit / bsize);
+            statfs.bfree = limit_to(statfs.bfree, free);
+            statfs.bavail = limit_to(statfs.bavail, free);
+        }
+        if let Some(limit) = export.quota_inodes {
+            statfs.files = limit_to(statfs.files, limit);
+            statfs.ffree = limit_to(statfs.ffree, limit.saturating_sub(usage.inodes));
+        }
+        statfs
+    }
+}
+
+impl<F: FileSystem> Server<F> {
+    // Returns the metadata of the entry `name` of `dir`, a directory of `export`, if the export
+    // has a quota and the entry exists, so that it can be taken off the usage once the entry is
+    // removed or replaced.
+    pub(super) fn quota_entry(
+        &self,
+        export: &Export,
+        dir: &F::Inode,
+        name: &CStr,
+    ) -> Option<libc::stat64> {
+        if !self.quotas.tracks(export) {
+            return None;
+        }
+        let inode = self.fs.lookup(dir, name).ok()?;
+        self.fs.stat(&inode).ok()
+    }
+}
@@ -2,16 +2,21 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::BTreeMap;
 use std::io::Result;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
 
 use libc::F_DUPFD_CLOEXEC;
 
 use crate::protocol::P9String;
 
+/// An entry of a directory, as returned by `FileSystem::readdir`.
 pub struct DirEntry {
-    pub ino: libc::ino64_t,
+    /// Where the entry after this one starts.
     pub offset: u64,
+    /// The type of the file, as in the `d_type` of a Linux dirent.
     pub type_: u8,
     pub name: P9String,
 }
@@ -39,8 +44,7 @@ impl ReadDir {
 
         // SAFETY: `dirent64` is a non-NULL pointer, as checked above.
         // We trust the C library to return a correctly-aligned, valid pointer.
-        let (d_ino, d_off, d_type) =
-            unsafe { ((*dirent64).d_ino, (*dirent64).d_off, (*dirent64).d_type) };
+        let (d_off, d_type) = unsafe { ((*dirent64).d_off, (*dirent64).d_type) };
 
         let d_name: &[u8] = unsafe { std::mem::transmute((*dirent64).d_name.as_ref()) };
         let name = match P9String::new(strip_padding(d_name)) {
@@ -49,7 +53,6 @@ impl ReadDir {
         };
 
         let entry = DirEntry {
-            ino: d_ino,
             offset: d_off as u64,
             type_: d_type,
             name,
@@ -59,7 +62,7 @@ impl ReadDir {
     }
 }
 
-pub fn read_dir<D: AsRawFd>(dir: &mut D, offset: libc::c_long) -> Result<ReadDir> {
+pub fn read_dir<D: AsRawFd>(dir: &D, offset: libc::c_long) -> Result<ReadDir> {
     let dup_fd = unsafe { libc::fcntl(dir.as_raw_fd(), F_DUPFD_CLOEXEC, 0) };
     let dir = unsafe { libc::fdopendir(dup_fd) };
     if dir.is_null() {
@@ -75,6 +78,151 @@ pub fn read_dir<D: AsRawFd>(dir: &mut D, offset: libc::c_long) -> Result<ReadDir
     Ok(read_dir)
 }
 
+// Entries are put in the order of cookies made from their names, so that the offset of an entry
+// is the same whenever the directory is read and can be found again without the stream that it
+// came from. A cookie is a hash of the name above the position of the name among those with the
+// same hash, with room below for "." and ".." to come first. It is never 0, which is the start of
+// the directory, and never has the top bit set, for clients that take offsets to be signed.
+const COOKIE_HASH_SHIFT: u32 = 10;
+const COOKIE_INDEX_BITS: u32 = 8;
+const MAX_COOKIE_INDEX: u64 = (1 << COOKIE_INDEX_BITS) - 1;
+
+// Returns the 64-bit FNV-1a hash of `name`, which unlike the hashers of the standard library
+// stays the same across restarts and versions of rust.
+fn name_hash(name: &[u8]) -> u64 {
+    name.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Returns `entries` in the order of their cookies, each along with its cookie, which is the
+/// offset that the entries after it start at.
+pub(super) fn sort_by_cookie<T>(entries: Vec<(P9String, T)>) -> Vec<(u64, P9String, T)> {
+    let mut entries: Vec<(u64, P9String, T)> = entries
+        .into_iter()
+        .map(|(name, value)| {
+            let key = match name.as_bytes() {
+                b"." => 0,
+                b".." => 1,
+                n => ((name_hash(n) >> COOKIE_HASH_SHIFT) + 1) << COOKIE_INDEX_BITS,
+            };
+            (key, name, value)
+        })
+        .collect();
+    entries.sort_unstable_by(|a, b| (a.0, a.1.as_bytes()).cmp(&(b.0, b.1.as_bytes())));
+
+    // Names with the same hash are told apart by their order, and any past the last index share
+    // it, which only ever happens for names crafted to collide.
+    let mut prev = None;
+    let mut index = 0;
+    for entry in &mut entries {
+        index = if prev == Some(entry.0) {
+            (index + 1).min(MAX_COOKIE_INDEX)
+        } else {
+            0
+        };
+        prev = Some(entry.0);
+        entry.0 += index + 1;
+    }
+    entries
+}
+
+// How many directories a file system keeps the entries of between reads.
+const MAX_DIR_STREAMS: usize = 16;
+
+// How far behind the clock the timestamps that file systems give changes may be. A directory that
+// changed this recently when it was read may change again without its mtime moving on.
+const TIMESTAMP_SLACK: Duration = Duration::from_secs(1);
+
+// The entries that a directory had when a client started reading it, in the order of their
+// cookies along with them and their `d_type`, so that the reads that carry on from there don't
+// have to read the whole directory again.
+struct DirStream {
+    // The device and inode number of the directory, and its mtime when it was read.
+    file: (u64, u64),
+    mtime: Duration,
+    entries: Vec<(u64, P9String, u8)>,
+    // The offset of the last read, and the offset of the last entry that it returned. The next
+    // read carries on from somewhere in between, since the client may have skipped some of
+    // them.
+    last: u64,
+    next: u64,
+    used: u64,
+}
+
+/// The entries of the directories that are being read, by the file descriptor that they are
+/// open in. A directory is read in full when a client starts reading it at offset 0, and the
+/// reads after that get the entries that it had then, like they would from a directory stream
+/// that was kept open between them. A directory that changed since it was read is read again.
+#[derive(Default)]
+pub(super) struct DirStreams {
+    streams: BTreeMap<RawFd, DirStream>,
+    tick: u64,
+}
+
+impl DirStreams {
+    // Returns the entries of the directory `file` with mtime `mtime` that is open in `fd`, in the
+    // order of their cookies, if a read of it at `offset` carries on from the last one.
+    pub(super) fn take(
+        &mut self,
+        fd: RawFd,
+        file: (u64, u64),
+        mtime: Duration,
+        offset: u64,
+    ) -> Option<Vec<(u64, P9String, u8)>> {
+        let stream = self.streams.get(&fd)?;
+        // A file descriptor that was closed since may have been reused for another directory,
+        // or for the same one opened again.
+        if stream.file != file
+            || stream.mtime != mtime
+            || !(stream.last < offset && offset <= stream.next)
+        {
+            return None;
+        }
+        self.streams.remove(&fd).map(|stream| stream.entries)
+    }
+
+    // Keeps `entries`, which were read at `read_at` since the epoch, for the next read of the
+    // directory `file` with mtime `mtime` that is open in `fd`, after a read at `offset` that
+    // returned up to the entry at `next`.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn put(
+        &mut self,
+        fd: RawFd,
+        file: (u64, u64),
+        mtime: Duration,
+        read_at: Duration,
+        entries: Vec<(u64, P9String, u8)>,
+        offset: u64,
+        next: u64,
+    ) {
+        if mtime + TIMESTAMP_SLACK > read_at {
+            self.streams.remove(&fd);
+            return;
+        }
+        self.tick += 1;
+        let stream = DirStream {
+            file,
+            mtime,
+            entries,
+            last: offset,
+            next,
+            used: self.tick,
+        };
+        self.streams.insert(fd, stream);
+        if self.streams.len() > MAX_DIR_STREAMS {
+            let oldest = self
+                .streams
+                .iter()
+                .min_by_key(|(_, stream)| stream.used)
+                .map(|(&fd, _)| fd);
+            if let Some(fd) = oldest {
+                self.streams.remove(&fd);
+            }
+        }
+    }
+}
+
 // Trims any trailing '\0' bytes. Panics if `b` doesn't contain any '\0' bytes.
 fn strip_padding(b: &[u8]) -> &[u8] {
     // It would be nice if we could use memchr here but that's locked behind an unstable gate.
@@ -99,6 +247,61 @@ mod test {
         assert_eq!(strip_padding(b"interior\0nul bytes\0\0\0"), b"interior");
     }
 
+    #[test]
+    fn cookies() {
+        let names = ["b", "..", "a", ".", "c"];
+        let entries = names
+            .iter()
+            .map(|&n| (P9String::new(n.as_bytes()).unwrap(), ()))
+            .collect();
+        let sorted = sort_by_cookie(entries);
+        assert_eq!(sorted[0].0, 1);
+        assert_eq!(sorted[0].1, ".");
+        assert_eq!(sorted[1].0, 2);
+        assert_eq!(sorted[1].1, "..");
+        assert!(sorted.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(sorted.iter().all(|e| e.0 < 1 << 63));
+
+        // Cookies only depend on the names themselves.
+        let entries = ["c", "a"]
+            .iter()
+            .map(|&n| (P9String::new(n.as_bytes()).unwrap(), ()))
+            .collect();
+        for (cookie, name, _) in sort_by_cookie(entries) {
+            assert!(sorted
+                .iter()
+                .any(|e| e.0 == cookie && e.1.as_bytes() == name.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn dir_streams() {
+        let mut streams = DirStreams::default();
+        let entries = vec![(2, P9String::new(b"a").unwrap(), 0)];
+        let mtime = Duration::from_secs(100);
+        let read_at = mtime + TIMESTAMP_SLACK;
+        streams.put(3, (1, 2), mtime, read_at, entries.clone(), 0, 10);
+
+        // Only reads of the same directory that carry on from the last one get the entries.
+        assert!(streams.take(3, (1, 3), mtime, 10).is_none());
+        assert!(streams.take(3, (1, 2), mtime + read_at, 10).is_none());
+        assert!(streams.take(3, (1, 2), mtime, 0).is_none());
+        assert!(streams.take(3, (1, 2), mtime, 11).is_none());
+        assert_eq!(streams.take(3, (1, 2), mtime, 5).map(|e| e.len()), Some(1));
+        assert!(streams.take(3, (1, 2), mtime, 5).is_none());
+
+        // Nor do the entries of a directory that changed right before it was read.
+        streams.put(3, (1, 2), read_at, read_at, entries.clone(), 0, 10);
+        assert!(streams.take(3, (1, 2), read_at, 10).is_none());
+
+        for fd in 0..MAX_DIR_STREAMS as RawFd + 1 {
+            streams.put(fd, (1, 2), mtime, read_at, entries.clone(), 0, 10);
+        }
+        assert_eq!(streams.streams.len(), MAX_DIR_STREAMS);
+        assert!(streams.take(0, (1, 2), mtime, 10).is_none());
+        assert!(streams.take(1, (1, 2), mtime, 10).is_some());
+    }
+
     #[test]
     #[should_panic(expected = "`b` doesn't contain any nul bytes")]
     fn no_nul_byte() {
@@ -0,0 +1,91 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Rread replies whose data is read straight into the frame that they are sent in, rather than
+//! into a buffer of its own that is then copied into the frame.
+
+use std::io;
+
+use crate::protocol::*;
+
+// Where the data of the Rread for the request being handled goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum ReadInto {
+    // The Rread itself.
+    #[default]
+    Reply,
+    // The `ReadFrame` of the server, right after where the header of the Rread goes.
+    Frame,
+    // The `SplicePipe` of the server, to be spliced into the transport after the Rread.
+    Pipe,
+}
+
+// Returns the size of the header of an Rread, which is all of it but the data.
+pub(super) fn rread_header_size() -> usize {
+    Rframe {
+        tag: 0,
+        msg: Rmessage::Read(Rread {
+            data: Data(Vec::new()),
+        }),
+    }
+    .byte_size() as usize
+}
+
+// Writes the header of an Rread with tag `tag` and `len` bytes of data to the front of `buf`.
+pub(super) fn encode_rread_header(tag: u16, len: usize, buf: &mut [u8]) -> io::Result<()> {
+    let header = Rframe {
+        tag,
+        msg: Rmessage::Read(Rread {
+            data: Data(Vec::new()),
+        }),
+    };
+    let size = header.encode_to_slice(buf, Dialect::Linux)?;
+    let len = len as u32;
+    let frame_size = size as u32 + len;
+    buf[..4].copy_from_slice(&frame_size.to_le_bytes());
+    buf[size - 4..size].copy_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+// The frame that the data of Rreads is read into. It is kept from one request to the next so
+// that it only has to be allocated once.
+#[derive(Default)]
+pub(super) struct ReadFrame {
+    buf: Vec<u8>,
+    // How much data was read into the frame for the request being handled, if any was.
+    len: Option<usize>,
+}
+
+impl ReadFrame {
+    // Returns the part of the frame that up to `capacity` bytes of data are read into.
+    pub(super) fn data(&mut self, capacity: usize) -> &mut [u8] {
+        let header = rread_header_size();
+        if self.buf.len() < header + capacity {
+            self.buf.resize(header + capacity, 0);
+        }
+        &mut self.buf[header..header + capacity]
+    }
+
+    // Records that `len` bytes of data were read into the frame.
+    pub(super) fn set_len(&mut self, len: usize) {
+        self.len = Some(len);
+    }
+
+    // Returns the frame to send for `response`, if it is an Rread whose data was read into the
+    // frame. Whatever was read into it is of no use for any other reply.
+    pub(super) fn take(&mut self, response: &Option<Rframe>) -> io::Result<Option<&[u8]>> {
+        let (len, tag) = match (self.len.take(), response) {
+            (
+                Some(len),
+                Some(Rframe {
+                    tag,
+                    msg: Rmessage::Read(_),
+                }),
+            ) => (len, *tag),
+            _ => return Ok(None),
+        };
+        encode_rread_header(tag, len, &mut self.buf)?;
+        Ok(Some(&self.buf[..rread_header_size() + len]))
+    }
+}
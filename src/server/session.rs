@@ -0,0 +1,148 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::Fid;
+use super::FidTable;
+use super::FileSystem;
+use super::LockOwner;
+use super::Passthrough;
+use crate::syscall;
+
+// The state of a session whose connection went away, kept until it is resumed or expires.
+pub(super) struct SavedSession<F: FileSystem> {
+    pub(super) fids: FidTable<Fid<F>>,
+    pub(super) locks: BTreeMap<LockOwner, F::Handle>,
+    expires: Instant,
+}
+
+struct Sessions<F: FileSystem> {
+    grace: Duration,
+    saved: BTreeMap<u64, SavedSession<F>>,
+}
+
+impl<F: FileSystem> Sessions<F> {
+    // Drops the sessions that were not resumed in time, closing their files.
+    fn expire(&mut self, now: Instant) {
+        self.saved.retain(|_, session| session.expires > now);
+    }
+}
+
+/// Keeps the fids of sessions whose connection dropped so that a client that reconnects can
+/// resume where it left off. The same store should be given to every `Server` that serves the
+/// same tree.
+///
+/// A client that negotiated the `resume` extension asks for a session id with a Tresume without
+/// a session. If its `Server` is dropped while it has a session id, the open fids are kept in the
+/// store for the grace period. Within that time, a Tresume with the same id on a fresh connection
+/// moves them to the new `Server`.
+pub struct SessionStore<F: FileSystem = Passthrough> {
+    inner: Arc<Mutex<Sessions<F>>>,
+}
+
+impl<F: FileSystem> Clone for SessionStore<F> {
+    fn clone(&self) -> Self {
+        SessionStore {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<F: FileSystem> SessionStore<F> {
+    /// Creates a store that keeps the fids of a dropped session for `grace`.
+    pub fn new(grace: Duration) -> SessionStore<F> {
+        SessionStore {
+            inner: Arc::new(Mutex::new(Sessions {
+                grace,
+                saved: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Returns the number of sessions that are waiting to be resumed.
+    pub fn len(&self) -> usize {
+        let mut sessions = self.inner.lock().unwrap();
+        sessions.expire(Instant::now());
+        sessions.saved.len()
+    }
+
+    /// Returns true if no sessions are waiting to be resumed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the sessions that weren't resumed within the grace period, closing their files.
+    /// `Server::reap_idle` does this for the store of the server.
+    pub fn expire(&self) {
+        self.inner.lock().unwrap().expire(Instant::now());
+    }
+
+    // Returns a new session id. Ids are random so that one client can't easily take over the
+    // session of another, and never 0.
+    pub(super) fn new_id(&self) -> io::Result<u64> {
+        let sessions = self.inner.lock().unwrap();
+        loop {
+            let id = random_u64()?;
+            if id != 0 && !sessions.saved.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    // Keeps the state of session `id` until it is resumed or the grace period runs out.
+    pub(super) fn save(
+        &self,
+        id: u64,
+        fids: FidTable<Fid<F>>,
+        locks: BTreeMap<LockOwner, F::Handle>,
+    ) {
+        let mut sessions = self.inner.lock().unwrap();
+        let now = Instant::now();
+        sessions.expire(now);
+        let expires = now + sessions.grace;
+        sessions.saved.insert(
+            id,
+            SavedSession {
+                fids,
+                locks,
+                expires,
+            },
+        );
+    }
+
+    // Removes and returns the state of session `id`, if it hasn't expired.
+    pub(super) fn take(&self, id: u64) -> Option<SavedSession<F>> {
+        let mut sessions = self.inner.lock().unwrap();
+        sessions.expire(Instant::now());
+        sessions.saved.remove(&id)
+    }
+}
+
+fn random_u64() -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    let mut filled = 0;
+    while filled < buf.len() {
+        // Safe because the kernel only writes to the part of `buf` that we pass it and we check
+        // the return value.
+        let ret = syscall!(unsafe {
+            libc::getrandom(
+                buf[filled..].as_mut_ptr() as *mut libc::c_void,
+                buf.len() - filled,
+                0,
+            )
+        });
+        match ret {
+            Ok(n) => filled += n as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(u64::from_le_bytes(buf))
+}
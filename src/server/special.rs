@@ -0,0 +1,55 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! What clients can do with the FIFOs and unix sockets in the exported trees. Opening a FIFO
+//! waits for the other end to be opened, which on the host may never happen, and sockets belong
+//! to the services of the host.
+
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// What clients can do with FIFOs and unix sockets. As an option it is `open`, `nonblock`,
+/// `refuse` or `hide`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpecialFiles {
+    /// Open them like any other file, which for a FIFO waits until it has another end.
+    #[default]
+    Open,
+    /// Open them with O_NONBLOCK, so that opening a FIFO never waits, and neither do reads and
+    /// writes of it.
+    NonBlock,
+    /// List them and walk to them, but fail to open them with EACCES.
+    Refuse,
+    /// Leave them out of directories altogether, so that clients can neither see them nor
+    /// create them.
+    Hide,
+}
+
+impl FromStr for SpecialFiles {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(SpecialFiles::Open),
+            "nonblock" => Ok(SpecialFiles::NonBlock),
+            "refuse" => Ok(SpecialFiles::Refuse),
+            "hide" => Ok(SpecialFiles::Hide),
+            _ => Err("`special_files` must be one of `open`, `nonblock`, `refuse` or `hide`"),
+        }
+    }
+}
+
+// Returns true if `mode` is that of a file that `SpecialFiles` applies to.
+pub(super) fn is_special(mode: libc::mode_t) -> bool {
+    matches!(mode & libc::S_IFMT, libc::S_IFIFO | libc::S_IFSOCK)
+}
+
+impl SpecialFiles {
+    // Returns true if a file with mode `mode` is left out of directories.
+    pub(super) fn hides(self, mode: libc::mode_t) -> bool {
+        self == SpecialFiles::Hide && is_special(mode)
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Replies to Tread whose data is moved from the file into the transport with splice(2), without
+//! being copied through a buffer on the way.
+
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::ptr;
+
+use super::check_cancelled;
+use super::CancelToken;
+use super::IO_CHUNK_SIZE;
+use crate::syscall;
+
+// A pipe that holds the data of a reply between the file that it came from and the transport.
+// Data can only be spliced between two files when one of them is a pipe.
+pub(super) struct SplicePipe {
+    read: File,
+    write: File,
+    capacity: usize,
+    // How much data is in the pipe.
+    len: usize,
+}
+
+impl SplicePipe {
+    // Creates a pipe that holds at least `size` bytes if the host lets it, though it may hold
+    // less.
+    fn new(size: usize) -> io::Result<SplicePipe> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two file descriptors that pipe2 returns.
+        syscall!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        // SAFETY: pipe2 succeeded, so both are open file descriptors that nothing else owns.
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        // Growing a pipe past /proc/sys/fs/pipe-max-size takes CAP_SYS_RESOURCE, so this is
+        // allowed to fail.
+        let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+        // SAFETY: this only changes the size of a pipe that we own.
+        let _ = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+        // SAFETY: this only returns the size of a pipe that we own.
+        let capacity = syscall!(unsafe { libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ) })?;
+        Ok(SplicePipe {
+            read,
+            write,
+            capacity: capacity as usize,
+            len: 0,
+        })
+    }
+
+    // Returns how much data the pipe can hold.
+    pub(super) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Returns how much data is waiting in the pipe.
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    // Moves up to `len` bytes of `file` from `offset` on into the pipe, which must be able to
+    // hold them so that this never waits for the pipe to be drained. Returns how many there
+    // were, which is only fewer than `len` at the end of the file.
+    pub(super) fn fill(
+        &mut self,
+        file: &File,
+        offset: u64,
+        len: usize,
+        cancel: &CancelToken,
+    ) -> io::Result<usize> {
+        debug_assert!(self.len + len <= self.capacity);
+        let mut count = 0;
+        while count < len {
+            check_cancelled(cancel)?;
+
+            let chunk = min(len - count, IO_CHUNK_SIZE);
+            let mut off = (offset + count as u64) as libc::loff_t;
+            // SAFETY: both are open file descriptors, and `off` outlives the call.
+            let n = syscall!(unsafe {
+                libc::splice(
+                    file.as_raw_fd(),
+                    &mut off,
+                    self.write.as_raw_fd(),
+                    ptr::null_mut(),
+                    chunk,
+                    0,
+                )
+            })? as usize;
+            count += n;
+            self.len += n;
+            if n < chunk {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    // Moves all of the data in the pipe into `writer`. Transports that can't be spliced into are
+    // written to the usual way instead.
+    pub(super) fn drain<W: Write + AsRawFd>(&mut self, writer: &mut W) -> io::Result<()> {
+        while self.len > 0 {
+            // SAFETY: both are open file descriptors.
+            let res = syscall!(unsafe {
+                libc::splice(
+                    self.read.as_raw_fd(),
+                    ptr::null_mut(),
+                    writer.as_raw_fd(),
+                    ptr::null_mut(),
+                    self.len,
+                    libc::SPLICE_F_MOVE,
+                )
+            });
+            match res {
+                Ok(n) => self.len -= n as usize,
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return self.copy(writer),
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => wait_writable(writer)?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn copy<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let mut buf = vec![0u8; self.len];
+        self.read.read_exact(&mut buf)?;
+        self.len = 0;
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+}
+
+// Returns the pipe in `pipe`, creating one that holds `size` bytes if there isn't one yet, or
+// None if it can't be created.
+pub(super) fn pipe(pipe: &mut Option<SplicePipe>, size: usize) -> Option<&mut SplicePipe> {
+    if pipe.is_none() {
+        *pipe = SplicePipe::new(size).ok();
+    }
+    pipe.as_mut()
+}
+
+// Waits until a transport that doesn't block can be written to.
+fn wait_writable<W: AsRawFd>(writer: &W) -> io::Result<()> {
+    let mut fd = libc::pollfd {
+        fd: writer.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    // SAFETY: `fd` outlives the call.
+    match syscall!(unsafe { libc::poll(&mut fd, 1, -1) }) {
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+        res => res.map(drop),
+    }
+}
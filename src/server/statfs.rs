@@ -0,0 +1,112 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! What clients see of the size of an exported tree, when it shouldn't be that of the file
+//! system on the host that holds it.
+
+use std::cmp::min;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::protocol::Rstatfs;
+
+/// Replaces what Tstatfs reports about an export. As an option it is a comma-separated list of
+/// `bytes=N`, `free_bytes=N`, `files=N`, `free_files=N` and `quota`, such as
+/// `quota,files=100000`. Everything left out is what the host reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatfsOverride {
+    /// Reports the quotas of the export as the size of the file system, and what they leave as
+    /// free, rather than only keeping the numbers of the host from going over them.
+    pub quota: bool,
+    /// The size of the file system in bytes.
+    pub bytes: Option<u64>,
+    /// How many of `bytes` are free, to everyone.
+    pub free_bytes: Option<u64>,
+    /// How many files the file system can hold.
+    pub files: Option<u64>,
+    /// How many more files it can hold.
+    pub free_files: Option<u64>,
+}
+
+impl FromStr for StatfsOverride {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut statfs = StatfsOverride::default();
+        for field in s.split(',').filter(|field| !field.is_empty()) {
+            if field == "quota" {
+                statfs.quota = true;
+                continue;
+            }
+            let (name, value) = field
+                .split_once('=')
+                .ok_or("`statfs` fields must be `quota` or of the form `name=number`")?;
+            let value = Some(
+                value
+                    .parse()
+                    .map_err(|_| "`statfs` values must be numbers")?,
+            );
+            match name {
+                "bytes" => statfs.bytes = value,
+                "free_bytes" => statfs.free_bytes = value,
+                "files" => statfs.files = value,
+                "free_files" => statfs.free_files = value,
+                _ => return Err("unrecognized `statfs` field"),
+            }
+        }
+        Ok(statfs)
+    }
+}
+
+impl StatfsOverride {
+    // Replaces the fields of `statfs` that this overrides. Nothing is left reporting more free
+    // than there is in all.
+    pub(super) fn apply(&self, mut statfs: Rstatfs) -> Rstatfs {
+        let bsize = u64::from(statfs.bsize.max(1));
+        if let Some(bytes) = self.bytes {
+            statfs.blocks = bytes / bsize;
+        }
+        if let Some(free) = self.free_bytes {
+            statfs.bfree = free / bsize;
+            statfs.bavail = free / bsize;
+        }
+        if let Some(files) = self.files {
+            statfs.files = files;
+        }
+        if let Some(free) = self.free_files {
+            statfs.ffree = free;
+        }
+
+        statfs.bfree = min(statfs.bfree, statfs.blocks);
+        statfs.bavail = min(statfs.bavail, statfs.bfree);
+        statfs.ffree = min(statfs.ffree, statfs.files);
+        statfs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let statfs: StatfsOverride = "quota,bytes=4096,free_files=3".parse().unwrap();
+        assert_eq!(
+            statfs,
+            StatfsOverride {
+                quota: true,
+                bytes: Some(4096),
+                free_bytes: None,
+                files: None,
+                free_files: Some(3),
+            }
+        );
+        assert_eq!("".parse(), Ok(StatfsOverride::default()));
+        for bad in ["bytes", "bytes=x", "size=1", "quota=1"] {
+            assert!(bad.parse::<StatfsOverride>().is_err(), "{bad}");
+        }
+    }
+}
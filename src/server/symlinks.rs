@@ -0,0 +1,141 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! What clients see of the absolute targets of symlinks. Those are paths on the host, which
+//! the client resolves in its own tree, where the export is mounted somewhere else entirely.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::Component;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// What clients see of a symlink whose target is an absolute path. As an option it is `keep`,
+/// `relative` or `contained`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AbsoluteSymlinks {
+    /// The target as it is.
+    #[default]
+    Keep,
+    /// A relative target for one that is under the root of the export, which then leads to the
+    /// same file in the tree of the client. Other targets are kept as they are.
+    Relative,
+    /// Like `Relative`, but the targets of others can't be read at all, as though clients
+    /// weren't allowed to, and 9P2000.u stats show them with an empty target.
+    Contained,
+}
+
+impl FromStr for AbsoluteSymlinks {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(AbsoluteSymlinks::Keep),
+            "relative" => Ok(AbsoluteSymlinks::Relative),
+            "contained" => Ok(AbsoluteSymlinks::Contained),
+            _ => Err("`absolute_symlinks` must be one of `keep`, `relative` or `contained`"),
+        }
+    }
+}
+
+// Returns the components of the absolute path `path`, with `.` and `..` resolved the way they
+// would be if none of the others were symlinks.
+fn components(path: &[u8]) -> Vec<&[u8]> {
+    let mut components = Vec::new();
+    for component in path.split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components
+}
+
+impl AbsoluteSymlinks {
+    // Returns the target that clients see for the symlink at `link`, which is its path from the
+    // root of the export at `root` on the host, when its target is `target`. Returns None if
+    // clients can't see it at all.
+    pub(super) fn target(self, root: &Path, link: &Path, target: Vec<u8>) -> Option<Vec<u8>> {
+        if self == AbsoluteSymlinks::Keep || !target.starts_with(b"/") {
+            return Some(target);
+        }
+
+        let root = components(root.as_os_str().as_bytes());
+        let components = components(&target);
+        if !components.starts_with(&root) {
+            return match self {
+                AbsoluteSymlinks::Contained => None,
+                _ => Some(target),
+            };
+        }
+
+        // The target is reached by going up from the directory of the link to the root of the
+        // export, and down from there.
+        let depth = link.parent().map_or(0, |dir| {
+            dir.components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .count()
+        });
+        let mut relative: Vec<&[u8]> = vec![b".."; depth];
+        relative.extend(&components[root.len()..]);
+        if relative.is_empty() {
+            return Some(b".".to_vec());
+        }
+        Some(relative.join(&b'/'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_targets() {
+        let root = Path::new("/data");
+        let target = |policy: AbsoluteSymlinks, link: &str, target: &str| {
+            policy
+                .target(root, Path::new(link), target.as_bytes().to_vec())
+                .map(|t| String::from_utf8(t).unwrap())
+        };
+
+        let relative = AbsoluteSymlinks::Relative;
+        assert_eq!(target(relative, "/link", "/data/foo"), Some("foo".into()));
+        assert_eq!(
+            target(relative, "/a/b/link", "/data/foo/bar"),
+            Some("../../foo/bar".into())
+        );
+        assert_eq!(target(relative, "/a/link", "/data"), Some("..".into()));
+        assert_eq!(target(relative, "/link", "/data/"), Some(".".into()));
+        assert_eq!(
+            target(relative, "/link", "//data/./x/../foo"),
+            Some("foo".into())
+        );
+        assert_eq!(target(relative, "/link", "foo/bar"), Some("foo/bar".into()));
+        assert_eq!(
+            target(relative, "/link", "/etc/passwd"),
+            Some("/etc/passwd".into())
+        );
+        assert_eq!(
+            target(relative, "/link", "/database"),
+            Some("/database".into())
+        );
+        assert_eq!(
+            target(relative, "/link", "/data/../etc"),
+            Some("/data/../etc".into())
+        );
+
+        let contained = AbsoluteSymlinks::Contained;
+        assert_eq!(target(contained, "/link", "/etc/passwd"), None);
+        assert_eq!(target(contained, "/link", "/data/foo"), Some("foo".into()));
+        assert_eq!(target(contained, "/link", "../foo"), Some("../foo".into()));
+
+        let keep = AbsoluteSymlinks::Keep;
+        assert_eq!(target(keep, "/link", "/data/foo"), Some("/data/foo".into()));
+    }
+}
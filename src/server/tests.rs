@@ -14,13 +14,20 @@ use std::io;
 use std::io::Cursor;
 use std::mem;
 use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::symlink;
+use std::os::unix::fs::FileExt;
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
 use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
-use std::u32;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
 
 use super::*;
 
@@ -224,7 +231,7 @@ fn check_attr(server: &mut Server, fid: u32, md: &fs::Metadata) {
     assert_eq!(rgetattr.data_version, 0);
 }
 
-fn check_content(server: &mut Server, content: &[u8], fid: u32) {
+fn check_content<F: FileSystem>(server: &mut Server<F>, content: &[u8], fid: u32) {
     for offset in 0..content.len() {
         let tread = Tread {
             fid,
@@ -349,14 +356,14 @@ fn create<P: Into<PathBuf>>(
     server.lcreate(tlcreate)
 }
 
-struct Readdir<'a> {
-    server: &'a mut Server,
+struct Readdir<'a, F: FileSystem = Passthrough> {
+    server: &'a mut Server<F>,
     fid: u32,
     offset: u64,
     cursor: Cursor<Vec<u8>>,
 }
 
-impl<'a> Iterator for Readdir<'a> {
+impl<'a, F: FileSystem> Iterator for Readdir<'a, F> {
     type Item = Dirent;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -386,7 +393,7 @@ impl<'a> Iterator for Readdir<'a> {
     }
 }
 
-fn readdir(server: &mut Server, fid: u32) -> Readdir {
+fn readdir<F: FileSystem>(server: &mut Server<F>, fid: u32) -> Readdir<'_, F> {
     Readdir {
         server,
         fid,
@@ -399,6 +406,21 @@ fn readdir(server: &mut Server, fid: u32) -> Readdir {
 // directory to act as the server root and sends an initial Tattach message.
 // At the end of setup, fid 1 points to the root of the server.
 fn setup<P: AsRef<Path>>(name: P) -> (ScopedPath<OsString>, Server) {
+    setup_version(name, "9P2000.L")
+}
+
+// Like `setup` but negotiates the protocol dialect identified by `version`.
+fn setup_version<P: AsRef<Path>>(name: P, version: &str) -> (ScopedPath<OsString>, Server) {
+    setup_config(name, version, Default::default())
+}
+
+// Like `setup_version` but creates the server with `cfg`, with the temporary directory as its
+// root.
+fn setup_config<P: AsRef<Path>>(
+    name: P,
+    version: &str,
+    cfg: Config,
+) -> (ScopedPath<OsString>, Server) {
     let mut test_dir = env::var_os("T")
         .map(PathBuf::from)
         .unwrap_or_else(env::temp_dir);
@@ -465,19 +487,22 @@ fn setup<P: AsRef<Path>>(name: P) -> (ScopedPath<OsString>, Server) {
         .symlink_metadata()
         .expect("failed to get metadata for root dir");
 
-    let mut server = Server::new(&*test_dir, Default::default(), Default::default())
-        .expect("Failed to create server");
+    let mut server = Server::with_config(Config {
+        root: Path::new(&*test_dir).into(),
+        ..cfg
+    })
+    .expect("Failed to create server");
 
     let tversion = Tversion {
         msize: DEFAULT_BUFFER_SIZE,
-        version: P9String::new("9P2000.L").unwrap(),
+        version: P9String::new(version).unwrap(),
     };
 
     let rversion = server
         .version(&tversion)
         .expect("failed to get version from server");
     assert_eq!(rversion.msize, DEFAULT_BUFFER_SIZE);
-    assert_eq!(rversion.version, "9P2000.L");
+    assert_eq!(rversion.version, version);
 
     let tattach = Tattach {
         fid: ROOT_FID,
@@ -545,6 +570,194 @@ fn get_attr() {
     check_attr(&mut server, ROOT_FID, &md);
 }
 
+#[test]
+fn qid_map() {
+    // Files on the first device that the map sees keep their inode numbers as their paths.
+    let map = QidMap::new();
+    let mut tmpfs = setup_filesystem(Tmpfs::new(), Default::default());
+    tmpfs.set_qid_map(map.clone());
+    let rlcreate = tmpfs
+        .lcreate(Tlcreate {
+            fid: ROOT_FID,
+            name: P9String::new("f").unwrap(),
+            flags: P9_RDWR,
+            mode: 0o644,
+            gid: u32::MAX,
+        })
+        .expect("failed to create file");
+    let st = tmpfs
+        .filesystem()
+        .stat(&lock_fid(&tmpfs.fids.get(ROOT_FID).unwrap()).path)
+        .unwrap();
+    assert_eq!(rlcreate.qid.path, st.st_ino);
+
+    // Those on other devices get paths of their own, wherever the qid comes from.
+    let (test_dir, mut server) = setup("qid_map");
+    server.set_qid_map(map);
+    let fid = ROOT_FID + 1;
+    let rwalk = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: fid,
+            wnames: vec![
+                P9String::new("subdir").unwrap(),
+                P9String::new("b").unwrap(),
+            ],
+        })
+        .expect("failed to walk");
+    let ino = fs::symlink_metadata(test_dir.join("subdir/b"))
+        .unwrap()
+        .ino();
+    assert_eq!(rwalk.wqids[1].path, 1 << 48 | ino);
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid,
+            request_mask: P9_GETATTR_BASIC,
+        })
+        .expect("failed to get attributes");
+    assert_eq!(rgetattr.qid.path, rwalk.wqids[1].path);
+}
+
+#[test]
+fn persistent_qid_map() {
+    let (test_dir, mut server) = setup("persistent_qid_map");
+    let map_path = test_dir.join("qids");
+    let walk_qid = |server: &mut Server, map| {
+        server.set_qid_map(map);
+        let rwalk = server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: ROOT_FID + 1,
+                wnames: vec![P9String::new("subdir").unwrap()],
+            })
+            .expect("failed to walk");
+        server.clunk(&Tclunk { fid: ROOT_FID + 1 }).unwrap();
+        rwalk.wqids[0]
+    };
+
+    // The tree is on the second device that the map sees, and still is after a restart even
+    // though it is the first one seen then.
+    let map = QidMap::open(&map_path).expect("failed to open qid map");
+    let mut tmpfs = setup_filesystem(Tmpfs::new(), Default::default());
+    tmpfs.set_qid_map(map.clone());
+    tmpfs
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("d").unwrap(),
+            mode: 0o755,
+            gid: u32::MAX,
+        })
+        .expect("failed to create directory");
+    let qid = walk_qid(&mut server, map);
+    assert_eq!(qid.path >> 48, 1);
+    let map = QidMap::open(&map_path).expect("failed to reopen qid map");
+    assert_eq!(walk_qid(&mut server, map).path, qid.path);
+
+    fs::write(&map_path, "d 1 2\n").unwrap();
+    let err = QidMap::open(&map_path).err();
+    assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn get_attr_request_mask() {
+    let (test_dir, mut server) = setup("get_attr_request_mask");
+
+    let md = test_dir
+        .symlink_metadata()
+        .expect("failed to get metadata for test dir");
+
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: ROOT_FID,
+            request_mask: P9_GETATTR_SIZE | P9_GETATTR_MTIME,
+        })
+        .expect("failed to call get_attr");
+    check_qid(&rgetattr.qid, &md);
+    assert_eq!(rgetattr.size, md.size());
+    assert_eq!(rgetattr.mtime_sec, md.mtime() as u64);
+    if cfg!(all(target_os = "linux", target_env = "gnu")) {
+        assert_eq!(rgetattr.valid, P9_GETATTR_SIZE | P9_GETATTR_MTIME);
+        assert_eq!(rgetattr.mode, 0);
+        assert_eq!(rgetattr.nlink, 0);
+    }
+
+    // Fields that can't be provided are left out of `valid`.
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: ROOT_FID,
+            request_mask: u64::MAX,
+        })
+        .expect("failed to call get_attr");
+    assert_eq!(rgetattr.valid & P9_GETATTR_BASIC, P9_GETATTR_BASIC);
+    assert_eq!(rgetattr.valid & !(P9_GETATTR_BASIC | P9_GETATTR_BTIME), 0);
+    assert_eq!(rgetattr.mode, md.mode());
+}
+
+#[test]
+fn get_attr_btime() {
+    let (test_dir, mut server) = setup("get_attr_btime");
+
+    let md = test_dir
+        .symlink_metadata()
+        .expect("failed to get metadata for test dir");
+
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: ROOT_FID,
+            request_mask: P9_GETATTR_BASIC | P9_GETATTR_BTIME,
+        })
+        .expect("failed to call get_attr");
+
+    // Birth times come from statx, and only some file systems keep them.
+    match md.created() {
+        Ok(created) if cfg!(all(target_os = "linux", target_env = "gnu")) => {
+            let created = created
+                .duration_since(UNIX_EPOCH)
+                .expect("birth time is before the epoch");
+            assert_eq!(rgetattr.valid & P9_GETATTR_BTIME, P9_GETATTR_BTIME);
+            assert_eq!(rgetattr.btime_sec, created.as_secs());
+            assert_eq!(rgetattr.btime_nsec, u64::from(created.subsec_nanos()));
+        }
+        _ => {
+            assert_eq!(rgetattr.valid & P9_GETATTR_BTIME, 0);
+            assert_eq!(rgetattr.btime_sec, 0);
+            assert_eq!(rgetattr.btime_nsec, 0);
+        }
+    }
+}
+
+#[test]
+fn get_attr_inode_generation() {
+    let (_test_dir, mut server) = setup("get_attr_inode_generation");
+
+    let tgetattr = Tgetattr {
+        fid: ROOT_FID,
+        request_mask: P9_GETATTR_BASIC | P9_GETATTR_GEN,
+    };
+    let rgetattr = server.get_attr(&tgetattr).expect("failed to call get_attr");
+    assert_eq!(rgetattr.valid & P9_GETATTR_GEN, 0);
+    assert_eq!(rgetattr.gen, 0);
+
+    let cfg: Config = "inode_generation=true"
+        .parse()
+        .expect("failed to parse config");
+    assert!(cfg.inode_generation);
+    let (test_dir, mut server) = setup_config("get_attr_inode_generation_enabled", "9P2000.L", cfg);
+
+    // Not every file system that the tests may run on keeps generation numbers, so only check
+    // that the server agrees with the ioctl.
+    let dir = File::open(&*test_dir).expect("failed to open test dir");
+    let rgetattr = server.get_attr(&tgetattr).expect("failed to call get_attr");
+    match get_generation(&dir) {
+        Ok(gen) => {
+            assert_eq!(rgetattr.valid & P9_GETATTR_GEN, P9_GETATTR_GEN);
+            assert_eq!(rgetattr.gen, gen);
+        }
+        Err(_) => assert_eq!(rgetattr.valid & P9_GETATTR_GEN, 0),
+    }
+    assert_eq!(rgetattr.valid & P9_GETATTR_BASIC, P9_GETATTR_BASIC);
+}
+
 #[test]
 fn tree_walk() {
     let (test_dir, mut server) = setup("readdir");
@@ -608,747 +821,7394 @@ fn tree_walk() {
     }
 }
 
+// Returns the file status flags of `f`.
+fn fd_flags(f: &File) -> libc::c_int {
+    // Safe because this doesn't touch any memory and the return value is checked.
+    let flags = unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFL) };
+    assert!(flags >= 0, "failed to get file status flags");
+    flags
+}
+
 #[test]
-fn create_existing_file() {
-    let (test_dir, mut server) = setup("create_existing");
+fn unopened_fids_use_o_path() {
+    let (test_dir, mut server) = setup("unopened_fids");
+    let fid = ROOT_FID + 1;
+    let names = vec![
+        P9String::new("subdir").unwrap(),
+        P9String::new("b").unwrap(),
+    ];
+    walk(&mut server, &*test_dir, ROOT_FID, fid, names);
 
-    let name = "existing";
-    create_local_file(&test_dir, name);
+    // Walking only takes an O_PATH descriptor, which can't be used for I/O.
+    assert_ne!(
+        fd_flags(&lock_fid(&server.fids.get(fid).unwrap()).path) & libc::O_PATH,
+        0
+    );
+    assert!(lock_fid(&server.fids.get(fid).unwrap()).file.is_none());
 
-    let fid = ROOT_FID + 1;
+    // Opening the fid reopens the file for real and keeps the O_PATH one for the inode.
+    server
+        .lopen(&Tlopen {
+            fid,
+            flags: P9_RDONLY,
+        })
+        .expect("failed to open file");
+    assert_ne!(
+        fd_flags(&lock_fid(&server.fids.get(fid).unwrap()).path) & libc::O_PATH,
+        0
+    );
+    let f = server.fids.get(fid).unwrap();
+    let file = lock_fid(&f).file.as_ref().map(fd_flags);
+    assert_eq!(file.expect("fid has no open file") & libc::O_PATH, 0);
+    check_content(&mut server, b"hello, world!", fid);
+}
+
+#[test]
+fn max_open_files() {
+    let cfg: Config = "max_open_files=1".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("max_open_files", "9P2000.L", cfg);
+
+    let (a, b) = (ROOT_FID + 1, ROOT_FID + 2);
     create(
         &mut server,
         &*test_dir,
         ROOT_FID,
-        fid,
-        name,
-        P9_APPEND,
+        a,
+        "a",
+        P9_RDWR | P9_TRUNC,
         0o644,
     )
-    .expect_err("successfully created existing file");
-}
-
-enum SetAttrKind {
-    File,
-    Directory,
-}
-
-fn set_attr_test<F>(kind: SetAttrKind, set_fields: F) -> io::Result<fs::Metadata>
-where
-    F: FnOnce(&mut Tsetattr),
-{
-    let (test_dir, mut server) = setup("set_attr");
-
-    let name = "existing";
-    match kind {
-        SetAttrKind::File => {
-            create_local_file(&test_dir, name);
-        }
-        SetAttrKind::Directory => {
-            let tmkdir = Tmkdir {
-                dfid: ROOT_FID,
-                name: P9String::new(name).unwrap(),
-                mode: 0o755,
-                gid: 0,
-            };
-
-            let rmkdir = server.mkdir(tmkdir).expect("failed to create directory");
-            let md = fs::symlink_metadata(test_dir.join(name))
-                .expect("failed to get metadata for directory");
-
-            assert!(md.is_dir());
-            check_qid(&rmkdir.qid, &md);
-        }
+    .expect("failed to create a");
+    let write = |server: &mut Server, fid, offset, data: &[u8]| {
+        server
+            .write(&Twrite {
+                fid,
+                offset,
+                data: Data(data.to_vec()),
+            })
+            .expect("failed to write file");
     };
+    write(&mut server, a, 0, b"hello");
 
-    let fid = ROOT_FID + 1;
-    walk(
+    // Opening a second file closes the first one, which is reopened without truncating it when
+    // it is used again.
+    create(
         &mut server,
         &*test_dir,
         ROOT_FID,
-        fid,
-        vec![P9String::new(name).unwrap()],
-    );
+        b,
+        "b",
+        P9_RDWR | P9_TRUNC,
+        0o644,
+    )
+    .expect("failed to create b");
+    assert!(lock_fid(&server.fids.get(a).unwrap()).file.is_none());
+    assert!(lock_fid(&server.fids.get(b).unwrap()).file.is_some());
 
-    let mut tsetattr = Tsetattr {
-        fid,
-        valid: 0,
-        mode: 0,
-        uid: 0,
-        gid: 0,
-        size: 0,
-        atime_sec: 0,
-        atime_nsec: 0,
-        mtime_sec: 0,
-        mtime_nsec: 0,
-    };
+    write(&mut server, a, 5, b", world!");
+    assert!(lock_fid(&server.fids.get(a).unwrap()).file.is_some());
+    assert!(lock_fid(&server.fids.get(b).unwrap()).file.is_none());
+    check_content(&mut server, b"hello, world!", a);
 
-    set_fields(&mut tsetattr);
-    server.set_attr(&tsetattr)?;
+    write(&mut server, b, 0, b"bye");
+    check_content(&mut server, b"bye", b);
+    assert_eq!(
+        fs::read(test_dir.join("a")).expect("failed to read a"),
+        b"hello, world!"
+    );
 
-    fs::symlink_metadata(test_dir.join(name))
+    server.clunk(&Tclunk { fid: b }).expect("failed to clunk b");
+    assert!(!server.fd_cache.is_evicted(b));
+    check_content(&mut server, b"hello, world!", a);
 }
 
 #[test]
-fn set_len() {
-    let len = 661;
-    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_SIZE;
-        tsetattr.size = len;
-    })
-    .expect("failed to run set length of file");
+fn no_cross_mounts() {
+    // /proc is always a mount of its own.
+    let walk_proc = |no_cross_mounts| {
+        let cfg = Config {
+            no_cross_mounts,
+            ..Default::default()
+        };
+        let fs = Passthrough::new(&cfg).expect("failed to create passthrough file system");
+        let mut server = setup_filesystem(fs, cfg);
+        server.walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![P9String::new("proc").unwrap()],
+        })
+    };
 
-    assert_eq!(md.size(), len);
+    walk_proc(false).expect("failed to walk into /proc");
+    let err = walk_proc(true).expect_err("walked into /proc");
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
 }
 
 #[test]
-fn set_file_mode() {
-    let mode = 0o640;
-    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_MODE;
-        tsetattr.mode = mode;
-    })
-    .expect("failed to set mode");
-
-    assert_eq!(md.mode() & 0o777, mode);
+fn lookups_stay_beneath_root() {
+    let (test_dir, mut server) = setup("lookups_beneath");
+    let root = fs::symlink_metadata(&*test_dir).expect("failed to get metadata for root");
+
+    // Going up stops at the root of the export.
+    let rwalk = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![
+                P9String::new("subdir").unwrap(),
+                P9String::new("..").unwrap(),
+                P9String::new("..").unwrap(),
+            ],
+        })
+        .expect("failed to walk up");
+    check_qid(&rwalk.wqids[1], &root);
+    check_qid(&rwalk.wqids[2], &root);
+
+    // Names that are really paths could lead anywhere.
+    let err = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 2,
+            wnames: vec![P9String::new("subdir/../..").unwrap()],
+        })
+        .expect_err("walked a path");
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+
+    let err = create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        ROOT_FID + 3,
+        "../escaped",
+        P9_RDWR,
+        0o644,
+    )
+    .expect_err("created a file outside of the export");
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+
+    let err = server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: P9String::new("世界.txt").unwrap(),
+            newdirfid: ROOT_FID,
+            newname: P9String::new("../escaped").unwrap(),
+        })
+        .expect_err("renamed a file out of the export");
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+    assert!(test_dir.join("世界.txt").exists());
 }
 
 #[test]
-fn set_file_mtime() {
-    let (secs, nanos) = (1245247825, 524617);
-    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET;
-        tsetattr.mtime_sec = secs;
-        tsetattr.mtime_nsec = nanos;
-    })
-    .expect("failed to set mtime");
+fn ascii_casefold() {
+    let cfg: Config = "ascii_casefold=true:hide=secret*"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("ascii_casefold", "9P2000.L", cfg);
+    create_local_file(&test_dir, "Foo");
+    create_local_file(&test_dir, "Secret");
+    let names = || {
+        let mut names: Vec<String> = fs::read_dir(&*test_dir)
+            .expect("failed to read directory")
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+    let before = names();
+
+    // Walks find files whatever the case of their names, except for hidden ones.
+    let rwalk = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![
+                P9String::new("SUBDIR").unwrap(),
+                P9String::new("B").unwrap(),
+            ],
+        })
+        .expect("failed to walk");
+    check_qid(
+        &rwalk.wqids[1],
+        &fs::symlink_metadata(test_dir.join("subdir/b")).unwrap(),
+    );
+    let err = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 2,
+            wnames: vec![P9String::new("SECRET").unwrap()],
+        })
+        .expect_err("walked to a hidden file");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    // New files can't differ from existing ones only in case.
+    let err = create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        ROOT_FID + 2,
+        "fOO",
+        P9_RDWR,
+        0o644,
+    )
+    .expect_err("created a file that only differs in case");
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    let err = server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("FOO").unwrap(),
+            mode: 0o755,
+            gid: u32::MAX,
+        })
+        .expect_err("created a directory that only differs in case");
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    assert_eq!(names(), before);
+
+    // Renames replace files whatever the case of their names, or change the case of the file
+    // itself.
+    let rename = |server: &mut Server, oldname: &str, newname: &str| {
+        server.rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: P9String::new(oldname).unwrap(),
+            newdirfid: ROOT_FID,
+            newname: P9String::new(newname).unwrap(),
+        })
+    };
+    rename(&mut server, "foo", "FOO").expect("failed to change the case of a file");
+    assert!(names().contains(&"FOO".to_string()));
+    create_local_file(&test_dir, "bar");
+    rename(&mut server, "foo", "BAR").expect("failed to rename file");
+    assert!(!names().contains(&"FOO".to_string()));
+    assert!(names().contains(&"bar".to_string()));
 
-    assert_eq!(md.mtime() as u64, secs);
-    assert_eq!(md.mtime_nsec() as u64, nanos);
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: P9String::new("Bar").unwrap(),
+            flags: 0,
+        })
+        .expect("failed to unlink file");
+    assert!(!test_dir.join("bar").exists());
 }
 
 #[test]
-fn set_file_atime() {
-    let (secs, nanos) = (9247605, 4016);
-    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_ATIME | P9_SETATTR_ATIME_SET;
-        tsetattr.atime_sec = secs;
-        tsetattr.atime_nsec = nanos;
-    })
-    .expect("failed to set atime");
-
-    assert_eq!(md.atime() as u64, secs);
-    assert_eq!(md.atime_nsec() as u64, nanos);
-}
+fn normalize_names() {
+    let cfg: Config = "normalize_names=nfc:hide=secr\u{e9}t"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("normalize_names", "9P2000.L", cfg);
+    // The way macOS would name them.
+    create_local_file(&test_dir, "cafe\u{301}");
+    create_local_file(&test_dir, "secre\u{301}t");
+
+    let mut walk_to = |name: &str, newfid| {
+        server.walk(Twalk {
+            fid: ROOT_FID,
+            newfid,
+            wnames: vec![P9String::new(name).unwrap()],
+        })
+    };
+    let rwalk = walk_to("caf\u{e9}", ROOT_FID + 1).expect("failed to walk");
+    check_qid(
+        &rwalk.wqids[0],
+        &fs::symlink_metadata(test_dir.join("cafe\u{301}")).unwrap(),
+    );
+    let err = walk_to("secr\u{e9}t", ROOT_FID + 2).expect_err("walked to a hidden file");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
 
-#[test]
-fn set_dir_mode() {
-    let mode = 0o640;
-    let md = set_attr_test(SetAttrKind::Directory, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_MODE;
-        tsetattr.mode = mode;
-    })
-    .expect("failed to set mode");
+    // New files get composed names, and can't just be another form of an existing name.
+    let err = create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        ROOT_FID + 2,
+        "caf\u{e9}",
+        P9_RDWR,
+        0o644,
+    )
+    .expect_err("created a file that only differs in normal form");
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("nai\u{308}ve").unwrap(),
+            mode: 0o755,
+            gid: u32::MAX,
+        })
+        .expect("failed to create directory");
+    assert!(test_dir.join("na\u{ef}ve").is_dir());
 
-    assert_eq!(md.mode() & 0o777, mode);
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: P9String::new("caf\u{e9}").unwrap(),
+            flags: 0,
+        })
+        .expect("failed to unlink file");
+    assert!(!test_dir.join("cafe\u{301}").exists());
+
+    assert!("normalize_names=nfkc".parse::<Config>().is_err());
 }
 
 #[test]
-fn set_dir_mtime() {
-    let (secs, nanos) = (1245247825, 524617);
-    let md = set_attr_test(SetAttrKind::Directory, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET;
-        tsetattr.mtime_sec = secs;
-        tsetattr.mtime_nsec = nanos;
-    })
-    .expect("failed to set mtime");
-
-    assert_eq!(md.mtime() as u64, secs);
-    assert_eq!(md.mtime_nsec() as u64, nanos);
-}
+fn max_file_size() {
+    let cfg: Config = "max_file_size=10".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("max_file_size", "9P2000.L", cfg);
 
-#[test]
-fn set_dir_atime() {
-    let (secs, nanos) = (9247605, 4016);
-    let md = set_attr_test(SetAttrKind::Directory, |tsetattr| {
-        tsetattr.valid = P9_SETATTR_ATIME | P9_SETATTR_ATIME_SET;
-        tsetattr.atime_sec = secs;
-        tsetattr.atime_nsec = nanos;
-    })
-    .expect("failed to set atime");
+    let fid = ROOT_FID + 1;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "f", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    let mut write = |offset, data: &[u8]| {
+        server.write(&Twrite {
+            fid,
+            offset,
+            data: Data(data.to_vec()),
+        })
+    };
+    assert_eq!(write(0, b"hello, ").expect("failed to write").count, 7);
+    assert_eq!(write(7, b"world!").expect("failed to write").count, 3);
+    let err = write(10, b"!").expect_err("wrote past the maximum size");
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    assert_eq!(
+        fs::read(test_dir.join("f")).expect("failed to read file"),
+        b"hello, wor"
+    );
 
-    assert_eq!(md.atime() as u64, secs);
-    assert_eq!(md.atime_nsec() as u64, nanos);
+    let mut truncate = |size| {
+        server.set_attr(&Tsetattr {
+            fid,
+            valid: P9_SETATTR_SIZE,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            size,
+            atime_sec: 0,
+            atime_nsec: 0,
+            mtime_sec: 0,
+            mtime_nsec: 0,
+        })
+    };
+    let err = truncate(11).expect_err("grew a file past the maximum size");
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    truncate(5).expect("failed to shrink file");
 }
 
 #[test]
-fn huge_directory() {
-    let (test_dir, mut server) = setup("huge_directory");
-
-    let name = "newdir";
-    let newdir = test_dir.join(name);
-    fs::create_dir(&newdir).expect("failed to create directory");
+fn append_and_noatime() {
+    let cfg: Config = "max_file_size=10".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("append_and_noatime", "9P2000.L", cfg);
 
-    let dfid = ROOT_FID + 1;
-    walk(
+    // Appends go to the end of the file whatever their offset, and stop at the maximum size.
+    let fid = ROOT_FID + 1;
+    create(
         &mut server,
         &*test_dir,
         ROOT_FID,
-        dfid,
-        vec![P9String::new(name).unwrap()],
+        fid,
+        "f",
+        P9_WRONLY | P9_APPEND | P9_NOATIME,
+        0o644,
+    )
+    .expect("failed to create file");
+    let mut write = |data: &[u8]| {
+        server.write(&Twrite {
+            fid,
+            offset: 0,
+            data: Data(data.to_vec()),
+        })
+    };
+    assert_eq!(write(b"hello, ").expect("failed to write").count, 7);
+    assert_eq!(write(b"world!").expect("failed to write").count, 3);
+    let err = write(b"!").expect_err("appended past the maximum size");
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    assert_eq!(
+        fs::read(test_dir.join("f")).expect("failed to read file"),
+        b"hello, wor"
+    );
+
+    // O_NOATIME is dropped unless the server is asked to keep it.
+    assert_eq!(
+        lock_fid(&server.fids.get(fid).unwrap()).open_flags & P9_NOATIME,
+        0
+    );
+    let cfg: Config = "noatime=true".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("noatime", "9P2000.L", cfg);
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        "f",
+        P9_RDWR | P9_NOATIME,
+        0o644,
+    )
+    .expect("failed to create file");
+    assert_ne!(
+        lock_fid(&server.fids.get(fid).unwrap()).open_flags & P9_NOATIME,
+        0
+    );
+
+    assert!("noatime=yes".parse::<Config>().is_err());
+}
+
+#[test]
+fn statfs_override() {
+    let cfg: Config = "statfs=bytes=1048576,free_bytes=4194304,files=100"
+        .parse()
+        .expect("failed to parse config");
+    let (_test_dir, mut server) = setup_config("statfs_override", "9P2000.L", cfg);
+    let statfs = server
+        .statfs(&Tstatfs { fid: ROOT_FID })
+        .expect("failed to statfs");
+    let blocks = 1048576 / u64::from(statfs.bsize);
+    assert_eq!(statfs.blocks, blocks);
+    // There is never more free than there is in all.
+    assert_eq!((statfs.bfree, statfs.bavail), (blocks, blocks));
+    assert_eq!(statfs.files, 100);
+    assert!(statfs.ffree <= 100);
+
+    // The default tree has 6 files, the root included.
+    let cfg: Config = "quota_inodes=1000000000:statfs=quota"
+        .parse()
+        .expect("failed to parse config");
+    let (_test_dir, mut server) = setup_config("statfs_override_quota", "9P2000.L", cfg);
+    let statfs = server
+        .statfs(&Tstatfs { fid: ROOT_FID })
+        .expect("failed to statfs");
+    assert_eq!(statfs.files, 1000000000);
+    assert_eq!(statfs.ffree, 1000000000 - 6);
+}
+
+#[test]
+fn quotas() {
+    let cfg: Config = "quota_bytes=66:quota_inodes=8"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("quotas", "9P2000.L", cfg);
+
+    // The tree that the server starts with has 6 files, the root included, with 56 bytes in
+    // them, so that there is room for 2 more files and 10 more bytes.
+    let fid = ROOT_FID + 1;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "f", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    let write = |server: &mut Server, fid, offset, data: &[u8]| {
+        server.write(&Twrite {
+            fid,
+            offset,
+            data: Data(data.to_vec()),
+        })
+    };
+    assert_eq!(
+        write(&mut server, fid, 0, b"hello, ")
+            .expect("failed to write")
+            .count,
+        7
+    );
+    assert_eq!(
+        write(&mut server, fid, 7, b"world!")
+            .expect("failed to write")
+            .count,
+        3
+    );
+    let err = write(&mut server, fid, 10, b"!").expect_err("wrote past the quota");
+    assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+    // Overwriting data that is already there doesn't use up any more.
+    assert_eq!(
+        write(&mut server, fid, 0, b"HELLO")
+            .expect("failed to write")
+            .count,
+        5
+    );
+
+    let statfs = server
+        .statfs(&Tstatfs { fid: ROOT_FID })
+        .expect("failed to statfs");
+    assert_eq!(statfs.files, 8);
+    assert_eq!(statfs.ffree, 1);
+    assert_eq!(statfs.bavail, 0);
+
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("d").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })
+        .expect("failed to create directory");
+    let err = create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid + 1,
+        "g",
+        P9_RDWR,
+        0o644,
+    )
+    .expect_err("created a file past the quota");
+    assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+
+    // Removing a file gives back both its space and its inode.
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: P9String::new("f").unwrap(),
+            flags: 0,
+        })
+        .expect("failed to unlink file");
+    let fid = fid + 2;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "g", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    assert_eq!(
+        write(&mut server, fid, 0, b"0123456789")
+            .expect("failed to write")
+            .count,
+        10
+    );
+}
+
+#[test]
+fn create_umask() {
+    let cfg: Config = "umask=027:mknod=fifos"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("create_umask", "9P2000.L", cfg);
+    let mode = |name: &str| {
+        fs::symlink_metadata(test_dir.join(name))
+            .expect("failed to stat file")
+            .mode()
+            & 0o7777
+    };
+
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        ROOT_FID + 1,
+        "f",
+        P9_RDWR,
+        0o666,
+    )
+    .expect("failed to create file");
+    assert_eq!(mode("f"), 0o640);
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("d").unwrap(),
+            mode: 0o777,
+            gid: u32::MAX,
+        })
+        .expect("failed to create directory");
+    assert_eq!(mode("d"), 0o750);
+    server
+        .mknod(&Tmknod {
+            dfid: ROOT_FID,
+            name: P9String::new("p").unwrap(),
+            mode: libc::S_IFIFO | 0o666,
+            major: 0,
+            minor: 0,
+            gid: u32::MAX,
+        })
+        .expect("failed to create FIFO");
+    assert_eq!(mode("p"), 0o640);
+
+    // The umask of the process doesn't apply on top of it, whatever it is.
+    let cfg: Config = "umask=0".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("create_umask_none", "9P2000.L", cfg);
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        ROOT_FID + 1,
+        "f",
+        P9_RDWR,
+        0o666,
+    )
+    .expect("failed to create file");
+    let md = fs::symlink_metadata(test_dir.join("f")).expect("failed to stat file");
+    assert_eq!(md.mode() & 0o7777, 0o666);
+
+    assert!("umask=1777".parse::<Config>().is_err());
+    assert!("umask=9".parse::<Config>().is_err());
+}
+
+#[test]
+fn sync_dirs() {
+    let cfg: Config = "sync_dirs=true".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("sync_dirs", "9P2000.L", cfg);
+
+    // Every change to a directory still goes through with its fsync.
+    let fid = ROOT_FID + 1;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "f", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("d").unwrap(),
+            mode: 0o755,
+            gid: u32::MAX,
+        })
+        .expect("failed to create directory");
+    let dir_fid = fid + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        dir_fid,
+        vec![P9String::new("d").unwrap()],
+    );
+    server
+        .link(Tlink {
+            dfid: dir_fid,
+            fid,
+            name: P9String::new("g").unwrap(),
+        })
+        .expect("failed to link file");
+    server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: P9String::new("f").unwrap(),
+            newdirfid: dir_fid,
+            newname: P9String::new("h").unwrap(),
+        })
+        .expect("failed to rename file");
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: dir_fid,
+            name: P9String::new("g").unwrap(),
+            flags: 0,
+        })
+        .expect("failed to unlink file");
+
+    assert!(!test_dir.join("f").exists());
+    assert!(!test_dir.join("d/g").exists());
+    assert!(test_dir.join("d/h").exists());
+}
+
+#[test]
+fn sync_on_close() {
+    let cfg: Config = "sync_on_close=fdatasync:max_open_files=1"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("sync_on_close", "9P2000.L", cfg);
+
+    // Clunking syncs files even if they were closed to make room for others.
+    let (f, g) = (ROOT_FID + 1, ROOT_FID + 2);
+    create(&mut server, &*test_dir, ROOT_FID, f, "f", P9_WRONLY, 0o644)
+        .expect("failed to create file");
+    server
+        .write(&Twrite {
+            fid: f,
+            offset: 0,
+            data: Data(b"hello".to_vec()),
+        })
+        .expect("failed to write");
+    create(&mut server, &*test_dir, ROOT_FID, g, "g", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    server.clunk(&Tclunk { fid: f }).expect("failed to clunk");
+    server.clunk(&Tclunk { fid: g }).expect("failed to clunk");
+    assert_eq!(fs::read(test_dir.join("f")).unwrap(), b"hello");
+
+    assert!("sync_on_close=fsync".parse::<Config>().is_ok());
+    assert!("sync_on_close=always".parse::<Config>().is_err());
+}
+
+#[test]
+fn strip_setid() {
+    let cfg: Config = "strip_setid=true".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("strip_setid", "9P2000.L", cfg);
+    let mode = |name: &str| {
+        fs::symlink_metadata(test_dir.join(name))
+            .expect("failed to stat file")
+            .mode()
+            & 0o7777
+    };
+
+    let fid = ROOT_FID + 1;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "f", P9_RDWR, 0o4755)
+        .expect("failed to create file");
+    assert_eq!(mode("f"), 0o755);
+
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("d").unwrap(),
+            mode: 0o2755,
+            gid: u32::MAX,
+        })
+        .expect("failed to create directory");
+    assert_eq!(mode("d"), 0o755);
+
+    let chmod = |server: &mut Server, mode| {
+        server.set_attr(&Tsetattr {
+            fid,
+            valid: P9_SETATTR_MODE,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime_sec: 0,
+            atime_nsec: 0,
+            mtime_sec: 0,
+            mtime_nsec: 0,
+        })
+    };
+    chmod(&mut server, 0o6711).expect("failed to chmod file");
+    assert_eq!(mode("f"), 0o711);
+
+    // Files that got the bits some other way keep them, but clients can be kept from seeing them.
+    let getattr = |server: &mut Server, fid| {
+        server
+            .get_attr(&Tgetattr {
+                fid,
+                request_mask: P9_GETATTR_BASIC,
+            })
+            .expect("failed to getattr")
+            .mode
+            & 0o7777
+    };
+    fs::set_permissions(test_dir.join("f"), fs::Permissions::from_mode(0o4755))
+        .expect("failed to chmod file");
+    assert_eq!(getattr(&mut server, fid), 0o4755);
+
+    let cfg: Config = "hide_setid=true".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("hide_setid", "9P2000.L", cfg);
+    fs::set_permissions(
+        test_dir.join("subdir/b"),
+        fs::Permissions::from_mode(0o4755),
+    )
+    .expect("failed to chmod file");
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![
+            P9String::new("subdir").unwrap(),
+            P9String::new("b").unwrap(),
+        ],
+    );
+    assert_eq!(getattr(&mut server, fid), 0o755);
+}
+
+#[test]
+fn create_existing_file() {
+    let (test_dir, mut server) = setup("create_existing");
+
+    let name = "existing";
+    create_local_file(&test_dir, name);
+
+    let fid = ROOT_FID + 1;
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        name,
+        P9_APPEND,
+        0o644,
+    )
+    .expect_err("successfully created existing file");
+}
+
+enum SetAttrKind {
+    File,
+    Directory,
+}
+
+fn set_attr_test<F>(kind: SetAttrKind, set_fields: F) -> io::Result<fs::Metadata>
+where
+    F: FnOnce(&mut Tsetattr),
+{
+    let (test_dir, mut server) = setup("set_attr");
+
+    let name = "existing";
+    match kind {
+        SetAttrKind::File => {
+            create_local_file(&test_dir, name);
+        }
+        SetAttrKind::Directory => {
+            let tmkdir = Tmkdir {
+                dfid: ROOT_FID,
+                name: P9String::new(name).unwrap(),
+                mode: 0o755,
+                gid: 0,
+            };
+
+            let rmkdir = server.mkdir(tmkdir).expect("failed to create directory");
+            let md = fs::symlink_metadata(test_dir.join(name))
+                .expect("failed to get metadata for directory");
+
+            assert!(md.is_dir());
+            check_qid(&rmkdir.qid, &md);
+        }
+    };
+
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    let mut tsetattr = Tsetattr {
+        fid,
+        valid: 0,
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        atime_sec: 0,
+        atime_nsec: 0,
+        mtime_sec: 0,
+        mtime_nsec: 0,
+    };
+
+    set_fields(&mut tsetattr);
+    server.set_attr(&tsetattr)?;
+
+    fs::symlink_metadata(test_dir.join(name))
+}
+
+#[test]
+fn set_len() {
+    let len = 661;
+    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_SIZE;
+        tsetattr.size = len;
+    })
+    .expect("failed to run set length of file");
+
+    assert_eq!(md.size(), len);
+}
+
+#[test]
+fn set_file_mode() {
+    let mode = 0o640;
+    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_MODE;
+        tsetattr.mode = mode;
+    })
+    .expect("failed to set mode");
+
+    assert_eq!(md.mode() & 0o777, mode);
+}
+
+#[test]
+fn set_file_mtime() {
+    let (secs, nanos) = (1245247825, 524617);
+    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET;
+        tsetattr.mtime_sec = secs;
+        tsetattr.mtime_nsec = nanos;
+    })
+    .expect("failed to set mtime");
+
+    assert_eq!(md.mtime() as u64, secs);
+    assert_eq!(md.mtime_nsec() as u64, nanos);
+}
+
+#[test]
+fn set_file_atime() {
+    let (secs, nanos) = (9247605, 4016);
+    let md = set_attr_test(SetAttrKind::File, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_ATIME | P9_SETATTR_ATIME_SET;
+        tsetattr.atime_sec = secs;
+        tsetattr.atime_nsec = nanos;
+    })
+    .expect("failed to set atime");
+
+    assert_eq!(md.atime() as u64, secs);
+    assert_eq!(md.atime_nsec() as u64, nanos);
+}
+
+#[test]
+fn set_dir_mode() {
+    let mode = 0o640;
+    let md = set_attr_test(SetAttrKind::Directory, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_MODE;
+        tsetattr.mode = mode;
+    })
+    .expect("failed to set mode");
+
+    assert_eq!(md.mode() & 0o777, mode);
+}
+
+#[test]
+fn set_dir_mtime() {
+    let (secs, nanos) = (1245247825, 524617);
+    let md = set_attr_test(SetAttrKind::Directory, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET;
+        tsetattr.mtime_sec = secs;
+        tsetattr.mtime_nsec = nanos;
+    })
+    .expect("failed to set mtime");
+
+    assert_eq!(md.mtime() as u64, secs);
+    assert_eq!(md.mtime_nsec() as u64, nanos);
+}
+
+#[test]
+fn set_dir_atime() {
+    let (secs, nanos) = (9247605, 4016);
+    let md = set_attr_test(SetAttrKind::Directory, |tsetattr| {
+        tsetattr.valid = P9_SETATTR_ATIME | P9_SETATTR_ATIME_SET;
+        tsetattr.atime_sec = secs;
+        tsetattr.atime_nsec = nanos;
+    })
+    .expect("failed to set atime");
+
+    assert_eq!(md.atime() as u64, secs);
+    assert_eq!(md.atime_nsec() as u64, nanos);
+}
+
+#[test]
+fn huge_directory() {
+    let (test_dir, mut server) = setup("huge_directory");
+
+    let name = "newdir";
+    let newdir = test_dir.join(name);
+    fs::create_dir(&newdir).expect("failed to create directory");
+
+    let dfid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        dfid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    // Create ~4K files in the directory and then attempt to read them all.
+    let mut filenames = HashSet::with_capacity(4096);
+    for i in 0..4096 {
+        let name = format!("file_{}", i);
+        create_local_file(&newdir, &name);
+        assert!(filenames.insert(P9String::new(name).unwrap()));
+    }
+
+    let fid = dfid + 1;
+    open(&mut server, &newdir, dfid, "", fid, P9_DIRECTORY).expect("Failed to open directory");
+    for f in readdir(&mut server, fid) {
+        let dir_name = Path::new(std::str::from_utf8(f.name.as_bytes()).unwrap());
+        let path = newdir.join(dir_name);
+
+        let md = fs::symlink_metadata(path).expect("failed to get metadata for path");
+        check_qid(&f.qid, &md);
+
+        if f.name == "." || f.name == ".." {
+            assert_eq!(f.ty, libc::DT_DIR);
+        } else {
+            assert_eq!(f.ty, libc::DT_REG);
+            assert!(filenames.remove(&f.name));
+        }
+    }
+
+    assert!(filenames.is_empty());
+}
+
+#[test]
+fn mkdir() {
+    let (test_dir, mut server) = setup("mkdir");
+
+    let name = "conan";
+    let tmkdir = Tmkdir {
+        dfid: ROOT_FID,
+        name: P9String::new(name).unwrap(),
+        mode: 0o755,
+        gid: 0,
+    };
+
+    let rmkdir = server.mkdir(tmkdir).expect("failed to create directory");
+    let md =
+        fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for directory");
+
+    assert!(md.is_dir());
+    check_qid(&rmkdir.qid, &md);
+}
+
+#[test]
+fn openat2_config() {
+    assert!(Config::default().openat2);
+    let cfg: Config = "openat2=false:hide=.*"
+        .parse()
+        .expect("failed to parse config");
+    assert!(!cfg.openat2);
+    assert!("openat2=sometimes".parse::<Config>().is_err());
+
+    // Names are looked up one at a time whether openat2 is used or not, so a name with a "/" in
+    // it can't get around `hide`.
+    for cfg in [
+        cfg.clone(),
+        Config {
+            openat2: true,
+            ..cfg
+        },
+    ] {
+        let (test_dir, mut server) = setup_config("openat2_config", "9P2000.L", cfg);
+        create_local_file(test_dir.join("subdir"), ".secret");
+        let err = server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: ROOT_FID + 1,
+                wnames: vec![P9String::new("subdir/.secret").unwrap()],
+            })
+            .expect_err("walked to a hidden file");
+        assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        let rwalk = server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: ROOT_FID + 1,
+                wnames: vec![
+                    P9String::new("subdir").unwrap(),
+                    P9String::new(".secret").unwrap(),
+                ],
+            })
+            .expect("failed to walk");
+        assert_eq!(rwalk.wqids.len(), 1);
+        walk(
+            &mut server,
+            &*test_dir,
+            ROOT_FID,
+            ROOT_FID + 1,
+            vec![
+                P9String::new("subdir").unwrap(),
+                P9String::new("b").unwrap(),
+            ],
+        );
+    }
+}
+
+#[test]
+fn remove_moved_on_host() {
+    let (test_dir, mut server) = setup("remove_moved_on_host");
+    let (outside, _) = setup("remove_moved_on_host_outside");
+    let walk = |server: &mut Server, fid: u32, name: &str| {
+        walk(
+            server,
+            &*test_dir,
+            ROOT_FID,
+            fid,
+            vec![
+                P9String::new("subdir").unwrap(),
+                P9String::new(name).unwrap(),
+            ],
+        )
+    };
+
+    // Files that were renamed on the host are found where they are now.
+    walk(&mut server, ROOT_FID + 1, "b");
+    fs::rename(test_dir.join("subdir/b"), test_dir.join("subdir/moved"))
+        .expect("failed to rename file");
+    server
+        .remove(&Tremove { fid: ROOT_FID + 1 })
+        .expect("failed to remove renamed file");
+    assert!(!test_dir.join("subdir/moved").exists());
+
+    // But not once they have left the export, even if the directory that the client walked
+    // through now leads there.
+    create_local_file(test_dir.join("subdir"), "a");
+    walk(&mut server, ROOT_FID + 2, "a");
+    fs::rename(test_dir.join("subdir/a"), outside.join("a")).expect("failed to move file");
+    fs::rename(test_dir.join("subdir"), test_dir.join("old")).expect("failed to rename dir");
+    std::os::unix::fs::symlink(&*outside, test_dir.join("subdir"))
+        .expect("failed to create symlink");
+    server
+        .remove(&Tremove { fid: ROOT_FID + 2 })
+        .expect_err("removed file outside of the export");
+    assert!(outside.join("a").exists());
+}
+
+#[test]
+fn id_map() {
+    // Safe because these don't touch any memory.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let cfg: Config = format!("uid_map=5000/{}/1:gid_map=6000/{}/1", uid, gid)
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("id_map", "9P2000.L", cfg);
+
+    let tgetattr = Tgetattr {
+        fid: ROOT_FID,
+        request_mask: P9_GETATTR_BASIC,
+    };
+    let rgetattr = server.get_attr(&tgetattr).expect("failed to call get_attr");
+    assert_eq!((rgetattr.uid, rgetattr.gid), (5000, 6000));
+
+    // The ids that the client sends are mapped back, so this only succeeds if it changes the
+    // owner to the one that the test already has.
+    let tsetattr = Tsetattr {
+        fid: ROOT_FID,
+        valid: P9_SETATTR_UID | P9_SETATTR_GID,
+        mode: 0,
+        uid: 5000,
+        gid: 6000,
+        size: 0,
+        atime_sec: 0,
+        atime_nsec: 0,
+        mtime_sec: 0,
+        mtime_nsec: 0,
+    };
+    server.set_attr(&tsetattr).expect("failed to set owner");
+    let md = fs::symlink_metadata(&*test_dir).expect("failed to get metadata");
+    assert_eq!((md.uid(), md.gid()), (uid, gid));
+
+    // Ids outside the map can't be given to files, but the ones that aren't being changed don't
+    // have to be in it.
+    let err = server
+        .set_attr(&Tsetattr {
+            valid: P9_SETATTR_GID,
+            gid: 7000,
+            ..tsetattr
+        })
+        .expect_err("changed the group to an unmapped one");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    server
+        .set_attr(&Tsetattr {
+            valid: P9_SETATTR_GID,
+            uid: 7000,
+            ..tsetattr
+        })
+        .expect("failed to set group");
+
+    // Groups outside the map are left alone.
+    for (name, client_gid) in [("mapped", 6000), ("unmapped", 7000)] {
+        let tmkdir = Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new(name).unwrap(),
+            mode: 0o755,
+            gid: client_gid,
+        };
+        server.mkdir(tmkdir).expect("failed to create directory");
+        let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata");
+        assert_eq!(md.gid(), gid);
+    }
+}
+
+#[test]
+fn all_squash() {
+    // Safe because these don't touch any memory.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let cfg: Config = format!("all_squash={}/{}/700/800", uid, gid)
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("all_squash", "9P2000.L", cfg);
+
+    let name = "dir";
+    let tmkdir = Tmkdir {
+        dfid: ROOT_FID,
+        name: P9String::new(name).unwrap(),
+        mode: 0o755,
+        gid: 12345,
+    };
+    server.mkdir(tmkdir).expect("failed to create directory");
+    let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata");
+    assert_eq!((md.uid(), md.gid()), (uid, gid));
+
+    // Whatever owner the client asks for, the file stays with the squashed one.
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+    let tsetattr = Tsetattr {
+        fid,
+        valid: P9_SETATTR_UID | P9_SETATTR_GID,
+        mode: 0,
+        uid: 4242,
+        gid: 4343,
+        size: 0,
+        atime_sec: 0,
+        atime_nsec: 0,
+        mtime_sec: 0,
+        mtime_nsec: 0,
+    };
+    server.set_attr(&tsetattr).expect("failed to set owner");
+    let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata");
+    assert_eq!((md.uid(), md.gid()), (uid, gid));
+
+    let tgetattr = Tgetattr {
+        fid,
+        request_mask: P9_GETATTR_BASIC,
+    };
+    let rgetattr = server.get_attr(&tgetattr).expect("failed to call get_attr");
+    assert_eq!((rgetattr.uid, rgetattr.gid), (700, 800));
+}
+
+#[test]
+fn exports() {
+    let cfg: Config = "export=a=/srv/a:read_only_export=b=/srv/b"
+        .parse()
+        .expect("failed to parse config");
+    assert_eq!(&*cfg.exports["a"].root, Path::new("/srv/a"));
+    assert!(!cfg.exports["a"].read_only);
+    assert!(cfg.exports["b"].read_only);
+
+    let (test_dir, mut server) = setup("exports");
+    server.cfg.exports.insert(
+        "sub".to_string(),
+        Export {
+            read_only: true,
+            ..Export::new(test_dir.join("subdir"))
+        },
+    );
+
+    let attach = |server: &mut Server, fid: u32, aname: &str| {
+        server.attach(&Tattach {
+            fid,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new(aname).unwrap(),
+            n_uname: 1000,
+        })
+    };
+
+    let (root_fid, sub_fid) = (ROOT_FID + 1, ROOT_FID + 2);
+    let rattach = attach(&mut server, sub_fid, "sub").expect("failed to attach to export");
+    let md = fs::symlink_metadata(test_dir.join("subdir")).expect("failed to get metadata");
+    check_qid(&rattach.qid, &md);
+    let rattach = attach(&mut server, root_fid, "").expect("failed to attach to root");
+    let md = fs::symlink_metadata(&*test_dir).expect("failed to get metadata");
+    check_qid(&rattach.qid, &md);
+    let err = attach(&mut server, ROOT_FID + 3, "bogus").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    // Only the read-only export refuses changes.
+    let mkdir = |dfid| {
+        Tmessage::Mkdir(Tmkdir {
+            dfid,
+            name: P9String::new("dir").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })
+    };
+    let err = server.dispatch(Ok(mkdir(sub_fid))).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    server
+        .dispatch(Ok(mkdir(root_fid)))
+        .expect("failed to create directory");
+
+    // Nothing moves between exports.
+    let err = server
+        .rename_at(Trenameat {
+            olddirfid: sub_fid,
+            oldname: P9String::new("b").unwrap(),
+            newdirfid: root_fid,
+            newname: P9String::new("b").unwrap(),
+        })
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+    assert!(test_dir.join("subdir/b").exists());
+}
+
+#[test]
+fn runtime_exports() {
+    let (test_dir, mut server) = setup("runtime_exports");
+    let attach = |server: &mut Server, fid: u32, aname: &str| {
+        server.attach(&Tattach {
+            fid,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new(aname).unwrap(),
+            n_uname: 1000,
+        })
+    };
+
+    let sub = test_dir.join("subdir");
+    server
+        .add_export("sub".to_string(), Export::new(sub.clone()))
+        .expect("failed to add export");
+    let err = server
+        .add_export("sub".to_string(), Export::new(sub.clone()))
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    let err = server
+        .add_export(String::new(), Export::new(sub.clone()))
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let sub_fid = ROOT_FID + 1;
+    attach(&mut server, sub_fid, "sub").expect("failed to attach to export");
+    open(&mut server, &sub, sub_fid, "b", sub_fid + 1, P9_RDONLY).expect("failed to open file");
+    assert_eq!(server.export_fids(&sub), 2);
+
+    // Removing the export only keeps new attaches out.
+    let export = server.remove_export("sub").expect("export not found");
+    assert_eq!(&*export.root, &*sub);
+    assert!(server.remove_export("sub").is_none());
+    let err = attach(&mut server, sub_fid + 2, "sub").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    check_content(&mut server, b"hello, world!", sub_fid + 1);
+    server
+        .clunk(&Tclunk { fid: sub_fid })
+        .expect("failed to clunk fid");
+    assert_eq!(server.export_fids(&sub), 1);
+    server
+        .clunk(&Tclunk { fid: sub_fid + 1 })
+        .expect("failed to clunk fid");
+    assert_eq!(server.export_fids(&sub), 0);
+
+    server
+        .add_export("sub".to_string(), export)
+        .expect("failed to add export");
+    attach(&mut server, sub_fid, "sub").expect("failed to attach to export");
+}
+
+#[test]
+fn reopen_roots() {
+    let (test_dir, mut server) = setup("reopen_roots");
+    let out = test_dir.join("out");
+    for (dir, name, content) in [("b1", "a", "old"), ("b2", "b", "new")] {
+        fs::create_dir(test_dir.join(dir)).expect("failed to create directory");
+        fs::write(test_dir.join(dir).join(name), content).expect("failed to write file");
+    }
+    symlink("b1", &out).expect("failed to create symlink");
+    server
+        .add_export("out".to_string(), Export::new(out.clone()))
+        .expect("failed to add export");
+
+    let root_fid = ROOT_FID + 1;
+    server
+        .attach(&Tattach {
+            fid: root_fid,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new("out").unwrap(),
+            n_uname: 1000,
+        })
+        .expect("failed to attach to export");
+    walk(
+        &mut server,
+        &out,
+        root_fid,
+        root_fid + 1,
+        vec![P9String::new("a").unwrap()],
+    );
+
+    // Flip the symlink that the export is to another directory.
+    symlink("b2", test_dir.join("next")).expect("failed to create symlink");
+    fs::rename(test_dir.join("next"), &out).expect("failed to replace symlink");
+    server.reopen_roots().expect("failed to reopen roots");
+
+    let twalk = |name: &str| Twalk {
+        fid: root_fid,
+        newfid: root_fid + 2,
+        wnames: vec![P9String::new(name).unwrap()],
+    };
+    let err = server.walk(twalk("a")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    open(&mut server, &out, root_fid, "b", root_fid + 2, P9_RDONLY).expect("failed to open file");
+    check_content(&mut server, b"new", root_fid + 2);
+
+    // The fid that was walked before keeps its file.
+    server
+        .lopen(&Tlopen {
+            fid: root_fid + 1,
+            flags: P9_RDONLY,
+        })
+        .expect("failed to open file");
+    check_content(&mut server, b"old", root_fid + 1);
+}
+
+#[test]
+fn attach_hook() {
+    let (test_dir, mut server) = setup("attach_hook");
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    server.set_attach_hook({
+        let seen = seen.clone();
+        Box::new(move |uname, n_uname, aname| {
+            seen.lock()
+                .unwrap()
+                .push((uname.to_string(), n_uname, aname.to_string()));
+            match uname {
+                "guest" => Ok(AttachOptions {
+                    read_only: true,
+                    all_squash: Some(Squash {
+                        host_uid: 0,
+                        host_gid: 0,
+                        client_uid: 700,
+                        client_gid: 800,
+                    }),
+                }),
+                "unittest" => Ok(AttachOptions::default()),
+                _ => Err(io::Error::from_raw_os_error(libc::EACCES)),
+            }
+        })
+    });
+
+    let attach = |server: &mut Server, fid: u32, uname: &str| {
+        server.attach(&Tattach {
+            fid,
+            afid: P9_NOFID,
+            uname: P9String::new(uname).unwrap(),
+            aname: P9String::new("").unwrap(),
+            n_uname: u32::MAX,
+        })
+    };
+    let err = attach(&mut server, ROOT_FID + 1, "intruder").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    assert!(!server.fids.contains(ROOT_FID + 1));
+    assert_eq!(
+        seen.lock().unwrap().last(),
+        Some(&("intruder".to_string(), None, String::new()))
+    );
+
+    let guest_fid = ROOT_FID + 1;
+    attach(&mut server, guest_fid, "guest").expect("failed to attach");
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: guest_fid,
+            request_mask: P9_GETATTR_BASIC,
+        })
+        .expect("failed to call get_attr");
+    assert_eq!((rgetattr.uid, rgetattr.gid), (700, 800));
+    let tmkdir = |dfid| {
+        Tmessage::Mkdir(Tmkdir {
+            dfid,
+            name: P9String::new("dir").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })
+    };
+    let err = server.dispatch(Ok(tmkdir(guest_fid))).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+
+    // Other attaches keep the options of the export.
+    let user_fid = ROOT_FID + 2;
+    attach(&mut server, user_fid, "unittest").expect("failed to attach");
+    server
+        .dispatch(Ok(tmkdir(user_fid)))
+        .expect("failed to create directory");
+    assert!(test_dir.join("dir").is_dir());
+}
+
+struct LogMiddleware {
+    name: &'static str,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Middleware for LogMiddleware {
+    fn before(&mut self, msg: &mut Tmessage) -> io::Result<()> {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} before", self.name));
+        match msg {
+            // Walking to "alias" leads to "subdir".
+            Tmessage::Walk(walk) if self.name == "rewrite" => {
+                for wname in walk.wnames.iter_mut() {
+                    if wname.as_bytes() == b"alias" {
+                        *wname = P9String::new("subdir").unwrap();
+                    }
+                }
+                Ok(())
+            }
+            Tmessage::Remove(_) if self.name == "veto" => {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn after(&mut self, reply: &mut io::Result<Rmessage>) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} after", self.name));
+        if self.name == "rewrite" && reply.is_err() {
+            *reply = Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+    }
+}
+
+#[test]
+fn middleware() {
+    let (test_dir, mut server) = setup("middleware");
+    let log = Arc::new(Mutex::new(Vec::new()));
+    for name in ["rewrite", "veto"] {
+        server.add_middleware(Box::new(LogMiddleware {
+            name,
+            log: log.clone(),
+        }));
+    }
+
+    let rmsg = server
+        .dispatch(Ok(Tmessage::Walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![P9String::new("alias").unwrap()],
+        })))
+        .expect("failed to walk");
+    let md = fs::symlink_metadata(test_dir.join("subdir")).expect("failed to get metadata");
+    match rmsg {
+        Rmessage::Walk(rwalk) => check_qid(&rwalk.wqids[0], &md),
+        _ => panic!("unexpected reply"),
+    }
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "rewrite before",
+            "veto before",
+            "veto after",
+            "rewrite after"
+        ]
+    );
+
+    // The veto keeps the request from the server, and the middleware before it still sees the
+    // reply.
+    log.lock().unwrap().clear();
+    let err = server
+        .dispatch(Ok(Tmessage::Remove(Tremove { fid: ROOT_FID + 1 })))
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert!(test_dir.join("subdir").exists());
+    assert!(server.fids.contains(ROOT_FID + 1));
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "rewrite before",
+            "veto before",
+            "veto after",
+            "rewrite after"
+        ]
+    );
+}
+
+struct ChannelSink(std::sync::mpsc::Sender<AuditEvent>);
+
+impl AuditSink for ChannelSink {
+    fn record(&mut self, event: AuditEvent) {
+        self.0.send(event).unwrap();
+    }
+}
+
+#[test]
+fn audit() {
+    let (test_dir, mut server) = setup("audit");
+    let (sender, receiver) = std::sync::mpsc::channel();
+    server
+        .set_audit_sink(Box::new(ChannelSink(sender)), 2)
+        .expect("failed to set audit sink");
+
+    let fid = ROOT_FID + 1;
+    let requests = vec![
+        Tmessage::Walk(Twalk {
+            fid: ROOT_FID,
+            newfid: fid,
+            wnames: Vec::new(),
+        }),
+        Tmessage::Lcreate(Tlcreate {
+            fid,
+            name: P9String::new("foo").unwrap(),
+            flags: P9_RDWR,
+            mode: 0o600,
+            gid: 0,
+        }),
+        Tmessage::Write(Twrite {
+            fid,
+            offset: 0,
+            data: Data(b"hello".to_vec()),
+        }),
+        Tmessage::SetAttr(Tsetattr {
+            fid,
+            valid: P9_SETATTR_MODE,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime_sec: 0,
+            atime_nsec: 0,
+            mtime_sec: 0,
+            mtime_nsec: 0,
+        }),
+        Tmessage::RenameAt(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: P9String::new("foo").unwrap(),
+            newdirfid: ROOT_FID,
+            newname: P9String::new("bar").unwrap(),
+        }),
+        Tmessage::UnlinkAt(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: P9String::new("missing").unwrap(),
+            flags: 0,
+        }),
+    ];
+    for msg in requests {
+        let _ = server.dispatch(Ok(msg));
+    }
+    assert!(test_dir.join("bar").exists());
+
+    // The sink is done once the server is gone.
+    drop(server);
+    let events: Vec<AuditEvent> = receiver.iter().collect();
+    let summary: Vec<(AuditOp, Vec<&str>, Option<i32>)> = events
+        .iter()
+        .map(|event| {
+            assert_eq!(event.identity.uname, "unittest");
+            assert_eq!(&*event.root, &*test_dir);
+            let paths = event.paths.iter().map(|p| p.to_str().unwrap()).collect();
+            (event.op, paths, event.error)
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (AuditOp::Create, vec!["/foo"], None),
+            (AuditOp::Write, vec!["/foo"], None),
+            (AuditOp::SetAttr, vec!["/foo"], None),
+            (AuditOp::Rename, vec!["/foo", "/bar"], None),
+            (AuditOp::Remove, vec!["/missing"], Some(libc::ENOENT)),
+        ]
+    );
+}
+
+#[test]
+fn read_only() {
+    let cfg: Config = "read_only=true".parse().expect("failed to parse config");
+    assert!(cfg.read_only);
+    let (test_dir, mut server) = setup_config("read_only", "9P2000.L", cfg);
+
+    let erofs = |result: io::Result<Rmessage>| {
+        assert_eq!(
+            result.map(|_| ()).unwrap_err().raw_os_error(),
+            Some(libc::EROFS)
+        );
+    };
+
+    let name = "conan";
+    erofs(server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+        dfid: ROOT_FID,
+        name: P9String::new(name).unwrap(),
+        mode: 0o755,
+        gid: 0,
+    }))));
+    assert!(!test_dir.join(name).exists());
+
+    let content = create_local_file(&test_dir, "file");
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new("file").unwrap()],
+    );
+    for flags in [P9_WRONLY, P9_RDWR, P9_RDONLY | P9_TRUNC] {
+        erofs(server.dispatch(Ok(Tmessage::Lopen(Tlopen { fid, flags }))));
+    }
+    server
+        .dispatch(Ok(Tmessage::Lopen(Tlopen {
+            fid,
+            flags: P9_RDONLY,
+        })))
+        .expect("failed to open file for reading");
+    check_content(&mut server, &content, fid);
+
+    erofs(server.dispatch(Ok(Tmessage::Write(Twrite {
+        fid,
+        offset: 0,
+        data: Data(vec![0; 8]),
+    }))));
+
+    // Tremove still clunks the fid.
+    erofs(server.dispatch(Ok(Tmessage::Remove(Tremove { fid }))));
+    assert!(!server.fids.contains(fid));
+    assert!(test_dir.join("file").exists());
+}
+
+#[test]
+fn unlink_all() {
+    let (test_dir, mut server) = setup("readdir");
+
+    let mut next_fid = ROOT_FID + 1;
+
+    let mut dirs = VecDeque::new();
+    dirs.push_back((ROOT_FID, test_dir.to_path_buf()));
+
+    // First iterate over the whole directory.
+    let mut unlinks = VecDeque::new();
+    while let Some((dfid, dir)) = dirs.pop_front() {
+        let mut names = VecDeque::new();
+        for entry in fs::read_dir(dir).expect("failed to read directory") {
+            let entry = entry.expect("unable to iterate over directory");
+            let ft = entry
+                .file_type()
+                .expect("failed to get file type for entry");
+            if ft.is_dir() {
+                let fid = next_fid;
+                next_fid += 1;
+
+                let wnames: Vec<P9String> = entry
+                    .path()
+                    .strip_prefix(&test_dir)
+                    .expect("test directory is not prefix of subdir")
+                    .components()
+                    .map(|c| P9String::try_from(c.as_os_str()).unwrap())
+                    .collect();
+                walk(&mut server, &*test_dir, ROOT_FID, fid, wnames);
+                dirs.push_back((fid, entry.path()));
+            }
+
+            names.push_back((
+                P9String::new(entry.file_name().as_bytes())
+                    .expect("failed to convert entry name to string"),
+                if ft.is_dir() {
+                    libc::AT_REMOVEDIR as u32
+                } else {
+                    0
+                },
+            ));
+        }
+
+        unlinks.push_back((dfid, names));
+    }
+
+    // Now remove everything in reverse order.
+    while let Some((dfid, names)) = unlinks.pop_back() {
+        for (name, flags) in names {
+            let tunlinkat = Tunlinkat {
+                dirfd: dfid,
+                name,
+                flags,
+            };
+
+            server.unlink_at(tunlinkat).expect("failed to unlink path");
+        }
+    }
+}
+
+#[test]
+fn rename_at() {
+    let (test_dir, mut server) = setup("rename");
+
+    let name = "oldfile";
+    let content = create_local_file(&test_dir, name);
+
+    let newname = "newfile";
+    let trename = Trenameat {
+        olddirfid: ROOT_FID,
+        oldname: P9String::new(name).unwrap(),
+        newdirfid: ROOT_FID,
+        newname: P9String::new(newname).unwrap(),
+    };
+
+    server.rename_at(trename).expect("failed to rename file");
+
+    assert!(!test_dir.join(name).exists());
+
+    let mut newcontent = Vec::with_capacity(content.len());
+    let size = File::open(test_dir.join(newname))
+        .expect("failed to open file")
+        .read_to_end(&mut newcontent)
+        .expect("failed to read new file content");
+    assert_eq!(size, content.len());
+    assert_eq!(newcontent, content);
+}
+
+fn setlk_tlock(fid: u32, len: u64, start: u64, type_: i32) -> Tlock {
+    Tlock {
+        fid,
+        type_: type_ as u8,
+        flags: 0,
+        start,
+        length: len,
+        proc_id: SERVER_PID,
+        client_id: P9String::new("test-server").unwrap(),
+    }
+}
+
+fn getlk_tgetlock(fid: u32, type_: i32) -> Tgetlock {
+    Tgetlock {
+        fid,
+        type_: type_ as u8,
+        start: 0,
+        length: 0,
+        proc_id: SERVER_PID,
+        client_id: P9String::new("test-server").unwrap(),
+    }
+}
+
+fn setup_simple_lock_no_open() -> Server {
+    let (test_dir, server) = setup("simple lock");
+
+    let filename = "file";
+    create_local_file(&test_dir, filename);
+
+    server
+}
+
+fn setup_simple_lock(flags: u32) -> Server {
+    let (test_dir, mut server) = setup("simple lock");
+
+    let filename = "file";
+    create_local_file(&test_dir, filename);
+
+    open(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        filename,
+        ROOT_FID + 1,
+        flags,
+    )
+    .expect("failed to open file");
+
+    server
+}
+
+#[test]
+fn lock_rdlck_no_open_file() {
+    let mut server = setup_simple_lock_no_open();
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_RDLCK);
+
+    server.lock(&tlock).expect_err("Bad file descriptor");
+}
+
+#[test]
+fn lock_rdlck() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_RDLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+}
+#[test]
+fn lock_wrlck_no_open_file() {
+    let mut server = setup_simple_lock_no_open();
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_WRLCK);
+
+    server.lock(&tlock).expect_err("Bad file descriptor");
+}
+#[test]
+fn lock_wrlck() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_WRLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+}
+
+#[test]
+fn lock_unlck_no_lock() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 0, 0, libc::F_UNLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+}
+
+#[test]
+fn lock_unlck() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 0, 0, libc::F_UNLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+}
+
+#[test]
+fn lock_unlck_relock() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+
+    let tlock = setlk_tlock(ROOT_FID + 1, 0, 0, libc::F_UNLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+
+    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+}
+
+#[test]
+fn getlock_rdlck_nolock() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tgetlock = getlk_tgetlock(ROOT_FID + 1, libc::F_RDLCK);
+
+    server
+        .get_lock(&tgetlock)
+        .expect("failed to get lock on file");
+}
+
+#[test]
+fn getlock_wrlck() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_WRLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+
+    let tgetlock = getlk_tgetlock(ROOT_FID + 1, libc::F_WRLCK);
+
+    server
+        .get_lock(&tgetlock)
+        .expect("failed to get lock on file");
+}
+
+#[test]
+fn getlock_rdlck() {
+    let mut server = setup_simple_lock(P9_RDWR);
+
+    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
+
+    server.lock(&tlock).expect("failed to lock file");
+
+    let tgetlock = getlk_tgetlock(ROOT_FID + 1, libc::F_RDLCK);
+
+    server
+        .get_lock(&tgetlock)
+        .expect("failed to get lock on file");
+}
+
+macro_rules! open_test {
+    ($name:ident, $flags:expr) => {
+        #[test]
+        fn $name() {
+            let (test_dir, mut server) = setup("open");
+
+            let fid = ROOT_FID + 1;
+            let name = "test.txt";
+            let content = create_local_file(&test_dir, name);
+
+            let rlopen = open(&mut server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
+                .expect("failed to open file");
+
+            let md =
+                fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
+            check_qid(&rlopen.qid, &md);
+            assert_eq!(rlopen.iounit, 0);
+
+            check_attr(&mut server, fid, &md);
+
+            // Check that the file has the proper contents as long as we didn't
+            // truncate it first.
+            if $flags & P9_TRUNC == 0 && $flags & P9_WRONLY == 0 {
+                check_content(&mut server, &content, fid);
+            }
+
+            // Check that we can write to the file.
+            if $flags & P9_RDWR != 0 || $flags & P9_WRONLY != 0 {
+                write(&mut server, &test_dir, name, fid, $flags);
+            }
+
+            let tclunk = Tclunk { fid };
+            server.clunk(&tclunk).expect("Unable to clunk file");
+        }
+    };
+    ($name:ident, $flags:expr, $expected_err:expr) => {
+        #[test]
+        fn $name() {
+            let (test_dir, mut server) = setup("open_fail");
+
+            let fid = ROOT_FID + 1;
+            let name = "test.txt";
+            create_local_file(&test_dir, name);
+
+            let err = open(&mut server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
+                .expect_err("successfully opened file");
+            assert_eq!(err.kind(), $expected_err);
+
+            let tclunk = Tclunk { fid };
+            server.clunk(&tclunk).expect("Unable to clunk file");
+        }
+    };
+}
+
+open_test!(read_only_file_open, P9_RDONLY);
+open_test!(read_write_file_open, P9_RDWR);
+open_test!(write_only_file_open, P9_WRONLY);
+
+open_test!(create_read_only_file_open, P9_CREATE | P9_RDONLY);
+open_test!(create_read_write_file_open, P9_CREATE | P9_RDWR);
+open_test!(create_write_only_file_open, P9_CREATE | P9_WRONLY);
+
+open_test!(append_read_only_file_open, P9_APPEND | P9_RDONLY);
+open_test!(append_read_write_file_open, P9_APPEND | P9_RDWR);
+open_test!(append_write_only_file_open, P9_APPEND | P9_WRONLY);
+
+open_test!(trunc_read_only_file_open, P9_TRUNC | P9_RDONLY);
+open_test!(trunc_read_write_file_open, P9_TRUNC | P9_RDWR);
+open_test!(trunc_write_only_file_open, P9_TRUNC | P9_WRONLY);
+
+open_test!(
+    create_append_read_only_file_open,
+    P9_CREATE | P9_APPEND | P9_RDONLY
+);
+open_test!(
+    create_append_read_write_file_open,
+    P9_CREATE | P9_APPEND | P9_RDWR
+);
+open_test!(
+    create_append_wronly_file_open,
+    P9_CREATE | P9_APPEND | P9_WRONLY
+);
+
+open_test!(
+    create_trunc_read_only_file_open,
+    P9_CREATE | P9_TRUNC | P9_RDONLY
+);
+open_test!(
+    create_trunc_read_write_file_open,
+    P9_CREATE | P9_TRUNC | P9_RDWR
+);
+open_test!(
+    create_trunc_wronly_file_open,
+    P9_CREATE | P9_TRUNC | P9_WRONLY
+);
+
+open_test!(
+    append_trunc_read_only_file_open,
+    P9_APPEND | P9_TRUNC | P9_RDONLY
+);
+open_test!(
+    append_trunc_read_write_file_open,
+    P9_APPEND | P9_TRUNC | P9_RDWR
+);
+open_test!(
+    append_trunc_wronly_file_open,
+    P9_APPEND | P9_TRUNC | P9_WRONLY
+);
+
+open_test!(
+    create_append_trunc_read_only_file_open,
+    P9_CREATE | P9_APPEND | P9_TRUNC | P9_RDONLY
+);
+open_test!(
+    create_append_trunc_read_write_file_open,
+    P9_CREATE | P9_APPEND | P9_TRUNC | P9_RDWR
+);
+open_test!(
+    create_append_trunc_wronly_file_open,
+    P9_CREATE | P9_APPEND | P9_TRUNC | P9_WRONLY
+);
+
+open_test!(
+    create_excl_read_only_file_open,
+    P9_CREATE | P9_EXCL | P9_RDONLY,
+    io::ErrorKind::AlreadyExists
+);
+open_test!(
+    create_excl_read_write_file_open,
+    P9_CREATE | P9_EXCL | P9_RDWR,
+    io::ErrorKind::AlreadyExists
+);
+open_test!(
+    create_excl_wronly_file_open,
+    P9_CREATE | P9_EXCL | P9_WRONLY,
+    io::ErrorKind::AlreadyExists
+);
+
+macro_rules! create_test {
+    ($name:ident, $flags:expr, $mode:expr) => {
+        #[test]
+        fn $name() {
+            let (test_dir, mut server) = setup("create");
+
+            let name = "foo.txt";
+            let fid = ROOT_FID + 1;
+            let rlcreate = create(&mut server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
+                .expect("failed to create file");
+
+            let md =
+                fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
+            assert_eq!(rlcreate.iounit, 0);
+            check_qid(&rlcreate.qid, &md);
+            check_attr(&mut server, fid, &md);
+
+            // Check that we can write to the file.
+            if $flags & P9_RDWR != 0 || $flags & P9_WRONLY != 0 {
+                write(&mut server, &test_dir, name, fid, $flags);
+            }
+
+            let tclunk = Tclunk { fid };
+            server.clunk(&tclunk).expect("Unable to clunk file");
+        }
+    };
+    ($name:ident, $flags:expr, $mode:expr, $expected_err:expr) => {
+        #[test]
+        fn $name() {
+            let (test_dir, mut server) = setup("create_fail");
+
+            let name = "foo.txt";
+            // The `fid` in the lcreate call initially points to the directory
+            // but is supposed to point to the newly created file after the call
+            // completes.  Duplicate the fid so that we don't end up consuming the
+            // root fid.
+            let fid = ROOT_FID + 1;
+            let err = create(&mut server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
+                .expect_err("successfully created file");
+            assert_eq!(err.kind(), $expected_err);
+        }
+    };
+}
+
+create_test!(read_only_file_create, P9_RDONLY, 0o600u32);
+create_test!(read_write_file_create, P9_RDWR, 0o600u32);
+create_test!(write_only_file_create, P9_WRONLY, 0o600u32);
+
+create_test!(
+    append_read_only_file_create,
+    P9_APPEND | P9_RDONLY,
+    0o600u32
+);
+create_test!(append_read_write_file_create, P9_APPEND | P9_RDWR, 0o600u32);
+create_test!(append_wronly_file_create, P9_APPEND | P9_WRONLY, 0o600u32);
+
+#[test]
+fn lcreate_set_len() {
+    let (test_dir, mut server) = setup("lcreate_set_len");
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        name,
+        P9_RDWR,
+        0o600u32,
+    )
+    .expect("failed to create file");
+
+    let tsetattr = Tsetattr {
+        fid,
+        valid: 0x8, // P9_SETATTR_SIZE
+        size: 100,
+        // The other fields are not used because the relevant flags aren't set in `valid`.
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        atime_sec: 0,
+        atime_nsec: 0,
+        mtime_sec: 0,
+        mtime_nsec: 0,
+    };
+    server
+        .set_attr(&tsetattr)
+        .expect("failed to set file length after lcreate");
+
+    let tclunk = Tclunk { fid };
+    server.clunk(&tclunk).expect("Unable to clunk file");
+}
+
+#[test]
+fn readlink() {
+    let (test_dir, mut server) = setup("readlink");
+    create_local_symlink(&test_dir, "symlink", "target/of/symlink");
+
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new("symlink").unwrap()],
+    );
+
+    let treadlink = Treadlink { fid };
+
+    let rreadlink = server.readlink(&treadlink).expect("failed to readlink");
+
+    assert_eq!(rreadlink.target, "target/of/symlink");
+}
+
+#[test]
+fn absolute_symlinks() {
+    let cfg: Config = "absolute_symlinks=contained"
+        .parse()
+        .expect("failed to parse config");
+    assert!("absolute_symlinks=all".parse::<Config>().is_err());
+    let (test_dir, mut server) = setup_config("absolute_symlinks", "9P2000.L", cfg);
+    let inside = test_dir.join("subdir/b");
+    create_local_symlink(test_dir.join("subdir"), "inside", inside.to_str().unwrap());
+    create_local_symlink(&test_dir, "outside", "/etc/passwd");
+
+    let name = |name: &str| P9String::new(name).unwrap();
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![name("subdir"), name("inside")],
+    );
+    let rreadlink = server
+        .readlink(&Treadlink { fid })
+        .expect("failed to readlink");
+    assert_eq!(rreadlink.target, "../subdir/b");
+
+    let fid = fid + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![name("outside")],
+    );
+    let err = server
+        .readlink(&Treadlink { fid })
+        .expect_err("read target outside of export");
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+}
+
+#[test]
+fn hide() {
+    let cfg: Config = "hide=.*,*.sw[op]".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("hide", "9P2000.L", cfg);
+    create_local_file(&test_dir, ".secret");
+    create_local_file(&test_dir, "main.rs.swp");
+
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID,
+            flags: 0,
+        })
+        .expect("failed to open directory");
+    let mut names: Vec<_> = readdir(&mut server, ROOT_FID)
+        .map(|dirent| dirent.name)
+        .collect();
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    assert_eq!(names, [".", "..", "subdir", "世界.txt"]);
+
+    let errno = |rmsg: io::Result<Rmessage>| rmsg.err().and_then(|e| e.raw_os_error());
+    let name = |name: &str| P9String::new(name).unwrap();
+    let err = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![name(".secret")],
+        })
+        .expect_err("walked to a hidden file");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    let rmsg = server.dispatch(Ok(Tmessage::UnlinkAt(Tunlinkat {
+        dirfd: ROOT_FID,
+        name: name("main.rs.swp"),
+        flags: 0,
+    })));
+    assert_eq!(errno(rmsg), Some(libc::ENOENT));
+    let rmsg = server.dispatch(Ok(Tmessage::RenameAt(Trenameat {
+        olddirfid: ROOT_FID,
+        oldname: name("世界.txt"),
+        newdirfid: ROOT_FID,
+        newname: name(".world"),
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EACCES));
+    let rmsg = server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+        dfid: ROOT_FID,
+        name: name(".git"),
+        mode: 0o755,
+        gid: 0,
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EACCES));
+
+    assert!(test_dir.join("main.rs.swp").exists());
+    assert!(test_dir.join("世界.txt").exists());
+    assert!(!test_dir.join(".git").exists());
+}
+
+#[test]
+fn mknod_policy() {
+    let tmknod = |name: &str, mode, major, minor| Tmknod {
+        dfid: ROOT_FID,
+        name: P9String::new(name).unwrap(),
+        mode,
+        major,
+        minor,
+        gid: u32::MAX,
+    };
+
+    let (test_dir, mut server) = setup("mknod_policy_default");
+    let err = server
+        .mknod(&tmknod("fifo", libc::S_IFIFO | 0o644, 0, 0))
+        .expect_err("created a FIFO by default");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    assert!(!test_dir.join("fifo").exists());
+
+    let cfg: Config = "mknod=fifos".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("mknod_policy_fifos", "9P2000.L", cfg);
+    let Rmknod { qid } = server
+        .mknod(&tmknod("fifo", libc::S_IFIFO | 0o644, 0, 0))
+        .expect("failed to create FIFO");
+    let md = fs::symlink_metadata(test_dir.join("fifo")).expect("failed to stat FIFO");
+    assert_eq!(md.mode() & libc::S_IFMT, libc::S_IFIFO);
+    assert_eq!(qid.path, md.ino());
+    let err = server
+        .mknod(&tmknod("null", libc::S_IFCHR | 0o666, 1, 3))
+        .expect_err("created a device node");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+    let cfg: Config = "mknod=c/1/3".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("mknod_policy_devices", "9P2000.L", cfg);
+    let err = server
+        .mknod(&tmknod("zero", libc::S_IFCHR | 0o666, 1, 5))
+        .expect_err("created a device node that isn't allowed");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    let err = server
+        .mknod(&tmknod("null", libc::S_IFBLK | 0o666, 1, 3))
+        .expect_err("created a block device for an allowed character device");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    assert!(!test_dir.join("zero").exists());
+    assert!(!test_dir.join("null").exists());
+}
+
+#[test]
+fn special_files() {
+    let make_special = |dir: &Path| {
+        let fifo = CString::new(dir.join("fifo").into_os_string().into_vec()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        std::os::unix::net::UnixListener::bind(dir.join("socket")).expect("failed to bind socket")
+    };
+    let twalk = |name: &str| Twalk {
+        fid: ROOT_FID,
+        newfid: ROOT_FID + 1,
+        wnames: vec![P9String::new(name).unwrap()],
+    };
+    let tlopen = Tlopen {
+        fid: ROOT_FID + 1,
+        flags: P9_RDONLY,
+    };
+
+    let cfg: Config = "special_files=hide:mknod=fifos"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("special_files_hide", "9P2000.L", cfg);
+    let _socket = make_special(&test_dir);
+    for name in ["fifo", "socket"] {
+        let err = server.walk(twalk(name)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT), "{name}");
+    }
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID,
+            flags: P9_DIRECTORY,
+        })
+        .expect("failed to open directory");
+    let mut names: Vec<_> = readdir(&mut server, ROOT_FID)
+        .map(|dirent| dirent.name)
+        .collect();
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    assert_eq!(names, [".", "..", "subdir", "世界.txt"]);
+    let err = server
+        .mknod(&Tmknod {
+            dfid: ROOT_FID,
+            name: P9String::new("fifo2").unwrap(),
+            mode: libc::S_IFIFO | 0o644,
+            major: 0,
+            minor: 0,
+            gid: u32::MAX,
+        })
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+    let cfg: Config = "special_files=refuse"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("special_files_refuse", "9P2000.L", cfg);
+    let _socket = make_special(&test_dir);
+    for name in ["fifo", "socket"] {
+        server.walk(twalk(name)).expect("failed to walk");
+        let err = server.lopen(&tlopen).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES), "{name}");
+        server
+            .clunk(&Tclunk { fid: ROOT_FID + 1 })
+            .expect("failed to clunk fid");
+    }
+
+    // Opening the FIFO for reading would wait for a writer without O_NONBLOCK.
+    let cfg: Config = "special_files=nonblock"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("special_files_nonblock", "9P2000.L", cfg);
+    let _socket = make_special(&test_dir);
+    server.walk(twalk("fifo")).expect("failed to walk");
+    server.lopen(&tlopen).expect("failed to open FIFO");
+
+    assert!("special_files=bogus".parse::<Config>().is_err());
+}
+
+#[test]
+fn rules() {
+    let cfg: Config = "rule=/subdir/nested=none:rule=/subdir=read"
+        .parse()
+        .expect("failed to parse config");
+    assert_eq!(
+        cfg.rules[1],
+        Rule {
+            pattern: "/subdir".to_string(),
+            access: Access::Read,
+        }
+    );
+    let (test_dir, mut server) = setup_config("rules", "9P2000.L", cfg);
+    let name = |name: &str| P9String::new(name).unwrap();
+    let errno = |rmsg: io::Result<Rmessage>| rmsg.err().and_then(|e| e.raw_os_error());
+
+    // Walks stop before the files that can't be reached.
+    let rwalk = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![name("subdir"), name("nested")],
+        })
+        .expect("failed to walk");
+    assert_eq!(rwalk.wqids.len(), 1);
+    assert!(!server.fids.contains(ROOT_FID + 1));
+
+    let b = ROOT_FID + 2;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        b,
+        vec![name("subdir"), name("b")],
+    );
+    let rmsg = server.dispatch(Ok(Tmessage::Lopen(Tlopen {
+        fid: b,
+        flags: P9_RDWR,
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EACCES));
+    server
+        .dispatch(Ok(Tmessage::Lopen(Tlopen {
+            fid: b,
+            flags: P9_RDONLY,
+        })))
+        .expect("failed to open file for reading");
+    check_content(&mut server, b"hello, world!", b);
+
+    // Links would give the file the access of their own names.
+    let rmsg = server.dispatch(Ok(Tmessage::Link(Tlink {
+        dfid: ROOT_FID,
+        fid: b,
+        name: name("b"),
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EACCES));
+
+    let rmsg = server.dispatch(Ok(Tmessage::Remove(Tremove { fid: b })));
+    assert_eq!(errno(rmsg), Some(libc::EACCES));
+    assert!(!server.fids.contains(b));
+    assert!(test_dir.join("subdir/b").exists());
+
+    // Anything that no rule matches is fully accessible.
+    let dir = ROOT_FID + 3;
+    walk(&mut server, &*test_dir, ROOT_FID, dir, vec![name("subdir")]);
+    let rmsg = server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+        dfid: dir,
+        name: name("new"),
+        mode: 0o755,
+        gid: 0,
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EACCES));
+    server
+        .dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: name("new"),
+            mode: 0o755,
+            gid: 0,
+        })))
+        .expect("failed to create directory");
+}
+
+#[test]
+fn links() {
+    let cfg: Config = "links=same_dir".parse().expect("failed to parse config");
+    assert_eq!(cfg.links, LinkPolicy::SameDir);
+    assert!("links=some".parse::<Config>().is_err());
+    let (test_dir, mut server) = setup_config("links", "9P2000.L", cfg);
+    let name = |name: &str| P9String::new(name).unwrap();
+
+    let fid = ROOT_FID + 1;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "f", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    let dir = fid + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, dir, vec![name("subdir")]);
+    let err = server
+        .link(Tlink {
+            dfid: dir,
+            fid,
+            name: name("g"),
+        })
+        .expect_err("linked file into another directory");
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+    server
+        .link(Tlink {
+            dfid: ROOT_FID,
+            fid,
+            name: name("g"),
+        })
+        .expect("failed to link file");
+
+    // Both names are the same file.
+    let link = dir + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, link, vec![name("g")]);
+    for fid in [fid, link] {
+        let rgetattr = server
+            .get_attr(&Tgetattr {
+                fid,
+                request_mask: P9_GETATTR_BASIC,
+            })
+            .expect("failed to get attributes");
+        assert_eq!(rgetattr.nlink, 2);
+        check_qid(
+            &rgetattr.qid,
+            &fs::symlink_metadata(test_dir.join("f")).unwrap(),
+        );
+    }
+
+    server.cfg.links = LinkPolicy::None;
+    let err = server
+        .link(Tlink {
+            dfid: ROOT_FID,
+            fid,
+            name: name("h"),
+        })
+        .expect_err("linked file");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+fn fid_timeout() {
+    let cfg: Config = "fid_timeout=60".parse().expect("failed to parse config");
+    assert_eq!(cfg.fid_timeout, Some(Duration::from_secs(60)));
+    let (test_dir, mut server) = setup_config("fid_timeout", "9P2000.L", cfg);
+    server.cfg.fid_timeout = Some(Duration::from_millis(300));
+    let expired = Arc::new(Mutex::new(Vec::new()));
+    let hook_expired = expired.clone();
+    server.set_fid_expiry_hook(Box::new(move |fid| hook_expired.lock().unwrap().push(fid)));
+
+    let (idle, used) = (ROOT_FID + 1, ROOT_FID + 2);
+    walk(&mut server, &*test_dir, ROOT_FID, idle, Vec::new());
+    walk(&mut server, &*test_dir, ROOT_FID, used, Vec::new());
+    thread::sleep(Duration::from_millis(200));
+    server
+        .dispatch(Ok(Tmessage::GetAttr(Tgetattr {
+            fid: used,
+            request_mask: P9_GETATTR_BASIC,
+        })))
+        .expect("failed to get attributes");
+    assert_eq!(server.expire_fids(), 0);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(server.expire_fids(), 1);
+    assert!(!server.fids.contains(idle));
+    assert!(server.fids.contains(used));
+    // The fid of the attach never expires.
+    assert!(server.fids.contains(ROOT_FID));
+    assert_eq!(*expired.lock().unwrap(), [idle]);
+}
+
+#[test]
+fn max_fids() {
+    let cfg: Config = "max_fids=3".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("max_fids", "9P2000.L", cfg);
+    let clone = |server: &mut Server, fid, newfid| {
+        server.walk(Twalk {
+            fid,
+            newfid,
+            wnames: Vec::new(),
+        })
+    };
+
+    walk(&mut server, &*test_dir, ROOT_FID, ROOT_FID + 1, Vec::new());
+    walk(&mut server, &*test_dir, ROOT_FID, ROOT_FID + 2, Vec::new());
+    let err = clone(&mut server, ROOT_FID, ROOT_FID + 3).expect_err("went over max_fids");
+    assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+    let err = server
+        .xattr_walk(&Txattrwalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 3,
+            name: P9String::new("").unwrap(),
+        })
+        .expect_err("went over max_fids");
+    assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+
+    // Walks that reuse their fid don't make new ones.
+    clone(&mut server, ROOT_FID + 2, ROOT_FID + 2).expect("failed to walk fid to itself");
+    server
+        .clunk(&Tclunk { fid: ROOT_FID + 2 })
+        .expect("failed to clunk fid");
+    clone(&mut server, ROOT_FID, ROOT_FID + 3).expect("failed to walk under max_fids");
+}
+
+#[test]
+fn dry_run() {
+    let cfg: Config = "dry_run=succeed".parse().expect("failed to parse config");
+    assert!("dry_run=maybe".parse::<Config>().is_err());
+    let (test_dir, mut server) = setup_config("dry_run", "9P2000.L", cfg);
+    let content = create_local_file(&*test_dir, "f");
+    let requests = Arc::new(Mutex::new(0));
+    let hook_requests = requests.clone();
+    server.set_dry_run_hook(Box::new(move |_| *hook_requests.lock().unwrap() += 1));
+    let name = |name: &str| P9String::new(name).unwrap();
+    let errno = |rmsg: io::Result<Rmessage>| rmsg.err().and_then(|e| e.raw_os_error());
+
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![name("f")]);
+    server
+        .dispatch(Ok(Tmessage::Lopen(Tlopen {
+            fid,
+            flags: P9_RDWR | P9_TRUNC,
+        })))
+        .expect("failed to open file");
+    match server.dispatch(Ok(Tmessage::Write(Twrite {
+        fid,
+        offset: 0,
+        data: Data(b"new".to_vec()),
+    }))) {
+        Ok(Rmessage::Write(Rwrite { count })) => assert_eq!(count, 3),
+        _ => panic!("failed to write"),
+    }
+    // Reads still go through, and see that nothing changed.
+    check_content(&mut server, &content, fid);
+
+    server
+        .dispatch(Ok(Tmessage::UnlinkAt(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: name("f"),
+            flags: 0,
+        })))
+        .expect("failed to unlink file");
+    let rmsg = server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+        dfid: ROOT_FID,
+        name: name("d"),
+        mode: 0o755,
+        gid: 0,
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EROFS));
+    assert_eq!(fs::read(test_dir.join("f")).unwrap(), content);
+    assert!(!test_dir.join("d").exists());
+    assert_eq!(*requests.lock().unwrap(), 4);
+
+    server.cfg.dry_run = Some(DryRun::Fail);
+    let rmsg = server.dispatch(Ok(Tmessage::UnlinkAt(Tunlinkat {
+        dirfd: ROOT_FID,
+        name: name("f"),
+        flags: 0,
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EROFS));
+    assert_eq!(*requests.lock().unwrap(), 5);
+}
+
+#[test]
+fn freeze() {
+    let cfg: Config = "freeze_fails=true".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("freeze", "9P2000.L", cfg);
+    let errno = |rmsg: io::Result<Rmessage>| rmsg.err().and_then(|e| e.raw_os_error());
+    let mkdir = |name: &str| {
+        Ok(Tmessage::Mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new(name).unwrap(),
+            mode: 0o755,
+            gid: 0,
+        }))
+    };
+
+    // Files that are open for writing are committed.
+    let fid = ROOT_FID + 1;
+    create(&mut server, &*test_dir, ROOT_FID, fid, "f", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    server.freeze().expect("failed to freeze");
+
+    assert_eq!(errno(server.dispatch(mkdir("d"))), Some(libc::EAGAIN));
+    let rmsg = server.dispatch(Ok(Tmessage::Write(Twrite {
+        fid,
+        offset: 0,
+        data: Data(b"hello".to_vec()),
+    })));
+    assert_eq!(errno(rmsg), Some(libc::EAGAIN));
+    server
+        .dispatch(Ok(Tmessage::GetAttr(Tgetattr {
+            fid,
+            request_mask: P9_GETATTR_BASIC,
+        })))
+        .expect("failed to get attributes");
+
+    server.thaw();
+    server
+        .dispatch(mkdir("d"))
+        .expect("failed to create directory");
+    assert!(test_dir.join("d").is_dir());
+}
+
+#[test]
+fn no_symlinks() {
+    let cfg: Config = "no_symlinks=true".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("no_symlinks", "9P2000.L", cfg);
+    create_local_symlink(&test_dir, "symlink", "subdir");
+
+    let err = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![
+                P9String::new("symlink").unwrap(),
+                P9String::new("b").unwrap(),
+            ],
+        })
+        .expect_err("walked through a symlink");
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    // Not even as part of a single name.
+    let err = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 1,
+            wnames: vec![P9String::new("symlink/b").unwrap()],
+        })
+        .expect_err("walked through a symlink");
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+
+    // Everything else can still be reached, and the symlink is still listed.
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        ROOT_FID + 1,
+        vec![
+            P9String::new("subdir").unwrap(),
+            P9String::new("b").unwrap(),
+        ],
+    );
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID,
+            flags: 0,
+        })
+        .expect("failed to open directory");
+    assert!(readdir(&mut server, ROOT_FID).any(|dirent| dirent.name == "symlink"));
+}
+
+// Returns a stat with every field set to the value that tells Twstat not to change it.
+fn dont_touch_stat() -> Stat {
+    Stat {
+        ty: u16::MAX,
+        dev: u32::MAX,
+        qid: Qid {
+            ty: u8::MAX,
+            version: u32::MAX,
+            path: u64::MAX,
+        },
+        mode: u32::MAX,
+        atime: u32::MAX,
+        mtime: u32::MAX,
+        length: u64::MAX,
+        name: Default::default(),
+        uid: Default::default(),
+        gid: Default::default(),
+        muid: Default::default(),
+        extension: Default::default(),
+        n_uid: u32::MAX,
+        n_gid: u32::MAX,
+        n_muid: u32::MAX,
+    }
+}
+
+#[test]
+fn dotu_version() {
+    let (_test_dir, server) = setup_version("dotu_version", "9P2000.u");
+    assert_eq!(server.dialect, Dialect::Unix);
+}
+
+#[test]
+fn dotu_stat() {
+    let (test_dir, mut server) = setup_version("dotu_stat", "9P2000.u");
+
+    let name = "世界.txt";
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    let Rstat { stat } = server.stat(&Tstat { fid }).expect("failed to stat file");
+    let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
+
+    check_qid(&stat.qid, &md);
+    assert_eq!(stat.name, name);
+    assert_eq!(stat.mode, md.mode() & 0o777);
+    assert_eq!(stat.length, md.size());
+    assert_eq!(stat.mtime, md.mtime() as u32);
+    assert_eq!(stat.n_uid, md.uid());
+    assert_eq!(stat.n_gid, md.gid());
+    assert_eq!(stat.uid, &*md.uid().to_string());
+
+    let Rstat { stat } = server
+        .stat(&Tstat { fid: ROOT_FID })
+        .expect("failed to stat root");
+    assert_eq!(stat.mode & P9_DMDIR, P9_DMDIR);
+}
+
+#[test]
+fn dotu_read_dir() {
+    let (test_dir, mut server) = setup_version("dotu_read_dir", "9P2000.u");
+
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![]);
+    server
+        .open(&Topen {
+            fid,
+            mode: P9_OREAD,
+        })
+        .expect("failed to open directory");
+
+    // Use a small count so that the directory has to be read across multiple messages.
+    let mut names = HashSet::new();
+    let mut offset = 0;
+    loop {
+        let tread = Tread {
+            fid,
+            offset,
+            count: 128,
+        };
+        let Rread { data } = server.read(&tread).expect("failed to read directory");
+        if data.is_empty() {
+            break;
+        }
+        offset += data.len() as u64;
+
+        let mut cursor = Cursor::new(data.0);
+        while cursor.position() < cursor.get_ref().len() as u64 {
+            let stat: Stat = WireFormat::decode(&mut cursor).expect("failed to decode stat");
+            let md = fs::symlink_metadata(
+                test_dir.join(std::str::from_utf8(stat.name.as_bytes()).unwrap()),
+            )
+            .expect("failed to get metadata for entry");
+            check_qid(&stat.qid, &md);
+            assert!(names.insert(stat.name));
+        }
+    }
+
+    let expected: HashSet<P9String> = fs::read_dir(&*test_dir)
+        .expect("failed to read directory")
+        .map(|e| P9String::try_from(e.unwrap().file_name().as_os_str()).unwrap())
+        .collect();
+    assert_eq!(names, expected);
+
+    // Reads must continue from where the last one left off.
+    let tread = Tread {
+        fid,
+        offset: 1,
+        count: 128,
+    };
+    server
+        .read(&tread)
+        .expect_err("successfully read from an invalid offset");
+}
+
+#[test]
+fn remove_on_close() {
+    let (test_dir, mut server) = setup_version("remove_on_close", "9P2000.u");
+    create_local_file(&test_dir, "f");
+
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new("f").unwrap()],
+    );
+    server
+        .open(&Topen {
+            fid,
+            mode: P9_ORDWR | P9_ORCLOSE,
+        })
+        .expect("failed to open file");
+
+    // The file is removed from wherever it is when the fid is clunked.
+    server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: P9String::new("f").unwrap(),
+            newdirfid: ROOT_FID,
+            newname: P9String::new("g").unwrap(),
+        })
+        .expect("failed to rename file");
+    assert!(test_dir.join("g").exists());
+    server.clunk(&Tclunk { fid }).expect("failed to clunk fid");
+    assert!(!test_dir.join("g").exists());
+
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![]);
+    server
+        .create(Tcreate {
+            fid,
+            name: P9String::new("h").unwrap(),
+            perm: 0o644,
+            mode: P9_ORDWR | P9_ORCLOSE,
+            extension: P9String::new("").unwrap(),
+        })
+        .expect("failed to create file");
+    assert!(test_dir.join("h").exists());
+
+    // So are those of fids that go away along with the session.
+    server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.u").unwrap(),
+        })
+        .expect("failed to negotiate version");
+    assert!(!test_dir.join("h").exists());
+}
+
+#[test]
+fn tmpfile() {
+    let (test_dir, mut server) = setup("tmpfile");
+    let entries = || {
+        fs::read_dir(&*test_dir)
+            .expect("failed to read directory")
+            .count()
+    };
+    let before = entries();
+
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![]);
+    server
+        .lopen(&Tlopen {
+            fid,
+            flags: P9_TMPFILE | P9_RDWR,
+        })
+        .expect("failed to open unnamed file");
+    server
+        .write(&Twrite {
+            fid,
+            offset: 0,
+            data: Data(b"hello".to_vec()),
+        })
+        .expect("failed to write");
+    assert_eq!(entries(), before);
+
+    server
+        .link(Tlink {
+            dfid: ROOT_FID,
+            fid,
+            name: P9String::new("t").unwrap(),
+        })
+        .expect("failed to link unnamed file");
+    server.clunk(&Tclunk { fid }).expect("failed to clunk fid");
+    assert_eq!(
+        fs::read(test_dir.join("t")).expect("failed to read file"),
+        b"hello"
+    );
+
+    let err = server
+        .lopen(&Tlopen {
+            fid: ROOT_FID,
+            flags: P9_TMPFILE,
+        })
+        .expect_err("opened an unnamed file read-only");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+}
+
+#[test]
+fn dotu_wstat() {
+    let (test_dir, mut server) = setup_version("dotu_wstat", "9P2000.u");
+
+    let name = "existing";
+    create_local_file(&test_dir, name);
+
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    let mut stat = dont_touch_stat();
+    stat.mode = 0o640;
+    stat.length = 17;
+    stat.mtime = 1245247825;
+    server
+        .wstat(&Twstat { fid, stat })
+        .expect("failed to wstat file");
+
+    let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
+    assert_eq!(md.mode() & 0o777, 0o640);
+    assert_eq!(md.size(), 17);
+    assert_eq!(md.mtime(), 1245247825);
+
+    let newname = "renamed";
+    let mut stat = dont_touch_stat();
+    stat.name = P9String::new(newname).unwrap();
+    server
+        .wstat(&Twstat { fid, stat })
+        .expect("failed to rename file");
+
+    assert!(!test_dir.join(name).exists());
+    assert!(test_dir.join(newname).exists());
+
+    let mut stat = dont_touch_stat();
+    stat.mode = P9_DMDIR | 0o755;
+    server
+        .wstat(&Twstat { fid, stat })
+        .expect_err("successfully turned a file into a directory");
+}
+
+#[test]
+fn dotu_create() {
+    let (test_dir, mut server) = setup_version("dotu_create", "9P2000.u");
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![]);
+    let rcreate = server
+        .create(Tcreate {
+            fid,
+            name: P9String::new(name).unwrap(),
+            perm: 0o600,
+            mode: P9_ORDWR,
+            extension: Default::default(),
+        })
+        .expect("failed to create file");
+
+    let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
+    check_qid(&rcreate.qid, &md);
+    assert_eq!(md.mode() & 0o777, 0o600);
+    write(&mut server, &test_dir, name, fid, P9_RDWR);
+
+    let name = "dir";
+    let fid = ROOT_FID + 2;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![]);
+    let rcreate = server
+        .create(Tcreate {
+            fid,
+            name: P9String::new(name).unwrap(),
+            perm: P9_DMDIR | 0o755,
+            mode: P9_OREAD,
+            extension: Default::default(),
+        })
+        .expect("failed to create directory");
+
+    let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for dir");
+    assert!(md.is_dir());
+    check_qid(&rcreate.qid, &md);
+}
+
+#[test]
+fn dotu_remove() {
+    let (test_dir, mut server) = setup_version("dotu_remove", "9P2000.u");
+
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![
+            P9String::new("subdir").unwrap(),
+            P9String::new("b").unwrap(),
+        ],
+    );
+    server
+        .remove(&Tremove { fid })
+        .expect("failed to remove file");
+    assert!(!test_dir.join("subdir/b").exists());
+
+    // The fid is clunked by the remove.
+    server
+        .clunk(&Tclunk { fid })
+        .expect_err("successfully clunked removed fid");
+
+    let fid = ROOT_FID + 2;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new("subdir").unwrap()],
+    );
+    server
+        .remove(&Tremove { fid })
+        .expect_err("successfully removed non-empty directory");
+
+    server
+        .remove(&Tremove { fid: ROOT_FID })
+        .expect_err("successfully removed root directory");
+    assert!(test_dir.exists());
+}
+
+#[test]
+fn dotu_error() {
+    let (_test_dir, mut server) = setup_version("dotu_error", "9P2000.u");
+
+    let tframe = Tframe {
+        tag: 7,
+        msg: Ok(Tmessage::Clunk(Tclunk { fid: ROOT_FID + 1 })),
+    };
+    let mut request = Vec::new();
+    tframe
+        .encode(&mut request)
+        .expect("failed to encode request");
+
+    let mut response = Vec::new();
+    server
+        .handle_message(&mut Cursor::new(request), &mut response)
+        .expect("failed to handle message");
+
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert_eq!(rframe.tag, 7);
+    match rframe.msg {
+        Rmessage::Error(rerror) => assert_eq!(rerror.errno, libc::EBADF as u32),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+}
+
+#[test]
+fn legacy_version() {
+    let (_test_dir, server) = setup_version("legacy_version", "9P2000");
+    assert_eq!(server.dialect, Dialect::Legacy);
+
+    // Unknown extensions fall back to plain 9P2000.
+    let (_test_dir, mut server) = setup_version("legacy_version_ext", "9P2000.u");
+    let rversion = server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.x").unwrap(),
+        })
+        .expect("failed to get version from server");
+    assert_eq!(rversion.version, "9P2000");
+    assert_eq!(server.dialect, Dialect::Legacy);
+}
+
+// Sends a raw request with type `ty` and body `body` to `server` and returns the raw response.
+fn legacy_request(server: &mut Server, ty: u8, body: &[u8]) -> Vec<u8> {
+    let mut request = Vec::new();
+    ((body.len() + 7) as u32).encode(&mut request).unwrap();
+    ty.encode(&mut request).unwrap();
+    3u16.encode(&mut request).unwrap();
+    request.extend_from_slice(body);
+
+    let mut response = Vec::new();
+    server
+        .handle_message(&mut Cursor::new(request), &mut response)
+        .expect("failed to handle message");
+    response
+}
+
+#[test]
+fn legacy_messages() {
+    let (test_dir, mut server) = setup_version("legacy_messages", "9P2000");
+
+    // A Tcreate without the 9P2000.u extension.
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, vec![]);
+    let mut body = Vec::new();
+    fid.encode(&mut body).unwrap();
+    P9String::new(name).unwrap().encode(&mut body).unwrap();
+    0o600u32.encode(&mut body).unwrap();
+    P9_ORDWR.encode(&mut body).unwrap();
+    let response = legacy_request(&mut server, 114, &body);
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    match rframe.msg {
+        Rmessage::Create(rcreate) => {
+            let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata");
+            check_qid(&rcreate.qid, &md);
+        }
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+
+    // The stat in an Rstat ends after the muid.
+    let response = legacy_request(&mut server, 124, &fid.to_le_bytes());
+    let Rstat { stat } = server.stat(&Tstat { fid }).expect("failed to stat file");
+    let expected = stat.byte_size_dialect(Dialect::Legacy);
+    // An empty extension and three numeric ids.
+    assert_eq!(expected, stat.byte_size() - 2 - 3 * 4);
+    assert_eq!(response.len() as u32, 7 + 2 + expected);
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    match rframe.msg {
+        Rmessage::Stat(Rstat { stat }) => {
+            assert_eq!(stat.name, name);
+            assert!(stat.extension.is_empty());
+            assert_eq!(stat.n_uid, u32::MAX);
+        }
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+
+    // An Rerror only carries the error string.
+    let response = legacy_request(&mut server, 120, &(ROOT_FID + 10).to_le_bytes());
+    let mut cursor = Cursor::new(response);
+    let size: u32 = WireFormat::decode(&mut cursor).unwrap();
+    let ty: u8 = WireFormat::decode(&mut cursor).unwrap();
+    let tag: u16 = WireFormat::decode(&mut cursor).unwrap();
+    let ename: P9String = WireFormat::decode(&mut cursor).unwrap();
+    assert_eq!(ty, 107);
+    assert_eq!(tag, 3);
+    assert_eq!(size, 7 + ename.byte_size());
+    assert_eq!(cursor.position(), size as u64);
+}
+
+// Reads the entire contents of the xattr fid `fid`.
+fn read_xattr_fid<F: FileSystem>(server: &mut Server<F>, fid: u32, size: u64) -> Vec<u8> {
+    let mut value = Vec::new();
+    while (value.len() as u64) < size {
+        let tread = Tread {
+            fid,
+            offset: value.len() as u64,
+            count: 5,
+        };
+        let Rread { data } = server.read(&tread).expect("failed to read xattr");
+        assert!(!data.is_empty());
+        value.extend_from_slice(&data);
+    }
+    assert_eq!(value.len() as u64, size);
+    value
+}
+
+#[test]
+fn xattr() {
+    let (test_dir, mut server) = setup("xattr");
+
+    let name = "existing";
+    create_local_file(&test_dir, name);
+
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    // Set an attribute by converting a clone of the fid into an xattr fid.
+    let attr = P9String::new("user.p9.test").unwrap();
+    let value = b"some attribute value";
+    let xfid = ROOT_FID + 2;
+    walk(&mut server, test_dir.join(name), fid, xfid, vec![]);
+    server
+        .xattr_create(&Txattrcreate {
+            fid: xfid,
+            name: attr.clone(),
+            attr_size: value.len() as u64,
+            flags: 0,
+        })
+        .expect("failed to create xattr");
+    for (i, chunk) in value.chunks(8).enumerate() {
+        let twrite = Twrite {
+            fid: xfid,
+            offset: (i * 8) as u64,
+            data: Data(chunk.to_vec()),
+        };
+        server.write(&twrite).expect("failed to write xattr");
+    }
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to set xattr");
+
+    // Read it back.
+    let rxattrwalk = server
+        .xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: attr.clone(),
+        })
+        .expect("failed to walk to xattr");
+    assert_eq!(read_xattr_fid(&mut server, xfid, rxattrwalk.size), value);
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    // An empty name lists all the attributes.
+    let rxattrwalk = server
+        .xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: Default::default(),
+        })
+        .expect("failed to list xattrs");
+    let names = read_xattr_fid(&mut server, xfid, rxattrwalk.size);
+    assert!(names.split(|&b| b == 0).any(|name| name == attr.as_bytes()));
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    // Writing fewer bytes than promised fails when the fid is clunked.
+    walk(&mut server, test_dir.join(name), fid, xfid, vec![]);
+    server
+        .xattr_create(&Txattrcreate {
+            fid: xfid,
+            name: attr.clone(),
+            attr_size: 4,
+            flags: 0,
+        })
+        .expect("failed to create xattr");
+    let twrite = Twrite {
+        fid: xfid,
+        offset: 0,
+        data: Data(b"ab".to_vec()),
+    };
+    server.write(&twrite).expect("failed to write xattr");
+    let err = server
+        .clunk(&Tclunk { fid: xfid })
+        .expect_err("successfully set truncated xattr");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    // A zero-sized attribute removes it.
+    walk(&mut server, test_dir.join(name), fid, xfid, vec![]);
+    server
+        .xattr_create(&Txattrcreate {
+            fid: xfid,
+            name: attr.clone(),
+            attr_size: 0,
+            flags: 0,
+        })
+        .expect("failed to create xattr");
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to remove xattr");
+
+    let err = server
+        .xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: attr,
+        })
+        .expect_err("successfully walked to removed xattr");
+    assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+}
+
+#[test]
+fn posix_acl() {
+    let cfg: Config = "uid_map=5000/1234/1:gid_map=6000/2345/1"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("posix_acl", "9P2000.L", cfg);
+
+    let name = "existing";
+    create_local_file(&test_dir, name);
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    // A version and then entries of a tag, the permissions and an id.
+    let acl = |user: u32, group: u32| {
+        let mut value = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, user),
+            (0x04, 4, u32::MAX),
+            (0x08, 4, group),
+            (0x10, 4, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    };
+    let attr = P9String::new("system.posix_acl_access").unwrap();
+    let xfid = ROOT_FID + 2;
+    let set = |server: &mut Server, value: &[u8]| {
+        walk(server, test_dir.join(name), fid, xfid, vec![]);
+        server
+            .xattr_create(&Txattrcreate {
+                fid: xfid,
+                name: attr.clone(),
+                attr_size: value.len() as u64,
+                flags: 0,
+            })
+            .expect("failed to create xattr");
+        server
+            .write(&Twrite {
+                fid: xfid,
+                offset: 0,
+                data: Data(value.to_vec()),
+            })
+            .expect("failed to write xattr");
+        server.clunk(&Tclunk { fid: xfid })
+    };
+
+    // The ids in the ACL are mapped to the host ones on the way in.
+    set(&mut server, &acl(5000, 6000)).expect("failed to set ACL");
+    let path = CString::new(test_dir.join(name).into_os_string().into_vec()).unwrap();
+    let mut value = vec![0u8; 256];
+    // Safe because this only writes up to `value.len()` bytes to `value`.
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            attr.as_c_str().as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    assert!(
+        len >= 0,
+        "failed to get ACL: {}",
+        io::Error::last_os_error()
+    );
+    value.truncate(len as usize);
+    assert_eq!(value, acl(1234, 2345));
+
+    // And back to the client ones on the way out.
+    let rxattrwalk = server
+        .xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: attr.clone(),
+        })
+        .expect("failed to walk to ACL");
+    assert_eq!(
+        read_xattr_fid(&mut server, xfid, rxattrwalk.size),
+        acl(5000, 6000)
+    );
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    // Values that aren't ACLs are rejected.
+    let mut bad = acl(5000, 6000);
+    bad.pop();
+    let err = set(&mut server, &bad).expect_err("set a truncated ACL");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+}
+
+#[test]
+fn xattr_larger_than_msize() {
+    // Host file systems may not have room for a value this large, so keep it in memory.
+    let mut server = setup_filesystem(Tmpfs::new(), Default::default());
+    let xfid = ROOT_FID + 1;
+    let clone = |server: &mut Server<Tmpfs>| {
+        server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: xfid,
+                wnames: Vec::new(),
+            })
+            .expect("failed to clone fid");
+    };
+
+    // The value takes many messages to send, in any order, and to read back.
+    let attr = P9String::new("user.p9.large").unwrap();
+    let value: Vec<u8> = (0..60000u32).map(|i| (i % 251) as u8).collect();
+    let chunk_size = DEFAULT_BUFFER_SIZE as usize - 64;
+    let set = |server: &mut Server<Tmpfs>, skip: Option<usize>| {
+        clone(server);
+        server
+            .xattr_create(&Txattrcreate {
+                fid: xfid,
+                name: attr.clone(),
+                attr_size: value.len() as u64,
+                flags: 0,
+            })
+            .expect("failed to create xattr");
+        for (i, chunk) in value.chunks(chunk_size).enumerate().rev() {
+            if Some(i) == skip {
+                continue;
+            }
+            let rwrite = server
+                .write(&Twrite {
+                    fid: xfid,
+                    offset: (i * chunk_size) as u64,
+                    data: Data(chunk.to_vec()),
+                })
+                .expect("failed to write xattr");
+            assert_eq!(rwrite.count as usize, chunk.len());
+        }
+        server.clunk(&Tclunk { fid: xfid })
+    };
+    let err = set(&mut server, Some(3)).expect_err("set xattr with a hole in it");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    set(&mut server, None).expect("failed to set xattr");
+
+    let rxattrwalk = server
+        .xattr_walk(&Txattrwalk {
+            fid: ROOT_FID,
+            newfid: xfid,
+            name: attr,
+        })
+        .expect("failed to walk to xattr");
+    assert_eq!(rxattrwalk.size, value.len() as u64);
+    let mut read = Vec::new();
+    loop {
+        let Rread { data } = server
+            .read(&Tread {
+                fid: xfid,
+                offset: read.len() as u64,
+                count: u32::MAX,
+            })
+            .expect("failed to read xattr");
+        if data.is_empty() {
+            break;
+        }
+        assert!(data.len() < DEFAULT_BUFFER_SIZE as usize);
+        read.extend_from_slice(&data);
+    }
+    assert_eq!(read, value);
+}
+
+#[test]
+fn capability_xattr() {
+    let name = "existing";
+    let fid = ROOT_FID + 1;
+    let xfid = ROOT_FID + 2;
+    let setup_file = |test_name, cfg: &str| {
+        let cfg: Config = cfg.parse().expect("failed to parse config");
+        let (test_dir, mut server) = setup_config(test_name, "9P2000.L", cfg);
+        create_local_file(&test_dir, name);
+        walk(
+            &mut server,
+            &*test_dir,
+            ROOT_FID,
+            fid,
+            vec![P9String::new(name).unwrap()],
+        );
+        walk(&mut server, test_dir.join(name), fid, xfid, vec![]);
+        (test_dir, server)
+    };
+    let xattr_create = |server: &mut Server, attr: &str, size: usize| {
+        server.xattr_create(&Txattrcreate {
+            fid: xfid,
+            name: P9String::new(attr).unwrap(),
+            attr_size: size as u64,
+            flags: 0,
+        })
+    };
+    let xattr_walk = |server: &mut Server, attr: &str| {
+        server.xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: P9String::new(attr).unwrap(),
+        })
+    };
+
+    let (_test_dir, mut server) = setup_file("capability_xattr_deny", "capability_xattr=deny");
+    let err =
+        xattr_create(&mut server, "security.capability", 4).expect_err("set security.capability");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    xattr_create(&mut server, "user.p9.security.capability", 0)
+        .expect("failed to create other xattr");
+
+    // With remapping, clients set and see an attribute that the host keeps under another name.
+    let (test_dir, mut server) = setup_file("capability_xattr_remap", "capability_xattr=remap");
+    let value = b"not really a capability";
+    xattr_create(&mut server, "security.capability", value.len()).expect("failed to create xattr");
+    server
+        .write(&Twrite {
+            fid: xfid,
+            offset: 0,
+            data: Data(value.to_vec()),
+        })
+        .expect("failed to write xattr");
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to set xattr");
+
+    let path = CString::new(test_dir.join(name).into_os_string().into_vec()).unwrap();
+    let mut host_value = vec![0u8; 256];
+    // Safe because this only writes up to `host_value.len()` bytes to `host_value`.
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            c"user.p9.security.capability".as_ptr(),
+            host_value.as_mut_ptr().cast(),
+            host_value.len(),
+        )
+    };
+    assert!(
+        len >= 0,
+        "failed to get xattr: {}",
+        io::Error::last_os_error()
+    );
+    assert_eq!(&host_value[..len as usize], value);
+
+    let rxattrwalk =
+        xattr_walk(&mut server, "security.capability").expect("failed to walk to xattr");
+    assert_eq!(read_xattr_fid(&mut server, xfid, rxattrwalk.size), value);
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    let rxattrwalk = xattr_walk(&mut server, "").expect("failed to list xattrs");
+    let names = read_xattr_fid(&mut server, xfid, rxattrwalk.size);
+    let names: Vec<&[u8]> = names.split(|&b| b == 0).collect();
+    assert!(names.contains(&&b"security.capability"[..]));
+    assert!(!names.contains(&&b"user.p9.security.capability"[..]));
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    // The name that the host keeps it under can't be used directly.
+    let err = xattr_walk(&mut server, "user.p9.security.capability")
+        .expect_err("walked to remapped xattr");
+    assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+    walk(&mut server, test_dir.join(name), fid, xfid, vec![]);
+    let err = xattr_create(&mut server, "user.p9.security.capability", 0)
+        .expect_err("removed remapped xattr");
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+fn xattr_filter() {
+    let cfg: Config = "xattr_allow=user.*:xattr_deny=user.secret.*"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("xattr_filter", "9P2000.L", cfg);
+
+    let name = "existing";
+    create_local_file(&test_dir, name);
+    let fid = ROOT_FID + 1;
+    let xfid = ROOT_FID + 2;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new(name).unwrap()],
+    );
+
+    // Attributes that were set on the host are only seen if the patterns allow them.
+    let path = CString::new(test_dir.join(name).into_os_string().into_vec()).unwrap();
+    for attr in [c"user.visible", c"user.secret.key"] {
+        // Safe because this only reads from `path`, `attr` and the value.
+        let ret =
+            unsafe { libc::setxattr(path.as_ptr(), attr.as_ptr(), b"value".as_ptr().cast(), 5, 0) };
+        assert_eq!(
+            ret,
+            0,
+            "failed to set xattr: {}",
+            io::Error::last_os_error()
+        );
+    }
+    let xattr_walk = |server: &mut Server, attr: &str| {
+        server.xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: P9String::new(attr).unwrap(),
+        })
+    };
+    let rxattrwalk = xattr_walk(&mut server, "").expect("failed to list xattrs");
+    assert_eq!(
+        read_xattr_fid(&mut server, xfid, rxattrwalk.size),
+        b"user.visible\0"
+    );
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+    let rxattrwalk = xattr_walk(&mut server, "user.visible").expect("failed to walk to xattr");
+    assert_eq!(read_xattr_fid(&mut server, xfid, rxattrwalk.size), b"value");
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+    let err = xattr_walk(&mut server, "user.secret.key").expect_err("walked to denied xattr");
+    assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+
+    // Nor can they be set or removed.
+    for attr in ["user.secret.key", "trusted.overlay.opaque"] {
+        walk(&mut server, test_dir.join(name), fid, xfid, vec![]);
+        let err = server
+            .xattr_create(&Txattrcreate {
+                fid: xfid,
+                name: P9String::new(attr).unwrap(),
+                attr_size: 0,
+                flags: 0,
+            })
+            .expect_err("removed hidden xattr");
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        server
+            .clunk(&Tclunk { fid: xfid })
+            .expect("failed to clunk");
+    }
+}
+
+fn tlock(fid: u32, type_: u8, proc_id: u32) -> Tlock {
+    Tlock {
+        fid,
+        type_,
+        flags: 0,
+        start: 0,
+        length: 0,
+        proc_id,
+        client_id: P9String::new("unittest").unwrap(),
+    }
+}
+
+#[test]
+fn posix_locks() {
+    let (test_dir, mut server) = setup("posix_locks");
+
+    let name = "locked";
+    create_local_file(&test_dir, name);
+
+    let fid1 = ROOT_FID + 1;
+    let fid2 = ROOT_FID + 2;
+    open(&mut server, &*test_dir, ROOT_FID, name, fid1, P9_RDWR).expect("failed to open file");
+    open(&mut server, &*test_dir, ROOT_FID, name, fid2, P9_RDWR).expect("failed to open file");
+
+    let rlock = server
+        .lock(&tlock(fid1, P9_LOCK_TYPE_WRLCK, 1))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+
+    // A different owner conflicts, even through a different fid.
+    let rlock = server
+        .lock(&tlock(fid2, P9_LOCK_TYPE_RDLCK, 2))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_BLOCKED);
+
+    let tgetlock = Tgetlock {
+        fid: fid2,
+        type_: P9_LOCK_TYPE_RDLCK,
+        start: 0,
+        length: 0,
+        proc_id: 2,
+        client_id: P9String::new("unittest").unwrap(),
+    };
+    let rgetlock = server.get_lock(&tgetlock).expect("failed to get lock");
+    assert_eq!(rgetlock.type_, P9_LOCK_TYPE_WRLCK);
+
+    // The same owner doesn't conflict with itself and can release the lock through another fid.
+    let rlock = server
+        .lock(&tlock(fid2, P9_LOCK_TYPE_WRLCK, 1))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+    let rlock = server
+        .lock(&tlock(fid2, P9_LOCK_TYPE_UNLCK, 1))
+        .expect("failed to unlock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+
+    let rgetlock = server.get_lock(&tgetlock).expect("failed to get lock");
+    assert_eq!(rgetlock.type_, P9_LOCK_TYPE_UNLCK);
+    let rlock = server
+        .lock(&tlock(fid2, P9_LOCK_TYPE_WRLCK, 2))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+
+    // Clunking the fid releases the locks taken through it.
+    server
+        .clunk(&Tclunk { fid: fid2 })
+        .expect("failed to clunk fid");
+    let rlock = server
+        .lock(&tlock(fid1, P9_LOCK_TYPE_WRLCK, 1))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+}
+
+#[test]
+fn flock_locks() {
+    let (test_dir, mut server) = setup_version("flock_locks", "9P2000.L+flock");
+
+    let name = "locked";
+    create_local_file(&test_dir, name);
+
+    let fid1 = ROOT_FID + 1;
+    let fid2 = ROOT_FID + 2;
+    open(&mut server, &*test_dir, ROOT_FID, name, fid1, P9_RDONLY).expect("failed to open file");
+    open(&mut server, &*test_dir, ROOT_FID, name, fid2, P9_RDONLY).expect("failed to open file");
+
+    let flock = |fid, type_| Tlock {
+        flags: P9_LOCK_FLAGS_FLOCK,
+        ..tlock(fid, type_, 1)
+    };
+
+    // flock locks belong to the fid, not to the lock owner, and don't need write access.
+    let rlock = server
+        .lock(&flock(fid1, P9_LOCK_TYPE_WRLCK))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+    let rlock = server
+        .lock(&flock(fid2, P9_LOCK_TYPE_RDLCK))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_BLOCKED);
+
+    // They don't interact with POSIX locks either.
+    let rlock = server
+        .lock(&tlock(fid2, P9_LOCK_TYPE_RDLCK, 2))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+
+    // Only whole-file locks are supported.
+    let tlock = Tlock {
+        length: 10,
+        ..flock(fid2, P9_LOCK_TYPE_RDLCK)
+    };
+    server
+        .lock(&tlock)
+        .expect_err("successfully took a partial flock lock");
+
+    server
+        .clunk(&Tclunk { fid: fid1 })
+        .expect("failed to clunk fid");
+    let rlock = server
+        .lock(&flock(fid2, P9_LOCK_TYPE_WRLCK))
+        .expect("failed to lock file");
+    assert_eq!(rlock.status, P9_LOCK_SUCCESS);
+
+    // Clients that didn't ask for flock locks get POSIX locks, which need write access.
+    let (test_dir, mut server) = setup("flock_locks_plain");
+    create_local_file(&test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid1, P9_RDONLY).expect("failed to open file");
+    let err = server
+        .lock(&flock(fid1, P9_LOCK_TYPE_WRLCK))
+        .expect_err("took a write lock on a file opened for reading");
+    assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+}
+
+// Encodes `msg` with tag `tag`, passes it to `server`, and returns the raw response.
+fn round_trip(server: &mut Server, tag: u16, msg: Tmessage) -> Vec<u8> {
+    let mut request = Vec::new();
+    Tframe { tag, msg: Ok(msg) }
+        .encode(&mut request)
+        .expect("failed to encode request");
+
+    let mut response = Vec::new();
+    server
+        .handle_message(&mut Cursor::new(request), &mut response)
+        .expect("failed to handle message");
+    response
+}
+
+#[test]
+fn flush() {
+    let (test_dir, mut server) = setup("flush");
+
+    // A request that is flushed before the server gets to it is never handled or replied to.
+    let flusher = server.flusher();
+    flusher.queue(9);
+    flusher.flush(9);
+    let twalk = Twalk {
+        fid: ROOT_FID,
+        newfid: ROOT_FID + 1,
+        wnames: vec![],
+    };
+    let response = round_trip(&mut server, 9, Tmessage::Walk(twalk));
+    assert!(response.is_empty());
+    assert!(!server.fids.contains(ROOT_FID + 1));
+
+    // The Tflush itself is always answered right away.
+    let response = round_trip(&mut server, 10, Tmessage::Flush(Tflush { oldtag: 9 }));
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert_eq!(rframe.tag, 10);
+    assert!(matches!(rframe.msg, Rmessage::Flush));
+
+    // The tag can be reused afterwards.
+    let twalk = Twalk {
+        fid: ROOT_FID,
+        newfid: ROOT_FID + 1,
+        wnames: vec![],
+    };
+    let response = round_trip(&mut server, 9, Tmessage::Walk(twalk));
+    assert!(!response.is_empty());
+    assert!(server.fids.contains(ROOT_FID + 1));
+
+    // Flushing a tag that isn't in flight, such as one that was already replied to, doesn't
+    // stop the next request with it from being handled.
+    flusher.flush(9);
+    let response = round_trip(
+        &mut server,
+        9,
+        Tmessage::Clunk(Tclunk { fid: ROOT_FID + 1 }),
+    );
+    assert!(!response.is_empty());
+    assert!(!server.fids.contains(ROOT_FID + 1));
+
+    // A request that is cancelled while it is being handled is aborted.
+    let name = "foo";
+    create_local_file(&test_dir, name);
+    let fid = ROOT_FID + 2;
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+    server.cancel.cancel();
+    let err = server
+        .read(&Tread {
+            fid,
+            offset: 0,
+            count: DEFAULT_BUFFER_SIZE,
+        })
+        .expect_err("successfully read in a cancelled request");
+    assert_eq!(err.raw_os_error(), Some(libc::EINTR));
+}
+
+// Authenticates a client that writes back the secret that it read from the auth fid.
+struct EchoAuthenticator;
+
+struct EchoSession {
+    secret: Vec<u8>,
+    authenticated: bool,
+}
+
+impl Authenticator for EchoAuthenticator {
+    fn start(
+        &mut self,
+        uname: &str,
+        _n_uname: Option<u32>,
+        _aname: &str,
+    ) -> io::Result<Box<dyn AuthSession>> {
+        if uname == "nobody" {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        Ok(Box::new(EchoSession {
+            secret: format!("secret for {}", uname).into_bytes(),
+            authenticated: false,
+        }))
+    }
+}
+
+impl AuthSession for EchoSession {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len(), self.secret.len());
+        buf[..len].copy_from_slice(&self.secret[..len]);
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.authenticated = buf == &self.secret[..];
+        Ok(buf.len())
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+}
+
+#[test]
+fn authenticated_attach() {
+    let (_test_dir, mut server) = setup("authenticated_attach");
+    server.set_authenticator(Box::new(EchoAuthenticator));
+
+    let afid = ROOT_FID + 1;
+    let fid = ROOT_FID + 2;
+    let tauth = |uname: &str| Tauth {
+        afid,
+        uname: P9String::new(uname).unwrap(),
+        aname: Default::default(),
+        n_uname: 1000,
+    };
+    let tattach = |afid, uname: &str| Tattach {
+        fid,
+        afid,
+        uname: P9String::new(uname).unwrap(),
+        aname: Default::default(),
+        n_uname: 1000,
+    };
+
+    // Attaching without authenticating is not allowed.
+    let err = server
+        .attach(&tattach(P9_NOFID, "unittest"))
+        .expect_err("successfully attached without authenticating");
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+    // The authenticator can reject users outright.
+    server
+        .auth(&tauth("nobody"))
+        .expect_err("successfully started authenticating rejected user");
+
+    let rauth = server
+        .auth(&tauth("unittest"))
+        .expect("failed to authenticate");
+    assert_eq!(rauth.aqid.ty, P9_QTAUTH);
+
+    // The fid can't be used until the client has answered the challenge.
+    server
+        .attach(&tattach(afid, "unittest"))
+        .expect_err("successfully attached before authenticating");
+
+    let Rread { data } = server
+        .read(&Tread {
+            fid: afid,
+            offset: 0,
+            count: DEFAULT_BUFFER_SIZE,
+        })
+        .expect("failed to read challenge");
+    server
+        .write(&Twrite {
+            fid: afid,
+            offset: 0,
+            data,
+        })
+        .expect("failed to write response");
+
+    // An auth fid only works for the user that it was established for.
+    server
+        .attach(&tattach(afid, "someone else"))
+        .expect_err("successfully attached as a different user");
+    server
+        .attach(&tattach(afid, "unittest"))
+        .expect("failed to attach after authenticating");
+
+    server
+        .clunk(&Tclunk { fid: afid })
+        .expect("failed to clunk auth fid");
+}
+
+#[test]
+fn msize_negotiation() {
+    let (_test_dir, mut server) = setup("msize_negotiation");
+
+    // Frames larger than the negotiated msize are rejected without being read.
+    let twrite = Twrite {
+        fid: ROOT_FID,
+        offset: 0,
+        data: Data(vec![0; DEFAULT_BUFFER_SIZE as usize]),
+    };
+    let mut request = Vec::new();
+    Tframe {
+        tag: 1,
+        msg: Ok(Tmessage::Write(twrite)),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+    let mut reader = Cursor::new(request);
+    let mut response = Vec::new();
+    let err = server
+        .handle_message(&mut reader, &mut response)
+        .expect_err("successfully handled oversized frame");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(reader.position(), 4);
+    assert!(response.is_empty());
+
+    // The msize can be renegotiated, but never beyond what the server is configured for.
+    let rversion = server
+        .version(&Tversion {
+            msize: 2 * DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L").unwrap(),
+        })
+        .expect("failed to renegotiate version");
+    assert_eq!(rversion.msize, 2 * DEFAULT_BUFFER_SIZE);
+
+    let rversion = server
+        .version(&Tversion {
+            msize: u32::MAX,
+            version: P9String::new("9P2000.L").unwrap(),
+        })
+        .expect("failed to renegotiate version");
+    assert_eq!(rversion.msize, server.cfg.msize);
+
+    server
+        .version(&Tversion {
+            msize: 16,
+            version: P9String::new("9P2000.L").unwrap(),
+        })
+        .expect_err("successfully negotiated tiny msize");
+}
+
+#[test]
+fn long_walks() {
+    let (test_dir, mut server) = setup("long_walks");
+
+    let depth = 2 * MAXWELEM + 3;
+    let mut path = test_dir.to_path_buf();
+    let mut wnames = Vec::with_capacity(depth);
+    for i in 0..depth {
+        let name = format!("d{}", i);
+        path.push(&name);
+        wnames.push(P9String::new(name).unwrap());
+    }
+    fs::create_dir_all(&path).expect("failed to create nested directories");
+
+    // A single walk may not have more than MAXWELEM names.
+    let twalk = Twalk {
+        fid: ROOT_FID,
+        newfid: ROOT_FID + 1,
+        wnames: wnames[..MAXWELEM + 1].to_vec(),
+    };
+    let err = server
+        .walk(twalk)
+        .expect_err("successfully walked too many names");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let walks = Twalk::split(ROOT_FID, ROOT_FID + 1, wnames);
+    assert_eq!(walks.len(), 3);
+    for twalk in walks {
+        assert!(twalk.wnames.len() <= MAXWELEM);
+        let expected = twalk.wnames.len();
+        let rwalk = server.walk(twalk).expect("failed to walk");
+        assert_eq!(rwalk.wqids.len(), expected);
+    }
+
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: ROOT_FID + 1,
+            request_mask: P9_GETATTR_BASIC,
+        })
+        .expect("failed to getattr");
+    let md = fs::symlink_metadata(&path).expect("failed to get metadata");
+    check_qid(&rgetattr.qid, &md);
+}
+
+#[test]
+fn vendor_messages() {
+    let (_test_dir, mut server) = setup("vendor_messages");
+
+    // Echo the oldtag of a Tflush-shaped request back in an Rwrite-shaped reply.
+    let mut registry = VendorRegistry::new();
+    registry
+        .register(200, |flush: Tflush| {
+            Ok(Rwrite {
+                count: u32::from(flush.oldtag) * 2,
+            })
+        })
+        .expect("failed to register vendor message");
+    registry
+        .register(200, |flush: Tflush| Ok(flush))
+        .expect_err("successfully registered vendor message twice");
+    registry
+        .register(104, |flush: Tflush| Ok(flush))
+        .expect_err("successfully registered Tattach as a vendor message");
+    server.set_vendor_registry(registry);
+
+    let mut body = Vec::new();
+    21u16.encode(&mut body).unwrap();
+    let tvendor = Tvendor {
+        ty: 200,
+        body: VendorBody(body),
+    };
+    let response = round_trip(&mut server, 4, Tmessage::Vendor(tvendor));
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert_eq!(rframe.tag, 4);
+    match rframe.msg {
+        Rmessage::Vendor(rvendor) => {
+            assert_eq!(rvendor.ty, 201);
+            let rwrite: Rwrite = WireFormat::decode(&mut Cursor::new(rvendor.body.0))
+                .expect("failed to decode vendor reply");
+            assert_eq!(rwrite.count, 42);
+        }
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+
+    // Unregistered vendor messages are rejected without breaking the connection.
+    let tvendor = Tvendor {
+        ty: 202,
+        body: Default::default(),
+    };
+    let response = round_trip(&mut server, 5, Tmessage::Vendor(tvendor));
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    match rframe.msg {
+        Rmessage::Lerror(rlerror) => assert_eq!(rlerror.ecode, libc::EOPNOTSUPP as u32),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+}
+
+#[test]
+fn borrowed_writes() {
+    let (test_dir, mut server) = setup("borrowed_writes");
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDWR).expect("failed to open file");
+
+    let content = b"not copied on the way in".to_vec();
+    let twrite = Twrite {
+        fid,
+        offset: 3,
+        data: Data(content.clone()),
+    };
+    let mut request = Vec::new();
+    Tframe {
+        tag: 6,
+        msg: Ok(Tmessage::Write(twrite)),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+
+    let (tag, write) = TwriteRef::decode_frame(&request)
+        .expect("failed to decode frame")
+        .expect("frame does not hold a Twrite");
+    assert_eq!(tag, 6);
+    assert_eq!(&*write.data, &content[..]);
+    assert_eq!(
+        write.data.0.as_ptr(),
+        request[request.len() - content.len()..].as_ptr()
     );
 
-    // Create ~4K files in the directory and then attempt to read them all.
-    let mut filenames = HashSet::with_capacity(4096);
-    for i in 0..4096 {
-        let name = format!("file_{}", i);
-        create_local_file(&newdir, &name);
-        assert!(filenames.insert(P9String::new(name).unwrap()));
+    let mut response = Vec::new();
+    server
+        .handle_message_slice(&request, &mut response)
+        .expect("failed to handle message");
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert_eq!(rframe.tag, 6);
+    match rframe.msg {
+        Rmessage::Write(rwrite) => assert_eq!(rwrite.count, content.len() as u32),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+    let actual = fs::read(test_dir.join(name)).expect("failed to read back content from file");
+    assert_eq!(actual[3..3 + content.len()], content[..]);
+
+    // Every other message goes through the regular path.
+    let mut request = Vec::new();
+    Tframe {
+        tag: 7,
+        msg: Ok(Tmessage::Clunk(Tclunk { fid })),
     }
+    .encode(&mut request)
+    .expect("failed to encode request");
+    assert!(TwriteRef::decode_frame(&request)
+        .expect("failed to decode frame")
+        .is_none());
+    let mut response = Vec::new();
+    server
+        .handle_message_slice(&request, &mut response)
+        .expect("failed to handle message");
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert!(matches!(rframe.msg, Rmessage::Clunk));
+    assert!(!server.fids.contains(fid));
+}
 
-    let fid = dfid + 1;
-    open(&mut server, &newdir, dfid, "", fid, P9_DIRECTORY).expect("Failed to open directory");
-    for f in readdir(&mut server, fid) {
-        let dir_name = Path::new(std::str::from_utf8(f.name.as_bytes()).unwrap());
-        let path = newdir.join(dir_name);
+// Records the buffers of every call to `write_vectored`.
+#[derive(Default)]
+struct VectoredWriter {
+    calls: Vec<Vec<Vec<u8>>>,
+}
+
+impl io::Write for VectoredWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[io::IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        // Take no more than 8 bytes at a time so that the rest has to be written again.
+        let mut call = Vec::new();
+        let mut left = 8;
+        for buf in bufs {
+            let n = min(left, buf.len());
+            call.push(buf[..n].to_vec());
+            left -= n;
+        }
+        self.calls.push(call);
+        Ok(8 - left)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn vectored_replies() {
+    let (test_dir, mut server) = setup("vectored_replies");
+
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, Vec::new());
+    open(&mut server, &*test_dir, fid, "", fid, P9_DIRECTORY).expect("failed to open directory");
+    let treaddir = || Treaddir {
+        fid,
+        offset: 0,
+        count: DEFAULT_BUFFER_SIZE,
+    };
+    let mut expected = Vec::new();
+    Rframe {
+        tag: 5,
+        msg: Rmessage::Readdir(
+            server
+                .readdir(&treaddir())
+                .expect("failed to read directory"),
+        ),
+    }
+    .encode(&mut expected)
+    .expect("failed to encode reply");
+
+    // The header and the entries are written together, and what is left of both after a short
+    // write is written again.
+    let mut request = Vec::new();
+    Tframe {
+        tag: 5,
+        msg: Ok(Tmessage::Readdir(treaddir())),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+    let mut writer = VectoredWriter::default();
+    server
+        .handle_message(&mut Cursor::new(&request), &mut writer)
+        .expect("failed to handle message");
+    assert_eq!(writer.calls[0].len(), 2);
+    assert_eq!(writer.calls[1], [&expected[8..11], &expected[11..16]]);
+    let written: Vec<u8> = writer.calls.concat().concat();
+    assert_eq!(written, expected);
+}
+
+#[test]
+fn buffer_pool() {
+    let (test_dir, mut server) = setup("buffer_pool");
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    let content = create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+    // Middlewares make reads copy their data into the Rread.
+    server.add_middleware(Box::new(LogMiddleware {
+        name: "log",
+        log: Default::default(),
+    }));
+
+    // After the first few messages, requests, replies and the data of reads all reuse the
+    // buffers of the ones before them.
+    let misses = server.buffers.misses();
+    for tag in 0..8 {
+        let response = round_trip(
+            &mut server,
+            tag,
+            Tmessage::GetAttr(Tgetattr {
+                fid,
+                request_mask: P9_GETATTR_BASIC,
+            }),
+        );
+        let rframe: Rframe =
+            WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+        assert!(matches!(rframe.msg, Rmessage::GetAttr(_)));
+
+        let tread = Tread {
+            fid,
+            offset: 0,
+            count: 64,
+        };
+        let response = round_trip(&mut server, tag, Tmessage::Read(tread));
+        let rframe: Rframe =
+            WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+        match rframe.msg {
+            Rmessage::Read(rread) => assert_eq!(&*rread.data, &content[..64]),
+            msg => panic!("unexpected response: {:?}", msg),
+        }
+    }
+    assert!(server.buffers.misses() - misses <= 2);
+    assert!(server.buffers.hits() >= 30);
+}
+
+#[test]
+fn reads_in_frame() {
+    let (test_dir, mut server) = setup("reads_in_frame");
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+
+    // Data that is read straight into the frame makes for the same replies as data that is read
+    // into an Rread and then encoded.
+    for (tag, offset, count) in [(5, 0, 64), (6, 10, 1000), (7, LOCAL_FILE_LEN, 64)] {
+        let tread = || Tread { fid, offset, count };
+        let mut expected = Vec::new();
+        Rframe {
+            tag,
+            msg: Rmessage::Read(server.read(&tread()).expect("failed to read file")),
+        }
+        .encode(&mut expected)
+        .expect("failed to encode reply");
+
+        let mut request = Vec::new();
+        Tframe {
+            tag,
+            msg: Ok(Tmessage::Read(tread())),
+        }
+        .encode(&mut request)
+        .expect("failed to encode request");
+        assert_eq!(
+            round_trip(&mut server, tag, Tmessage::Read(tread())),
+            expected
+        );
+
+        let mut buf = vec![0xa5; DEFAULT_BUFFER_SIZE as usize];
+        let len = server
+            .handle_message_into(&mut Cursor::new(&request), &mut buf)
+            .expect("failed to handle message");
+        assert_eq!(buf[..len], expected[..]);
+        assert!(buf[len..].iter().all(|&b| b == 0xa5));
+
+        let mut response = Vec::new();
+        server
+            .handle_message_slice(&request, &mut response)
+            .expect("failed to handle message");
+        assert_eq!(response, expected);
+    }
+
+    // Nor does the frame hold on to the data of a read for the next reply.
+    let mut request = Vec::new();
+    Tframe {
+        tag: 8,
+        msg: Ok(Tmessage::Read(Tread {
+            fid,
+            offset: 0,
+            count: 64,
+        })),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+    let mut buf = [0; 16];
+    server
+        .handle_message_into(&mut Cursor::new(&request), &mut buf)
+        .expect_err("encoded frame into a buffer that is too small");
+    let response = round_trip(&mut server, 9, Tmessage::Clunk(Tclunk { fid }));
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert!(matches!(rframe.msg, Rmessage::Clunk));
+}
+
+#[test]
+fn spliced_reads() {
+    let (test_dir, mut server) = setup("spliced_reads");
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    let content = create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+
+    let (mut writer, mut reader) = UnixStream::pair().expect("failed to create socket pair");
+    let mut splice = |server: &mut Server, tag: u16, msg: Tmessage| {
+        let mut request = Vec::new();
+        Tframe { tag, msg: Ok(msg) }
+            .encode(&mut request)
+            .expect("failed to encode request");
+        server
+            .handle_message_splice(&mut Cursor::new(request), &mut writer)
+            .expect("failed to handle message");
+
+        let mut size = [0u8; 4];
+        reader.read_exact(&mut size).expect("failed to read reply");
+        let mut response = size.to_vec();
+        response.resize(u32::from_le_bytes(size) as usize, 0);
+        reader
+            .read_exact(&mut response[4..])
+            .expect("failed to read reply");
+        response
+    };
+
+    // Replies come out the same as they would have been copied, whether or not their data was
+    // spliced.
+    let reads = [(5, 0, 64), (6, 10, 1000), (7, LOCAL_FILE_LEN, 64)];
+    for (tag, offset, count) in reads {
+        let tread = || Tmessage::Read(Tread { fid, offset, count });
+        let spliced = splice(&mut server, tag, tread());
+        assert_eq!(spliced, round_trip(&mut server, tag, tread()));
+
+        let rframe: Rframe =
+            WireFormat::decode(&mut Cursor::new(spliced)).expect("failed to decode response");
+        assert_eq!(rframe.tag, tag);
+        let start = min(offset, LOCAL_FILE_LEN) as usize;
+        let end = min(offset + u64::from(count), LOCAL_FILE_LEN) as usize;
+        match rframe.msg {
+            Rmessage::Read(rread) => assert_eq!(&*rread.data, &content[start..end]),
+            msg => panic!("unexpected response: {:?}", msg),
+        }
+    }
+    assert_eq!(server.splice_pipe.as_ref().map(|pipe| pipe.len()), Some(0));
+
+    // Errors, and everything other than Tread, are replied to as usual.
+    let tread = Tread {
+        fid: fid + 1,
+        offset: 0,
+        count: 64,
+    };
+    let response = splice(&mut server, 8, Tmessage::Read(tread));
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    match rframe.msg {
+        Rmessage::Lerror(rlerror) => assert_eq!(rlerror.ecode, libc::EBADF as u32),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+    let response = splice(&mut server, 9, Tmessage::Clunk(Tclunk { fid }));
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    assert!(matches!(rframe.msg, Rmessage::Clunk));
+}
+
+#[test]
+fn encode_into_buffer() {
+    let (_test_dir, mut server) = setup("encode_into_buffer");
+
+    let mut request = Vec::new();
+    Tframe {
+        tag: 8,
+        msg: Ok(Tmessage::GetAttr(Tgetattr {
+            fid: ROOT_FID,
+            request_mask: P9_GETATTR_BASIC,
+        })),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+
+    // The reply goes into the front of the buffer and nothing past it is touched.
+    let mut buf = vec![0xa5; DEFAULT_BUFFER_SIZE as usize];
+    let len = server
+        .handle_message_into(&mut Cursor::new(&request), &mut buf)
+        .expect("failed to handle message");
+    assert!(buf[len..].iter().all(|&b| b == 0xa5));
+
+    let mut expected = Vec::new();
+    server
+        .handle_message(&mut Cursor::new(&request), &mut expected)
+        .expect("failed to handle message");
+    assert_eq!(buf[..len], expected[..]);
+
+    let rframe = Rframe {
+        tag: 8,
+        msg: Rmessage::Clunk,
+    };
+    let mut buf = [0; 6];
+    rframe
+        .encode_to_slice(&mut buf, Dialect::Linux)
+        .expect_err("encoded frame into a buffer that is too small");
+    assert_eq!(buf, [0; 6]);
+}
+
+#[test]
+fn readdirplus() {
+    let (test_dir, mut server) = setup_version("readdirplus", "9P2000.L+readdirplus");
+    assert!(server.extensions().contains(Extensions::READDIRPLUS));
+
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, Vec::new());
+    open(&mut server, &*test_dir, fid, "", fid, P9_DIRECTORY).expect("failed to open directory");
+
+    let Rreaddirplus { data } = server
+        .readdirplus(&Treaddirplus {
+            fid,
+            offset: 0,
+            count: DEFAULT_BUFFER_SIZE,
+        })
+        .expect("failed to read directory");
+
+    let mut names = Vec::new();
+    let mut cursor = Cursor::new(data.0);
+    while cursor.position() < cursor.get_ref().len() as u64 {
+        let entry: DirentPlus = WireFormat::decode(&mut cursor).expect("failed to decode dirent");
+        let name = std::str::from_utf8(entry.dirent.name.as_bytes()).unwrap();
+        let md = fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata");
+
+        check_qid(&entry.dirent.qid, &md);
+        assert_eq!(entry.attr.valid, P9_GETATTR_BASIC);
+        check_qid(&entry.attr.qid, &md);
+        assert_eq!(entry.attr.mode, md.mode());
+        assert_eq!(entry.attr.nlink, md.nlink());
+        assert_eq!(entry.attr.size, md.size());
+        assert_eq!(entry.attr.mtime_sec, md.mtime() as u64);
+        assert_eq!(entry.attr.mtime_nsec, md.mtime_nsec() as u64);
+
+        names.push(name.to_string());
+    }
+    names.sort();
+    assert_eq!(names, [".", "..", "subdir", "世界.txt"]);
+
+    // The extension has to be asked for, and only exists for 9P2000.L.
+    let rversion = server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.u+readdirplus").unwrap(),
+        })
+        .expect("failed to get version from server");
+    assert_eq!(rversion.version, "9P2000.u");
+    assert!(server.extensions().is_empty());
+
+    let (_test_dir, mut server) = setup("readdirplus_disabled");
+    let err = server
+        .readdirplus(&Treaddirplus {
+            fid: ROOT_FID,
+            offset: 0,
+            count: DEFAULT_BUFFER_SIZE,
+        })
+        .expect_err("read directory without negotiating readdirplus");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
+
+// Encodes `tframe` followed by its checksum.
+fn checksummed(tframe: &Tframe) -> Vec<u8> {
+    let mut writer = ChecksumWriter::new(Vec::new());
+    tframe
+        .encode(&mut writer)
+        .expect("failed to encode request");
+    writer.finish().expect("failed to append checksum")
+}
+
+#[test]
+fn frame_checksums() {
+    let (_test_dir, mut server) = setup_version("frame_checksums", "9P2000.L+crc32c");
+    assert!(server.extensions().contains(Extensions::CRC32C));
+
+    let getattr = Tframe {
+        tag: 4,
+        msg: Ok(Tmessage::GetAttr(Tgetattr {
+            fid: ROOT_FID,
+            request_mask: P9_GETATTR_BASIC,
+        })),
+    };
+    let request = checksummed(&getattr);
+
+    // Replies are checksummed too, whichever way the request came in.
+    let mut response = Vec::new();
+    server
+        .handle_message(&mut Cursor::new(&request), &mut response)
+        .expect("failed to handle message");
+    let mut reader = ChecksumReader::new(Cursor::new(&response));
+    let rframe = Rframe::decode(&mut reader).expect("failed to decode response");
+    reader.verify().expect("response has a bad checksum");
+    assert!(matches!(rframe.msg, Rmessage::GetAttr(_)));
+
+    let mut slice_response = Vec::new();
+    server
+        .handle_message_slice(&request, &mut slice_response)
+        .expect("failed to handle message");
+    assert_eq!(slice_response, response);
+
+    let mut buf = vec![0; DEFAULT_BUFFER_SIZE as usize];
+    let len = server
+        .handle_message_into(&mut Cursor::new(&request), &mut buf)
+        .expect("failed to handle message");
+    assert_eq!(buf[..len], response[..]);
+
+    // A corrupted request is rejected before it is handled.
+    let mut corrupted = request.clone();
+    corrupted[7] ^= 0x01;
+    let err = server
+        .handle_message(&mut Cursor::new(&corrupted), &mut Vec::new())
+        .expect_err("handled a request with a bad checksum");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = server
+        .handle_message_slice(&corrupted, &mut Vec::new())
+        .expect_err("handled a request with a bad checksum");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // Tversion never carries a checksum, and turns them off unless it asks for them again.
+    let mut request = Vec::new();
+    Tframe {
+        tag: u16::MAX,
+        msg: Ok(Tmessage::Version(Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L").unwrap(),
+        })),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+    let mut response = Vec::new();
+    server
+        .handle_message(&mut Cursor::new(&request), &mut response)
+        .expect("failed to handle message");
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(&response)).expect("failed to decode response");
+    assert_eq!(rframe.byte_size() as usize, response.len());
+    assert!(server.extensions().is_empty());
+}
+
+#[test]
+fn ping() {
+    let (_test_dir, server) = setup_version("ping", "9P2000.L+readdirplus+ping");
+    assert!(server.extensions().contains(Extensions::PING));
+    server.ping().expect("failed to ping");
+
+    let (_test_dir, server) = setup("ping_disabled");
+    let err = server.ping().expect_err("pinged without negotiating ping");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
+
+#[test]
+fn reap_idle() {
+    let (_test_dir, mut server) = setup("reap_idle");
+    assert!(server.idle_deadline().is_none());
+    assert!(!server.reap_idle());
+    assert!(server.fids.contains(ROOT_FID));
+
+    let cfg: Config = "idle_timeout=3600".parse().expect("failed to parse config");
+    server.cfg.idle_timeout = cfg.idle_timeout;
+    let deadline = server.idle_deadline().expect("no idle deadline");
+    assert!(deadline > Instant::now());
+    assert!(!server.reap_idle());
+
+    // Every request counts as activity.
+    server.cfg.idle_timeout = Some(Duration::ZERO);
+    let before = Instant::now();
+    let mut request = Vec::new();
+    Tframe {
+        tag: 1,
+        msg: Ok(Tmessage::Ping),
+    }
+    .encode(&mut request)
+    .expect("failed to encode request");
+    server
+        .handle_message(&mut Cursor::new(&request), &mut Vec::new())
+        .expect("failed to handle message");
+    assert!(server.last_activity >= before);
+
+    assert!(server.reap_idle());
+    assert!(server.fids.is_empty());
+}
+
+// Negotiates the resume extension with `server` after giving it `store`.
+fn enable_resume(server: &mut Server, store: &SessionStore) {
+    server.set_session_store(store.clone());
+    let rversion = server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L+resume").unwrap(),
+        })
+        .expect("failed to get version from server");
+    assert_eq!(rversion.version, "9P2000.L+resume");
+}
+
+#[test]
+fn resume_session() {
+    let store = SessionStore::new(Duration::from_secs(60));
+
+    let (test_dir, mut server) = setup("resume_session");
+    enable_resume(&mut server, &store);
+    server
+        .attach(&Tattach {
+            fid: ROOT_FID,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new("").unwrap(),
+            n_uname: 1000,
+        })
+        .expect("failed to attach");
+    let fid = ROOT_FID + 1;
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        fid,
+        vec![P9String::new("subdir").unwrap()],
+    );
+
+    let Rresume { session } = server
+        .resume(&Tresume { session: None })
+        .expect("failed to get a session id");
+    assert_ne!(session, 0);
+    let Rresume { session: again } = server
+        .resume(&Tresume { session: None })
+        .expect("failed to get a session id");
+    assert_eq!(again, session);
+
+    // Dropping the server keeps the fids for the next connection.
+    let md = test_dir.join("subdir").symlink_metadata().unwrap();
+    drop(server);
+    assert_eq!(store.len(), 1);
+
+    let mut server = Server::new(&*test_dir, Default::default(), Default::default())
+        .expect("failed to create server");
+    enable_resume(&mut server, &store);
+    server
+        .resume(&Tresume {
+            session: Some(session ^ 1),
+        })
+        .expect_err("resumed an unknown session");
+    let rresume = server
+        .resume(&Tresume {
+            session: Some(session),
+        })
+        .expect("failed to resume session");
+    assert_eq!(rresume.session, session);
+    assert!(store.is_empty());
+    check_attr(&mut server, fid, &md);
+
+    // Tversion clunks the fids, so there is nothing left to resume.
+    enable_resume(&mut server, &store);
+    drop(server);
+    assert!(store.is_empty());
+}
+
+#[test]
+fn resume_expired_session() {
+    let store = SessionStore::new(Duration::ZERO);
+
+    let (test_dir, mut server) = setup("resume_expired_session");
+    enable_resume(&mut server, &store);
+    let Rresume { session } = server
+        .resume(&Tresume { session: None })
+        .expect("failed to get a session id");
+    drop(server);
+
+    let mut server = Server::new(&*test_dir, Default::default(), Default::default())
+        .expect("failed to create server");
+    server
+        .resume(&Tresume {
+            session: Some(session),
+        })
+        .expect_err("resumed without negotiating resume");
+    enable_resume(&mut server, &store);
+    let err = server
+        .resume(&Tresume {
+            session: Some(session),
+        })
+        .expect_err("resumed an expired session");
+    assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+}
+
+// Returns how many of the files that this process has open are `path`.
+fn open_count(path: &Path) -> usize {
+    fs::read_dir("/proc/self/fd")
+        .expect("failed to read /proc/self/fd")
+        .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+        .filter(|target| target == path)
+        .count()
+}
+
+#[test]
+fn reap_expired_sessions() {
+    let store = SessionStore::new(Duration::from_millis(20));
+
+    let (test_dir, mut server) = setup("reap_expired_sessions");
+    enable_resume(&mut server, &store);
+    server
+        .attach(&Tattach {
+            fid: ROOT_FID,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new("").unwrap(),
+            n_uname: 1000,
+        })
+        .expect("failed to attach");
+    let name = "foo.txt";
+    create_local_file(&*test_dir, name);
+    open(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        name,
+        ROOT_FID + 1,
+        P9_RDONLY,
+    )
+    .expect("failed to open file");
+    server
+        .resume(&Tresume { session: None })
+        .expect("failed to get a session id");
+    drop(server);
+
+    // Nothing else uses the store, so the file stays open after the session expires until the
+    // store is reaped.
+    let path = test_dir.join(name);
+    thread::sleep(Duration::from_millis(50));
+    assert_ne!(open_count(&path), 0);
+
+    let mut server = Server::new(&*test_dir, Default::default(), Default::default())
+        .expect("failed to create server");
+    server.set_session_store(store);
+    assert!(!server.reap_idle());
+    assert_eq!(open_count(&path), 0);
+}
+
+#[test]
+fn negotiate_extensions() {
+    let (_test_dir, mut server) = setup("negotiate_extensions");
+    assert!(server.extensions().is_empty());
+
+    // Unknown extensions are dropped from the reply, and so is resume without a session store.
+    let rversion = server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L+resume+frobnicate+ping+readdirplus").unwrap(),
+        })
+        .expect("failed to get version from server");
+    assert_eq!(rversion.version, "9P2000.L+readdirplus+ping");
+    assert_eq!(
+        server.extensions(),
+        Extensions::READDIRPLUS.union(Extensions::PING)
+    );
+
+    server.set_session_store(SessionStore::new(Duration::from_secs(1)));
+    let rversion = server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L+resume").unwrap(),
+        })
+        .expect("failed to get version from server");
+    assert_eq!(rversion.version, "9P2000.L+resume");
+    assert_eq!(server.extensions(), Extensions::RESUME);
 
-        let md = fs::symlink_metadata(path).expect("failed to get metadata for path");
-        check_qid(&f.qid, &md);
+    let rversion = server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L").unwrap(),
+        })
+        .expect("failed to get version from server");
+    assert_eq!(rversion.version, "9P2000.L");
+    assert!(server.extensions().is_empty());
+}
 
-        if f.name == "." || f.name == ".." {
-            assert_eq!(f.ty, libc::DT_DIR);
-        } else {
-            assert_eq!(f.ty, libc::DT_REG);
-            assert!(filenames.remove(&f.name));
+#[test]
+fn read_file_partial() {
+    let mut buf = vec![0; IO_CHUNK_SIZE * 3];
+
+    // An error after the first chunk cuts the read short, and so does a flush.
+    let cancel = CancelToken::default();
+    let mut calls = 0;
+    let read = |chunk: &mut [u8], _| {
+        calls += 1;
+        match calls {
+            1 => Ok(chunk.len()),
+            _ => Err(io::Error::from_raw_os_error(libc::EIO)),
         }
-    }
+    };
+    let count = read_file(read, FileType::Regular, &mut buf, 0, &cancel).expect("read failed");
+    assert_eq!(count, IO_CHUNK_SIZE);
 
-    assert!(filenames.is_empty());
+    let read = |chunk: &mut [u8], _| {
+        cancel.cancel();
+        Ok(chunk.len())
+    };
+    let count = read_file(read, FileType::Regular, &mut buf, 0, &cancel).expect("read failed");
+    assert_eq!(count, IO_CHUNK_SIZE);
+
+    // Only an error before anything was read fails the read.
+    let read = |_: &mut [u8], _| Ok(0);
+    let err = read_file(read, FileType::Regular, &mut buf, 0, &cancel).expect_err("read worked");
+    assert_eq!(err.raw_os_error(), Some(libc::EINTR));
 }
 
 #[test]
-fn mkdir() {
-    let (test_dir, mut server) = setup("mkdir");
+fn copy_range() {
+    let (test_dir, mut server) = setup_version("copy_range", "9P2000.L+copyrange");
+    assert!(server.extensions().contains(Extensions::COPYRANGE));
+
+    let content = create_local_file(&test_dir, "src");
+    let src_fid = ROOT_FID + 1;
+    open(&mut server, &*test_dir, ROOT_FID, "src", src_fid, P9_RDONLY)
+        .expect("failed to open source");
+    let dst_fid = ROOT_FID + 2;
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        dst_fid,
+        "dst",
+        P9_WRONLY,
+        0o644,
+    )
+    .expect("failed to create destination");
+
+    // Copies stop at the end of the source file.
+    let Rcopyrange { count } = server
+        .copy_range(&Tcopyrange {
+            src_fid,
+            src_offset: 10,
+            dst_fid,
+            dst_offset: 0,
+            count: LOCAL_FILE_LEN * 2,
+        })
+        .expect("failed to copy range");
+    assert_eq!(count, LOCAL_FILE_LEN - 10);
+    let copied = fs::read(test_dir.join("dst")).expect("failed to read back copy");
+    assert_eq!(copied[..], content[10..]);
+
+    let err = server
+        .copy_range(&Tcopyrange {
+            src_fid: ROOT_FID,
+            src_offset: 0,
+            dst_fid,
+            dst_offset: 0,
+            count: 1,
+        })
+        .expect_err("copied from a directory");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let (_test_dir, mut server) = setup("copy_range_disabled");
+    let err = server
+        .copy_range(&Tcopyrange {
+            src_fid: ROOT_FID,
+            src_offset: 0,
+            dst_fid: ROOT_FID,
+            dst_offset: 0,
+            count: 1,
+        })
+        .expect_err("copied without negotiating copyrange");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
 
-    let name = "conan";
-    let tmkdir = Tmkdir {
-        dfid: ROOT_FID,
-        name: P9String::new(name).unwrap(),
-        mode: 0o755,
-        gid: 0,
+#[test]
+fn clone_range() {
+    let (test_dir, mut server) = setup_version("clone_range", "9P2000.L+clonerange");
+    assert!(server.extensions().contains(Extensions::CLONERANGE));
+
+    let content = create_local_file(&test_dir, "src");
+    let src_fid = ROOT_FID + 1;
+    open(&mut server, &*test_dir, ROOT_FID, "src", src_fid, P9_RDONLY)
+        .expect("failed to open source");
+    let dst_fid = ROOT_FID + 2;
+    create(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        dst_fid,
+        "dst",
+        P9_WRONLY,
+        0o644,
+    )
+    .expect("failed to create destination");
+
+    // Only some file systems can share storage between files, and the others must say so cleanly.
+    let tclonerange = Tclonerange {
+        src_fid,
+        src_offset: 0,
+        dst_fid,
+        dst_offset: 0,
+        count: 0,
     };
+    match server.clone_range(&tclonerange) {
+        Ok(()) => {
+            let cloned = fs::read(test_dir.join("dst")).expect("failed to read back clone");
+            assert_eq!(cloned, content);
+        }
+        Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+    }
 
-    let rmkdir = server.mkdir(tmkdir).expect("failed to create directory");
-    let md =
-        fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for directory");
+    let (_test_dir, mut server) = setup("clone_range_disabled");
+    let err = server
+        .clone_range(&tclonerange)
+        .expect_err("cloned without negotiating clonerange");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
 
-    assert!(md.is_dir());
-    check_qid(&rmkdir.qid, &md);
+#[test]
+fn rename_at2() {
+    let (test_dir, mut server) = setup_version("rename_at2", "9P2000.L+renameat2");
+    assert!(server.extensions().contains(Extensions::RENAMEAT2));
+    let a = create_local_file(&test_dir, "a");
+    let b = create_local_file(&test_dir, "b");
+
+    let rename = |server: &mut Server, flags| {
+        server.rename_at2(&Trenameat2 {
+            olddirfid: ROOT_FID,
+            oldname: P9String::new("a").unwrap(),
+            newdirfid: ROOT_FID,
+            newname: P9String::new("b").unwrap(),
+            flags,
+        })
+    };
+    let err = rename(&mut server, P9_RENAME_NOREPLACE).expect_err("replaced an existing file");
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    let err = rename(&mut server, P9_RENAME_NOREPLACE | P9_RENAME_EXCHANGE)
+        .expect_err("renamed with conflicting flags");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    match rename(&mut server, P9_RENAME_EXCHANGE) {
+        Ok(()) => {
+            assert_eq!(fs::read(test_dir.join("a")).expect("failed to read a"), b);
+            assert_eq!(fs::read(test_dir.join("b")).expect("failed to read b"), a);
+        }
+        // Not every file system can swap files.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+        Err(e) => panic!("failed to exchange files: {}", e),
+    }
+
+    let (_test_dir, mut server) = setup("rename_at2_disabled");
+    let err = rename(&mut server, 0).expect_err("renamed without negotiating renameat2");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
 }
 
 #[test]
-fn unlink_all() {
-    let (test_dir, mut server) = setup("readdir");
+fn lseek() {
+    let (test_dir, mut server) = setup_version("lseek", "9P2000.L+lseek");
+    assert!(server.extensions().contains(Extensions::LSEEK));
+
+    // A file with 64 KiB of data after a 1 MiB hole. File systems that don't track holes treat
+    // the whole file as data.
+    let path = test_dir.join("sparse");
+    let f = File::create(&path).expect("failed to create sparse file");
+    f.write_all_at(&[0xa5; 64 * 1024], 1 << 20)
+        .expect("failed to write sparse file");
+    drop(f);
 
-    let mut next_fid = ROOT_FID + 1;
+    let fid = ROOT_FID + 1;
+    open(&mut server, &*test_dir, ROOT_FID, "sparse", fid, P9_RDONLY)
+        .expect("failed to open sparse file");
 
-    let mut dirs = VecDeque::new();
-    dirs.push_back((ROOT_FID, test_dir.to_path_buf()));
+    let Rlseek { offset } = server
+        .lseek(&Tlseek {
+            fid,
+            offset: 0,
+            whence: P9_SEEK_DATA,
+        })
+        .expect("failed to seek to data");
+    assert!(offset <= 1 << 20);
+
+    let Rlseek { offset } = server
+        .lseek(&Tlseek {
+            fid,
+            offset: 1 << 20,
+            whence: P9_SEEK_HOLE,
+        })
+        .expect("failed to seek to hole");
+    assert_eq!(offset, (1 << 20) + 64 * 1024);
+
+    // There's no data past the end of the file.
+    let err = server
+        .lseek(&Tlseek {
+            fid,
+            offset: 1 << 30,
+            whence: P9_SEEK_DATA,
+        })
+        .expect_err("found data past the end of the file");
+    assert_eq!(err.raw_os_error(), Some(libc::ENXIO));
+
+    let err = server
+        .lseek(&Tlseek {
+            fid,
+            offset: 0,
+            whence: libc::SEEK_CUR as u32,
+        })
+        .expect_err("seeked from the current position");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let (_test_dir, mut server) = setup("lseek_disabled");
+    let err = server
+        .lseek(&Tlseek {
+            fid: ROOT_FID,
+            offset: 0,
+            whence: P9_SEEK_DATA,
+        })
+        .expect_err("seeked without negotiating lseek");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
 
-    // First iterate over the whole directory.
-    let mut unlinks = VecDeque::new();
-    while let Some((dfid, dir)) = dirs.pop_front() {
-        let mut names = VecDeque::new();
-        for entry in fs::read_dir(dir).expect("failed to read directory") {
-            let entry = entry.expect("unable to iterate over directory");
-            let ft = entry
-                .file_type()
-                .expect("failed to get file type for entry");
-            if ft.is_dir() {
-                let fid = next_fid;
-                next_fid += 1;
+#[test]
+fn fallocate() {
+    let (test_dir, mut server) = setup_version("fallocate", "9P2000.L+fallocate");
+    assert!(server.extensions().contains(Extensions::FALLOCATE));
 
-                let wnames: Vec<P9String> = entry
-                    .path()
-                    .strip_prefix(&test_dir)
-                    .expect("test directory is not prefix of subdir")
-                    .components()
-                    .map(|c| P9String::try_from(c.as_os_str()).unwrap())
-                    .collect();
-                walk(&mut server, &*test_dir, ROOT_FID, fid, wnames);
-                dirs.push_back((fid, entry.path()));
-            }
+    let content = create_local_file(&test_dir, "file");
+    let fid = ROOT_FID + 1;
+    open(&mut server, &*test_dir, ROOT_FID, "file", fid, P9_RDWR).expect("failed to open file");
+    let path = test_dir.join("file");
 
-            names.push_back((
-                P9String::new(entry.file_name().as_bytes())
-                    .expect("failed to convert entry name to string"),
-                if ft.is_dir() {
-                    libc::AT_REMOVEDIR as u32
-                } else {
-                    0
-                },
-            ));
+    let mut tfallocate = Tfallocate {
+        fid,
+        mode: 0,
+        offset: 0,
+        length: 4096,
+    };
+    server
+        .fallocate(&tfallocate)
+        .expect("failed to preallocate");
+    let md = fs::metadata(&path).expect("failed to get metadata");
+    assert_eq!(md.len(), 4096);
+
+    // Not every file system supports every mode, but those that don't must say so.
+    tfallocate.mode = P9_FALLOC_FL_PUNCH_HOLE | P9_FALLOC_FL_KEEP_SIZE;
+    tfallocate.length = 100;
+    match server.fallocate(&tfallocate) {
+        Ok(()) => {
+            let data = fs::read(&path).expect("failed to read back file");
+            assert_eq!(data.len(), 4096);
+            assert!(data[..100].iter().all(|&b| b == 0));
+            assert_eq!(data[100..content.len()], content[100..]);
         }
+        Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+    }
 
-        unlinks.push_back((dfid, names));
+    tfallocate.mode = P9_FALLOC_FL_ZERO_RANGE;
+    tfallocate.offset = 8192;
+    match server.fallocate(&tfallocate) {
+        Ok(()) => {
+            let md = fs::metadata(&path).expect("failed to get metadata");
+            assert_eq!(md.len(), 8192 + 100);
+        }
+        Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
     }
 
-    // Now remove everything in reverse order.
-    while let Some((dfid, names)) = unlinks.pop_back() {
-        for (name, flags) in names {
-            let tunlinkat = Tunlinkat {
-                dirfd: dfid,
-                name,
-                flags,
-            };
+    // Holes can only be punched without changing the size, and modes don't mix.
+    for mode in [
+        P9_FALLOC_FL_PUNCH_HOLE,
+        P9_FALLOC_FL_PUNCH_HOLE | P9_FALLOC_FL_ZERO_RANGE,
+        0x08,
+    ] {
+        tfallocate.mode = mode;
+        let err = server
+            .fallocate(&tfallocate)
+            .expect_err("fallocate accepted an invalid mode");
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
 
-            server.unlink_at(tunlinkat).expect("failed to unlink path");
-        }
+    let (_test_dir, mut server) = setup("fallocate_disabled");
+    let err = server
+        .fallocate(&tfallocate)
+        .expect_err("allocated without negotiating fallocate");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
+
+#[test]
+fn fadvise() {
+    let (test_dir, mut server) = setup_version("fadvise", "9P2000.L+fadvise");
+    assert!(server.extensions().contains(Extensions::FADVISE));
+
+    create_local_file(&test_dir, "file");
+    let fid = ROOT_FID + 1;
+    open(&mut server, &*test_dir, ROOT_FID, "file", fid, P9_RDONLY).expect("failed to open file");
+
+    let mut tfadvise = Tfadvise {
+        fid,
+        offset: 0,
+        length: 0,
+        advice: P9_FADV_NORMAL,
+    };
+    for advice in [
+        P9_FADV_NORMAL,
+        P9_FADV_RANDOM,
+        P9_FADV_SEQUENTIAL,
+        P9_FADV_WILLNEED,
+        P9_FADV_DONTNEED,
+        P9_FADV_NOREUSE,
+    ] {
+        tfadvise.advice = advice;
+        server
+            .fadvise(&tfadvise)
+            .unwrap_or_else(|e| panic!("failed to give advice {}: {}", advice, e));
     }
+
+    tfadvise.advice = 6;
+    let err = server
+        .fadvise(&tfadvise)
+        .expect_err("accepted unknown advice");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let (_test_dir, mut server) = setup("fadvise_disabled");
+    tfadvise.fid = ROOT_FID;
+    tfadvise.advice = P9_FADV_NORMAL;
+    let err = server
+        .fadvise(&tfadvise)
+        .expect_err("gave advice without negotiating fadvise");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
 }
 
 #[test]
-fn rename_at() {
-    let (test_dir, mut server) = setup("rename");
+fn ioctl() {
+    let (test_dir, mut server) = setup_version("ioctl", "9P2000.L+ioctl");
+    assert!(server.extensions().contains(Extensions::IOCTL));
 
-    let name = "oldfile";
-    let content = create_local_file(&test_dir, name);
+    create_local_file(&test_dir, "file");
+    let fid = ROOT_FID + 1;
+    open(&mut server, &*test_dir, ROOT_FID, "file", fid, P9_RDONLY).expect("failed to open file");
 
-    let newname = "newfile";
-    let trename = Trenameat {
-        olddirfid: ROOT_FID,
-        oldname: P9String::new(name).unwrap(),
-        newdirfid: ROOT_FID,
-        newname: P9String::new(newname).unwrap(),
+    let mut tioctl = Tioctl {
+        fid,
+        request: ioctl::FIGETBSZ.request,
+        data: Data(Vec::new()),
     };
 
-    server.rename_at(trename).expect("failed to rename file");
+    // Nothing is allowed by default.
+    let err = server
+        .ioctl(&tioctl)
+        .expect_err("ran an ioctl that wasn't allowed");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
 
-    assert!(!test_dir.join(name).exists());
+    let cfg: Config = "ioctls=FIGETBSZ,FS_IOC_GETFLAGS"
+        .parse()
+        .expect("failed to parse config");
+    assert_eq!(
+        cfg.ioctls,
+        [ioctl::FIGETBSZ.request, ioctl::FS_IOC_GETFLAGS.request]
+    );
+    assert!("ioctls=FS_IOC_SETFLAGS".parse::<Config>().is_err());
+    server.cfg.ioctls = cfg.ioctls;
+
+    let Rioctl { data } = server.ioctl(&tioctl).expect("failed to run FIGETBSZ");
+    let block_size = u32::from_le_bytes(data[..].try_into().expect("wrong output size"));
+    assert!(block_size.is_power_of_two());
+
+    tioctl.data = Data(vec![0; 4]);
+    let err = server
+        .ioctl(&tioctl)
+        .expect_err("ran an ioctl with the wrong input");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    tioctl.request = ioctl::FS_IOC_GETVERSION.request;
+    tioctl.data = Data(Vec::new());
+    let err = server
+        .ioctl(&tioctl)
+        .expect_err("ran an ioctl that wasn't allowed");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+
+    let (_test_dir, mut server) = setup("ioctl_disabled");
+    tioctl.fid = ROOT_FID;
+    let err = server
+        .ioctl(&tioctl)
+        .expect_err("ran an ioctl without negotiating ioctl");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
 
-    let mut newcontent = Vec::with_capacity(content.len());
-    let size = File::open(test_dir.join(newname))
-        .expect("failed to open file")
-        .read_to_end(&mut newcontent)
-        .expect("failed to read new file content");
-    assert_eq!(size, content.len());
-    assert_eq!(newcontent, content);
+// Reads the events that are pending on the watch fid `fid`.
+fn read_watch_events(server: &mut Server, fid: u32) -> Vec<WatchEvent> {
+    let Rread { data } = server
+        .read(&Tread {
+            fid,
+            offset: 0,
+            count: DEFAULT_BUFFER_SIZE,
+        })
+        .expect("failed to read watch");
+
+    let mut events = Vec::new();
+    let mut cursor = Cursor::new(data.0);
+    while cursor.position() < cursor.get_ref().len() as u64 {
+        events.push(WireFormat::decode(&mut cursor).expect("failed to decode event"));
+    }
+    events
 }
 
-fn setlk_tlock(fid: u32, len: u64, start: u64, type_: i32) -> Tlock {
-    Tlock {
+#[test]
+fn watch() {
+    let (test_dir, mut server) = setup_version("watch", "9P2000.L+notify");
+    assert!(server.extensions().contains(Extensions::NOTIFY));
+
+    let fid = ROOT_FID + 1;
+    server
+        .watch(&Twatch {
+            fid: ROOT_FID,
+            newfid: fid,
+            mask: libc::IN_CREATE | libc::IN_DELETE,
+        })
+        .expect("failed to watch directory");
+    assert!(read_watch_events(&mut server, fid).is_empty());
+
+    fs::write(test_dir.join("new"), b"hello").expect("failed to create file");
+    fs::remove_file(test_dir.join("new")).expect("failed to remove file");
+    let events = read_watch_events(&mut server, fid);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].mask, libc::IN_CREATE);
+    assert_eq!(events[0].name, "new");
+    assert_eq!(events[1].mask, libc::IN_DELETE);
+    assert_eq!(events[1].name, "new");
+    assert!(read_watch_events(&mut server, fid).is_empty());
+
+    server
+        .clunk(&Tclunk { fid })
+        .expect("failed to clunk watch");
+
+    let err = server
+        .watch(&Twatch {
+            fid: ROOT_FID,
+            newfid: fid,
+            mask: libc::IN_ONESHOT,
+        })
+        .expect_err("watched with an invalid mask");
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let (_test_dir, mut server) = setup("watch_disabled");
+    let err = server
+        .watch(&Twatch {
+            fid: ROOT_FID,
+            newfid: fid,
+            mask: libc::IN_CREATE,
+        })
+        .expect_err("watched without negotiating notify");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+}
+
+#[test]
+fn lease() {
+    let table = LeaseTable::new();
+
+    let (test_dir, mut a) = setup_version("lease", "9P2000.L+lease");
+    assert!(a.extensions().contains(Extensions::LEASE));
+    a.set_lease_table(table.clone());
+
+    let mut b = Server::new(&*test_dir, Default::default(), Default::default())
+        .expect("failed to create server");
+    b.set_lease_table(table);
+    b.version(&Tversion {
+        msize: DEFAULT_BUFFER_SIZE,
+        version: P9String::new("9P2000.L+lease").unwrap(),
+    })
+    .expect("failed to get version from server");
+    b.attach(&Tattach {
+        fid: ROOT_FID,
+        afid: P9_NOFID,
+        uname: P9String::new("unittest").unwrap(),
+        aname: P9String::new("").unwrap(),
+        n_uname: 1000,
+    })
+    .expect("failed to attach");
+
+    create_local_file(&test_dir, "file");
+    let fid = ROOT_FID + 1;
+    open(&mut a, &*test_dir, ROOT_FID, "file", fid, P9_RDONLY).expect("failed to open file");
+    a.lease(&Tlease {
         fid,
-        type_: type_ as u8,
-        flags: 0,
-        start,
-        length: len,
-        proc_id: SERVER_PID,
-        client_id: P9String::new("test-server").unwrap(),
+        ty: LeaseType::Read,
+    })
+    .expect("failed to get read lease");
+
+    // Readers can share a file.
+    open(&mut b, &*test_dir, ROOT_FID, "file", fid, P9_RDONLY).expect("failed to open file");
+    b.lease(&Tlease {
+        fid,
+        ty: LeaseType::Read,
+    })
+    .expect("failed to get read lease");
+    let err = b
+        .lease(&Tlease {
+            fid,
+            ty: LeaseType::Write,
+        })
+        .expect_err("got a write lease on a file that is open elsewhere");
+    assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+    assert!(a
+        .lease_breaks()
+        .expect("failed to get breaks")
+        .fids
+        .is_empty());
+
+    // A writer breaks the read leases of other clients, but not those of its own fids.
+    let writer = ROOT_FID + 2;
+    open(&mut b, &*test_dir, ROOT_FID, "file", writer, P9_RDWR).expect("failed to open file");
+    assert_eq!(a.lease_breaks().expect("failed to get breaks").fids, [fid]);
+    assert!(a
+        .lease_breaks()
+        .expect("failed to get breaks")
+        .fids
+        .is_empty());
+    assert!(b
+        .lease_breaks()
+        .expect("failed to get breaks")
+        .fids
+        .is_empty());
+    let err = a
+        .lease(&Tlease {
+            fid,
+            ty: LeaseType::Read,
+        })
+        .expect_err("got a read lease on a file that is being written");
+    assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+    b.clunk(&Tclunk { fid }).expect("failed to clunk fid");
+    b.clunk(&Tclunk { fid: writer })
+        .expect("failed to clunk fid");
+    a.lease(&Tlease {
+        fid,
+        ty: LeaseType::Write,
+    })
+    .expect("failed to get write lease");
+
+    // Any other open breaks a write lease.
+    open(&mut b, &*test_dir, ROOT_FID, "file", fid, P9_RDONLY).expect("failed to open file");
+    assert_eq!(a.lease_breaks().expect("failed to get breaks").fids, [fid]);
+    a.lease(&Tlease {
+        fid,
+        ty: LeaseType::Release,
+    })
+    .expect("failed to release lease");
+
+    // Leases of unknown types don't decode.
+    let mut body = Vec::new();
+    fid.encode(&mut body).unwrap();
+    3u8.encode(&mut body).unwrap();
+    let response = legacy_request(&mut a, 68, &body);
+    let rframe: Rframe =
+        WireFormat::decode(&mut Cursor::new(response)).expect("failed to decode response");
+    match rframe.msg {
+        Rmessage::Lerror(rlerror) => assert_eq!(rlerror.ecode, libc::EINVAL as u32),
+        msg => panic!("unexpected response: {:?}", msg),
     }
+    let err = a
+        .lease(&Tlease {
+            fid: ROOT_FID,
+            ty: LeaseType::Read,
+        })
+        .expect_err("got a lease on a fid that isn't open");
+    assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+    let (_test_dir, mut server) = setup("lease_disabled");
+    let err = server
+        .lease_breaks()
+        .expect_err("got lease breaks without negotiating lease");
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
 }
 
-fn getlk_tgetlock(fid: u32, type_: i32) -> Tgetlock {
-    Tgetlock {
-        fid,
-        type_: type_ as u8,
-        start: 0,
-        length: 0,
-        proc_id: SERVER_PID,
-        client_id: P9String::new("test-server").unwrap(),
+// Sets up a server for `fs` with `cfg` like `setup` does for a host directory.
+fn setup_filesystem<F: FileSystem>(fs: F, cfg: Config) -> Server<F> {
+    let mut server = Server::with_filesystem(fs, cfg);
+    server
+        .version(&Tversion {
+            msize: DEFAULT_BUFFER_SIZE,
+            version: P9String::new("9P2000.L").unwrap(),
+        })
+        .expect("failed to get version from server");
+    server
+        .attach(&Tattach {
+            fid: ROOT_FID,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new("").unwrap(),
+            n_uname: 1000,
+        })
+        .expect("failed to attach to server");
+    server
+}
+
+// Checks that `server`, with `strict_unlink`, fails to remove entries like unlinkat(2) would.
+fn check_strict_unlink<F: FileSystem>(server: &mut Server<F>) {
+    let name = |name: &str| P9String::new(name).unwrap();
+    let clone = |server: &mut Server<F>, newfid: u32| {
+        server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid,
+                wnames: Vec::new(),
+            })
+            .expect("failed to clone fid");
+    };
+    let lcreate = |server: &mut Server<F>, fid: u32, file: &str| {
+        clone(server, fid);
+        server
+            .lcreate(Tlcreate {
+                fid,
+                name: name(file),
+                flags: P9_RDWR,
+                mode: 0o644,
+                gid: 0,
+            })
+            .expect("failed to create file");
+    };
+    for dir in ["d", "full"] {
+        server
+            .mkdir(Tmkdir {
+                dfid: ROOT_FID,
+                name: name(dir),
+                mode: 0o755,
+                gid: 0,
+            })
+            .expect("failed to create directory");
     }
+    lcreate(server, ROOT_FID + 1, "f");
+    server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 2,
+            wnames: vec![name("full")],
+        })
+        .expect("failed to walk");
+    server
+        .lcreate(Tlcreate {
+            fid: ROOT_FID + 2,
+            name: name("inner"),
+            flags: P9_RDWR,
+            mode: 0o644,
+            gid: 0,
+        })
+        .expect("failed to create file");
+
+    let rmdir = libc::AT_REMOVEDIR as u32;
+    let matrix = [
+        ("f", rmdir, libc::ENOTDIR),
+        ("d", 0, libc::EISDIR),
+        ("full", rmdir, libc::ENOTEMPTY),
+        ("missing", 0, libc::ENOENT),
+        ("missing", rmdir, libc::ENOENT),
+        (".", rmdir, libc::EINVAL),
+        ("..", rmdir, libc::ENOTEMPTY),
+        (".", 0, libc::EISDIR),
+        ("..", 0, libc::EISDIR),
+        ("f", 1, libc::EINVAL),
+        ("d", rmdir | 1, libc::EINVAL),
+    ];
+    for (entry, flags, errno) in matrix {
+        let err = server
+            .unlink_at(Tunlinkat {
+                dirfd: ROOT_FID,
+                name: name(entry),
+                flags,
+            })
+            .expect_err("removed an entry that can't be");
+        assert_eq!(err.raw_os_error(), Some(errno), "{entry} {flags:#x}");
+    }
+    for (entry, flags) in [("f", 0), ("d", rmdir)] {
+        server
+            .unlink_at(Tunlinkat {
+                dirfd: ROOT_FID,
+                name: name(entry),
+                flags,
+            })
+            .expect("failed to remove entry");
+    }
+
+    // The root can't be removed, and the fid goes away anyway.
+    clone(server, ROOT_FID + 3);
+    let err = server.remove(&Tremove { fid: ROOT_FID + 3 }).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+    assert!(!server.fids.contains(ROOT_FID + 3));
+
+    // Removing a file through a fid that has it open leaves it open through the others.
+    lcreate(server, ROOT_FID + 4, "g");
+    server
+        .write(&Twrite {
+            fid: ROOT_FID + 4,
+            offset: 0,
+            data: Data(b"data".to_vec()),
+        })
+        .expect("failed to write");
+    server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 5,
+            wnames: vec![name("g")],
+        })
+        .expect("failed to walk");
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID + 5,
+            flags: P9_RDONLY,
+        })
+        .expect("failed to open file");
+    server
+        .remove(&Tremove { fid: ROOT_FID + 5 })
+        .expect("failed to remove file");
+    let err = server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: ROOT_FID + 5,
+            wnames: vec![name("g")],
+        })
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    check_content(server, b"data", ROOT_FID + 4);
 }
 
-fn setup_simple_lock_no_open() -> Server {
-    let (test_dir, server) = setup("simple lock");
+#[test]
+fn strict_unlink() {
+    let cfg: Config = "strict_unlink=true"
+        .parse()
+        .expect("failed to parse config");
+    assert!(cfg.strict_unlink);
+    let (_test_dir, mut server) = setup_config("strict_unlink", "9P2000.L", cfg.clone());
+    check_strict_unlink(&mut server);
+
+    let mut server = setup_filesystem(Tmpfs::new(), cfg);
+    check_strict_unlink(&mut server);
+}
 
-    let filename = "file";
-    create_local_file(&test_dir, filename);
+#[test]
+fn tmpfs() {
+    let fs = Tmpfs::new();
+    let mut server = setup_filesystem(fs.clone(), Default::default());
+    let name = |name: &str| P9String::new(name).unwrap();
+    let walk = |server: &mut Server<Tmpfs>, fid: u32, newfid: u32, wnames: &[&str]| {
+        server.walk(Twalk {
+            fid,
+            newfid,
+            wnames: wnames.iter().map(|wname| name(wname)).collect(),
+        })
+    };
+
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: name("dir"),
+            mode: 0o755,
+            gid: 0,
+        })
+        .expect("failed to create directory");
+    let fid = ROOT_FID + 1;
+    walk(&mut server, ROOT_FID, fid, &["dir"]).expect("failed to walk to directory");
+    server
+        .lcreate(Tlcreate {
+            fid,
+            name: name("a"),
+            flags: P9_RDWR,
+            mode: 0o644,
+            gid: 0,
+        })
+        .expect("failed to create file");
+    let content = b"hello, world!";
+    server
+        .write(&Twrite {
+            fid,
+            offset: 0,
+            data: Data(content.to_vec()),
+        })
+        .expect("failed to write file");
+    let Rread { data } = server
+        .read(&Tread {
+            fid,
+            offset: 7,
+            count: 100,
+        })
+        .expect("failed to read file");
+    assert_eq!(&*data, b"world!");
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid,
+            request_mask: P9_GETATTR_BASIC,
+        })
+        .expect("failed to get attributes");
+    assert_eq!(rgetattr.size, content.len() as u64);
+    assert_eq!(rgetattr.nlink, 1);
+    assert_eq!(rgetattr.mode, libc::S_IFREG | 0o644);
+
+    // Extended attributes.
+    let xfid = ROOT_FID + 2;
+    walk(&mut server, fid, xfid, &[]).expect("failed to clone fid");
+    server
+        .xattr_create(&Txattrcreate {
+            fid: xfid,
+            name: name("user.p9.test"),
+            attr_size: 5,
+            flags: 0,
+        })
+        .expect("failed to create xattr");
+    server
+        .write(&Twrite {
+            fid: xfid,
+            offset: 0,
+            data: Data(b"value".to_vec()),
+        })
+        .expect("failed to write xattr");
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to set xattr");
+    let rxattrwalk = server
+        .xattr_walk(&Txattrwalk {
+            fid,
+            newfid: xfid,
+            name: Default::default(),
+        })
+        .expect("failed to list xattrs");
+    assert_eq!(
+        read_xattr_fid(&mut server, xfid, rxattrwalk.size),
+        b"user.p9.test\0"
+    );
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    // The file is still there when the open fid is renamed out from under it, and the fid
+    // follows it.
+    server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: name("dir"),
+            newdirfid: ROOT_FID,
+            newname: name("renamed"),
+        })
+        .expect("failed to rename directory");
+    let err = walk(&mut server, ROOT_FID, xfid, &["dir", "a"]).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    walk(&mut server, ROOT_FID, xfid, &["renamed", "a"]).expect("failed to walk to file");
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
+
+    // A directory can't be moved into itself.
+    walk(&mut server, ROOT_FID, xfid, &["renamed"]).expect("failed to walk to directory");
+    let err = server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: name("renamed"),
+            newdirfid: xfid,
+            newname: name("loop"),
+        })
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    server
+        .clunk(&Tclunk { fid: xfid })
+        .expect("failed to clunk");
 
+    // Removing the file leaves it readable through the fid that has it open.
+    walk(&mut server, fid, xfid, &[]).expect("failed to clone fid");
+    server
+        .remove(&Tremove { fid: xfid })
+        .expect("failed to remove file");
+    let rwalk = walk(&mut server, ROOT_FID, xfid, &["renamed", "a"]).expect("failed to walk");
+    assert_eq!(rwalk.wqids.len(), 1);
+    check_content(&mut server, content, fid);
+
+    // Another server on the same file system sees the same tree, but a different export is
+    // a tree of its own.
+    let mut other = setup_filesystem(fs.clone(), Default::default());
+    walk(&mut other, ROOT_FID, fid, &["renamed"]).expect("failed to walk on another server");
+    server
+        .cfg
+        .exports
+        .insert("scratch".to_string(), Export::new(Path::new("/scratch")));
     server
+        .attach(&Tattach {
+            fid: xfid,
+            afid: P9_NOFID,
+            uname: name("unittest"),
+            aname: name("scratch"),
+            n_uname: 1000,
+        })
+        .expect("failed to attach to export");
+    server
+        .lopen(&Tlopen {
+            fid: xfid,
+            flags: 0,
+        })
+        .expect("failed to open directory");
+    let names: Vec<_> = readdir(&mut server, xfid)
+        .map(|dirent| dirent.name)
+        .collect();
+    assert_eq!(names, [".", ".."]);
 }
 
-fn setup_simple_lock(flags: u32) -> Server {
-    let (test_dir, mut server) = setup("simple lock");
-
-    let filename = "file";
-    create_local_file(&test_dir, filename);
-
-    open(
-        &mut server,
-        &*test_dir,
-        ROOT_FID,
-        filename,
-        ROOT_FID + 1,
-        flags,
-    )
-    .expect("failed to open file");
+#[test]
+fn tmpfs_capacity() {
+    let mut server = setup_filesystem(Tmpfs::with_capacity(8192), Default::default());
+    let file = |server: &mut Server<Tmpfs>, name: &str, fid: u32| {
+        server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: fid,
+                wnames: vec![],
+            })
+            .expect("failed to clone fid");
+        server
+            .lcreate(Tlcreate {
+                fid,
+                name: P9String::new(name).unwrap(),
+                flags: P9_RDWR,
+                mode: 0o644,
+                gid: u32::MAX,
+            })
+            .expect("failed to create file");
+    };
+    let write = |server: &mut Server<Tmpfs>, fid: u32, offset: u64, len: usize| {
+        server.write(&Twrite {
+            fid,
+            offset,
+            data: Data(vec![0xa5; len]),
+        })
+    };
+    let bfree = |server: &mut Server<Tmpfs>| {
+        let rstatfs = server
+            .statfs(&Tstatfs { fid: ROOT_FID })
+            .expect("failed to statfs");
+        assert_eq!(rstatfs.blocks, 2);
+        rstatfs.bfree
+    };
 
+    let (a, b) = (ROOT_FID + 1, ROOT_FID + 2);
+    file(&mut server, "a", a);
+    file(&mut server, "b", b);
+    write(&mut server, a, 0, 4096).expect("failed to write");
+    assert_eq!(bfree(&mut server), 1);
+
+    // Neither a write far past the end nor growing a file can take more than what is left.
+    let err = write(&mut server, b, 1 << 31, 1).expect_err("wrote past the capacity");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    let resize = |size| Tsetattr {
+        fid: b,
+        valid: P9_SETATTR_SIZE,
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        size,
+        atime_sec: 0,
+        atime_nsec: 0,
+        mtime_sec: 0,
+        mtime_nsec: 0,
+    };
+    let err = server
+        .set_attr(&resize(8192))
+        .expect_err("grew a file past the capacity");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    server.set_attr(&resize(4096)).expect("failed to grow file");
+    assert_eq!(bfree(&mut server), 0);
+
+    // Space comes back once a file shrinks or goes away.
     server
+        .set_attr(&resize(0))
+        .expect("failed to truncate file");
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: P9String::new("a").unwrap(),
+            flags: 0,
+        })
+        .expect("failed to remove file");
+    server.clunk(&Tclunk { fid: a }).expect("failed to clunk");
+    assert_eq!(bfree(&mut server), 2);
+    write(&mut server, b, 0, 8192).expect("failed to write");
 }
 
 #[test]
-fn lock_rdlck_no_open_file() {
-    let mut server = setup_simple_lock_no_open();
-
-    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_RDLCK);
+fn tmpfs_readdir_offsets() {
+    let mut server = setup_filesystem(Tmpfs::new(), Default::default());
+    let fid = ROOT_FID + 1;
+    for i in 0..10 {
+        server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: fid,
+                wnames: Vec::new(),
+            })
+            .expect("failed to clone fid");
+        server
+            .lcreate(Tlcreate {
+                fid,
+                name: P9String::new(format!("{i}")).unwrap(),
+                flags: P9_RDWR,
+                mode: 0o644,
+                gid: 0,
+            })
+            .expect("failed to create file");
+        server.clunk(&Tclunk { fid }).expect("failed to clunk");
+    }
 
-    server.lock(&tlock).expect_err("Bad file descriptor");
+    server
+        .walk(Twalk {
+            fid: ROOT_FID,
+            newfid: fid,
+            wnames: Vec::new(),
+        })
+        .expect("failed to clone fid");
+    server
+        .lopen(&Tlopen { fid, flags: 0 })
+        .expect("failed to open directory");
+    let mut dir = readdir(&mut server, fid);
+    let first: Vec<_> = dir.by_ref().take(5).map(|dirent| dirent.name).collect();
+    assert_eq!(first, [".", "..", "0", "1", "2"]);
+
+    // Entries that are removed or added in the meantime don't move the ones that are left.
+    let offset = dir.offset;
+    for name in ["1", "5"] {
+        server
+            .unlink_at(Tunlinkat {
+                dirfd: ROOT_FID,
+                name: P9String::new(name).unwrap(),
+                flags: 0,
+            })
+            .expect("failed to unlink file");
+    }
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("new").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })
+        .expect("failed to create directory");
+    let Rreaddir { data } = server
+        .readdir(&Treaddir {
+            fid,
+            offset,
+            count: DEFAULT_BUFFER_SIZE,
+        })
+        .expect("failed to read directory");
+    let mut cursor = Cursor::new(data.0);
+    let mut rest = Vec::new();
+    while cursor.position() < cursor.get_ref().len() as u64 {
+        let dirent: Dirent = WireFormat::decode(&mut cursor).expect("failed to decode dirent");
+        rest.push(dirent.name);
+    }
+    assert_eq!(rest, ["3", "4", "6", "7", "8", "9", "new"]);
 }
 
 #[test]
-fn lock_rdlck() {
-    let mut server = setup_simple_lock(P9_RDWR);
+fn readdir_offsets_survive_reopen() {
+    let (test_dir, mut server) = setup("readdir_offsets_survive_reopen");
+    for i in 0..20 {
+        create_local_file(&*test_dir, &format!("file{i}"));
+    }
+    let mut names: Vec<String> = fs::read_dir(&*test_dir)
+        .expect("failed to read directory")
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
 
-    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_RDLCK);
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, Vec::new());
+    open(&mut server, &*test_dir, fid, "", fid, P9_DIRECTORY).expect("failed to open directory");
+    let mut dir = readdir(&mut server, fid);
+    let mut seen: Vec<String> = dir
+        .by_ref()
+        .take(10)
+        .map(|dirent| String::from_utf8(dirent.name.as_bytes().to_vec()).unwrap())
+        .collect();
+    let offset = dir.offset;
+    server.clunk(&Tclunk { fid }).expect("failed to clunk fid");
+
+    // A directory that is opened again carries on from the same entry.
+    walk(&mut server, &*test_dir, ROOT_FID, fid, Vec::new());
+    open(&mut server, &*test_dir, fid, "", fid, P9_DIRECTORY).expect("failed to open directory");
+    let rest = Readdir {
+        server: &mut server,
+        fid,
+        offset,
+        cursor: Cursor::new(Vec::new()),
+    };
+    seen.extend(rest.map(|dirent| String::from_utf8(dirent.name.as_bytes().to_vec()).unwrap()));
 
-    server.lock(&tlock).expect("failed to lock file");
+    seen.sort();
+    names.extend([".".to_string(), "..".to_string()]);
+    names.sort();
+    assert_eq!(seen, names);
 }
-#[test]
-fn lock_wrlck_no_open_file() {
-    let mut server = setup_simple_lock_no_open();
-
-    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_WRLCK);
 
-    server.lock(&tlock).expect_err("Bad file descriptor");
-}
 #[test]
-fn lock_wrlck() {
-    let mut server = setup_simple_lock(P9_RDWR);
-
-    let tlock = setlk_tlock(ROOT_FID + 1, 8, 0, libc::F_WRLCK);
-
-    server.lock(&tlock).expect("failed to lock file");
+fn overlay() {
+    check_overlay("overlay", true);
 }
 
 #[test]
-fn lock_unlck_no_lock() {
-    let mut server = setup_simple_lock(P9_RDWR);
+fn overlay_without_openat2() {
+    check_overlay("overlay_without_openat2", false);
+}
 
-    let tlock = setlk_tlock(ROOT_FID + 1, 0, 0, libc::F_UNLCK);
+// Checks the overlay file system, looking up names with openat2 or without it.
+fn check_overlay(test: &str, openat2: bool) {
+    let (lower, _) = setup(format!("{test}_lower"));
+    let (upper_dir, _) = setup(format!("{test}_upper"));
+    let upper = upper_dir.join("upper");
+    fs::create_dir(&upper).expect("failed to create upper directory");
+    let cfg = Config {
+        root: upper.clone().into(),
+        openat2,
+        ..Default::default()
+    };
+    let fs = Overlay::new(&*lower, &cfg).expect("failed to create overlay");
+    let mut server = setup_filesystem(fs, cfg);
+    let name = |name: &str| P9String::new(name).unwrap();
+    let walk = |server: &mut Server<Overlay>, newfid: u32, wnames: &[&str]| {
+        server.walk(Twalk {
+            fid: ROOT_FID,
+            newfid,
+            wnames: wnames.iter().map(|wname| name(wname)).collect(),
+        })
+    };
+    let list = |server: &mut Server<Overlay>, wnames: &[&str]| {
+        let fid = ROOT_FID + 10;
+        walk(server, fid, wnames).expect("failed to walk to directory");
+        server
+            .lopen(&Tlopen { fid, flags: 0 })
+            .expect("failed to open directory");
+        let mut names: Vec<_> = readdir(server, fid).map(|dirent| dirent.name).collect();
+        server.clunk(&Tclunk { fid }).expect("failed to clunk");
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        names
+    };
 
-    server.lock(&tlock).expect("failed to lock file");
+    // Everything in the lower directory shows through, and changing a file copies it up.
+    assert_eq!(list(&mut server, &[]), [".", "..", "subdir", "世界.txt"]);
+    let fid = ROOT_FID + 1;
+    walk(&mut server, fid, &["subdir", "b"]).expect("failed to walk to file");
+    server
+        .lopen(&Tlopen {
+            fid,
+            flags: P9_RDWR,
+        })
+        .expect("failed to open file");
+    check_content(&mut server, b"hello, world!", fid);
+    server
+        .write(&Twrite {
+            fid,
+            offset: 0,
+            data: Data(b"HELLO".to_vec()),
+        })
+        .expect("failed to write file");
+    check_content(&mut server, b"HELLO, world!", fid);
+    assert_eq!(
+        fs::read(upper.join("subdir/b")).expect("failed to read upper file"),
+        b"HELLO, world!"
+    );
+    assert_eq!(
+        fs::read(lower.join("subdir/b")).expect("failed to read lower file"),
+        b"hello, world!"
+    );
+    assert_eq!(list(&mut server, &["subdir"]), [".", "..", "b", "nested"]);
+
+    // Removed files are whited out and can be created again.
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID,
+            name: name("世界.txt"),
+            flags: 0,
+        })
+        .expect("failed to unlink file");
+    assert!(lower.join("世界.txt").exists());
+    let err = walk(&mut server, ROOT_FID + 2, &["世界.txt"]).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    assert_eq!(list(&mut server, &[]), [".", "..", "subdir"]);
+    walk(&mut server, ROOT_FID + 2, &[]).expect("failed to clone fid");
+    server
+        .lcreate(Tlcreate {
+            fid: ROOT_FID + 2,
+            name: name("世界.txt"),
+            flags: P9_RDWR,
+            mode: 0o644,
+            gid: 0,
+        })
+        .expect("failed to create file");
+    check_content(&mut server, b"", ROOT_FID + 2);
+    assert_eq!(list(&mut server, &[]), [".", "..", "subdir", "世界.txt"]);
+
+    // A directory that only looks empty can be removed, and one made in its place starts out
+    // empty.
+    let nested = "Огонь по готовности!";
+    walk(&mut server, ROOT_FID + 3, &["subdir"]).expect("failed to walk to directory");
+    let err = server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID + 3,
+            name: name("nested"),
+            flags: libc::AT_REMOVEDIR as u32,
+        })
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+    walk(&mut server, ROOT_FID + 4, &["subdir", "nested"]).expect("failed to walk to directory");
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID + 4,
+            name: name(nested),
+            flags: 0,
+        })
+        .expect("failed to unlink file");
+    server
+        .unlink_at(Tunlinkat {
+            dirfd: ROOT_FID + 3,
+            name: name("nested"),
+            flags: libc::AT_REMOVEDIR as u32,
+        })
+        .expect("failed to remove directory");
+    server
+        .mkdir(Tmkdir {
+            dfid: ROOT_FID + 3,
+            name: name("nested"),
+            mode: 0o755,
+            gid: 0,
+        })
+        .expect("failed to create directory");
+    assert_eq!(list(&mut server, &["subdir", "nested"]), [".", ".."]);
+    assert!(lower.join("subdir/nested").join(nested).exists());
+
+    // Directories from the lower directory can't be renamed, but files can.
+    let err = server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID,
+            oldname: name("subdir"),
+            newdirfid: ROOT_FID,
+            newname: name("renamed"),
+        })
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+    server
+        .rename_at(Trenameat {
+            olddirfid: ROOT_FID + 3,
+            oldname: name("b"),
+            newdirfid: ROOT_FID,
+            newname: name("b"),
+        })
+        .expect("failed to rename file");
+    assert_eq!(list(&mut server, &["subdir"]), [".", "..", "nested"]);
+    walk(&mut server, ROOT_FID + 5, &["b"]).expect("failed to walk to renamed file");
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID + 5,
+            flags: 0,
+        })
+        .expect("failed to open file");
+    check_content(&mut server, b"HELLO, world!", ROOT_FID + 5);
 }
 
 #[test]
-fn lock_unlck() {
-    let mut server = setup_simple_lock(P9_RDWR);
+fn union() {
+    let (first, _) = setup("union_first");
+    let (second, _) = setup("union_second");
+    let (upper_dir, _) = setup("union_upper");
+    let upper = upper_dir.join("upper");
+    fs::create_dir(&upper).expect("failed to create upper directory");
+    create_local_file(first.join("subdir"), "first");
+    let content = create_local_file(second.join("subdir"), "second");
+    fs::write(second.join("subdir/b"), b"hidden").expect("failed to write file");
+    let cfg = Config {
+        root: upper.clone().into(),
+        ..Default::default()
+    };
+    let fs = Overlay::with_layers([first.join("subdir"), second.join("subdir")], &cfg)
+        .expect("failed to create union");
+    let mut server = setup_filesystem(fs, cfg);
 
-    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
+    // Directories are merged and the first layer wins.
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID,
+            flags: 0,
+        })
+        .expect("failed to open directory");
+    let mut names: Vec<_> = readdir(&mut server, ROOT_FID)
+        .map(|dirent| dirent.name)
+        .collect();
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    assert_eq!(names, [".", "..", "b", "first", "nested", "second"]);
+
+    let open = |server: &mut Server<Overlay>, fid: u32, wname: &str, flags: u32| {
+        server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: fid,
+                wnames: vec![P9String::new(wname).unwrap()],
+            })
+            .expect("failed to walk to file");
+        server
+            .lopen(&Tlopen { fid, flags })
+            .expect("failed to open file");
+    };
+    open(&mut server, ROOT_FID + 1, "b", 0);
+    check_content(&mut server, b"hello, world!", ROOT_FID + 1);
+    open(&mut server, ROOT_FID + 2, "second", P9_RDWR);
+    check_content(&mut server, &content, ROOT_FID + 2);
 
-    server.lock(&tlock).expect("failed to lock file");
+    // Writes only ever go to the upper directory.
+    server
+        .write(&Twrite {
+            fid: ROOT_FID + 2,
+            offset: 0,
+            data: Data(b"changed".to_vec()),
+        })
+        .expect("failed to write file");
+    let changed = fs::read(upper.join("second")).expect("failed to read upper file");
+    assert!(changed.starts_with(b"changed"));
+    assert_eq!(
+        fs::read(second.join("subdir/second")).expect("failed to read lower file"),
+        content
+    );
+}
 
-    let tlock = setlk_tlock(ROOT_FID + 1, 0, 0, libc::F_UNLCK);
+// Writes `n` to the tar header `field` as nul-terminated octal.
+fn tar_octal(field: &mut [u8], n: u64) {
+    let text = format!("{:0width$o}\0", n, width = field.len() - 1);
+    field.copy_from_slice(text.as_bytes());
+}
 
-    server.lock(&tlock).expect("failed to lock file");
+// Returns the ustar header and the padded contents of a tar entry.
+fn tar_entry(name: &str, type_: u8, mode: u64, link: &str, data: &[u8]) -> Vec<u8> {
+    let mut header = vec![0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    tar_octal(&mut header[100..108], mode);
+    tar_octal(&mut header[108..116], 1000);
+    tar_octal(&mut header[116..124], 1001);
+    tar_octal(&mut header[124..136], data.len() as u64);
+    tar_octal(&mut header[136..148], 1_700_000_000);
+    header[156] = type_;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    tar_octal(&mut header[148..155], sum);
+
+    header.extend_from_slice(data);
+    header.resize(header.len().next_multiple_of(512), 0);
+    header
 }
 
 #[test]
-fn lock_unlck_relock() {
-    let mut server = setup_simple_lock(P9_RDWR);
-
-    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
-
-    server.lock(&tlock).expect("failed to lock file");
+fn tar_archive() {
+    let (test_dir, _) = setup("tar_archive");
+    let content = b"hello, world!";
+    let tar = [
+        tar_entry("./", b'5', 0o755, "", b""),
+        tar_entry("dir/", b'5', 0o750, "", b""),
+        tar_entry("dir/file", b'0', 0o640, "", content),
+        tar_entry("dir/link", b'2', 0o777, "file", b""),
+        tar_entry("dir/hard", b'1', 0o640, "dir/file", b""),
+        tar_entry("implicit/deep/file", b'0', 0o644, "", b"deep"),
+        tar_entry("fifo", b'6', 0o644, "", b""),
+        tar_entry("../escape", b'0', 0o644, "", b"outside"),
+        vec![0; 1024],
+    ]
+    .concat();
+    let path = test_dir.join("archive.tar");
+    fs::write(&path, tar).expect("failed to write archive");
+    let mut server = setup_filesystem(
+        Archive::open(&path).expect("failed to open archive"),
+        Default::default(),
+    );
 
-    let tlock = setlk_tlock(ROOT_FID + 1, 0, 0, libc::F_UNLCK);
+    server
+        .lopen(&Tlopen {
+            fid: ROOT_FID,
+            flags: 0,
+        })
+        .expect("failed to open root");
+    let names: Vec<_> = readdir(&mut server, ROOT_FID)
+        .map(|dirent| dirent.name)
+        .collect();
+    assert_eq!(names, [".", "..", "dir", "fifo", "implicit"]);
+
+    let walk = |server: &mut Server<Archive>, newfid: u32, wnames: &[&str]| {
+        server.walk(Twalk {
+            fid: ROOT_FID,
+            newfid,
+            wnames: wnames.iter().map(|n| P9String::new(*n).unwrap()).collect(),
+        })
+    };
+    let errno = |result: io::Result<Rmessage>| result.map(|_| ()).unwrap_err().raw_os_error();
+
+    for (fid, path) in [(ROOT_FID + 1, "file"), (ROOT_FID + 2, "hard")] {
+        walk(&mut server, fid, &["dir", path]).expect("failed to walk to file");
+        server
+            .lopen(&Tlopen { fid, flags: 0 })
+            .expect("failed to open file");
+        check_content(&mut server, content, fid);
+    }
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: ROOT_FID + 1,
+            request_mask: P9_GETATTR_BASIC,
+        })
+        .expect("failed to get attributes");
+    assert_eq!(rgetattr.mode, libc::S_IFREG | 0o640);
+    assert_eq!((rgetattr.uid, rgetattr.gid), (1000, 1001));
+    assert_eq!(rgetattr.size, content.len() as u64);
+    assert_eq!(rgetattr.mtime_sec, 1_700_000_000);
+
+    walk(&mut server, ROOT_FID + 3, &["dir", "link"]).expect("failed to walk to symlink");
+    let rreadlink = server
+        .readlink(&Treadlink { fid: ROOT_FID + 3 })
+        .expect("failed to read symlink");
+    assert_eq!(rreadlink.target, "file");
+
+    walk(&mut server, ROOT_FID + 4, &["implicit", "deep", "file"])
+        .expect("failed to walk to file in implicit directory");
+
+    // Nothing can be changed, and there is nothing to open behind special files.
+    walk(&mut server, ROOT_FID + 5, &["fifo"]).expect("failed to walk to fifo");
+    assert_eq!(
+        errno(server.dispatch(Ok(Tmessage::Lopen(Tlopen {
+            fid: ROOT_FID + 5,
+            flags: 0,
+        })))),
+        Some(libc::ENXIO)
+    );
+    assert_eq!(
+        errno(server.dispatch(Ok(Tmessage::Lopen(Tlopen {
+            fid: ROOT_FID + 4,
+            flags: P9_RDWR,
+        })))),
+        Some(libc::EROFS)
+    );
+    assert_eq!(
+        errno(server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("new").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })))),
+        Some(libc::EROFS)
+    );
 
-    server.lock(&tlock).expect("failed to lock file");
+    // Exports are directories in the archive that can't be walked out of.
+    let cfg = Config {
+        root: Path::new("/dir").into(),
+        ..Default::default()
+    };
+    let mut server = setup_filesystem(Archive::open(&path).unwrap(), cfg);
+    let rwalk = walk(&mut server, ROOT_FID + 1, &["..", "file"]).expect("failed to walk");
+    assert_eq!(rwalk.wqids.len(), 2);
+}
 
-    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
+// Returns a zip archive of `entries`, which are the name, method, contents as they are in the
+// archive, size and CRC-32 of each file.
+fn zip_file(entries: &[(&str, u16, &[u8], u32, u32)]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut central = Vec::new();
+    for &(name, method, data, size, crc) in entries {
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&method.to_le_bytes());
+        common.extend_from_slice(&[0; 4]);
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&0x0314u16.to_le_bytes());
+        central.extend_from_slice(&common);
+        central.extend_from_slice(&[0; 6]);
+        let mode = if name.ends_with('/') {
+            libc::S_IFDIR | 0o755
+        } else {
+            libc::S_IFREG | 0o600
+        };
+        central.extend_from_slice(&(mode << 16).to_le_bytes());
+        central.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        zip.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&common);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(data);
+    }
 
-    server.lock(&tlock).expect("failed to lock file");
+    let offset = zip.len() as u32;
+    zip.extend_from_slice(&central);
+    zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    zip.extend_from_slice(&[0; 4]);
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&offset.to_le_bytes());
+    zip.extend_from_slice(&0u16.to_le_bytes());
+    zip
 }
 
 #[test]
-fn getlock_rdlck_nolock() {
-    let mut server = setup_simple_lock(P9_RDWR);
-
-    let tgetlock = getlk_tgetlock(ROOT_FID + 1, libc::F_RDLCK);
+fn zip_archive() {
+    let (test_dir, _) = setup("zip_archive");
+    let content = b"hello, hello, hello, world!\n";
+    let deflated = [
+        0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa1, 0xca, 0xf3, 0x8b, 0x72, 0x52,
+        0x14, 0xb9, 0x00,
+    ];
+    let crc = inflate::crc32(content);
+    let size = content.len() as u32;
+    let zip = zip_file(&[
+        ("dir/", 0, b"", 0, 0),
+        ("dir/stored", 0, content, size, crc),
+        ("dir/deflated", 8, &deflated, size, crc),
+        ("dir/corrupt", 8, &deflated, size, !crc),
+        ("dir/lzma", 14, b"", 0, 0),
+    ]);
+    let path = test_dir.join("archive.zip");
+    fs::write(&path, zip).expect("failed to write archive");
+    let mut server = setup_filesystem(
+        Archive::open(&path).expect("failed to open archive"),
+        Default::default(),
+    );
 
-    server
-        .get_lock(&tgetlock)
-        .expect("failed to get lock on file");
+    let mut open = |fid: u32, name: &str| {
+        server
+            .walk(Twalk {
+                fid: ROOT_FID,
+                newfid: fid,
+                wnames: vec![P9String::new("dir").unwrap(), P9String::new(name).unwrap()],
+            })
+            .expect("failed to walk to file");
+        server
+            .dispatch(Ok(Tmessage::Lopen(Tlopen { fid, flags: 0 })))
+            .map(|_| ())
+            .map_err(|e| e.raw_os_error())
+    };
+    assert_eq!(open(ROOT_FID + 1, "stored"), Ok(()));
+    assert_eq!(open(ROOT_FID + 2, "deflated"), Ok(()));
+    assert_eq!(open(ROOT_FID + 3, "corrupt"), Err(Some(libc::EIO)));
+    assert_eq!(open(ROOT_FID + 4, "lzma"), Err(Some(libc::EOPNOTSUPP)));
+    check_content(&mut server, content, ROOT_FID + 1);
+    check_content(&mut server, content, ROOT_FID + 2);
+
+    // There is nothing past the end, however far past it is.
+    let rread = server
+        .read(&Tread {
+            fid: ROOT_FID + 1,
+            offset: u64::MAX,
+            count: 64,
+        })
+        .expect("failed to read past the end");
+    assert!(rread.data.is_empty());
+
+    let rgetattr = server
+        .get_attr(&Tgetattr {
+            fid: ROOT_FID + 2,
+            request_mask: P9_GETATTR_BASIC,
+        })
+        .expect("failed to get attributes");
+    assert_eq!(rgetattr.mode, libc::S_IFREG | 0o600);
+    assert_eq!(rgetattr.size, content.len() as u64);
 }
 
 #[test]
-fn getlock_wrlck() {
-    let mut server = setup_simple_lock(P9_RDWR);
-
-    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_WRLCK);
-
-    server.lock(&tlock).expect("failed to lock file");
+fn control_tree() {
+    let cfg: Config = "control=ctl:control_writable=true"
+        .parse()
+        .expect("failed to parse config");
+    let (_test_dir, mut server) = setup_config("control_tree", "9P2000.L", cfg);
+    let ctl = ROOT_FID + 1;
+    server
+        .attach(&Tattach {
+            fid: ctl,
+            afid: P9_NOFID,
+            uname: P9String::new("unittest").unwrap(),
+            aname: P9String::new("ctl").unwrap(),
+            n_uname: 1000,
+        })
+        .expect("failed to attach to control tree");
+    let errno = |result: io::Result<Rmessage>| result.map(|_| ()).unwrap_err().raw_os_error();
 
-    let tgetlock = getlk_tgetlock(ROOT_FID + 1, libc::F_WRLCK);
+    server
+        .dispatch(Ok(Tmessage::Lopen(Tlopen { fid: ctl, flags: 0 })))
+        .expect("failed to open control tree");
+    let names: Vec<_> = match server.dispatch(Ok(Tmessage::Readdir(Treaddir {
+        fid: ctl,
+        offset: 0,
+        count: DEFAULT_BUFFER_SIZE,
+    }))) {
+        Ok(Rmessage::Readdir(Rreaddir { data })) => {
+            let mut cursor = Cursor::new(&*data.0);
+            let mut names = Vec::new();
+            while (cursor.position() as usize) < data.len() {
+                let dirent = Dirent::decode(&mut cursor).expect("failed to decode dirent");
+                names.push(dirent.name);
+            }
+            names
+        }
+        _ => panic!("failed to read control tree"),
+    };
+    assert_eq!(names, [".", "..", "connection", "ctl", "fids", "stats"]);
+
+    // Every file is generated when it is opened.
+    let open = |server: &mut Server, fid: u32, name: &str, flags: u32| {
+        server.dispatch(Ok(Tmessage::Walk(Twalk {
+            fid: ctl,
+            newfid: fid,
+            wnames: vec![P9String::new(name).unwrap()],
+        })))?;
+        server.dispatch(Ok(Tmessage::Lopen(Tlopen { fid, flags })))?;
+        match server.dispatch(Ok(Tmessage::Read(Tread {
+            fid,
+            offset: 0,
+            count: DEFAULT_BUFFER_SIZE,
+        }))) {
+            Ok(Rmessage::Read(Rread { data })) => Ok(String::from_utf8(data.0).unwrap()),
+            Ok(_) => unreachable!(),
+            Err(e) => Err(e),
+        }
+    };
+    let stats = open(&mut server, ROOT_FID + 2, "stats", 0).expect("failed to read stats");
+    assert!(stats.contains("requests "), "{stats}");
+    assert!(stats.contains("buffer_pool_hits "), "{stats}");
+    let fids = open(&mut server, ROOT_FID + 3, "fids", 0).expect("failed to read fids");
+    assert!(fids.starts_with(&format!("{ROOT_FID} - ")), "{fids}");
+    let ctl_contents = open(&mut server, ROOT_FID + 4, "ctl", P9_RDWR).expect("failed to open ctl");
+    assert!(ctl_contents.contains("read_only false\n"), "{ctl_contents}");
+    assert_eq!(
+        open(&mut server, ROOT_FID + 5, "stats", P9_RDWR).map_err(|e| e.raw_os_error()),
+        Err(Some(libc::EACCES))
+    );
 
+    // Writing to ctl changes the options, including for existing fids.
+    let write = |server: &mut Server, data: &[u8]| {
+        server.dispatch(Ok(Tmessage::Write(Twrite {
+            fid: ROOT_FID + 4,
+            offset: 0,
+            data: Data(data.to_vec()),
+        })))
+    };
+    assert_eq!(errno(write(&mut server, b"bogus 1\n")), Some(libc::EINVAL));
+    write(&mut server, b"read_only true\nidle_timeout 30\n").expect("failed to write ctl");
+    assert_eq!(server.cfg.idle_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(
+        errno(server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("dir").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })))),
+        Some(libc::EROFS)
+    );
+    write(&mut server, b"read_only false\n").expect("failed to write ctl");
     server
-        .get_lock(&tgetlock)
-        .expect("failed to get lock on file");
+        .dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("dir").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })))
+        .expect("failed to create directory");
+
+    // Control fids aren't files of the exported tree.
+    assert_eq!(
+        errno(server.dispatch(Ok(Tmessage::Readlink(Treadlink { fid: ctl })))),
+        Some(libc::EBADF)
+    );
+    server
+        .dispatch(Ok(Tmessage::Clunk(Tclunk { fid: ctl })))
+        .expect("failed to clunk control fid");
+    assert!(!server.control_fids.contains_key(&ctl));
 }
 
 #[test]
-fn getlock_rdlck() {
-    let mut server = setup_simple_lock(P9_RDWR);
-
-    let tlock = setlk_tlock(ROOT_FID + 1, LOCAL_FILE_LEN / 2, 0, libc::F_RDLCK);
-
-    server.lock(&tlock).expect("failed to lock file");
-
-    let tgetlock = getlk_tgetlock(ROOT_FID + 1, libc::F_RDLCK);
+fn control_read_only() {
+    let attach = |server: &mut Server| {
+        server
+            .dispatch(Ok(Tmessage::Attach(Tattach {
+                fid: ROOT_FID + 1,
+                afid: P9_NOFID,
+                uname: P9String::new("unittest").unwrap(),
+                aname: P9String::new("ctl").unwrap(),
+                n_uname: 1000,
+            })))
+            .expect("failed to attach to control tree");
+        server
+            .dispatch(Ok(Tmessage::Walk(Twalk {
+                fid: ROOT_FID + 1,
+                newfid: ROOT_FID + 2,
+                wnames: vec![P9String::new("ctl").unwrap()],
+            })))
+            .expect("failed to walk to ctl");
+        server.dispatch(Ok(Tmessage::Lopen(Tlopen {
+            fid: ROOT_FID + 2,
+            flags: P9_RDWR,
+        })))
+    };
+    let mkdir = |server: &mut Server| {
+        server.dispatch(Ok(Tmessage::Mkdir(Tmkdir {
+            dfid: ROOT_FID,
+            name: P9String::new("dir").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        })))
+    };
 
-    server
-        .get_lock(&tgetlock)
-        .expect("failed to get lock on file");
+    // Clients can't change options unless they are allowed to.
+    let cfg: Config = "control=ctl".parse().expect("failed to parse config");
+    let (_test_dir, mut server) = setup_config("control_read_only", "9P2000.L", cfg);
+    let err = attach(&mut server).expect_err("opened ctl for writing");
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+    // Turning `read_only` off again leaves exports that are configured to be read-only alone.
+    let cfg: Config = "control=ctl:control_writable=true:read_only=true"
+        .parse()
+        .expect("failed to parse config");
+    let (_test_dir, mut server) = setup_config("control_read_only", "9P2000.L", cfg);
+    attach(&mut server).expect("failed to open ctl");
+    for data in [&b"read_only true\n"[..], b"read_only false\n"] {
+        server
+            .dispatch(Ok(Tmessage::Write(Twrite {
+                fid: ROOT_FID + 2,
+                offset: 0,
+                data: Data(data.to_vec()),
+            })))
+            .expect("failed to write ctl");
+    }
+    let err = mkdir(&mut server).expect_err("created a directory in a read-only export");
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
 }
 
-macro_rules! open_test {
-    ($name:ident, $flags:expr) => {
-        #[test]
-        fn $name() {
-            let (test_dir, mut server) = setup("open");
+#[test]
+fn dispatcher() {
+    let (test_dir, mut server) = setup("dispatcher");
 
-            let fid = ROOT_FID + 1;
-            let name = "test.txt";
-            let content = create_local_file(&test_dir, name);
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    let content = create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDWR).expect("failed to open file");
+
+    let mut requests = Vec::new();
+    let msgs = vec![
+        (
+            1,
+            Tmessage::Read(Tread {
+                fid,
+                offset: 0,
+                count: 64,
+            }),
+        ),
+        (2, Tmessage::Fsync(Tfsync { fid, datasync: 0 })),
+        (
+            3,
+            Tmessage::GetAttr(Tgetattr {
+                fid: ROOT_FID,
+                request_mask: P9_GETATTR_BASIC,
+            }),
+        ),
+        (
+            4,
+            Tmessage::Read(Tread {
+                fid,
+                offset: 100,
+                count: 64,
+            }),
+        ),
+        (5, Tmessage::Clunk(Tclunk { fid })),
+        (6, Tmessage::Flush(Tflush { oldtag: 99 })),
+        (
+            7,
+            Tmessage::Read(Tread {
+                fid,
+                offset: 0,
+                count: 64,
+            }),
+        ),
+    ];
+    for (tag, msg) in msgs {
+        Tframe { tag, msg: Ok(msg) }
+            .encode(&mut requests)
+            .expect("failed to encode request");
+    }
 
-            let rlopen = open(&mut server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
-                .expect("failed to open file");
+    let dispatcher = Dispatcher::new(server, 4);
+    let mut responses = Vec::new();
+    dispatcher
+        .serve(&mut Cursor::new(requests), &mut responses)
+        .expect("failed to serve requests");
+
+    let mut reader = Cursor::new(responses);
+    let mut replies = BTreeMap::new();
+    while (reader.position() as usize) < reader.get_ref().len() {
+        let rframe: Rframe = WireFormat::decode(&mut reader).expect("failed to decode response");
+        assert!(replies.insert(rframe.tag, rframe.msg).is_none());
+    }
+    assert_eq!(replies.len(), 7);
 
-            let md =
-                fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
-            check_qid(&rlopen.qid, &md);
-            assert_eq!(rlopen.iounit, 0);
+    // The requests for a fid are handled in order, so both reads come before the Tclunk and the
+    // one after it doesn't find the fid.
+    match &replies[&1] {
+        Rmessage::Read(rread) => assert_eq!(&*rread.data, &content[..64]),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+    match &replies[&4] {
+        Rmessage::Read(rread) => assert_eq!(&*rread.data, &content[100..164]),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
+    assert!(matches!(replies[&2], Rmessage::Fsync));
+    assert!(matches!(replies[&3], Rmessage::GetAttr(_)));
+    assert!(matches!(replies[&5], Rmessage::Clunk));
+    assert!(matches!(replies[&6], Rmessage::Flush));
+    match &replies[&7] {
+        Rmessage::Lerror(rlerror) => assert_eq!(rlerror.ecode, libc::EBADF as u32),
+        msg => panic!("unexpected response: {:?}", msg),
+    }
 
-            check_attr(&mut server, fid, &md);
+    let server = dispatcher.into_server();
+    assert!(!server.fids.contains(fid));
+}
 
-            // Check that the file has the proper contents as long as we didn't
-            // truncate it first.
-            if $flags & P9_TRUNC == 0 && $flags & P9_WRONLY == 0 {
-                check_content(&mut server, &content, fid);
-            }
+#[test]
+fn dispatcher_max_open_files() {
+    let cfg: Config = "max_open_files=1".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("dispatcher_max_open_files", "9P2000.L", cfg);
 
-            // Check that we can write to the file.
-            if $flags & P9_RDWR != 0 || $flags & P9_WRONLY != 0 {
-                write(&mut server, &test_dir, name, fid, $flags);
+    let fid = ROOT_FID + 1;
+    create_local_file(&*test_dir, "a");
+    create_local_file(&*test_dir, "b");
+    open(&mut server, &*test_dir, ROOT_FID, "a", fid, P9_RDWR).expect("failed to open file");
+
+    // Opening another file can close the one of `fid` while a dispatcher is syncing it, which
+    // must not get in the way.
+    server.offload = true;
+    let response = server.handle_request(1, |server| {
+        server.dispatch(Ok(Tmessage::Fsync(Tfsync { fid, datasync: 0 })))
+    });
+    server.offload = false;
+    assert!(response.is_none());
+    let Deferred { cancel, job, .. } = server.deferred.take().expect("Tfsync wasn't offloaded");
+    open(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        "b",
+        ROOT_FID + 2,
+        P9_RDONLY,
+    )
+    .expect("failed to open file");
+    assert!(lock_fid(&server.fids.get(fid).unwrap()).file.is_none());
+    assert!(matches!(job(&cancel), Ok(Rmessage::Fsync)));
+    server.finish_request(1, &cancel, Ok(Rmessage::Fsync));
+
+    // Not even when there are lots of them.
+    let mut requests = Vec::new();
+    for i in 0..16 {
+        let other = ROOT_FID + 10 + i;
+        let msgs = [
+            Tmessage::Fsync(Tfsync { fid, datasync: 0 }),
+            Tmessage::Walk(Twalk {
+                fid: ROOT_FID,
+                newfid: other,
+                wnames: vec![P9String::new("b").unwrap()],
+            }),
+            Tmessage::Lopen(Tlopen {
+                fid: other,
+                flags: P9_RDONLY,
+            }),
+        ];
+        for (tag, msg) in msgs.into_iter().enumerate() {
+            Tframe {
+                tag: (i * 3) as u16 + tag as u16,
+                msg: Ok(msg),
             }
-
-            let tclunk = Tclunk { fid };
-            server.clunk(&tclunk).expect("Unable to clunk file");
+            .encode(&mut requests)
+            .expect("failed to encode request");
         }
-    };
-    ($name:ident, $flags:expr, $expected_err:expr) => {
-        #[test]
-        fn $name() {
-            let (test_dir, mut server) = setup("open_fail");
+    }
 
-            let fid = ROOT_FID + 1;
-            let name = "test.txt";
-            create_local_file(&test_dir, name);
+    let dispatcher = Dispatcher::new(server, 4);
+    let mut responses = Vec::new();
+    dispatcher
+        .serve(&mut Cursor::new(requests), &mut responses)
+        .expect("failed to serve requests");
+
+    let mut reader = Cursor::new(responses);
+    let mut replies = 0;
+    while (reader.position() as usize) < reader.get_ref().len() {
+        let rframe: Rframe = WireFormat::decode(&mut reader).expect("failed to decode response");
+        if rframe.tag.is_multiple_of(3) {
+            assert!(matches!(rframe.msg, Rmessage::Fsync), "{:?}", rframe.msg);
+        } else {
+            assert!(
+                !matches!(rframe.msg, Rmessage::Lerror(_)),
+                "{:?}",
+                rframe.msg
+            );
+        }
+        replies += 1;
+    }
+    assert_eq!(replies, 48);
+}
 
-            let err = open(&mut server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
-                .expect_err("successfully opened file");
-            assert_eq!(err.kind(), $expected_err);
+// Waits for `delay` before handing out `frame`.
+struct SlowReader {
+    delay: Duration,
+    frame: Cursor<Vec<u8>>,
+}
 
-            let tclunk = Tclunk { fid };
-            server.clunk(&tclunk).expect("Unable to clunk file");
-        }
-    };
+impl io::Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(mem::take(&mut self.delay));
+        self.frame.read(buf)
+    }
 }
 
-open_test!(read_only_file_open, P9_RDONLY);
-open_test!(read_write_file_open, P9_RDWR);
-open_test!(write_only_file_open, P9_WRONLY);
+#[test]
+fn dispatcher_reap_idle() {
+    let (_test_dir, mut server) = setup("dispatcher_reap_idle");
+    server.cfg.idle_timeout = Some(Duration::from_millis(20));
+
+    let mut frame = Vec::new();
+    Tframe {
+        tag: 1,
+        msg: Ok(Tmessage::Ping),
+    }
+    .encode(&mut frame)
+    .expect("failed to encode request");
+    let mut reader = SlowReader {
+        delay: Duration::from_millis(200),
+        frame: Cursor::new(frame),
+    };
 
-open_test!(create_read_only_file_open, P9_CREATE | P9_RDONLY);
-open_test!(create_read_write_file_open, P9_CREATE | P9_RDWR);
-open_test!(create_write_only_file_open, P9_CREATE | P9_WRONLY);
+    // The session ends while the client is quiet, without waiting for it to send anything.
+    let dispatcher = Dispatcher::new(server, 2);
+    let mut responses = Vec::new();
+    let err = dispatcher
+        .serve(&mut reader, &mut responses)
+        .expect_err("idle session wasn't ended");
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(responses.is_empty());
+    assert!(dispatcher.into_server().fids.is_empty());
+}
 
-open_test!(append_read_only_file_open, P9_APPEND | P9_RDONLY);
-open_test!(append_read_write_file_open, P9_APPEND | P9_RDWR);
-open_test!(append_write_only_file_open, P9_APPEND | P9_WRONLY);
+#[test]
+fn dispatcher_flush() {
+    let (test_dir, mut server) = setup("dispatcher_flush");
 
-open_test!(trunc_read_only_file_open, P9_TRUNC | P9_RDONLY);
-open_test!(trunc_read_write_file_open, P9_TRUNC | P9_RDWR);
-open_test!(trunc_write_only_file_open, P9_TRUNC | P9_WRONLY);
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    let content = create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDWR).expect("failed to open file");
+
+    let mut requests = Vec::new();
+    let msgs = vec![
+        (
+            1,
+            Tmessage::Read(Tread {
+                fid,
+                offset: 0,
+                count: 64,
+            }),
+        ),
+        (2, Tmessage::Flush(Tflush { oldtag: 1 })),
+        (3, Tmessage::Fsync(Tfsync { fid, datasync: 1 })),
+        (
+            4,
+            Tmessage::GetAttr(Tgetattr {
+                fid: ROOT_FID,
+                request_mask: P9_GETATTR_BASIC,
+            }),
+        ),
+    ];
+    for (tag, msg) in msgs {
+        Tframe { tag, msg: Ok(msg) }
+            .encode(&mut requests)
+            .expect("failed to encode request");
+    }
 
-open_test!(
-    create_append_read_only_file_open,
-    P9_CREATE | P9_APPEND | P9_RDONLY
-);
-open_test!(
-    create_append_read_write_file_open,
-    P9_CREATE | P9_APPEND | P9_RDWR
-);
-open_test!(
-    create_append_wronly_file_open,
-    P9_CREATE | P9_APPEND | P9_WRONLY
-);
+    let dispatcher = Dispatcher::new(server, 1);
+    let mut responses = Vec::new();
+    dispatcher
+        .serve(&mut Cursor::new(requests), &mut responses)
+        .expect("failed to serve requests");
+
+    let mut reader = Cursor::new(responses);
+    let mut replies = Vec::new();
+    while (reader.position() as usize) < reader.get_ref().len() {
+        let rframe: Rframe = WireFormat::decode(&mut reader).expect("failed to decode response");
+        replies.push(rframe);
+    }
+    let position = |tag| replies.iter().position(|rframe| rframe.tag == tag);
+
+    // The Tread may or may not be done by the time it is flushed, but if it is replied to then
+    // that goes out before the Rflush.
+    let flushed = position(2).expect("no reply to Tflush");
+    assert!(matches!(replies[flushed].msg, Rmessage::Flush));
+    if let Some(read) = position(1) {
+        assert!(read < flushed);
+        match &replies[read].msg {
+            Rmessage::Read(rread) => assert_eq!(&*rread.data, &content[..64]),
+            msg => panic!("unexpected response: {:?}", msg),
+        }
+    }
+    assert!(matches!(replies[position(3).unwrap()].msg, Rmessage::Fsync));
+    assert!(matches!(
+        replies[position(4).unwrap()].msg,
+        Rmessage::GetAttr(_)
+    ));
+    // Nothing is left behind for the tags once they have been replied to.
+    assert!(dispatcher.into_server().flusher.finish(1).is_none());
+}
 
-open_test!(
-    create_trunc_read_only_file_open,
-    P9_CREATE | P9_TRUNC | P9_RDONLY
-);
-open_test!(
-    create_trunc_read_write_file_open,
-    P9_CREATE | P9_TRUNC | P9_RDWR
-);
-open_test!(
-    create_trunc_wronly_file_open,
-    P9_CREATE | P9_TRUNC | P9_WRONLY
-);
+// Hands out one request frame at a time, and records how many replies had been written to
+// `replies` by the time that each was asked for.
+struct FrameReader {
+    frames: VecDeque<Vec<u8>>,
+    current: Cursor<Vec<u8>>,
+    replies: Arc<Mutex<Vec<u8>>>,
+    replied: Vec<usize>,
+}
 
-open_test!(
-    append_trunc_read_only_file_open,
-    P9_APPEND | P9_TRUNC | P9_RDONLY
-);
-open_test!(
-    append_trunc_read_write_file_open,
-    P9_APPEND | P9_TRUNC | P9_RDWR
-);
-open_test!(
-    append_trunc_wronly_file_open,
-    P9_APPEND | P9_TRUNC | P9_WRONLY
-);
+impl io::Read for FrameReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.current.position() as usize == self.current.get_ref().len() {
+            let frame = match self.frames.pop_front() {
+                Some(frame) => frame,
+                None => return Ok(0),
+            };
+            let replies = self.replies.lock().unwrap();
+            let mut count = 0;
+            let mut pos = 0;
+            while pos < replies.len() {
+                let size: [u8; 4] = replies[pos..pos + 4].try_into().unwrap();
+                pos += u32::from_le_bytes(size) as usize;
+                count += 1;
+            }
+            self.replied.push(count);
+            self.current = Cursor::new(frame);
+        }
+        self.current.read(buf)
+    }
+}
 
-open_test!(
-    create_append_trunc_read_only_file_open,
-    P9_CREATE | P9_APPEND | P9_TRUNC | P9_RDONLY
-);
-open_test!(
-    create_append_trunc_read_write_file_open,
-    P9_CREATE | P9_APPEND | P9_TRUNC | P9_RDWR
-);
-open_test!(
-    create_append_trunc_wronly_file_open,
-    P9_CREATE | P9_APPEND | P9_TRUNC | P9_WRONLY
-);
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
 
-open_test!(
-    create_excl_read_only_file_open,
-    P9_CREATE | P9_EXCL | P9_RDONLY,
-    io::ErrorKind::AlreadyExists
-);
-open_test!(
-    create_excl_read_write_file_open,
-    P9_CREATE | P9_EXCL | P9_RDWR,
-    io::ErrorKind::AlreadyExists
-);
-open_test!(
-    create_excl_wronly_file_open,
-    P9_CREATE | P9_EXCL | P9_WRONLY,
-    io::ErrorKind::AlreadyExists
-);
+impl io::Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
 
-macro_rules! create_test {
-    ($name:ident, $flags:expr, $mode:expr) => {
-        #[test]
-        fn $name() {
-            let (test_dir, mut server) = setup("create");
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-            let name = "foo.txt";
-            let fid = ROOT_FID + 1;
-            let rlcreate = create(&mut server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
-                .expect("failed to create file");
+#[test]
+fn max_inflight() {
+    let cfg: Config = "max_inflight=1".parse().expect("failed to parse config");
+    assert_eq!(cfg.max_inflight, Some(1));
+    let (test_dir, mut server) = setup_config("max_inflight", "9P2000.L", cfg);
 
-            let md =
-                fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
-            assert_eq!(rlcreate.iounit, 0);
-            check_qid(&rlcreate.qid, &md);
-            check_attr(&mut server, fid, &md);
+    let name = "foo.txt";
+    let content = create_local_file(&*test_dir, name);
+    for fid in ROOT_FID + 1..ROOT_FID + 5 {
+        open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+    }
 
-            // Check that we can write to the file.
-            if $flags & P9_RDWR != 0 || $flags & P9_WRONLY != 0 {
-                write(&mut server, &test_dir, name, fid, $flags);
+    let frames = (ROOT_FID + 1..ROOT_FID + 5)
+        .enumerate()
+        .map(|(tag, fid)| {
+            let mut frame = Vec::new();
+            let tread = Tread {
+                fid,
+                offset: 0,
+                count: 64,
+            };
+            Tframe {
+                tag: tag as u16,
+                msg: Ok(Tmessage::Read(tread)),
             }
+            .encode(&mut frame)
+            .expect("failed to encode request");
+            frame
+        })
+        .collect();
+    let replies = Arc::new(Mutex::new(Vec::new()));
+    let mut reader = FrameReader {
+        frames,
+        current: Cursor::new(Vec::new()),
+        replies: replies.clone(),
+        replied: Vec::new(),
+    };
 
-            let tclunk = Tclunk { fid };
-            server.clunk(&tclunk).expect("Unable to clunk file");
+    // Each request goes to a worker of its own, but the next one isn't read until the one
+    // before it has been replied to.
+    let dispatcher = Dispatcher::new(server, 4);
+    dispatcher
+        .serve(&mut reader, SharedWriter(replies.clone()))
+        .expect("failed to serve requests");
+    assert_eq!(reader.replied, vec![0, 1, 2, 3]);
+
+    let replies = replies.lock().unwrap();
+    let mut replies = Cursor::new(&replies[..]);
+    for tag in 0..4 {
+        let rframe: Rframe = WireFormat::decode(&mut replies).expect("failed to decode response");
+        assert_eq!(rframe.tag, tag);
+        match rframe.msg {
+            Rmessage::Read(rread) => assert_eq!(&*rread.data, &content[..64]),
+            msg => panic!("unexpected response: {:?}", msg),
         }
+    }
+}
+
+#[test]
+fn readahead() {
+    let cfg: Config = "readahead=100".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("readahead", "9P2000.L", cfg);
+
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    let content = create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+
+    let read = |server: &mut Server, offset, count| {
+        let rread = server
+            .read(&Tread { fid, offset, count })
+            .expect("failed to read file");
+        let start = min(offset, LOCAL_FILE_LEN) as usize;
+        let end = min(offset + u64::from(count), LOCAL_FILE_LEN) as usize;
+        assert_eq!(&*rread.data, &content[start..end]);
+        lock_fid(&server.fids.get(fid).unwrap()).read_ahead
     };
-    ($name:ident, $flags:expr, $mode:expr, $expected_err:expr) => {
-        #[test]
-        fn $name() {
-            let (test_dir, mut server) = setup("create_fail");
 
-            let name = "foo.txt";
-            // The `fid` in the lcreate call initially points to the directory
-            // but is supposed to point to the newly created file after the call
-            // completes.  Duplicate the fid so that we don't end up consuming the
-            // root fid.
-            let fid = ROOT_FID + 1;
-            let err = create(&mut server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
-                .expect_err("successfully created file");
-            assert_eq!(err.kind(), $expected_err);
+    // Reading from the start is sequential, and the host isn't told again until the reads are
+    // halfway through what it was told to read ahead.
+    assert_eq!(read(&mut server, 0, 10), (10, 110));
+    assert_eq!(read(&mut server, 10, 10), (20, 110));
+    assert_eq!(read(&mut server, 20, 40), (60, 160));
+    // Reads elsewhere aren't, but the ones after them can be.
+    assert_eq!(read(&mut server, 150, 10), (160, 160));
+    assert_eq!(read(&mut server, 0, 10), (10, 10));
+    assert_eq!(read(&mut server, 10, 10), (20, 120));
+}
+
+#[test]
+fn coalesce_writes() {
+    let cfg: Config = "coalesce_writes=16"
+        .parse()
+        .expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("coalesce_writes", "9P2000.L", cfg);
+
+    let (a, b) = (ROOT_FID + 1, ROOT_FID + 2);
+    create(&mut server, &*test_dir, ROOT_FID, a, "f", P9_RDWR, 0o644)
+        .expect("failed to create file");
+    open(&mut server, &*test_dir, ROOT_FID, "f", b, P9_RDWR).expect("failed to open file");
+    let write = |server: &mut Server, fid, offset, data: &[u8]| {
+        let twrite = Twrite {
+            fid,
+            offset,
+            data: Data(data.to_vec()),
+        };
+        match server.dispatch(Ok(Tmessage::Write(twrite))) {
+            Ok(Rmessage::Write(rwrite)) => assert_eq!(rwrite.count as usize, data.len()),
+            rmsg => panic!("unexpected response: {:?}", rmsg),
         }
     };
-}
+    let contents = || fs::read(test_dir.join("f")).expect("failed to read file");
+
+    // Writes that follow on from each other wait for more.
+    write(&mut server, a, 0, b"hello");
+    write(&mut server, a, 5, b", wor");
+    assert_eq!(contents(), b"");
+
+    // Writes through other fids of the file go after them, and anything else sees them all.
+    write(&mut server, b, 0, b"J");
+    assert_eq!(contents(), b"hello, wor");
+    let tread = Tread {
+        fid: a,
+        offset: 0,
+        count: 64,
+    };
+    match server.dispatch(Ok(Tmessage::Read(tread))) {
+        Ok(Rmessage::Read(rread)) => assert_eq!(&*rread.data, b"Jello, wor"),
+        rmsg => panic!("unexpected response: {:?}", rmsg),
+    }
 
-create_test!(read_only_file_create, P9_RDONLY, 0o600u32);
-create_test!(read_write_file_create, P9_RDWR, 0o600u32);
-create_test!(write_only_file_create, P9_WRONLY, 0o600u32);
+    // Writes that don't follow on, or that are too big to hold back, go out right away.
+    write(&mut server, a, 10, b"ld");
+    write(&mut server, a, 0, b"h");
+    assert_eq!(contents(), b"Jello, world");
+    write(&mut server, a, 12, b"! and then some more");
+    assert_eq!(contents(), b"hello, world! and then some more");
 
-create_test!(
-    append_read_only_file_create,
-    P9_APPEND | P9_RDONLY,
-    0o600u32
-);
-create_test!(append_read_write_file_create, P9_APPEND | P9_RDWR, 0o600u32);
-create_test!(append_wronly_file_create, P9_APPEND | P9_WRONLY, 0o600u32);
+    // Clunking the fid writes what it has left.
+    write(&mut server, a, 0, b"H");
+    assert_eq!(contents(), b"hello, world! and then some more");
+    server
+        .clunk(&Tclunk { fid: a })
+        .expect("failed to clunk fid");
+    assert_eq!(contents(), b"Hello, world! and then some more");
+    assert!(server.dirty.is_empty());
+}
 
 #[test]
-fn lcreate_set_len() {
-    let (test_dir, mut server) = setup("lcreate_set_len");
+fn attr_cache() {
+    let cfg: Config = "attr_cache=60".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("attr_cache", "9P2000.L", cfg);
 
+    let (a, b) = (ROOT_FID + 1, ROOT_FID + 2);
     let name = "foo.txt";
-    let fid = ROOT_FID + 1;
-    create(
+    create_local_file(&*test_dir, name);
+    walk(
         &mut server,
         &*test_dir,
         ROOT_FID,
-        fid,
-        name,
-        P9_RDWR,
-        0o600u32,
-    )
-    .expect("failed to create file");
+        a,
+        vec![P9String::new(name).unwrap()],
+    );
+    walk(
+        &mut server,
+        &*test_dir,
+        ROOT_FID,
+        b,
+        vec![P9String::new(name).unwrap()],
+    );
+    let mode = |server: &mut Server, fid| {
+        let tgetattr = Tgetattr {
+            fid,
+            request_mask: P9_GETATTR_BASIC,
+        };
+        match server.dispatch(Ok(Tmessage::GetAttr(tgetattr))) {
+            Ok(Rmessage::GetAttr(rgetattr)) => rgetattr.mode & 0o7777,
+            rmsg => panic!("unexpected response: {:?}", rmsg),
+        }
+    };
+    let chmod = |mode| {
+        fs::set_permissions(test_dir.join(name), fs::Permissions::from_mode(mode))
+            .expect("failed to change mode");
+    };
 
+    chmod(0o644);
+    assert_eq!(mode(&mut server, a), 0o644);
+    // Changes made on the host don't show up while the attributes are cached.
+    chmod(0o600);
+    assert_eq!(mode(&mut server, a), 0o644);
+
+    // Changes made through another fid of the file do.
     let tsetattr = Tsetattr {
-        fid,
-        valid: 0x8, // P9_SETATTR_SIZE
-        size: 100,
-        // The other fields are not used because the relevant flags aren't set in `valid`.
-        mode: 0,
+        fid: b,
+        valid: P9_SETATTR_MODE,
+        mode: 0o640,
         uid: 0,
         gid: 0,
+        size: 0,
         atime_sec: 0,
         atime_nsec: 0,
         mtime_sec: 0,
         mtime_nsec: 0,
     };
     server
-        .set_attr(&tsetattr)
-        .expect("failed to set file length after lcreate");
+        .dispatch(Ok(Tmessage::SetAttr(tsetattr)))
+        .expect("failed to set attributes");
+    assert_eq!(mode(&mut server, a), 0o640);
+    assert_eq!(mode(&mut server, b), 0o640);
 
-    let tclunk = Tclunk { fid };
-    server.clunk(&tclunk).expect("Unable to clunk file");
+    // So do changes to the tree.
+    chmod(0o600);
+    let tmkdir = Tmkdir {
+        dfid: ROOT_FID,
+        name: P9String::new("dir").unwrap(),
+        mode: 0o755,
+        gid: 0,
+    };
+    server
+        .dispatch(Ok(Tmessage::Mkdir(tmkdir)))
+        .expect("failed to make directory");
+    assert_eq!(mode(&mut server, a), 0o600);
+
+    let attrs = server.attrs.as_ref().unwrap();
+    assert_eq!((attrs.hits(), attrs.misses()), (2, 3));
 }
 
 #[test]
-fn readlink() {
-    let (test_dir, mut server) = setup("readlink");
-    create_local_symlink(&test_dir, "symlink", "target/of/symlink");
+fn attr_cache_create() {
+    let cfg: Config = "attr_cache=60".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("attr_cache_create", "9P2000.L", cfg);
 
+    // A fid that becomes the file that it creates doesn't keep the attributes of its directory.
     let fid = ROOT_FID + 1;
-    walk(
-        &mut server,
-        &*test_dir,
-        ROOT_FID,
+    walk(&mut server, &*test_dir, ROOT_FID, fid, Vec::new());
+    let getattr = |server: &mut Server| {
+        let tgetattr = Tgetattr {
+            fid,
+            request_mask: P9_GETATTR_BASIC,
+        };
+        match server.dispatch(Ok(Tmessage::GetAttr(tgetattr))) {
+            Ok(Rmessage::GetAttr(rgetattr)) => rgetattr.mode & libc::S_IFMT,
+            rmsg => panic!("unexpected response: {:?}", rmsg),
+        }
+    };
+    assert_eq!(getattr(&mut server), libc::S_IFDIR);
+    let tlcreate = Tlcreate {
         fid,
-        vec![P9String::new("symlink").unwrap()],
-    );
+        name: P9String::new("f").unwrap(),
+        flags: P9_RDWR,
+        mode: 0o644,
+        gid: 0,
+    };
+    server
+        .dispatch(Ok(Tmessage::Lcreate(tlcreate)))
+        .expect("failed to create file");
+    assert_eq!(getattr(&mut server), libc::S_IFREG);
+}
 
-    let treadlink = Treadlink { fid };
+#[test]
+fn negative_cache() {
+    let cfg: Config = "negative_cache=60".parse().expect("failed to parse config");
+    let (test_dir, mut server) = setup_config("negative_cache", "9P2000.L", cfg);
 
-    let rreadlink = server.readlink(&treadlink).expect("failed to readlink");
+    let fid = ROOT_FID + 1;
+    let lookup = |server: &mut Server, name: &str| {
+        let twalk = Twalk {
+            fid: ROOT_FID,
+            newfid: fid,
+            wnames: vec![P9String::new(name).unwrap()],
+        };
+        let res = server.dispatch(Ok(Tmessage::Walk(twalk)));
+        if res.is_ok() {
+            server.clunk(&Tclunk { fid }).expect("failed to clunk fid");
+        }
+        res.map(drop).map_err(|e| e.raw_os_error())
+    };
 
-    assert_eq!(rreadlink.target, "target/of/symlink");
+    assert_eq!(lookup(&mut server, "foo.h"), Err(Some(libc::ENOENT)));
+    // Files created on the host don't show up while the name is cached.
+    create_local_file(&*test_dir, "foo.h");
+    assert_eq!(lookup(&mut server, "foo.h"), Err(Some(libc::ENOENT)));
+    assert_eq!(server.missing.as_ref().unwrap().hits(), 1);
+
+    // Files created in the directory through the server do.
+    let tmkdir = Tmkdir {
+        dfid: ROOT_FID,
+        name: P9String::new("dir").unwrap(),
+        mode: 0o755,
+        gid: 0,
+    };
+    server
+        .dispatch(Ok(Tmessage::Mkdir(tmkdir)))
+        .expect("failed to make directory");
+    assert_eq!(lookup(&mut server, "foo.h"), Ok(()));
+
+    // Other errors aren't cached.
+    let error = lookup(&mut server, &"x".repeat(300));
+    assert_eq!(error, Err(Some(libc::ENAMETOOLONG)));
+    assert_eq!(lookup(&mut server, &"x".repeat(300)), error);
+    assert_eq!(server.missing.as_ref().unwrap().hits(), 1);
+}
+
+#[test]
+fn readdir_keeps_stream() {
+    let (test_dir, mut server) = setup("readdir_keeps_stream");
+    for i in 0..100 {
+        create_local_file(&*test_dir, &format!("file{i}"));
+    }
+    // Entries are only kept for directories that haven't changed right before they are read.
+    let age = |dir: &Path| {
+        File::open(dir)
+            .and_then(|dir| dir.set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000)))
+            .expect("failed to set mtime")
+    };
+    age(&test_dir);
+
+    let fid = ROOT_FID + 1;
+    walk(&mut server, &*test_dir, ROOT_FID, fid, Vec::new());
+    open(&mut server, &*test_dir, fid, "", fid, P9_DIRECTORY).expect("failed to open directory");
+    let page = |server: &mut Server, offset: u64| {
+        let Rreaddir { data } = server
+            .readdir(&Treaddir {
+                fid,
+                offset,
+                count: 256,
+            })
+            .expect("failed to read directory");
+        let mut cursor = Cursor::new(data.0);
+        let mut dirents = Vec::new();
+        while cursor.position() < cursor.get_ref().len() as u64 {
+            let dirent: Dirent = WireFormat::decode(&mut cursor).expect("failed to decode dirent");
+            dirents.push(dirent);
+        }
+        dirents
+    };
+    let all = |server: &mut Server, mut offset: u64| {
+        let mut names = Vec::new();
+        loop {
+            let dirents = page(server, offset);
+            match dirents.last() {
+                Some(dirent) => offset = dirent.offset,
+                None => return names,
+            }
+            names.extend(dirents.into_iter().map(|dirent| dirent.name));
+        }
+    };
+
+    let first = page(&mut server, 0);
+    let offset = first.last().unwrap().offset;
+    // With "." and "..".
+    let len = fs::read_dir(&*test_dir).unwrap().count() + 2;
+    assert!(first.len() < len);
+
+    // A file that is added behind the back of the server without the mtime of the directory
+    // moving on doesn't show up in the reads that carry on from there, like it wouldn't in a
+    // directory stream, but does once the directory is read from the start again.
+    create_local_file(&*test_dir, "new");
+    age(&test_dir);
+    let mut names: Vec<_> = first.into_iter().map(|dirent| dirent.name).collect();
+    names.extend(all(&mut server, offset));
+    assert_eq!(names.len(), len);
+    assert!(!names.iter().any(|name| name.as_bytes() == b"new"));
+
+    let again = all(&mut server, 0);
+    assert_eq!(again.len(), len + 1);
+    assert!(again.iter().any(|name| name.as_bytes() == b"new"));
+}
+
+#[test]
+fn read_cached_data() {
+    let (test_dir, mut server) = setup("read_cached_data");
+    let name = "foo.txt";
+    let fid = ROOT_FID + 1;
+    let content = create_local_file(&*test_dir, name);
+    open(&mut server, &*test_dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+
+    // The file was just written, so its data is in the page cache and can be read without
+    // waiting, as far as the end of the file.
+    let file = File::open(test_dir.join(name)).expect("failed to open file");
+    let mut buf = vec![0; 300];
+    assert_eq!(read_cached(&file, &mut buf, 10), (190, true));
+    assert_eq!(&buf[..190], &content[10..]);
+    assert_eq!(read_cached(&file, &mut buf, 300), (0, true));
+
+    // A dispatcher then doesn't hand the read to a thread.
+    server.offload = true;
+    let rread = server
+        .read(&Tread {
+            fid,
+            offset: 0,
+            count: 100,
+        })
+        .expect("failed to read file");
+    server.offload = false;
+    assert!(server.offloaded.is_none());
+    assert_eq!(&*rread.data, &content[..100]);
 }
@@ -0,0 +1,899 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::*;
+use crate::protocol::*;
+
+// The magic number that statfs(2) reports for tmpfs.
+const TMPFS_MAGIC: u32 = 0x01021994;
+
+// Files are kept in a single buffer so they can't be sparse. This keeps a stray write at a huge
+// offset from taking all of the memory of the server.
+const MAX_FILE_SIZE: u64 = 1 << 32;
+
+// The block size that statfs reports.
+const STATFS_BLOCK_SIZE: u64 = 4096;
+
+// The longest name an entry can have, as on Linux.
+const NAME_MAX: usize = 255;
+
+// How many entries of a directory are copied out at a time by readdir.
+const READDIR_BATCH: usize = 64;
+
+// The offsets of the "." and ".." entries of every directory. The other entries come after them.
+const DOT_OFFSET: u64 = 1;
+const DOTDOT_OFFSET: u64 = 2;
+
+fn err(errno: libc::c_int) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn now() -> libc::timespec {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    libc::timespec {
+        tv_sec: since_epoch.as_secs() as _,
+        tv_nsec: since_epoch.subsec_nanos() as _,
+    }
+}
+
+/// Serves trees that only exist in memory, for tests and scratch space, without ever touching
+/// the host. Every export gets an empty tree of its own the first time a client attaches to it,
+/// which lives as long as the file system does. Clones share the same trees, so several servers
+/// can serve them at the same time.
+///
+/// There is no limit on how much data the files can hold unless it is created with
+/// `Tmpfs::with_capacity`. Permissions aren't checked, as if every request came from the owner of
+/// the file, so it is up to the client to enforce them. There are no symlinks or special files,
+/// and none of the requests that need a file on the host, such as locks, are supported.
+#[derive(Clone)]
+pub struct Tmpfs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl Tmpfs {
+    /// Creates an empty file system whose files belong to the user and group of the server
+    /// unless clients say otherwise.
+    pub fn new() -> Tmpfs {
+        // Safe because these calls can't fail and don't touch any memory.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Tmpfs {
+            tree: Arc::new(Mutex::new(Tree {
+                nodes: BTreeMap::new(),
+                roots: BTreeMap::new(),
+                next_ino: 0,
+                uid,
+                gid,
+                capacity: None,
+                used: 0,
+            })),
+        }
+    }
+
+    /// Creates an empty file system whose files can hold at most `capacity` bytes between them,
+    /// like a tmpfs mount with a `size`. Writes that would take it over fail with ENOSPC.
+    pub fn with_capacity(capacity: u64) -> Tmpfs {
+        let fs = Tmpfs::new();
+        fs.tree.lock().unwrap().capacity = Some(capacity);
+        fs
+    }
+
+    // Returns a new reference to `ino`, reached through `link`. The caller must hold the lock on
+    // the tree, which is passed in as `tree`.
+    fn inode(&self, tree: &mut Tree, ino: u64, link: Option<(u64, Vec<u8>)>) -> TmpfsInode {
+        if let Some(node) = tree.nodes.get_mut(&ino) {
+            node.refs += 1;
+        }
+        TmpfsInode {
+            tree: self.tree.clone(),
+            ino,
+            link,
+        }
+    }
+}
+
+impl Default for Tmpfs {
+    fn default() -> Tmpfs {
+        Tmpfs::new()
+    }
+}
+
+/// A reference to a file in a `Tmpfs`, which keeps it around until it is dropped even once the
+/// file has been removed.
+pub struct TmpfsInode {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+    // The directory that the file was looked up in and its name in there.
+    link: Option<(u64, Vec<u8>)>,
+}
+
+impl Drop for TmpfsInode {
+    fn drop(&mut self) {
+        // Nothing can be freed any more once another thread panicked with the lock held.
+        if let Ok(mut tree) = self.tree.lock() {
+            if let Some(node) = tree.nodes.get_mut(&self.ino) {
+                node.refs -= 1;
+            }
+            tree.maybe_free(self.ino);
+        }
+    }
+}
+
+/// A file in a `Tmpfs` that a client has opened.
+pub struct TmpfsHandle {
+    inode: TmpfsInode,
+    flags: u32,
+}
+
+struct Tree {
+    nodes: BTreeMap<u64, Node>,
+    // The root directory of every export that has been attached to.
+    roots: BTreeMap<Box<Path>, u64>,
+    next_ino: u64,
+    // Who owns the files that clients don't give an owner.
+    uid: u32,
+    gid: u32,
+    // How many bytes of data the files may hold between them, and how many they do.
+    capacity: Option<u64>,
+    used: u64,
+}
+
+struct Node {
+    // The type and permissions of the file, as in `st_mode`.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    atime: libc::timespec,
+    mtime: libc::timespec,
+    ctime: libc::timespec,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    // Every directory that the file is in, along with its name in there. Directories have at
+    // most one, and roots none.
+    links: Vec<(u64, Vec<u8>)>,
+    // How many inodes refer to the file. It is freed once both this and `links` are empty.
+    refs: usize,
+    contents: Contents,
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Dir(Dir),
+}
+
+struct Dir {
+    // The entries by the offset that they were added at, which never changes, so that the
+    // offsets handed out by readdir stay valid however the directory changes in between.
+    entries: BTreeMap<u64, (Vec<u8>, u64)>,
+    // The offsets of `entries` by name.
+    names: BTreeMap<Vec<u8>, u64>,
+    next_offset: u64,
+}
+
+impl Dir {
+    fn new() -> Dir {
+        Dir {
+            entries: BTreeMap::new(),
+            names: BTreeMap::new(),
+            next_offset: DOTDOT_OFFSET,
+        }
+    }
+}
+
+impl Node {
+    fn dir(&self) -> io::Result<&Dir> {
+        match self.contents {
+            Contents::Dir(ref dir) => Ok(dir),
+            Contents::File(_) => Err(err(libc::ENOTDIR)),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.contents, Contents::Dir(_))
+    }
+
+    fn touch(&mut self) {
+        let now = now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+impl Tree {
+    fn node(&self, ino: u64) -> io::Result<&Node> {
+        self.nodes.get(&ino).ok_or_else(|| err(libc::ESTALE))
+    }
+
+    fn node_mut(&mut self, ino: u64) -> io::Result<&mut Node> {
+        self.nodes.get_mut(&ino).ok_or_else(|| err(libc::ESTALE))
+    }
+
+    fn file_mut(&mut self, ino: u64) -> io::Result<&mut Vec<u8>> {
+        match self.node_mut(ino)?.contents {
+            Contents::File(ref mut data) => Ok(data),
+            Contents::Dir(_) => Err(err(libc::EISDIR)),
+        }
+    }
+
+    // Resizes the regular file `ino` to `len` bytes, as long as that fits in the capacity of the
+    // file system. The data is checked before any memory is allocated for it.
+    fn resize(&mut self, ino: u64, len: u64) -> io::Result<()> {
+        let old = self.file_mut(ino)?.len() as u64;
+        if len > MAX_FILE_SIZE {
+            return Err(err(libc::EFBIG));
+        }
+        let used = self.used - old + len;
+        if len > old && self.capacity.is_some_and(|capacity| used > capacity) {
+            return Err(err(libc::ENOSPC));
+        }
+
+        self.file_mut(ino)?.resize(len as usize, 0);
+        self.used = used;
+        Ok(())
+    }
+
+    fn dir_mut(&mut self, ino: u64) -> io::Result<&mut Dir> {
+        match self.node_mut(ino)?.contents {
+            Contents::Dir(ref mut dir) => Ok(dir),
+            Contents::File(_) => Err(err(libc::ENOTDIR)),
+        }
+    }
+
+    fn is_root(&self, ino: u64) -> bool {
+        self.roots.values().any(|&root| root == ino)
+    }
+
+    // Returns a directory that `ino` is in, or `ino` itself for a root.
+    fn parent_of(&self, ino: u64) -> io::Result<u64> {
+        let node = self.node(ino)?;
+        match node.links.first() {
+            Some(&(parent, _)) => Ok(parent),
+            None if self.is_root(ino) => Ok(ino),
+            None => Err(err(libc::ENOENT)),
+        }
+    }
+
+    // Returns the entry `name` of the directory `dir`.
+    fn child(&self, dir: u64, name: &[u8]) -> io::Result<u64> {
+        let dir = self.node(dir)?.dir()?;
+        dir.names
+            .get(name)
+            .and_then(|offset| dir.entries.get(offset))
+            .map(|&(_, ino)| ino)
+            .ok_or_else(|| err(libc::ENOENT))
+    }
+
+    // Checks that `dir` is a directory that new entries can be added to.
+    fn check_live_dir(&self, dir: u64) -> io::Result<()> {
+        let node = self.node(dir)?;
+        node.dir()?;
+        if node.links.is_empty() && !self.is_root(dir) {
+            return Err(err(libc::ENOENT));
+        }
+        Ok(())
+    }
+
+    fn add_node(&mut self, mode: u32, uid: u32, gid: u32, contents: Contents) -> u64 {
+        self.next_ino += 1;
+        let now = now();
+        self.nodes.insert(
+            self.next_ino,
+            Node {
+                mode,
+                uid: if uid == u32::MAX { self.uid } else { uid },
+                gid: if gid == u32::MAX { self.gid } else { gid },
+                atime: now,
+                mtime: now,
+                ctime: now,
+                xattrs: BTreeMap::new(),
+                links: Vec::new(),
+                refs: 0,
+                contents,
+            },
+        );
+        self.next_ino
+    }
+
+    // Adds `ino` to `dir` as `name`, which must not be there yet.
+    fn add_link(&mut self, dir: u64, name: &[u8], ino: u64) -> io::Result<()> {
+        let entries = self.dir_mut(dir)?;
+        entries.next_offset += 1;
+        let offset = entries.next_offset;
+        entries.entries.insert(offset, (name.to_vec(), ino));
+        entries.names.insert(name.to_vec(), offset);
+        self.node_mut(dir)?.touch();
+
+        let node = self.node_mut(ino)?;
+        node.links.push((dir, name.to_vec()));
+        node.ctime = now();
+        Ok(())
+    }
+
+    // Removes `name` from `dir` and returns what it was.
+    fn remove_link(&mut self, dir: u64, name: &[u8]) -> io::Result<u64> {
+        let entries = self.dir_mut(dir)?;
+        let offset = entries
+            .names
+            .remove(name)
+            .ok_or_else(|| err(libc::ENOENT))?;
+        let (_, ino) = entries
+            .entries
+            .remove(&offset)
+            .ok_or_else(|| err(libc::ENOENT))?;
+        self.node_mut(dir)?.touch();
+
+        let node = self.node_mut(ino)?;
+        if let Some(pos) = node
+            .links
+            .iter()
+            .position(|(parent, n)| *parent == dir && n == name)
+        {
+            node.links.remove(pos);
+        }
+        node.ctime = now();
+        self.maybe_free(ino);
+        Ok(ino)
+    }
+
+    // Frees `ino` if nothing refers to it any more.
+    fn maybe_free(&mut self, ino: u64) {
+        let unused = self
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| node.refs == 0 && node.links.is_empty());
+        if unused && !self.is_root(ino) {
+            if let Some(Node {
+                contents: Contents::File(data),
+                ..
+            }) = self.nodes.remove(&ino)
+            {
+                self.used -= data.len() as u64;
+            }
+        }
+    }
+
+    fn stat(&self, ino: u64) -> io::Result<libc::stat64> {
+        let node = self.node(ino)?;
+        let (size, nlink) = match node.contents {
+            Contents::File(ref data) => (data.len() as u64, node.links.len() as u64),
+            Contents::Dir(ref dir) => {
+                let subdirs = dir
+                    .entries
+                    .values()
+                    .filter(|&&(_, child)| self.nodes.get(&child).is_some_and(Node::is_dir))
+                    .count() as u64;
+                let live = !node.links.is_empty() || self.is_root(ino);
+                (0, if live { 2 + subdirs } else { 0 })
+            }
+        };
+
+        // Safe because this only has integer fields, for which zero is a valid value.
+        let mut st: libc::stat64 = unsafe { mem::zeroed() };
+        st.st_ino = ino as _;
+        st.st_mode = node.mode;
+        st.st_nlink = nlink as _;
+        st.st_uid = node.uid;
+        st.st_gid = node.gid;
+        st.st_size = size as _;
+        st.st_blksize = 4096;
+        st.st_blocks = size.div_ceil(512) as _;
+        st.st_atime = node.atime.tv_sec;
+        st.st_atime_nsec = node.atime.tv_nsec as _;
+        st.st_mtime = node.mtime.tv_sec;
+        st.st_mtime_nsec = node.mtime.tv_nsec as _;
+        st.st_ctime = node.ctime.tv_sec;
+        st.st_ctime_nsec = node.ctime.tv_nsec as _;
+        Ok(st)
+    }
+
+    // Returns the entries of the directory `dir` that come after `offset`, up to `READDIR_BATCH`
+    // of them, including those for "." and "..".
+    fn dir_entries(&self, dir: u64, offset: u64) -> io::Result<Vec<(DirEntry, libc::stat64)>> {
+        let entries = self.node(dir)?.dir()?;
+
+        let mut dots = Vec::new();
+        if offset < DOT_OFFSET {
+            dots.push((DOT_OFFSET, &b"."[..], dir));
+        }
+        if offset < DOTDOT_OFFSET {
+            dots.push((DOTDOT_OFFSET, &b".."[..], self.parent_of(dir)?));
+        }
+
+        let rest = entries
+            .entries
+            .range(offset.max(DOTDOT_OFFSET) + 1..)
+            .map(|(&offset, (name, ino))| (offset, &name[..], *ino));
+        dots.into_iter()
+            .chain(rest)
+            .take(READDIR_BATCH)
+            .map(|(offset, name, ino)| {
+                let st = self.stat(ino)?;
+                let type_ = if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                    libc::DT_DIR
+                } else {
+                    libc::DT_REG
+                };
+                let entry = DirEntry {
+                    offset,
+                    type_,
+                    name: P9String::new(name)?,
+                };
+                Ok((entry, st))
+            })
+            .collect()
+    }
+}
+
+// Checks that `name` can be the name of a new entry.
+fn check_name(name: &CStr) -> io::Result<&[u8]> {
+    let name = name.to_bytes();
+    if name.is_empty() || name.contains(&b'/') {
+        return Err(err(libc::EINVAL));
+    }
+    if name == b"." || name == b".." {
+        return Err(err(libc::EEXIST));
+    }
+    if name.len() > NAME_MAX {
+        return Err(err(libc::ENAMETOOLONG));
+    }
+    Ok(name)
+}
+
+// Checks that `name` is in one of the namespaces of extended attributes that Linux knows of.
+fn check_xattr_name(name: &CStr) -> io::Result<&[u8]> {
+    let name = name.to_bytes();
+    let namespaces: [&[u8]; 4] = [b"user.", b"trusted.", b"security.", b"system."];
+    if namespaces
+        .iter()
+        .any(|ns| name.starts_with(ns) && name.len() > ns.len())
+    {
+        Ok(name)
+    } else {
+        Err(err(libc::EOPNOTSUPP))
+    }
+}
+
+impl FileSystem for Tmpfs {
+    type Inode = TmpfsInode;
+    type Handle = TmpfsHandle;
+
+    fn root(&self, export: &Export) -> io::Result<TmpfsInode> {
+        let mut tree = self.tree.lock().unwrap();
+        let ino = match tree.roots.get(&export.root) {
+            Some(&ino) => ino,
+            None => {
+                // Like the root of a tmpfs mount, anyone may create files in there.
+                let ino = tree.add_node(
+                    libc::S_IFDIR | 0o1777,
+                    u32::MAX,
+                    u32::MAX,
+                    Contents::Dir(Dir::new()),
+                );
+                tree.roots.insert(export.root.clone(), ino);
+                ino
+            }
+        };
+        Ok(self.inode(&mut tree, ino, None))
+    }
+
+    fn lookup(&self, parent: &TmpfsInode, name: &CStr) -> io::Result<TmpfsInode> {
+        let mut tree = self.tree.lock().unwrap();
+        tree.node(parent.ino)?.dir()?;
+        let (ino, link) = match name.to_bytes() {
+            b"." => (parent.ino, parent.link.clone()),
+            b".." => {
+                let ino = tree.parent_of(parent.ino)?;
+                (ino, tree.node(ino)?.links.first().cloned())
+            }
+            name => (
+                tree.child(parent.ino, name)?,
+                Some((parent.ino, name.to_vec())),
+            ),
+        };
+        Ok(self.inode(&mut tree, ino, link))
+    }
+
+    fn try_clone(&self, inode: &TmpfsInode) -> io::Result<TmpfsInode> {
+        let mut tree = self.tree.lock().unwrap();
+        Ok(self.inode(&mut tree, inode.ino, inode.link.clone()))
+    }
+
+    fn stat(&self, inode: &TmpfsInode) -> io::Result<libc::stat64> {
+        self.tree.lock().unwrap().stat(inode.ino)
+    }
+
+    fn setattr(
+        &self,
+        inode: &TmpfsInode,
+        _handle: Option<&TmpfsHandle>,
+        attr: &Tsetattr,
+    ) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        if attr.valid & P9_SETATTR_SIZE != 0 {
+            tree.resize(inode.ino, attr.size)?;
+            tree.node_mut(inode.ino)?.mtime = now();
+        }
+
+        let node = tree.node_mut(inode.ino)?;
+        if attr.valid & P9_SETATTR_MODE != 0 {
+            node.mode = node.mode & libc::S_IFMT | attr.mode & 0o7777;
+        }
+        if attr.valid & P9_SETATTR_UID != 0 {
+            node.uid = attr.uid;
+        }
+        if attr.valid & P9_SETATTR_GID != 0 {
+            node.gid = attr.gid;
+        }
+
+        let time = |set: u32, sec: u64, nsec: u64| {
+            if attr.valid & set == 0 {
+                now()
+            } else {
+                libc::timespec {
+                    tv_sec: sec as _,
+                    tv_nsec: nsec as _,
+                }
+            }
+        };
+        if attr.valid & P9_SETATTR_ATIME != 0 {
+            node.atime = time(P9_SETATTR_ATIME_SET, attr.atime_sec, attr.atime_nsec);
+        }
+        if attr.valid & P9_SETATTR_MTIME != 0 {
+            node.mtime = time(P9_SETATTR_MTIME_SET, attr.mtime_sec, attr.mtime_nsec);
+        }
+
+        if attr.valid != 0 {
+            node.ctime = now();
+        }
+        Ok(())
+    }
+
+    fn open(&self, inode: &TmpfsInode, flags: u32) -> io::Result<TmpfsHandle> {
+        let mut tree = self.tree.lock().unwrap();
+        let writable = flags & P9_NOACCESS != P9_RDONLY;
+        match tree.node(inode.ino)?.contents {
+            Contents::Dir(_) if writable => return Err(err(libc::EISDIR)),
+            Contents::File(_) if flags & P9_DIRECTORY != 0 => return Err(err(libc::ENOTDIR)),
+            Contents::File(_) if writable && flags & P9_TRUNC != 0 => {
+                tree.resize(inode.ino, 0)?;
+                tree.node_mut(inode.ino)?.touch();
+            }
+            _ => {}
+        }
+
+        Ok(TmpfsHandle {
+            inode: self.inode(&mut tree, inode.ino, inode.link.clone()),
+            flags,
+        })
+    }
+
+    fn create(
+        &self,
+        dir: &TmpfsInode,
+        name: &CStr,
+        flags: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<(TmpfsInode, TmpfsHandle)> {
+        let name = check_name(name)?;
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_live_dir(dir.ino)?;
+        if tree.child(dir.ino, name).is_ok() {
+            return Err(err(libc::EEXIST));
+        }
+
+        let contents = Contents::File(Vec::new());
+        let ino = tree.add_node(libc::S_IFREG | mode & 0o7777, uid, gid, contents);
+        tree.add_link(dir.ino, name, ino)?;
+
+        let link = Some((dir.ino, name.to_vec()));
+        let handle = TmpfsHandle {
+            inode: self.inode(&mut tree, ino, link.clone()),
+            flags,
+        };
+        Ok((self.inode(&mut tree, ino, link), handle))
+    }
+
+    fn read(&self, handle: &TmpfsHandle, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if handle.flags & P9_NOACCESS == P9_WRONLY {
+            return Err(ebadf());
+        }
+
+        let tree = self.tree.lock().unwrap();
+        let data = match tree.node(handle.inode.ino)?.contents {
+            Contents::File(ref data) => data,
+            Contents::Dir(_) => return Err(err(libc::EISDIR)),
+        };
+        let start = min(offset, data.len() as u64) as usize;
+        let count = min(buf.len(), data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write(&self, handle: &TmpfsHandle, buf: &[u8], offset: u64) -> io::Result<usize> {
+        if handle.flags & P9_NOACCESS == P9_RDONLY {
+            return Err(ebadf());
+        }
+
+        let mut tree = self.tree.lock().unwrap();
+        let ino = handle.inode.ino;
+        let len = tree.file_mut(ino)?.len() as u64;
+        let start = if handle.flags & P9_APPEND != 0 {
+            len
+        } else {
+            offset
+        };
+        let end = start
+            .checked_add(buf.len() as u64)
+            .ok_or_else(|| err(libc::EFBIG))?;
+
+        if end > len {
+            tree.resize(ino, end)?;
+        }
+        tree.file_mut(ino)?[start as usize..end as usize].copy_from_slice(buf);
+        tree.node_mut(ino)?.touch();
+        Ok(buf.len())
+    }
+
+    fn readdir(
+        &self,
+        dir: &TmpfsInode,
+        _handle: &mut TmpfsHandle,
+        mut offset: u64,
+        entry: &mut dyn FnMut(DirEntry, &libc::stat64) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        loop {
+            // `entry` may call back into the file system so the tree can't stay locked while it
+            // runs.
+            let batch = self.tree.lock().unwrap().dir_entries(dir.ino, offset)?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            for (dirent, st) in batch {
+                offset = dirent.offset;
+                if !entry(dirent, &st)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn readlink(&self, _inode: &TmpfsInode) -> io::Result<Vec<u8>> {
+        Err(err(libc::EINVAL))
+    }
+
+    fn mkdir(
+        &self,
+        dir: &TmpfsInode,
+        name: &CStr,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<libc::stat64> {
+        let name = check_name(name)?;
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_live_dir(dir.ino)?;
+        if tree.child(dir.ino, name).is_ok() {
+            return Err(err(libc::EEXIST));
+        }
+
+        let contents = Contents::Dir(Dir::new());
+        let ino = tree.add_node(libc::S_IFDIR | mode & 0o7777, uid, gid, contents);
+        tree.add_link(dir.ino, name, ino)?;
+        tree.stat(ino)
+    }
+
+    fn link(&self, inode: &TmpfsInode, dir: &TmpfsInode, name: &CStr) -> io::Result<()> {
+        let name = check_name(name)?;
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_live_dir(dir.ino)?;
+        let node = tree.node(inode.ino)?;
+        if node.is_dir() {
+            return Err(err(libc::EPERM));
+        }
+        if node.links.is_empty() {
+            return Err(err(libc::ENOENT));
+        }
+        if tree.child(dir.ino, name).is_ok() {
+            return Err(err(libc::EEXIST));
+        }
+
+        tree.add_link(dir.ino, name, inode.ino)
+    }
+
+    fn rename(
+        &self,
+        olddir: &TmpfsInode,
+        oldname: &CStr,
+        newdir: &TmpfsInode,
+        newname: &CStr,
+    ) -> io::Result<()> {
+        let newname = check_name(newname)?;
+        let oldname = oldname.to_bytes();
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_live_dir(newdir.ino)?;
+        let ino = tree.child(olddir.ino, oldname)?;
+        let is_dir = tree.node(ino)?.is_dir();
+
+        let replaced = tree.child(newdir.ino, newname).ok();
+        if replaced == Some(ino) {
+            // Both names are links to the same file, so there is nothing to do.
+            return Ok(());
+        }
+        if let Some(replaced) = replaced {
+            let replaced = tree.node(replaced)?;
+            match (is_dir, replaced.dir()) {
+                (true, Ok(dir)) if !dir.entries.is_empty() => return Err(err(libc::ENOTEMPTY)),
+                (true, Err(_)) => return Err(err(libc::ENOTDIR)),
+                (false, Ok(_)) => return Err(err(libc::EISDIR)),
+                _ => {}
+            }
+        }
+
+        // A directory can't be moved into itself.
+        if is_dir {
+            let mut dir = newdir.ino;
+            while !tree.is_root(dir) {
+                if dir == ino {
+                    return Err(err(libc::EINVAL));
+                }
+                dir = tree.parent_of(dir)?;
+            }
+        }
+
+        // Keep the file alive while it is in neither directory.
+        tree.node_mut(ino)?.refs += 1;
+        if replaced.is_some() {
+            tree.remove_link(newdir.ino, newname)?;
+        }
+        tree.remove_link(olddir.ino, oldname)?;
+        let added = tree.add_link(newdir.ino, newname, ino);
+        tree.node_mut(ino)?.refs -= 1;
+        tree.maybe_free(ino);
+        added
+    }
+
+    fn unlink(&self, dir: &TmpfsInode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+        let name = name.to_bytes();
+        let mut tree = self.tree.lock().unwrap();
+        let ino = tree.child(dir.ino, name)?;
+        match (flags & libc::AT_REMOVEDIR != 0, tree.node(ino)?.dir()) {
+            (true, Ok(dir)) if !dir.entries.is_empty() => return Err(err(libc::ENOTEMPTY)),
+            (true, Err(_)) => return Err(err(libc::ENOTDIR)),
+            (false, Ok(_)) => return Err(err(libc::EISDIR)),
+            _ => {}
+        }
+
+        tree.remove_link(dir.ino, name)?;
+        Ok(())
+    }
+
+    fn parent(
+        &self,
+        _export: &Export,
+        inode: &TmpfsInode,
+        _walk_path: &Path,
+    ) -> io::Result<(TmpfsInode, CString)> {
+        let mut tree = self.tree.lock().unwrap();
+        if tree.is_root(inode.ino) {
+            return Err(err(libc::EBUSY));
+        }
+
+        // Prefer the link that the client walked through, if it is still there.
+        let links = &tree.node(inode.ino)?.links;
+        let (parent, name) = inode
+            .link
+            .as_ref()
+            .filter(|link| links.contains(link))
+            .or_else(|| links.first())
+            .cloned()
+            .ok_or_else(|| err(libc::ENOENT))?;
+
+        let link = tree.node(parent)?.links.first().cloned();
+        let name = CString::new(name).map_err(|_| err(libc::EINVAL))?;
+        Ok((self.inode(&mut tree, parent, link), name))
+    }
+
+    fn statfs(&self, _inode: &TmpfsInode) -> io::Result<Rstatfs> {
+        // Like a tmpfs mount, which reports no blocks at all without a size limit, and no inodes
+        // without a limit on those.
+        let tree = self.tree.lock().unwrap();
+        let (blocks, bfree) = match tree.capacity {
+            Some(capacity) => (
+                capacity / STATFS_BLOCK_SIZE,
+                capacity.saturating_sub(tree.used) / STATFS_BLOCK_SIZE,
+            ),
+            None => (0, 0),
+        };
+        Ok(Rstatfs {
+            ty: TMPFS_MAGIC,
+            bsize: STATFS_BLOCK_SIZE as u32,
+            blocks,
+            bfree,
+            bavail: bfree,
+            files: 0,
+            ffree: 0,
+            fsid: 0,
+            namelen: NAME_MAX as u32,
+        })
+    }
+
+    fn fsync(&self, _handle: &TmpfsHandle, _datasync: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn getxattr(
+        &self,
+        inode: &TmpfsInode,
+        _handle: Option<&TmpfsHandle>,
+        name: &CStr,
+    ) -> io::Result<Vec<u8>> {
+        let tree = self.tree.lock().unwrap();
+        let xattrs = &tree.node(inode.ino)?.xattrs;
+        if name.is_empty() {
+            let mut names = Vec::new();
+            for name in xattrs.keys() {
+                names.extend_from_slice(name);
+                names.push(0);
+            }
+            return Ok(names);
+        }
+
+        xattrs
+            .get(check_xattr_name(name)?)
+            .cloned()
+            .ok_or_else(|| err(libc::ENODATA))
+    }
+
+    fn setxattr(
+        &self,
+        inode: &TmpfsInode,
+        _handle: Option<&TmpfsHandle>,
+        name: &CStr,
+        value: &[u8],
+        flags: libc::c_int,
+    ) -> io::Result<()> {
+        let name = check_xattr_name(name)?;
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.node_mut(inode.ino)?;
+        let exists = node.xattrs.contains_key(name);
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(err(libc::EEXIST));
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(err(libc::ENODATA));
+        }
+
+        node.xattrs.insert(name.to_vec(), value.to_vec());
+        node.ctime = now();
+        Ok(())
+    }
+
+    fn removexattr(
+        &self,
+        inode: &TmpfsInode,
+        _handle: Option<&TmpfsHandle>,
+        name: &CStr,
+    ) -> io::Result<()> {
+        let name = check_xattr_name(name)?;
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.node_mut(inode.ino)?;
+        node.xattrs.remove(name).ok_or_else(|| err(libc::ENODATA))?;
+        node.ctime = now();
+        Ok(())
+    }
+}
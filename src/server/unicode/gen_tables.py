@@ -0,0 +1,144 @@
+#!/usr/bin/env python3
+# Copyright 2026 The ChromiumOS Authors
+# Use of this source code is governed by a BSD-style license that can be
+# found in the LICENSE file.
+
+"""Generates tables.rs from the Unicode Character Database in Python's unicodedata module.
+
+The tables are pinned to Unicode 14.0.0, which is what Python 3.11 ships. Run it from this
+directory with that Python to regenerate them:
+
+    python3.11 gen_tables.py > tables.rs
+
+Moving to a newer version of Unicode means changing UNICODE_VERSION, running this with a Python
+that ships it and checking that the tests still pass.
+"""
+
+import sys
+import unicodedata
+
+UNICODE_VERSION = "14.0.0"
+
+# Hangul syllables are decomposed and composed arithmetically instead.
+HANGUL_FIRST = 0xAC00
+HANGUL_LAST = 0xD7A3
+
+LINE_WIDTH = 100
+
+HEADER = """\
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Generated by gen_tables.py from the Unicode Character Database, version {version}, by listing
+// the canonical decompositions, the primary composites and the nonzero canonical combining classes
+// in it. Hangul syllables are left out since they are decomposed and composed arithmetically.
+"""
+
+TABLE = """
+{comment}
+#[rustfmt::skip]
+pub(super) const {name}: &[{ty}] = &[
+{rows}
+];
+"""
+
+
+def char(cp):
+    """Returns a Rust char literal for a code point."""
+    if cp == 0:
+        return "'\\0'"
+    if 0x20 < cp < 0x7F and chr(cp) not in "\\'":
+        return "'%s'" % chr(cp)
+    return "'\\u{%X}'" % cp
+
+
+def decompositions():
+    """Returns the canonical decompositions and the primary composites, leaving out Hangul."""
+    decomps = []
+    comps = []
+    for cp in range(sys.maxunicode + 1):
+        if HANGUL_FIRST <= cp <= HANGUL_LAST:
+            continue
+        fields = unicodedata.decomposition(chr(cp))
+        # Compatibility decompositions start with a tag like <compat>.
+        if not fields or fields.startswith("<"):
+            continue
+        parts = [int(field, 16) for field in fields.split()]
+        assert len(parts) in (1, 2), hex(cp)
+        if len(parts) == 1:
+            decomps.append((cp, parts[0], 0))
+            continue
+        decomps.append((cp, parts[0], parts[1]))
+        # A pair that composes back to the character is a primary composite, which leaves out
+        # the composition exclusions.
+        if unicodedata.normalize("NFC", chr(parts[0]) + chr(parts[1])) == chr(cp):
+            comps.append((parts[0], parts[1], cp))
+    comps.sort()
+    return decomps, comps
+
+
+def combining_classes():
+    """Returns the inclusive ranges of characters with the same nonzero combining class."""
+    ranges = []
+    for cp in range(sys.maxunicode + 1):
+        ccc = unicodedata.combining(chr(cp))
+        if not ccc:
+            continue
+        if ranges and ranges[-1][1] == cp - 1 and ranges[-1][2] == ccc:
+            ranges[-1][1] = cp
+        else:
+            ranges.append([cp, cp, ccc])
+    return ranges
+
+
+def rows(items):
+    """Formats tuples of Rust literals as rows that fit in the line width."""
+    lines = []
+    line = "   "
+    for item in items:
+        entry = " (%s)," % ", ".join(item)
+        if len(line) + len(entry) > LINE_WIDTH:
+            lines.append(line)
+            line = "   "
+        line += entry
+    lines.append(line)
+    return "\n".join(lines)
+
+
+def table(comment, name, ty, items):
+    return TABLE.format(comment=comment, name=name, ty=ty, rows=rows(items))
+
+
+def main():
+    if unicodedata.unidata_version != UNICODE_VERSION:
+        sys.exit(
+            "this Python has Unicode %s instead of %s"
+            % (unicodedata.unidata_version, UNICODE_VERSION)
+        )
+    decomps, comps = decompositions()
+    out = HEADER.format(version=UNICODE_VERSION)
+    out += table(
+        "// Characters and the one or two characters that they decompose to, the second of which"
+        " is nul\n// for singletons. Sorted by character.",
+        "DECOMPOSITIONS",
+        "(char, char, char)",
+        [tuple(char(cp) for cp in row) for row in decomps],
+    )
+    out += table(
+        "// Pairs of characters and the primary composite that they compose to. Sorted by pair.",
+        "COMPOSITIONS",
+        "(char, char, char)",
+        [tuple(char(cp) for cp in row) for row in comps],
+    )
+    out += table(
+        "// Ranges of characters, inclusive, and their canonical combining class. Sorted.",
+        "COMBINING_CLASSES",
+        "(char, char, u8)",
+        [(char(first), char(last), str(ccc)) for first, last, ccc in combining_classes()],
+    )
+    sys.stdout.write(out)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,228 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Unicode normalization of file names, so that the decomposed names that macOS tools create and
+//! the composed ones that Linux clients send can refer to the same file. See Unicode Standard
+//! Annex #15 for the algorithms. The tables are generated from Unicode 14.0.0 by gen_tables.py.
+
+mod tables;
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use tables::COMBINING_CLASSES;
+use tables::COMPOSITIONS;
+use tables::DECOMPOSITIONS;
+
+/// A Unicode normalization form for file names. As an option it is `nfc` or `nfd`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NormalForm {
+    /// Canonical composition, which Linux tools produce.
+    Nfc,
+    /// Canonical decomposition, which macOS tools produce.
+    Nfd,
+}
+
+impl FromStr for NormalForm {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(NormalForm::Nfc),
+            "nfd" => Ok(NormalForm::Nfd),
+            _ => Err("`normalize_names` must be one of `nfc` or `nfd`"),
+        }
+    }
+}
+
+// Hangul syllables are composed of a leading consonant, a vowel and optionally a trailing
+// consonant, and are numbered by those.
+const S_BASE: u32 = 0xac00;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11a7;
+const L_COUNT: u32 = 19;
+const V_COUNT: u32 = 21;
+const T_COUNT: u32 = 28;
+const N_COUNT: u32 = V_COUNT * T_COUNT;
+const S_COUNT: u32 = L_COUNT * N_COUNT;
+
+fn combining_class(c: char) -> u8 {
+    COMBINING_CLASSES
+        .binary_search_by(|&(first, last, _)| {
+            if last < c {
+                Ordering::Less
+            } else if first > c {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        })
+        .map_or(0, |i| COMBINING_CLASSES[i].2)
+}
+
+// Pushes the full canonical decomposition of `c` to `out`.
+fn decompose(c: char, out: &mut Vec<char>) {
+    let s = (c as u32).wrapping_sub(S_BASE);
+    if s < S_COUNT {
+        let jamo = [
+            L_BASE + s / N_COUNT,
+            V_BASE + s % N_COUNT / T_COUNT,
+            T_BASE + s % T_COUNT,
+        ];
+        let len = if s.is_multiple_of(T_COUNT) { 2 } else { 3 };
+        out.extend(jamo[..len].iter().filter_map(|&c| char::from_u32(c)));
+        return;
+    }
+
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(c, _, _)| c) {
+        Ok(i) => {
+            let (_, first, second) = DECOMPOSITIONS[i];
+            decompose(first, out);
+            if second != '\0' {
+                decompose(second, out);
+            }
+        }
+        Err(_) => out.push(c),
+    }
+}
+
+// Returns the primary composite of `first` and `second`, if they have one.
+fn compose_pair(first: char, second: char) -> Option<char> {
+    let (a, b) = (first as u32, second as u32);
+    if (L_BASE..L_BASE + L_COUNT).contains(&a) && (V_BASE..V_BASE + V_COUNT).contains(&b) {
+        return char::from_u32(S_BASE + ((a - L_BASE) * V_COUNT + b - V_BASE) * T_COUNT);
+    }
+    if (S_BASE..S_BASE + S_COUNT).contains(&a)
+        && (a - S_BASE).is_multiple_of(T_COUNT)
+        && (T_BASE + 1..T_BASE + T_COUNT).contains(&b)
+    {
+        return char::from_u32(a + b - T_BASE);
+    }
+
+    COMPOSITIONS
+        .binary_search_by_key(&(first, second), |&(a, b, _)| (a, b))
+        .ok()
+        .map(|i| COMPOSITIONS[i].2)
+}
+
+// Returns the canonical decomposition of `s`, with the combining marks in canonical order.
+fn nfd(s: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        decompose(c, &mut chars);
+    }
+
+    // Marks are sorted by their class, stably, but never past a starter, which has class 0.
+    for i in 1..chars.len() {
+        let mut j = i;
+        while j > 0 && combining_class(chars[j]) != 0 {
+            if combining_class(chars[j - 1]) <= combining_class(chars[j]) {
+                break;
+            }
+            chars.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+    chars
+}
+
+// Composes the decomposed `chars` in place.
+fn compose(chars: &mut Vec<char>) {
+    let mut composed: Vec<char> = Vec::with_capacity(chars.len());
+    let mut starter = None;
+    for &c in chars.iter() {
+        let class = combining_class(c);
+        if let Some(i) = starter {
+            // A mark is blocked from the starter by any character in between that has the same
+            // class or a higher one, and a starter by any character at all.
+            let blocked = composed.len() > i + 1 && {
+                let last = combining_class(composed[composed.len() - 1]);
+                last == 0 || last >= class
+            };
+            if let Some(c) = compose_pair(composed[i], c).filter(|_| !blocked) {
+                composed[i] = c;
+                continue;
+            }
+        }
+        if class == 0 {
+            starter = Some(composed.len());
+        }
+        composed.push(c);
+    }
+    *chars = composed;
+}
+
+impl NormalForm {
+    // Returns `name` in this form. Names that aren't UTF-8 are returned as they are.
+    pub(super) fn normalize(self, name: &[u8]) -> Cow<'_, [u8]> {
+        let s = match std::str::from_utf8(name) {
+            Ok(s) if !s.is_ascii() => s,
+            _ => return Cow::Borrowed(name),
+        };
+
+        let mut chars = nfd(s);
+        if self == NormalForm::Nfc {
+            compose(&mut chars);
+        }
+        let normalized: String = chars.into_iter().collect();
+        if normalized == s {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(normalized.into_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        let cases = [
+            ("plain ascii", "plain ascii", "plain ascii"),
+            ("e\u{301}", "\u{e9}", "e\u{301}"),
+            ("\u{e9}", "\u{e9}", "e\u{301}"),
+            // The Angstrom sign always becomes the letter.
+            ("\u{212b}", "\u{c5}", "A\u{30a}"),
+            // Marks are put in order before composing.
+            ("d\u{307}\u{323}", "\u{1e0d}\u{307}", "d\u{323}\u{307}"),
+            ("A\u{30a}\u{327}", "\u{c5}\u{327}", "A\u{327}\u{30a}"),
+            ("a\u{344}", "\u{e4}\u{301}", "a\u{308}\u{301}"),
+            (
+                "\u{1100}\u{1161}\u{11a8}",
+                "\u{ac01}",
+                "\u{1100}\u{1161}\u{11a8}",
+            ),
+            (
+                "\u{d55c}\u{ae00}",
+                "\u{d55c}\u{ae00}",
+                "\u{1112}\u{1161}\u{11ab}\u{1100}\u{1173}\u{11af}",
+            ),
+            ("\u{b47}\u{b3e}", "\u{b4b}", "\u{b47}\u{b3e}"),
+        ];
+        for (name, nfc, nfd) in cases {
+            assert_eq!(
+                NormalForm::Nfc.normalize(name.as_bytes()),
+                nfc.as_bytes(),
+                "{name:?}"
+            );
+            assert_eq!(
+                NormalForm::Nfd.normalize(name.as_bytes()),
+                nfd.as_bytes(),
+                "{name:?}"
+            );
+        }
+
+        let invalid = b"caf\xc3";
+        assert_eq!(NormalForm::Nfc.normalize(invalid), &invalid[..]);
+        assert_eq!("nfd".parse(), Ok(NormalForm::Nfd));
+        assert!("nfkc".parse::<NormalForm>().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Which extended attributes clients can see and change, and the names that they are kept under
+//! on the host.
+
+use std::ffi::CStr;
+use std::io;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::glob;
+use super::Config;
+
+const CAPABILITY: &CStr = c"security.capability";
+const REMAPPED_CAPABILITY: &CStr = c"user.p9.security.capability";
+
+/// What clients may do with the security.capability extended attribute, which gives the programs
+/// that the host runs from a file the capabilities in it. As an option it is `allow`, `deny` or
+/// `remap`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CapabilityXattr {
+    /// Clients see and change the attribute of the host file.
+    #[default]
+    Allow,
+    /// Clients see the attribute of the host file, but setting or removing it fails with EPERM.
+    Deny,
+    /// Clients get an attribute of their own, which is kept on the host as
+    /// user.p9.security.capability where the host takes no notice of it. They can't see the real
+    /// attribute of the host file, nor the one it is kept under.
+    Remap,
+}
+
+impl FromStr for CapabilityXattr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(CapabilityXattr::Allow),
+            "deny" => Ok(CapabilityXattr::Deny),
+            "remap" => Ok(CapabilityXattr::Remap),
+            _ => Err("`capability_xattr` must be one of `allow`, `deny` or `remap`"),
+        }
+    }
+}
+
+impl CapabilityXattr {
+    // Returns the name on the host of the attribute that clients see as `name`, or None if
+    // clients can't see it.
+    fn host_name<'a>(&self, name: &'a CStr) -> Option<&'a CStr> {
+        match self {
+            CapabilityXattr::Remap if name == CAPABILITY => Some(REMAPPED_CAPABILITY),
+            CapabilityXattr::Remap if name == REMAPPED_CAPABILITY => None,
+            _ => Some(name),
+        }
+    }
+
+    // Returns the name that clients see for the attribute of a host file named `name`, or None
+    // if they can't see it.
+    fn client_name<'a>(&self, name: &'a CStr) -> Option<&'a CStr> {
+        match self {
+            CapabilityXattr::Remap if name == REMAPPED_CAPABILITY => Some(CAPABILITY),
+            CapabilityXattr::Remap if name == CAPABILITY => None,
+            _ => Some(name),
+        }
+    }
+}
+
+impl Config {
+    // Returns true if the `xattr_allow` and `xattr_deny` patterns let clients see and use the
+    // attribute that they know as `name`.
+    fn xattr_visible(&self, name: &CStr) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| glob::matches(pattern.as_bytes(), name.to_bytes()))
+        };
+        (self.xattr_allow.is_empty() || matches(&self.xattr_allow)) && !matches(&self.xattr_deny)
+    }
+
+    // Returns the name on the host of the attribute `name` that a client wants to read, or fails
+    // with ENODATA if it can't see it.
+    pub(super) fn readable_xattr<'a>(&self, name: &'a CStr) -> io::Result<&'a CStr> {
+        Some(name)
+            .filter(|name| self.xattr_visible(name))
+            .and_then(|name| self.capability_xattr.host_name(name))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))
+    }
+
+    // Returns the name on the host of the attribute `name` that a client wants to set or remove,
+    // or fails with EPERM if it may not.
+    pub(super) fn writable_xattr<'a>(&self, name: &'a CStr) -> io::Result<&'a CStr> {
+        let denied = self.capability_xattr == CapabilityXattr::Deny && name == CAPABILITY;
+        Some(name)
+            .filter(|name| !denied && self.xattr_visible(name))
+            .and_then(|name| self.capability_xattr.host_name(name))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))
+    }
+
+    // Rewrites `list`, the names of the attributes of a host file each followed by a nul byte, as
+    // clients see them, leaving out the ones that they can't.
+    pub(super) fn xattr_list_to_client(&self, list: Vec<u8>) -> Vec<u8> {
+        if self.capability_xattr != CapabilityXattr::Remap
+            && self.xattr_allow.is_empty()
+            && self.xattr_deny.is_empty()
+        {
+            return list;
+        }
+
+        let mut names = Vec::with_capacity(list.len());
+        for name in list.split_inclusive(|&b| b == 0) {
+            let name = match CStr::from_bytes_with_nul(name) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if let Some(name) = self
+                .capability_xattr
+                .client_name(name)
+                .filter(|name| self.xattr_visible(name))
+            {
+                names.extend_from_slice(name.to_bytes_with_nul());
+            }
+        }
+        names
+    }
+}